@@ -342,32 +342,78 @@ function parseLog(text) {
   return { parsed, rawLines: lines };
 }
 
+// Detection.csv gained columns over time, so cells are looked up by header name; a file
+// without a header has the original five columns.
+const CSV_COLUMNS = ["timestamp", "present", "avg_distance_m", "avg_strength", "agree_pct"];
+
 function parseCsv(text) {
   const lines = text.split(/\r?\n/).filter(Boolean);
   if (!lines.length) return [];
 
-  const hasHeader = /^timestamp\s*,\s*present\s*,\s*avg_distance_m\s*,\s*avg_strength\s*,\s*agree_pct\s*$/i.test(lines[0]);
+  const hasHeader = /^timestamp\s*,\s*present\s*,/i.test(lines[0]);
+  const columns = hasHeader ? lines[0].split(",").map((c) => c.trim().toLowerCase()) : CSV_COLUMNS;
   const startIdx = hasHeader ? 1 : 0;
 
   const events = [];
   for (let i = startIdx; i < lines.length; i++) {
     const line = lines[i];
-    const parts = line.split(",");
+    const parts = splitCsvLine(line);
     if (parts.length < 2) continue;
 
-    const ts = (parts[0] || "").trim();
-    const presentStr = (parts[1] || "").trim().toLowerCase();
+    const cell = (name) => {
+      const k = columns.indexOf(name);
+      const v = k >= 0 && parts[k] !== undefined ? parts[k].trim() : "";
+      return v.length ? v : undefined;
+    };
+    const num = (name) => (cell(name) === undefined ? undefined : Number(cell(name)));
+
+    const ts = cell("timestamp") || "";
+    const presentStr = (cell("present") || "").toLowerCase();
     const present = presentStr === "true" || presentStr === "1";
-    const distance = parts[2] && parts[2].trim().length ? Number(parts[2].trim()) : undefined;
-    const strength = parts[3] && parts[3].trim().length ? Number(parts[3].trim()) : undefined;
-    const agreePct = parts[4] && parts[4].trim().length ? Number(parts[4].trim()) : undefined;
 
-    events.push({ ts, present, distance, strength, agreePct, raw: line });
+    events.push({
+      ts,
+      present,
+      distance: num("avg_distance_m"),
+      strength: num("avg_strength"),
+      agreePct: num("agree_pct"),
+      state: cell("state"),
+      raw: line,
+    });
   }
 
   return events;
 }
 
+// One CSV line into cells; a quoted cell (a song url with a comma) may hold commas and "".
+function splitCsvLine(line) {
+  const cells = [];
+  let cur = "";
+  let quoted = false;
+  for (let i = 0; i < line.length; i++) {
+    const c = line[i];
+    if (quoted) {
+      if (c === '"' && line[i + 1] === '"') {
+        cur += '"';
+        i++;
+      } else if (c === '"') {
+        quoted = false;
+      } else {
+        cur += c;
+      }
+    } else if (c === '"') {
+      quoted = true;
+    } else if (c === ",") {
+      cells.push(cur);
+      cur = "";
+    } else {
+      cur += c;
+    }
+  }
+  cells.push(cur);
+  return cells;
+}
+
 function StatusPill({ present }) {
   return (
     <span
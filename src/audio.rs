@@ -0,0 +1,1504 @@
+//! src/audio.rs
+//! Capture backends behind one trait, so the detection modes don't care whether
+//! samples come from cpal, WASAPI loopback, a decoded file or a generator.
+
+use anyhow::Result;
+use cpal::traits::{ DeviceTrait, HostTrait, StreamTrait };
+use crossbeam_channel::{ bounded, select, Receiver, Sender };
+use std::{
+    fmt,
+    path::Path,
+    sync::{ atomic::{ AtomicBool, AtomicU64, Ordering }, Arc, Condvar, Mutex },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::backpressure;
+use crate::supervise;
+use crate::logger::Logger;
+use crate::prescan::{ self, Fingerprint, FpType };
+use crate::{
+    audio_sink_thread,
+    build_input_stream,
+    decode,
+    maybe_rate_supported,
+    wasapi_loopback,
+    Config,
+    SharedBuf,
+};
+
+/// How multichannel audio is folded to the mono stream the detectors work on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelMix {
+    Average, // mean of all channels
+    LeftRight, // mean of the first two (front L/R), ignoring centre and surrounds
+    Channel(usize), // one channel, 0-based
+}
+
+impl ChannelMix {
+    /// `average`, `lr`, `first`, or a 1-based channel number.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "average" | "avg" => Ok(ChannelMix::Average),
+            "lr" | "l+r" => Ok(ChannelMix::LeftRight),
+            "first" => Ok(ChannelMix::Channel(0)),
+            other =>
+                match other.parse::<usize>() {
+                    Ok(n) if n >= 1 => Ok(ChannelMix::Channel(n - 1)),
+                    _ =>
+                        Err(
+                            format!(
+                                "Invalid channel mix: {}. Valid options: average, lr, first, or a channel number (1 = left)",
+                                s
+                            )
+                        ),
+                }
+        }
+    }
+
+    pub fn as_str(&self) -> String {
+        match self {
+            ChannelMix::Average => "average".to_string(),
+            ChannelMix::LeftRight => "lr".to_string(),
+            ChannelMix::Channel(n) => format!("{}", n + 1),
+        }
+    }
+
+    /// Fold one interleaved frame. Channel numbers past the end fall back to the last channel.
+    #[inline]
+    pub fn frame(&self, frame: &[f32]) -> f32 {
+        match (self, frame.len()) {
+            (_, 0) => 0.0,
+            (_, 1) => frame[0],
+            (ChannelMix::Average, n) => frame.iter().sum::<f32>() / (n as f32),
+            (ChannelMix::LeftRight, _) => 0.5 * (frame[0] + frame[1]),
+            (ChannelMix::Channel(c), n) => frame[(*c).min(n - 1)],
+        }
+    }
+
+    /// Interleaved → mono; a trailing partial frame is dropped.
+    pub fn downmix(&self, interleaved: &[f32], channels: usize) -> Vec<f32> {
+        if channels <= 1 {
+            return interleaved.to_vec();
+        }
+        interleaved
+            .chunks_exact(channels)
+            .map(|f| self.frame(f))
+            .collect()
+    }
+}
+
+/// The first two channels of one capture block, deinterleaved: (left, right).
+pub type StereoBlock = (Vec<f32>, Vec<f32>);
+
+/// Deinterleave channels 0 and 1; a trailing partial frame is dropped.
+pub fn split_stereo(interleaved: &[f32], channels: usize) -> StereoBlock {
+    if channels < 2 {
+        return (interleaved.to_vec(), interleaved.to_vec());
+    }
+    interleaved
+        .chunks_exact(channels)
+        .map(|f| (f[0], f[1]))
+        .unzip()
+}
+
+/// `--probe-tone`: when the inaudible sine plays into the output, so the loopback has content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeMode {
+    Off,
+    On,
+    Auto, // only while the loopback carries no media
+}
+
+impl ProbeMode {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ProbeMode::Off),
+            "on" => Ok(ProbeMode::On),
+            "auto" => Ok(ProbeMode::Auto),
+            _ => Err(format!("Invalid probe tone mode: {}. Valid options: on, off, auto", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeMode::Off => "off",
+            ProbeMode::On => "on",
+            ProbeMode::Auto => "auto",
+        }
+    }
+}
+
+/// `--probe-signal`: what the probe plays at `--probe-freq-hz`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProbeSignal {
+    Sine,
+    Prbs, // carrier phase-flipped by a pseudo-random chip sequence from --probe-seed
+}
+
+impl ProbeSignal {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "sine" => Ok(ProbeSignal::Sine),
+            "prbs" => Ok(ProbeSignal::Prbs),
+            _ => Err(format!("Invalid probe signal: {}. Valid options: sine, prbs", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProbeSignal::Sine => "sine",
+            ProbeSignal::Prbs => "prbs",
+        }
+    }
+}
+
+/// Chip rate of the `prbs` probe; it occupies `--probe-freq-hz` ± this.
+pub const PRBS_CHIP_HZ: f32 = 2000.0;
+/// Chips before the `prbs` sequence repeats (~0.5 s at PRBS_CHIP_HZ, longer than a correlation frame).
+const PRBS_CHIPS: usize = 1023;
+/// Normalized template match above which the loopback counts as carrying the `prbs` probe.
+const PRBS_LOCK_MIN: f32 = 0.25;
+
+/// The ±1 chip sequence for `seed` (xorshift32), shared by the probe generator and the detector.
+pub fn prbs_chips(seed: u32) -> Vec<f32> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9) ^ 0x6d2b_79f5;
+    if state == 0 {
+        state = 0x6d2b_79f5;
+    }
+    (0..PRBS_CHIPS)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            if state & 1 == 1 { 1.0 } else { -1.0 }
+        })
+        .collect()
+}
+
+/// Loopback this long without media before `--probe-tone auto` switches the probe on.
+const PROBE_AUTO_QUIET: Duration = Duration::from_secs(1);
+/// Media level is measured below the probe frequency minus this, so the probe does not count as media.
+const PROBE_GUARD_HZ: f32 = 1000.0;
+
+/// Probe tone settings, from `--probe-tone`, `--probe-signal`, `--probe-seed`, `--probe-freq-hz` and `--probe-amp`.
+#[derive(Clone, Copy, Debug)]
+pub struct ProbeTone {
+    pub mode: ProbeMode,
+    pub signal: ProbeSignal,
+    pub seed: u32, // prbs chip sequence
+    pub freq_hz: f32,
+    pub amp: f32, // linear, full scale = 1
+    pub quiet_rms: f32, // loopback below this (outside the probe band) counts as no media: --min-ref-rms
+}
+
+impl ProbeTone {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            mode: cfg.probe_tone,
+            signal: cfg.probe_signal,
+            seed: cfg.probe_seed,
+            freq_hz: cfg.probe_freq_hz,
+            amp: cfg.probe_amp,
+            quiet_rms: cfg.min_ref_rms,
+        }
+    }
+
+    /// Band the probe occupies: the sine's frequency, or the prbs main lobe around it.
+    pub fn band(&self) -> (f32, f32) {
+        match self.signal {
+            ProbeSignal::Sine => (self.freq_hz, self.freq_hz),
+            ProbeSignal::Prbs => (self.freq_hz - PRBS_CHIP_HZ, self.freq_hz + PRBS_CHIP_HZ),
+        }
+    }
+
+    /// One period of the unit-amplitude prbs probe at `sr`; None for the sine.
+    pub fn prbs_period(&self, sr: f32) -> Option<Vec<f32>> {
+        if self.signal != ProbeSignal::Prbs {
+            return None;
+        }
+        let per_chip = ((sr / PRBS_CHIP_HZ).round() as usize).max(1);
+        let w = (2.0 * std::f32::consts::PI * self.freq_hz) / sr;
+        let chips = prbs_chips(self.seed);
+        Some(
+            (0..chips.len() * per_chip)
+                .map(|i| chips[i / per_chip] * ((w * (i as f32)) % (2.0 * std::f32::consts::PI)).sin())
+                .collect()
+        )
+    }
+
+    /// Loopback level of `block` apart from the probe itself.
+    pub fn media_rms(&self, block: &[f32], sr: f32) -> f32 {
+        let mut x = block.to_vec();
+        crate::sonar_presence::band_limit(&mut x, sr, 0.0, self.band().0 - PROBE_GUARD_HZ);
+        prescan::rms(&x)
+    }
+}
+
+/// The detector's copy of the `prbs` probe: finds where in the sequence a loopback frame is and
+/// hands back that stretch of the clean template to correlate the mic against, so media or a
+/// narrowband interferer in the loopback does not smear the correlation.
+pub struct ProbeTemplate {
+    probe: ProbeTone,
+    period: Option<(f32, Vec<f32>)>, // built for the first sample rate seen
+}
+
+impl ProbeTemplate {
+    /// Some when the probe plays the prbs signal, from the same `--probe-seed` as the generator.
+    pub fn from_config(cfg: &Config) -> Option<Self> {
+        let probe = ProbeTone::from_config(cfg);
+        (probe.mode != ProbeMode::Off && probe.signal == ProbeSignal::Prbs).then_some(Self { probe, period: None })
+    }
+
+    pub fn band(&self) -> (f32, f32) {
+        self.probe.band()
+    }
+
+    /// The template stretch aligned with `reference`, or None when the probe is not in it.
+    pub fn align(&mut self, reference: &[f32], sr: f32) -> Option<Vec<f32>> {
+        if self.period.as_ref().is_none_or(|(at, _)| *at != sr) {
+            self.period = self.probe.prbs_period(sr).map(|p| (sr, p));
+        }
+        let period = &self.period.as_ref()?.1;
+        let len = period.len();
+        let (lo, hi) = self.probe.band();
+        let mut r = reference.to_vec();
+        crate::sonar_presence::band_limit(&mut r, sr, lo, hi);
+
+        // fold the frame onto one period and correlate circularly
+        let mut folded = vec![0.0f32; len];
+        for (i, v) in r.iter().enumerate() {
+            folded[i % len] += v;
+        }
+        let mut planner = realfft::RealFftPlanner::<f32>::new();
+        let r2c = planner.plan_fft_forward(len);
+        let c2r = planner.plan_fft_inverse(len);
+        let (mut fx, mut fp) = (r2c.make_output_vec(), r2c.make_output_vec());
+        r2c.process(&mut folded, &mut fx).ok()?;
+        r2c.process(&mut period.clone(), &mut fp).ok()?;
+        let mut spec: Vec<_> = fp.iter().zip(&fx).map(|(p, x)| p * x.conj()).collect();
+        let mut corr = vec![0.0f32; len];
+        c2r.process(&mut spec, &mut corr).ok()?;
+        let offset = (0..len).max_by(|&a, &b| corr[a].total_cmp(&corr[b]))?;
+
+        let segment: Vec<f32> = (0..r.len()).map(|i| period[(offset + i) % len]).collect();
+        let dot: f32 = r.iter().zip(&segment).map(|(a, b)| a * b).sum();
+        let norm = (r.iter().map(|v| v * v).sum::<f32>() * segment.iter().map(|v| v * v).sum::<f32>()).sqrt();
+        (dot / (norm + 1e-9) >= PRBS_LOCK_MIN).then_some(segment)
+    }
+}
+
+/// `--probe-tone auto`: on once the loopback has been quiet for PROBE_AUTO_QUIET, off as soon as media plays.
+#[derive(Default)]
+pub struct ProbeGate {
+    pub on: bool,
+    quiet_since: Option<Instant>,
+}
+
+impl ProbeGate {
+    /// Feed one loopback block's media level; returns the new state when the probe should switch.
+    pub fn update(&mut self, media_rms: f32, quiet_rms: f32, now: Instant) -> Option<bool> {
+        if media_rms >= quiet_rms {
+            self.quiet_since = None;
+            return self.on.then(|| {
+                self.on = false;
+                false
+            });
+        }
+        let since = *self.quiet_since.get_or_insert(now);
+        if !self.on && now.saturating_duration_since(since) >= PROBE_AUTO_QUIET {
+            self.on = true;
+            return Some(true);
+        }
+        None
+    }
+}
+
+/// Which render audio the loopback reference captures.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoopbackTarget {
+    Default, // default console render device
+    Device(String), // --loopback-device: endpoint ID, or (part of) its friendly name
+    Process(String), // --loopback-process: PID or executable name, child processes included
+}
+
+impl LoopbackTarget {
+    pub fn describe(&self) -> String {
+        match self {
+            LoopbackTarget::Default => "default render device".to_string(),
+            LoopbackTarget::Device(d) => format!("render device '{}'", d),
+            LoopbackTarget::Process(p) => format!("process '{}'", p),
+        }
+    }
+}
+
+/// `--loopback-device`: index of the endpoint `wanted` names among `(id, friendly name)` pairs.
+/// An exact ID or name wins; otherwise the name must contain `wanted` for exactly one endpoint.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))] // only the WASAPI backend resolves targets
+pub fn pick_endpoint(wanted: &str, endpoints: &[(String, String)]) -> Result<usize, String> {
+    let key = wanted.to_lowercase();
+    if let Some(i) = endpoints.iter().position(|(id, name)| id == wanted || name.to_lowercase() == key) {
+        return Ok(i);
+    }
+    let hits: Vec<usize> = (0..endpoints.len()).filter(|&i| endpoints[i].1.to_lowercase().contains(&key)).collect();
+    let names = || endpoints.iter().map(|(_, name)| format!("'{}'", name)).collect::<Vec<_>>().join(", ");
+    match hits[..] {
+        [i] => Ok(i),
+        [] => Err(format!("no render device matches '{}'; active devices: {}", wanted, names())),
+        _ => Err(format!("'{}' matches several render devices: {}", wanted, names())),
+    }
+}
+
+/// `--loopback-process`: the PID to capture among running `(pid, parent pid, exe name)`.
+/// A number is taken as a PID; a name (".exe" optional) picks the top of that program's
+/// process tree, since its child processes are captured along with it.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub fn pick_process(wanted: &str, procs: &[(u32, u32, String)]) -> Option<u32> {
+    if let Ok(pid) = wanted.parse::<u32>() {
+        return procs.iter().any(|p| p.0 == pid).then_some(pid);
+    }
+    let key = wanted.to_lowercase();
+    let key = key.strip_suffix(".exe").unwrap_or(&key);
+    let is_it = |exe: &str| {
+        let exe = exe.to_lowercase();
+        exe.strip_suffix(".exe").unwrap_or(&exe) == key
+    };
+    let hits: Vec<&(u32, u32, String)> = procs.iter().filter(|p| is_it(&p.2)).collect();
+    hits.iter()
+        .find(|p| !hits.iter().any(|parent| parent.0 == p.1))
+        .or(hits.first())
+        .map(|p| p.0)
+}
+
+/// Why a capture or playback device could not be opened. `AudioSource::start` passes these on
+/// inside its `anyhow::Error`, so callers can `downcast_ref::<CaptureError>()` to tell a missing
+/// device from a driver failure.
+#[derive(Debug)]
+pub enum CaptureError {
+    NoInputDevice,
+    NoOutputDevice,
+    UnsupportedFormat(cpal::SampleFormat),
+    Config(cpal::DefaultStreamConfigError),
+    Build(cpal::BuildStreamError),
+    Play(cpal::PlayStreamError),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoInputDevice => write!(f, "No default input device (microphone) found"),
+            Self::NoOutputDevice => write!(f, "No default output device found"),
+            Self::UnsupportedFormat(format) => write!(f, "Unsupported sample format: {:?}", format),
+            Self::Config(e) => write!(f, "cannot read the device's stream config: {}", e),
+            Self::Build(e) => write!(f, "cannot open the audio stream: {}", e),
+            Self::Play(e) => write!(f, "cannot start the audio stream: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Config(e) => Some(e),
+            Self::Build(e) => Some(e),
+            Self::Play(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<cpal::DefaultStreamConfigError> for CaptureError {
+    fn from(e: cpal::DefaultStreamConfigError) -> Self {
+        Self::Config(e)
+    }
+}
+
+impl From<cpal::BuildStreamError> for CaptureError {
+    fn from(e: cpal::BuildStreamError) -> Self {
+        Self::Build(e)
+    }
+}
+
+impl From<cpal::PlayStreamError> for CaptureError {
+    fn from(e: cpal::PlayStreamError) -> Self {
+        Self::Play(e)
+    }
+}
+
+/// A mono sample stream.
+pub trait AudioSource {
+    /// Short description for the log.
+    fn describe(&self) -> String;
+
+    /// Start delivering mono blocks. `want_sr` is the rate the caller would like;
+    /// sources that cannot honour it deliver their own. Returns the actual rate.
+    fn start(&mut self, want_sr: Option<u32>, logger: Arc<Logger>) -> Result<(f32, Receiver<Vec<f32>>)>;
+
+    /// True once a finite source has delivered everything; live devices never finish.
+    fn finished(&self) -> bool {
+        false
+    }
+
+    /// The mic's ring buffer, handed over before `start` for sources that align themselves
+    /// to what the mic hears.
+    fn hear(&mut self, _mic: &SharedBuf) {}
+
+    /// After `start`: the first two channels, block for block with the mono stream, for
+    /// sources asked to keep them that have them.
+    fn stereo(&mut self) -> Option<Receiver<StereoBlock>> {
+        None
+    }
+
+    /// A live device that can be opened again by calling `start` once more, after the stream
+    /// stopped delivering (device removed, driver glitch).
+    fn restartable(&self) -> bool {
+        false
+    }
+
+    /// Release the device of a restartable source; `start` opens it again.
+    fn stop(&mut self) {}
+
+    /// After `start`: the name of the live device behind the source, which per-device
+    /// calibration is kept under. None for files.
+    fn device(&self) -> Option<String> {
+        None
+    }
+
+    /// The virtual clock a stepped source delivers by; the pipeline then ticks by it too.
+    fn clock(&self) -> Option<StepClock> {
+        None
+    }
+}
+
+/// Mic + reference sources for the live modes: `--ref-file` in place of the loopback, the
+/// recorded `--mic-wav`/`--ref-wav` pair when both are given (in real time, or stepped on a
+/// virtual clock at any other `--replay-speed`), otherwise the capture devices.
+pub fn sources_from_config(
+    cfg: &Config,
+    loopback_tick_ms: u64
+) -> Result<(Box<dyn AudioSource>, Box<dyn AudioSource>)> {
+    let mix = cfg.channel_mix;
+    if !cfg.ref_file.is_empty() {
+        let mic: Box<dyn AudioSource> = if cfg.replay_mic_wav.is_empty() {
+            Box::new(CpalMic::new(mix).keep_stereo(cfg.bearing))
+        } else {
+            Box::new(MemorySource::from_file(Path::new(&cfg.replay_mic_wav), mix)?)
+        };
+        return Ok((mic, Box::new(FileReference::from_file(cfg)?)));
+    }
+    if !cfg.replay_mic_wav.is_empty() && !cfg.replay_ref_wav.is_empty() {
+        let mut mic = MemorySource::from_file(Path::new(&cfg.replay_mic_wav), mix)?;
+        let mut reference = MemorySource::from_file(Path::new(&cfg.replay_ref_wav), mix)?;
+        if cfg.replay_speed != 1.0 {
+            let clock = StepClock::paced(cfg.replay_speed);
+            mic = mic.stepped(&clock);
+            reference = reference.stepped(&clock);
+        }
+        return Ok((Box::new(mic), Box::new(reference)));
+    }
+    Ok((Box::new(CpalMic::new(mix).keep_stereo(cfg.bearing)), Box::new(Loopback::new(loopback_tick_ms, mix, cfg.loopback.clone(), ProbeTone::from_config(cfg)))))
+}
+
+/// Start `source` and keep its most recent `RING_SECONDS` in a shared ring buffer.
+pub fn capture(source: &mut dyn AudioSource, want_sr: Option<u32>, logger: Arc<Logger>) -> Result<SharedBuf> {
+    capture_fed(source, want_sr, logger).map(|(shared, _)| shared)
+}
+
+/// Live streams that arrive at another rate than their ring are converted with this.
+pub const LIVE_RESAMPLE: decode::ResampleQuality = decode::ResampleQuality::Sinc;
+
+/// Hands the blocks of a restarted source to the thread filling its ring, so the ring, and
+/// everyone reading it, carries on across the restart.
+pub struct Feed(Sender<(f32, Receiver<Vec<f32>>)>);
+
+/// `capture`, keeping the `Feed` that `restart` needs.
+pub fn capture_fed(source: &mut dyn AudioSource, want_sr: Option<u32>, logger: Arc<Logger>) -> Result<(SharedBuf, Feed)> {
+    let (sr, rx) = source.start(want_sr, logger.clone())?;
+    logger.info(&format!("{}: {} Hz", source.describe(), sr))?;
+    Ok(fill(SharedBuf::new(sr, crate::RING_SECONDS), sr, rx, "mic", source.clock(), logger))
+}
+
+/// `capture_fed` at exactly `sr`, for a stream that is correlated sample for sample with one
+/// already at that rate: a source that delivers another rate is resampled on its way in.
+pub fn capture_as(source: &mut dyn AudioSource, sr: u32, logger: Arc<Logger>) -> Result<(SharedBuf, Feed)> {
+    let (got, rx) = source.start(Some(sr), logger.clone())?;
+    logger.info(&format!("{}: {} Hz", source.describe(), got))?;
+    if got != (sr as f32) {
+        logger.warn(&format!("{} runs at {} Hz, the mic at {} Hz: resampling it to {} Hz", source.describe(), got, sr, sr))?;
+    }
+    Ok(fill(SharedBuf::new(sr as f32, crate::RING_SECONDS), got, rx, "ref", source.clock(), logger))
+}
+
+fn fill(
+    shared: SharedBuf,
+    sr_in: f32,
+    mut rx: Receiver<Vec<f32>>,
+    source: &'static str,
+    clock: Option<StepClock>,
+    logger: Arc<Logger>
+) -> (SharedBuf, Feed) {
+    crate::privacy::register(&shared);
+    let mut sink = Sink::new(shared.clone(), sr_in, source);
+    sink.clock = clock;
+    let (feed_tx, feed_rx) = bounded::<(f32, Receiver<Vec<f32>>)>(1);
+    // after a panic the filler carries on with the stream it had, the resampler started afresh
+    supervise::spawn("capture ring filler", logger, move || {
+        sink.switch(sink.sr_in);
+        fed_sink_thread(&mut rx, &feed_rx, &mut sink)
+    });
+    (shared, Feed(feed_tx))
+}
+
+/// Start `source` again and fill `shared` from the new stream; whatever the old one still
+/// delivers is dropped. A device that comes back at another rate is resampled to the ring's.
+pub fn restart(source: &mut dyn AudioSource, shared: &SharedBuf, feed: &Feed, logger: Arc<Logger>) -> Result<()> {
+    let (sr, rx) = source.start(Some(shared.sr as u32), logger.clone())?;
+    if sr != shared.sr {
+        logger.warn(&format!("{} came back at {} Hz: resampling it to {} Hz", source.describe(), sr, shared.sr))?;
+    }
+    feed.0.send((sr, rx)).map_err(|_| anyhow::anyhow!("{}: capture thread has exited", source.describe()))
+}
+
+/// Close a source `restart` can open again: the device is released and the thread filling
+/// `shared` drops the old stream (a capture thread that feeds it then ends) and waits for a restart.
+pub fn close(source: &mut dyn AudioSource, shared: &SharedBuf, feed: &Feed) {
+    source.stop();
+    let (_, closed) = bounded::<Vec<f32>>(1);
+    let _ = feed.0.send((shared.sr, closed));
+}
+
+/// The writing end of a ring, converting a stream at another rate to the ring's.
+struct Sink {
+    shared: SharedBuf,
+    resampler: Option<decode::Resampler>,
+    sr_in: f32, // rate of the stream being written
+    source: &'static str, // `--backpressure` counters
+    clock: Option<StepClock>, // a stepped source: blocks arrive at its time, and are acknowledged
+}
+
+impl Sink {
+    fn new(shared: SharedBuf, sr_in: f32, source: &'static str) -> Self {
+        let mut sink = Self { shared, resampler: None, sr_in, source, clock: None };
+        sink.switch(sr_in);
+        sink
+    }
+
+    /// A new stream at `sr_in` follows.
+    fn switch(&mut self, sr_in: f32) {
+        self.sr_in = sr_in;
+        let sr = self.shared.sr;
+        self.resampler = (sr_in != sr).then(|| decode::Resampler::new(sr_in as u32, sr as u32, LIVE_RESAMPLE));
+    }
+
+    fn push(&mut self, block: &[f32]) {
+        let at = self.clock.as_ref().map_or_else(Instant::now, StepClock::instant);
+        match self.resampler.as_mut() {
+            Some(r) => self.shared.push_at(&r.process(block), at),
+            None => self.shared.push_at(block, at),
+        }
+        if let Some(c) = &self.clock {
+            c.landed();
+        }
+    }
+}
+
+/// `audio_sink_thread` that switches to a new stream whenever `feed` hands one over. The ring
+/// keeps a single writer: this thread, whichever stream it reads.
+fn fed_sink_thread(rx: &mut Receiver<Vec<f32>>, feed: &Receiver<(f32, Receiver<Vec<f32>>)>, sink: &mut Sink) {
+    let mut feed_open = true;
+    loop {
+        if !feed_open {
+            // nobody can restart it any more
+            if sink.resampler.is_none() && sink.clock.is_none() {
+                return audio_sink_thread(rx.clone(), sink.shared.clone(), sink.source);
+            }
+            while let Ok(block) = rx.recv() {
+                if !backpressure::skip_oldest(rx, sink.source) {
+                    sink.push(&block);
+                }
+            }
+            return;
+        }
+        let next = select! {
+            recv(rx) -> block => match block {
+                Ok(block) => {
+                    if !backpressure::skip_oldest(rx, sink.source) {
+                        sink.push(&block);
+                    }
+                    continue;
+                }
+                // the source stopped: wait for a restart
+                Err(_) => feed.recv().ok(),
+            },
+            recv(feed) -> next => match next {
+                Ok(next) => Some(next),
+                Err(_) => {
+                    feed_open = false;
+                    continue;
+                }
+            },
+        };
+        match next {
+            Some((sr_in, next)) => {
+                sink.switch(sr_in);
+                *rx = next;
+            }
+            None => return,
+        }
+    }
+}
+
+/// Keep a stereo source's two channels in rings of their own, sample for sample with the
+/// mono ring `capture` keeps.
+pub fn capture_stereo(rx: Receiver<StereoBlock>, sr: f32, logger: Arc<Logger>) -> (SharedBuf, SharedBuf) {
+    let (left, right) = (SharedBuf::new(sr, crate::RING_SECONDS), SharedBuf::new(sr, crate::RING_SECONDS));
+    crate::privacy::register(&left);
+    crate::privacy::register(&right);
+    let (l, r) = (left.clone(), right.clone());
+    supervise::spawn("stereo ring filler", logger, move || {
+        while let Ok((a, b)) = rx.recv() {
+            l.push(&a);
+            r.push(&b);
+        }
+    });
+    (left, right)
+}
+
+/// Default input device through cpal.
+pub struct CpalMic {
+    mix: ChannelMix,
+    stereo: bool, // also deliver the first two channels (--bearing)
+    name: String,
+    stream: Option<cpal::Stream>, // kept alive while capturing
+    pair_rx: Option<Receiver<StereoBlock>>,
+}
+
+impl CpalMic {
+    pub fn new(mix: ChannelMix) -> Self {
+        Self { mix, stereo: false, name: String::new(), stream: None, pair_rx: None }
+    }
+
+    pub fn keep_stereo(mut self, on: bool) -> Self {
+        self.stereo = on;
+        self
+    }
+}
+
+impl AudioSource for CpalMic {
+    fn describe(&self) -> String {
+        format!("Mic '{}'", self.name)
+    }
+
+    fn start(&mut self, want_sr: Option<u32>, logger: Arc<Logger>) -> Result<(f32, Receiver<Vec<f32>>)> {
+        let host = cpal::default_host();
+        let device = host
+            .default_input_device()
+            .ok_or(CaptureError::NoInputDevice)?;
+        let mut config = device.default_input_config().map_err(CaptureError::from)?.config();
+        if let Some(sr) = want_sr.and_then(|want| maybe_rate_supported(&device, want)) {
+            config.sample_rate.0 = sr;
+        }
+        self.name = device.name().unwrap_or_default();
+
+        logger.info(&format!("Mic device: {}", self.name))?;
+        logger.info(
+            &format!("Mic: sample rate {} Hz, channels {}", config.sample_rate.0, config.channels)
+        )?;
+
+        let (tx, rx) = bounded::<Vec<f32>>(8);
+        let channels = config.channels.max(1) as usize;
+        let pair_tx = match (self.stereo, channels) {
+            (false, _) => None,
+            (true, 1) => {
+                logger.warn("--bearing needs a stereo mic; this one has a single channel")?;
+                None
+            }
+            (true, _) => {
+                let (pair_tx, pair_rx) = bounded::<StereoBlock>(8);
+                self.pair_rx = Some(pair_rx);
+                Some(pair_tx)
+            }
+        };
+        let stream = build_input_stream(&device, &config, channels, self.mix, tx, pair_tx, logger)?;
+        stream.play().map_err(CaptureError::from)?;
+        self.stream = Some(stream);
+        Ok((config.sample_rate.0 as f32, rx))
+    }
+
+    fn stereo(&mut self) -> Option<Receiver<StereoBlock>> {
+        self.pair_rx.take()
+    }
+
+    fn restartable(&self) -> bool {
+        true
+    }
+
+    fn device(&self) -> Option<String> {
+        (!self.name.is_empty()).then(|| self.name.clone())
+    }
+
+    fn stop(&mut self) {
+        self.stream = None;
+        self.pair_rx = None;
+    }
+}
+
+/// What a render device, or one program, is playing (WASAPI loopback, Windows only).
+pub struct Loopback {
+    tick_ms: u64,
+    mix: ChannelMix,
+    target: LoopbackTarget,
+    probe: ProbeTone,
+    _probe_stream: Option<cpal::Stream>, // kept alive while capturing
+}
+
+impl Loopback {
+    /// `tick_ms` sets the capture polling interval.
+    pub fn new(tick_ms: u64, mix: ChannelMix, target: LoopbackTarget, probe: ProbeTone) -> Self {
+        Self { tick_ms, mix, target, probe, _probe_stream: None }
+    }
+}
+
+impl AudioSource for Loopback {
+    fn describe(&self) -> String {
+        format!("Loopback ({})", self.target.describe())
+    }
+
+    /// The endpoint named, or the default one: a process loopback hears what plays through it.
+    fn device(&self) -> Option<String> {
+        match &self.target {
+            LoopbackTarget::Device(d) => Some(d.clone()),
+            _ => cpal::default_host().default_output_device().and_then(|d| d.name().ok()),
+        }
+    }
+
+    fn start(&mut self, want_sr: Option<u32>, logger: Arc<Logger>) -> Result<(f32, Receiver<Vec<f32>>)> {
+        let sr = want_sr.unwrap_or(48_000);
+        let rx = wasapi_loopback::start(sr, logger.clone(), self.tick_ms, self.mix, self.target.clone())?;
+        if self.probe.mode == ProbeMode::Off {
+            return Ok((sr as f32, rx));
+        }
+
+        let gate = Arc::new(AtomicBool::new(self.probe.mode == ProbeMode::On));
+        match crate::start_probe(sr, &self.probe, gate.clone()) {
+            Ok(stream) => {
+                self._probe_stream = Some(stream);
+                logger.info(
+                    &format!(
+                        "Probe tone {} ({} at {:.0} Hz, amplitude {})",
+                        self.probe.mode.as_str(),
+                        self.probe.signal.as_str(),
+                        self.probe.freq_hz,
+                        self.probe.amp
+                    )
+                )?;
+            }
+            Err(e) => {
+                logger.warn(&format!("Probe tone unavailable: {}", e))?;
+                return Ok((sr as f32, rx));
+            }
+        }
+        if self.probe.mode == ProbeMode::On {
+            return Ok((sr as f32, rx));
+        }
+
+        // auto: watch the loopback on its way to the ring and switch the probe with the media
+        let probe = self.probe;
+        let (tx, relayed) = bounded::<Vec<f32>>(8);
+        thread::spawn(move || {
+            let mut auto = ProbeGate::default();
+            for block in rx {
+                if let Some(on) = auto.update(probe.media_rms(&block, sr as f32), probe.quiet_rms, Instant::now()) {
+                    gate.store(on, Ordering::Relaxed);
+                    let _ = logger.info(if on { "No media on the loopback: probe tone on" } else { "Media playing: probe tone off" });
+                }
+                if !backpressure::send(&tx, block, "ref") {
+                    break;
+                }
+            }
+        });
+        Ok((sr as f32, relayed))
+    }
+
+    fn restartable(&self) -> bool {
+        true
+    }
+
+    // the capture thread ends once `close` has dropped its receiver
+    fn stop(&mut self) {
+        self._probe_stream = None;
+    }
+}
+
+/// Virtual time for sources that deliver as fast as the pipeline takes their audio rather than
+/// in real time. The pipeline advances it a tick at a time and waits until every stepped source
+/// has delivered, and its ring filler written, the audio up to the new time; each block is
+/// stamped with that time. A run on stepped sources is deterministic and takes as long as its
+/// analysis, not as long as the audio, unless the clock is `paced` to a speed.
+#[derive(Clone)]
+pub struct StepClock(Arc<(Mutex<Steps>, Condvar)>);
+
+struct Steps {
+    origin: Instant,
+    speed: f32, // virtual seconds per wall-clock second; 0 = as fast as the pipeline goes
+    now: Duration,
+    step: u64, // advances so far
+    sources: usize, // joined and not yet done
+    behind: usize, // of those, still delivering up to `now`
+    in_flight: usize, // blocks sent and not yet in a ring
+}
+
+impl Default for StepClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StepClock {
+    pub fn new() -> Self {
+        Self::paced(0.0)
+    }
+
+    /// A clock that advances no faster than `speed` times real time (`--replay-speed`).
+    pub fn paced(speed: f32) -> Self {
+        let steps = Steps { origin: Instant::now(), speed, now: Duration::ZERO, step: 0, sources: 0, behind: 0, in_flight: 0 };
+        Self(Arc::new((Mutex::new(steps), Condvar::new())))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Steps> {
+        self.0.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait<'a>(&self, g: std::sync::MutexGuard<'a, Steps>) -> std::sync::MutexGuard<'a, Steps> {
+        self.0.1.wait(g).unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The virtual time as an Instant: the clock's creation plus the time advanced.
+    pub fn instant(&self) -> Instant {
+        let g = self.lock();
+        g.origin + g.now
+    }
+
+    /// Move virtual time on to `at` and wait until the stepped sources have caught up with it.
+    pub fn advance_to(&self, at: Instant) {
+        let (origin, speed) = {
+            let g = self.lock();
+            (g.origin, g.speed)
+        };
+        if speed > 0.0 {
+            let due = origin + at.saturating_duration_since(origin).div_f32(speed);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+        let mut g = self.lock();
+        let target = at.saturating_duration_since(g.origin);
+        if target <= g.now {
+            return;
+        }
+        g.now = target;
+        g.step += 1;
+        g.behind = g.sources;
+        self.0.1.notify_all();
+        while g.behind > 0 || g.in_flight > 0 {
+            g = self.wait(g);
+        }
+    }
+
+    /// A source starts delivering by this clock.
+    fn join(&self) {
+        let mut g = self.lock();
+        g.sources += 1;
+        g.behind += 1;
+    }
+
+    /// The source's next block ends at `t`: wait until virtual time has reached it.
+    fn until(&self, t: Duration) {
+        let mut g = self.lock();
+        while g.now < t {
+            let step = g.step;
+            g.behind = g.behind.saturating_sub(1);
+            self.0.1.notify_all();
+            while g.step == step {
+                g = self.wait(g);
+            }
+        }
+    }
+
+    /// A block is on its way to a ring; returns once every block sent is in its ring.
+    fn deliver(&self, send: impl FnOnce() -> bool) -> bool {
+        self.lock().in_flight += 1;
+        if !send() {
+            self.landed();
+            return false;
+        }
+        let mut g = self.lock();
+        while g.in_flight > 0 {
+            g = self.wait(g);
+        }
+        true
+    }
+
+    /// A ring has taken a block of a stepped source.
+    pub fn landed(&self) {
+        let mut g = self.lock();
+        g.in_flight = g.in_flight.saturating_sub(1);
+        self.0.1.notify_all();
+    }
+
+    /// The source has delivered everything.
+    fn leave(&self) {
+        let mut g = self.lock();
+        g.sources = g.sources.saturating_sub(1);
+        g.behind = g.behind.saturating_sub(1);
+        self.0.1.notify_all();
+    }
+}
+
+/// Samples held in memory: a decoded file, a recording, or synthetic test audio. Delivered in
+/// real time, or by a `StepClock` (`stepped`).
+pub struct MemorySource {
+    label: String,
+    samples: Vec<f32>,
+    sr: u32,
+    clock: Option<StepClock>,
+    done: Arc<AtomicBool>,
+}
+
+impl MemorySource {
+    pub fn new(label: &str, samples: Vec<f32>, sr: u32) -> Self {
+        Self { label: label.to_string(), samples, sr, clock: None, done: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Deliver by `clock` instead of in real time.
+    pub fn stepped(mut self, clock: &StepClock) -> Self {
+        self.clock = Some(clock.clone());
+        self
+    }
+
+    /// Decode an audio file, folded to mono with `mix`.
+    pub fn from_file(path: &Path, mix: ChannelMix) -> Result<Self> {
+        let audio = decode::load_mono(path, mix)?;
+        Ok(Self::new(&format!("File '{}'", path.display()), audio.samples_mono, audio.sr))
+    }
+}
+
+impl AudioSource for MemorySource {
+    fn describe(&self) -> String {
+        self.label.clone()
+    }
+
+    fn start(&mut self, want_sr: Option<u32>, _logger: Arc<Logger>) -> Result<(f32, Receiver<Vec<f32>>)> {
+        let sr = want_sr.unwrap_or(self.sr);
+        let samples = decode::resample_linear_mono(&std::mem::take(&mut self.samples), self.sr, sr);
+        // 10 ms blocks, like a device callback
+        let block = ((sr as usize) / 100).max(1);
+
+        let (tx, rx) = bounded::<Vec<f32>>(8);
+        let (clock, done) = (self.clock.clone(), self.done.clone());
+        if let Some(c) = &clock {
+            c.join();
+        }
+        thread::spawn(move || {
+            let t0 = Instant::now();
+            for (i, chunk) in samples.chunks(block).enumerate() {
+                let sent = match &clock {
+                    Some(c) => {
+                        c.until(Duration::from_secs_f64(((i * block + chunk.len()) as f64) / (sr as f64)));
+                        c.deliver(|| tx.send(chunk.to_vec()).is_ok())
+                    }
+                    None => {
+                        let due = t0 + Duration::from_secs_f64(((i * block) as f64) / (sr as f64));
+                        let now = Instant::now();
+                        if due > now {
+                            thread::sleep(due - now);
+                        }
+                        tx.send(chunk.to_vec()).is_ok()
+                    }
+                };
+                if !sent {
+                    break;
+                }
+            }
+            done.store(true, Ordering::SeqCst);
+            if let Some(c) = &clock {
+                c.leave();
+            }
+        });
+        Ok((sr as f32, rx))
+    }
+
+    fn finished(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    fn clock(&self) -> Option<StepClock> {
+        self.clock.clone()
+    }
+}
+
+/// Plays a decoded file on the default output device and delivers, as its stream, exactly the
+/// samples handed to the device so far: the reference for `--mode play`, without a loopback.
+/// The file is folded to mono (`--channel-mix`) and sent to every output channel.
+pub struct FilePlayer {
+    label: String,
+    samples: Vec<f32>,
+    sr: u32,
+    quality: decode::ResampleQuality,
+    done: Arc<AtomicBool>,
+    stream: Option<cpal::Stream>, // kept alive while playing
+}
+
+impl FilePlayer {
+    pub fn from_file(path: &Path, mix: ChannelMix, quality: decode::ResampleQuality) -> Result<Self> {
+        let audio = decode::load_mono(path, mix)?;
+        Ok(Self {
+            label: format!("Playback '{}'", path.display()),
+            samples: audio.samples_mono,
+            sr: audio.sr,
+            quality,
+            done: Arc::new(AtomicBool::new(false)),
+            stream: None,
+        })
+    }
+
+    /// Length of the file in seconds.
+    pub fn duration_s(&self) -> f32 {
+        (self.samples.len() as f32) / (self.sr as f32)
+    }
+}
+
+impl AudioSource for FilePlayer {
+    fn describe(&self) -> String {
+        self.label.clone()
+    }
+
+    fn start(&mut self, want_sr: Option<u32>, logger: Arc<Logger>) -> Result<(f32, Receiver<Vec<f32>>)> {
+        let host = cpal::default_host();
+        let device = host
+            .default_output_device()
+            .ok_or(CaptureError::NoOutputDevice)?;
+        let supported = device.default_output_config().map_err(CaptureError::from)?;
+        let config = supported.config();
+        let out_sr = config.sample_rate.0;
+        let channels = config.channels.max(1) as usize;
+        logger.info(
+            &format!(
+                "Output device: {} ({} Hz, {} channels)",
+                device.name().unwrap_or_default(),
+                out_sr,
+                channels
+            )
+        )?;
+
+        // the device plays at its own rate; the reference goes out at the rate asked for
+        let ref_sr = want_sr.unwrap_or(out_sr);
+        let played = Arc::new(decode::resample_mono(&self.samples, self.sr, out_sr, self.quality));
+        let reference = decode::resample_mono(&std::mem::take(&mut self.samples), self.sr, ref_sr, self.quality);
+
+        let pos = Arc::new(AtomicU64::new(0)); // frames handed to the device
+        let stream = match supported.sample_format() {
+            cpal::SampleFormat::F32 => build_player::<f32>(&device, &config, channels, played.clone(), pos.clone(), logger.clone())?,
+            cpal::SampleFormat::I16 => build_player::<i16>(&device, &config, channels, played.clone(), pos.clone(), logger.clone())?,
+            cpal::SampleFormat::U16 => build_player::<u16>(&device, &config, channels, played.clone(), pos.clone(), logger.clone())?,
+            other => {
+                return Err(CaptureError::UnsupportedFormat(other).into());
+            }
+        };
+        stream.play().map_err(CaptureError::from)?;
+        self.stream = Some(stream);
+
+        // follow the device position in 10 ms steps, like a capture callback
+        let (tx, rx) = bounded::<Vec<f32>>(8);
+        let done = self.done.clone();
+        let total = played.len() as u64;
+        thread::spawn(move || {
+            let mut sent = 0usize;
+            loop {
+                let frames = pos.load(Ordering::Relaxed).min(total);
+                let upto = ((((frames as f64) * (ref_sr as f64)) / (out_sr as f64)).round() as usize).min(reference.len());
+                if upto > sent {
+                    if tx.send(reference[sent..upto].to_vec()).is_err() {
+                        return;
+                    }
+                    sent = upto;
+                }
+                if frames >= total {
+                    done.store(true, Ordering::SeqCst);
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        Ok((ref_sr as f32, rx))
+    }
+
+    fn finished(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+}
+
+/// Output stream writing `samples` (mono) to every channel from frame `pos` on, silence after.
+fn build_player<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    channels: usize,
+    samples: Arc<Vec<f32>>,
+    pos: Arc<AtomicU64>,
+    logger: Arc<Logger>
+) -> Result<cpal::Stream, CaptureError>
+    where T: cpal::SizedSample + cpal::FromSample<f32>
+{
+    let err_fn = move |e| {
+        let _ = logger.error(&format!("output stream error: {}", e));
+    };
+    Ok(
+        device.build_output_stream(
+            config,
+            move |out: &mut [T], _| {
+                let start = pos.load(Ordering::Relaxed) as usize;
+                for (k, frame) in out.chunks_mut(channels).enumerate() {
+                    let s = T::from_sample(samples.get(start + k).copied().unwrap_or(0.0));
+                    frame.fill(s);
+                }
+                pos.fetch_add((out.len() / channels) as u64, Ordering::Relaxed);
+            },
+            err_fn,
+            None
+        )?
+    )
+}
+
+/// The reference runs this far ahead of what the mic hears, so the direct path lands inside
+/// the correlator's 0..MAX_PIPELINE_DELAY_MS search even with a few fingerprint frames of error.
+const REF_LEAD_S: f32 = 0.1;
+/// Give up on finding the mic audio in `--ref-file` after this long.
+const REF_ALIGN_TIMEOUT_S: f32 = 60.0;
+
+/// `--ref-file`: the known content being played, read from the decoded file instead of captured
+/// from the loopback. It starts at `--ref-offset-s` (file position when the program started), or
+/// where a fingerprint of the mic audio places it, and then runs on the wall clock.
+pub struct FileReference {
+    label: String,
+    samples: Vec<f32>,
+    sr: u32,
+    offset: Option<(f32, Instant)>, // file position at an instant
+    fp_win_s: f32,
+    fp_thr: f32,
+    mic: Option<SharedBuf>,
+    done: Arc<AtomicBool>,
+}
+
+impl FileReference {
+    pub fn from_file(cfg: &Config) -> Result<Self> {
+        let t0 = Instant::now();
+        let path = Path::new(&cfg.ref_file);
+        let audio = decode::load_mono(path, cfg.channel_mix)?;
+        Ok(Self {
+            label: format!("Reference file '{}'", path.display()),
+            samples: audio.samples_mono,
+            sr: audio.sr,
+            offset: cfg.ref_offset_s.map(|s| (s, t0)),
+            fp_win_s: cfg.fp_win_s,
+            fp_thr: cfg.fp_thr,
+            mic: None,
+            done: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Wait until a fingerprint of the last few seconds of mic audio matches the file; returns
+    /// the file position heard at the instant the mic audio was taken.
+    fn align(&self, samples: &[f32], sr: u32, logger: &Logger) -> Result<(f32, Instant)> {
+        let mic = self.mic
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("--ref-file needs --ref-offset-s when no mic is captured"))?;
+        let mut fper = prescan::Fingerprinter::new(sr as f32, self.fp_win_s, FpType::ConstellationV2, self.fp_win_s / 2.0);
+        fper.push(samples);
+        let stored = fper.finish();
+        logger.info(
+            &format!(
+                "Listening for {} ({} fingerprint(s)); pass --ref-offset-s to skip",
+                self.label,
+                stored.len()
+            )
+        )?;
+
+        let lead_s = prescan::fingerprint_lead_s(self.fp_win_s);
+        let t_start = Instant::now();
+        let mut best_sim = 0.0f32;
+        while t_start.elapsed().as_secs_f32() < REF_ALIGN_TIMEOUT_S {
+            thread::sleep(Duration::from_secs(1));
+            let mic_sr = mic.sr;
+            let need = (lead_s * mic_sr) as usize;
+            let Some(chunk) = mic.latest(need) else {
+                continue;
+            };
+            let taken = Instant::now();
+            if let Some((sim, pos)) = locate(&stored, &chunk, mic_sr, self.fp_win_s) {
+                best_sim = best_sim.max(sim);
+                if sim >= self.fp_thr {
+                    logger.info(&format!("Reference aligned by fingerprint: file at {:.2}s (similarity {:.2})", pos, sim))?;
+                    return Ok((pos, taken));
+                }
+            }
+        }
+        anyhow::bail!(
+            "The mic did not hear {} within {:.0}s (best similarity {:.2}); is it playing? Pass --ref-offset-s to skip",
+            self.label,
+            REF_ALIGN_TIMEOUT_S,
+            best_sim
+        )
+    }
+}
+
+/// Best match of `chunk` (mic audio) among the file's fingerprints: (similarity, file position
+/// at the end of the chunk).
+fn locate(stored: &[Fingerprint], chunk: &[f32], sr: f32, fp_win_s: f32) -> Option<(f32, f32)> {
+    let live = prescan::make_fingerprint(chunk, sr, fp_win_s, FpType::ConstellationV2)?;
+    let chunk_s = (chunk.len() as f32) / sr;
+    stored
+        .iter()
+        .map(|f| {
+            let (sim, lag_s) = prescan::fp_match(&live, f);
+            (sim, f.offset_s + lag_s - live.offset_s + chunk_s)
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+impl AudioSource for FileReference {
+    fn describe(&self) -> String {
+        self.label.clone()
+    }
+
+    fn hear(&mut self, mic: &SharedBuf) {
+        self.mic = Some(mic.clone());
+    }
+
+    fn start(&mut self, want_sr: Option<u32>, logger: Arc<Logger>) -> Result<(f32, Receiver<Vec<f32>>)> {
+        let sr = want_sr.unwrap_or(self.sr);
+        let samples = decode::resample_linear_mono(&std::mem::take(&mut self.samples), self.sr, sr);
+        let (pos_s, at) = match self.offset {
+            Some(o) => o,
+            None => self.align(&samples, sr, &logger)?,
+        };
+        let start_s = pos_s + at.elapsed().as_secs_f32() + REF_LEAD_S;
+        let start = (start_s.max(0.0) * (sr as f32)) as usize;
+        if start >= samples.len() {
+            anyhow::bail!("{} is only {:.1}s long, but playback is at {:.1}s", self.label, (samples.len() as f32) / (sr as f32), start_s);
+        }
+        logger.info(&format!("{}: reading from {:.2}s ({:.0} ms ahead of the mic)", self.label, start_s, REF_LEAD_S * 1000.0))?;
+
+        // 10 ms blocks on the wall clock, like a device callback
+        let block = ((sr as usize) / 100).max(1);
+        let (tx, rx) = bounded::<Vec<f32>>(8);
+        let done = self.done.clone();
+        thread::spawn(move || {
+            let t0 = Instant::now();
+            for (i, chunk) in samples[start..].chunks(block).enumerate() {
+                let due = t0 + Duration::from_secs_f64(((i * block) as f64) / (sr as f64));
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+                if tx.send(chunk.to_vec()).is_err() {
+                    return;
+                }
+            }
+            done.store(true, Ordering::SeqCst);
+        });
+        Ok((sr as f32, rx))
+    }
+
+    fn finished(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator;
+
+    #[test]
+    fn file_reference_finds_the_heard_position() {
+        let sr = 48_000.0;
+        let file = simulator::music(sr, 40.0, 11);
+        let mut fper = prescan::Fingerprinter::new(sr, 5.0, FpType::ConstellationV2, 2.5);
+        fper.push(&file);
+        let stored = fper.finish();
+
+        // the mic heard 7 s of the file up to 23.4 s, quieter and with a little noise
+        let mut rng = simulator::Lcg(3);
+        let (a, b) = ((16.4 * sr) as usize, (23.4 * sr) as usize);
+        let heard: Vec<f32> = file[a..b].iter().map(|v| 0.3 * v + 0.01 * rng.next()).collect();
+        let (sim, pos) = locate(&stored, &heard, sr, 5.0).unwrap();
+        assert!(sim >= 0.6, "similarity {:.2}", sim);
+        assert!((pos - 23.4).abs() < REF_LEAD_S / 2.0, "found {:.3}s", pos);
+    }
+
+    #[test]
+    fn ring_keeps_the_latest_samples_across_wraps() {
+        let ring = SharedBuf::new(10.0, 1); // 10 samples
+        assert!(ring.is_empty() && ring.latest(1).is_none());
+        ring.push(&[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(ring.latest(3), Some(vec![4.0, 5.0, 6.0]));
+        ring.push(&[7.0, 8.0, 9.0, 10.0, 11.0, 12.0]);
+        assert_eq!((ring.written(), ring.len()), (13, 10));
+        assert_eq!(ring.read_since(0).1, (3..13).map(|v| v as f32).collect::<Vec<_>>());
+        assert_eq!(ring.read(8, 3), Some(vec![8.0, 9.0, 10.0]));
+        assert_eq!(ring.read(2, 3), None); // already overwritten
+        assert_eq!(ring.read(12, 2), None); // not written yet
+        assert_eq!(ring.read_since(11), (11, vec![11.0, 12.0]));
+        assert_eq!(ring.read_since(0).0, 3);
+
+        // per-tick scratch: refilled in place, no new allocation
+        let mut scratch = Vec::with_capacity(4);
+        let at = scratch.as_ptr();
+        assert!(ring.latest_into(4, &mut scratch) && scratch == [9.0, 10.0, 11.0, 12.0]);
+        ring.tail_into(4, &mut scratch);
+        assert_eq!(scratch.as_ptr(), at);
+        assert!(!ring.latest_into(11, &mut scratch) && scratch.is_empty());
+
+        // a block longer than the ring leaves its tail
+        ring.push(&(100..125).map(|v| v as f32).collect::<Vec<_>>());
+        assert_eq!(ring.latest(10), Some((115..125).map(|v| v as f32).collect()));
+
+        // a reader racing the writer only ever sees consecutive samples
+        let writer = ring.clone();
+        let done = std::sync::Arc::new(AtomicBool::new(false));
+        let stop = done.clone();
+        let t = thread::spawn(move || {
+            let mut next = 0u32;
+            while !stop.load(Ordering::Relaxed) {
+                let block: Vec<f32> = (0..3).map(|i| ((next + i) % 4096) as f32).collect();
+                writer.push(&block);
+                next += 3;
+            }
+        });
+        while ring.written() < 200 {
+            thread::yield_now();
+        }
+        for _ in 0..20_000 {
+            let got = ring.latest(8).unwrap();
+            assert!(got.windows(2).all(|w| w[1] == (w[0] + 1.0) % 4096.0), "{:?}", got);
+        }
+        done.store(true, Ordering::Relaxed);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn reference_at_another_rate_is_resampled_to_the_mic_rate() {
+        /// A render device stuck at 44.1 kHz, whatever it is asked for: one second of 1 kHz.
+        struct At44k;
+        impl AudioSource for At44k {
+            fn describe(&self) -> String {
+                "44.1 kHz".to_string()
+            }
+            fn start(&mut self, _want_sr: Option<u32>, _logger: Arc<Logger>) -> Result<(f32, Receiver<Vec<f32>>)> {
+                let tone: Vec<f32> = (0..44_100).map(|i| ((i as f32) * std::f32::consts::TAU * 1000.0 / 44_100.0).sin()).collect();
+                let (tx, rx) = bounded::<Vec<f32>>(200);
+                for block in tone.chunks(441) {
+                    tx.send(block.to_vec()).unwrap();
+                }
+                Ok((44_100.0, rx))
+            }
+        }
+
+        let logger = Arc::new(Logger::new(&std::env::temp_dir().join("sonar-resample.log").to_string_lossy(), false).unwrap());
+        let (ring, _feed) = capture_as(&mut At44k, 48_000, logger).unwrap();
+        assert_eq!(ring.sr, 48_000.0);
+        let t0 = Instant::now();
+        while ring.written() < 47_000 && t0.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // a second of audio at the ring's rate, still a 1 kHz tone there
+        let n = ring.written() as usize;
+        assert!((47_000..=48_000).contains(&n), "{} samples", n);
+        let x = ring.read(0, 24_000).unwrap();
+        let rising = x.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!((499..=501).contains(&rising), "{} cycles in 0.5 s", rising);
+    }
+
+    #[test]
+    fn frames_pair_by_arrival_time_not_by_block_size() {
+        use crate::mods::presence::{ FramePairer, Pairing };
+        let logger = Logger::new(&std::env::temp_dir().join("sonar-pairing.log").to_string_lossy(), false).unwrap();
+        let cfg = crate::Config { max_skew_ms: 500, ..crate::Config::default() };
+        let (mic, reference) = (SharedBuf::new(1000.0, 10), SharedBuf::new(1000.0, 10));
+        let t0 = Instant::now();
+        // sample i was captured at i ms; the mic delivers every 10 ms, the reference every 250 ms
+        let feed = |ring: &SharedBuf, block: usize, until_ms: usize| {
+            for end in (block..=until_ms).step_by(block) {
+                let samples: Vec<f32> = (end - block..end).map(|i| i as f32).collect();
+                ring.push_at(&samples, t0 + Duration::from_millis(end as u64));
+            }
+        };
+        let mut frames = FramePairer::new(100, &cfg);
+        assert_eq!(frames.pair(&mic, &reference, &logger), Pairing::Filling);
+
+        feed(&mic, 10, 1070);
+        feed(&reference, 250, 1000);
+        assert_eq!(frames.pair(&mic, &reference, &logger), Pairing::Ready);
+        let expected: Vec<f32> = (900..1000).map(|i| i as f32).collect();
+        assert_eq!(frames.mic, expected); // not the mic's newest 970..1070
+        assert_eq!(frames.reference, expected);
+
+        // the reference stalls while the mic runs on
+        mic.push_at(&(1070..1600).map(|i| i as f32).collect::<Vec<_>>(), t0 + Duration::from_millis(1600));
+        assert_eq!(frames.pair(&mic, &reference, &logger), Pairing::Skewed);
+        assert!(frames.mic.is_empty() && frames.reference.is_empty());
+    }
+
+    #[test]
+    fn loopback_targets_resolve_by_id_name_or_exe() {
+        let endpoints = [
+            ("{0.0.0.00000000}.{aaa}".to_string(), "Speakers (Realtek(R) Audio)".to_string()),
+            ("{0.0.0.00000000}.{bbb}".to_string(), "Headphones (USB Audio)".to_string()),
+            ("{0.0.0.00000000}.{ccc}".to_string(), "Speakers (USB Audio)".to_string()),
+        ];
+        assert_eq!(pick_endpoint("{0.0.0.00000000}.{ccc}", &endpoints), Ok(2));
+        assert_eq!(pick_endpoint("headphones", &endpoints), Ok(1));
+        assert_eq!(pick_endpoint("speakers (usb audio)", &endpoints), Ok(2)); // exact name beats the partial matches
+        assert!(pick_endpoint("speakers", &endpoints).unwrap_err().contains("several"));
+        assert!(pick_endpoint("hdmi", &endpoints).unwrap_err().contains("'Headphones (USB Audio)'"));
+
+        // a browser: the main process and two children sharing its name
+        let procs = [
+            (4, 0, "System".to_string()),
+            (700, 600, "spotify.exe".to_string()),
+            (910, 800, "firefox.exe".to_string()),
+            (920, 910, "firefox.exe".to_string()),
+            (930, 910, "firefox.exe".to_string()),
+        ];
+        assert_eq!(pick_process("Firefox", &procs), Some(910));
+        assert_eq!(pick_process("spotify.exe", &procs), Some(700));
+        assert_eq!(pick_process("920", &procs), Some(920));
+        assert_eq!(pick_process("1234", &procs), None);
+        assert_eq!(pick_process("vlc", &procs), None);
+    }
+
+    #[test]
+    fn auto_probe_follows_media_but_not_itself() {
+        let cfg = Config { probe_tone: ProbeMode::Auto, ..Config::default() };
+        let probe = ProbeTone::from_config(&cfg);
+        let sr = 48_000.0;
+        let tone = |hz: f32, amp: f32| -> Vec<f32> {
+            (0..4800).map(|i| amp * (2.0 * std::f32::consts::PI * hz * (i as f32) / sr).sin()).collect()
+        };
+        // the probe's own tone in the loopback is not media; quiet music is
+        assert!(probe.media_rms(&tone(probe.freq_hz, probe.amp), sr) < probe.quiet_rms);
+        assert!(probe.media_rms(&tone(440.0, 0.01), sr) > probe.quiet_rms);
+
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let mut gate = ProbeGate::default();
+        assert_eq!(gate.update(0.05, probe.quiet_rms, at(0)), None);
+        assert_eq!(gate.update(0.0, probe.quiet_rms, at(100)), None);
+        assert_eq!(gate.update(0.0, probe.quiet_rms, at(900)), None);
+        assert_eq!(gate.update(0.0, probe.quiet_rms, at(1100)), Some(true));
+        assert_eq!(gate.update(0.0, probe.quiet_rms, at(1200)), None);
+        assert_eq!(gate.update(0.05, probe.quiet_rms, at(1300)), Some(false));
+        assert!(!gate.on);
+    }
+}
@@ -0,0 +1,72 @@
+//! src/auth.rs
+//! Who may use the network endpoints. `--api-token` is asked of HTTP clients as
+//! `Authorization: Bearer <token>` (the metrics endpoint); sensor nodes present `--pair-token`
+//! in their hello. Endpoints bound beyond loopback without one are reported at startup. No
+//! endpoint speaks TLS (the build has no TLS stack), so a token set on one of those is sent in
+//! clear text; that is reported too, and the README points to a TLS-terminating proxy.
+
+use std::net::{ SocketAddr, ToSocketAddrs };
+
+use crate::logger::Logger;
+
+/// `given == expected`, in time that does not depend on where they differ.
+pub fn same_secret(given: &str, expected: &str) -> bool {
+    let (g, e) = (given.as_bytes(), expected.as_bytes());
+    let mut diff = (g.len() != e.len()) as u8;
+    for (i, &b) in e.iter().enumerate() {
+        diff |= b ^ g.get(i).copied().unwrap_or(0);
+    }
+    diff == 0
+}
+
+/// The token of an `Authorization: Bearer <token>` header among `headers`.
+pub fn bearer(headers: &[String]) -> Option<&str> {
+    headers.iter().find_map(|h| {
+        let (name, value) = h.split_once(':')?;
+        if !name.trim().eq_ignore_ascii_case("authorization") {
+            return None;
+        }
+        let (scheme, token) = value.trim().split_once(' ')?;
+        scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+    })
+}
+
+/// Whether an HTTP request with `headers` may be served under `token` (empty: anyone).
+pub fn authorized(headers: &[String], token: &str) -> bool {
+    token.is_empty() || bearer(headers).is_some_and(|t| same_secret(t, token))
+}
+
+/// When `addr` is reachable from other machines: warn if `what` asks nothing of clients (`token`
+/// empty), else note that the token crosses the network unencrypted.
+pub fn warn_if_open(addr: &str, what: &str, flag: &str, token: &str, logger: &Logger) {
+    let open = addr
+        .to_socket_addrs()
+        .map(|mut a| a.any(|a: SocketAddr| !a.ip().is_loopback()))
+        .unwrap_or(false);
+    if !open {
+        return;
+    }
+    let _ = if token.is_empty() {
+        logger.warn(&format!("{} on {} is open to the network without {}", what, addr, flag))
+    } else {
+        logger.info(
+            &format!("{} on {} is plain TCP: {} is sent unencrypted; use a TLS proxy or a VPN across untrusted networks", what, addr, flag)
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_right_bearer_token_is_let_in() {
+        let headers = |auth: &str| vec!["Host: desk:9100".to_string(), auth.to_string()];
+        assert!(authorized(&headers("authorization: Bearer s3cret"), "s3cret"));
+        assert!(!authorized(&headers("Authorization: Bearer s3cre"), "s3cret"));
+        assert!(!authorized(&headers("Authorization: Basic czNjcmV0"), "s3cret"));
+        assert!(!authorized(&headers("X-Token: s3cret"), "s3cret"));
+        assert!(authorized(&headers("X-Token: s3cret"), ""));
+        assert!(same_secret("", "") && !same_secret("s3cret\0", "s3cret") && !same_secret("", "s3cret"));
+    }
+}
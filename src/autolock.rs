@@ -0,0 +1,101 @@
+//! src/autolock.rs
+//! `--auto-lock`: lock the Windows workstation after a sustained absence and
+//! wake the display again when presence returns.
+
+use std::{ sync::Arc, time::{ Duration, Instant } };
+
+use crate::logger::Logger;
+use crate::Config;
+
+pub struct AutoLock {
+    enabled: bool,
+    lock_after: Duration,
+    absent_since: Option<Instant>,
+    locked: bool,
+    logger: Arc<Logger>,
+}
+
+impl AutoLock {
+    pub fn new(cfg: &Config, logger: Arc<Logger>) -> Self {
+        if cfg.auto_lock {
+            let _ = logger.info(
+                &format!("auto-lock enabled: locking after {:.0}s of absence", cfg.lock_after_s)
+            );
+            if !cfg!(target_os = "windows") {
+                let _ = logger.warn("auto-lock is only supported on Windows; ignoring");
+            }
+        }
+        Self {
+            enabled: cfg.auto_lock && cfg!(target_os = "windows"),
+            lock_after: Duration::from_secs_f32(cfg.lock_after_s.max(0.0)),
+            absent_since: None,
+            locked: false,
+            logger,
+        }
+    }
+
+    /// Feed the smoothed presence state once per tick.
+    pub fn update(&mut self, present: bool) {
+        if !self.enabled {
+            return;
+        }
+        if present {
+            self.absent_since = None;
+            if self.locked {
+                self.locked = false;
+                let _ = self.logger.info("auto-lock: presence returned, waking display");
+                platform::wake_display();
+            }
+            return;
+        }
+
+        let since = *self.absent_since.get_or_insert_with(Instant::now);
+        if !self.locked && since.elapsed() >= self.lock_after {
+            self.locked = true;
+            match platform::lock_workstation() {
+                Ok(()) => {
+                    let _ = self.logger.info(
+                        &format!(
+                            "auto-lock: absent for {:.0}s, workstation locked",
+                            since.elapsed().as_secs_f32()
+                        )
+                    );
+                }
+                Err(e) => {
+                    let _ = self.logger.error(&format!("auto-lock: LockWorkStation failed: {}", e));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use windows::Win32::System::Power::{
+        SetThreadExecutionState,
+        ES_DISPLAY_REQUIRED,
+        ES_SYSTEM_REQUIRED,
+    };
+    use windows::Win32::System::Shutdown::LockWorkStation;
+
+    pub fn lock_workstation() -> anyhow::Result<()> {
+        unsafe { LockWorkStation()? }
+        Ok(())
+    }
+
+    /// Resetting the display idle timer turns a blanked display back on.
+    pub fn wake_display() {
+        unsafe {
+            SetThreadExecutionState(ES_DISPLAY_REQUIRED | ES_SYSTEM_REQUIRED);
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+mod platform {
+    pub fn lock_workstation() -> anyhow::Result<()> {
+        anyhow::bail!("workstation locking is only available on Windows")
+    }
+
+    pub fn wake_display() {}
+}
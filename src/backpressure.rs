@@ -0,0 +1,162 @@
+//! src/backpressure.rs
+//! `--backpressure`: what a live capture stream does when the queue to its ring buffer is
+//! full (8 blocks), and how often it happened per source. The counts go to the heartbeat
+//! and the metrics exporter. Recorded and file sources always wait: they are paced by the
+//! reader, and a gap in them would only misalign the replay.
+
+use std::sync::{ atomic::{ AtomicU64, AtomicU8, Ordering }, Arc, Mutex };
+
+use crossbeam_channel::{ Receiver, Sender, TrySendError };
+
+use crate::supervise::LockExt;
+
+/// What to do with a captured block that finds its queue full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    DropOldest, // the ring filler skips the oldest queued block to make room
+    Block, // the capture thread waits (a late block; the driver may overrun meanwhile)
+    DropNewest, // the new block is discarded
+}
+
+impl Backpressure {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "drop-oldest" => Ok(Backpressure::DropOldest),
+            "block" => Ok(Backpressure::Block),
+            "drop-newest" => Ok(Backpressure::DropNewest),
+            _ => Err(format!("Invalid backpressure value: {}. Valid options: drop-oldest, block, drop-newest", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Backpressure::DropOldest => "drop-oldest",
+            Backpressure::Block => "block",
+            Backpressure::DropNewest => "drop-newest",
+        }
+    }
+}
+
+/// Blocks of one source that were dropped, or delivered only after the capture thread waited.
+#[derive(Default)]
+pub struct QueueStats {
+    pub dropped: AtomicU64,
+    pub late: AtomicU64,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(Backpressure::Block as u8);
+static QUEUES: Mutex<Vec<(&'static str, Arc<QueueStats>)>> = Mutex::new(Vec::new());
+
+/// The policy every live capture stream of this process follows.
+pub fn set_policy(policy: Backpressure) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+pub fn policy() -> Backpressure {
+    match POLICY.load(Ordering::Relaxed) {
+        p if p == (Backpressure::DropOldest as u8) => Backpressure::DropOldest,
+        p if p == (Backpressure::DropNewest as u8) => Backpressure::DropNewest,
+        _ => Backpressure::Block,
+    }
+}
+
+/// The counters of `source` (`mic`, `ref`).
+pub fn stats(source: &'static str) -> Arc<QueueStats> {
+    let mut all = QUEUES.locked();
+    if let Some((_, s)) = all.iter().find(|(name, _)| *name == source) {
+        return s.clone();
+    }
+    let s = Arc::new(QueueStats::default());
+    all.push((source, s.clone()));
+    s
+}
+
+/// (dropped, late) of `source` so far.
+pub fn counts(source: &'static str) -> (u64, u64) {
+    let s = stats(source);
+    (s.dropped.load(Ordering::Relaxed), s.late.load(Ordering::Relaxed))
+}
+
+/// Queue a captured block of `source` under the process policy; false once the reader is gone.
+pub fn send(tx: &Sender<Vec<f32>>, block: Vec<f32>, source: &'static str) -> bool {
+    send_with(tx, block, policy(), &stats(source))
+}
+
+fn send_with(tx: &Sender<Vec<f32>>, block: Vec<f32>, policy: Backpressure, stats: &QueueStats) -> bool {
+    match tx.try_send(block) {
+        Ok(()) => true,
+        Err(TrySendError::Disconnected(_)) => false,
+        Err(TrySendError::Full(block)) if policy == Backpressure::Block => {
+            stats.late.fetch_add(1, Ordering::Relaxed);
+            tx.send(block).is_ok()
+        }
+        // drop-oldest gets here only while the ring filler is stuck: nothing old can go either
+        Err(TrySendError::Full(_)) => {
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+    }
+}
+
+/// For the ring filler, after taking a block off `rx`: true when that block should be skipped
+/// because drop-oldest is on and the queue was full when it was taken.
+pub fn skip_oldest(rx: &Receiver<Vec<f32>>, source: &'static str) -> bool {
+    skip_oldest_with(rx, policy(), &stats(source))
+}
+
+fn skip_oldest_with(rx: &Receiver<Vec<f32>>, policy: Backpressure, stats: &QueueStats) -> bool {
+    let skip = policy == Backpressure::DropOldest && rx.capacity().is_some_and(|cap| rx.len() + 1 >= cap);
+    if skip {
+        stats.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+    skip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::bounded;
+
+    #[test]
+    fn full_queues_are_counted_per_policy() {
+        let (tx, rx) = bounded::<Vec<f32>>(2);
+        let stats = QueueStats::default();
+        for i in 0..3 {
+            assert!(send_with(&tx, vec![i as f32], Backpressure::DropNewest, &stats));
+        }
+        assert_eq!(stats.dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(rx.try_iter().flatten().collect::<Vec<f32>>(), [0.0, 1.0]);
+
+        // drop-oldest: the ring filler skips what it takes off a full queue, the stream never finds it full
+        let stats = QueueStats::default();
+        let mut kept = Vec::new();
+        assert!(send_with(&tx, vec![0.0], Backpressure::DropOldest, &stats));
+        for i in 1..4 {
+            assert!(send_with(&tx, vec![i as f32], Backpressure::DropOldest, &stats));
+            let block = rx.recv().unwrap();
+            if !skip_oldest_with(&rx, Backpressure::DropOldest, &stats) {
+                kept.extend(block);
+            }
+        }
+        kept.extend(rx.try_iter().flatten().filter(|_| !skip_oldest_with(&rx, Backpressure::DropOldest, &stats)));
+        assert_eq!((kept, stats.dropped.load(Ordering::Relaxed)), (vec![3.0], 3));
+        assert!(!skip_oldest_with(&rx, Backpressure::Block, &stats));
+
+        // block: the second send waits for the reader and is counted late
+        let (tx, rx) = bounded::<Vec<f32>>(1);
+        let stats = Arc::new(QueueStats::default());
+        let (t, s) = (tx.clone(), stats.clone());
+        assert!(send_with(&tx, vec![0.0], Backpressure::Block, &stats));
+        let waiting = std::thread::spawn(move || send_with(&t, vec![1.0], Backpressure::Block, &s));
+        while !waiting.is_finished() && stats.late.load(Ordering::Relaxed) == 0 {
+            std::thread::yield_now();
+        }
+        assert_eq!(rx.recv().unwrap(), vec![0.0]);
+        assert!(waiting.join().unwrap());
+        assert_eq!((rx.recv().unwrap(), stats.late.load(Ordering::Relaxed)), (vec![1.0], 1));
+        drop(rx);
+        assert!(!send_with(&tx, vec![2.0], Backpressure::DropNewest, &stats));
+        assert_eq!(Backpressure::parse("Drop-Oldest"), Ok(Backpressure::DropOldest));
+        assert!(Backpressure::parse("fifo").is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! src/calibration.rs
+//! `--calibration`: the window agreement as a probability that someone is there. Agreement is
+//! the share of the window's ticks that voted, which says little on its own: a fan or a
+//! reverberant room may vote a third of the time while empty. Replaying a recorded session
+//! with `--labels` (when someone really was there) counts, per agreement bin, how many of the
+//! windows falling in it were occupied; isotonic regression (pool adjacent violators) turns
+//! the counts into a curve that never falls as agreement rises. The counts are kept in the
+//! file, so each further labelled session adds to the fit.
+
+use anyhow::{ Context, Result };
+use std::{ fs, path::Path };
+
+use crate::logger::Logger;
+use crate::output::{ self, JsonObj };
+use crate::{ csvio, json, Config };
+
+/// Agreement bins, 5 % wide.
+pub const BINS: usize = 20;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Calibration {
+    counts: Vec<(u64, u64)>, // per bin: (windows labelled present, windows)
+    curve: Vec<f32>, // fitted probability per bin
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Calibration {
+    pub fn new() -> Self {
+        Self { counts: vec![(0, 0); BINS], curve: vec![0.5; BINS] }
+    }
+
+    /// The curve `--calibration` names, for reporting probabilities. None without the flag;
+    /// an error when the file is missing (a labelled replay writes it) or unreadable.
+    pub fn from_config(cfg: &Config, logger: &Logger) -> Result<Option<Self>> {
+        if cfg.calibration.is_empty() || !cfg.labels.is_empty() {
+            // a labelled replay is learning the curve, not applying it
+            return Ok(None);
+        }
+        let cal = Self::load(Path::new(&cfg.calibration))?;
+        logger.info(&format!("Calibration: {} ({} labelled windows)", cfg.calibration, cal.windows()))?;
+        Ok(Some(cal))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading calibration {}", path.display()))?;
+        Self::from_json(&text).with_context(|| format!("parsing calibration {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        output::write_atomic(path, &self.to_json()).with_context(|| format!("writing calibration {}", path.display()))
+    }
+
+    fn bin(agree: f32) -> usize {
+        ((agree.clamp(0.0, 1.0) * (BINS as f32)) as usize).min(BINS - 1)
+    }
+
+    /// One labelled window.
+    pub fn add(&mut self, agree: f32, present: bool) {
+        let c = &mut self.counts[Self::bin(agree)];
+        c.0 += present as u64;
+        c.1 += 1;
+    }
+
+    /// Take in another session's counts (the curve needs a `fit` after).
+    pub fn merge(&mut self, other: &Calibration) {
+        for (c, o) in self.counts.iter_mut().zip(&other.counts) {
+            c.0 += o.0;
+            c.1 += o.1;
+        }
+    }
+
+    pub fn windows(&self) -> u64 {
+        self.counts.iter().map(|c| c.1).sum()
+    }
+
+    /// Refit the curve to the counts: pool adjacent bins whose rates fall, then give empty
+    /// bins the value of their nearest non-empty neighbours.
+    pub fn fit(&mut self) {
+        // blocks of (rate, weight, bins pooled)
+        let mut blocks: Vec<(f64, f64, Vec<usize>)> = Vec::new();
+        for (i, &(p, n)) in self.counts.iter().enumerate() {
+            if n == 0 {
+                continue;
+            }
+            blocks.push(((p as f64) / (n as f64), n as f64, vec![i]));
+            while blocks.len() >= 2 && blocks[blocks.len() - 2].0 > blocks[blocks.len() - 1].0 {
+                let (r2, w2, b2) = blocks.pop().unwrap();
+                let (r1, w1, b1) = blocks.last_mut().unwrap();
+                *r1 = (*r1 * *w1 + r2 * w2) / (*w1 + w2);
+                *w1 += w2;
+                b1.extend(b2);
+            }
+        }
+        let mut fitted: Vec<Option<f32>> = vec![None; BINS];
+        for (rate, _, bins) in &blocks {
+            for &i in bins {
+                fitted[i] = Some(*rate as f32);
+            }
+        }
+        for i in 0..BINS {
+            let before = fitted[..i].iter().rev().find_map(|v| *v);
+            let after = fitted[i..].iter().find_map(|v| *v);
+            self.curve[i] = match (fitted[i], before, after) {
+                (Some(v), _, _) => v,
+                (None, Some(a), Some(b)) => (a + b) / 2.0,
+                (None, a, b) => a.or(b).unwrap_or(0.5),
+            };
+        }
+    }
+
+    /// Probability of presence for a window's agreement, interpolated between bin centres.
+    pub fn probability(&self, agree: f32) -> f32 {
+        let x = agree.clamp(0.0, 1.0) * (BINS as f32) - 0.5;
+        let i = (x.floor().max(0.0) as usize).min(BINS - 1);
+        let j = (i + 1).min(BINS - 1);
+        let t = (x - (i as f32)).clamp(0.0, 1.0);
+        self.curve[i] + (self.curve[j] - self.curve[i]) * t
+    }
+
+    /// Mean squared error of the curve over the counted windows (0 = perfect, 0.25 = a coin).
+    pub fn brier(&self) -> f64 {
+        let (mut err, mut n) = (0.0, 0u64);
+        for (&(p, total), &q) in self.counts.iter().zip(&self.curve) {
+            let q = q as f64;
+            err += (p as f64) * (1.0 - q).powi(2) + ((total - p) as f64) * q.powi(2);
+            n += total;
+        }
+        if n > 0 { err / (n as f64) } else { 0.0 }
+    }
+
+    fn to_json(&self) -> String {
+        let bins: Vec<String> = self.counts
+            .iter()
+            .zip(&self.curve)
+            .enumerate()
+            .map(|(i, (&(p, n), &q))| {
+                JsonObj::new()
+                    .num("agree_from", (i as f64) / (BINS as f64))
+                    .int("present", p as i64)
+                    .int("windows", n as i64)
+                    .num("probability", q as f64)
+                    .finish()
+            })
+            .collect();
+        JsonObj::new().str("score", "agreement").str("method", "isotonic").arr("bins", &bins).finish()
+    }
+
+    fn from_json(text: &str) -> Result<Self> {
+        let doc = json::parse(text)?;
+        let bins = doc
+            .get("bins")
+            .and_then(|b| b.as_array())
+            .filter(|b| b.len() == BINS)
+            .with_context(|| format!("expected {} bins", BINS))?;
+        let mut cal = Self::new();
+        for (i, b) in bins.iter().enumerate() {
+            let num = |k: &str| b.get(k).and_then(|v| v.as_f64()).with_context(|| format!("bin {}: no {}", i, k));
+            let (p, n) = (num("present")? as u64, num("windows")? as u64);
+            cal.counts[i] = (p.min(n), n);
+            cal.curve[i] = (num("probability")? as f32).clamp(0.0, 1.0);
+        }
+        Ok(cal)
+    }
+}
+
+/// `--labels`: `start_s,end_s[,state]` rows, the stretches of the replayed session (seconds
+/// from its start) when someone was there. A row marked `absent` (or `false`/`0`) only
+/// documents an empty stretch: time outside every present row counts as absent anyway.
+/// Rows that do not parse (a header) are skipped.
+pub fn load_labels(path: &Path) -> Result<Vec<(f64, f64)>> {
+    let text = fs::read_to_string(path).with_context(|| format!("reading labels {}", path.display()))?;
+    let rows: Vec<(f64, f64, bool)> = csvio
+        ::parse(&text)
+        .iter()
+        .filter_map(|rec| {
+            let from = rec.first()?.trim().parse::<f64>().ok()?;
+            let to = rec.get(1)?.trim().parse::<f64>().ok()?;
+            let absent = rec.get(2).is_some_and(|s| matches!(s.trim().to_lowercase().as_str(), "absent" | "false" | "0"));
+            (to > from).then_some((from, to, !absent))
+        })
+        .collect();
+    if rows.is_empty() {
+        anyhow::bail!("{}: no start_s,end_s rows", path.display());
+    }
+    Ok(rows.into_iter().filter(|r| r.2).map(|(from, to, _)| (from, to)).collect())
+}
+
+/// Someone was there `t_s` into the session.
+pub fn labelled_present(labels: &[(f64, f64)], t_s: f64) -> bool {
+    labels.iter().any(|&(from, to)| (from..to).contains(&t_s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isotonic_fit_rises_with_agreement_and_round_trips() {
+        let mut cal = Calibration::new();
+        // an empty room still votes up to ~30 %; occupied windows agree more, with one dip at 70 %
+        for _ in 0..40 {
+            cal.add(0.1, false);
+            cal.add(0.3, false);
+        }
+        for i in 0..10 {
+            cal.add(0.3, i < 2);
+            cal.add(0.72, i < 6);
+            cal.add(0.62, i < 8);
+            cal.add(0.95, true);
+        }
+        cal.fit();
+        let p = |a: f32| cal.probability(a);
+        assert!(p(0.1) < 0.01);
+        assert!((p(0.325) - 0.04).abs() < 1e-3, "{}", p(0.325)); // 2 of 50
+        // 62 % and 72 % were out of order: pooled to 14 of 20
+        assert!((p(0.625) - 0.7).abs() < 1e-3 && (p(0.725) - 0.7).abs() < 1e-3);
+        assert!((p(0.975) - 1.0).abs() < 1e-6);
+        let mut last = 0.0;
+        for k in 0..=100 {
+            let v = p((k as f32) / 100.0);
+            assert!(v + 1e-6 >= last, "falls at {}%", k);
+            last = v;
+        }
+        assert!(cal.brier() < 0.1);
+        assert_eq!(Calibration::from_json(&cal.to_json()).unwrap().windows(), cal.windows());
+
+        let labels = vec![(10.0, 20.0), (30.5, 31.0)];
+        assert!(labelled_present(&labels, 10.0) && labelled_present(&labels, 30.7));
+        assert!(!labelled_present(&labels, 20.0) && !labelled_present(&labels, 5.0));
+    }
+}
@@ -0,0 +1,114 @@
+//! src/console.rs
+//! Terminal output for the live modes: log entries at the `--quiet`/`--verbose` level on
+//! stderr, and one status line that is redrawn in place each window instead of scrolling.
+
+use std::{
+    io::{ self, IsTerminal, Write },
+    sync::atomic::{ AtomicBool, Ordering },
+};
+
+use crate::logger::LogLevel;
+use crate::Config;
+
+/// How much reaches the console (`--quiet`, `--verbose`); Detection.log follows `--log-level`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Verbosity {
+    Quiet, // errors only, no status line
+    #[default]
+    Normal, // warnings and errors, status line
+    Verbose, // every entry down to debug, status line
+}
+
+impl Verbosity {
+    /// Lowest log level echoed to the console.
+    pub fn console_level(&self) -> LogLevel {
+        match self {
+            Self::Quiet => LogLevel::Error,
+            Self::Normal => LogLevel::Warning,
+            Self::Verbose => LogLevel::Debug,
+        }
+    }
+}
+
+static STATUS_SHOWN: AtomicBool = AtomicBool::new(false); // status line on screen, not yet ended by a newline
+
+fn use_color() -> bool {
+    io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+fn paint(text: &str, sgr: &str, color: bool) -> String {
+    if color { format!("\x1b[{}m{}\x1b[0m", sgr, text) } else { text.to_string() }
+}
+
+/// One log entry on stderr; a status line on screen is cleared first and comes back on the
+/// next update.
+pub fn print_entry(level: LogLevel, message: &str) {
+    let color = use_color();
+    let tag = match level {
+        LogLevel::Debug => paint("DEBUG", "2", color),
+        LogLevel::Info => "INFO".to_string(),
+        LogLevel::Warning => paint("WARN", "33", color),
+        LogLevel::Error => paint("ERROR", "31", color),
+    };
+    let mut err = io::stderr().lock();
+    if STATUS_SHOWN.swap(false, Ordering::Relaxed) {
+        let _ = write!(err, "\r\x1b[2K");
+    }
+    let _ = writeln!(err, "[{}] {}", tag, message);
+}
+
+/// The live state on one terminal line: presence, distance and confidence.
+pub struct LiveStatus {
+    on: bool,
+}
+
+impl LiveStatus {
+    /// Only when stderr is a terminal, and not with `--quiet`.
+    pub fn new(cfg: &Config) -> Self {
+        Self { on: cfg.verbosity != Verbosity::Quiet && io::stderr().is_terminal() }
+    }
+
+    /// Redraw the line; `confidence` is the window's presence-vote fraction, or with --calibration the probability.
+    pub fn update(&mut self, present: bool, distance_m: f64, confidence: f32) {
+        if !self.on {
+            return;
+        }
+        let line = status_line(present, distance_m, confidence, use_color());
+        let mut err = io::stderr().lock();
+        let _ = write!(err, "\r\x1b[2K{}", line);
+        let _ = err.flush();
+        STATUS_SHOWN.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for LiveStatus {
+    fn drop(&mut self) {
+        if self.on && STATUS_SHOWN.swap(false, Ordering::Relaxed) {
+            eprintln!();
+        }
+    }
+}
+
+/// `● PRESENT  0.82 m  confidence 83%` or `○ absent  confidence 10%`.
+fn status_line(present: bool, distance_m: f64, confidence: f32, color: bool) -> String {
+    let confidence = format!("confidence {:.0}%", confidence * 100.0);
+    if present {
+        let distance = if distance_m.is_finite() { format!("  {:.2} m", distance_m) } else { String::new() };
+        format!("{}{}  {}", paint("● PRESENT", "1;32", color), distance, confidence)
+    } else {
+        format!("{}  {}", paint("○ absent", "2", color), confidence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_line_reads_plainly_without_color() {
+        assert_eq!(status_line(true, 0.824, 0.83, false), "● PRESENT  0.82 m  confidence 83%");
+        assert_eq!(status_line(false, f64::INFINITY, 0.1, false), "○ absent  confidence 10%");
+        assert!(status_line(true, 1.0, 1.0, true).starts_with("\x1b[1;32m● PRESENT\x1b[0m"));
+        assert_eq!(Verbosity::Quiet.console_level(), LogLevel::Error);
+    }
+}
@@ -0,0 +1,413 @@
+//! src/control.rs
+//! Local control interface (`--control <PATH>`): a Unix socket, or a named pipe on Windows.
+//! Line protocol, one reply line per command:
+//! `status`, `pause`, `resume`, `set <key> <value>`, `get <key>`, `recalibrate`, `shutdown`, `help`.
+
+use std::{
+    io::{ self, BufRead, BufReader, Read, Write },
+    sync::{ atomic::{ AtomicBool, Ordering }, Arc, Mutex },
+    thread,
+};
+
+use crate::logger::Logger;
+use crate::output::JsonObj;
+use crate::strategy::DecisionPolicy;
+use crate::supervise::{ self, LockExt };
+use crate::Config;
+
+/// Detector settings that can be changed while running.
+#[derive(Clone, Copy, Debug)]
+struct Tunables {
+    strength_thr: f32,
+    dist_max_m: f32,
+    enter_frac: f32,
+    exit_frac: f32,
+    min_dwell_ms: u64,
+}
+
+const TUNABLE_KEYS: &str = "strength_thr, dist_max_m, enter_frac, exit_frac, min_dwell_ms";
+
+impl Tunables {
+    fn get(&self, key: &str) -> Option<String> {
+        Some(match key {
+            "strength_thr" => format!("{}", self.strength_thr),
+            "dist_max_m" => format!("{}", self.dist_max_m),
+            "enter_frac" => format!("{}", self.enter_frac),
+            "exit_frac" => format!("{}", self.exit_frac),
+            "min_dwell_ms" => format!("{}", self.min_dwell_ms),
+            _ => {
+                return None;
+            }
+        })
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let frac = |v: &str| -> Result<f32, String> {
+            match v.parse::<f32>() {
+                Ok(x) if (0.0..=1.0).contains(&x) => Ok(x),
+                _ => Err(format!("{} must be a number in 0..1", key)),
+            }
+        };
+        let non_neg = |v: &str| -> Result<f32, String> {
+            match v.parse::<f32>() {
+                Ok(x) if x >= 0.0 => Ok(x),
+                _ => Err(format!("{} must be a non-negative number", key)),
+            }
+        };
+        match key {
+            "strength_thr" => {
+                self.strength_thr = non_neg(value)?;
+            }
+            "dist_max_m" => {
+                self.dist_max_m = non_neg(value)?;
+            }
+            "enter_frac" => {
+                self.enter_frac = frac(value)?;
+            }
+            "exit_frac" => {
+                self.exit_frac = frac(value)?;
+            }
+            "min_dwell_ms" => {
+                self.min_dwell_ms = value
+                    .parse::<u64>()
+                    .map_err(|_| "min_dwell_ms must be an integer".to_string())?;
+            }
+            _ => {
+                return Err(format!("unknown key '{}' (one of: {})", key, TUNABLE_KEYS));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// State shared between the control server and the analysis loop of the running mode.
+pub struct Control {
+    mode: &'static str,
+    paused: AtomicBool,
+    recalibrate: AtomicBool,
+    shutdown: AtomicBool,
+    tunables: Mutex<Tunables>,
+    // latest detector snapshot (JSON object) published by the mode
+    detector: Mutex<String>,
+    logger: Arc<Logger>,
+}
+
+impl Control {
+    /// Always returns a handle; the server only runs when `--control` is set.
+    pub fn start(cfg: &Config, mode: &'static str, logger: Arc<Logger>) -> anyhow::Result<Arc<Self>> {
+        let control = Arc::new(Self {
+            mode,
+            paused: AtomicBool::new(false),
+            recalibrate: AtomicBool::new(false),
+            shutdown: AtomicBool::new(false),
+            tunables: Mutex::new(Tunables {
+                strength_thr: cfg.strength_thr,
+                dist_max_m: cfg.dist_max_m,
+                enter_frac: cfg.enter_frac,
+                exit_frac: cfg.exit_frac,
+                min_dwell_ms: cfg.min_dwell_ms,
+            }),
+            detector: Mutex::new(String::from("null")),
+            logger: logger.clone(),
+        });
+        if !cfg.control_path.is_empty() {
+            let path = platform::endpoint(&cfg.control_path);
+            platform::listen(&path, control.clone())?;
+            let _ = logger.info(&format!("control interface listening on {}", path));
+        }
+        Ok(control)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// True once per `recalibrate` command.
+    pub fn take_recalibrate(&self) -> bool {
+        self.recalibrate.swap(false, Ordering::SeqCst)
+    }
+
+    /// Set by `shutdown` (another instance taking over, `--takeover`): end the run as on Ctrl+C.
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Copy the current runtime settings onto the loop's config and decision policy.
+    pub fn apply(&self, live: &mut Config, policy: &mut dyn DecisionPolicy) {
+        let t = *self.tunables.locked();
+        live.strength_thr = t.strength_thr;
+        live.dist_max_m = t.dist_max_m;
+        live.enter_frac = t.enter_frac;
+        live.exit_frac = t.exit_frac;
+        live.min_dwell_ms = t.min_dwell_ms;
+        policy.tune(live);
+    }
+
+    /// Publish the mode's latest state for `status` (a JSON object).
+    pub fn set_status(&self, json: String) {
+        *self.detector.locked() = json;
+    }
+
+    fn status(&self) -> String {
+        let t = *self.tunables.locked();
+        let mut doc = JsonObj::new()
+            .str("mode", self.mode)
+            .bool("paused", self.is_paused())
+            .num("strength_thr", t.strength_thr as f64)
+            .num("dist_max_m", t.dist_max_m as f64)
+            .num("enter_frac", t.enter_frac as f64)
+            .num("exit_frac", t.exit_frac as f64)
+            .int("min_dwell_ms", t.min_dwell_ms as i64)
+            .finish();
+        // splice the detector snapshot in as a nested object
+        doc.pop();
+        doc.push_str(",\"detector\":");
+        doc.push_str(&self.detector.locked());
+        doc.push('}');
+        doc
+    }
+
+    /// Execute one command line and return the reply (None for blank lines).
+    fn handle(&self, line: &str) -> Option<String> {
+        let mut parts = line.split_whitespace();
+        let cmd = parts.next()?.to_ascii_lowercase();
+        let args: Vec<&str> = parts.collect();
+
+        let reply = match (cmd.as_str(), args.as_slice()) {
+            ("status", []) => self.status(),
+            ("pause", []) => {
+                self.paused.store(true, Ordering::SeqCst);
+                "ok paused".to_string()
+            }
+            ("resume", []) => {
+                self.paused.store(false, Ordering::SeqCst);
+                "ok resumed".to_string()
+            }
+            ("recalibrate", []) => {
+                self.recalibrate.store(true, Ordering::SeqCst);
+                "ok recalibrating".to_string()
+            }
+            ("shutdown", []) => {
+                self.shutdown.store(true, Ordering::SeqCst);
+                "ok shutting down".to_string()
+            }
+            ("get", [key]) => {
+                match self.tunables.locked().get(key) {
+                    Some(v) => format!("{}={}", key, v),
+                    None => format!("error: unknown key '{}' (one of: {})", key, TUNABLE_KEYS),
+                }
+            }
+            ("set", [key, value]) => {
+                let mut t = self.tunables.locked();
+                match t.set(key, value) {
+                    Ok(()) => format!("ok {}={}", key, t.get(key).unwrap_or_default()),
+                    Err(e) => format!("error: {}", e),
+                }
+            }
+            ("help", []) =>
+                format!(
+                    "commands: status | pause | resume | recalibrate | shutdown | get <key> | set <key> <value>  (keys: {})",
+                    TUNABLE_KEYS
+                ),
+            _ => format!("error: unrecognised command '{}' (try help)", line.trim()),
+        };
+
+        if cmd != "status" && cmd != "get" && cmd != "help" && !reply.starts_with("error") {
+            let _ = self.logger.info(&format!("control: {} -> {}", line.trim(), reply));
+        }
+        Some(reply)
+    }
+}
+
+/// Send one command to the control interface of another running instance and return its reply.
+pub fn request(path: &str, command: &str) -> io::Result<String> {
+    let stream = platform::connect(&platform::endpoint(path))?;
+    let mut writer = &stream;
+    writeln!(writer, "{}", command)?;
+    writer.flush()?;
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    Ok(reply.trim_end().to_string())
+}
+
+/// Serve one client connection until it disconnects.
+fn session<S>(stream: &S, control: &Control) where for<'a> &'a S: Read + Write {
+    let reader = BufReader::new(stream);
+    let mut writer = stream;
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if let Some(reply) = control.handle(&line) {
+            if writeln!(writer, "{}", reply).and_then(|_| writer.flush()).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::*;
+    use std::os::unix::{ fs::FileTypeExt, net::{ UnixListener, UnixStream } };
+
+    pub fn endpoint(path: &str) -> String {
+        path.to_string()
+    }
+
+    pub fn connect(path: &str) -> io::Result<UnixStream> {
+        UnixStream::connect(path)
+    }
+
+    pub fn listen(path: &str, control: Arc<Control>) -> io::Result<()> {
+        // a socket file left behind by a previous run would make bind fail; anything else at the
+        // path is not ours to delete
+        match std::fs::symlink_metadata(path) {
+            Ok(m) if m.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path)));
+            }
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(path)?;
+        supervise::spawn("control server", control.logger.clone(), move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let control = control.clone();
+                thread::spawn(move || session(&stream, &control));
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::*;
+    use std::{ fs::File, os::windows::io::{ FromRawHandle, RawHandle }, time::Duration };
+    use windows::core::{ HRESULT, PCWSTR };
+    use windows::Win32::Foundation::{ CloseHandle, ERROR_PIPE_CONNECTED, HANDLE };
+    use windows::Win32::Storage::FileSystem::PIPE_ACCESS_DUPLEX;
+    use windows::Win32::System::Pipes::{
+        ConnectNamedPipe,
+        CreateNamedPipeW,
+        PIPE_READMODE_BYTE,
+        PIPE_REJECT_REMOTE_CLIENTS,
+        PIPE_TYPE_BYTE,
+        PIPE_UNLIMITED_INSTANCES,
+        PIPE_WAIT,
+    };
+
+    /// Bare names become `\\.\pipe\<name>`.
+    pub fn endpoint(path: &str) -> String {
+        if path.starts_with(r"\\") { path.to_string() } else { format!(r"\\.\pipe\{}", path) }
+    }
+
+    /// The client end of a pipe opens like a file.
+    pub fn connect(path: &str) -> io::Result<File> {
+        std::fs::OpenOptions::new().read(true).write(true).open(path)
+    }
+
+    fn create_instance(name: &[u16]) -> io::Result<HANDLE> {
+        let h = unsafe {
+            CreateNamedPipeW(
+                PCWSTR(name.as_ptr()),
+                PIPE_ACCESS_DUPLEX,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                4096,
+                4096,
+                0,
+                None
+            )
+        };
+        if h.is_invalid() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(h)
+    }
+
+    pub fn listen(path: &str, control: Arc<Control>) -> io::Result<()> {
+        let name: Vec<u16> = path.encode_utf16().chain(Some(0)).collect();
+        // fail early (bad name, no permission) before handing off to the accept thread
+        let first = create_instance(&name)?;
+        unsafe {
+            let _ = CloseHandle(first);
+        }
+
+        supervise::spawn("control server", control.logger.clone(), move || {
+            loop {
+                let pipe = match create_instance(&name) {
+                    Ok(h) => h,
+                    Err(e) => {
+                        let _ = control.logger.error(&format!("control pipe: {}", e));
+                        thread::sleep(Duration::from_secs(1));
+                        continue;
+                    }
+                };
+                // blocks until a client opens the pipe
+                let connected = match unsafe { ConnectNamedPipe(pipe, None) } {
+                    Ok(()) => true,
+                    Err(e) => e.code() == HRESULT::from_win32(ERROR_PIPE_CONNECTED.0),
+                };
+                if !connected {
+                    unsafe {
+                        let _ = CloseHandle(pipe);
+                    }
+                    continue;
+                }
+                // the File owns the handle from here and closes it on drop
+                let file = unsafe { File::from_raw_handle(pipe.0 as RawHandle) };
+                let control = control.clone();
+                thread::spawn(move || session(&file, &control));
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+mod platform {
+    use super::*;
+
+    pub fn endpoint(path: &str) -> String {
+        path.to_string()
+    }
+
+    pub fn listen(_path: &str, _control: Arc<Control>) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "control interface not supported on this platform"))
+    }
+
+    pub fn connect(_path: &str) -> io::Result<std::fs::File> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "control interface not supported on this platform"))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_stale_socket_is_replaced() {
+        let dir = std::env::temp_dir().join(format!("sonar-control-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let logger = Arc::new(Logger::new(&dir.join("Detection.log").to_string_lossy(), false).unwrap());
+        let path = dir.join("control.sock");
+        let cfg = Config { control_path: path.to_string_lossy().into_owned(), ..Config::default() };
+
+        std::fs::write(&path, "notes").unwrap();
+        let err = Control::start(&cfg, "presence", logger.clone()).err().unwrap();
+        assert!(err.to_string().contains("not a socket"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "notes");
+
+        std::fs::remove_file(&path).unwrap();
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap()); // left behind by a previous run
+        Control::start(&cfg, "presence", logger).unwrap();
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
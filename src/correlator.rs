@@ -0,0 +1,249 @@
+//! src/correlator.rs
+//! Cross-correlation products for `estimate_from_ref`, by FFT. Consecutive ticks' frames overlap
+//! by most of their length, so when the caller says where its frames sit in the capture rings
+//! (`FramePos`) the reference is cut into blocks on a fixed grid of ring positions and the
+//! products of every block that lies wholly inside the frame are kept: the next tick only
+//! transforms the blocks new to it and the few at the frame's edges (overlap-save).
+
+use std::collections::HashMap;
+
+use realfft::RealFftPlanner;
+
+/// Ring positions (samples written before them) of a frame pair's first samples. Only for raw
+/// frames: a band-limited or whitened frame is not a cut of the ring.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FramePos {
+    pub reference: u64,
+    pub mic: u64,
+}
+
+/// Smallest block; the block grows with the lag span so one block's products cover every lag.
+const MIN_BLOCK: usize = 1024;
+
+// (block start in the reference ring, mic - reference offset, first lag, last lag)
+type BlockKey = (u64, i64, i64, i64);
+
+pub struct Correlator {
+    planner: RealFftPlanner<f64>,
+    blocks: HashMap<BlockKey, Vec<f64>>,
+}
+
+impl Default for Correlator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Correlator {
+    pub fn new() -> Self {
+        Self { planner: RealFftPlanner::new(), blocks: HashMap::new() }
+    }
+
+    /// Forget the kept blocks (the rings were reopened).
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    /// Normalized cross-correlation of `a` against `b` (equal length) for `first ≤ lag ≤ last`:
+    /// Σ a[i]·b[i+lag] over the overlap, divided by both overlaps' energies. `at` lets the
+    /// products of blocks seen on an earlier tick be reused; the frames must then be
+    /// DC-removed and pre-emphasized (every sample but the first then depends on the ring alone).
+    pub fn normalized(&mut self, a: &[f32], b: &[f32], first: i64, last: i64, at: Option<FramePos>) -> Vec<f32> {
+        let n = a.len().min(b.len());
+        let nums = self.products(&a[..n], &b[..n], first, last, at);
+        let energy = |x: &[f32]| {
+            let mut acc = Vec::with_capacity(n + 1);
+            acc.push(0.0f64);
+            for &v in x {
+                acc.push(acc[acc.len() - 1] + (v as f64) * (v as f64));
+            }
+            acc
+        };
+        let (ea, eb) = (energy(&a[..n]), energy(&b[..n]));
+        // the old time-domain loop worked on unit-norm frames; keep its epsilon at that scale
+        let eps = 1e-9 * (ea[n].sqrt() * eb[n].sqrt());
+        let n = n as i64;
+        nums.iter()
+            .enumerate()
+            .map(|(k, &num)| {
+                let lag = first + (k as i64);
+                let (i0, i1) = (0.max(-lag) as usize, n.min(n - lag).max(0) as usize);
+                let (j0, j1) = (0.max(lag) as usize, n.min(n + lag).max(0) as usize);
+                let (ex, ey) = ((ea[i1.max(i0)] - ea[i0]).max(0.0), (eb[j1.max(j0)] - eb[j0]).max(0.0));
+                (num / (ex.sqrt() * ey.sqrt() + eps)) as f32
+            })
+            .collect()
+    }
+
+    /// Σ a[i]·b[i+lag] for `first ≤ lag ≤ last`, `b` taken as zero outside the frame.
+    fn products(&mut self, a: &[f32], b: &[f32], first: i64, last: i64, at: Option<FramePos>) -> Vec<f64> {
+        let n = a.len();
+        let span = (last - first) as usize;
+        let mut out = vec![0.0f64; span + 1];
+        let block = (span + 1).next_power_of_two().max(MIN_BLOCK);
+        let at = match at {
+            Some(at) if n >= 4 * block => at,
+            // short frame or no positions: the whole frame is one block
+            _ => {
+                self.block(a, b, 0, n, first, span, &mut out);
+                return out;
+            }
+        };
+
+        let offset = (at.mic as i64) - (at.reference as i64);
+        let grid = (at.reference / (block as u64)) * (block as u64);
+        let end = at.reference + (n as u64);
+        let mut p = grid;
+        while p < end {
+            let i0 = (p as i64) - (at.reference as i64);
+            // sample 0 of either frame depends on the frame's mean, the edges on where it was cut
+            let interior =
+                i0 >= 1 && i0 + first >= 1 && i0 + (block as i64) <= (n as i64) && i0 + (block as i64) - 1 + last < (n as i64);
+            let key = (p, offset, first, last);
+            match self.blocks.get(&key) {
+                Some(kept) if interior => {
+                    for (o, k) in out.iter_mut().zip(kept) {
+                        *o += k;
+                    }
+                }
+                _ => {
+                    let mut part = vec![0.0f64; span + 1];
+                    self.block(a, b, i0, block, first, span, &mut part);
+                    for (o, k) in out.iter_mut().zip(&part) {
+                        *o += k;
+                    }
+                    if interior {
+                        self.blocks.insert(key, part);
+                    }
+                }
+            }
+            p += block as u64;
+        }
+        // blocks before this frame will not come round again
+        self.blocks.retain(|k, _| k.0 >= grid);
+        out
+    }
+
+    /// Add Σ a[i]·b[i+first+m] over i in `i0..i0+len` (clipped to the frame) to `out[m]`.
+    #[allow(clippy::too_many_arguments)]
+    fn block(&mut self, a: &[f32], b: &[f32], i0: i64, len: usize, first: i64, span: usize, out: &mut [f64]) {
+        let n = a.len() as i64;
+        let size = (len + span).next_power_of_two();
+        let r2c = self.planner.plan_fft_forward(size);
+        let c2r = self.planner.plan_fft_inverse(size);
+        let at = |x: &[f32], i: i64| if (0..n).contains(&i) { x[i as usize] as f64 } else { 0.0 };
+
+        let mut xa = vec![0.0f64; size];
+        for (t, v) in xa.iter_mut().take(len).enumerate() {
+            *v = at(a, i0 + (t as i64));
+        }
+        let mut xb = vec![0.0f64; size];
+        for (u, v) in xb.iter_mut().take(len + span).enumerate() {
+            *v = at(b, i0 + first + (u as i64));
+        }
+        let mut sa = r2c.make_output_vec();
+        let mut sb = r2c.make_output_vec();
+        if r2c.process(&mut xa, &mut sa).is_err() || r2c.process(&mut xb, &mut sb).is_err() {
+            return;
+        }
+        // conj(A)·B is the correlation; B's segment is longer by the span, so lags 0..=span never wrap
+        for (x, y) in sb.iter_mut().zip(&sa) {
+            *x *= y.conj();
+        }
+        sb[0].im = 0.0;
+        if let Some(last) = sb.last_mut() {
+            last.im = 0.0;
+        }
+        let mut c = vec![0.0f64; size];
+        if c2r.process(&mut sb, &mut c).is_err() {
+            return;
+        }
+        let scale = 1.0 / (size as f64);
+        for (o, v) in out.iter_mut().zip(&c) {
+            *o += v * scale;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the time-domain sum the FFT replaces
+    fn direct(a: &[f32], b: &[f32], first: i64, last: i64) -> Vec<f32> {
+        let n = a.len();
+        (first..=last)
+            .map(|lag| {
+                let (ka, kb) = if lag >= 0 { (0, lag as usize) } else { (lag.unsigned_abs() as usize, 0) };
+                let (mut num, mut ex, mut ey) = (0.0f64, 0.0f64, 0.0f64);
+                for i in 0..n - ka - kb {
+                    let (x, y) = (a[i + ka] as f64, b[i + kb] as f64);
+                    num += x * y;
+                    ex += x * x;
+                    ey += y * y;
+                }
+                (num / (ex.sqrt() * ey.sqrt() + 1e-12)) as f32
+            })
+            .collect()
+    }
+
+    fn noise(len: usize, seed: u64) -> Vec<f32> {
+        let mut s = seed;
+        (0..len)
+            .map(|_| {
+                s = s.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                ((s >> 33) as f32) / (u32::MAX as f32) - 0.25
+            })
+            .collect()
+    }
+
+    // the same cut of two rings, prepared as estimate_from_ref prepares it
+    fn cut(ring: &[f32], start: usize, len: usize) -> Vec<f32> {
+        let mut x = ring[start..start + len].to_vec();
+        let mean = x.iter().sum::<f32>() / (len as f32);
+        let mut prev = 0.0;
+        for v in x.iter_mut() {
+            let cur = *v - mean;
+            *v = cur - prev;
+            prev = cur;
+        }
+        x
+    }
+
+    #[test]
+    fn fft_products_match_the_direct_sum() {
+        let (a, b) = (noise(3000, 1), noise(3000, 2));
+        let got = Correlator::new().normalized(&a, &b, -40, 900, None);
+        for (g, d) in got.iter().zip(direct(&a, &b, -40, 900)) {
+            assert!((g - d).abs() < 1e-5, "{} vs {}", g, d);
+        }
+    }
+
+    #[test]
+    fn overlapping_frames_reuse_blocks_and_agree_with_a_fresh_pass() {
+        let reference = noise(40_000, 3);
+        // the mic hears the reference 100 samples late and its frames are cut 200 samples earlier
+        let mic: Vec<f32> = (0..40_000).map(|i| if i >= 100 { 0.5 * reference[i - 100] } else { 0.0 }).collect();
+        let (len, hop) = (16_384usize, 4_800usize);
+        let mut corr = Correlator::new();
+        let mut kept = 0;
+        for tick in 0..4 {
+            let r = 1_000 + tick * hop;
+            let (a, b) = (cut(&reference, r, len), cut(&mic, r - 200, len));
+            let at = FramePos { reference: r as u64, mic: (r - 200) as u64 };
+            let got = corr.normalized(&a, &b, 250, 1_400, Some(at));
+            let fresh = Correlator::new().normalized(&a, &b, 250, 1_400, None);
+            for (g, f) in got.iter().zip(&fresh) {
+                assert!((g - f).abs() < 1e-4, "tick {}: {} vs {}", tick, g, f);
+            }
+            let best = got.iter().enumerate().max_by(|x, y| x.1.total_cmp(y.1)).unwrap().0;
+            assert_eq!((best as i64) + 250, 300);
+            if tick > 0 {
+                // the blocks the last frame kept that are still inside this one were reused
+                assert!(corr.blocks.len() >= kept.min(2));
+            }
+            kept = corr.blocks.len();
+        }
+        assert!(kept > 0);
+    }
+}
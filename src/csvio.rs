@@ -0,0 +1,105 @@
+//! src/csvio.rs
+//! RFC 4180 CSV fields: quoting on write, a quote-aware parser on read.
+//! SongScan.csv carries free-form URLs, so a plain `split(',')` is not enough.
+
+use std::borrow::Cow;
+
+/// Quote a field if it contains a comma, quote or line break; quotes are doubled.
+pub fn field(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// One record, fields escaped and joined (no line terminator).
+pub fn record<S: AsRef<str>>(fields: &[S]) -> String {
+    fields
+        .iter()
+        .map(|f| field(f.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Parse a whole file. Records end at LF or CRLF outside quotes; quoted fields may hold
+/// commas, doubled quotes and line breaks. A blank line comes back as `[""]`.
+pub fn parse(text: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut rec: Vec<String> = Vec::new();
+    let mut cur = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    cur.push('"');
+                }
+                '"' => {
+                    quoted = false;
+                }
+                _ => cur.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                quoted = true;
+            }
+            ',' => rec.push(std::mem::take(&mut cur)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                rec.push(std::mem::take(&mut cur));
+                records.push(std::mem::take(&mut rec));
+            }
+            _ => cur.push(c),
+        }
+    }
+    // last record without a trailing newline
+    if !cur.is_empty() || !rec.is_empty() {
+        rec.push(cur);
+        records.push(rec);
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(fields: &[&str]) {
+        let line = format!("{}\n", record(fields));
+        assert_eq!(parse(&line), vec![fields.to_vec()], "line {:?}", line);
+    }
+
+    #[test]
+    fn plain_fields_are_not_quoted() {
+        assert_eq!(record(&["a", "1.5", ""]), "a,1.5,");
+        round_trip(&["https://example.com/watch?v=abc", "12.000", "", "x"]);
+    }
+
+    #[test]
+    fn commas_quotes_and_newlines_round_trip() {
+        assert_eq!(field("a,b"), "\"a,b\"");
+        assert_eq!(field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        round_trip(&["https://x.test/?q=a,b&t=1", "\"quoted\"", "two\nlines", "cr\r\nlf", ","]);
+        round_trip(&["\"", "\"\"", ""]);
+    }
+
+    #[test]
+    fn parses_crlf_blank_lines_and_missing_final_newline() {
+        let text = "url,start_s\r\n\"a,b\",1\r\n\r\nc,2";
+        assert_eq!(
+            parse(text),
+            vec![vec!["url", "start_s"], vec!["a,b", "1"], vec![""], vec!["c", "2"]]
+        );
+    }
+
+    #[test]
+    fn empty_quoted_field_is_empty() {
+        // the `notes` column is written as ""
+        assert_eq!(parse("a,\"\",b\n"), vec![vec!["a", "", "b"]]);
+    }
+}
@@ -0,0 +1,247 @@
+//! src/devcal.rs
+//! Per-device calibration for presence mode: what a run learns about one render/capture pair
+//! (the pipeline delay, the clocks' drift, the empty room's echoes) is kept in
+//! `profiles/<name>/<render>__<capture>.json` beside the log and loaded when the same pair is
+//! opened again, so the delay lock and drift fit start from where they were and the room's
+//! own echoes are held to a threshold of their own. `--device-profile <name>` keeps rooms and
+//! headsets that share devices apart.
+
+use anyhow::{ Context, Result };
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{ Duration, Instant },
+};
+
+use crate::logger::Logger;
+use crate::output::{ self, JsonObj };
+use crate::{ json, Config };
+
+/// Distance covered by one bin of the background profile.
+pub const DIST_BIN_M: f32 = 0.25;
+/// Strength steps each bin counts the empty room's echoes in (strength is 0..1).
+const STRENGTH_STEPS: usize = 100;
+/// Empty-room ticks a bin needs before its threshold counts (a minute at the default tick).
+const MIN_TICKS: u64 = 600;
+/// An echo has to stand this far above the empty room's 95th percentile at its distance.
+const MARGIN: f32 = 1.25;
+/// Beyond this many ticks a bin's counts are halved, so a rearranged room wins out over time.
+const BIN_CAP: u64 = 100_000;
+/// The file is rewritten at most this often (and once more on the way out).
+const SAVE_EVERY: Duration = Duration::from_secs(60);
+
+/// What was learned about one render/capture pair.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceCal {
+    pub render: String,
+    pub capture: String,
+    pub delay_ms: Option<f32>, // the locked pipeline delay
+    pub drift_ppm: Option<f64>, // reference clock against the mic clock
+    background: Vec<Vec<u64>>, // per DIST_BIN_M: strengths of the best echo while nobody was there
+}
+
+impl DeviceCal {
+    pub fn new(render: &str, capture: &str) -> Self {
+        Self { render: render.to_string(), capture: capture.to_string(), delay_ms: None, drift_ppm: None, background: Vec::new() }
+    }
+
+    fn bin(distance_m: f32) -> usize {
+        (distance_m.max(0.0) / DIST_BIN_M) as usize
+    }
+
+    /// The best echo of a tick while the room is empty.
+    pub fn observe_absent(&mut self, distance_m: f32, strength: f32) {
+        let i = Self::bin(distance_m);
+        if self.background.len() <= i {
+            self.background.resize_with(i + 1, || vec![0; STRENGTH_STEPS]);
+        }
+        let hist = &mut self.background[i];
+        let step = ((strength.clamp(0.0, 1.0) * (STRENGTH_STEPS as f32)) as usize).min(STRENGTH_STEPS - 1);
+        hist[step] += 1;
+        if hist.iter().sum::<u64>() > BIN_CAP {
+            hist.iter_mut().for_each(|c| *c /= 2);
+        }
+    }
+
+    /// Empty-room ticks behind the profile.
+    pub fn ticks(&self) -> u64 {
+        self.background.iter().flatten().sum()
+    }
+
+    /// 95th percentile of the empty room's echo strength in bin `i`, and the ticks behind it.
+    fn p95(&self, i: usize) -> Option<(f32, u64)> {
+        let hist = self.background.get(i)?;
+        let n: u64 = hist.iter().sum();
+        if n == 0 {
+            return None;
+        }
+        let mut seen = 0;
+        for (step, &c) in hist.iter().enumerate() {
+            seen += c;
+            if (seen as f64) >= 0.95 * (n as f64) {
+                return Some((((step + 1) as f32) / (STRENGTH_STEPS as f32), n));
+            }
+        }
+        None
+    }
+
+    /// The strength an echo at `distance_m` needs beyond `--strength-thr`, once enough of the
+    /// empty room has been heard at that distance.
+    pub fn threshold_at(&self, distance_m: f32) -> Option<f32> {
+        self.p95(Self::bin(distance_m)).filter(|&(_, n)| n >= MIN_TICKS).map(|(p, _)| p * MARGIN)
+    }
+
+    fn to_json(&self) -> String {
+        let bins: Vec<String> = self.background
+            .iter()
+            .enumerate()
+            .map(|(i, hist)| {
+                let (p95, n) = self.p95(i).unwrap_or((0.0, 0));
+                let counts: Vec<String> = hist.iter().map(|c| c.to_string()).collect();
+                JsonObj::new()
+                    .num("from_m", (i as f64) * (DIST_BIN_M as f64))
+                    .int("ticks", n as i64)
+                    .num("p95_strength", p95 as f64)
+                    .opt_num("threshold", self.threshold_at((i as f32) * DIST_BIN_M).map(|t| t as f64))
+                    .arr("counts", &counts)
+                    .finish()
+            })
+            .collect();
+        JsonObj::new()
+            .str("render", &self.render)
+            .str("capture", &self.capture)
+            .str("updated", &chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string())
+            .opt_num("delay_ms", self.delay_ms.map(|d| d as f64))
+            .opt_num("drift_ppm", self.drift_ppm)
+            .arr("background", &bins)
+            .finish()
+    }
+
+    fn from_json(text: &str) -> Result<Self> {
+        let doc = json::parse(text)?;
+        let text_of = |k: &str| doc.get(k).and_then(|v| v.as_str()).with_context(|| format!("no {}", k));
+        let mut cal = Self::new(text_of("render")?, text_of("capture")?);
+        cal.delay_ms = doc.get("delay_ms").and_then(|v| v.as_f64()).map(|d| d as f32);
+        cal.drift_ppm = doc.get("drift_ppm").and_then(|v| v.as_f64());
+        for (i, b) in doc.get("background").and_then(|b| b.as_array()).unwrap_or_default().iter().enumerate() {
+            let counts = b
+                .get("counts")
+                .and_then(|c| c.as_array())
+                .filter(|c| c.len() == STRENGTH_STEPS)
+                .with_context(|| format!("background bin {}: expected {} counts", i, STRENGTH_STEPS))?;
+            cal.background.push(counts.iter().map(|c| c.as_f64().unwrap_or(0.0).max(0.0) as u64).collect());
+        }
+        Ok(cal)
+    }
+}
+
+/// File name for a device pair: both names, reduced to what any file system takes.
+pub fn file_name(render: &str, capture: &str) -> String {
+    let slug = |s: &str| -> String {
+        let s: String = s
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+            .collect();
+        s.split('-')
+            .filter(|p| !p.is_empty())
+            .collect::<Vec<_>>()
+            .join("-")
+    };
+    format!("{}__{}.json", slug(render), slug(capture))
+}
+
+/// A usable `--device-profile` name (it becomes a directory): letters, digits, `-` and `_`.
+pub fn is_profile_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The live loop's side: load once the devices are open, save as the run goes.
+pub struct DeviceStore {
+    path: PathBuf,
+    saved: Option<Instant>,
+    logger: Arc<Logger>,
+}
+
+impl DeviceStore {
+    /// None with --no-device-cal, or when either source is not a live device (files, --ref-file).
+    /// The calibration is the one saved for the pair, or an empty one.
+    pub fn open(cfg: &Config, render: Option<String>, capture: Option<String>, logger: Arc<Logger>) -> Option<(Self, DeviceCal)> {
+        if !cfg.device_cal {
+            return None;
+        }
+        let (render, capture) = (render?, capture?);
+        let path = output::sibling_path(&cfg.log_path, "profiles").join(&cfg.device_profile).join(file_name(&render, &capture));
+        let loaded = fs
+            ::read_to_string(&path)
+            .ok()
+            .map(|text| DeviceCal::from_json(&text).with_context(|| format!("parsing {}", path.display())));
+        let cal = match loaded {
+            Some(Ok(cal)) => {
+                let _ = logger.info(
+                    &format!(
+                        "device calibration '{}' for {} / {}: delay {}, drift {}, {} empty-room ticks",
+                        cfg.device_profile,
+                        render,
+                        capture,
+                        cal.delay_ms.map_or("not locked".to_string(), |d| format!("{:.1} ms", d)),
+                        cal.drift_ppm.map_or("not fitted".to_string(), |p| format!("{:+.1} ppm", p)),
+                        cal.ticks()
+                    )
+                );
+                cal
+            }
+            Some(Err(e)) => {
+                let _ = logger.warn(&format!("{:#}; learning the devices afresh", e));
+                DeviceCal::new(&render, &capture)
+            }
+            None => {
+                let _ = logger.info(&format!("device calibration '{}': nothing saved for {} / {} yet", cfg.device_profile, render, capture));
+                DeviceCal::new(&render, &capture)
+            }
+        };
+        Some((Self { path, saved: None, logger }, cal))
+    }
+
+    /// Call every tick; writes when SAVE_EVERY has passed, or now with `force`.
+    pub fn save(&mut self, cal: &DeviceCal, force: bool) {
+        if !force && self.saved.is_some_and(|at| at.elapsed() < SAVE_EVERY) {
+            return;
+        }
+        self.saved = Some(Instant::now());
+        let written = self.path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| output::write_atomic(&self.path, &cal.to_json()));
+        if let Err(e) = written {
+            let _ = self.logger.warn(&format!("could not write {}: {}", self.path.display(), e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn background_sets_thresholds_per_distance_and_survives_a_round_trip() {
+        let mut cal = DeviceCal::new("Speakers (Realtek)", "Mic Array");
+        cal.delay_ms = Some(23.5);
+        cal.drift_ppm = Some(-12.25);
+        // a fan at about 1.1 m keeps voting around 0.3; elsewhere the room is quiet
+        for k in 0..1000 {
+            cal.observe_absent(1.1, 0.25 + ((k % 10) as f32) * 0.01);
+            cal.observe_absent(0.6, 0.05);
+        }
+        let fan = cal.threshold_at(1.2).unwrap();
+        assert!(fan > 0.34 && fan < 0.45, "{}", fan);
+        assert!(cal.threshold_at(0.7).unwrap() < 0.1);
+        assert_eq!(cal.threshold_at(0.1), None);
+        assert_eq!(cal.threshold_at(3.0), None);
+
+        let back = DeviceCal::from_json(&cal.to_json()).unwrap();
+        assert_eq!(back, cal);
+        assert_eq!(file_name("Speakers (Realtek)", "Mic Array"), "speakers-realtek__mic-array.json");
+        assert!(is_profile_name("office_2") && !is_profile_name("tuned.toml") && !is_profile_name("../x"));
+    }
+}
@@ -0,0 +1,534 @@
+//! src/discovery.rs
+//! mDNS / DNS-SD for sensor nodes: an instance accepting nodes (`--node-listen`) answers
+//! queries for `_sonar-presence._tcp.local` with its port, and a node started without
+//! `--server` asks for that service and tries what answers. Only what that needs is here: PTR,
+//! SRV, TXT and A records, one service, IPv4. The port is shared with the system's own
+//! responder (Bonjour, Avahi, Windows), so the socket is opened with address reuse.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{ IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket },
+    sync::Arc,
+    time::{ Duration, Instant },
+};
+
+use crate::logger::Logger;
+use crate::supervise;
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// The service nodes look for.
+pub const SERVICE: &str = "_sonar-presence._tcp.local";
+/// How long a node listens for answers.
+pub const BROWSE_FOR: Duration = Duration::from_secs(2);
+const TTL_S: u32 = 120;
+/// Record TTL in answers to legacy unicast queriers, which cache like plain DNS (RFC 6762 §6.7).
+const LEGACY_TTL_S: u32 = 10;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Question class bit asking for a unicast answer; in records, the cache-flush bit.
+const CLASS_TOP: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+/// A service instance that answered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Found {
+    pub name: String, // instance label
+    pub addr: SocketAddr,
+    pub txt: Vec<String>, // key=value
+}
+
+/// What an instance advertises.
+#[derive(Clone, Debug)]
+pub struct Advert {
+    pub instance: String, // one DNS label: no dots, at most 63 bytes
+    pub host: String, // `<host>.local` in the SRV record
+    pub port: u16,
+    pub txt: Vec<String>,
+}
+
+impl Advert {
+    pub fn new(instance: &str, port: u16, txt: Vec<String>) -> Self {
+        let label = |s: &str| -> String {
+            let mut s: String = s
+                .chars()
+                .map(|c| if c == '.' { '-' } else { c })
+                .collect();
+            while s.len() > 63 {
+                s.pop();
+            }
+            s
+        };
+        let host: String = instance
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect();
+        Self { instance: label(instance), host: label(&host), port, txt }
+    }
+
+    fn full_name(&self) -> String {
+        format!("{}.{}", self.instance, SERVICE)
+    }
+}
+
+/// Labels of `name`, dots separating them; `first` is taken whole as the first label.
+fn put_name(out: &mut Vec<u8>, first: Option<&str>, rest: &str) {
+    put_labels(out, first.into_iter().chain(rest.split('.').filter(|l| !l.is_empty())));
+}
+
+fn put_labels<'a>(out: &mut Vec<u8>, labels: impl Iterator<Item = &'a str>) {
+    for label in labels {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_record(out: &mut Vec<u8>, name: (Option<&str>, &str), rtype: u16, flush: bool, ttl: u32, data: &[u8]) {
+    put_name(out, name.0, name.1);
+    put_u16(out, rtype);
+    put_u16(out, CLASS_IN | (if flush { CLASS_TOP } else { 0 }));
+    out.extend_from_slice(&ttl.to_be_bytes());
+    put_u16(out, data.len() as u16);
+    out.extend_from_slice(data);
+}
+
+/// A query for the service, asking for unicast answers.
+pub fn query_packet() -> Vec<u8> {
+    let mut out = Vec::new();
+    for v in [0, 0, 1, 0, 0, 0] {
+        put_u16(&mut out, v);
+    }
+    put_name(&mut out, None, SERVICE);
+    put_u16(&mut out, TYPE_PTR);
+    put_u16(&mut out, CLASS_IN | CLASS_TOP);
+    out
+}
+
+/// The answer for `ad`: PTR, with its SRV and TXT alongside.
+pub fn response_packet(id: u16, ad: &Advert) -> Vec<u8> {
+    answer_packet(id, &[], ad)
+}
+
+/// The answer to `ask`: a legacy unicast querier gets its id and questions back, short TTLs
+/// and no cache-flush bits (RFC 6762 §6.7), anyone else the plain multicast-style answer.
+fn reply_packet(ask: &Ask, ad: &Advert) -> Vec<u8> {
+    match (ask.legacy, ask.unicast) {
+        (true, _) => answer_packet(ask.id, &ask.questions, ad),
+        (false, true) => response_packet(ask.id, ad),
+        (false, false) => response_packet(0, ad),
+    }
+}
+
+/// An answer for `ad`; with `questions` (a legacy unicast reply) they are repeated first.
+fn answer_packet(id: u16, questions: &[(Vec<String>, u16, u16)], ad: &Advert) -> Vec<u8> {
+    let legacy = !questions.is_empty();
+    let (ttl, flush) = if legacy { (LEGACY_TTL_S, false) } else { (TTL_S, true) };
+    let mut out = Vec::new();
+    for v in [id, FLAG_RESPONSE | FLAG_AUTHORITATIVE, questions.len() as u16, 1, 0, 2] {
+        put_u16(&mut out, v);
+    }
+    for (name, qtype, qclass) in questions {
+        put_labels(&mut out, name.iter().map(String::as_str));
+        put_u16(&mut out, *qtype);
+        put_u16(&mut out, *qclass);
+    }
+    let mut ptr = Vec::new();
+    put_name(&mut ptr, Some(&ad.instance), SERVICE);
+    put_record(&mut out, (None, SERVICE), TYPE_PTR, false, ttl, &ptr);
+    let mut srv = Vec::new();
+    for v in [0, 0, ad.port] {
+        put_u16(&mut srv, v);
+    }
+    put_name(&mut srv, Some(&ad.host), "local");
+    put_record(&mut out, (Some(&ad.instance), SERVICE), TYPE_SRV, flush, ttl, &srv);
+    let mut txt = Vec::new();
+    for kv in ad.txt.iter().filter(|kv| kv.len() < 256) {
+        txt.push(kv.len() as u8);
+        txt.extend_from_slice(kv.as_bytes());
+    }
+    if txt.is_empty() {
+        txt.push(0);
+    }
+    put_record(&mut out, (Some(&ad.instance), SERVICE), TYPE_TXT, flush, ttl, &txt);
+    out
+}
+
+fn u16_at(pkt: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*pkt.get(pos)?, *pkt.get(pos + 1)?]))
+}
+
+/// The name at `*pos` as labels, following compression pointers; `*pos` moves past it.
+fn read_name(pkt: &[u8], pos: &mut usize) -> Option<Vec<String>> {
+    let mut labels = Vec::new();
+    let mut at = *pos;
+    let mut jumped = false;
+    for _ in 0..128 {
+        let len = *pkt.get(at)? as usize;
+        if len == 0 {
+            if !jumped {
+                *pos = at + 1;
+            }
+            return Some(labels);
+        }
+        if len & 0xc0 == 0xc0 {
+            let target = (u16_at(pkt, at)? & 0x3fff) as usize;
+            if !jumped {
+                *pos = at + 2;
+            }
+            jumped = true;
+            at = target;
+            continue;
+        }
+        labels.push(String::from_utf8_lossy(pkt.get(at + 1..at + 1 + len)?).into_owned());
+        at += 1 + len;
+    }
+    None
+}
+
+fn is_name(labels: &[String], name: &str) -> bool {
+    labels.join(".").eq_ignore_ascii_case(name)
+}
+
+/// One resource record, its data read as far as discovery cares.
+#[derive(Debug)]
+enum Record {
+    Ptr(Vec<String>, Vec<String>), // (owner, target)
+    Srv(Vec<String>, u16, Vec<String>), // (owner, port, target host)
+    Txt(Vec<String>, Vec<String>),
+    A(Vec<String>, Ipv4Addr),
+    Other,
+}
+
+/// What a packet holds.
+struct Packet {
+    id: u16,
+    flags: u16,
+    questions: Vec<(Vec<String>, u16, u16)>, // (name, type, class)
+    records: Vec<Record>, // answers, authority and additional alike
+}
+
+fn parse_packet(pkt: &[u8]) -> Option<Packet> {
+    let (id, flags) = (u16_at(pkt, 0)?, u16_at(pkt, 2)?);
+    let counts = [u16_at(pkt, 4)?, u16_at(pkt, 6)?, u16_at(pkt, 8)?, u16_at(pkt, 10)?];
+    let mut pos = 12;
+    let mut questions = Vec::new();
+    for _ in 0..counts[0] {
+        let name = read_name(pkt, &mut pos)?;
+        questions.push((name, u16_at(pkt, pos)?, u16_at(pkt, pos + 2)?));
+        pos += 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..(counts[1] as usize) + (counts[2] as usize) + (counts[3] as usize) {
+        let owner = read_name(pkt, &mut pos)?;
+        let rtype = u16_at(pkt, pos)?;
+        let len = u16_at(pkt, pos + 8)? as usize;
+        let start = pos + 10;
+        let data = pkt.get(start..start + len)?;
+        records.push(match rtype {
+            TYPE_PTR => Record::Ptr(owner, read_name(pkt, &mut { start })?),
+            TYPE_SRV => Record::Srv(owner, u16_at(data, 4)?, read_name(pkt, &mut { start + 6 })?),
+            TYPE_TXT => {
+                let mut kv = Vec::new();
+                let mut at = 0;
+                while let Some(&n) = data.get(at) {
+                    if n > 0 {
+                        kv.push(String::from_utf8_lossy(data.get(at + 1..at + 1 + (n as usize))?).into_owned());
+                    }
+                    at += 1 + (n as usize);
+                }
+                Record::Txt(owner, kv)
+            }
+            TYPE_A if len == 4 => Record::A(owner, Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            _ => Record::Other,
+        });
+        pos = start + len;
+    }
+    Some(Packet { id, flags, questions, records })
+}
+
+/// A query that asks for an advert.
+#[derive(Debug, PartialEq)]
+struct Ask {
+    id: u16,
+    unicast: bool, // the answer goes back to the sender alone
+    legacy: bool, // a one-shot querier off port 5353, which reads the reply as plain DNS
+    questions: Vec<(Vec<String>, u16, u16)>,
+}
+
+/// The query in `pkt`, when it asks for `ad`. The answer goes back to the sender alone for a
+/// unicast question or a one-shot querier off port 5353.
+fn asks_for(pkt: &[u8], from: SocketAddr, ad: &Advert) -> Option<Ask> {
+    let p = parse_packet(pkt)?;
+    if p.flags & FLAG_RESPONSE != 0 {
+        return None;
+    }
+    let full = ad.full_name();
+    let q = p.questions.iter().find(|(name, qtype, _)| {
+        (is_name(name, SERVICE) && matches!(*qtype, TYPE_PTR | TYPE_ANY)) ||
+            (is_name(name, &full) && matches!(*qtype, TYPE_SRV | TYPE_TXT | TYPE_ANY))
+    })?;
+    let legacy = from.port() != MDNS_PORT;
+    Some(Ask { id: p.id, unicast: q.2 & CLASS_TOP != 0 || legacy, legacy, questions: p.questions })
+}
+
+/// Answers gathered while browsing, put together once the time is up.
+#[derive(Default)]
+struct Answers {
+    instances: Vec<String>,
+    srv: BTreeMap<String, (u16, String, IpAddr)>, // instance → (port, host, who answered)
+    txt: BTreeMap<String, Vec<String>>,
+    hosts: BTreeMap<String, Ipv4Addr>,
+}
+
+impl Answers {
+    fn take(&mut self, pkt: &[u8], from: IpAddr) {
+        let Some(p) = parse_packet(pkt).filter(|p| p.flags & FLAG_RESPONSE != 0) else {
+            return;
+        };
+        // the instance label of a `<instance>._sonar-presence._tcp.local` owner
+        let instance = |labels: &[String]| -> Option<String> {
+            (labels.len() > 1 && is_name(&labels[1..], SERVICE)).then(|| labels[0].clone())
+        };
+        for r in p.records {
+            match r {
+                Record::Ptr(owner, target) if is_name(&owner, SERVICE) => {
+                    if let Some(name) = instance(&target).filter(|n| !self.instances.contains(n)) {
+                        self.instances.push(name);
+                    }
+                }
+                Record::Srv(owner, port, host) => {
+                    if let Some(name) = instance(&owner) {
+                        self.srv.insert(name, (port, host.join(".").to_ascii_lowercase(), from));
+                    }
+                }
+                Record::Txt(owner, kv) => {
+                    if let Some(name) = instance(&owner) {
+                        self.txt.insert(name, kv);
+                    }
+                }
+                Record::A(owner, ip) => {
+                    self.hosts.insert(owner.join(".").to_ascii_lowercase(), ip);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn found(&self) -> Vec<Found> {
+        self.instances
+            .iter()
+            .filter_map(|name| {
+                let (port, host, from) = self.srv.get(name)?;
+                let ip = self.hosts.get(host).map_or(*from, |&a| IpAddr::V4(a));
+                Some(Found { name: name.clone(), addr: SocketAddr::new(ip, *port), txt: self.txt.get(name).cloned().unwrap_or_default() })
+            })
+            .collect()
+    }
+}
+
+/// Ask the local network for the service; what answered within `wait`.
+pub fn browse(wait: Duration) -> io::Result<Vec<Found>> {
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    sock.set_multicast_loop_v4(true)?;
+    sock.set_read_timeout(Some(Duration::from_millis(100)))?;
+    sock.send_to(&query_packet(), (MDNS_ADDR, MDNS_PORT))?;
+    let mut answers = Answers::default();
+    let mut buf = [0u8; 9000];
+    let t0 = Instant::now();
+    while t0.elapsed() < wait {
+        match sock.recv_from(&mut buf) {
+            Ok((n, from)) => answers.take(&buf[..n], from.ip()),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+    Ok(answers.found())
+}
+
+/// Answer queries for `ad` for the rest of the run. The failure to join the multicast group
+/// is returned; queries that cannot be answered later are only logged.
+pub fn advertise(ad: Advert, logger: Arc<Logger>) -> io::Result<()> {
+    let sock = platform::shared_socket(MDNS_PORT)?;
+    sock.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    sock.set_multicast_loop_v4(true)?;
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_ADDR, MDNS_PORT));
+    // announce once, for browsers already listening
+    sock.send_to(&response_packet(0, &ad), group)?;
+    supervise::spawn("mDNS responder", logger.clone(), move || {
+        let mut buf = [0u8; 9000];
+        loop {
+            let Ok((n, from)) = sock.recv_from(&mut buf) else {
+                continue;
+            };
+            let Some(ask) = asks_for(&buf[..n], from, &ad) else {
+                continue;
+            };
+            let to = if ask.unicast { from } else { group };
+            if let Err(e) = sock.send_to(&reply_packet(&ask, &ad), to) {
+                let _ = logger.debug(&format!("mDNS: cannot answer {}: {}", from, e));
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::*;
+    use std::os::fd::FromRawFd;
+
+    /// A UDP socket on `port` that the system's own responder can share.
+    pub fn shared_socket(port: u16) -> io::Result<UdpSocket> {
+        unsafe {
+            let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // owned from here: closed on any error below
+            let sock = UdpSocket::from_raw_fd(fd);
+            let one: libc::c_int = 1;
+            for opt in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+                let set = libc::setsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    opt,
+                    (&one as *const libc::c_int).cast(),
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t
+                );
+                if set != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            let mut addr: libc::sockaddr_in = std::mem::zeroed();
+            addr.sin_family = libc::AF_INET as libc::sa_family_t;
+            addr.sin_port = port.to_be();
+            #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+            {
+                addr.sin_len = std::mem::size_of::<libc::sockaddr_in>() as u8;
+            }
+            let bound = libc::bind(
+                fd,
+                (&addr as *const libc::sockaddr_in).cast(),
+                std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            );
+            if bound != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(sock)
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::*;
+    use std::os::windows::io::FromRawSocket;
+    use windows::Win32::Networking::WinSock::{
+        bind,
+        setsockopt,
+        socket,
+        WSAStartup,
+        AF_INET,
+        IPPROTO_UDP,
+        SOCKADDR,
+        SOCKADDR_IN,
+        SOCK_DGRAM,
+        SOL_SOCKET,
+        SO_REUSEADDR,
+        WSADATA,
+    };
+
+    /// A UDP socket on `port` that the system's own responder can share.
+    pub fn shared_socket(port: u16) -> io::Result<UdpSocket> {
+        unsafe {
+            let mut wsa = WSADATA::default();
+            let started = WSAStartup(0x0202, &mut wsa);
+            if started != 0 {
+                return Err(io::Error::from_raw_os_error(started));
+            }
+            let s = socket(AF_INET.0 as i32, SOCK_DGRAM, IPPROTO_UDP.0).map_err(io::Error::other)?;
+            // owned from here: closed on any error below
+            let sock = UdpSocket::from_raw_socket(s.0 as u64);
+            if setsockopt(s, SOL_SOCKET, SO_REUSEADDR, Some(&1i32.to_ne_bytes())) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let addr = SOCKADDR_IN { sin_family: AF_INET, sin_port: port.to_be(), ..Default::default() };
+            if bind(s, (&addr as *const SOCKADDR_IN).cast::<SOCKADDR>(), std::mem::size_of::<SOCKADDR_IN>() as i32) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(sock)
+        }
+    }
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+mod platform {
+    use super::*;
+
+    pub fn shared_socket(_port: u16) -> io::Result<UdpSocket> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "mDNS is not supported on this platform"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_query_for_the_service_is_answered_and_the_answer_read_back() {
+        let ad = Advert::new("desk.lan", 7790, vec!["protocol=2".to_string(), "token=required".to_string()]);
+        assert_eq!(ad.instance, "desk-lan");
+
+        // a one-shot browser on an ephemeral port gets the answer to itself, as plain DNS:
+        // its id and question back, short TTLs, no cache-flush bits
+        let node: SocketAddr = "192.168.1.20:50000".parse().unwrap();
+        let mut query = query_packet();
+        query[..2].copy_from_slice(&0x1234u16.to_be_bytes());
+        let ask = asks_for(&query, node, &ad).unwrap();
+        assert!(ask.unicast && ask.legacy && ask.id == 0x1234);
+        let reply = reply_packet(&ask, &ad);
+        let p = parse_packet(&reply).unwrap();
+        assert_eq!((p.id, p.flags & FLAG_RESPONSE != 0), (0x1234, true));
+        assert_eq!(p.questions, parse_packet(&query).unwrap().questions);
+        assert_eq!(reply[12..query.len()], query[12..]);
+        let first_ttl = query.len() + SERVICE.len() + 2 + 4;
+        assert_eq!(u32::from_be_bytes(reply[first_ttl..first_ttl + 4].try_into().unwrap()), LEGACY_TTL_S);
+        assert!(!reply[query.len()..].windows(2).any(|w| w == (CLASS_IN | CLASS_TOP).to_be_bytes()));
+
+        // a querier on 5353 asking by multicast gets the multicast answer, with no questions
+        let peer: SocketAddr = "192.168.1.21:5353".parse().unwrap();
+        let mut multicast = query.clone();
+        let class_at = multicast.len() - 2;
+        multicast[class_at..].copy_from_slice(&CLASS_IN.to_be_bytes());
+        let ask = asks_for(&multicast, peer, &ad).unwrap();
+        assert!(!ask.unicast && !ask.legacy);
+        let p = parse_packet(&reply_packet(&ask, &ad)).unwrap();
+        assert!(p.id == 0 && p.questions.is_empty());
+
+        let mut other = query_packet();
+        other[12 + 1] = b'X'; // `_Xonar-presence`
+        assert_eq!(asks_for(&other, node, &ad), None);
+        assert_eq!(asks_for(&response_packet(0, &ad), node, &ad), None);
+
+        let mut answers = Answers::default();
+        answers.take(&response_packet(7, &ad), "192.168.1.5".parse().unwrap());
+        assert_eq!(
+            answers.found(),
+            vec![Found { name: "desk-lan".to_string(), addr: "192.168.1.5:7790".parse().unwrap(), txt: ad.txt.clone() }]
+        );
+    }
+}
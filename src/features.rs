@@ -0,0 +1,364 @@
+//! src/features.rs
+//! `--features <PATH>`: a per-tick feature table for training presence classifiers outside
+//! sonar-presence. Beside what `--debug-dump` keeps, each row describes the echo band of the
+//! correlation as a whole and the mic residual (the mic with the direct path taken out): how
+//! much its spectrum changed since the last tick and how much energy sits in the Doppler
+//! sidebands of the strongest tone. The columns are a versioned schema (`COLUMNS`), written
+//! beside the table as `<name>.schema.json`; a new version only ever appends columns.
+
+use anyhow::{ Context, Result };
+use realfft::{ num_complex::Complex, RealFftPlanner };
+use std::{
+    fs::File,
+    io::{ self, BufWriter, Write },
+    path::PathBuf,
+    sync::Arc,
+};
+
+use crate::logger::Logger;
+use crate::output::{ self, JsonObj };
+use crate::recorder::TickMeta;
+use crate::sonar_presence::Measurement;
+use crate::strategy::DetectorKind;
+use crate::Config;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The table's columns in order, with their meaning (empty cells: not measured that tick).
+pub const COLUMNS: &[(&str, &str)] = &[
+    ("tick", "tick number from 1"),
+    ("t_s", "seconds since the run started (the replay clock in replay mode)"),
+    ("rms_ref", "RMS of the correlated reference frame (within the probe band when one is set)"),
+    ("rms_mic", "RMS of the correlated mic frame"),
+    ("direct_lag", "direct-path lag in samples, mic after reference"),
+    ("direct_r", "normalized correlation at the direct path"),
+    ("distance_m", "distance of the strongest echo"),
+    ("strength", "prominence of that echo, 0..1"),
+    ("echo_r_peak", "highest correlation in the echo band"),
+    ("echo_r_mean", "mean correlation over the echo band"),
+    ("echo_r_std", "standard deviation of the correlation over the echo band"),
+    ("echo_r_p95", "95th percentile of the correlation over the echo band"),
+    ("snr_db", "echo peak over the median |r| of the echo band, dB"),
+    ("peak_sidelobe", "echo peak over the strongest lag outside its neighbourhood"),
+    ("bearing_deg", "bearing of a voted echo (--bearing)"),
+    ("residual_rms", "RMS of the mic residual: the mic less the reference scaled and delayed to the direct path"),
+    ("spectral_flux", "half the L1 distance between this tick's and the last tick's normalized residual spectra in the band, 0..1"),
+    ("carrier_hz", "frequency of the strongest reference bin in the band"),
+    ("doppler_db", "residual energy within DOPPLER_HZ of the carrier (its own bins excluded) over the mic's energy at the carrier, dB"),
+    ("vote", "the tick counted as a presence vote"),
+    ("agree_pct", "share of the window that voted, once the window is full"),
+    ("present", "the smoothed presence state after the tick"),
+];
+
+/// Spectra are looked at from here up to Nyquist unless a probe band (`--corr-band`, a ping
+/// schedule) says where the signal is.
+const BAND_LO_HZ: f32 = 15000.0;
+/// Sidebands counted as Doppler: a person walking at 1.5 m/s shifts a 19 kHz tone by ~170 Hz.
+const DOPPLER_HZ: f32 = 200.0;
+
+/// The columns `--detector onnx` feeds its model, in this order, as a [1, n] float tensor:
+/// everything measured before the vote (so not the bearing), missing values as 0.
+pub const MODEL_INPUTS: &[&str] = &[
+    "rms_ref", "rms_mic", "direct_lag", "direct_r", "distance_m", "strength", "echo_r_peak", "echo_r_mean", "echo_r_std",
+    "echo_r_p95", "snr_db", "peak_sidelobe", "residual_rms", "spectral_flux", "carrier_hz", "doppler_db",
+];
+
+/// The features are computed for `--features` and for `--detector onnx`.
+pub fn wanted(cfg: &Config) -> bool {
+    !cfg.features.is_empty() || cfg.detector == DetectorKind::Onnx
+}
+
+/// The model's input for a tick, in `MODEL_INPUTS` order.
+pub fn model_input(rms: (f32, f32), m: &Measurement, f: &Features) -> Vec<f32> {
+    let or0 = |v: Option<f32>| v.unwrap_or(0.0);
+    vec![
+        rms.0,
+        rms.1,
+        m.direct_lag as f32,
+        m.direct_r,
+        m.distance_m,
+        m.strength,
+        or0(f.echo_r_peak),
+        or0(f.echo_r_mean),
+        or0(f.echo_r_std),
+        or0(f.echo_r_p95),
+        m.snr_db,
+        m.peak_sidelobe,
+        or0(f.residual_rms),
+        or0(f.spectral_flux),
+        or0(f.carrier_hz),
+        or0(f.doppler_db),
+    ]
+}
+
+/// The features `PresenceDetector::tick` adds for `--features`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Features {
+    pub echo_r_peak: Option<f32>,
+    pub echo_r_mean: Option<f32>,
+    pub echo_r_std: Option<f32>,
+    pub echo_r_p95: Option<f32>,
+    pub residual_rms: Option<f32>,
+    pub spectral_flux: Option<f32>,
+    pub carrier_hz: Option<f32>,
+    pub doppler_db: Option<f32>,
+}
+
+/// Computes `Features` tick by tick; keeps the last residual spectrum for the flux.
+pub struct Extractor {
+    planner: RealFftPlanner<f32>,
+    last: Option<Vec<f32>>,
+}
+
+impl Extractor {
+    /// None unless `--features` is set or `--detector onnx` needs them.
+    pub fn from_config(cfg: &Config) -> Option<Self> {
+        wanted(cfg).then(|| Self { planner: RealFftPlanner::new(), last: None })
+    }
+
+    /// After a gap in the audio the last spectrum says nothing about this one.
+    pub fn restart(&mut self) {
+        self.last = None;
+    }
+
+    /// `reference`/`mic` are the frames the correlation ran on, `m` what it found (with the
+    /// correlation kept), `band` the probe band if there is one.
+    pub fn extract(
+        &mut self,
+        reference: &[f32],
+        mic: &[f32],
+        sr: f32,
+        band: Option<(f32, f32)>,
+        m: Option<&Measurement>,
+        cfg: &Config
+    ) -> Features {
+        let mut f = Features::default();
+        let Some(m) = m else {
+            self.last = None;
+            return f;
+        };
+        echo_band_stats(m, sr, cfg, &mut f);
+
+        let n = reference.len().min(mic.len());
+        let residual = residual(&reference[..n], &mic[..n], m.direct_lag);
+        f.residual_rms = Some((residual.iter().map(|x| x * x).sum::<f32>() / (n.max(1) as f32)).sqrt());
+
+        let (lo, hi) = band.unwrap_or((BAND_LO_HZ, sr / 2.0));
+        let bin_hz = sr / (n as f32);
+        let bins = ((lo / bin_hz).ceil() as usize)..((hi / bin_hz).floor() as usize).min(n / 2);
+        if n < 2 || bins.is_empty() {
+            self.last = None;
+            return f;
+        }
+        let power = |planner: &mut RealFftPlanner<f32>, x: &[f32]| -> Vec<f32> {
+            power_spectrum(planner, x)[bins.clone()].to_vec()
+        };
+        let (ref_p, mic_p, res_p) = (power(&mut self.planner, &reference[..n]), power(&mut self.planner, &mic[..n]), power(&mut self.planner, &residual));
+
+        // flux of the normalized magnitude spectrum, so level changes alone do not count
+        let mut mag: Vec<f32> = res_p.iter().map(|p| p.sqrt()).collect();
+        let total = mag.iter().sum::<f32>().max(1e-12);
+        mag.iter_mut().for_each(|v| *v /= total);
+        if let Some(last) = self.last.as_ref().filter(|l| l.len() == mag.len()) {
+            f.spectral_flux = Some(mag.iter().zip(last).map(|(a, b)| (a - b).abs()).sum::<f32>() / 2.0);
+        }
+        self.last = Some(mag);
+
+        let (kc, _) = ref_p.iter().enumerate().fold((0, 0.0f32), |best, (k, &p)| if p > best.1 { (k, p) } else { best });
+        f.carrier_hz = Some(((bins.start + kc) as f32) * bin_hz);
+        let reach = ((DOPPLER_HZ / bin_hz).round() as usize).max(2);
+        let side: f32 = (kc.saturating_sub(reach)..(kc + reach + 1).min(res_p.len()))
+            .filter(|&k| k.abs_diff(kc) > 1)
+            .map(|k| res_p[k])
+            .sum();
+        f.doppler_db = Some(10.0 * (side.max(1e-20) / mic_p[kc].max(1e-20)).log10());
+        f
+    }
+}
+
+/// Statistics of the correlation over the lags echoes are searched in.
+fn echo_band_stats(m: &Measurement, sr: f32, cfg: &Config, f: &mut Features) {
+    let lag = |metres: f32| (((2.0 * metres) / 343.0) * sr).round() as i64;
+    let band: Vec<f32> = (m.direct_lag + lag(cfg.front_min_m)..=m.direct_lag + lag(cfg.front_max_m))
+        .filter_map(|k| m.r_at(k))
+        .collect();
+    if band.is_empty() {
+        return;
+    }
+    let n = band.len() as f32;
+    let mean = band.iter().sum::<f32>() / n;
+    let var = band.iter().map(|r| (r - mean).powi(2)).sum::<f32>() / n;
+    let mut sorted = band.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    f.echo_r_peak = sorted.last().copied();
+    f.echo_r_mean = Some(mean);
+    f.echo_r_std = Some(var.sqrt());
+    f.echo_r_p95 = Some(sorted[((n * 0.95) as usize).min(sorted.len() - 1)]);
+}
+
+/// The mic less the reference delayed by the direct path and scaled to it (least squares).
+fn residual(reference: &[f32], mic: &[f32], direct_lag: i64) -> Vec<f32> {
+    let n = mic.len();
+    let shifted = |i: usize| -> f32 {
+        let j = (i as i64) - direct_lag;
+        if j >= 0 && (j as usize) < n { reference[j as usize] } else { 0.0 }
+    };
+    let (mut xy, mut xx) = (0.0f64, 0.0f64);
+    for (i, &y) in mic.iter().enumerate() {
+        let x = shifted(i) as f64;
+        xy += x * (y as f64);
+        xx += x * x;
+    }
+    let gain = if xx > 0.0 { (xy / xx) as f32 } else { 0.0 };
+    mic.iter().enumerate().map(|(i, &y)| y - gain * shifted(i)).collect()
+}
+
+/// |X[k]|² of a Hann-windowed frame.
+fn power_spectrum(planner: &mut RealFftPlanner<f32>, x: &[f32]) -> Vec<f32> {
+    let n = x.len();
+    let fft = planner.plan_fft_forward(n);
+    let mut buf: Vec<f32> = x
+        .iter()
+        .enumerate()
+        .map(|(i, v)| v * (0.5 - 0.5 * ((2.0 * std::f32::consts::PI * (i as f32)) / (n as f32)).cos()))
+        .collect();
+    let mut spec = fft.make_output_vec();
+    if fft.process(&mut buf, &mut spec).is_err() {
+        spec.fill(Complex::new(0.0, 0.0));
+    }
+    spec.iter().map(|c| c.norm_sqr()).collect()
+}
+
+/// `--features`: the table, one row per tick, and its schema beside it.
+pub struct FeatureTable {
+    out: BufWriter<File>,
+    path: PathBuf,
+    tick: u64,
+    logger: Arc<Logger>,
+}
+
+impl FeatureTable {
+    /// None unless `--features` is set. An existing table is replaced.
+    pub fn open(cfg: &Config, logger: Arc<Logger>) -> Result<Option<Self>> {
+        if cfg.features.is_empty() {
+            return Ok(None);
+        }
+        let path = PathBuf::from(&cfg.features);
+        let schema = path.with_extension("schema.json");
+        output::write_atomic(&schema, &(schema_json() + "\n")).with_context(|| format!("writing {}", schema.display()))?;
+        let mut out = BufWriter::new(File::create(&path).with_context(|| format!("creating {}", path.display()))?);
+        writeln!(out, "{}", COLUMNS.iter().map(|c| c.0).collect::<Vec<_>>().join(","))?;
+        logger.info(&format!("per-tick features to {} (schema v{} in {})", path.display(), SCHEMA_VERSION, schema.display()))?;
+        Ok(Some(Self { out, path, tick: 0, logger }))
+    }
+
+    /// Call once per tick after the detector ran, like `DebugDump::tick`.
+    pub fn tick(&mut self, t_s: f64, meta: &TickMeta) {
+        if let Err(e) = self.write_tick(t_s, meta) {
+            let _ = self.logger.error(&format!("features {}: {}", self.path.display(), e));
+        }
+    }
+
+    fn write_tick(&mut self, t_s: f64, meta: &TickMeta) -> io::Result<()> {
+        self.tick += 1;
+        writeln!(self.out, "{}", row(self.tick, t_s, meta).join(","))?;
+        if self.tick.is_multiple_of(20) {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FeatureTable {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+fn row(tick: u64, t_s: f64, meta: &TickMeta) -> Vec<String> {
+    let cell = |v: Option<f32>, prec: usize| v.map(|v| format!("{:.*}", prec, v)).unwrap_or_default();
+    let f = meta.features.unwrap_or_default();
+    vec![
+        tick.to_string(),
+        format!("{:.3}", t_s),
+        cell(meta.rms.map(|r| r.0), 6),
+        cell(meta.rms.map(|r| r.1), 6),
+        meta.direct_lag.map(|l| l.to_string()).unwrap_or_default(),
+        cell(meta.direct_r, 4),
+        cell(meta.estimate.map(|e| e.0), 3),
+        cell(meta.estimate.map(|e| e.1), 4),
+        cell(f.echo_r_peak, 4),
+        cell(f.echo_r_mean, 4),
+        cell(f.echo_r_std, 4),
+        cell(f.echo_r_p95, 4),
+        cell(meta.snr_db, 2),
+        cell(meta.peak_sidelobe, 3),
+        cell(meta.bearing_deg, 1),
+        cell(f.residual_rms, 6),
+        cell(f.spectral_flux, 4),
+        cell(f.carrier_hz, 1),
+        cell(f.doppler_db, 2),
+        (meta.vote as u8).to_string(),
+        cell(meta.agree.map(|a| a * 100.0), 1),
+        (meta.present as u8).to_string(),
+    ]
+}
+
+fn schema_json() -> String {
+    let columns: Vec<String> = COLUMNS.iter().map(|(name, about)| JsonObj::new().str("name", name).str("description", about).finish()).collect();
+    JsonObj::new().str("table", "sonar-presence features").int("version", SCHEMA_VERSION as i64).str("format", "csv").arr("columns", &columns).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(freq: f32, sr: f32, n: usize, amp: f32) -> Vec<f32> {
+        (0..n).map(|i| amp * (2.0 * std::f32::consts::PI * freq * (i as f32) / sr).sin()).collect()
+    }
+
+    #[test]
+    fn residual_drops_the_direct_path_and_doppler_shows_in_the_sidebands() {
+        let (sr, n, lag) = (48000.0, 4800, 40usize);
+        let reference = tone(19000.0, sr, n, 0.5);
+        let mut still = vec![0.0; n];
+        for i in lag..n {
+            still[i] = 0.3 * reference[i - lag];
+        }
+        let r = residual(&reference, &still, lag as i64);
+        assert!(r[lag..].iter().all(|v| v.abs() < 1e-4));
+
+        let m = Measurement {
+            distance_m: 1.0,
+            strength: 0.5,
+            peak_lag: 320,
+            direct_lag: lag as i64,
+            direct_r: 0.9,
+            snr_db: 10.0,
+            peak_sidelobe: 2.0,
+            correlation: Some(vec![0.1; 1200]),
+            first_lag: 0,
+        };
+        let cfg = Config { features: "f.csv".to_string(), ..Config::default() };
+        let mut x = Extractor::from_config(&cfg).unwrap();
+        let quiet = x.extract(&reference, &still, sr, None, Some(&m), &cfg);
+        assert!((quiet.carrier_hz.unwrap() - 19000.0).abs() < 10.0);
+        assert_eq!(quiet.spectral_flux, None); // nothing to compare with yet
+        assert!((quiet.echo_r_mean.unwrap() - 0.1).abs() < 1e-6 && quiet.echo_r_std.unwrap() < 1e-6);
+
+        // someone walking: an echo 100 Hz above the carrier
+        let walking: Vec<f32> = still.iter().zip(tone(19100.0, sr, n, 0.02)).map(|(a, b)| a + b).collect();
+        let moved = x.extract(&reference, &walking, sr, None, Some(&m), &cfg);
+        assert!(moved.doppler_db.unwrap() > quiet.doppler_db.unwrap() + 20.0, "{:?} {:?}", quiet, moved);
+        assert!(moved.spectral_flux.unwrap() > 0.1);
+
+        let meta = TickMeta { features: Some(moved), ..TickMeta::default() };
+        assert_eq!(row(1, 0.1, &meta).len(), COLUMNS.len());
+        assert!(json_columns_match());
+    }
+
+    fn json_columns_match() -> bool {
+        let doc = crate::json::parse(&schema_json()).unwrap();
+        doc.get("columns").and_then(|c| c.as_array()).map(|c| c.len()) == Some(COLUMNS.len())
+    }
+}
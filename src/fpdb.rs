@@ -0,0 +1,535 @@
+//! src/fpdb.rs
+//! Binary fingerprint database (`--fp-db`): the songs of SongScan.csv in a compact file
+//! with an inverted index of `constellation_v2` landmark hashes, so gated mode only scores
+//! the few stored fingerprints that share landmarks with the live one.
+//!
+//! Layout (little-endian):
+//!   magic "SSFPDB", version u16, song count u32, fingerprint count u32, index length u32
+//!   per song: url (u32 length + UTF-8), windows (u32 count + f32 start_s/end_s pairs),
+//!             fingerprints (u32 count + each: type (u8 length + ASCII), bands u16, hop_s f32,
+//!             offset_s f32, bins (u32 length + bytes))
+//!   index: (hash u32, fingerprint u32, anchor frame u16) sorted by hash; fingerprints are
+//!          numbered in file order across all songs.
+
+use anyhow::{ bail, Result };
+use std::{
+    fs::{ self, File },
+    io::{ self, BufWriter, Write },
+    path::Path,
+};
+
+use crate::logger::Logger;
+use crate::mods::gated;
+use crate::prescan::{ self, Fingerprint, FpType, Segment };
+use crate::songscan;
+use crate::Config;
+
+const MAGIC: &[u8; 6] = b"SSFPDB";
+pub const VERSION: u16 = 1;
+
+/// Stored fingerprints scored exactly per live fingerprint; the rest of the index hits are dropped.
+const CANDIDATES: usize = 32;
+
+/// One stored song: gate windows plus its fingerprints.
+#[derive(Clone, Debug)]
+pub struct SongWindows {
+    pub url: String,
+    pub segs: Vec<(f32, f32)>, // [start_s, end_s]
+    pub fps: Vec<Fingerprint>, // lead-in first, then one every --fp-every-s
+}
+
+impl SongWindows {
+    pub fn new(url: &str, segs: &[Segment], fps: &[Fingerprint]) -> Self {
+        Self {
+            url: url.to_string(),
+            segs: segs
+                .iter()
+                .map(|s| (s.start_s, s.end_s))
+                .collect(),
+            fps: fps.to_vec(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FpDb {
+    pub songs: Vec<SongWindows>,
+    refs: Vec<(u32, u32)>, // fingerprint number → (song, fingerprint within the song)
+    index: Vec<(u32, u32, u16)>, // (landmark hash, fingerprint number, anchor frame), sorted
+}
+
+impl FpDb {
+    /// Build the index for `songs`.
+    pub fn new(songs: Vec<SongWindows>) -> Self {
+        let refs = fp_refs(&songs);
+        let mut index = Vec::new();
+        for (n, &(si, fi)) in refs.iter().enumerate() {
+            let fp = &songs[si as usize].fps[fi as usize];
+            for (h, t) in prescan::constellation_landmarks(fp) {
+                index.push((h, n as u32, t));
+            }
+        }
+        index.sort_unstable();
+        Self { songs, refs, index }
+    }
+
+    pub fn fingerprint_count(&self) -> usize {
+        self.refs.len()
+    }
+
+    /// Fingerprint types present, in order of first appearance.
+    pub fn fp_types(&self) -> Vec<FpType> {
+        let mut out: Vec<FpType> = Vec::new();
+        for t in self.songs
+            .iter()
+            .flat_map(|s| s.fps.iter())
+            .filter_map(|f| FpType::parse(&f.fp_type)) {
+            if !out.contains(&t) {
+                out.push(t);
+            }
+        }
+        out
+    }
+
+    /// Stored fingerprints worth scoring against `live`, as (song, fingerprint) in file order.
+    /// `constellation_v2` goes through the index: each live landmark votes for the offsets it
+    /// finds, and the fingerprints with the most votes within ±2 frames of one offset are kept.
+    /// Other types have no index, so every stored fingerprint of the same type is returned.
+    pub fn candidates(&self, live: &Fingerprint) -> Vec<(usize, usize)> {
+        if FpType::parse(&live.fp_type) != Some(FpType::ConstellationV2) {
+            return self.songs
+                .iter()
+                .enumerate()
+                .flat_map(|(si, s)| {
+                    s.fps
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, f)| f.fp_type == live.fp_type)
+                        .map(move |(fi, _)| (si, fi))
+                })
+                .collect();
+        }
+
+        // (fingerprint number, offset in frames); each live landmark counts once per offset
+        let mut hits: Vec<(u32, i32)> = Vec::new();
+        let mut seen: Vec<(u32, i32)> = Vec::new();
+        for (h, ta) in prescan::constellation_landmarks(live) {
+            seen.clear();
+            for key in prescan::tolerant_hashes(h) {
+                let lo = self.index.partition_point(|e| e.0 < key);
+                for &(_, n, tb) in self.index[lo..].iter().take_while(|e| e.0 == key) {
+                    let hit = (n, (tb as i32) - (ta as i32));
+                    if !seen.contains(&hit) {
+                        seen.push(hit);
+                        hits.push(hit);
+                    }
+                }
+            }
+        }
+        hits.sort_unstable();
+
+        // best windowed vote per fingerprint
+        let mut votes: Vec<(usize, u32)> = Vec::new();
+        let mut start = 0;
+        while start < hits.len() {
+            let n = hits[start].0;
+            let end = start + hits[start..].partition_point(|e| e.0 == n);
+            let offs = &hits[start..end];
+            let (mut lo, mut hi, mut best) = (0, 0, 0);
+            for &(_, o) in offs {
+                while offs[lo].1 < o - prescan::CONST_DT_TOL {
+                    lo += 1;
+                }
+                while hi < offs.len() && offs[hi].1 <= o + prescan::CONST_DT_TOL {
+                    hi += 1;
+                }
+                best = best.max(hi - lo);
+            }
+            votes.push((best, n));
+            start = end;
+        }
+        votes.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        votes.truncate(CANDIDATES);
+
+        let mut out: Vec<(usize, usize)> = votes
+            .into_iter()
+            .map(|(_, n)| {
+                let (si, fi) = self.refs[n as usize];
+                (si as usize, fi as usize)
+            })
+            .collect();
+        out.sort_unstable();
+        out
+    }
+
+    /// Read a database written by `save`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path)?;
+        let mut r = Reader { data: &data, pos: 0 };
+        if r.take(MAGIC.len())? != MAGIC {
+            bail!("{} is not a fingerprint database", path.display());
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            bail!("{}: unsupported fingerprint database version {} (expected {})", path.display(), version, VERSION);
+        }
+        let n_songs = r.u32()? as usize;
+        let n_fps = r.u32()? as usize;
+        let n_index = r.u32()? as usize;
+
+        let mut songs = Vec::with_capacity(n_songs.min(data.len()));
+        for _ in 0..n_songs {
+            let len = r.u32()? as usize;
+            let url = String::from_utf8(r.take(len)?.to_vec())?;
+            let mut segs = Vec::new();
+            for _ in 0..r.u32()? {
+                segs.push((r.f32()?, r.f32()?));
+            }
+            let mut fps = Vec::new();
+            for _ in 0..r.u32()? {
+                let len = r.u8()? as usize;
+                let fp_type = String::from_utf8(r.take(len)?.to_vec())?;
+                let bands = r.u16()? as usize;
+                let hop_s = r.f32()?;
+                let offset_s = r.f32()?;
+                let len = r.u32()? as usize;
+                let bins = r.take(len)?.to_vec();
+                fps.push(Fingerprint { fp_type, bands, hop_s, offset_s, bins });
+            }
+            songs.push(SongWindows { url, segs, fps });
+        }
+        let refs = fp_refs(&songs);
+        if refs.len() != n_fps {
+            bail!("{}: header says {} fingerprints, found {}", path.display(), n_fps, refs.len());
+        }
+
+        let mut index = Vec::with_capacity(n_index.min(data.len() / 10));
+        for _ in 0..n_index {
+            let entry = (r.u32()?, r.u32()?, r.u16()?);
+            if (entry.1 as usize) >= n_fps {
+                bail!("{}: index refers to fingerprint {} of {}", path.display(), entry.1, n_fps);
+            }
+            index.push(entry);
+        }
+        if !index.is_sorted() {
+            bail!("{}: index is not sorted", path.display());
+        }
+        Ok(Self { songs, refs, index })
+    }
+
+    /// Write the database (to a temp file, then rename, like SongScan.csv).
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        {
+            let mut w = BufWriter::new(File::create(&tmp)?);
+            w.write_all(MAGIC)?;
+            w.write_all(&VERSION.to_le_bytes())?;
+            w.write_all(&(self.songs.len() as u32).to_le_bytes())?;
+            w.write_all(&(self.refs.len() as u32).to_le_bytes())?;
+            w.write_all(&(self.index.len() as u32).to_le_bytes())?;
+            for s in &self.songs {
+                w.write_all(&(s.url.len() as u32).to_le_bytes())?;
+                w.write_all(s.url.as_bytes())?;
+                w.write_all(&(s.segs.len() as u32).to_le_bytes())?;
+                for &(start, end) in &s.segs {
+                    w.write_all(&start.to_le_bytes())?;
+                    w.write_all(&end.to_le_bytes())?;
+                }
+                w.write_all(&(s.fps.len() as u32).to_le_bytes())?;
+                for f in &s.fps {
+                    w.write_all(&[f.fp_type.len() as u8])?;
+                    w.write_all(f.fp_type.as_bytes())?;
+                    w.write_all(&(f.bands as u16).to_le_bytes())?;
+                    w.write_all(&f.hop_s.to_le_bytes())?;
+                    w.write_all(&f.offset_s.to_le_bytes())?;
+                    w.write_all(&(f.bins.len() as u32).to_le_bytes())?;
+                    w.write_all(&f.bins)?;
+                }
+            }
+            for &(h, n, t) in &self.index {
+                w.write_all(&h.to_le_bytes())?;
+                w.write_all(&n.to_le_bytes())?;
+                w.write_all(&t.to_le_bytes())?;
+            }
+            w.flush()?;
+        }
+        fs::rename(&tmp, path)
+    }
+}
+
+/// (song, fingerprint) for every stored fingerprint, in file order.
+fn fp_refs(songs: &[SongWindows]) -> Vec<(u32, u32)> {
+    songs
+        .iter()
+        .enumerate()
+        .flat_map(|(si, s)| (0..s.fps.len()).map(move |fi| (si as u32, fi as u32)))
+        .collect()
+}
+
+fn load_or_empty(path: &Path) -> Result<Vec<SongWindows>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(FpDb::load(path)?.songs)
+}
+
+/// Store a track in the database at `path`. Like SongScan.csv this is an upsert by url unless
+/// `append` is set, in which case the windows and fingerprints are added to the stored song.
+/// Returns the number of songs in the database.
+pub fn store_song(path: &Path, song: SongWindows, append: bool) -> Result<usize> {
+    let mut songs = load_or_empty(path)?;
+    upsert(&mut songs, song, append);
+    let n = songs.len();
+    FpDb::new(songs).save(path)?;
+    Ok(n)
+}
+
+fn upsert(songs: &mut Vec<SongWindows>, song: SongWindows, append: bool) {
+    match songs.iter_mut().find(|s| s.url == song.url) {
+        Some(s) if append => {
+            s.segs.extend(song.segs);
+            s.fps.extend(song.fps);
+        }
+        Some(s) => {
+            *s = song;
+        }
+        None => songs.push(song),
+    }
+}
+
+/// `--fp-db`: store a finished track next to its SongScan.csv rows (nothing to do without the flag).
+pub fn store_track(cli: &Config, tag: &str, segs: &[Segment], fps: &[Fingerprint], logger: &Logger) -> Result<()> {
+    if cli.fp_db.is_empty() {
+        return Ok(());
+    }
+    let db_path = Path::new(&cli.fp_db);
+    let csv_path = Path::new(&cli.scansong_path);
+    if db_path.exists() || !csv_path.exists() {
+        let n = store_song(db_path, SongWindows::new(tag, segs, fps), cli.scan_append)?;
+        logger.info(&format!("Stored {} in {} ({} song(s))", tag, cli.fp_db, n))?;
+        return Ok(());
+    }
+    // No database yet: start from every song in SongScan.csv, not just this one. The track's rows
+    // were stored there first, so it is usually among them already (with any appended windows).
+    let mut songs = gated::parse_scansong(csv_path, logger)?;
+    if !songs.iter().any(|s| s.url == tag) {
+        upsert(&mut songs, SongWindows::new(tag, segs, fps), false);
+    }
+    let n = songs.len();
+    FpDb::new(songs).save(db_path)?;
+    logger.info(&format!("Built fingerprint database {} from {} ({} song(s))", cli.fp_db, cli.scansong_path, n))?;
+    Ok(())
+}
+
+/// `--prune-url`: drop the song's rows from SongScan.csv and, with `--fp-db`, the song from the
+/// database. Returns what was removed, one line per file.
+pub fn prune_track(cli: &Config) -> Result<Vec<String>> {
+    let removed = songscan::prune_url(Path::new(&cli.scansong_path), &cli.prune_url)?;
+    let mut done = vec![format!("Removed {} row(s) for {} from {}", removed, cli.prune_url, cli.scansong_path)];
+    if !cli.fp_db.is_empty() && prune_url(Path::new(&cli.fp_db), &cli.prune_url)? {
+        done.push(format!("Removed {} from {}", cli.prune_url, cli.fp_db));
+    }
+    Ok(done)
+}
+
+/// Remove the song stored under `url` (`--prune-url`). Returns whether it was there.
+pub fn prune_url(path: &Path, url: &str) -> Result<bool> {
+    let mut songs = load_or_empty(path)?;
+    let before = songs.len();
+    songs.retain(|s| s.url != url);
+    if songs.len() == before {
+        return Ok(false);
+    }
+    FpDb::new(songs).save(path)?;
+    Ok(true)
+}
+
+/// Bounds-checked little-endian reader over the file contents.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            bail!("fingerprint database is truncated");
+        }
+        let out = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator;
+
+    const SR: f32 = 16_000.0;
+
+    fn song(url: &str, seed: u64) -> SongWindows {
+        let audio = simulator::music(SR, 16.0, seed);
+        let mut f = prescan::Fingerprinter::new(SR, 5.0, FpType::ConstellationV2, 5.0);
+        f.push(&audio);
+        SongWindows { url: url.to_string(), segs: vec![(1.0, 4.0), (9.5, 12.0)], fps: f.finish() }
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("fpdb-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("songs.fpdb")
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let path = temp_path("roundtrip");
+        let mut bandpeak = song("b,\"quoted\"", 2);
+        bandpeak.fps.push(prescan::make_fingerprint(&simulator::music(SR, 6.0, 2), SR, 5.0, FpType::BandPeakV1).unwrap());
+        let db = FpDb::new(vec![song("a", 1), bandpeak]);
+        db.save(&path).unwrap();
+
+        let loaded = FpDb::load(&path).unwrap();
+        assert_eq!(loaded.songs.len(), 2);
+        assert_eq!(loaded.fingerprint_count(), db.fingerprint_count());
+        assert_eq!(loaded.index, db.index);
+        assert!(!loaded.index.is_empty());
+        for (a, b) in loaded.songs.iter().zip(&db.songs) {
+            assert_eq!(a.url, b.url);
+            assert_eq!(a.segs, b.segs);
+            for (fa, fb) in a.fps.iter().zip(&b.fps) {
+                assert_eq!((&fa.fp_type, fa.bands, fa.hop_s, fa.offset_s, &fa.bins), (&fb.fp_type, fb.bands, fb.hop_s, fb.offset_s, &fb.bins));
+            }
+        }
+        assert_eq!(loaded.fp_types(), vec![FpType::ConstellationV2, FpType::BandPeakV1]);
+
+        // a future version or a foreign file is refused rather than misread
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[6] = 9;
+        fs::write(&path, &bytes).unwrap();
+        assert!(FpDb::load(&path).unwrap_err().to_string().contains("version 9"));
+        fs::write(&path, &bytes[..40]).unwrap();
+        assert!(FpDb::load(&path).is_err());
+        fs::write(&path, b"url,start_s\n").unwrap();
+        assert!(FpDb::load(&path).is_err());
+    }
+
+    #[test]
+    fn store_upserts_appends_and_prunes() {
+        let path = temp_path("store");
+        assert_eq!(store_song(&path, song("a", 1), false).unwrap(), 1);
+        assert_eq!(store_song(&path, song("b", 2), false).unwrap(), 2);
+        // re-scanning a song replaces it
+        assert_eq!(store_song(&path, song("a", 1), false).unwrap(), 2);
+        let n = FpDb::load(&path).unwrap().songs[0].fps.len();
+        // --scan-append adds to it instead
+        store_song(&path, song("a", 1), true).unwrap();
+        let db = FpDb::load(&path).unwrap();
+        assert_eq!(db.songs[0].fps.len(), 2 * n);
+        assert_eq!(db.songs[0].segs.len(), 4);
+
+        assert!(prune_url(&path, "a").unwrap());
+        assert!(!prune_url(&path, "a").unwrap());
+        let db = FpDb::load(&path).unwrap();
+        assert_eq!(db.songs.iter().map(|s| s.url.as_str()).collect::<Vec<_>>(), vec!["b"]);
+    }
+
+    /// SongScan.csv rows for `song`: one per window, each with its first fingerprint.
+    fn songscan_rows(song: &SongWindows) -> String {
+        let f = &song.fps[0];
+        song.segs
+            .iter()
+            .map(|(a, b)| {
+                format!("{},{:.3},{:.3},{},{},{:.5},{:.3},{}\n", song.url, a, b, f.fp_type, f.bands, f.hop_s, f.offset_s, songscan::to_hex(&f.bins))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_missing_database_is_seeded_from_songscan() {
+        let path = temp_path("seed");
+        let csv = path.with_file_name("SongScan.csv");
+        let (a, b) = (song("a", 1), song("b", 2));
+        let header = "url,start_s,end_s,fp_type,fp_bands,fp_hop_s,fp_offset_s,fp_bins_hex";
+        fs::write(&csv, format!("{}\n{}{}", header, songscan_rows(&a), songscan_rows(&b))).unwrap();
+        let cli = Config {
+            scansong_path: csv.to_string_lossy().into_owned(),
+            fp_db: path.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let logger = Logger::new(&path.with_file_name("Detection.log").to_string_lossy(), false).unwrap();
+
+        // "b" was just scanned (its rows are already in SongScan.csv); "a" came from an earlier scan
+        store_track(&cli, "b", &[], &b.fps, &logger).unwrap();
+        let db = FpDb::load(&path).unwrap();
+        assert_eq!(db.songs.iter().map(|s| s.url.as_str()).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(db.songs[1].segs, b.segs);
+
+        // once the database exists it is an upsert again
+        store_track(&cli, "c", &[], &song("c", 3).fps, &logger).unwrap();
+        assert_eq!(FpDb::load(&path).unwrap().songs.len(), 3);
+    }
+
+    #[test]
+    fn prune_url_clears_the_song_from_songscan_and_the_database() {
+        let path = temp_path("prune");
+        let csv = path.with_file_name("SongScan.csv");
+        let row = |url: &str| format!("{},1.000,4.000\n", url);
+        fs::write(&csv, format!("{}\n{}{}{}", songscan::SONGSCAN_HEADER, row("a"), row("b"), row("a"))).unwrap();
+        store_song(&path, song("a", 1), false).unwrap();
+        store_song(&path, song("b", 2), false).unwrap();
+        let cli = Config {
+            scansong_path: csv.to_string_lossy().into_owned(),
+            fp_db: path.to_string_lossy().into_owned(),
+            prune_url: "a".to_string(),
+            ..Config::default()
+        };
+
+        let done = prune_track(&cli).unwrap();
+        assert_eq!(done.len(), 2, "{:?}", done);
+        assert!(done[0].starts_with("Removed 2 row(s) for a"));
+        let text = fs::read_to_string(&csv).unwrap();
+        assert_eq!(text.lines().skip(1).collect::<Vec<_>>(), ["b,1.000,4.000"]);
+        let db = FpDb::load(&path).unwrap();
+        assert_eq!(db.songs.iter().map(|s| s.url.as_str()).collect::<Vec<_>>(), vec!["b"]);
+
+        // again: nothing left to remove, and the database is not reported
+        assert_eq!(prune_track(&cli).unwrap(), [format!("Removed 0 row(s) for a from {}", cli.scansong_path)]);
+    }
+
+    #[test]
+    fn index_finds_the_song_among_many() {
+        let db = FpDb::new((0..40).map(|i| song(&format!("song-{}", i), 100 + i)).collect());
+        let target = simulator::music(SR, 16.0, 100 + 23);
+        // 7 s of song 23 starting 6.4 s in
+        let start = (6.4 * SR) as usize;
+        let live = prescan::make_fingerprint(&target[start..start + (7.0 * SR) as usize], SR, 5.0, FpType::ConstellationV2).unwrap();
+
+        let cands = db.candidates(&live);
+        assert!(cands.len() <= CANDIDATES);
+        let (sim, si) = cands
+            .iter()
+            .map(|&(si, fi)| (prescan::fp_match(&live, &db.songs[si].fps[fi]).0, si))
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap();
+        assert_eq!(db.songs[si].url, "song-23");
+        assert!(sim > 0.6, "similarity {}", sim);
+    }
+}
@@ -0,0 +1,200 @@
+//! src/heartbeat.rs
+//! `--heartbeat-s`: a status record every N seconds whether or not the state changed, so
+//! consumers of Detection.csv/Detection.jsonl can tell "still absent" from "detector gone".
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{ Duration, Instant },
+};
+
+use crate::backpressure;
+use crate::logger::Logger;
+use crate::output::{ self, JsonObj, RotatingCsv };
+use crate::recorder::TickMeta;
+use crate::Config;
+
+pub const HEARTBEAT_CSV_HEADER: &str =
+    "timestamp,mode,present,uptime_s,last_distance_m,last_strength,last_measurement_age_s,ticks,analysed,votes,skipped,dropped_blocks,late_blocks";
+
+/// Health counters since the run started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub ticks: u64,
+    pub analysed: u64, // ticks that ran the correlation
+    pub votes: u64, // ...and counted as presence votes
+    pub skipped: u64, // ticks with nothing to analyse (gated out, buffers filling, a stalled stream)
+}
+
+/// Capture blocks of the mic and the loopback dropped or held up on their way to the rings
+/// (`--backpressure`), since the run started.
+fn capture_losses() -> (u64, u64) {
+    let (mic, reference) = (backpressure::counts("mic"), backpressure::counts("ref"));
+    (mic.0 + reference.0, mic.1 + reference.1)
+}
+
+/// Writes one row to `Heartbeat.csv` and one `heartbeat` event to `Detection.jsonl` every
+/// `--heartbeat-s`, beside the log file.
+pub struct Heartbeat {
+    every: Duration,
+    mode: &'static str,
+    started: Instant,
+    due: Instant,
+    counters: Counters,
+    last: Option<((f32, f32), Instant)>, // last echo estimate and when it came
+    present: bool,
+    csv: RotatingCsv,
+    jsonl: PathBuf,
+    logger: Arc<Logger>,
+}
+
+impl Heartbeat {
+    /// None unless `--heartbeat-s` is set.
+    pub fn open(cfg: &Config, mode: &'static str, logger: Arc<Logger>) -> anyhow::Result<Option<Self>> {
+        if cfg.heartbeat_s <= 0.0 {
+            return Ok(None);
+        }
+        let csv_path = output::sibling_path(&cfg.log_path, "Heartbeat.csv");
+        let csv = RotatingCsv::open(&csv_path, HEARTBEAT_CSV_HEADER, output::Rotation::from_config(cfg))?;
+        let every = Duration::from_secs_f32(cfg.heartbeat_s);
+        let now = Instant::now();
+        let _ = logger.info(&format!("heartbeat every {}s to {}", cfg.heartbeat_s, csv_path.display()));
+        Ok(
+            Some(Self {
+                every,
+                mode,
+                started: now,
+                due: now + every,
+                counters: Counters::default(),
+                last: None,
+                present: false,
+                csv,
+                jsonl: output::sibling_path(&cfg.log_path, "Detection.jsonl"),
+                logger,
+            })
+        )
+    }
+
+    /// Call once per tick with what the detector made of it; writes when a heartbeat is due.
+    pub fn tick(&mut self, meta: &TickMeta) {
+        self.tick_at(meta, Instant::now());
+    }
+
+    fn tick_at(&mut self, meta: &TickMeta, now: Instant) {
+        self.observe(meta, now);
+        if now < self.due {
+            return;
+        }
+        // a stalled loop skips the missed beats rather than writing them in a burst
+        while self.due <= now {
+            self.due += self.every;
+        }
+        let (csv, json) = self.records(now);
+        if let Err(e) = self.csv.write_line(&csv) {
+            let _ = self.logger.error(&format!("heartbeat: {}", e));
+        }
+        let _ = output::append_jsonl(&self.jsonl, &json);
+    }
+
+    fn observe(&mut self, meta: &TickMeta, now: Instant) {
+        self.counters.ticks += 1;
+        if meta.analysed {
+            self.counters.analysed += 1;
+        } else {
+            self.counters.skipped += 1;
+        }
+        if meta.vote {
+            self.counters.votes += 1;
+        }
+        if let Some(e) = meta.estimate {
+            self.last = Some((e, now));
+        }
+        self.present = meta.present;
+    }
+
+    /// The heartbeat as a Heartbeat.csv row and a Detection.jsonl event.
+    fn records(&self, now: Instant) -> (String, String) {
+        let ts = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let uptime_s = now.saturating_duration_since(self.started).as_secs_f64();
+        let age_s = self.last.map(|(_, at)| now.saturating_duration_since(at).as_secs_f64());
+        let c = self.counters;
+        let (dropped, late) = capture_losses();
+        let cell = |v: Option<f64>, prec: usize| v.map(|v| format!("{:.*}", prec, v)).unwrap_or_default();
+        let csv = format!(
+            "{},{},{},{:.0},{},{},{},{},{},{},{},{},{}",
+            ts,
+            self.mode,
+            self.present,
+            uptime_s,
+            cell(self.last.map(|((d, _), _)| d as f64), 2),
+            cell(self.last.map(|((_, s), _)| s as f64), 2),
+            cell(age_s, 1),
+            c.ticks,
+            c.analysed,
+            c.votes,
+            c.skipped,
+            dropped,
+            late
+        );
+        let json = JsonObj::new()
+            .str("ts", &ts)
+            .str("mode", self.mode)
+            .str("event", "heartbeat")
+            .bool("present", self.present)
+            .num("uptime_s", uptime_s)
+            .opt_num("last_distance_m", self.last.map(|((d, _), _)| d as f64))
+            .opt_num("last_strength", self.last.map(|((_, s), _)| s as f64))
+            .opt_num("last_measurement_age_s", age_s)
+            .int("ticks", c.ticks as i64)
+            .int("analysed", c.analysed as i64)
+            .int("votes", c.votes as i64)
+            .int("skipped", c.skipped as i64)
+            .int("dropped_blocks", dropped as i64)
+            .int("late_blocks", late as i64)
+            .finish();
+        (csv, json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn heartbeat_writes_on_schedule_with_counters() {
+        let dir = std::env::temp_dir().join(format!("sonar-heartbeat-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("Detection.log").to_string_lossy().into_owned();
+        let cfg = Config { heartbeat_s: 1.0, log_path: log_path.clone(), ..Config::default() };
+        let logger = Arc::new(Logger::new(&log_path, false).unwrap());
+        assert!(Heartbeat::open(&Config { heartbeat_s: 0.0, ..cfg.clone() }, "presence", logger.clone()).unwrap().is_none());
+        let mut hb = Heartbeat::open(&cfg, "presence", logger).unwrap().unwrap();
+
+        let t0 = hb.started;
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let voted = TickMeta { analysed: true, estimate: Some((0.8, 0.6)), vote: true, present: true, ..TickMeta::default() };
+        let idle = TickMeta { present: true, ..TickMeta::default() };
+        hb.tick_at(&voted, at(200));
+        hb.tick_at(&idle, at(500));
+        hb.tick_at(&idle, at(1000)); // first beat
+        hb.tick_at(&idle, at(1250));
+        hb.tick_at(&idle, at(3500)); // one beat for the stall, not two
+
+        let csv = fs::read_to_string(dir.join("Heartbeat.csv")).unwrap();
+        let rows: Vec<Vec<&str>> = csv.lines().map(|l| l.split(',').collect()).collect();
+        assert_eq!(rows.len(), 3, "{}", csv);
+        assert_eq!(rows[0].join(","), HEARTBEAT_CSV_HEADER);
+        assert_eq!(&rows[1][1..], ["presence", "true", "1", "0.80", "0.60", "0.8", "3", "1", "1", "2", "0", "0"]);
+        assert_eq!(&rows[2][6..], ["3.3", "5", "1", "1", "4", "0", "0"]);
+
+        let events: Vec<String> = fs::read_to_string(dir.join("Detection.jsonl")).unwrap().lines().map(String::from).collect();
+        assert_eq!(events.len(), 2);
+        let ev = crate::json::parse(&events[1]).unwrap();
+        assert_eq!(ev.get("event").and_then(|v| v.as_str()), Some("heartbeat"));
+        assert_eq!(ev.get("ticks").and_then(|v| v.as_f64()), Some(5.0));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
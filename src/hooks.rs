@@ -0,0 +1,171 @@
+//! src/hooks.rs
+//! External commands run when the smoothed presence state flips (`--on-enter` / `--on-exit`).
+
+use std::{
+    process::{ Command, Stdio },
+    sync::Arc,
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::sonar_presence::PresenceState;
+use crate::logger::Logger;
+use crate::Config;
+
+/// Snapshot passed to the hook process through `SONAR_*` environment variables.
+#[derive(Clone, Copy, Debug)]
+pub struct HookEvent {
+    pub present: bool,
+    pub state: PresenceState, // idle or active on entering
+    pub distance_m: f64,
+    pub strength: f64,
+    pub agree: f32,
+    pub probability: Option<f32>, // --calibration
+}
+
+pub struct Hooks {
+    on_enter: Option<String>,
+    on_exit: Option<String>,
+    mode: &'static str,
+    debounce: Duration,
+    timeout: Duration,
+    // state change waiting for the debounce period to elapse
+    pending: Option<(HookEvent, Instant)>,
+    // last state a hook actually fired for (None until the first flip)
+    fired: Option<bool>,
+    logger: Arc<Logger>,
+}
+
+impl Hooks {
+    pub fn new(cfg: &Config, mode: &'static str, logger: Arc<Logger>) -> Self {
+        let non_empty = |s: &String| if s.trim().is_empty() { None } else { Some(s.clone()) };
+        Self {
+            on_enter: non_empty(&cfg.on_enter_cmd),
+            on_exit: non_empty(&cfg.on_exit_cmd),
+            mode,
+            debounce: Duration::from_millis(cfg.hook_debounce_ms),
+            timeout: Duration::from_millis(cfg.hook_timeout_ms),
+            pending: None,
+            fired: None,
+            logger,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.on_enter.is_some() || self.on_exit.is_some()
+    }
+
+    /// Record a state flip. The hook fires from `poll` once the state has held for the debounce period.
+    pub fn state_changed(&mut self, ev: HookEvent) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.pending = Some((ev, Instant::now()));
+    }
+
+    /// Call once per tick; fires the pending hook when it survived the debounce period.
+    pub fn poll(&mut self) {
+        let Some((ev, since)) = self.pending else {
+            return;
+        };
+        if since.elapsed() < self.debounce {
+            return;
+        }
+        self.pending = None;
+        if self.fired == Some(ev.present) || (self.fired.is_none() && !ev.present) {
+            // flapped back to the state we already reported (or never left absent)
+            return;
+        }
+        self.fired = Some(ev.present);
+
+        let cmd = if ev.present { &self.on_enter } else { &self.on_exit };
+        if let Some(cmd) = cmd.clone() {
+            self.spawn(cmd, ev);
+        }
+    }
+
+    fn spawn(&self, cmd: String, ev: HookEvent) {
+        let logger = self.logger.clone();
+        let timeout = self.timeout;
+        let mode = self.mode;
+        thread::spawn(move || {
+            let which = if ev.present { "enter" } else { "exit" };
+            let _ = logger.info(&format!("hook({}) running: {}", which, cmd));
+
+            #[cfg(target_os = "windows")]
+            let mut command = {
+                let mut c = Command::new("cmd");
+                c.arg("/C").arg(&cmd);
+                c
+            };
+            #[cfg(not(target_os = "windows"))]
+            let mut command = {
+                let mut c = Command::new("sh");
+                c.arg("-c").arg(&cmd);
+                c
+            };
+
+            command
+                .env("SONAR_EVENT", which)
+                .env("SONAR_MODE", mode)
+                .env("SONAR_PRESENT", if ev.present { "1" } else { "0" })
+                .env("SONAR_STATE", ev.state.as_str())
+                .env(
+                    "SONAR_DISTANCE_M",
+                    if ev.distance_m.is_finite() {
+                        format!("{:.2}", ev.distance_m)
+                    } else {
+                        String::new()
+                    }
+                )
+                .env("SONAR_STRENGTH", format!("{:.3}", ev.strength))
+                .env("SONAR_CONFIDENCE", format!("{:.3}", ev.agree))
+                .env("SONAR_AGREE_PCT", format!("{:.0}", ev.agree * 100.0))
+                .env("SONAR_PROBABILITY", ev.probability.map(|p| format!("{:.3}", p)).unwrap_or_default())
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null());
+
+            let mut child = match command.spawn() {
+                Ok(c) => c,
+                Err(e) => {
+                    let _ = logger.error(&format!("hook({}) failed to start: {}", which, e));
+                    return;
+                }
+            };
+
+            let started = Instant::now();
+            loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => {
+                        if !status.success() {
+                            let _ = logger.warn(
+                                &format!("hook({}) exited with {:?}", which, status.code())
+                            );
+                        }
+                        return;
+                    }
+                    Ok(None) => {
+                        if started.elapsed() >= timeout {
+                            let _ = child.kill();
+                            let _ = child.wait();
+                            let _ = logger.warn(
+                                &format!(
+                                    "hook({}) killed after {} ms timeout",
+                                    which,
+                                    timeout.as_millis()
+                                )
+                            );
+                            return;
+                        }
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    Err(e) => {
+                        let _ = logger.error(&format!("hook({}) wait failed: {}", which, e));
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,174 @@
+//! src/hours.rs
+//! `--active-hours`: when the live modes listen at all. Outside the schedule the capture
+//! streams are closed (no mic in use, no loopback thread) and the loop only checks the clock,
+//! publishing a `paused` status until the next active stretch begins.
+
+use std::{ sync::Arc, time::Duration };
+
+use chrono::{ Datelike, NaiveDateTime, Timelike };
+
+use crate::logger::{ Field, Logger };
+use crate::output::JsonObj;
+use crate::Config;
+
+/// How often the clock is checked while the streams are closed.
+pub const CLOSED_POLL: Duration = Duration::from_secs(1);
+
+const DAYS: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+
+/// One `[DAYS ]HH:MM-HH:MM` rule. A range that ends before it starts runs past midnight and
+/// belongs to the day it starts on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HoursRule {
+    days: [bool; 7], // Monday first
+    from: u32, // minutes after midnight
+    to: u32, // 1440 for "24:00"
+}
+
+impl HoursRule {
+    /// `08:00-23:00`, `mon-fri 07:30-18:00`, `sat,sun 10:00-01:00`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let spec = spec.trim();
+        let (days, range) = match spec.rsplit_once(char::is_whitespace) {
+            Some((days, range)) => (parse_days(days.trim())?, range),
+            None => ([true; 7], spec),
+        };
+        let (from, to) = range.split_once('-').ok_or_else(|| format!("'{}': expected HH:MM-HH:MM", range))?;
+        let (from, to) = (parse_clock(from)?, parse_clock(to)?);
+        if from == to || from >= 1440 {
+            return Err(format!("'{}': empty range", range));
+        }
+        Ok(Self { days, from, to })
+    }
+
+    fn contains(&self, t: NaiveDateTime) -> bool {
+        let day = t.weekday().num_days_from_monday() as usize;
+        let minute = t.hour() * 60 + t.minute();
+        if self.from < self.to {
+            return self.days[day] && (self.from..self.to).contains(&minute);
+        }
+        // overnight: the evening of a listed day, or the morning after one
+        (self.days[day] && minute >= self.from) || (self.days[(day + 6) % 7] && minute < self.to)
+    }
+}
+
+/// `mon`, `mon-fri`, `fri-mon`, `sat,sun`, `mon-wed,fri` (or the full names).
+fn parse_days(s: &str) -> Result<[bool; 7], String> {
+    let day = |d: &str| {
+        let d = d.trim().to_lowercase();
+        DAYS.iter()
+            .position(|&name| d == name || d == name[..3])
+            .ok_or_else(|| format!("'{}': not a day (mon..sun)", d))
+    };
+    let mut days = [false; 7];
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((a, b)) => {
+                let (a, b) = (day(a)?, day(b)?);
+                let mut d = a;
+                loop {
+                    days[d] = true;
+                    if d == b {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            }
+            None => {
+                days[day(part)?] = true;
+            }
+        }
+    }
+    Ok(days)
+}
+
+fn parse_clock(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let (h, m) = s.split_once(':').ok_or_else(|| format!("'{}': expected HH:MM", s))?;
+    match (h.parse::<u32>(), m.parse::<u32>()) {
+        (Ok(h), Ok(m)) if (h < 24 && m < 60) || (h == 24 && m == 0) => Ok(h * 60 + m),
+        _ => Err(format!("'{}': not a time of day", s)),
+    }
+}
+
+/// Active at `t` (local time) under `rules`; no rules means always.
+pub fn active(rules: &[HoursRule], t: NaiveDateTime) -> bool {
+    rules.is_empty() || rules.iter().any(|r| r.contains(t))
+}
+
+/// The live loop's side: polled every tick, says when the streams should close or open again.
+pub struct ActiveHours {
+    rules: Vec<HoursRule>,
+    open: bool,
+    logger: Arc<Logger>,
+}
+
+impl ActiveHours {
+    /// None without --active-hours. The streams start open; the first poll closes them when
+    /// the run starts outside the schedule.
+    pub fn from_config(cfg: &Config, logger: Arc<Logger>) -> Option<Self> {
+        (!cfg.active_hours.is_empty()).then(|| Self { rules: cfg.active_hours.clone(), open: true, logger })
+    }
+
+    pub fn open(&self) -> bool {
+        self.open
+    }
+
+    /// Some(true) when an active stretch began, Some(false) when one ended.
+    pub fn poll(&mut self, now: NaiveDateTime) -> Option<bool> {
+        let open = active(&self.rules, now);
+        if open == self.open {
+            return None;
+        }
+        self.open = open;
+        let (msg, state) = if open {
+            ("active hours began: opening the audio streams", "active")
+        } else {
+            ("outside active hours: audio streams closed, detection paused", "paused")
+        };
+        let _ = self.logger.event(msg, &[("active_hours", Field::Str(state))]);
+        Some(open)
+    }
+}
+
+/// Status document (control `status`, gated status.json) while closed: the state held from
+/// before, and that nothing is being measured.
+pub fn paused_status(mode: &str, present: bool) -> String {
+    JsonObj::new()
+        .str("ts", &chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string())
+        .str("mode", mode)
+        .str("event", "paused")
+        .bool("present", present)
+        .str("state", "paused")
+        .str("reason", "active_hours")
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn rules_cover_days_and_run_past_midnight() {
+        // 2024-01-05 is a Friday
+        let at = |day: u32, h: u32, m: u32| NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(h, m, 0).unwrap();
+        let daily = vec![HoursRule::parse("08:00-23:00").unwrap()];
+        assert!(active(&daily, at(5, 8, 0)));
+        assert!(!active(&daily, at(5, 23, 0)));
+        assert!(!active(&daily, at(6, 7, 59)));
+
+        let week = vec![HoursRule::parse("mon-fri 07:30-18:00").unwrap(), HoursRule::parse("Sat,Sun 22:00-01:00").unwrap()];
+        assert!(active(&week, at(5, 12, 0))); // Friday
+        assert!(!active(&week, at(6, 12, 0))); // Saturday noon
+        assert!(active(&week, at(6, 23, 30))); // Saturday night
+        assert!(active(&week, at(8, 0, 30))); // Monday 00:30, still Sunday's night
+        assert!(!active(&week, at(8, 1, 0)));
+        assert!(active(&[], at(8, 3, 0)));
+
+        assert!(HoursRule::parse("fri-mon 00:00-24:00").is_ok());
+        assert!(HoursRule::parse("08:00-08:00").is_err());
+        assert!(HoursRule::parse("mon 8-9").is_err());
+        assert!(HoursRule::parse("someday 08:00-09:00").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! src/instance.rs
+//! One run on the audio devices at a time. Two instances on one mic (presence and impulse,
+//! say) hear each other's pings and both measure garbage, so the modes that open the devices
+//! hold an exclusive lock on `sonar-presence.lock` in the temp directory while they run. The
+//! OS drops the lock with the process, however it ends, so a crash leaves nothing to clean
+//! up. `sonar-presence.instance.json` beside it names the holder; `--takeover` asks the
+//! holder to shut down through its control interface and waits for the lock.
+
+use anyhow::{ Context, Result };
+use std::{
+    fs::{ self, File, OpenOptions, TryLockError },
+    io,
+    path::{ Path, PathBuf },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::logger::Logger;
+use crate::output::{ self, JsonObj };
+use crate::{ control, json, Config, Mode };
+
+const LOCK_FILE: &str = "sonar-presence.lock";
+const INFO_FILE: &str = "sonar-presence.instance.json";
+/// How long `--takeover` waits for the other instance to let go.
+const TAKEOVER_WAIT: Duration = Duration::from_secs(15);
+
+/// Held for the run; dropping it releases the devices to the next instance.
+pub struct InstanceLock {
+    _file: File,
+    info: PathBuf,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.info);
+    }
+}
+
+/// Who holds the lock, as it wrote down.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Holder {
+    pub pid: u32,
+    pub mode: String,
+    pub control: String, // its --control endpoint; empty = none to ask
+    pub started: String,
+}
+
+impl Holder {
+    fn to_json(&self) -> String {
+        JsonObj::new()
+            .int("pid", self.pid as i64)
+            .str("mode", &self.mode)
+            .str("control", &self.control)
+            .str("started", &self.started)
+            .finish()
+    }
+
+    fn from_json(text: &str) -> Option<Self> {
+        let doc = json::parse(text).ok()?;
+        let text_of = |k: &str| doc.get(k).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+        Some(Self { pid: doc.get("pid")?.as_f64()? as u32, mode: text_of("mode"), control: text_of("control"), started: text_of("started") })
+    }
+
+    fn describe(&self) -> String {
+        format!("PID {}, mode {}, since {}", self.pid, self.mode, self.started)
+    }
+}
+
+/// The modes that open the mic. A presence, gated or auto run on recorded files does not.
+pub fn needs_lock(cfg: &Config) -> bool {
+    let files = !cfg.replay_mic_wav.is_empty() && !cfg.replay_ref_wav.is_empty();
+    match cfg.mode {
+        Mode::Presence | Mode::Gated | Mode::Meter | Mode::Auto => !files,
+        Mode::Impulse | Mode::Play | Mode::SelfTest | Mode::Node => true,
+        _ => false,
+    }
+}
+
+/// The lock on `dir`'s lock file, or None while another process holds it.
+fn try_lock(dir: &Path) -> io::Result<Option<File>> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+fn holder(dir: &Path) -> Option<Holder> {
+    Holder::from_json(&fs::read_to_string(dir.join(INFO_FILE)).ok()?)
+}
+
+/// Lock `dir` for this process, taking it over from the holder with `takeover`.
+fn acquire_in(dir: &Path, cfg: &Config, logger: &Logger) -> Result<InstanceLock> {
+    let lock_path = dir.join(LOCK_FILE);
+    let mut file = try_lock(dir).with_context(|| format!("locking {}", lock_path.display()))?;
+    if file.is_none() {
+        let who = holder(dir);
+        let named = who.as_ref().map_or("another process".to_string(), |h| format!("another instance ({})", h.describe()));
+        if !cfg.takeover {
+            anyhow::bail!("{} is using the audio devices; stop it first, or pass --takeover to ask it to shut down", named);
+        }
+        let endpoint = who
+            .as_ref()
+            .map(|h| h.control.clone())
+            .filter(|c| !c.is_empty())
+            .with_context(|| format!("cannot take over from {}: it runs without --control, so there is no way to ask it", named))?;
+        let reply = control::request(&endpoint, "shutdown").with_context(|| format!("asking {} to shut down via {}", named, endpoint))?;
+        logger.info(&format!("--takeover: asked {} to shut down: {}", named, reply))?;
+        let t0 = Instant::now();
+        while file.is_none() {
+            if t0.elapsed() > TAKEOVER_WAIT {
+                anyhow::bail!("{} did not shut down within {} s", named, TAKEOVER_WAIT.as_secs());
+            }
+            thread::sleep(Duration::from_millis(100));
+            file = try_lock(dir)?;
+        }
+    }
+    let me = Holder {
+        pid: std::process::id(),
+        mode: cfg.mode.as_str().to_string(),
+        control: cfg.control_path.clone(),
+        started: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+    };
+    let info = dir.join(INFO_FILE);
+    output::write_atomic(&info, &me.to_json()).with_context(|| format!("writing {}", info.display()))?;
+    Ok(InstanceLock { _file: file.context("instance lock")?, info })
+}
+
+/// The instance lock for a mode that opens the audio devices; None for the others.
+pub fn acquire(cfg: &Config, logger: &Logger) -> Result<Option<InstanceLock>> {
+    if !needs_lock(cfg) {
+        return Ok(None);
+    }
+    acquire_in(&std::env::temp_dir(), cfg, logger).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_second_run_is_refused_and_named_until_the_first_ends() {
+        let dir = std::env::temp_dir().join(format!("sonar-instance-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let logger = Logger::new(&dir.join("Detection.log").to_string_lossy(), false).unwrap();
+        let cfg = Config { control_path: "/tmp/sonar.sock".to_string(), ..Config::default() };
+
+        let first = acquire_in(&dir, &cfg, &logger).unwrap();
+        let who = holder(&dir).unwrap();
+        assert_eq!((who.pid, who.mode.as_str(), who.control.as_str()), (std::process::id(), "presence", "/tmp/sonar.sock"));
+        let err = acquire_in(&dir, &cfg, &logger).err().unwrap().to_string();
+        assert!(err.contains(&format!("PID {}", std::process::id())) && err.contains("--takeover"), "{}", err);
+
+        drop(first);
+        assert!(holder(&dir).is_none());
+        assert!(acquire_in(&dir, &cfg, &logger).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,290 @@
+//! src/json.rs
+//! Minimal JSON reader for the documents this crate writes itself with `output::JsonObj`
+//! (sidecars, status files). No serde in this crate; numbers are read as f64.
+
+use anyhow::{ anyhow, bail, Result };
+
+use crate::output;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>), // in document order
+}
+
+impl Json {
+    /// Member `k` of an object.
+    pub fn get(&self, k: &str) -> Option<&Json> {
+        match self {
+            Json::Obj(members) => members.iter().find(|(name, _)| name == k).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Num(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Arr(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Back to compact JSON text.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            Json::Num(v) => out.push_str(&v.to_string()),
+            Json::Str(s) => output::push_json_str(out, s),
+            Json::Arr(items) => {
+                out.push('[');
+                for (i, v) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    v.write(out);
+                }
+                out.push(']');
+            }
+            Json::Obj(members) => {
+                out.push('{');
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    output::push_json_str(out, k);
+                    out.push(':');
+                    v.write(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+}
+
+/// Parse one JSON document; trailing non-whitespace is an error.
+pub fn parse(text: &str) -> Result<Json> {
+    let mut p = Parser { s: text.as_bytes(), i: 0 };
+    let v = p.value()?;
+    p.ws();
+    if p.i != p.s.len() {
+        bail!("trailing characters at byte {}", p.i);
+    }
+    Ok(v)
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    i: usize,
+}
+
+impl Parser<'_> {
+    fn ws(&mut self) {
+        while self.i < self.s.len() && matches!(self.s[self.i], b' ' | b'\t' | b'\n' | b'\r') {
+            self.i += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.ws();
+        self.s.get(self.i).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<()> {
+        if self.peek() != Some(c) {
+            bail!("expected '{}' at byte {}", c as char, self.i);
+        }
+        self.i += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, v: Json) -> Result<Json> {
+        if !self.s[self.i..].starts_with(word.as_bytes()) {
+            bail!("invalid literal at byte {}", self.i);
+        }
+        self.i += word.len();
+        Ok(v)
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        match self.peek().ok_or_else(|| anyhow!("unexpected end of input"))? {
+            b'{' => self.object(),
+            b'[' => self.array(),
+            b'"' => Ok(Json::Str(self.string()?)),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'n' => self.literal("null", Json::Null),
+            _ => self.number(),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        self.expect(b'{')?;
+        let mut members = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.i += 1;
+            return Ok(Json::Obj(members));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                bail!("expected a member name at byte {}", self.i);
+            }
+            let k = self.string()?;
+            self.expect(b':')?;
+            members.push((k, self.value()?));
+            match self.peek() {
+                Some(b',') => self.i += 1,
+                Some(b'}') => {
+                    self.i += 1;
+                    return Ok(Json::Obj(members));
+                }
+                _ => bail!("expected ',' or '}}' at byte {}", self.i),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        if self.peek() == Some(b']') {
+            self.i += 1;
+            return Ok(Json::Arr(items));
+        }
+        loop {
+            items.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.i += 1,
+                Some(b']') => {
+                    self.i += 1;
+                    return Ok(Json::Arr(items));
+                }
+                _ => bail!("expected ',' or ']' at byte {}", self.i),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let h = self.s
+            .get(self.i..self.i + 4)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u32::from_str_radix(h, 16).ok())
+            .ok_or_else(|| anyhow!("invalid \\u escape at byte {}", self.i))?;
+        self.i += 4;
+        Ok(h)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut out = String::new();
+        loop {
+            // copy the run up to the next quote or escape in one go (keeps UTF-8 intact)
+            let run = self.i;
+            while self.i < self.s.len() && !matches!(self.s[self.i], b'"' | b'\\') {
+                self.i += 1;
+            }
+            out.push_str(std::str::from_utf8(&self.s[run..self.i])?);
+            match self.s.get(self.i) {
+                None => bail!("unterminated string"),
+                Some(b'"') => {
+                    self.i += 1;
+                    return Ok(out);
+                }
+                _ => {}
+            }
+            let esc = *self.s.get(self.i + 1).ok_or_else(|| anyhow!("unterminated string"))?;
+            self.i += 2;
+            match esc {
+                b'"' => out.push('"'),
+                b'\\' => out.push('\\'),
+                b'/' => out.push('/'),
+                b'b' => out.push('\u{8}'),
+                b'f' => out.push('\u{c}'),
+                b'n' => out.push('\n'),
+                b'r' => out.push('\r'),
+                b't' => out.push('\t'),
+                b'u' => {
+                    let mut c = self.hex4()?;
+                    // surrogate pair
+                    if (0xd800..0xdc00).contains(&c) && self.s[self.i..].starts_with(b"\\u") {
+                        self.i += 2;
+                        let lo = self.hex4()?;
+                        c = 0x10000 + ((c - 0xd800) << 10) + (lo.wrapping_sub(0xdc00) & 0x3ff);
+                    }
+                    out.push(char::from_u32(c).unwrap_or('\u{fffd}'));
+                }
+                _ => bail!("invalid escape at byte {}", self.i - 1),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.i;
+        while self.i < self.s.len() && matches!(self.s[self.i], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+            self.i += 1;
+        }
+        let text = std::str::from_utf8(&self.s[start..self.i])?;
+        text.parse::<f64>()
+            .map(Json::Num)
+            .map_err(|_| anyhow!("invalid value at byte {}", start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::JsonObj;
+
+    #[test]
+    fn reads_what_jsonobj_writes() {
+        let doc = JsonObj::new()
+            .str("s", "tab\t \"quoted\" C:\\dir ünï")
+            .num("n", -12.5)
+            .bool("b", true)
+            .null("z")
+            .arr("a", &[JsonObj::new().int("k", 1).finish(), "2e3".to_string()])
+            .finish();
+        let v = parse(&doc).unwrap();
+        assert_eq!(v.get("s").and_then(Json::as_str), Some("tab\t \"quoted\" C:\\dir ünï"));
+        assert_eq!(v.get("n").and_then(Json::as_f64), Some(-12.5));
+        assert_eq!(v.get("b"), Some(&Json::Bool(true)));
+        assert_eq!(v.get("z"), Some(&Json::Null));
+        let a = v.get("a").and_then(Json::as_array).unwrap();
+        assert_eq!(a[0].get("k").and_then(Json::as_f64), Some(1.0));
+        assert_eq!(a[1].as_f64(), Some(2000.0));
+
+        assert_eq!(parse(r#" ["\u00e9\ud83d\ude00", {}] "#).unwrap(), Json::Arr(vec![Json::Str("é😀".into()), Json::Obj(vec![])]));
+        for bad in ["", "{", "[1,]", "{\"a\" 1}", "\"open", "tru", "[1] x"] {
+            assert!(parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
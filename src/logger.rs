@@ -1,7 +1,13 @@
-use std::fs::OpenOptions;
-use std::io::{ self, Write };
-use std::sync::Mutex;
+use std::fs::{ File, OpenOptions };
+use std::io::{ self, BufWriter, Write };
+use std::path::Path;
+use std::sync::{ atomic::{ AtomicU64, Ordering }, Arc };
+use std::thread;
 use chrono::Utc;
+use crossbeam_channel::{ bounded, Receiver, Sender, TrySendError };
+use crate::console;
+use crate::syslog::SystemLog;
+use crate::output::{ self, JsonObj, Rotation };
 //  order of log (Debug < Info < Warning < Error).
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LogLevel {
@@ -22,11 +28,114 @@ impl LogLevel {
     }
 }
 
+/// Layout of each Detection.log entry (`--log-format`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text, // `[ts UTC] [LEVEL] message`
+    Json, // one object per line: ts, level, module, message, fields
+}
+
+impl LogFormat {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Invalid log format '{}': use text or json", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// Where entries go (`--log-sink file,console,eventlog`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogSinks {
+    pub file: bool, // Detection.log
+    pub console: bool, // stderr, per --quiet/--verbose
+    pub system: bool, // Windows Event Log / syslog: warnings, errors and state changes
+}
+
+impl Default for LogSinks {
+    fn default() -> Self {
+        Self { file: true, console: true, system: false }
+    }
+}
+
+impl LogSinks {
+    /// Comma-separated; `syslog` is accepted for `eventlog`, both mean the platform's system log.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut sinks = Self { file: false, console: false, system: false };
+        for name in s.split(',').map(|n| n.trim().to_ascii_lowercase()) {
+            match name.as_str() {
+                "file" => {
+                    sinks.file = true;
+                }
+                "console" => {
+                    sinks.console = true;
+                }
+                "eventlog" | "syslog" => {
+                    sinks.system = true;
+                }
+                "" => {}
+                other => {
+                    return Err(format!("Invalid log sink '{}': use file, console or eventlog", other));
+                }
+            }
+        }
+        Ok(sinks)
+    }
+
+    pub fn as_str(&self) -> String {
+        let names = [(self.file, "file"), (self.console, "console"), (self.system, "eventlog")];
+        names
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// A structured value logged beside the message. JSON entries carry them under `fields`;
+/// text entries show only the message, which should say the same in words.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field<'a> {
+    Str(&'a str),
+    Num(f64), // non-finite becomes null
+    Int(i64),
+    Bool(bool),
+    Null,
+}
+
+/// Lines waiting for the writer thread; beyond this `log` drops them rather than block the caller.
+const QUEUE_LINES: usize = 4096;
+
+enum Msg {
+    Line(String),
+    Clear,
+    Rotation(Rotation),
+    Format(LogFormat),
+    System(LogLevel, String),
+    Flush(Sender<()>),
+}
+
+/// Appends to the log file from a background thread: `log` only formats the line and queues
+/// it, the writer keeps the file open and flushes once per batch of queued lines.
 pub struct Logger {
     file_path: String,
-    file_mutex: Mutex<()>,
     enabled: bool,
     min_level: LogLevel,
+    format: LogFormat,
+    console: Option<LogLevel>, // echo entries at or above this level to stderr
+    system: bool, // warnings, errors and events to the system log
+    tx: Sender<Msg>,
+    dropped: Arc<AtomicU64>, // lines lost to a full queue
 }
 
 impl Logger {
@@ -43,11 +152,21 @@ impl Logger {
             // ensure file exists
             OpenOptions::new().create(true).append(true).open(file_path)?;
         }
+        let (tx, rx) = bounded::<Msg>(QUEUE_LINES);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let (path, lost) = (file_path.to_string(), dropped.clone());
+        thread::Builder::new()
+            .name("logger".into())
+            .spawn(move || write_loop(&path, rx, &lost))?;
         Ok(Logger {
             file_path: file_path.to_string(),
-            file_mutex: Mutex::new(()),
             enabled,
             min_level,
+            format: LogFormat::Text,
+            console: None,
+            system: false,
+            tx,
+            dropped,
         })
     }
 
@@ -64,56 +183,95 @@ impl Logger {
         self.enabled && level >= self.min_level
     }
 
+    fn should_echo(&self, level: LogLevel) -> bool {
+        self.console.is_some_and(|min| level >= min)
+    }
+
+    /// Queue one line; never waits for the disk. A full queue drops the line and counts it.
+    #[track_caller]
     pub fn log(&self, level: LogLevel, message: &str) -> Result<(), io::Error> {
+        self.log_fields(level, message, &[])
+    }
+
+    /// `log` with structured fields for JSON logs.
+    #[track_caller]
+    pub fn log_fields(&self, level: LogLevel, message: &str, fields: &[(&str, Field)]) -> Result<(), io::Error> {
+        if self.system && level >= LogLevel::Warning {
+            self.send_system(level, message);
+        }
+        if self.should_echo(level) {
+            console::print_entry(level, message);
+        }
         if !self.should_log(level) {
             return Ok(());
         }
 
-        let _guard = self.file_mutex.lock().unwrap();
+        let module = caller_module(std::panic::Location::caller().file());
+        let formatted_message = entry(self.format, level, module, message, fields);
 
-        let timestamp = Utc::now();
-        let formatted_message = format!(
-            "[{}] [{}] {}\n",
-            timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
-            level.as_str(),
-            message
-        );
+        match self.tx.try_send(Msg::Line(formatted_message)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "log writer stopped")),
+        }
+    }
 
-        let mut file = OpenOptions::new().create(true).append(true).open(&self.file_path)?;
-        file.write_all(formatted_message.as_bytes())?;
-        file.flush()?;
-        Ok(())
+    /// A state change or similar milestone: logged at info, and sent to the system log too.
+    #[track_caller]
+    pub fn event(&self, message: &str, fields: &[(&str, Field)]) -> Result<(), io::Error> {
+        if self.system {
+            self.send_system(LogLevel::Info, message);
+        }
+        self.log_fields(LogLevel::Info, message, fields)
+    }
+
+    fn send_system(&self, level: LogLevel, message: &str) {
+        if self.tx.try_send(Msg::System(level, message.to_string())).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
     }
 
+    #[track_caller]
     pub fn log_fmt(&self, level: LogLevel, args: std::fmt::Arguments) -> Result<(), io::Error> {
-        if !self.should_log(level) {
+        if !self.should_log(level) && !self.should_echo(level) {
             return Ok(());
         }
         self.log(level, &format!("{}", args))
     }
 
+    #[track_caller]
     pub fn info(&self, message: &str) -> Result<(), io::Error> {
         self.log(LogLevel::Info, message)
     }
+    #[track_caller]
     pub fn warn(&self, message: &str) -> Result<(), io::Error> {
         self.log(LogLevel::Warning, message)
     }
+    #[track_caller]
     pub fn error(&self, message: &str) -> Result<(), io::Error> {
         self.log(LogLevel::Error, message)
     }
+    #[track_caller]
     pub fn debug(&self, message: &str) -> Result<(), io::Error> {
         self.log(LogLevel::Debug, message)
     }
 
+    #[track_caller]
     pub fn info_fmt(&self, args: std::fmt::Arguments) -> Result<(), io::Error> {
         self.log_fmt(LogLevel::Info, args)
     }
+    #[track_caller]
     pub fn warn_fmt(&self, args: std::fmt::Arguments) -> Result<(), io::Error> {
         self.log_fmt(LogLevel::Warning, args)
     }
+    #[track_caller]
     pub fn error_fmt(&self, args: std::fmt::Arguments) -> Result<(), io::Error> {
         self.log_fmt(LogLevel::Error, args)
     }
+    #[track_caller]
     pub fn debug_fmt(&self, args: std::fmt::Arguments) -> Result<(), io::Error> {
         self.log_fmt(LogLevel::Debug, args)
     }
@@ -122,9 +280,23 @@ impl Logger {
         if !self.enabled {
             return Ok(());
         }
-        let _guard = self.file_mutex.lock().unwrap();
-        std::fs::write(&self.file_path, "")?;
-        Ok(())
+        self.tx.send(Msg::Clear).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "log writer stopped"))?;
+        self.flush()
+    }
+
+    /// Block until every line queued so far is on disk.
+    pub fn flush(&self) -> Result<(), io::Error> {
+        let (ack_tx, ack_rx) = bounded(1);
+        self.tx
+            .send(Msg::Flush(ack_tx))
+            .ok()
+            .and_then(|_| ack_rx.recv().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "log writer stopped"))
+    }
+
+    /// Lines dropped so far because the writer fell behind.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
     }
 
     pub fn file_path(&self) -> &str {
@@ -142,6 +314,132 @@ impl Logger {
     pub fn set_min_level(&mut self, level: LogLevel) {
         self.min_level = level;
     }
+    pub fn set_rotation(&mut self, rotation: Rotation) {
+        let _ = self.tx.send(Msg::Rotation(rotation));
+    }
+    pub fn set_console(&mut self, level: Option<LogLevel>) {
+        self.console = level;
+    }
+    pub fn set_system(&mut self, on: bool) {
+        self.system = on;
+    }
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+    pub fn set_format(&mut self, format: LogFormat) {
+        self.format = format;
+        let _ = self.tx.send(Msg::Format(format));
+    }
+}
+
+impl Drop for Logger {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// The writer thread: drains whatever is queued, writes it through one buffered handle and
+/// flushes when the queue runs dry. Ends when the logger is dropped.
+fn write_loop(path: &str, rx: Receiver<Msg>, dropped: &AtomicU64) {
+    let mut rotation = Rotation::default();
+    let mut format = LogFormat::Text;
+    let mut system: Option<Option<SystemLog>> = None; // opened on first use; Some(None) = unavailable
+    let mut file: Option<BufWriter<File>> = None;
+    let mut reported = 0u64;
+    while let Ok(first) = rx.recv() {
+        if rotation.is_enabled() {
+            // close before renaming (Windows refuses to rename open files)
+            file = None;
+            // a failed rotation must not stop logging
+            let _ = output::rotate_if_due(Path::new(path), &rotation);
+        }
+        for msg in std::iter::once(first).chain(rx.try_iter()) {
+            match msg {
+                Msg::Line(line) => write_line(path, &mut file, &line),
+                Msg::Clear => {
+                    file = None;
+                    let _ = std::fs::write(path, "");
+                }
+                Msg::Rotation(r) => {
+                    rotation = r;
+                }
+                Msg::Format(f) => {
+                    format = f;
+                }
+                Msg::System(level, message) => {
+                    let sink = system.get_or_insert_with(|| {
+                        SystemLog::open()
+                            .map_err(|e| eprintln!("system log unavailable: {}", e))
+                            .ok()
+                    });
+                    if let Some(sink) = sink {
+                        let _ = sink.send(level, &message);
+                    }
+                }
+                Msg::Flush(ack) => {
+                    if let Some(f) = file.as_mut() {
+                        let _ = f.flush();
+                    }
+                    let _ = ack.send(());
+                }
+            }
+        }
+        let lost = dropped.load(Ordering::Relaxed);
+        if lost > reported {
+            let message = format!("logger: {} line(s) dropped, the writer could not keep up", lost - reported);
+            let line = entry(format, LogLevel::Warning, "logger", &message, &[("dropped", Field::Int((lost - reported) as i64))]);
+            write_line(path, &mut file, &line);
+            reported = lost;
+        }
+        if let Some(f) = file.as_mut() {
+            let _ = f.flush();
+        }
+    }
+}
+
+/// One log entry, newline included.
+fn entry(format: LogFormat, level: LogLevel, module: &str, message: &str, fields: &[(&str, Field)]) -> String {
+    let timestamp = Utc::now();
+    match format {
+        LogFormat::Text => format!("[{}] [{}] {}\n", timestamp.format("%Y-%m-%d %H:%M:%S UTC"), level.as_str(), message),
+        LogFormat::Json => {
+            let mut obj = JsonObj::new();
+            for &(k, v) in fields {
+                obj = match v {
+                    Field::Str(s) => obj.str(k, s),
+                    Field::Num(x) => obj.num(k, x),
+                    Field::Int(n) => obj.int(k, n),
+                    Field::Bool(b) => obj.bool(k, b),
+                    Field::Null => obj.null(k),
+                };
+            }
+            let line = JsonObj::new()
+                .str("ts", &timestamp.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())
+                .str("level", level.as_str())
+                .str("module", module)
+                .str("message", message)
+                .raw("fields", &obj.finish())
+                .finish();
+            format!("{}\n", line)
+        }
+    }
+}
+
+/// `src/mods/gated.rs` → `gated`.
+fn caller_module(file: &str) -> &str {
+    let name = file.rsplit(['/', '\\']).next().unwrap_or(file);
+    name.strip_suffix(".rs").unwrap_or(name)
+}
+
+fn write_line(path: &str, file: &mut Option<BufWriter<File>>, line: &str) {
+    if file.is_none() {
+        *file = OpenOptions::new().create(true).append(true).open(path).ok().map(BufWriter::new);
+    }
+    if let Some(f) = file.as_mut() {
+        if f.write_all(line.as_bytes()).is_err() {
+            *file = None; // reopen on the next line
+        }
+    }
 }
 
 #[macro_export]
@@ -180,3 +478,90 @@ macro_rules! log_debug {
         $logger.debug_fmt(format_args!($($arg)*))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn lines_reach_the_file_in_order() {
+        let path = std::env::temp_dir().join(format!("sonar-logger-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let logger = Logger::new_with_level(&path.to_string_lossy(), true, LogLevel::Info).unwrap();
+        for i in 0..100 {
+            logger.info(&format!("line {}", i)).unwrap();
+        }
+        logger.debug("below the level").unwrap();
+        logger.flush().unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!((lines.len(), logger.dropped()), (100, 0));
+        assert!(lines.iter().all(|l| l.contains("[INFO] line ")));
+        assert!(lines.first().unwrap().ends_with("line 0"));
+
+        logger.clear().unwrap();
+        logger.warn("after clear").unwrap();
+        drop(logger);
+        assert!(fs::read_to_string(&path).unwrap().trim_end().ends_with("[WARN] after clear"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn numbered_rotation_keeps_the_newest_files() {
+        let path = std::env::temp_dir().join(format!("sonar-logger-rot-{}.log", std::process::id()));
+        let archive = |n| output::numbered_path(&path, n);
+        for f in [path.clone(), archive(1), archive(2), archive(3)] {
+            let _ = fs::remove_file(f);
+        }
+        let mut logger = Logger::new(&path.to_string_lossy(), true).unwrap();
+        logger.set_rotation(Rotation { max_bytes: 1, keep_days: 0, keep_files: 2 });
+        // every batch finds the file over 1 byte, so each line ends up in a file of its own
+        for line in ["alpha", "bravo", "charlie", "delta"] {
+            logger.info(line).unwrap();
+            logger.flush().unwrap();
+        }
+        drop(logger);
+
+        let all: String = [path.clone(), archive(1), archive(2)]
+            .iter()
+            .filter_map(|f| fs::read_to_string(f).ok())
+            .collect();
+        assert!(!archive(3).exists());
+        assert!(archive(2).exists());
+        assert!(all.contains("delta") && all.contains("charlie"), "{}", all);
+        assert!(!all.contains("alpha"), "{}", all);
+        for f in [path.clone(), archive(1), archive(2)] {
+            let _ = fs::remove_file(f);
+        }
+    }
+
+    #[test]
+    fn json_entries_carry_module_and_fields() {
+        let path = std::env::temp_dir().join(format!("sonar-logger-json-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut logger = Logger::new(&path.to_string_lossy(), true).unwrap();
+        logger.set_format(LogFormat::Json);
+        logger
+            .log_fields(LogLevel::Info, "present=true", &[("present", Field::Bool(true)), ("avg_distance_m", Field::Num(0.75)), ("bearing_deg", Field::Null)])
+            .unwrap();
+        logger.warn("say \"hi\"").unwrap();
+        drop(logger);
+
+        let text = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = text.lines().map(|l| crate::json::parse(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2, "{}", text);
+        let first = &lines[0];
+        assert_eq!(first.get("level").and_then(|v| v.as_str()), Some("INFO"));
+        assert_eq!(first.get("module").and_then(|v| v.as_str()), Some("logger"));
+        assert_eq!(first.get("message").and_then(|v| v.as_str()), Some("present=true"));
+        let fields = first.get("fields").unwrap();
+        assert_eq!(fields.get("avg_distance_m").and_then(|v| v.as_f64()), Some(0.75));
+        assert_eq!(fields.get("present"), Some(&crate::json::Json::Bool(true)));
+        assert_eq!(lines[1].get("message").and_then(|v| v.as_str()), Some("say \"hi\""));
+        assert!(lines[1].get("ts").and_then(|v| v.as_str()).unwrap().ends_with('Z'));
+        assert_eq!(LogFormat::parse("JSON"), Ok(LogFormat::Json));
+        let _ = fs::remove_file(&path);
+    }
+}
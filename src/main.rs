@@ -2,21 +2,86 @@
 
 use anyhow::Result;
 use cpal::traits::{ DeviceTrait, HostTrait, StreamTrait };
-use crossbeam_channel::{ bounded, Receiver };
+use crossbeam_channel::Receiver;
 use std::{
     env,
-    fs::{ File, OpenOptions },
-    io::{ BufRead, BufReader, Write },
     path::Path,
-    sync::{ atomic::{ AtomicBool, Ordering }, Arc, Mutex },
-    thread,
+    sync::{ atomic::{ fence, AtomicBool, AtomicU32, AtomicU64, Ordering }, Arc },
     time::{ Duration, Instant },
 };
 
 mod logger;
 use logger::Logger;
 
-use crate::logger::LogLevel;
+mod output;
+
+mod hooks;
+
+mod autolock;
+
+mod metrics;
+
+mod songscan;
+
+mod csvio;
+
+mod fpdb;
+
+mod smtc;
+
+mod json;
+
+mod pingsched;
+
+mod control;
+
+mod audio;
+
+mod recorder;
+
+mod heartbeat;
+
+mod watchdog;
+
+mod power;
+
+mod strategy;
+
+mod correlator;
+
+mod hours;
+
+mod resume;
+
+mod calibration;
+
+mod profile;
+mod features;
+mod onnx;
+mod script;
+mod rules;
+mod micbusy;
+mod devcal;
+mod instance;
+mod node;
+mod discovery;
+mod auth;
+mod privacy;
+mod backpressure;
+mod supervise;
+mod winquality;
+mod scanscore;
+mod spectrogram;
+mod scanfeatures;
+
+mod console;
+
+mod syslog;
+
+#[cfg(test)]
+mod simulator;
+
+use crate::logger::{ LogFormat, LogLevel, LogSinks };
 
 // expose the split mode files in src/mods/
 mod mods;
@@ -26,6 +91,9 @@ mod mods;
 // ───────────────────────────────────────────────────────────────────────────────
 pub mod sonar_presence {
     use std::collections::VecDeque;
+    use std::time::{ Duration, Instant };
+
+    use crate::correlator::{ Correlator, FramePos };
 
     // Defaults (overridable via CLI) - now moved to Config::default()
     pub const TICK_MS: u64 = 250;
@@ -38,6 +106,21 @@ pub mod sonar_presence {
         ((1000 / (tick_ms as usize)) * (window_sec as usize)).max(1)
     }
 
+    /// Correlation frame length covering pipeline delay + echo range, rounded up to a power of two.
+    pub fn analysis_len(sr: f32, front_max_m: f32) -> usize {
+        let c = 343.0_f32;
+        let echo_max = (((2.0 * front_max_m) / c) * sr).ceil() as usize;
+        let base_max = (((MAX_PIPELINE_DELAY_MS as f32) / 1000.0) * sr).ceil() as usize;
+        (base_max + echo_max + 1024).next_power_of_two().max(4096)
+    }
+
+    /// Frame length that still holds the echo range after a direct path found up to `direct_last`
+    /// (`--power-save` with a locked delay); `analysis_len` for the full search.
+    pub fn frame_len(sr: f32, front_max_m: f32, direct_last: i64) -> usize {
+        let echo_max = (((2.0 * front_max_m) / 343.0) * sr).ceil() as usize;
+        ((direct_last.max(0) as usize) + echo_max + 1024).next_power_of_two().max(4096).min(analysis_len(sr, front_max_m))
+    }
+
     #[inline]
     fn l2norm_in_place(x: &mut [f32]) {
         let e =
@@ -67,14 +150,125 @@ pub mod sonar_presence {
         }
     }
 
-    /// Estimate (distance_m, strength) by correlating RENDER (ref) with MIC.
+    /// Keep only `lo..=hi` Hz of `x` (FFT brick-wall), so a probe band is correlated alone.
+    pub fn band_limit(x: &mut [f32], sr: f32, lo: f32, hi: f32) {
+        let n = x.len();
+        if n < 2 {
+            return;
+        }
+        let mut planner = realfft::RealFftPlanner::<f32>::new();
+        let r2c = planner.plan_fft_forward(n);
+        let c2r = planner.plan_fft_inverse(n);
+        let mut spec = r2c.make_output_vec();
+        let mut buf = x.to_vec();
+        if r2c.process(&mut buf, &mut spec).is_err() {
+            return;
+        }
+        let bin_hz = sr / (n as f32);
+        for (k, c) in spec.iter_mut().enumerate() {
+            let f = (k as f32) * bin_hz;
+            if f < lo || f > hi {
+                *c = realfft::num_complex::Complex::new(0.0, 0.0);
+            }
+        }
+        if c2r.process(&mut spec, x).is_ok() {
+            let scale = 1.0 / (n as f32);
+            for v in x.iter_mut() {
+                *v *= scale;
+            }
+        }
+    }
+
+    /// One ref↔mic correlation: the person echo and what it was picked from.
+    #[derive(Clone, Debug)]
+    pub struct Measurement {
+        pub distance_m: f32, // capped at dist_max_m
+        pub strength: f32, // echo prominence 0..1
+        pub peak_lag: i64, // echo lag in samples (mic after reference)
+        pub direct_lag: i64, // direct-path lag in samples; negative when the mic runs ahead (--max-lead-ms)
+        pub direct_r: f32, // correlation at the direct path: how well the mic hears the speaker
+        pub snr_db: f32, // echo peak over the median |r| of the echo band
+        pub peak_sidelobe: f32, // echo peak over the strongest lag outside its neighbourhood
+        pub correlation: Option<Vec<f32>>, // r[k] for k = first_lag..=kmax, when asked for
+        pub first_lag: i64, // lag of correlation[0]: the first lag searched
+    }
+
+    impl Measurement {
+        /// (distance_m, strength): what votes, logs and recordings keep.
+        pub fn pair(&self) -> (f32, f32) {
+            (self.distance_m, self.strength)
+        }
+
+        /// Correlation at `lag` samples, when it was kept and searched.
+        pub fn r_at(&self, lag: i64) -> Option<f32> {
+            let i = usize::try_from(lag - self.first_lag).ok()?;
+            self.correlation.as_ref()?.get(i).copied()
+        }
+
+        /// How much a strength can be trusted: what Detection.csv and the JSON events report.
+        pub fn quality(&self) -> CorrQuality {
+            CorrQuality { peak_sidelobe: self.peak_sidelobe, snr_db: self.snr_db, direct_r: self.direct_r }
+        }
+    }
+
+    /// Correlation quality of one tick. A clean echo has a peak well above its sidelobes and the
+    /// echo band's noise, over a direct path the mic clearly hears.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct CorrQuality {
+        pub peak_sidelobe: f32,
+        pub snr_db: f32,
+        pub direct_r: f32,
+    }
+
+    /// Direct-path lags searched without a delay lock: `--max-lead-ms` before the reference to
+    /// MAX_PIPELINE_DELAY_MS after it, in samples.
+    pub fn full_lags(config: &crate::Config, sr: f32) -> (i64, i64) {
+        let base_max = (((MAX_PIPELINE_DELAY_MS as f32) / 1000.0) * sr).round() as i64;
+        let lead = ((((config.max_lead_ms as f32) / 1000.0) * sr).round() as i64).min(base_max);
+        (-lead, base_max)
+    }
+
+    /// Correlate RENDER (ref) with MIC and pick the person echo after the direct path.
+    /// `keep_correlation` returns the normalized correlation along with it.
     pub fn estimate_from_ref(
         x_ref: &[f32],
         x_mic: &[f32],
         sr: f32,
         config: &crate::Config,
+        keep_correlation: bool,
         logger: Option<&crate::logger::Logger> // Add logger parameter
-    ) -> Option<(f32, f32)> {
+    ) -> Option<Measurement> {
+        estimate_in(x_ref, x_mic, sr, config, full_lags(config, sr), keep_correlation, logger)
+    }
+
+    /// `estimate_from_ref` with the direct path searched only over `direct` lags (first, last);
+    /// the echo range after it is searched wherever it lands.
+    pub fn estimate_in(
+        x_ref: &[f32],
+        x_mic: &[f32],
+        sr: f32,
+        config: &crate::Config,
+        direct: (i64, i64),
+        keep_correlation: bool,
+        logger: Option<&crate::logger::Logger>
+    ) -> Option<Measurement> {
+        estimate_with(&mut Correlator::new(), None, x_ref, x_mic, sr, config, direct, keep_correlation, logger)
+    }
+
+    /// `estimate_in` through a caller's `Correlator`: with the frames' ring positions `at`, the
+    /// products of the part this frame shares with the last one are reused.
+    #[allow(clippy::too_many_arguments)]
+    pub fn estimate_with(
+        corr: &mut Correlator,
+        at: Option<FramePos>,
+        x_ref: &[f32],
+        x_mic: &[f32],
+        sr: f32,
+        config: &crate::Config,
+        direct: (i64, i64),
+        keep_correlation: bool,
+        logger: Option<&crate::logger::Logger>
+    ) -> Option<Measurement> {
         let n = x_ref.len().min(x_mic.len());
         if n < 1024 {
             return None;
@@ -118,8 +312,6 @@ pub mod sonar_presence {
         dc_remove_in_place(&mut b);
         preemph_diff_in_place(&mut a);
         preemph_diff_in_place(&mut b);
-        l2norm_in_place(&mut a);
-        l2norm_in_place(&mut b);
 
         let c = 343.0_f32;
         let min_echo = (((2.0 * config.front_min_m) / c) * sr).round() as usize;
@@ -128,24 +320,18 @@ pub mod sonar_presence {
             return None;
         }
 
-        let base_max = (((MAX_PIPELINE_DELAY_MS as f32) / 1000.0) * sr).round() as usize;
-        let kmax = (base_max + max_echo).min(n - 1);
+        // a negative lag (--max-lead-ms) means the mic runs ahead; indices below are lag - first
+        let first = direct.0.max(-((n / 2) as i64));
+        let last = (direct.1 + (max_echo as i64)).min((n - 1) as i64);
+        if last <= first {
+            return None;
+        }
+        let kmax = (last - first) as usize;
 
-        // normalized cross-correlation r_xy[k] for k≥0
-        let mut rs = Vec::with_capacity(kmax + 1);
+        // normalized cross-correlation r_xy[k] for first ≤ k ≤ last
+        let rs = corr.normalized(&a, &b, first, last, at);
         let mut best0 = (0usize, -1.0f32);
-        for k in 0..=kmax {
-            let m = n - k;
-            let (mut num, mut ex, mut ey) = (0.0f32, 0.0f32, 0.0f32);
-            for i in 0..m {
-                let xr = a[i];
-                let yr = b[i + k];
-                num += xr * yr;
-                ex += xr * xr;
-                ey += yr * yr;
-            }
-            let r = num / (ex.sqrt() * ey.sqrt() + 1e-9);
-            rs.push(r);
+        for (k, &r) in rs.iter().enumerate() {
             if r > best0.1 {
                 best0 = (k, r);
             }
@@ -160,9 +346,9 @@ pub mod sonar_presence {
         }
 
         let mut best1 = (start, -1.0f32);
-        for k in start..=end {
-            if rs[k] > best1.1 {
-                best1 = (k, rs[k]);
+        for (k, &r) in rs.iter().enumerate().take(end + 1).skip(start) {
+            if r > best1.1 {
+                best1 = (k, r);
             }
         }
 
@@ -171,10 +357,8 @@ pub mod sonar_presence {
         let mut second = -1.0f32;
         for (i, &r) in rs[start..=end].iter().enumerate() {
             let idx = start + i;
-            if idx + neigh < best1.0 || idx.saturating_sub(neigh) > best1.0 {
-                if r > second {
-                    second = r;
-                }
+            if (idx + neigh < best1.0 || idx.saturating_sub(neigh) > best1.0) && r > second {
+                second = r;
             }
         }
         if second < 0.0 {
@@ -197,51 +381,680 @@ pub mod sonar_presence {
         let delta_k = (best1.0 - k0) as f32; // samples between direct path and person echo
         let dist_m = ((delta_k / sr) * 343.0_f32) / 2.0;
 
-        Some((dist_m.min(config.dist_max_m), prominence))
+        let mut mags: Vec<f32> = band.iter().map(|r| r.abs()).collect();
+        mags.sort_by(|a, b| a.total_cmp(b));
+        let floor = mags[mags.len() / 2].max(1e-9);
+        let snr_db = 20.0 * (best1.1.max(1e-9) / floor).log10();
+
+        Some(Measurement {
+            distance_m: dist_m.min(config.dist_max_m),
+            strength: prominence,
+            peak_lag: first + (best1.0 as i64),
+            direct_lag: first + (k0 as i64),
+            direct_r: best0.1,
+            snr_db,
+            peak_sidelobe: best1.1 / second.max(1e-3),
+            correlation: keep_correlation.then_some(rs),
+            first_lag: first,
+        })
+    }
+
+    /// Bearing of the echo `estimate_from_ref` found at `peak_lag`, from when it reaches each of
+    /// two mics `spacing_m` apart: degrees off straight ahead, positive toward `right`.
+    /// `band` limits both channels like the mono correlation; None when the lags disagree with the spacing.
+    pub fn echo_bearing(
+        x_ref: &[f32],
+        left: &[f32],
+        right: &[f32],
+        band: Option<(f32, f32)>,
+        peak_lag: i64,
+        sr: f32,
+        spacing_m: f32
+    ) -> Option<f32> {
+        let n = x_ref.len().min(left.len()).min(right.len());
+        let max_tdoa = ((spacing_m / 343.0) * sr).ceil() as usize + 1;
+        // an echo ahead of the reference (--max-lead-ms) is not searched per channel
+        let peak_lag = usize::try_from(peak_lag).ok()?;
+        if spacing_m <= 0.0 || peak_lag + max_tdoa + 1 >= n {
+            return None;
+        }
+        let prep = |x: &[f32]| {
+            let mut v = x[..n].to_vec();
+            if let Some((lo, hi)) = band {
+                band_limit(&mut v, sr, lo, hi);
+            }
+            dc_remove_in_place(&mut v);
+            preemph_diff_in_place(&mut v);
+            l2norm_in_place(&mut v);
+            v
+        };
+        let a = prep(x_ref);
+        // echo lag in one channel, to a fraction of a sample (parabola through the peak)
+        let lag_in = |x: &[f32]| -> f32 {
+            let b = prep(x);
+            let r = |k: usize| -> f32 {
+                let (mut num, mut ex, mut ey) = (0.0f32, 0.0f32, 0.0f32);
+                for i in 0..n - k {
+                    num += a[i] * b[i + k];
+                    ex += a[i] * a[i];
+                    ey += b[i + k] * b[i + k];
+                }
+                num / (ex.sqrt() * ey.sqrt() + 1e-9)
+            };
+            let lo = peak_lag.saturating_sub(max_tdoa).max(1);
+            let rs: Vec<f32> = (lo - 1..=peak_lag + max_tdoa + 1).map(r).collect();
+            let best = (1..rs.len() - 1).max_by(|&i, &j| rs[i].total_cmp(&rs[j])).unwrap_or(1);
+            let (y0, y1, y2) = (rs[best - 1], rs[best], rs[best + 1]);
+            let curve = y0 - 2.0 * y1 + y2;
+            let frac = if curve < 0.0 { (0.5 * (y0 - y2)) / curve } else { 0.0 };
+            ((lo - 1 + best) as f32) + frac.clamp(-0.5, 0.5)
+        };
+        let tdoa_s = (lag_in(left) - lag_in(right)) / sr;
+        let sine = (tdoa_s * 343.0) / spacing_m;
+        // a little past ±1 is rounding at endfire; far past it is a mispicked peak
+        if sine.abs() > 1.2 {
+            return None;
+        }
+        Some(sine.clamp(-1.0, 1.0).asin().to_degrees())
+    }
+
+    /// How the Aggregator turns per-tick votes into an agreement value.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum AggStrategy {
+        Window, // share of votes in the last `window_sec`, all weighted equally
+        Ewma, // exponentially weighted: recent votes count most (time constant `--ewma-tau-ms`)
+    }
+
+    impl AggStrategy {
+        pub fn parse(s: &str) -> Option<Self> {
+            match s.trim().to_lowercase().as_str() {
+                "window" => Some(AggStrategy::Window),
+                "ewma" => Some(AggStrategy::Ewma),
+                _ => None,
+            }
+        }
+
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                AggStrategy::Window => "window",
+                AggStrategy::Ewma => "ewma",
+            }
+        }
+    }
+
+    /// Running EWMA state: vote rate and vote-weighted distance/strength sums.
+    struct Ewma {
+        alpha: f32,
+        warmup: usize, // ticks before the first estimate (one time constant)
+        n: usize,
+        rate: f32,
+        sum_d: f32,
+        sum_s: f32,
+    }
+
+    /// Which distance the Aggregator reports for the votes in its window.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum DistStat {
+        Mean,
+        Median,
+        Trimmed, // mean without the TRIM_FRAC nearest and farthest votes
+        Cluster, // mean of the densest --cluster-bin-cm span of votes
+    }
+
+    impl DistStat {
+        pub fn parse(s: &str) -> Option<Self> {
+            match s.trim().to_lowercase().as_str() {
+                "mean" => Some(DistStat::Mean),
+                "median" => Some(DistStat::Median),
+                "trimmed" | "trimmed-mean" => Some(DistStat::Trimmed),
+                "cluster" => Some(DistStat::Cluster),
+                _ => None,
+            }
+        }
+
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                DistStat::Mean => "mean",
+                DistStat::Median => "median",
+                DistStat::Trimmed => "trimmed",
+                DistStat::Cluster => "cluster",
+            }
+        }
+    }
+
+    /// Share of votes dropped at each end for `DistStat::Trimmed`.
+    pub const TRIM_FRAC: f32 = 0.2;
+
+    /// Linear-interpolated quantile `q` of sorted `xs`.
+    fn quantile(xs: &[f32], q: f32) -> f32 {
+        let pos = q.clamp(0.0, 1.0) * ((xs.len() - 1) as f32);
+        let (i, frac) = (pos.floor() as usize, pos.fract());
+        let next = xs[(i + 1).min(xs.len() - 1)];
+        xs[i] + (next - xs[i]) * frac
+    }
+
+    /// Default `--cluster-bin-cm`, in metres.
+    pub const CLUSTER_BIN_M: f32 = 0.1;
+
+    /// Mean of the `bin_m`-wide span of sorted `xs` that holds the most values (the nearest on a tie).
+    fn densest_cluster(xs: &[f32], bin_m: f32) -> f32 {
+        let (mut best, mut end) = ((0, 0), 0);
+        for start in 0..xs.len() {
+            while end < xs.len() && xs[end] <= xs[start] + bin_m {
+                end += 1;
+            }
+            if end - start > best.1 - best.0 {
+                best = (start, end);
+            }
+        }
+        let kept = &xs[best.0..best.1];
+        kept.iter().sum::<f32>() / (kept.len() as f32)
+    }
+
+    /// (distance per `stat`, interquartile range) of the vote distances; infinite without votes.
+    /// `cluster_bin_m` is the span `DistStat::Cluster` looks for votes in.
+    pub fn distance_stats(ds: &mut [f32], stat: DistStat, cluster_bin_m: f32) -> (f64, f64) {
+        if ds.is_empty() {
+            return (f64::INFINITY, f64::INFINITY);
+        }
+        ds.sort_by(|a, b| a.total_cmp(b));
+        let center = match stat {
+            DistStat::Mean => ds.iter().sum::<f32>() / (ds.len() as f32),
+            DistStat::Median => quantile(ds, 0.5),
+            DistStat::Trimmed => {
+                let cut = ((ds.len() as f32) * TRIM_FRAC).floor() as usize;
+                let kept = &ds[cut..ds.len() - cut];
+                kept.iter().sum::<f32>() / (kept.len() as f32)
+            }
+            DistStat::Cluster => densest_cluster(ds, cluster_bin_m),
+        };
+        (center as f64, (quantile(ds, 0.75) - quantile(ds, 0.25)) as f64)
     }
 
     pub struct Aggregator {
-        window_sec: u32,
         cap: usize,
         history: VecDeque<Option<(f32, f32)>>,
         agg_frac: f32,
+        ewma: Option<Ewma>,
+        dist_stat: DistStat,
+        cluster_bin_m: f32,
     }
     impl Aggregator {
         pub fn new(window_sec: u32, tick_ms: u64, agg_frac: f32) -> Self {
             let cap = window_cap(window_sec, tick_ms);
             Self {
-                window_sec,
                 cap,
                 history: VecDeque::with_capacity(cap),
                 agg_frac,
+                ewma: None,
+                dist_stat: DistStat::Mean,
+                cluster_bin_m: CLUSTER_BIN_M,
+            }
+        }
+        /// Aggregator for `--agg-strategy`/`--dist-stat`; `tick_ms` is the caller's actual tick.
+        pub fn from_config(cfg: &crate::Config, tick_ms: u64) -> Self {
+            let agg = Self::new(cfg.window_sec, tick_ms, cfg.agg_frac)
+                .with_dist_stat(cfg.dist_stat)
+                .with_cluster_bin(cfg.cluster_bin_cm / 100.0);
+            match cfg.agg_strategy {
+                AggStrategy::Window => agg,
+                AggStrategy::Ewma => agg.with_ewma(cfg.ewma_tau_ms, tick_ms),
+            }
+        }
+        /// Switch to exponential weighting with time constant `tau_ms`.
+        pub fn with_ewma(mut self, tau_ms: u64, tick_ms: u64) -> Self {
+            let tick = tick_ms.max(1) as f32;
+            let tau = (tau_ms as f32).max(tick);
+            self.ewma = Some(Ewma {
+                alpha: 1.0 - (-tick / tau).exp(),
+                warmup: (tau / tick).ceil() as usize,
+                n: 0,
+                rate: 0.0,
+                sum_d: 0.0,
+                sum_s: 0.0,
+            });
+            self
+        }
+        /// Report the window's median or trimmed-mean distance instead of the mean.
+        pub fn with_dist_stat(mut self, stat: DistStat) -> Self {
+            self.dist_stat = stat;
+            self
+        }
+        /// Width of the span `DistStat::Cluster` gathers votes in.
+        pub fn with_cluster_bin(mut self, bin_m: f32) -> Self {
+            self.cluster_bin_m = bin_m;
+            self
+        }
+        /// Forget all votes (window refills from scratch).
+        pub fn clear(&mut self) {
+            self.history.clear();
+            if let Some(e) = self.ewma.as_mut() {
+                e.n = 0;
+                e.rate = 0.0;
+                e.sum_d = 0.0;
+                e.sum_s = 0.0;
             }
         }
-        /// Sliding window aggregator (updated every tick)
-        pub fn push(&mut self, vote: Option<(f32, f32)>) -> Option<(bool, f64, f64, f32)> {
+        /// Refill the window as if `agree` of it had voted `vote` (spread evenly through it):
+        /// a saved window's summary standing in for the votes themselves (`--resume-grace-s`).
+        pub fn seed(&mut self, agree: f32, vote: Option<(f32, f32)>) {
+            self.clear();
+            let agree = if vote.is_some() { agree.clamp(0.0, 1.0) } else { 0.0 };
+            if let Some(e) = self.ewma.as_mut() {
+                // as if the warmup had passed at this rate
+                e.n = e.warmup.max(1);
+                let filled = 1.0 - (1.0 - e.alpha).powi(e.n as i32);
+                let (d, s) = vote.unwrap_or((0.0, 0.0));
+                e.rate = agree * filled;
+                e.sum_d = e.rate * d;
+                e.sum_s = e.rate * s;
+            }
+            let voted = ((self.cap as f32) * agree).round() as usize;
+            for i in 0..self.cap {
+                let hit = ((i + 1) * voted) / self.cap > (i * voted) / self.cap;
+                self.history.push_back(vote.filter(|_| hit));
+            }
+        }
+        /// Sliding window aggregator (updated every tick):
+        /// (present, distance_m, strength, agreement, distance IQR in m).
+        pub fn push(&mut self, vote: Option<(f32, f32)>) -> Option<(bool, f64, f64, f32, f64)> {
             self.history.push_back(vote);
             while self.history.len() > self.cap {
                 self.history.pop_front();
             }
-            if self.history.len() < self.cap {
+            // median/trimmed distance and the spread always come from the votes of the last window
+            let mut ds: Vec<f32> = self.history
+                .iter()
+                .flatten()
+                .map(|&(d, _)| d)
+                .collect();
+            let (robust_d, iqr_d) = distance_stats(&mut ds, self.dist_stat, self.cluster_bin_m);
+
+            let (present, avg_d, avg_s, agree) = match self.ewma.as_mut() {
+                Some(e) => Self::push_ewma(e, self.agg_frac, vote)?,
+                None => Self::window_agreement(&self.history, self.cap, self.agg_frac)?,
+            };
+            let avg_d = if self.dist_stat == DistStat::Mean { avg_d } else { robust_d };
+            Some((present, avg_d, avg_s, agree, iqr_d))
+        }
+        fn window_agreement(
+            history: &VecDeque<Option<(f32, f32)>>,
+            cap: usize,
+            agg_frac: f32
+        ) -> Option<(bool, f64, f64, f32)> {
+            if history.len() < cap {
                 return None;
             }
 
             let mut cnt = 0usize;
             let (mut sum_d, mut sum_s) = (0.0f32, 0.0f32);
-            for v in self.history.iter() {
-                if let Some((d, s)) = v {
-                    cnt += 1;
-                    sum_d += *d;
-                    sum_s += *s;
-                }
+            for (d, s) in history.iter().flatten() {
+                cnt += 1;
+                sum_d += *d;
+                sum_s += *s;
             }
 
-            let agree = (cnt as f32) / (self.cap as f32);
-            let present = agree >= self.agg_frac;
+            let agree = (cnt as f32) / (cap as f32);
+            let present = agree >= agg_frac;
             let avg_d = if cnt > 0 { (sum_d / (cnt as f32)) as f64 } else { f64::INFINITY };
             let avg_s = if cnt > 0 { (sum_s / (cnt as f32)) as f64 } else { 0.0 };
             Some((present, avg_d, avg_s, agree))
         }
+        fn push_ewma(e: &mut Ewma, agg_frac: f32, vote: Option<(f32, f32)>) -> Option<(bool, f64, f64, f32)> {
+            let (hit, d, s) = match vote {
+                Some((d, s)) => (1.0, d, s),
+                None => (0.0, 0.0, 0.0),
+            };
+            e.rate += e.alpha * (hit - e.rate);
+            e.sum_d += e.alpha * (d - e.sum_d);
+            e.sum_s += e.alpha * (s - e.sum_s);
+            e.n += 1;
+            if e.n < e.warmup {
+                return None;
+            }
+            // the averages start from zero; divide out the weight the first ticks have not filled
+            let filled = 1.0 - (1.0 - e.alpha).powi(e.n as i32);
+            let agree = (e.rate / filled).clamp(0.0, 1.0);
+            let present = agree >= agg_frac;
+            let voted = e.rate > 1e-6;
+            let avg_d = if voted { (e.sum_d / e.rate) as f64 } else { f64::INFINITY };
+            let avg_s = if voted { (e.sum_s / e.rate) as f64 } else { 0.0 };
+            Some((present, avg_d, avg_s, agree))
+        }
+    }
+
+    /// Smoothed presence state: enter/exit thresholds on the window agreement
+    /// plus a minimum dwell time between flips.
+    pub struct Hysteresis {
+        pub present: bool,
+        enter_frac: f32,
+        exit_frac: f32,
+        min_dwell: Duration,
+        last_flip: Instant,
+    }
+    impl Hysteresis {
+        pub fn new(enter_frac: f32, exit_frac: f32, min_dwell_ms: u64) -> Self {
+            let min_dwell = Duration::from_millis(min_dwell_ms);
+            Self {
+                present: false,
+                enter_frac,
+                exit_frac,
+                min_dwell,
+                last_flip: Instant::now() - min_dwell,
+            }
+        }
+        /// Feed the window agreement; returns true when the smoothed state flipped.
+        pub fn update(&mut self, agree: f32, now: Instant) -> bool {
+            let want_present = if self.present {
+                agree >= self.exit_frac
+            } else {
+                agree >= self.enter_frac
+            };
+            if want_present != self.present && now.duration_since(self.last_flip) >= self.min_dwell {
+                self.present = want_present;
+                self.last_flip = now;
+                return true;
+            }
+            false
+        }
+        /// Change thresholds/dwell without touching the current state.
+        pub fn set_params(&mut self, enter_frac: f32, exit_frac: f32, min_dwell_ms: u64) {
+            self.enter_frac = enter_frac;
+            self.exit_frac = exit_frac;
+            self.min_dwell = Duration::from_millis(min_dwell_ms);
+        }
+        /// Back to "absent" with the dwell timer expired.
+        pub fn reset(&mut self) {
+            self.present = false;
+            self.last_flip = Instant::now() - self.min_dwell;
+        }
+    }
+
+    /// The presence state with the present side split by motion (`--active-spread-cm`).
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub enum PresenceState {
+        #[default]
+        Absent,
+        Idle, // present, the echo holding still
+        Active, // present and moving
+    }
+
+    impl PresenceState {
+        /// For a mode that does not follow motion: present counts as idle.
+        pub fn from_present(present: bool) -> Self {
+            if present { PresenceState::Idle } else { PresenceState::Absent }
+        }
+
+        pub fn present(&self) -> bool {
+            *self != PresenceState::Absent
+        }
+
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                PresenceState::Absent => "absent",
+                PresenceState::Idle => "idle",
+                PresenceState::Active => "active",
+            }
+        }
+    }
+
+    /// Splits a present state by how much the echo moves. Someone sitting still returns the
+    /// echo from the same distance tick after tick; someone moving spreads the window's vote
+    /// distances. A spread of `--active-spread-cm` or more is Active, and the state settles
+    /// back to Idle once it has stayed below that for `--active-hold-ms`.
+    pub struct Activity {
+        spread_m: f64, // 0 = never active
+        hold: Duration,
+        state: PresenceState,
+        moved: Option<Instant>, // last window that spread past the threshold
+    }
+    impl Activity {
+        pub fn new(cfg: &crate::Config) -> Self {
+            Self {
+                spread_m: (cfg.active_spread_cm as f64) / 100.0,
+                hold: Duration::from_millis(cfg.active_hold_ms),
+                state: PresenceState::Absent,
+                moved: None,
+            }
+        }
+
+        pub fn state(&self) -> PresenceState {
+            self.state
+        }
+
+        /// Feed each full window with the smoothed `present` and the spread of its vote
+        /// distances; Some(new state) when the state changed.
+        pub fn update(&mut self, present: bool, iqr_d: f64, now: Instant) -> Option<PresenceState> {
+            if !present {
+                self.moved = None;
+            } else if self.spread_m > 0.0 && iqr_d.is_finite() && iqr_d >= self.spread_m {
+                self.moved = Some(now);
+            }
+            let next = match self.moved {
+                _ if !present => PresenceState::Absent,
+                Some(at) if now.saturating_duration_since(at) < self.hold => PresenceState::Active,
+                _ => PresenceState::Idle,
+            };
+            if next == self.state {
+                return None;
+            }
+            self.state = next;
+            Some(next)
+        }
+
+        /// Take up a saved state (`--resume-grace-s`); active holds for `--active-hold-ms` from now.
+        pub fn restore(&mut self, state: PresenceState, now: Instant) {
+            self.state = state;
+            self.moved = (state == PresenceState::Active).then_some(now);
+        }
+    }
+
+    /// Least an estimate needs: this many direct-path lags, spread over a quarter of the window.
+    const DRIFT_MIN_POINTS: usize = 8;
+    /// A direct-path lag this far (in seconds) off the fitted trend is a mispick, not drift…
+    const DRIFT_GATE_S: f64 = 0.001;
+    /// …unless this many in a row are: then the path really moved (device restart) and the fit starts over.
+    const DRIFT_RESET_AFTER: u32 = 20;
+
+    /// Clock drift between mic and reference, followed through the direct-path lag.
+    /// The two devices' crystals disagree by some ppm, so over minutes the lag walks; a
+    /// straight line fitted to the last `--drift-window-s` of lags gives the drift, and
+    /// `shift()` is how many samples to read the reference earlier to hold the lag still.
+    pub struct DriftTracker {
+        window_s: f64, // 0 = off
+        sr: f32,
+        t0: Option<Instant>,
+        points: VecDeque<(f64, f64)>, // (t_s, uncompensated direct lag in samples)
+        fit: Option<(f64, f64)>, // (slope samples/s, lag at t = 0)
+        anchor: f64, // fitted lag the shift is measured from
+        shift: i64,
+        rejected: u32,
+        prior: Option<f64>, // ppm learned in an earlier run, followed until the first fit
+    }
+
+    impl DriftTracker {
+        pub fn new(window_s: f32) -> Self {
+            Self {
+                window_s: window_s.max(0.0) as f64,
+                sr: 0.0,
+                t0: None,
+                points: VecDeque::new(),
+                fit: None,
+                anchor: 0.0,
+                shift: 0,
+                rejected: 0,
+                prior: None,
+            }
+        }
+
+        /// Follow a drift learned in an earlier run (device calibration) until this one is fitted.
+        pub fn seed(&mut self, ppm: f64) {
+            if self.window_s > 0.0 {
+                self.prior = Some(ppm);
+            }
+        }
+
+        /// Feed the direct-path lag measured on frames read with the current `shift()`.
+        pub fn observe(&mut self, now: Instant, direct_lag: i64, sr: f32) {
+            if self.window_s <= 0.0 {
+                return;
+            }
+            self.sr = sr;
+            let t = now.saturating_duration_since(*self.t0.get_or_insert(now)).as_secs_f64();
+            let raw = (direct_lag as f64) + (self.shift as f64);
+
+            if let Some((slope, icpt)) = self.fit {
+                if (raw - (icpt + slope * t)).abs() > DRIFT_GATE_S * (sr as f64) {
+                    self.rejected += 1;
+                    if self.rejected < DRIFT_RESET_AFTER {
+                        return;
+                    }
+                    self.points.clear();
+                    self.fit = None;
+                }
+            }
+            self.rejected = 0;
+            self.points.push_back((t, raw));
+            while self.points.front().is_some_and(|&(t_old, _)| t - t_old > self.window_s) {
+                self.points.pop_front();
+            }
+
+            let span = self.points.back().map_or(0.0, |p| p.0) - self.points.front().map_or(0.0, |p| p.0);
+            if self.points.len() < DRIFT_MIN_POINTS || span < self.window_s / 4.0 {
+                if let (None, Some(ppm)) = (self.fit, self.prior) {
+                    self.shift = ((ppm * 1e-6 * (sr as f64)) * t).round() as i64;
+                }
+                return;
+            }
+            self.prior = None;
+            let n = self.points.len() as f64;
+            let (mt, ml) = self.points.iter().fold((0.0, 0.0), |(a, b), &(t, l)| (a + t / n, b + l / n));
+            let (sxy, sxx) = self.points
+                .iter()
+                .fold((0.0, 0.0), |(xy, xx), &(t, l)| (xy + (t - mt) * (l - ml), xx + (t - mt) * (t - mt)));
+            let slope = sxy / sxx.max(1e-12);
+            let icpt = ml - slope * mt;
+            if self.fit.is_none() {
+                // hold whatever shift is applied now; follow the trend from here
+                self.anchor = icpt + slope * t - (self.shift as f64);
+            }
+            self.fit = Some((slope, icpt));
+            self.shift = (icpt + slope * t - self.anchor).round() as i64;
+        }
+
+        /// Reference clock against the mic clock in ppm (positive: the lag grows), once fitted.
+        pub fn ppm(&self) -> Option<f64> {
+            self.fit.map(|(slope, _)| (slope / (self.sr as f64)) * 1e6)
+        }
+
+        /// Samples to read the reference earlier by (negative: later).
+        pub fn shift(&self) -> i64 {
+            self.shift
+        }
+    }
+
+    /// Direct-path lags locked on before the search narrows, once `--delay-lock-s` has passed.
+    const LOCK_MIN_POINTS: usize = 8;
+    /// A locked search whose direct path lands on its edge this many ticks in a row has lost it.
+    const LOCK_MISSES: u32 = 5;
+
+    /// The pipeline delay, learned from the direct-path lags of the first `--delay-lock-s` of
+    /// analysed ticks. Until then every lag up to MAX_PIPELINE_DELAY_MS is searched; once the
+    /// lags agree the direct path is only looked for within `--delay-margin-ms` of their median,
+    /// a fraction of the correlation work. When it keeps landing on the window's edge (a device
+    /// change moved it) the full search comes back and the delay is learned again.
+    pub struct DelayLock {
+        learn: usize, // lags to collect before locking; 0 = never lock
+        margin_ms: f32,
+        lags: Vec<i64>,
+        locked: Option<i64>,
+        misses: u32,
+    }
+
+    /// What `DelayLock::observe` changed.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum LockChange {
+        Locked(i64),
+        Lost,
+    }
+
+    impl DelayLock {
+        pub fn new(cfg: &crate::Config) -> Self {
+            let ticks = ((cfg.delay_lock_s * 1000.0) / (cfg.tick_ms.max(1) as f32)).ceil() as usize;
+            let learn = if cfg.delay_lock_s > 0.0 { ticks.max(LOCK_MIN_POINTS) } else { 0 };
+            Self { learn, margin_ms: cfg.delay_margin_ms, lags: Vec::new(), locked: None, misses: 0 }
+        }
+
+        fn margin(&self, sr: f32) -> i64 {
+            (((self.margin_ms / 1000.0) * sr).ceil() as i64).max(1)
+        }
+
+        /// Direct-path lags to search this tick.
+        pub fn lags(&self, cfg: &crate::Config, sr: f32) -> (i64, i64) {
+            match self.locked {
+                Some(lag) => (lag - self.margin(sr), lag + self.margin(sr)),
+                None => full_lags(cfg, sr),
+            }
+        }
+
+        /// Feed the direct-path lag of a tick searched with `lags()`.
+        pub fn observe(&mut self, direct_lag: i64, sr: f32) -> Option<LockChange> {
+            if self.learn == 0 {
+                return None;
+            }
+            let margin = self.margin(sr);
+            if let Some(lag) = self.locked {
+                if (direct_lag - lag).abs() < margin {
+                    self.misses = 0;
+                    return None;
+                }
+                self.misses += 1;
+                if self.misses < LOCK_MISSES {
+                    return None;
+                }
+                self.locked = None;
+                self.misses = 0;
+                return Some(LockChange::Lost);
+            }
+            self.lags.push(direct_lag);
+            if self.lags.len() < self.learn {
+                return None;
+            }
+            let mut sorted = self.lags.clone();
+            sorted.sort_unstable();
+            let median = sorted[sorted.len() / 2];
+            let (q1, q3) = (sorted[sorted.len() / 4], sorted[(sorted.len() * 3) / 4]);
+            // mispicks are fine as long as most lags agree; otherwise keep learning on newer ones
+            if q3 - q1 > margin {
+                self.lags.remove(0);
+                return None;
+            }
+            self.lags.clear();
+            self.locked = Some(median);
+            Some(LockChange::Locked(median))
+        }
+
+        /// The delay the search is locked to, in samples.
+        pub fn locked(&self) -> Option<i64> {
+            self.locked
+        }
+
+        /// Start out locked to a delay learned in an earlier run (device calibration); one that
+        /// no longer holds is lost like any other.
+        pub fn seed(&mut self, lag: i64) {
+            if self.learn > 0 {
+                self.lags.clear();
+                self.locked = Some(lag);
+                self.misses = 0;
+            }
+        }
+
+        /// Learn the delay again from scratch (after sleep, a session switch).
+        pub fn reset(&mut self) {
+            self.lags.clear();
+            self.locked = None;
+            self.misses = 0;
+        }
     }
 }
 
@@ -256,6 +1069,39 @@ pub enum Mode {
     Gated,
     Enrich,
     Impulse,
+    Replay,
+    Play,
+    Report,
+    SelfTest,
+    Meter,
+    Eval,
+    Tune,
+    Node,
+    Hub,
+    Auto,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Presence => "presence",
+            Mode::Scan => "scan",
+            Mode::Offline => "offline",
+            Mode::Gated => "gated",
+            Mode::Enrich => "enrich",
+            Mode::Impulse => "impulse",
+            Mode::Replay => "replay",
+            Mode::Play => "play",
+            Mode::Report => "report",
+            Mode::SelfTest => "selftest",
+            Mode::Meter => "meter",
+            Mode::Eval => "eval",
+            Mode::Tune => "tune",
+            Mode::Node => "node",
+            Mode::Hub => "hub",
+            Mode::Auto => "auto",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -265,6 +1111,14 @@ pub struct Config {
     pub tick_ms: u64,
     pub agg_frac: f32,
     pub window_sec: u32,
+    pub agg_strategy: sonar_presence::AggStrategy,
+    pub ewma_tau_ms: u64, // --agg-strategy ewma time constant
+    pub dist_stat: sonar_presence::DistStat, // reported window distance: mean | median | trimmed | cluster
+    pub cluster_bin_cm: f32, // --dist-stat cluster: width of the span votes are gathered in
+    pub detector: strategy::DetectorKind, // how a tick's frames are measured: xcorr | phat | onnx
+    pub model: String, // --detector onnx: classifier of the --features vector
+    pub model_thr: f32, // ...whose probability counts as a vote from here
+    pub policy: strategy::PolicyKind, // how votes become the presence state: hysteresis | majority
 
     // presence detection parameters (now configurable)
     pub min_dwell_ms: u64,
@@ -275,11 +1129,39 @@ pub struct Config {
     pub strength_thr: f32,
     pub dist_max_m: f32,
     pub min_ref_rms: f32,
+    pub ref_file: String, // known content played, read instead of the loopback
+    pub ref_offset_s: Option<f32>, // --ref-file position at startup; None = align by fingerprint
+    pub max_skew_ms: u64, // mic/reference arrival gap beyond which a tick is skipped
+    pub max_lead_ms: u32, // the mic may run this far ahead of the reference (direct path at negative lag)
+    pub watchdog_s: f32, // restart a capture stream that delivered too little for this long; 0 = never
+    pub drift_window_s: f32, // direct-path lags the clock drift is fitted over; 0 = no drift compensation
+    pub delay_lock_s: f32, // learn the pipeline delay over this much analysis, then search only near it; 0 = always search all
+    pub delay_margin_ms: f32, // locked search: this far either side of the learned delay
+    pub power_save: bool, // stretch the tick and shrink the frame while nobody has been there for a while
+    pub idle_after_s: f32, // --power-save: no vote for this long before the tick stretches
+    pub idle_tick_ms: u64, // --power-save: the longest tick
+    pub power_ramp_s: f32, // --power-save: the tick doubles this often on the way there
+    pub active_spread_cm: f32, // present counts as active while the window's echo distances spread this much; 0 = never
+    pub active_hold_ms: u64, // active settles to idle after this long without that spread
+    pub active_hours: Vec<hours::HoursRule>, // live modes listen only then (local time); empty = always
+    pub pause_on_mic_busy: bool, // live modes: close the streams while another application records (Windows)
+    pub device_cal: bool, // presence: keep what is learned about the render/capture pair, and start from it
+    pub device_profile: String, // --device-profile <NAME>: the room or headset that device calibration is kept under
+    pub resume_grace_s: f32, // live modes: a restart within this long carries on from the saved state; 0 = never
+    pub ping_schedules: Vec<String>, // enrich sidecars: probe band and ping times to correlate
+    pub corr_band: Option<(f32, f32)>, // --corr-band lo:hi Hz; None = the ping schedules' band, if any
+    pub bearing: bool, // keep both channels of a stereo mic and estimate the echo's bearing
+    pub mic_spacing_m: f32, // distance between the two mics, for --bearing
     pub min_rms: f32,
 
     // paths
     pub log_path: String,
     pub scansong_path: String,
+    pub fp_db: String, // binary fingerprint DB beside SongScan.csv; empty = CSV only
+    pub log_rotate_mb: f64,
+    pub log_keep_days: u32,
+    pub log_max_mb: f64, // Detection.log only: numbered archives above this size; 0 = use --log-rotate-mb
+    pub log_keep: u32, // numbered Detection.log archives kept
 
     // scan/offline params
     pub frame_ms: f32,
@@ -292,28 +1174,124 @@ pub struct Config {
     pub merge_gap_s: f32,
     pub clamp_min_s: f32,
     pub clamp_max_s: f32,
+    pub scan_weights: prescan::ScanWeights, // window score weights (--scan-weights)
+    pub scan_scorer: scanscore::ScorerKind, // how scan/offline score a window
+    pub scan_model: String, // --scan-scorer onnx: regressor of the window features
+    pub export_spectrogram: String, // scan/offline: write the analysed spectrogram here (.png or raw)
+    pub export_features: String, // offline: write every scored window's features here (JSON)
 
     // scan capture rate flag
     pub scan_sample_rate_hz: u32,
+    pub scan_capture_duration_s: f32, // 0 = until Ctrl+C
+    pub scan_silence_stop_s: f32, // 0 = no silence auto-stop
+    pub scan_silence_dbfs: f32,
+    pub scan_split_gap_s: f32, // 0 = one track per capture
+    pub scan_split_prompt: bool,
+    pub scan_append: bool, // false = replace earlier rows of the same url (upsert)
+    pub prune_url: String,
 
     // gated/fingerprint params
     pub fp_win_s: f32,
+    pub fp_type: prescan::FpType, // what scan/offline store; gated follows the stored type
+    pub fp_every_s: f32, // scan/offline: another fingerprint every N s of the track; 0 = lead-in only
     pub fp_thr: f32,
     pub fp_margin: f32,
     pub guard_s: f32,
+    pub window_min_quality: f32, // gated: drop windows scoring below this in WindowQuality.csv; 0 = keep all
     pub fp_arm_dbfs: f32,
+    pub realign_s: f32, // gated: re-fingerprint the aligned song every N s; 0 = align once
+    pub realign_misses: u32, // gated: consecutive failed re-checks that drop the alignment
+    pub smtc: bool, // gated: align from the Windows media session when it names a known song
     pub offline_sample_rate_hz: u32,
+    pub offline_start_s: f32,
+    pub offline_duration_s: f32,
+    pub resample_quality: decode::ResampleQuality,
+    pub channel_mix: audio::ChannelMix,
+    pub backpressure: backpressure::Backpressure, // live capture block that finds its queue full: drop-oldest | block | drop-newest
+    pub loopback: audio::LoopbackTarget, // render device or program the loopback reference taps
+    pub probe_tone: audio::ProbeMode, // inaudible sine into the output: off | on | auto (only without media)
+    pub probe_signal: audio::ProbeSignal, // sine | prbs (phase-modulated by a pseudo-random sequence)
+    pub probe_seed: u32, // prbs sequence, shared by the probe and the detector's template
+    pub probe_freq_hz: f32,
+    pub probe_amp: f32,
 
     pub enrich_song_path: String,
+    pub enrich_song_dir: String, // batch: folder or M3U playlist of songs to enrich
+    pub enrich_out_dir: String, // batch output folder; empty = <song-dir>_3pings beside it
+    pub enrich_jobs: usize, // batch: files enriched in parallel; 0 = one per CPU
     pub enrich_interval_length_s: f32,
     pub enrich_ping_length_s: f32,
+    pub enrich_ping_freq_hz: f32,
+    pub enrich_ping_level_db: f32, // peak level, dB relative to full scale
+    pub enrich_ping_waveform: mods::enrich::PingWaveform,
+    pub enrich_ping_placement: mods::enrich::PingPlacement,
+    pub enrich_mask_margin_db: f32, // masked placement: ping level below the masking content
     pub ffmpeg_path: String,
 
     pub impulse_listen_ms: u64,
     pub impulse_length_ms: f32,
     pub impulse_amplitude: f32,
+    pub impulse_probe: mods::impulse::ImpulseProbe,
+    pub impulse_probe_band: (f32, f32), // Hz, for the burst and chirp probes
+    pub impulse_avg: usize, // pulses per measurement, averaged coherently
+
+    // replay of recorded loopback/mic files
+    pub replay_ref_wav: String,
+    pub replay_mic_wav: String,
+    pub replay_speed: f32, // 1.0 = realtime, 0 = as fast as possible
+    pub replay_gated: bool, // replay mode: through gated mode's SongScan windows instead of the presence detector
+    pub record_session: String,
+    pub debug_dump: String, // per-tick feature table (CSV); empty = off
+    pub log_every_tick: bool, // Measurements.csv beside the log: distance, strength, agreement and state of every tick
+    pub privacy_strict: bool, // refuse every output that keeps what the mic heard; zero the capture rings at exit
+    pub features: String, // per-tick ML feature table (CSV, schema beside it); empty = off
+    pub labels: String, // replay: start_s,end_s rows when someone was there; the session is learned into --calibration
+    pub calibration: String, // agreement → probability curve (JSON); empty = report agreement only
+    pub tune_sessions: Vec<String>, // tune: directories of ref.wav, mic.wav and labels.csv
+
+    // occupancy report from Detection.csv
+    pub report_in: String, // empty = Detection.csv beside the log
+    pub report_by: mods::report::ReportBy,
+    pub report_format: mods::report::ReportFormat,
+    pub report_out: String, // empty = stdout
+
+    // external commands on presence flips
+    pub on_enter_cmd: String,
+    pub on_exit_cmd: String,
+    pub hook_debounce_ms: u64,
+    pub hook_timeout_ms: u64,
+    pub event_script: String, // long-running filter of measurement/state-change events; empty = off
+    pub event_script_timeout_ms: u64,
+    pub event_rules: String, // in-process rule file deciding the same events; empty = off
+
+    // Windows workstation lock on absence
+    pub auto_lock: bool,
+    pub lock_after_s: f32,
+
+    // Prometheus exporter
+    pub metrics_addr: String,
+    pub metrics_file: String,
+    pub api_token: String, // HTTP endpoints (--metrics-addr) answer only `Authorization: Bearer <token>`; empty = anyone
+    pub heartbeat_s: f32, // status record every N s regardless of state changes; 0 = off
+
+    // local control socket / named pipe
+    pub control_path: String,
+    pub takeover: bool, // ask the instance holding the audio devices to shut down (through its --control) instead of failing
+
+    // sensor nodes
+    pub server: String, // node mode: HOST:PORT of the presence instance to send measurements to
+    pub node_name: String, // node mode: how this node is named on the server
+    pub node_listen: String, // presence/hub: accept sensor nodes on HOST:PORT; empty = off
+    pub pair_token: String, // node and presence/hub: nodes and servers pair only when theirs match; empty = none
+    pub mdns: bool, // advertise --node-listen on mDNS
+    pub node_weights: Vec<(String, f32)>, // hub: how much each named node's vote counts (others: 1)
+    pub hub_quorum: f32, // hub: share of the heard nodes' weight that has to vote for the tick to count
+    pub hub_delay_ms: u64, // hub: how long a tick slot waits for the nodes' ticks before it is fused
 
     pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    pub verbosity: console::Verbosity,
+    pub log_sinks: LogSinks,
 }
 impl Default for Config {
     fn default() -> Self {
@@ -337,7 +1315,7 @@ impl Default for Config {
         //     .to_string_lossy()
         //     .into_owned();
 
-        println!("log path {}", default_log);
+        eprintln!("log path {}", default_log);
 
         let default_scansong = {
             let p = Path::new(&default_log);
@@ -351,7 +1329,18 @@ impl Default for Config {
             tick_ms: sonar_presence::TICK_MS,
             agg_frac: sonar_presence::AGG_FRAC,
             window_sec: sonar_presence::DEFAULT_WINDOW_SEC,
+            agg_strategy: sonar_presence::AggStrategy::Window,
+            ewma_tau_ms: 2000,
+            dist_stat: sonar_presence::DistStat::Mean,
+            cluster_bin_cm: sonar_presence::CLUSTER_BIN_M * 100.0,
+            detector: strategy::DetectorKind::Xcorr,
+            model: String::new(),
+            model_thr: 0.5,
+            policy: strategy::PolicyKind::Hysteresis,
             log_level: LogLevel::Info, // ADD THIS LINE
+            log_format: LogFormat::Text,
+            verbosity: console::Verbosity::Normal,
+            log_sinks: LogSinks::default(),
 
             // New presence detection defaults
             min_dwell_ms: 5000,
@@ -362,10 +1351,38 @@ impl Default for Config {
             strength_thr: 0.2,
             dist_max_m: 1.5,
             min_ref_rms: 0.0001,
+            ref_file: String::new(),
+            ref_offset_s: None,
+            max_skew_ms: 500,
+            max_lead_ms: 0,
+            watchdog_s: 5.0,
+            drift_window_s: 60.0,
+            delay_lock_s: 5.0,
+            delay_margin_ms: 5.0,
+            power_save: false,
+            idle_after_s: 60.0,
+            idle_tick_ms: 1000,
+            power_ramp_s: 10.0,
+            active_spread_cm: 20.0,
+            active_hold_ms: 5000,
+            active_hours: Vec::new(),
+            pause_on_mic_busy: false,
+            device_cal: true,
+            device_profile: "default".to_string(),
+            resume_grace_s: 60.0,
+            ping_schedules: Vec::new(),
+            corr_band: None,
+            bearing: false,
+            mic_spacing_m: 0.1,
             min_rms: 0.0002,
 
             log_path: default_log,
             scansong_path: default_scansong,
+            fp_db: String::new(),
+            log_rotate_mb: 0.0,
+            log_keep_days: 0,
+            log_max_mb: 0.0,
+            log_keep: 5,
 
             frame_ms: 23.0,
             scan_window_s: 3.0,
@@ -377,24 +1394,110 @@ impl Default for Config {
             merge_gap_s: 3.0,
             clamp_min_s: 3.0,
             clamp_max_s: 60.0,
+            scan_weights: prescan::ScanWeights::default(),
+            scan_scorer: scanscore::ScorerKind::Heuristic,
+            scan_model: String::new(),
+            export_spectrogram: String::new(),
+            export_features: String::new(),
 
             scan_sample_rate_hz: 48000,
+            scan_capture_duration_s: 0.0,
+            scan_silence_stop_s: 0.0,
+            scan_silence_dbfs: -50.0,
+            scan_split_gap_s: 0.0,
+            scan_split_prompt: false,
+            scan_append: false,
+            prune_url: String::new(),
 
             fp_win_s: 5.0,
+            fp_type: prescan::FpType::MelPeakV3,
+            fp_every_s: 5.0,
             fp_thr: 0.6,
             fp_margin: 0.07,
             guard_s: 0.5,
+            window_min_quality: 0.0,
             fp_arm_dbfs: -40.0,
+            realign_s: 10.0,
+            realign_misses: 2,
+            smtc: false,
 
             offline_sample_rate_hz: 0,
+            offline_start_s: 0.0,
+            offline_duration_s: 0.0,
+            resample_quality: decode::ResampleQuality::Sinc,
+            channel_mix: audio::ChannelMix::Average,
+            backpressure: backpressure::Backpressure::Block,
+            loopback: audio::LoopbackTarget::Default,
+            probe_tone: audio::ProbeMode::Off,
+            probe_signal: audio::ProbeSignal::Sine,
+            probe_seed: 1,
+            probe_freq_hz: 18_000.0,
+            probe_amp: 0.02,
 
             enrich_song_path: String::new(),
+            enrich_song_dir: String::new(),
+            enrich_out_dir: String::new(),
+            enrich_jobs: 0,
             enrich_interval_length_s: 1.0,
             enrich_ping_length_s: 0.1,
+            enrich_ping_freq_hz: 18500.0,
+            enrich_ping_level_db: -35.0,
+            enrich_ping_waveform: mods::enrich::PingWaveform::Sine,
+            enrich_ping_placement: mods::enrich::PingPlacement::Interval,
+            enrich_mask_margin_db: 12.0,
             ffmpeg_path: String::from(".\\ffmpeg\\bin\\ffmpeg.exe"),
             impulse_listen_ms: 400,
             impulse_length_ms: 50.0,
             impulse_amplitude: 0.6,
+            impulse_probe: mods::impulse::ImpulseProbe::Click,
+            impulse_probe_band: (17_000.0, 20_000.0),
+            impulse_avg: 1,
+
+            replay_ref_wav: String::new(),
+            replay_mic_wav: String::new(),
+            replay_speed: 1.0,
+            replay_gated: false,
+            record_session: String::new(),
+            debug_dump: String::new(),
+            log_every_tick: false,
+            privacy_strict: false,
+            features: String::new(),
+            labels: String::new(),
+            calibration: String::new(),
+            tune_sessions: Vec::new(),
+
+            report_in: String::new(),
+            report_by: mods::report::ReportBy::Day,
+            report_format: mods::report::ReportFormat::Csv,
+            report_out: String::new(),
+
+            on_enter_cmd: String::new(),
+            on_exit_cmd: String::new(),
+            hook_debounce_ms: 2000,
+            hook_timeout_ms: 10000,
+            event_script: String::new(),
+            event_script_timeout_ms: 200,
+            event_rules: String::new(),
+
+            auto_lock: false,
+            lock_after_s: 60.0,
+
+            metrics_addr: String::new(),
+            metrics_file: String::new(),
+            api_token: String::new(),
+            heartbeat_s: 0.0,
+
+            control_path: String::new(),
+            takeover: false,
+
+            server: String::new(),
+            node_name: node::host_name(),
+            node_listen: String::new(),
+            pair_token: String::new(),
+            mdns: true,
+            node_weights: Vec::new(),
+            hub_quorum: 0.5,
+            hub_delay_ms: 300,
         }
     }
 }
@@ -409,38 +1512,114 @@ fn print_usage(cfg: &Config) {
     println!("Usage: sonar_presence [OPTIONS]\n");
     println!("General paths:");
     println!("  --log-path <PATH>             Path to Detection.log (default: {})", cfg.log_path);
+    println!("  --profile <TOML>              Read key = value settings as flags here (later flags override them)");
+    println!("  --device-profile <NAME>       Room or headset the device calibration is kept under (default: {})", cfg.device_profile);
     println!(
         "  --scansong-path <PATH>        Path to SongScan.csv (default: {})",
         cfg.scansong_path
     );
+    println!(
+        "  --fp-db <PATH>                Binary fingerprint database: scan/offline also store songs there, gated matches from it (built from SongScan.csv if missing)"
+    );
     println!();
     println!(
         "  --log-level <LEVEL>           Log level: debug, info, warning, error (default: info)"
     );
-    println!("Modes:");
-    println!("  --mode presence       (default) Run ref↔mic presence detector");
-    println!("  --mode scan           Pre-scan loopback audio and export best segments");
-    println!("  --mode offline        Scan a local audio file directly (no playback)");
+    println!("  -q, --quiet                   Console: errors only, no live status line");
+    println!("  -v, --verbose                 Console: every log entry down to debug (default: warnings and errors)");
     println!(
-        "  --mode gated          Presence, but only inside SongScan windows after 5s fingerprint align\n"
+        "  --log-sink <LIST>             Comma-separated: file, console, eventlog (syslog off Windows) (default: {})",
+        cfg.log_sinks.as_str()
     );
-    println!("  --mode enrich         Add sonar pings to audio file using FFmpeg\n");
-    println!("  --mode impulse        Run impulse-based presence detector");
-
-    println!("Presence options:");
-    println!("  -tm, --tick-ms <MS>           Analyser tick in ms (default: {})", cfg.tick_ms);
     println!(
-        "  -af, --agg-frac <FRAC>        Fraction of votes in window for 'present' [0..1] (default: {:.2})",
-        cfg.agg_frac
+        "  --log-format text|json        Detection.log as text lines or one JSON object per line (default: {})",
+        cfg.log_format.as_str()
     );
     println!(
-        "  -ws, --window-sec <SEC>       Sliding window length in seconds (default: {})",
-        cfg.window_sec
+        "  --log-rotate-mb <MB>          Rotate Detection.log/Detection.csv above this size (default: off)"
     );
-
-    println!("\nPresence detection thresholds:");
     println!(
-        "  --min-dwell-ms <MS>           Minimum dwell time for state change (default: {})",
+        "  --log-keep-days <DAYS>        Roll logs over daily and delete rotated files older than this (default: keep all)"
+    );
+    println!(
+        "  --log-max-mb <MB>             Rotate Detection.log to Detection.log.1, .2 … above this size (default: off)"
+    );
+    println!("  --log-keep <N>                Numbered Detection.log archives to keep (default: {})", cfg.log_keep);
+    println!(
+        "  --channel-mix <MIX>           Multichannel → mono for files, mic and loopback: average, lr, or channel number (default: {})",
+        cfg.channel_mix.as_str()
+    );
+    println!(
+        "  --backpressure <POLICY>       Mic/loopback block that finds its queue full: drop-oldest, block or drop-newest (default: {})",
+        cfg.backpressure.as_str()
+    );
+    println!("  --loopback-device <ID|NAME>   Loopback this render device instead of the default (endpoint ID or part of its name)");
+    println!("  --loopback-process <PID|EXE>  Loopback only this program and its child processes (Windows 10 2004+)");
+    println!(
+        "  --probe-tone <on|off|auto>    Play an inaudible tone so the loopback has content; auto = only while no media plays (default: {})",
+        cfg.probe_tone.as_str()
+    );
+    println!(
+        "  --probe-signal <sine|prbs>    Probe waveform; prbs = carrier phase-flipped by a pseudo-random sequence (default: {})",
+        cfg.probe_signal.as_str()
+    );
+    println!("  --probe-seed <N>              Seed of the prbs sequence the probe plays and the detector expects (default: {})", cfg.probe_seed);
+    println!("  --probe-freq-hz <HZ>          Probe tone frequency (default: {:.0})", cfg.probe_freq_hz);
+    println!("  --probe-amp <AMP>             Probe tone amplitude, 1 = full scale (default: {})", cfg.probe_amp);
+    println!("Modes:");
+    println!("  --mode presence       (default) Run ref↔mic presence detector");
+    println!("  --mode scan           Pre-scan loopback audio and export best segments");
+    println!("  --mode offline        Scan a local audio file directly (no playback)");
+    println!(
+        "  --mode gated          Presence, but only inside SongScan windows after 5s fingerprint align\n"
+    );
+    println!("  --mode enrich         Add sonar pings to audio file using FFmpeg\n");
+    println!("  --mode impulse        Run impulse-based presence detector");
+    println!("  --mode replay         Run the presence detector on recorded ref/mic files");
+    println!("  --mode play           Play --input on the default output and detect against it (no loopback)");
+    println!("  --mode report         Occupancy statistics from Detection.csv (CSV or JSON)");
+    println!("  --mode selftest       Check mic, loopback, direct path and output paths; print a pass/fail report");
+    println!("  --mode meter          Live mic/loopback levels, direct-path delay and echo SNR, with setup hints");
+    println!("  --mode node           Sensor node: measure echoes here and send them to a presence instance or hub");
+    println!("  --mode hub            Fuse several sensor nodes' votes into one presence state (--node-listen)");
+    println!("  --mode eval           Replay recorded ref/mic files against --labels: precision, recall, detect latency, false flips");
+    println!("  --mode tune           Sweep thresholds over labelled sessions and write the best as a --profile TOML");
+    println!("  --mode auto           Presence while media plays, the inaudible impulse probe while the loopback is quiet");
+
+    println!("Presence options:");
+    println!("  -tm, --tick-ms <MS>           Analyser tick in ms (default: {})", cfg.tick_ms);
+    println!(
+        "  -af, --agg-frac <FRAC>        Fraction of votes in window for 'present' [0..1] (default: {:.2})",
+        cfg.agg_frac
+    );
+    println!(
+        "  -ws, --window-sec <SEC>       Sliding window length in seconds (default: {})",
+        cfg.window_sec
+    );
+    println!(
+        "  --agg-strategy window|ewma    Vote aggregation: sliding window or exponential weighting (default: {})",
+        cfg.agg_strategy.as_str()
+    );
+    println!("  --ewma-tau-ms <MS>            EWMA time constant (default: {})", cfg.ewma_tau_ms);
+    println!(
+        "  --dist-stat mean|median|trimmed|cluster  Reported distance of the window's votes (default: {})",
+        cfg.dist_stat.as_str()
+    );
+    println!("  --cluster-bin-cm <CM>         Span --dist-stat cluster gathers votes in (default: {})", cfg.cluster_bin_cm);
+    println!(
+        "  --detector xcorr|phat|onnx    Echo measurement: cross-correlation, PHAT-whitened, or cross-correlation voted by --model (default: {})",
+        cfg.detector.as_str()
+    );
+    println!("  --model <PATH>                --detector onnx: ONNX classifier of the --features vector (presence/play/replay)");
+    println!("  --model-thr <P>               Model probability that counts as a vote [0..1] (default: {:.2})", cfg.model_thr);
+    println!(
+        "  --policy hysteresis|majority  Presence decision: enter/exit thresholds + dwell, or the window alone (default: {})",
+        cfg.policy.as_str()
+    );
+
+    println!("\nPresence detection thresholds:");
+    println!(
+        "  --min-dwell-ms <MS>           Minimum dwell time for state change (default: {})",
         cfg.min_dwell_ms
     );
     println!(
@@ -472,6 +1651,53 @@ fn print_usage(cfg: &Config) {
         cfg.min_ref_rms
     );
     println!("  --min-rms <VAL>               Minimum mic RMS level (default: {:.5})", cfg.min_rms);
+    println!("  --ref-file <PATH>             Read the reference from this file (the content being played) instead of the loopback");
+    println!("  --ref-offset-s <SEC>          --ref-file position when the program starts (default: found by fingerprinting the mic)");
+    println!(
+        "  --max-skew-ms <MS>            Skip ticks while mic and reference arrive further apart than this (default: {})",
+        cfg.max_skew_ms
+    );
+    println!(
+        "  --max-lead-ms <MS>            Also search for the direct path up to MS before the reference, max {} (default: {})",
+        sonar_presence::MAX_PIPELINE_DELAY_MS,
+        cfg.max_lead_ms
+    );
+    println!(
+        "  --watchdog-s <SEC>            Restart a mic/loopback stream that stalls this long, 0 = off (default: {:.0})",
+        cfg.watchdog_s
+    );
+    println!(
+        "  --drift-window-s <SEC>        Fit mic/reference clock drift over this much history and compensate it, 0 = off (default: {:.0})",
+        cfg.drift_window_s
+    );
+    println!(
+        "  --delay-lock-s <SEC>          Learn the pipeline delay over SEC of ticks, then search only near it, 0 = off (default: {:.0})",
+        cfg.delay_lock_s
+    );
+    println!(
+        "  --delay-margin-ms <MS>        Locked search: this far either side of the learned delay (default: {:.0})",
+        cfg.delay_margin_ms
+    );
+    println!(
+        "  --active-spread-cm <CM>       Present splits into active while the window's echo distances spread this much, 0 = never (default: {:.0})",
+        cfg.active_spread_cm
+    );
+    println!("  --active-hold-ms <MS>         Active settles to idle after this long without that spread (default: {})", cfg.active_hold_ms);
+    println!("  --active-hours <RULES>        Live modes: listen only then, e.g. \"08:00-23:00\" or \"mon-fri 07:30-18:00; sat,sun 10:00-01:00\" (repeatable)");
+    println!("  --no-device-cal               Presence: neither load nor keep the learned delay, drift and empty-room echoes per device pair");
+    println!("  --pause-on-mic-busy           Live modes: suspend detection while another application records from a microphone (Windows)");
+    println!(
+        "  --resume-grace-s <SEC>        Live modes: restarted within this long, carry on from the state saved in resume.json, 0 = never (default: {:.0})",
+        cfg.resume_grace_s
+    );
+    println!("  --power-save                  Presence: tick slower and correlate shorter frames while nobody has been there for a while");
+    println!("  --idle-after-s <SEC>          --power-save: seconds without a vote before the tick stretches (default: {:.0})", cfg.idle_after_s);
+    println!("  --idle-tick-ms <MS>           --power-save: the longest tick (default: {})", cfg.idle_tick_ms);
+    println!("  --power-ramp-s <SEC>          --power-save: the tick doubles this often until it gets there (default: {:.0})", cfg.power_ramp_s);
+    println!("  --ping-schedule <FILE>        Enrich sidecar (.json): correlate only its ping band, in gated mode only during its pings (repeatable)");
+    println!("  --corr-band <LO>:<HI>         Band-pass ref and mic to LO-HI Hz before correlating (default: the ping schedule's band, else full band)");
+    println!("  --bearing                     Stereo mic: estimate the echo's bearing from the delay between the two channels");
+    println!("  --mic-spacing-m <M>           Distance between the two mics for --bearing (default: {})", cfg.mic_spacing_m);
 
     println!("\nScan/Offline options:");
     println!("  --frame-ms <MS>               Analysis frame size (default: {:.0})", cfg.frame_ms);
@@ -502,13 +1728,42 @@ fn print_usage(cfg: &Config) {
         "  --clamp-max-s <SEC>           Maximum segment length (default: {:.1})",
         cfg.clamp_max_s
     );
+    println!(
+        "  --scan-weights <LIST>         Window score weights, name=value,... (default: {})",
+        cfg.scan_weights.as_arg()
+    );
+    println!(
+        "  --scan-scorer heuristic|hf-energy|crest|onnx  Window score: weighted features, HF level, crest factor, or --scan-model (default: {})",
+        cfg.scan_scorer.as_str()
+    );
+    println!("  --scan-model <PATH>           --scan-scorer onnx: ONNX regressor of the window features");
+    println!("  --export-spectrogram <PATH>   Write the analysed spectrogram with the segments marked (.png image, else raw f32)");
+    println!("  --export-features <PATH>      (offline) Write every analysed window's features and z-scores as JSON");
     println!(
         "  --sample-rate, --sr <HZ>      (scan) Loopback capture sample rate (default: {})",
         cfg.scan_sample_rate_hz
     );
+    println!("  --capture-duration-s <SEC>    (scan) Stop capturing after this long (default: 0 = until Ctrl+C)");
+    println!(
+        "  --silence-stop-s <SEC>        (scan) Stop after this much silence once audio has played (default: 0 = off)"
+    );
+    println!(
+        "  --silence-dbfs <DB>           (scan) Level below which the loopback counts as silent (default: {:.0})",
+        cfg.scan_silence_dbfs
+    );
+    println!(
+        "  --split-gap-s <SEC>           (scan) Start a new track after a gap this long below --silence-dbfs (default: 0 = off)"
+    );
+    println!(
+        "  --split-prompt                (scan) Ask for each split track's URL on stdin (default: auto-number URL#01, #02…)"
+    );
     println!("  --scan-url <URL>              Tag CSV rows with this URL");
     println!(
-        "  --input <PATH>                (offline) Audio file to analyze (.wav/.mp3/.mp4/.m4a)\n"
+        "  --scan-append                 Keep earlier rows of the same url/file (default: replace them)"
+    );
+    println!("  --prune-url <URL>             Delete all rows of this url/file from SongScan.csv (and --fp-db) and exit");
+    println!(
+        "  --input <PATH>                (offline, play) Audio file to analyze/play (.wav/.mp3/.mp4/.m4a)\n"
     );
 
     println!("Gated options:");
@@ -516,6 +1771,14 @@ fn print_usage(cfg: &Config) {
         "  --fp-win-s <SEC>              Fingerprint window length (default: {:.1})",
         cfg.fp_win_s
     );
+    println!(
+        "  --fp-type <TYPE>              Fingerprint stored by scan/offline: melpeak_v3, constellation_v2, bandpeak_v1 (default: {})",
+        cfg.fp_type.as_str()
+    );
+    println!(
+        "  --fp-every-s <SEC>            Scan/offline: also fingerprint every SEC of the track, for mid-song alignment (default: {:.0}, 0 = lead-in only)",
+        cfg.fp_every_s
+    );
     println!(
         "  --fp-thr <FRAC>               Min similarity to accept [0..1] (default: {:.2})",
         cfg.fp_thr
@@ -528,16 +1791,38 @@ fn print_usage(cfg: &Config) {
         "  --guard-s <SEC>               Guard band around segments (default: {:.1})",
         cfg.guard_s
     );
+    println!(
+        "  --window-min-quality <FRAC>   Drop windows scoring below this in WindowQuality.csv (default: {:.2}, 0 = keep all)",
+        cfg.window_min_quality
+    );
     println!(
         "  --fp-arm-dbfs <DB>            Loopback level to arm matching (default: {:.0})",
         cfg.fp_arm_dbfs
     );
+    println!(
+        "  --realign-s <SEC>             Gated: re-check the alignment every SEC, correcting drift and following pause/seek (default: {:.0}, 0 = align once)",
+        cfg.realign_s
+    );
+    println!(
+        "  --realign-misses <N>          Gated: drop the alignment after N re-checks in a row miss the song, e.g. on a track skip (default: {})",
+        cfg.realign_misses
+    );
+    println!("  --smtc                        Gated: align from the Windows media session's title and position, fingerprints as fallback");
     println!(
         "  --offline-sr <HZ>             (offline) Resample input to this rate before analysis (default: {}). Use 0 to keep native.",
         cfg.offline_sample_rate_hz
     );
+    println!("  --start-s <SEC>               (offline) Start analysis this far into the file (default: 0)");
+    println!("  --duration-s <SEC>            (offline) Analyse only this much audio (default: 0 = to the end)");
+    println!(
+        "  --resample-quality <Q>        (offline) sinc = windowed-sinc, linear = fast interpolation (default: {})",
+        cfg.resample_quality.as_str()
+    );
     println!("\nEnrich options:");
     println!("  --song-path <PATH>            Input audio file to enrich with sonar pings");
+    println!("  --song-dir <DIR|M3U>          Enrich every audio file in a folder (recursive) or M3U playlist");
+    println!("  --out-dir <DIR>               Batch output folder, relative paths kept (default: <song-dir>_3pings)");
+    println!("  --jobs <N>                    Batch: files enriched in parallel (default: 0 = one per CPU)");
     println!(
         "  --interval-length <SEC>       Time between ping bursts in seconds (default: {:.1})",
         cfg.enrich_interval_length_s
@@ -546,6 +1831,26 @@ fn print_usage(cfg: &Config) {
         "  --ping-length <SEC>           Duration of each ping burst in seconds (default: {:.1})",
         cfg.enrich_ping_length_s
     );
+    println!(
+        "  --ping-freq-hz <HZ>           Ping frequency; centre of the band for chirp/noise (default: {:.0})",
+        cfg.enrich_ping_freq_hz
+    );
+    println!(
+        "  --ping-level-db <DB>          Ping peak level in dBFS (default: {:.0})",
+        cfg.enrich_ping_level_db
+    );
+    println!(
+        "  --ping-waveform <W>           sine, chirp (2 kHz sweep) or noise (2 kHz band) (default: {})",
+        cfg.enrich_ping_waveform.as_str()
+    );
+    println!(
+        "  --ping-placement <P>          interval (fixed) or masked (one per interval where loud content hides it) (default: {})",
+        cfg.enrich_ping_placement.as_str()
+    );
+    println!(
+        "  --ping-mask-margin-db <DB>    Masked: keep pings this far below the masking content (default: {:.0})",
+        cfg.enrich_mask_margin_db
+    );
     println!(
         "  --ffmpeg-path <PATH>          Path to ffmpeg executable (default: {})",
         cfg.ffmpeg_path
@@ -564,6 +1869,88 @@ fn print_usage(cfg: &Config) {
         "  --impulse-amplitude <VAL>     Impulse signal amplitude 0.0-1.0 (default: {})",
         cfg.impulse_amplitude
     );
+    println!(
+        "  --impulse-probe <P>           click, burst (short tone in --probe-band) or chirp (sweep over --impulse-length-ms) (default: {})",
+        cfg.impulse_probe.as_str()
+    );
+    println!(
+        "  --impulse-avg <N>             Pulses per measurement, averaged before peak picking (default: {})",
+        cfg.impulse_avg
+    );
+    println!(
+        "  --probe-band <LO-HI>          Band of the burst/chirp probe in Hz (default: {:.0}-{:.0})",
+        cfg.impulse_probe_band.0,
+        cfg.impulse_probe_band.1
+    );
+    println!("\nReplay options:");
+    println!("  --ref-wav <PATH>              Recorded loopback (render reference) audio");
+    println!("  --mic-wav <PATH>              Recorded microphone audio, started together with --ref-wav");
+    println!("                                presence/gated play these in real time instead of the devices");
+    println!(
+        "  --replay-speed <X|max>        1 = realtime, 4 = 4x, max = as fast as possible (default: {})",
+        cfg.replay_speed
+    );
+    println!("  --replay-gated                replay: run the recording through gated mode and its --scansong-path windows");
+    println!(
+        "  --record-session <DIR>        presence/gated: save ref.wav, mic.wav and ticks.csv under DIR/session-<time>/"
+    );
+    println!("  --debug-dump <PATH>           presence/gated/replay: one CSV row per tick with the vote's features");
+    println!("  --log-every-tick              presence/gated/auto/replay: every tick's distance, strength, agreement and state to Measurements.csv");
+    println!("  --privacy-strict              refuse recordings and per-tick dumps; zero the capture buffers at exit");
+    println!("  --features <PATH>             presence/replay: per-tick feature table for training classifiers (CSV, schema in <name>.schema.json)");
+    println!("  --labels <CSV>                replay/eval: start_s,end_s[,absent] of each stretch someone was there (eval scores against it, replay learns it into --calibration)");
+    println!("  --calibration <PATH>          Agreement → probability curve: presence/gated/replay report it, --labels adds to it");
+    println!("  --tune-session <DIR>          tune: a labelled session (ref.wav, mic.wav, labels.csv); repeatable");
+    println!("\nReport options:");
+    println!("  --report-in <PATH>            Detection.csv to summarize, with its rotated archives (default: the one beside --log-path)");
+    println!("  --report-by <day|hour>        Period the statistics are summed over (default: {})", cfg.report_by.as_str());
+    println!("  --report-format <csv|json>    Output format (default: {})", cfg.report_format.as_str());
+    println!("  --report-out <PATH>           Write the report here instead of stdout");
+    println!("\nHooks (presence/gated/impulse):");
+    println!("  --on-enter <CMD>              Shell command run when presence starts");
+    println!("  --on-exit <CMD>               Shell command run when presence ends");
+    println!(
+        "  --hook-debounce-ms <MS>       State must hold this long before a hook fires (default: {})",
+        cfg.hook_debounce_ms
+    );
+    println!(
+        "  --hook-timeout-ms <MS>        Kill a hook still running after this long (default: {})",
+        cfg.hook_timeout_ms
+    );
+    println!("  --event-script <CMD>          presence/play: filter of JSON-line events that can suppress, rewrite or POST a webhook");
+    println!(
+        "  --event-script-timeout-ms <MS> Wait this long for the script's reply, then pass the event (default: {})",
+        cfg.event_script_timeout_ms
+    );
+    println!("  --event-rules <PATH>          presence/play: the same filter as embedded rules, evaluated in-process (no wait)");
+    println!(
+        "                                Hooks get SONAR_EVENT, SONAR_PRESENT, SONAR_DISTANCE_M, SONAR_STRENGTH, SONAR_CONFIDENCE, SONAR_AGREE_PCT"
+    );
+    println!("\nAuto-lock (Windows):");
+    println!("  --auto-lock                   Lock the workstation after sustained absence, wake display on return");
+    println!(
+        "  --lock-after-s <SEC>          Absence before locking (default: {:.0})",
+        cfg.lock_after_s
+    );
+    println!("\nMonitoring:");
+    println!("  --metrics-addr <HOST:PORT>    Serve Prometheus metrics at http://HOST:PORT/metrics");
+    println!("  --metrics-file <PATH>         Write Prometheus metrics to a textfile (node_exporter)");
+    println!("  --api-token <TOKEN>           Serve --metrics-addr only to requests with Authorization: Bearer <TOKEN>");
+    println!("  --heartbeat-s <SEC>           presence/gated: state, last measurement and health counters every SEC to Heartbeat.csv and Detection.jsonl");
+    println!("\nControl (presence/gated):");
+    println!("  --control <PATH>              Listen for commands on a Unix socket / named pipe (Windows: \\\\.\\pipe\\NAME or NAME)");
+    println!("  --takeover                    Another instance holds the audio devices: ask it to shut down through its --control, then start");
+    println!("                                Commands: status, pause, resume, recalibrate, get <key>, set <key> <value>");
+    println!("\nSensor nodes:");
+    println!("  --node-listen <HOST:PORT>     presence: accept measurements from sensor nodes; any node's echo counts as a vote");
+    println!("                                hub: where the nodes send to");
+    println!("  --node-weights <NAME=W,...>   hub: how much each node's vote counts (default: 1 each)");
+    println!("  --hub-quorum <FRAC>           hub: share of the heard nodes' weight that has to vote (default: {})", cfg.hub_quorum);
+    println!("  --hub-delay-ms <MS>           hub: how long each tick waits for late node ticks (default: {})", cfg.hub_delay_ms);
+    println!("  --server <HOST:PORT>          node: the presence instance or hub to send measurements to (default: found on mDNS)");
+    println!("  --pair-token <TOKEN>          node: the server to pair with; presence/hub: refuse nodes without it");
+    println!("  --no-mdns                     presence/hub: do not advertise --node-listen on mDNS");
+    println!("  --node-name <NAME>            node: name shown on the server (default: the host name)");
     println!("\nExamples:");
     println!("  sonar_presence --mode presence -tm 200 -af 0.60 -ws 3");
     println!("  sonar_presence --mode scan --scan-url https://youtu.be/dQw4w9WgXcQ");
@@ -575,9 +1962,11 @@ fn print_usage(cfg: &Config) {
 }
 
 fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
     let mut config = Config::default();
     let mut meta = ScanMeta::default();
+    // profiles read so far, so one that includes itself (or another that includes it) stops
+    let mut profiles_loaded = Vec::new();
 
     let mut i = 1;
     while i < args.len() {
@@ -605,12 +1994,60 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                     "impulse" => {
                         config.mode = Mode::Impulse;
                     }
+                    "replay" => {
+                        config.mode = Mode::Replay;
+                    }
+                    "play" => {
+                        config.mode = Mode::Play;
+                    }
+                    "report" => {
+                        config.mode = Mode::Report;
+                    }
+                    "selftest" | "self-test" => {
+                        config.mode = Mode::SelfTest;
+                    }
+                    "meter" => {
+                        config.mode = Mode::Meter;
+                    }
+                    "eval" => {
+                        config.mode = Mode::Eval;
+                    }
+                    "tune" => {
+                        config.mode = Mode::Tune;
+                    }
+                    "node" => {
+                        config.mode = Mode::Node;
+                    }
+                    "hub" => {
+                        config.mode = Mode::Hub;
+                    }
+                    "auto" => {
+                        config.mode = Mode::Auto;
+                    }
                     other => {
                         return Err(format!("Unknown mode: {}", other));
                     }
                 }
                 i += 2;
             }
+            "--profile" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --profile".to_string());
+                }
+                let flags = profile::load(&args[i + 1], &mut profiles_loaded)?;
+                // the profile's flags take its place; later ones override them
+                args.splice(i..i + 2, flags);
+            }
+            "--device-profile" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --device-profile".to_string());
+                }
+                if !devcal::is_profile_name(&args[i + 1]) {
+                    return Err(format!("Invalid --device-profile {} (letters, digits, - and _ only)", args[i + 1]));
+                }
+                config.device_profile = args[i + 1].to_string();
+                i += 2;
+            }
             "--log-path" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for --log-path".to_string());
@@ -643,6 +2080,67 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                 }
                 i += 2;
             }
+            "-q" | "--quiet" => {
+                config.verbosity = console::Verbosity::Quiet;
+                i += 1;
+            }
+            "-v" | "--verbose" => {
+                config.verbosity = console::Verbosity::Verbose;
+                i += 1;
+            }
+            "--log-sink" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --log-sink".to_string());
+                }
+                config.log_sinks = LogSinks::parse(&args[i + 1])?;
+                i += 2;
+            }
+            "--log-format" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --log-format".to_string());
+                }
+                config.log_format = LogFormat::parse(&args[i + 1])?;
+                i += 2;
+            }
+            "--log-rotate-mb" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --log-rotate-mb".to_string());
+                }
+                config.log_rotate_mb = args[i + 1]
+                    .parse::<f64>()
+                    .map_err(|_| "Invalid log-rotate-mb value".to_string())?
+                    .max(0.0);
+                i += 2;
+            }
+            "--log-keep-days" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --log-keep-days".to_string());
+                }
+                config.log_keep_days = args[i + 1]
+                    .parse::<u32>()
+                    .map_err(|_| "Invalid log-keep-days value".to_string())?;
+                i += 2;
+            }
+            "--log-max-mb" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --log-max-mb".to_string());
+                }
+                config.log_max_mb = args[i + 1]
+                    .parse::<f64>()
+                    .map_err(|_| "Invalid log-max-mb value".to_string())?
+                    .max(0.0);
+                i += 2;
+            }
+            "--log-keep" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --log-keep".to_string());
+                }
+                config.log_keep = args[i + 1]
+                    .parse::<u32>()
+                    .map_err(|_| "Invalid log-keep value".to_string())?
+                    .max(1);
+                i += 2;
+            }
             "--scansong-path" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for --scansong-path".to_string());
@@ -650,6 +2148,13 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                 config.scansong_path = args[i + 1].to_string();
                 i += 2;
             }
+            "--fp-db" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --fp-db".to_string());
+                }
+                config.fp_db = args[i + 1].to_string();
+                i += 2;
+            }
             "-tm" | "--tick-ms" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for tick-ms".to_string());
@@ -676,6 +2181,79 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                 config.window_sec = v.max(1);
                 i += 2;
             }
+            "--agg-strategy" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --agg-strategy".to_string());
+                }
+                config.agg_strategy = sonar_presence::AggStrategy::parse(&args[i + 1]).ok_or_else(|| {
+                    "Invalid --agg-strategy (use window|ewma)".to_string()
+                })?;
+                i += 2;
+            }
+            "--ewma-tau-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ewma-tau-ms".to_string());
+                }
+                let v: u64 = args[i + 1].parse().map_err(|_| "Invalid ewma-tau-ms value".to_string())?;
+                config.ewma_tau_ms = v.max(1);
+                i += 2;
+            }
+            "--dist-stat" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --dist-stat".to_string());
+                }
+                config.dist_stat = sonar_presence::DistStat::parse(&args[i + 1]).ok_or_else(|| {
+                    "Invalid --dist-stat (use mean|median|trimmed|cluster)".to_string()
+                })?;
+                i += 2;
+            }
+            "--cluster-bin-cm" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --cluster-bin-cm".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid cluster-bin-cm value".to_string())?;
+                if v <= 0.0 {
+                    return Err("--cluster-bin-cm must be positive".to_string());
+                }
+                config.cluster_bin_cm = v;
+                i += 2;
+            }
+            "--detector" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --detector".to_string());
+                }
+                config.detector = strategy::DetectorKind::parse(&args[i + 1]).ok_or_else(|| {
+                    "Invalid --detector (use xcorr|phat|onnx)".to_string()
+                })?;
+                i += 2;
+            }
+            "--model" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --model".to_string());
+                }
+                config.model = args[i + 1].to_string();
+                i += 2;
+            }
+            "--model-thr" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --model-thr".to_string());
+                }
+                config.model_thr = args[i + 1]
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|p| (0.0..=1.0).contains(p))
+                    .ok_or_else(|| "Invalid model-thr value (0..1)".to_string())?;
+                i += 2;
+            }
+            "--policy" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --policy".to_string());
+                }
+                config.policy = strategy::PolicyKind::parse(&args[i + 1]).ok_or_else(|| {
+                    "Invalid --policy (use hysteresis|majority)".to_string()
+                })?;
+                i += 2;
+            }
             // New presence detection flags
             "--min-dwell-ms" => {
                 if i + 1 >= args.len() {
@@ -848,6 +2426,43 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                     .map_err(|_| "Invalid clamp-max-s".to_string())?;
                 i += 2;
             }
+            "--scan-weights" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --scan-weights".to_string());
+                }
+                config.scan_weights = prescan::ScanWeights::parse(&args[i + 1])?;
+                i += 2;
+            }
+            "--scan-scorer" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --scan-scorer".to_string());
+                }
+                config.scan_scorer = scanscore::ScorerKind::parse(&args[i + 1]).ok_or_else(|| {
+                    "Invalid --scan-scorer (use heuristic|hf-energy|crest|onnx)".to_string()
+                })?;
+                i += 2;
+            }
+            "--scan-model" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --scan-model".to_string());
+                }
+                config.scan_model = args[i + 1].to_string();
+                i += 2;
+            }
+            "--export-spectrogram" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --export-spectrogram".to_string());
+                }
+                config.export_spectrogram = args[i + 1].to_string();
+                i += 2;
+            }
+            "--export-features" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --export-features".to_string());
+                }
+                config.export_features = args[i + 1].to_string();
+                i += 2;
+            }
             "--sample-rate" | "--sr" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for --sample-rate/--sr".to_string());
@@ -859,6 +2474,65 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                 config.scan_sample_rate_hz = v;
                 i += 2;
             }
+            "--capture-duration-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --capture-duration-s".to_string());
+                }
+                let v: f32 = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid capture-duration-s".to_string())?;
+                if v < 0.0 {
+                    return Err("--capture-duration-s must be >= 0".to_string());
+                }
+                config.scan_capture_duration_s = v;
+                i += 2;
+            }
+            "--silence-stop-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --silence-stop-s".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid silence-stop-s".to_string())?;
+                if v < 0.0 {
+                    return Err("--silence-stop-s must be >= 0".to_string());
+                }
+                config.scan_silence_stop_s = v;
+                i += 2;
+            }
+            "--split-gap-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --split-gap-s".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid split-gap-s".to_string())?;
+                if v < 0.0 {
+                    return Err("--split-gap-s must be >= 0".to_string());
+                }
+                config.scan_split_gap_s = v;
+                i += 2;
+            }
+            "--scan-append" => {
+                config.scan_append = true;
+                i += 1;
+            }
+            "--prune-url" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --prune-url".to_string());
+                }
+                config.prune_url = args[i + 1].to_string();
+                i += 2;
+            }
+            "--split-prompt" => {
+                config.scan_split_prompt = true;
+                i += 1;
+            }
+            "--silence-dbfs" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --silence-dbfs".to_string());
+                }
+                config.scan_silence_dbfs = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid silence-dbfs".to_string())?;
+                i += 2;
+            }
             "--scan-url" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for scan-url".to_string());
@@ -880,6 +2554,27 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                 config.fp_win_s = args[i + 1].parse().map_err(|_| "Invalid fp-win-s".to_string())?;
                 i += 2;
             }
+            "--fp-type" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --fp-type".to_string());
+                }
+                config.fp_type = prescan::FpType::parse(&args[i + 1]).ok_or_else(|| {
+                    format!(
+                        "Invalid fingerprint type: {}. Valid options: melpeak_v3, constellation_v2, bandpeak_v1",
+                        args[i + 1]
+                    )
+                })?;
+                i += 2;
+            }
+            "--fp-every-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for fp-every-s".to_string());
+                }
+                config.fp_every_s = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid fp-every-s".to_string())?;
+                i += 2;
+            }
             "--fp-thr" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for fp-thr".to_string());
@@ -903,15 +2598,50 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                 config.guard_s = args[i + 1].parse().map_err(|_| "Invalid guard-s".to_string())?;
                 i += 2;
             }
-            "--fp-arm-dbfs" => {
+            "--window-min-quality" => {
                 if i + 1 >= args.len() {
-                    return Err("Missing value for fp-arm-dbfs".to_string());
+                    return Err("Missing value for --window-min-quality".to_string());
                 }
-                config.fp_arm_dbfs = args[i + 1]
+                config.window_min_quality = args[i + 1]
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|q| (0.0..=1.0).contains(q))
+                    .ok_or_else(|| "Invalid window-min-quality value (0..1)".to_string())?;
+                i += 2;
+            }
+            "--fp-arm-dbfs" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for fp-arm-dbfs".to_string());
+                }
+                config.fp_arm_dbfs = args[i + 1]
                     .parse()
                     .map_err(|_| "Invalid fp-arm-dbfs".to_string())?;
                 i += 2;
             }
+            "--realign-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for realign-s".to_string());
+                }
+                config.realign_s = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid realign-s".to_string())?;
+                i += 2;
+            }
+            "--realign-misses" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for realign-misses".to_string());
+                }
+                let v: u32 = args[i + 1].parse().map_err(|_| "Invalid realign-misses".to_string())?;
+                if v == 0 {
+                    return Err("--realign-misses must be >= 1".to_string());
+                }
+                config.realign_misses = v;
+                i += 2;
+            }
+            "--smtc" => {
+                config.smtc = true;
+                i += 1;
+            }
             "--offline-sr" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for --offline-sr".to_string());
@@ -920,6 +2650,119 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                 config.offline_sample_rate_hz = v; // 0 => keep native
                 i += 2;
             }
+            "--start-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --start-s".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid start-s".to_string())?;
+                if v < 0.0 {
+                    return Err("--start-s must be >= 0".to_string());
+                }
+                config.offline_start_s = v;
+                i += 2;
+            }
+            "--duration-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --duration-s".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid duration-s".to_string())?;
+                if v < 0.0 {
+                    return Err("--duration-s must be >= 0".to_string());
+                }
+                config.offline_duration_s = v;
+                i += 2;
+            }
+            "--channel-mix" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --channel-mix".to_string());
+                }
+                config.channel_mix = audio::ChannelMix::parse(&args[i + 1])?;
+                i += 2;
+            }
+            "--backpressure" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --backpressure".to_string());
+                }
+                config.backpressure = backpressure::Backpressure::parse(&args[i + 1])?;
+                i += 2;
+            }
+            "--loopback-device" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --loopback-device".to_string());
+                }
+                if config.loopback != audio::LoopbackTarget::Default {
+                    return Err("--loopback-device and --loopback-process cannot be combined".to_string());
+                }
+                config.loopback = audio::LoopbackTarget::Device(args[i + 1].to_string());
+                i += 2;
+            }
+            "--loopback-process" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --loopback-process".to_string());
+                }
+                if config.loopback != audio::LoopbackTarget::Default {
+                    return Err("--loopback-device and --loopback-process cannot be combined".to_string());
+                }
+                config.loopback = audio::LoopbackTarget::Process(args[i + 1].to_string());
+                i += 2;
+            }
+            "--probe-tone" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --probe-tone".to_string());
+                }
+                config.probe_tone = audio::ProbeMode::parse(&args[i + 1])?;
+                i += 2;
+            }
+            "--probe-signal" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --probe-signal".to_string());
+                }
+                config.probe_signal = audio::ProbeSignal::parse(&args[i + 1])?;
+                i += 2;
+            }
+            "--probe-seed" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --probe-seed".to_string());
+                }
+                config.probe_seed = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid probe-seed value".to_string())?;
+                i += 2;
+            }
+            "--probe-freq-hz" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --probe-freq-hz".to_string());
+                }
+                config.probe_freq_hz = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid probe-freq-hz value".to_string())?;
+                i += 2;
+            }
+            "--probe-amp" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --probe-amp".to_string());
+                }
+                config.probe_amp = args[i + 1]
+                    .parse::<f32>()
+                    .map_err(|_| "Invalid probe-amp value".to_string())?
+                    .clamp(0.0, 1.0);
+                i += 2;
+            }
+            "--resample-quality" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --resample-quality".to_string());
+                }
+                config.resample_quality = match args[i + 1].to_lowercase().as_str() {
+                    "sinc" | "high" => decode::ResampleQuality::Sinc,
+                    "linear" | "fast" => decode::ResampleQuality::Linear,
+                    other => {
+                        return Err(
+                            format!("Invalid resample quality: {}. Valid options: sinc, linear", other)
+                        );
+                    }
+                };
+                i += 2;
+            }
             "--song-path" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for --song-path".to_string());
@@ -927,6 +2770,29 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                 config.enrich_song_path = args[i + 1].to_string();
                 i += 2;
             }
+            "--song-dir" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --song-dir".to_string());
+                }
+                config.enrich_song_dir = args[i + 1].to_string();
+                i += 2;
+            }
+            "--out-dir" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --out-dir".to_string());
+                }
+                config.enrich_out_dir = args[i + 1].to_string();
+                i += 2;
+            }
+            "--jobs" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --jobs".to_string());
+                }
+                config.enrich_jobs = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid jobs value".to_string())?;
+                i += 2;
+            }
             "--interval-length" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for --interval-length".to_string());
@@ -945,6 +2811,223 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                     .map_err(|_| "Invalid ping-length value".to_string())?;
                 i += 2;
             }
+            "--ping-freq-hz" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ping-freq-hz".to_string());
+                }
+                config.enrich_ping_freq_hz = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid ping-freq-hz value".to_string())?;
+                i += 2;
+            }
+            "--ping-level-db" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ping-level-db".to_string());
+                }
+                config.enrich_ping_level_db = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid ping-level-db value".to_string())?;
+                i += 2;
+            }
+            "--ping-waveform" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ping-waveform".to_string());
+                }
+                config.enrich_ping_waveform = mods::enrich::PingWaveform::parse(&args[i + 1]).ok_or_else(|| {
+                    format!("Invalid ping waveform: {}. Valid options: sine, chirp, noise", args[i + 1])
+                })?;
+                i += 2;
+            }
+            "--ref-file" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ref-file".to_string());
+                }
+                config.ref_file = args[i + 1].to_string();
+                i += 2;
+            }
+            "--max-skew-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --max-skew-ms".to_string());
+                }
+                config.max_skew_ms = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid max-skew-ms value".to_string())?;
+                i += 2;
+            }
+            "--max-lead-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --max-lead-ms".to_string());
+                }
+                let v: u32 = args[i + 1].parse().map_err(|_| "Invalid max-lead-ms value".to_string())?;
+                config.max_lead_ms = v.min(sonar_presence::MAX_PIPELINE_DELAY_MS);
+                i += 2;
+            }
+            "--watchdog-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --watchdog-s".to_string());
+                }
+                config.watchdog_s = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid watchdog-s value".to_string())?;
+                i += 2;
+            }
+            "--drift-window-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --drift-window-s".to_string());
+                }
+                config.drift_window_s = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid drift-window-s value".to_string())?;
+                i += 2;
+            }
+            "--delay-lock-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --delay-lock-s".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid delay-lock-s value".to_string())?;
+                config.delay_lock_s = v.max(0.0);
+                i += 2;
+            }
+            "--delay-margin-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --delay-margin-ms".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid delay-margin-ms value".to_string())?;
+                if v <= 0.0 {
+                    return Err("--delay-margin-ms must be positive".to_string());
+                }
+                config.delay_margin_ms = v;
+                i += 2;
+            }
+            "--active-spread-cm" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --active-spread-cm".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid active-spread-cm value".to_string())?;
+                config.active_spread_cm = v.max(0.0);
+                i += 2;
+            }
+            "--active-hold-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --active-hold-ms".to_string());
+                }
+                config.active_hold_ms = args[i + 1].parse().map_err(|_| "Invalid active-hold-ms value".to_string())?;
+                i += 2;
+            }
+            "--resume-grace-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --resume-grace-s".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid resume-grace-s value".to_string())?;
+                config.resume_grace_s = v.max(0.0);
+                i += 2;
+            }
+            "--active-hours" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --active-hours".to_string());
+                }
+                for rule in args[i + 1].split_terminator(';').filter(|r| !r.trim().is_empty()) {
+                    config.active_hours.push(
+                        hours::HoursRule::parse(rule).map_err(|e| format!("Invalid active-hours value: {}", e))?
+                    );
+                }
+                i += 2;
+            }
+            "--no-device-cal" => {
+                config.device_cal = false;
+                i += 1;
+            }
+            "--pause-on-mic-busy" => {
+                config.pause_on_mic_busy = true;
+                i += 1;
+            }
+            "--power-save" => {
+                config.power_save = true;
+                i += 1;
+            }
+            "--idle-after-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --idle-after-s".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid idle-after-s value".to_string())?;
+                config.idle_after_s = v.max(0.0);
+                i += 2;
+            }
+            "--idle-tick-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --idle-tick-ms".to_string());
+                }
+                config.idle_tick_ms = args[i + 1].parse().map_err(|_| "Invalid idle-tick-ms value".to_string())?;
+                i += 2;
+            }
+            "--power-ramp-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --power-ramp-s".to_string());
+                }
+                let v: f32 = args[i + 1].parse().map_err(|_| "Invalid power-ramp-s value".to_string())?;
+                config.power_ramp_s = v.max(0.0);
+                i += 2;
+            }
+            "--ref-offset-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ref-offset-s".to_string());
+                }
+                config.ref_offset_s = Some(
+                    args[i + 1].parse().map_err(|_| "Invalid ref-offset-s value".to_string())?
+                );
+                i += 2;
+            }
+            "--corr-band" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --corr-band".to_string());
+                }
+                let band = args[i + 1]
+                    .split_once(':')
+                    .and_then(|(lo, hi)| Some((lo.trim().parse::<f32>().ok()?, hi.trim().parse::<f32>().ok()?)))
+                    .filter(|&(lo, hi)| lo >= 0.0 && hi > lo);
+                config.corr_band = Some(band.ok_or_else(|| "Invalid corr-band value (expected <lo>:<hi> in Hz, lo < hi)".to_string())?);
+                i += 2;
+            }
+            "--bearing" => {
+                config.bearing = true;
+                i += 1;
+            }
+            "--mic-spacing-m" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --mic-spacing-m".to_string());
+                }
+                config.mic_spacing_m = args[i + 1]
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|&m| m > 0.0)
+                    .ok_or_else(|| "Invalid mic-spacing-m value".to_string())?;
+                i += 2;
+            }
+            "--ping-schedule" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ping-schedule".to_string());
+                }
+                config.ping_schedules.push(args[i + 1].to_string());
+                i += 2;
+            }
+            "--ping-placement" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ping-placement".to_string());
+                }
+                config.enrich_ping_placement = mods::enrich::PingPlacement::parse(&args[i + 1]).ok_or_else(|| {
+                    format!("Invalid ping placement: {}. Valid options: interval, masked", args[i + 1])
+                })?;
+                i += 2;
+            }
+            "--ping-mask-margin-db" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ping-mask-margin-db".to_string());
+                }
+                config.enrich_mask_margin_db = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid ping-mask-margin-db value".to_string())?;
+                i += 2;
+            }
             "--ffmpeg-path" => {
                 if i + 1 >= args.len() {
                     return Err("Missing value for --ffmpeg-path".to_string());
@@ -981,6 +3064,315 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
                     .clamp(0.0, 1.0);
                 i += 2;
             }
+            "--impulse-probe" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --impulse-probe".to_string());
+                }
+                config.impulse_probe = mods::impulse::ImpulseProbe::parse(&args[i + 1]).ok_or_else(|| {
+                    format!("Invalid impulse probe: {}. Valid options: click, burst, chirp", args[i + 1])
+                })?;
+                i += 2;
+            }
+            "--impulse-avg" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --impulse-avg".to_string());
+                }
+                config.impulse_avg = args[i + 1]
+                    .parse()
+                    .ok()
+                    .filter(|&n: &usize| n >= 1)
+                    .ok_or_else(|| "Invalid impulse-avg value (must be >= 1)".to_string())?;
+                i += 2;
+            }
+            "--probe-band" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --probe-band".to_string());
+                }
+                config.impulse_probe_band = mods::impulse::parse_band(&args[i + 1]).ok_or_else(|| {
+                    format!("Invalid probe band: {}. Expected LO-HI in Hz, e.g. 17000-20000", args[i + 1])
+                })?;
+                i += 2;
+            }
+            "--ref-wav" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --ref-wav".to_string());
+                }
+                config.replay_ref_wav = args[i + 1].to_string();
+                i += 2;
+            }
+            "--mic-wav" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --mic-wav".to_string());
+                }
+                config.replay_mic_wav = args[i + 1].to_string();
+                i += 2;
+            }
+            "--record-session" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --record-session".to_string());
+                }
+                config.record_session = args[i + 1].to_string();
+                i += 2;
+            }
+            "--debug-dump" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --debug-dump".to_string());
+                }
+                config.debug_dump = args[i + 1].to_string();
+                i += 2;
+            }
+            "--replay-gated" => {
+                config.replay_gated = true;
+                i += 1;
+            }
+            "--log-every-tick" => {
+                config.log_every_tick = true;
+                i += 1;
+            }
+            "--privacy-strict" => {
+                config.privacy_strict = true;
+                i += 1;
+            }
+            "--features" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --features".to_string());
+                }
+                config.features = args[i + 1].to_string();
+                i += 2;
+            }
+            "--replay-speed" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --replay-speed".to_string());
+                }
+                config.replay_speed = if args[i + 1].eq_ignore_ascii_case("max") {
+                    0.0
+                } else {
+                    args[i + 1]
+                        .parse::<f32>()
+                        .map_err(|_| "Invalid replay-speed value".to_string())?
+                        .max(0.0)
+                };
+                i += 2;
+            }
+            "--labels" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --labels".to_string());
+                }
+                config.labels = args[i + 1].to_string();
+                i += 2;
+            }
+            "--calibration" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --calibration".to_string());
+                }
+                config.calibration = args[i + 1].to_string();
+                i += 2;
+            }
+            "--tune-session" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --tune-session".to_string());
+                }
+                config.tune_sessions.push(args[i + 1].to_string());
+                i += 2;
+            }
+            "--report-in" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --report-in".to_string());
+                }
+                config.report_in = args[i + 1].to_string();
+                i += 2;
+            }
+            "--report-by" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --report-by".to_string());
+                }
+                config.report_by = mods::report::ReportBy::parse(&args[i + 1])?;
+                i += 2;
+            }
+            "--report-format" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --report-format".to_string());
+                }
+                config.report_format = mods::report::ReportFormat::parse(&args[i + 1])?;
+                i += 2;
+            }
+            "--report-out" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --report-out".to_string());
+                }
+                config.report_out = args[i + 1].to_string();
+                i += 2;
+            }
+            "--on-enter" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --on-enter".to_string());
+                }
+                config.on_enter_cmd = args[i + 1].to_string();
+                i += 2;
+            }
+            "--on-exit" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --on-exit".to_string());
+                }
+                config.on_exit_cmd = args[i + 1].to_string();
+                i += 2;
+            }
+            "--hook-debounce-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --hook-debounce-ms".to_string());
+                }
+                config.hook_debounce_ms = args[i + 1]
+                    .parse()
+                    .map_err(|_| "Invalid hook-debounce-ms value".to_string())?;
+                i += 2;
+            }
+            "--hook-timeout-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --hook-timeout-ms".to_string());
+                }
+                config.hook_timeout_ms = args[i + 1]
+                    .parse::<u64>()
+                    .map_err(|_| "Invalid hook-timeout-ms value".to_string())?
+                    .max(1);
+                i += 2;
+            }
+            "--event-script" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --event-script".to_string());
+                }
+                config.event_script = args[i + 1].to_string();
+                i += 2;
+            }
+            "--event-rules" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --event-rules".to_string());
+                }
+                config.event_rules = args[i + 1].to_string();
+                i += 2;
+            }
+            "--event-script-timeout-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --event-script-timeout-ms".to_string());
+                }
+                config.event_script_timeout_ms = args[i + 1]
+                    .parse::<u64>()
+                    .map_err(|_| "Invalid event-script-timeout-ms value".to_string())?
+                    .max(1);
+                i += 2;
+            }
+            "--auto-lock" => {
+                config.auto_lock = true;
+                i += 1;
+            }
+            "--lock-after-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --lock-after-s".to_string());
+                }
+                config.lock_after_s = args[i + 1]
+                    .parse::<f32>()
+                    .map_err(|_| "Invalid lock-after-s value".to_string())?
+                    .max(0.0);
+                i += 2;
+            }
+            "--metrics-addr" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --metrics-addr".to_string());
+                }
+                config.metrics_addr = args[i + 1].to_string();
+                i += 2;
+            }
+            "--metrics-file" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --metrics-file".to_string());
+                }
+                config.metrics_file = args[i + 1].to_string();
+                i += 2;
+            }
+            "--api-token" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --api-token".to_string());
+                }
+                config.api_token = args[i + 1].to_string();
+                i += 2;
+            }
+            "--heartbeat-s" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --heartbeat-s".to_string());
+                }
+                config.heartbeat_s = args[i + 1]
+                    .parse::<f32>()
+                    .map_err(|_| "Invalid heartbeat-s value".to_string())?
+                    .max(0.0);
+                i += 2;
+            }
+            "--takeover" => {
+                config.takeover = true;
+                i += 1;
+            }
+            "--server" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --server".to_string());
+                }
+                config.server = args[i + 1].to_string();
+                i += 2;
+            }
+            "--node-name" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --node-name".to_string());
+                }
+                config.node_name = args[i + 1].to_string();
+                i += 2;
+            }
+            "--node-listen" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --node-listen".to_string());
+                }
+                config.node_listen = args[i + 1].to_string();
+                i += 2;
+            }
+            "--pair-token" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --pair-token".to_string());
+                }
+                config.pair_token = args[i + 1].to_string();
+                i += 2;
+            }
+            "--no-mdns" => {
+                config.mdns = false;
+                i += 1;
+            }
+            "--node-weights" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --node-weights".to_string());
+                }
+                config.node_weights = node::parse_weights(&args[i + 1])?;
+                i += 2;
+            }
+            "--hub-quorum" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --hub-quorum".to_string());
+                }
+                config.hub_quorum = args[i + 1]
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|q| (0.0..=1.0).contains(q))
+                    .ok_or_else(|| "Invalid hub-quorum value (0..1)".to_string())?;
+                i += 2;
+            }
+            "--hub-delay-ms" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --hub-delay-ms".to_string());
+                }
+                config.hub_delay_ms = args[i + 1].parse::<u64>().map_err(|_| "Invalid hub-delay-ms value".to_string())?;
+                i += 2;
+            }
+            "--control" => {
+                if i + 1 >= args.len() {
+                    return Err("Missing value for --control".to_string());
+                }
+                config.control_path = args[i + 1].to_string();
+                i += 2;
+            }
             "-h" | "--help" => {
                 print_usage(&Config::default());
                 std::process::exit(0);
@@ -990,6 +3382,7 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
             }
         }
     }
+    privacy::check(&config)?;
 
     Ok((config, meta))
 }
@@ -1000,22 +3393,38 @@ fn parse_arguments() -> std::result::Result<(Config, ScanMeta), String> {
 #[cfg(target_os = "windows")]
 pub mod wasapi_loopback {
     use super::Logger;
-    use anyhow::Context;
+    use crate::audio::{ self, ChannelMix, LoopbackTarget };
+    use anyhow::{ anyhow, Context };
     use crossbeam_channel::{ bounded, Receiver, Sender };
     use std::{ sync::Arc, thread, time::Duration };
     use windows::{
-        core::GUID,
+        core::{ implement, Interface, IUnknown, GUID, HRESULT, PCWSTR, PROPVARIANT },
         Win32::{
+            Devices::FunctionDiscovery::PKEY_Device_FriendlyName,
+            Foundation::{ CloseHandle, BOOL, E_POINTER, HANDLE },
             Media::Audio::{
                 eConsole,
                 eRender,
+                ActivateAudioInterfaceAsync,
+                IActivateAudioInterfaceAsyncOperation,
+                IActivateAudioInterfaceCompletionHandler,
+                IActivateAudioInterfaceCompletionHandler_Impl,
                 IAudioCaptureClient,
                 IAudioClient,
                 IMMDevice,
                 IMMDeviceEnumerator,
                 AUDCLNT_BUFFERFLAGS_SILENT,
                 AUDCLNT_SHAREMODE_SHARED,
+                AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM,
+                AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
                 AUDCLNT_STREAMFLAGS_LOOPBACK,
+                AUDIOCLIENT_ACTIVATION_PARAMS,
+                AUDIOCLIENT_ACTIVATION_PARAMS_0,
+                AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+                AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS,
+                DEVICE_STATE_ACTIVE,
+                PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+                VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
                 WAVEFORMATEX,
                 WAVEFORMATEXTENSIBLE,
                 MMDeviceEnumerator,
@@ -1027,7 +3436,16 @@ pub mod wasapi_loopback {
                 CoUninitialize,
                 CLSCTX_ALL,
                 COINIT_MULTITHREADED,
+                STGM_READ,
+            },
+            System::Diagnostics::ToolHelp::{
+                CreateToolhelp32Snapshot,
+                Process32FirstW,
+                Process32NextW,
+                PROCESSENTRY32W,
+                TH32CS_SNAPPROCESS,
             },
+            System::Threading::{ CreateEventW, WaitForSingleObject },
         },
     };
 
@@ -1039,15 +3457,20 @@ pub mod wasapi_loopback {
     const KSDATAFORMAT_SUBTYPE_IEEE_FLOAT: GUID =
         GUID::from_u128(0x00000003_0000_0010_8000_00aa00389b71);
 
+    /// PROPVARIANT type tag of a byte blob.
+    const VT_BLOB: u16 = 65;
+
     pub fn start(
         target_sr: u32,
         logger: Arc<Logger>,
-        tick_ms: u64
+        tick_ms: u64,
+        mix: ChannelMix,
+        target: LoopbackTarget
     ) -> anyhow::Result<Receiver<Vec<f32>>> {
         let (tx, rx) = bounded::<Vec<f32>>(8);
 
         thread::spawn(move || {
-            if let Err(e) = capture_thread(target_sr, tx, logger, tick_ms) {
+            if let Err(e) = capture_thread(target_sr, tx, logger, tick_ms, mix, &target) {
                 eprintln!("WASAPI loopback thread error: {:?}", e);
             }
         });
@@ -1055,76 +3478,203 @@ pub mod wasapi_loopback {
         Ok(rx)
     }
 
+    /// A PROPVARIANT holding a VT_BLOB, laid out by hand: the activation parameters travel this way.
+    #[repr(C)]
+    struct BlobVariant {
+        vt: u16,
+        reserved: [u16; 3],
+        size: u32,
+        data: *const u8,
+    }
+
+    /// Receives the IAudioClient that ActivateAudioInterfaceAsync produces on a worker thread.
+    #[implement(IActivateAudioInterfaceCompletionHandler)]
+    struct Activated(Sender<windows::core::Result<IAudioClient>>);
+
+    impl IActivateAudioInterfaceCompletionHandler_Impl for Activated_Impl {
+        fn ActivateCompleted(&self, op: Option<&IActivateAudioInterfaceAsyncOperation>) -> windows::core::Result<()> {
+            let _ = self.0.send(unsafe { activated_client(op) });
+            Ok(())
+        }
+    }
+
+    unsafe fn activated_client(op: Option<&IActivateAudioInterfaceAsyncOperation>) -> windows::core::Result<IAudioClient> {
+        let op = op.ok_or(windows::core::Error::from(E_POINTER))?;
+        let mut hr = HRESULT(0);
+        let mut unknown: Option<IUnknown> = None;
+        op.GetActivateResult(&mut hr, &mut unknown)?;
+        hr.ok()?;
+        unknown.ok_or(windows::core::Error::from(E_POINTER))?.cast::<IAudioClient>()
+    }
+
+    /// Audio client capturing only `pid` and its child processes (Windows 10 2004+).
+    unsafe fn activate_process_loopback(pid: u32) -> anyhow::Result<IAudioClient> {
+        let params = AUDIOCLIENT_ACTIVATION_PARAMS {
+            ActivationType: AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+            Anonymous: AUDIOCLIENT_ACTIVATION_PARAMS_0 {
+                ProcessLoopbackParams: AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS {
+                    TargetProcessId: pid,
+                    ProcessLoopbackMode: PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+                },
+            },
+        };
+        let blob = BlobVariant {
+            vt: VT_BLOB,
+            reserved: [0; 3],
+            size: std::mem::size_of::<AUDIOCLIENT_ACTIVATION_PARAMS>() as u32,
+            data: &params as *const AUDIOCLIENT_ACTIVATION_PARAMS as *const u8,
+        };
+        let (tx, rx) = bounded(1);
+        let handler: IActivateAudioInterfaceCompletionHandler = Activated(tx).into();
+        let _op = ActivateAudioInterfaceAsync(
+            VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
+            &IAudioClient::IID,
+            Some(&blob as *const BlobVariant as *const PROPVARIANT),
+            &handler
+        ).context("process loopback needs Windows 10 version 2004 or later")?;
+        rx.recv_timeout(Duration::from_secs(5))
+            .context("process loopback activation did not complete")?
+            .context("process loopback activation failed")
+    }
+
+    /// The active render endpoint `wanted` names (ID or friendly name), and its name.
+    unsafe fn find_endpoint(enumerator: &IMMDeviceEnumerator, wanted: &str) -> anyhow::Result<(IMMDevice, String)> {
+        let all = enumerator.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)?;
+        let mut devices = Vec::new();
+        let mut endpoints = Vec::new();
+        for i in 0..all.GetCount()? {
+            let device = all.Item(i)?;
+            let id_ptr = device.GetId()?;
+            let id = id_ptr.to_string().unwrap_or_default();
+            CoTaskMemFree(Some(id_ptr.0 as *const _));
+            let name = device
+                .OpenPropertyStore(STGM_READ)
+                .and_then(|props| props.GetValue(&PKEY_Device_FriendlyName))
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            devices.push(device);
+            endpoints.push((id, name));
+        }
+        let i = audio::pick_endpoint(wanted, &endpoints).map_err(|e| anyhow!(e))?;
+        Ok((devices.swap_remove(i), endpoints.swap_remove(i).1))
+    }
+
+    /// PID of the running process `wanted` names (PID or executable name).
+    unsafe fn find_process(wanted: &str) -> anyhow::Result<u32> {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0)?;
+        let mut procs = Vec::new();
+        let mut entry = PROCESSENTRY32W { dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32, ..Default::default() };
+        let mut more = Process32FirstW(snapshot, &mut entry).is_ok();
+        while more {
+            let len = entry.szExeFile.iter().position(|&c| c == 0).unwrap_or(entry.szExeFile.len());
+            procs.push((entry.th32ProcessID, entry.th32ParentProcessID, String::from_utf16_lossy(&entry.szExeFile[..len])));
+            more = Process32NextW(snapshot, &mut entry).is_ok();
+        }
+        let _ = CloseHandle(snapshot);
+        audio::pick_process(wanted, &procs).ok_or_else(|| anyhow!("no running process matches '{}'", wanted))
+    }
+
     fn capture_thread(
         target_sr: u32,
         tx: Sender<Vec<f32>>,
         logger: Arc<Logger>,
-        tick_ms: u64
+        tick_ms: u64,
+        mix: ChannelMix,
+        target: &LoopbackTarget
     ) -> anyhow::Result<()> {
         unsafe {
             CoInitializeEx(None, COINIT_MULTITHREADED).ok()?;
 
-            let enumerator: IMMDeviceEnumerator = CoCreateInstance(
-                &MMDeviceEnumerator,
-                None,
-                CLSCTX_ALL
-            )?;
-            let device: IMMDevice = enumerator
-                .GetDefaultAudioEndpoint(eRender, eConsole)
-                .context("GetDefaultAudioEndpoint failed")?;
-            let audio_client: IAudioClient = device
-                .Activate::<IAudioClient>(CLSCTX_ALL, None)
-                .context("Activate IAudioClient failed")?;
-
-            let pwfx: *mut WAVEFORMATEX = audio_client.GetMixFormat()?;
-            let mix = *pwfx;
-            let (in_sr, channels, fmt_tag, subfmt) = {
-                let tag = mix.wFormatTag;
-                let ch = mix.nChannels;
-                let sr = mix.nSamplesPerSec;
-                let sub = if tag == WAVE_FORMAT_EXTENSIBLE_TAG {
-                    let wfxe = &*(pwfx as *const WAVEFORMATEXTENSIBLE);
-                    wfxe.SubFormat
-                } else if tag == WAVE_FORMAT_IEEE_FLOAT_TAG {
-                    KSDATAFORMAT_SUBTYPE_IEEE_FLOAT
-                } else {
-                    KSDATAFORMAT_SUBTYPE_PCM
-                };
-                (sr, ch, tag, sub)
-            };
+            let hns_buffer_duration: i64 = 10_000_000 / 10; // 100ms
 
-            let fmt_str = if fmt_tag == WAVE_FORMAT_EXTENSIBLE_TAG {
-                if subfmt == KSDATAFORMAT_SUBTYPE_IEEE_FLOAT {
-                    "Float32 (extensible)"
-                } else {
-                    "PCM (extensible)"
-                }
-            } else if fmt_tag == WAVE_FORMAT_IEEE_FLOAT_TAG {
-                "Float32"
+            // endpoint loopback polls; process loopback only delivers in event mode
+            let mut event: Option<HANDLE> = None;
+            let (audio_client, wfx): (IAudioClient, WAVEFORMATEX) = if let LoopbackTarget::Process(wanted) = target {
+                let pid = find_process(wanted)?;
+                let audio_client = activate_process_loopback(pid)?;
+                let _ = logger.info(&format!("WASAPI process loopback: '{}' (PID {}) and its child processes", wanted, pid));
+                // no mix format to ask for: the engine converts to what we request
+                let wfx = WAVEFORMATEX {
+                    wFormatTag: WAVE_FORMAT_IEEE_FLOAT_TAG,
+                    nChannels: 2,
+                    nSamplesPerSec: target_sr,
+                    nAvgBytesPerSec: target_sr * 8,
+                    nBlockAlign: 8,
+                    wBitsPerSample: 32,
+                    cbSize: 0,
+                };
+                audio_client.Initialize(
+                    AUDCLNT_SHAREMODE_SHARED,
+                    AUDCLNT_STREAMFLAGS_LOOPBACK | AUDCLNT_STREAMFLAGS_EVENTCALLBACK | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM,
+                    hns_buffer_duration,
+                    0,
+                    &wfx,
+                    None
+                )?;
+                let ready = CreateEventW(None, BOOL(0), BOOL(0), PCWSTR::null())?;
+                audio_client.SetEventHandle(ready)?;
+                event = Some(ready);
+                (audio_client, wfx)
             } else {
-                "PCM"
+                let enumerator: IMMDeviceEnumerator = CoCreateInstance(
+                    &MMDeviceEnumerator,
+                    None,
+                    CLSCTX_ALL
+                )?;
+                let device: IMMDevice = match target {
+                    LoopbackTarget::Device(wanted) => {
+                        let (device, name) = find_endpoint(&enumerator, wanted)?;
+                        let _ = logger.info(&format!("WASAPI loopback device: {}", name));
+                        device
+                    }
+                    _ =>
+                        enumerator
+                            .GetDefaultAudioEndpoint(eRender, eConsole)
+                            .context("GetDefaultAudioEndpoint failed")?,
+                };
+                let audio_client: IAudioClient = device
+                    .Activate::<IAudioClient>(CLSCTX_ALL, None)
+                    .context("Activate IAudioClient failed")?;
+
+                let pwfx: *mut WAVEFORMATEX = audio_client.GetMixFormat()?;
+                audio_client.Initialize(
+                    AUDCLNT_SHAREMODE_SHARED,
+                    AUDCLNT_STREAMFLAGS_LOOPBACK,
+                    hns_buffer_duration,
+                    0,
+                    pwfx,
+                    None
+                )?;
+                // the sub-format lives past the base struct; read it before the memory goes
+                let mut wfx = *pwfx;
+                if wfx.wFormatTag == WAVE_FORMAT_EXTENSIBLE_TAG {
+                    let wfxe = &*(pwfx as *const WAVEFORMATEXTENSIBLE);
+                    if wfxe.SubFormat == KSDATAFORMAT_SUBTYPE_IEEE_FLOAT {
+                        wfx.wFormatTag = WAVE_FORMAT_IEEE_FLOAT_TAG;
+                    } else if wfxe.SubFormat == KSDATAFORMAT_SUBTYPE_PCM {
+                        wfx.wFormatTag = WAVE_FORMAT_PCM_TAG;
+                    }
+                }
+                CoTaskMemFree(Some(pwfx as *const _ as _));
+                (audio_client, wfx)
             };
 
+            let (in_sr, channels, is_float) = (wfx.nSamplesPerSec, wfx.nChannels, wfx.wFormatTag == WAVE_FORMAT_IEEE_FLOAT_TAG);
             let _ = logger.info(
                 &format!(
                     "WASAPI loopback mix format: {} Hz, channels {}, {}",
                     in_sr,
                     channels,
-                    fmt_str
+                    if is_float { "Float32" } else { "PCM" }
                 )
             )?;
 
-            let hns_buffer_duration: i64 = 10_000_000 / 10; // 100ms
-
-            audio_client.Initialize(
-                AUDCLNT_SHAREMODE_SHARED,
-                AUDCLNT_STREAMFLAGS_LOOPBACK,
-                hns_buffer_duration,
-                0,
-                pwfx,
-                None
-            )?;
-            CoTaskMemFree(Some(pwfx as *const _ as _));
+            // a render device running at another rate than the mic (44.1 vs 48 kHz) is converted
+            // here; passed through, every distance would be off by the ratio
+            let mut resampler = (in_sr != target_sr).then(|| crate::decode::Resampler::new(in_sr, target_sr, audio::LIVE_RESAMPLE));
+            if resampler.is_some() {
+                let _ = logger.warn(&format!("WASAPI loopback runs at {} Hz, the mic at {} Hz: resampling the loopback", in_sr, target_sr));
+            }
 
             let capture: IAudioCaptureClient = audio_client.GetService()?;
             audio_client.Start()?;
@@ -1136,15 +3686,19 @@ pub mod wasapi_loopback {
                 let mut num_frames: u32 = 0;
                 let mut flags: u32 = 0;
                 let hr = capture.GetBuffer(&mut p_data, &mut num_frames, &mut flags, None, None);
+                if hr.is_err() {
+                    // device invalidated (unplugged, format change): end the stream so it can be reopened
+                    let _ = audio_client.Stop();
+                    if let Some(ready) = event {
+                        let _ = CloseHandle(ready);
+                    }
+                    CoUninitialize();
+                    anyhow::bail!("loopback capture stopped: {:?}", hr);
+                }
 
                 if hr.is_ok() && num_frames > 0 {
                     let mut mono = Vec::with_capacity(num_frames as usize);
 
-                    let is_float =
-                        fmt_tag == WAVE_FORMAT_IEEE_FLOAT_TAG ||
-                        (fmt_tag == WAVE_FORMAT_EXTENSIBLE_TAG &&
-                            subfmt == KSDATAFORMAT_SUBTYPE_IEEE_FLOAT);
-
                     if (flags & (AUDCLNT_BUFFERFLAGS_SILENT.0 as u32)) != 0 {
                         mono.resize(num_frames as usize, 0.0);
                     } else if is_float {
@@ -1152,21 +3706,26 @@ pub mod wasapi_loopback {
                             p_data as *const f32,
                             (num_frames * (channels as u32)) as usize
                         );
-                        for f in 0..num_frames as usize {
-                            mono.push(slice[f * (channels as usize)]); // first channel
-                        }
+                        mono = mix.downmix(slice, channels as usize);
                     } else {
                         let slice = std::slice::from_raw_parts(
                             p_data as *const i16,
                             (num_frames * (channels as u32)) as usize
                         );
-                        for f in 0..num_frames as usize {
-                            mono.push((slice[f * (channels as usize)] as f32) / 32768.0);
+                        let mut frame = vec![0.0f32; (channels as usize).max(1)];
+                        for f in slice.chunks_exact((channels as usize).max(1)) {
+                            for (dst, &s) in frame.iter_mut().zip(f) {
+                                *dst = (s as f32) / 32768.0;
+                            }
+                            mono.push(mix.frame(&frame));
                         }
                     }
 
                     capture.ReleaseBuffer(num_frames)?;
 
+                    if let Some(r) = resampler.as_mut() {
+                        mono = r.process(&mono);
+                    }
                     leftover.extend_from_slice(&mono);
                     let mut chunk = ((target_sr as usize) * (tick_ms as usize)) / 1000;
                     if chunk == 0 {
@@ -1174,12 +3733,17 @@ pub mod wasapi_loopback {
                     }
                     while leftover.len() >= chunk {
                         let out = leftover.drain(0..chunk).collect::<Vec<f32>>();
-                        if tx.send(out).is_err() {
+                        if !crate::backpressure::send(&tx, out, "ref") {
                             audio_client.Stop()?;
+                            if let Some(ready) = event {
+                                let _ = CloseHandle(ready);
+                            }
                             CoUninitialize();
                             return Ok(());
                         }
                     }
+                } else if let Some(ready) = event {
+                    WaitForSingleObject(ready, 200);
                 } else {
                     thread::sleep(Duration::from_millis(2));
                 }
@@ -1194,11 +3758,14 @@ pub mod wasapi_loopback {
     use crossbeam_channel::Receiver;
     use std::sync::Arc;
     use super::Logger;
+    use crate::audio::{ ChannelMix, LoopbackTarget };
 
     pub fn start(
         _target_sr: u32,
         _logger: Arc<Logger>,
-        _tick_ms: u64
+        _tick_ms: u64,
+        _mix: ChannelMix,
+        _target: LoopbackTarget
     ) -> Result<Receiver<Vec<f32>>> {
         anyhow::bail!("WASAPI loopback is only available on Windows")
     }
@@ -1207,12 +3774,10 @@ pub mod wasapi_loopback {
 // ───────────────────────────────────────────────────────────────────────────────
 // Optional: tiny built-in probe tone so loopback always has content
 // ───────────────────────────────────────────────────────────────────────────────
-#[cfg(target_os = "windows")]
-pub const ENABLE_PROBE_TONE: bool = false;
-
-#[cfg(target_os = "windows")]
-pub fn start_probe(sr: u32) -> anyhow::Result<cpal::Stream> {
-    use cpal::traits::{ DeviceTrait, HostTrait, StreamTrait };
+/// Play the `--probe-tone` sine or prbs sequence on the default output while `gate` is set
+/// (faded in and out over PROBE_FADE_S so switching does not click).
+pub fn start_probe(sr: u32, probe: &audio::ProbeTone, gate: Arc<AtomicBool>) -> anyhow::Result<cpal::Stream> {
+    const PROBE_FADE_S: f32 = 0.05;
     let host = cpal::default_host();
     let device = host
         .default_output_device()
@@ -1220,67 +3785,76 @@ pub fn start_probe(sr: u32) -> anyhow::Result<cpal::Stream> {
     let mut cfg = device.default_output_config()?.config();
     cfg.sample_rate.0 = sr;
 
-    let mut phase: f32 = 0.0;
-    const FREQ: f32 = 18_000.0;
-    const AMP: f32 = 0.02;
+    let (freq, amp) = (probe.freq_hz, probe.amp);
+    let period = probe.prbs_period(sr as f32);
+    let step = 1.0 / (PROBE_FADE_S * (sr as f32));
+    let tone = move || {
+        let mut phase: f32 = 0.0;
+        let mut pos = 0usize;
+        let mut level: f32 = 0.0;
+        let gate = gate.clone();
+        let period = period.clone();
+        move || {
+            level = if gate.load(Ordering::Relaxed) { (level + step).min(1.0) } else { (level - step).max(0.0) };
+            let s = match &period {
+                Some(p) => {
+                    pos = (pos + 1) % p.len();
+                    p[pos]
+                }
+                None => {
+                    phase += (2.0 * std::f32::consts::PI * freq) / (sr as f32);
+                    if phase > 2.0 * std::f32::consts::PI {
+                        phase -= 2.0 * std::f32::consts::PI;
+                    }
+                    phase.sin()
+                }
+            };
+            s * amp * level
+        }
+    };
     let err_fn = |e| eprintln!("output stream error: {e}");
     let channels = cfg.channels as usize;
 
     let stream = match device.default_output_config()?.sample_format() {
-        cpal::SampleFormat::F32 =>
+        cpal::SampleFormat::F32 => {
+            let mut next = tone();
             device.build_output_stream(
                 &cfg,
                 move |out: &mut [f32], _| {
                     for frame in out.chunks_mut(channels) {
-                        phase += (2.0 * std::f32::consts::PI * FREQ) / (sr as f32);
-                        if phase > 2.0 * std::f32::consts::PI {
-                            phase -= 2.0 * std::f32::consts::PI;
-                        }
-                        let s = phase.sin() * AMP;
-                        for ch in frame.iter_mut() {
-                            *ch = s;
-                        }
+                        frame.fill(next());
                     }
                 },
                 err_fn,
                 None
-            )?,
-        cpal::SampleFormat::I16 =>
+            )?
+        }
+        cpal::SampleFormat::I16 => {
+            let mut next = tone();
             device.build_output_stream(
                 &cfg,
                 move |out: &mut [i16], _| {
                     for frame in out.chunks_mut(channels) {
-                        phase += (2.0 * std::f32::consts::PI * FREQ) / (sr as f32);
-                        if phase > 2.0 * std::f32::consts::PI {
-                            phase -= 2.0 * std::f32::consts::PI;
-                        }
-                        let s = (phase.sin() * AMP * 32767.0) as i16;
-                        for ch in frame.iter_mut() {
-                            *ch = s;
-                        }
+                        frame.fill((next() * 32767.0) as i16);
                     }
                 },
                 err_fn,
                 None
-            )?,
-        cpal::SampleFormat::U16 =>
+            )?
+        }
+        cpal::SampleFormat::U16 => {
+            let mut next = tone();
             device.build_output_stream(
                 &cfg,
                 move |out: &mut [u16], _| {
                     for frame in out.chunks_mut(channels) {
-                        phase += (2.0 * std::f32::consts::PI * FREQ) / (sr as f32);
-                        if phase > 2.0 * std::f32::consts::PI {
-                            phase -= 2.0 * std::f32::consts::PI;
-                        }
-                        let s = ((phase.sin() * AMP * 0.5 + 0.5) * 65535.0) as u16;
-                        for ch in frame.iter_mut() {
-                            *ch = s;
-                        }
+                        frame.fill(((next() * 0.5 + 0.5) * 65535.0) as u16);
                     }
                 },
                 err_fn,
                 None
-            )?,
+            )?
+        }
         _ => anyhow::bail!("Unsupported output format"),
     };
 
@@ -1291,10 +3865,168 @@ pub fn start_probe(sr: u32) -> anyhow::Result<cpal::Stream> {
 // ───────────────────────────────────────────────────────────────────────────────
 // Shared ring buffer (used by presence/gated)
 // ───────────────────────────────────────────────────────────────────────────────
+/// Seconds of audio kept in each SharedBuf by `audio_sink_thread`.
+pub const RING_SECONDS: usize = 10;
+
+/// Fixed-capacity mono ring: `audio_sink_thread` is the only writer, analysis threads copy
+/// out what they need. Lock-free: samples are kept as f32 bits in atomics, the writer announces
+/// a block (`claimed`) before overwriting old slots and publishes it (`written`) afterwards, and
+/// a read that the writer lapped meanwhile is detected and retried or refused.
+/// Each block is stamped on arrival, so frames of two rings can be cut at the same moment.
 #[derive(Clone)]
 pub struct SharedBuf {
-    pub buf: Arc<Mutex<Vec<f32>>>, // mono ring buffer
-    pub sr: Arc<Mutex<f32>>,
+    ring: Arc<[AtomicU32]>,
+    pub sr: f32,
+    claimed: Arc<AtomicU64>, // end of the block being written
+    written: Arc<AtomicU64>, // samples ever appended and readable
+    origin: Instant,
+    stamp_ns: Arc<AtomicU64>, // arrival of the block ending at `written`, ns after `origin`
+}
+
+impl SharedBuf {
+    pub fn new(sr: f32, secs: usize) -> Self {
+        let cap = ((sr as usize) * secs).max(1);
+        Self {
+            ring: (0..cap).map(|_| AtomicU32::new(0)).collect(),
+            sr,
+            claimed: Arc::new(AtomicU64::new(0)),
+            written: Arc::new(AtomicU64::new(0)),
+            origin: Instant::now(),
+            stamp_ns: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// Overwrite every held sample with silence (`--privacy-strict`, at exit).
+    pub fn wipe(&self) {
+        for s in self.ring.iter() {
+            s.store(0, Ordering::SeqCst);
+        }
+        std::sync::atomic::fence(Ordering::SeqCst);
+    }
+
+    /// Samples ever appended; positions `written() - len()..written()` are held.
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Acquire)
+    }
+
+    pub fn len(&self) -> usize {
+        self.written().min(self.ring.len() as u64) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.written() == 0
+    }
+
+    /// Append a block that arrived now. Single producer: only the sink thread may call this.
+    pub fn push(&self, block: &[f32]) {
+        self.push_at(block, Instant::now());
+    }
+
+    /// Append a block whose last sample was captured at `at`.
+    pub fn push_at(&self, block: &[f32], at: Instant) {
+        let cap = self.ring.len();
+        let start = self.written.load(Ordering::Relaxed);
+        let end = start + (block.len() as u64);
+        self.claimed.store(end, Ordering::Relaxed);
+        fence(Ordering::Release);
+        // a block longer than the ring only leaves its tail
+        let skip = block.len().saturating_sub(cap);
+        for (i, v) in block.iter().enumerate().skip(skip) {
+            self.ring[((start + (i as u64)) % (cap as u64)) as usize].store(v.to_bits(), Ordering::Relaxed);
+        }
+        self.stamp_ns.store(at.saturating_duration_since(self.origin).as_nanos() as u64, Ordering::Relaxed);
+        self.written.store(end, Ordering::Release);
+    }
+
+    /// (`written`, when the newest sample arrived), read consistently.
+    pub fn head(&self) -> (u64, Instant) {
+        loop {
+            let written = self.written();
+            let ns = self.stamp_ns.load(Ordering::Relaxed);
+            fence(Ordering::Acquire);
+            // no block started since `written` was published: the stamp belongs to it
+            if self.claimed.load(Ordering::Relaxed) == written {
+                return (written, self.origin + Duration::from_nanos(ns));
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Copy positions `from..from + len` into `out`; false if the writer overwrote any of them.
+    fn copy(&self, from: u64, len: usize, out: &mut Vec<f32>) -> bool {
+        let cap = self.ring.len() as u64;
+        out.clear();
+        out.extend((0..len as u64).map(|i| f32::from_bits(self.ring[((from + i) % cap) as usize].load(Ordering::Relaxed))));
+        fence(Ordering::Acquire);
+        from >= self.claimed.load(Ordering::Relaxed).saturating_sub(cap)
+    }
+
+    /// Positions `from..from + len`; None until all are written or once any has left the ring.
+    pub fn read(&self, from: u64, len: usize) -> Option<Vec<f32>> {
+        let mut out = Vec::with_capacity(len);
+        self.read_into(from, len, &mut out).then_some(out)
+    }
+
+    /// `read` into a reused buffer; false with `out` empty when the positions are not held.
+    pub fn read_into(&self, from: u64, len: usize, out: &mut Vec<f32>) -> bool {
+        out.clear();
+        let written = self.written();
+        if from + (len as u64) > written || from < written.saturating_sub(self.ring.len() as u64) {
+            return false;
+        }
+        if !self.copy(from, len, out) {
+            out.clear();
+            return false;
+        }
+        true
+    }
+
+    /// The most recent `n` samples, or None while fewer than `n` are held.
+    pub fn latest(&self, n: usize) -> Option<Vec<f32>> {
+        let mut out = Vec::with_capacity(n);
+        self.latest_into(n, &mut out).then_some(out)
+    }
+
+    /// The most recent `n` samples into `out`, reusing its allocation (per-tick scratch);
+    /// false with `out` empty while fewer than `n` are held.
+    pub fn latest_into(&self, n: usize, out: &mut Vec<f32>) -> bool {
+        out.clear();
+        if n > self.ring.len() {
+            return false;
+        }
+        loop {
+            let written = self.written();
+            if written < (n as u64) {
+                return false;
+            }
+            if self.copy(written - (n as u64), n, out) {
+                return true;
+            }
+        }
+    }
+
+    /// The most recent `n` samples, or all that are held if fewer, into `out`.
+    pub fn tail_into(&self, n: usize, out: &mut Vec<f32>) {
+        let n = n.min(self.len());
+        self.latest_into(n, out);
+    }
+
+    /// Everything from position `from` (or the oldest still held) up to now, with the
+    /// position of its first sample.
+    pub fn read_since(&self, from: u64) -> (u64, Vec<f32>) {
+        let mut out = Vec::new();
+        loop {
+            let written = self.written();
+            let start = from.min(written).max(written.saturating_sub(self.ring.len() as u64));
+            if self.copy(start, (written - start) as usize, &mut out) {
+                return (start, out);
+            }
+        }
+    }
 }
 
 // ───────────────────────────────────────────────────────────────────────────────
@@ -1302,6 +4034,7 @@ pub struct SharedBuf {
 // ───────────────────────────────────────────────────────────────────────────────
 pub mod prescan {
     use realfft::RealFftPlanner;
+    use std::collections::{ HashMap, VecDeque };
 
     #[inline]
     fn hann(n: usize) -> Vec<f32> {
@@ -1391,6 +4124,37 @@ pub mod prescan {
         pub merge_gap_s: f32,
         pub clamp_min_s: f32,
         pub clamp_max_s: f32,
+        pub scorer: Box<dyn crate::scanscore::SegmentScorer>, // --scan-scorer
+    }
+
+    /// Mel bands of `WindowFeat::mel_db`, MEL_LO_HZ up to MEL_HI_HZ or Nyquist.
+    pub const MEL_BANDS: usize = 24;
+    pub const MEL_LO_HZ: f32 = 50.0;
+    pub const MEL_HI_HZ: f32 = 16000.0;
+
+    /// Mel scale: equal steps sound equally far apart, so the bands narrow towards the bass
+    /// where hearing (and music) resolves pitch finely.
+    pub fn hz_to_mel(hz: f32) -> f32 {
+        2595.0 * (1.0 + hz / 700.0).log10()
+    }
+
+    pub fn mel_to_hz(mel: f32) -> f32 {
+        700.0 * ((10.0f32).powf(mel / 2595.0) - 1.0)
+    }
+
+    /// The band each FFT bin of `bin_hz` falls in, for `n` bands evenly spaced in mel over
+    /// `lo_hz..hi_hz`; None outside. The bands depend on Hz only, not on the frame length or rate.
+    pub fn mel_band_map(n: usize, lo_hz: f32, hi_hz: f32, bin_hz: f32, n_bins: usize) -> Vec<Option<usize>> {
+        let (m_lo, m_hi) = (hz_to_mel(lo_hz), hz_to_mel(hi_hz));
+        (0..n_bins)
+            .map(|k| {
+                let hz = (k as f32) * bin_hz;
+                if hz < lo_hz || hz >= hi_hz || m_hi <= m_lo {
+                    return None;
+                }
+                Some(((((hz_to_mel(hz) - m_lo) / (m_hi - m_lo)) * (n as f32)) as usize).min(n - 1))
+            })
+            .collect()
     }
 
     #[derive(Clone)]
@@ -1405,6 +4169,7 @@ pub mod prescan {
         pub dyn_range: f32,
         pub tonality: f32,
         pub loudness_dbfs: f32,
+        pub mel_db: [f32; MEL_BANDS], // mid-frame energy per mel band, dB relative to the frame's total
         pub score: f32,
         pub z: FeatZ,
     }
@@ -1420,6 +4185,110 @@ pub mod prescan {
         pub tonality_z: f32,
     }
 
+    /// Weights of the window score: a weighted sum of the feature z-scores, less a penalty for
+    /// quiet (below -45 dBFS) and again for near-silent (below -60 dBFS) windows.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct ScanWeights {
+        pub flux: f32,
+        pub flatness: f32,
+        pub crest: f32,
+        pub bandwidth: f32,
+        pub hf: f32,
+        pub dynrange: f32,
+        pub tonality: f32, // negative: tonal windows mask a probe poorly
+        pub quiet: f32,
+        pub silent: f32,
+    }
+
+    impl Default for ScanWeights {
+        fn default() -> Self {
+            Self {
+                flux: 0.25,
+                flatness: 0.2,
+                crest: 0.2,
+                bandwidth: 0.15,
+                hf: 0.1,
+                dynrange: 0.1,
+                tonality: -0.2,
+                quiet: 0.5,
+                silent: 1.0,
+            }
+        }
+    }
+
+    impl ScanWeights {
+        /// `name=value` pairs separated by commas; names not given keep their default.
+        pub fn parse(s: &str) -> Result<Self, String> {
+            let mut w = Self::default();
+            for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                let (name, value) = pair
+                    .split_once('=')
+                    .ok_or_else(|| format!("Invalid scan weight '{}' (expected name=value)", pair))?;
+                let value = value
+                    .trim()
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .ok_or_else(|| format!("Invalid value for scan weight '{}'", name.trim()))?;
+                match name.trim() {
+                    "flux" => w.flux = value,
+                    "flatness" => w.flatness = value,
+                    "crest" => w.crest = value,
+                    "bandwidth" => w.bandwidth = value,
+                    "hf" => w.hf = value,
+                    "dynrange" => w.dynrange = value,
+                    "tonality" => w.tonality = value,
+                    "quiet" => w.quiet = value,
+                    "silent" => w.silent = value,
+                    other => {
+                        return Err(
+                            format!(
+                                "Unknown scan weight '{}' (flux, flatness, crest, bandwidth, hf, dynrange, tonality, quiet, silent)",
+                                other
+                            )
+                        );
+                    }
+                }
+            }
+            Ok(w)
+        }
+
+        /// Every weight, in the `--scan-weights` syntax: what SongScan.csv's `notes` records.
+        pub fn as_arg(&self) -> String {
+            format!(
+                "flux={},flatness={},crest={},bandwidth={},hf={},dynrange={},tonality={},quiet={},silent={}",
+                self.flux,
+                self.flatness,
+                self.crest,
+                self.bandwidth,
+                self.hf,
+                self.dynrange,
+                self.tonality,
+                self.quiet,
+                self.silent
+            )
+        }
+
+        /// The heuristic scorer's score of a window.
+        pub fn score(&self, z: &FeatZ, loudness_dbfs: f32) -> f32 {
+            let mut score =
+                self.flux * z.flux_z +
+                self.flatness * z.flatness_z +
+                self.crest * z.crest_z +
+                self.bandwidth * z.bandwidth_z +
+                self.hf * z.hf_ratio_z +
+                self.dynrange * z.dynrange_z +
+                self.tonality * z.tonality_z;
+            if loudness_dbfs < -45.0 {
+                score -= self.quiet;
+            }
+            if loudness_dbfs < -60.0 {
+                score -= self.silent;
+            }
+            score
+        }
+    }
+
     #[derive(Clone)]
     pub struct Segment {
         pub start_s: f32,
@@ -1427,22 +4296,55 @@ pub mod prescan {
         pub peak: WindowFeat,
     }
 
-    /// Simple fingerprint: sequence of coarse-band peak indices.
+    /// Fingerprint flavours, as stored in the `fp_type` column.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum FpType {
+        BandPeakV1, // strongest coarse band per frame (linear bands: kept for stored songs)
+        ConstellationV2, // spectral peaks, compared as hashed peak pairs (landmarks)
+        MelPeakV3, // strongest mel band per frame, on a grid fixed in time and Hz
+    }
+
+    impl FpType {
+        pub fn parse(s: &str) -> Option<Self> {
+            match s.trim().to_lowercase().as_str() {
+                "bandpeak_v1" | "bandpeak" => Some(FpType::BandPeakV1),
+                "constellation_v2" | "constellation" => Some(FpType::ConstellationV2),
+                "melpeak_v3" | "melpeak" => Some(FpType::MelPeakV3),
+                _ => None,
+            }
+        }
+
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                FpType::BandPeakV1 => "bandpeak_v1",
+                FpType::ConstellationV2 => "constellation_v2",
+                FpType::MelPeakV3 => "melpeak_v3",
+            }
+        }
+    }
+
+    /// Stored fingerprint. `bins` depends on the type: one band index per frame for
+    /// `bandpeak_v1`, packed peaks (frame u16 BE, band u8) for `constellation_v2`.
     #[derive(Clone, Debug)]
     pub struct Fingerprint {
-        pub fp_type: String, // "bandpeak_v1"
-        pub bands: usize, // number of coarse bands
+        pub fp_type: String, // FpType::as_str
+        pub bands: usize, // number of frequency bands
         pub hop_s: f32, // time between frames (seconds)
         pub offset_s: f32, // window start (relative to track start)
-        pub bins: Vec<u8>, // per frame: argmax band index (0..bands-1)
+        pub bins: Vec<u8>,
+    }
+
+    /// Seconds from the track start that `make_fingerprint` looks at.
+    pub fn fingerprint_lead_s(win_s: f32) -> f32 {
+        (7.0f32).max(win_s + 1.0)
     }
 
     /// Build a fingerprint from the most energetic `win_s` inside the first ~7s.
-    pub fn make_fingerprint(samples: &[f32], sr: f32, win_s: f32) -> Option<Fingerprint> {
+    pub fn make_fingerprint(samples: &[f32], sr: f32, win_s: f32, fp_type: FpType) -> Option<Fingerprint> {
         if samples.is_empty() || sr <= 0.0 {
             return None;
         }
-        let seek_s = (7.0f32).max(win_s + 1.0);
+        let seek_s = fingerprint_lead_s(win_s);
         let total_s = (samples.len() as f32) / sr;
         let search_s = seek_s.min(total_s).max(win_s);
 
@@ -1457,8 +4359,8 @@ pub mod prescan {
 
         // Sliding RMS to find most energetic window
         let mut cur_e = 0.0f64;
-        for k in 0..win_len {
-            let v = samples[k] as f64;
+        for &x in &samples[..win_len] {
+            let v = x as f64;
             cur_e += v * v;
         }
         let mut best_e = cur_e;
@@ -1475,6 +4377,114 @@ pub mod prescan {
             i += 1;
         }
 
+        let window = &samples[best_i..best_i + win_len];
+        let (bands, hop_s, bins) = match fp_type {
+            FpType::BandPeakV1 => bandpeak_bins(window, sr),
+            FpType::ConstellationV2 => constellation_bins(window, sr),
+            FpType::MelPeakV3 => melpeak_bins(window, sr),
+        };
+        if bins.is_empty() {
+            return None;
+        }
+
+        Some(Fingerprint {
+            fp_type: fp_type.as_str().to_string(),
+            bands,
+            hop_s,
+            offset_s: (best_i as f32) / sr,
+            bins,
+        })
+    }
+
+    /// Fingerprints along a track fed block by block: the usual lead-in one, then one of exactly
+    /// `win_s` every `every_s` from there on, so live playback joined mid-song overlaps a stored
+    /// window (fully covered when `every_s` <= `win_s`). Only the current window is buffered.
+    pub struct Fingerprinter {
+        sr: f32,
+        win_s: f32,
+        fp_type: FpType,
+        every: usize, // samples between window starts; 0 = lead-in only
+        lead_len: usize,
+        win_len: usize,
+        buf: Vec<f32>, // audio from `buf_start` on
+        buf_start: usize,
+        next: usize, // start of the next window (absolute sample)
+        done: bool,
+        fps: Vec<Fingerprint>,
+    }
+
+    impl Fingerprinter {
+        pub fn new(sr: f32, win_s: f32, fp_type: FpType, every_s: f32) -> Self {
+            let lead_len = ((fingerprint_lead_s(win_s) * sr).ceil() as usize) + 1;
+            Self {
+                sr,
+                win_s,
+                fp_type,
+                every: if every_s > 0.0 { ((every_s * sr) as usize).max(1) } else { 0 },
+                lead_len,
+                win_len: ((win_s * sr) as usize).max(1),
+                buf: Vec::with_capacity(lead_len),
+                buf_start: 0,
+                next: 0,
+                done: false,
+                fps: Vec::new(),
+            }
+        }
+
+        pub fn push(&mut self, block: &[f32]) {
+            if self.done {
+                return;
+            }
+            self.buf.extend_from_slice(block);
+            self.skip_to_next();
+            while self.buf.len() >= self.window_len() {
+                self.take(self.window_len());
+                if self.every == 0 {
+                    self.done = true;
+                    self.buf = Vec::new();
+                    return;
+                }
+                self.next += self.every;
+                self.skip_to_next();
+            }
+        }
+
+        /// Fingerprints so far (the lead-in one first).
+        pub fn fingerprints(&self) -> &[Fingerprint] {
+            &self.fps
+        }
+
+        /// Fingerprint a lead-in that never filled (short track); later partial windows are dropped.
+        pub fn finish(mut self) -> Vec<Fingerprint> {
+            if self.next == 0 && !self.done && !self.buf.is_empty() {
+                let n = self.buf.len();
+                self.take(n);
+            }
+            self.fps
+        }
+
+        fn window_len(&self) -> usize {
+            if self.next == 0 { self.lead_len } else { self.win_len }
+        }
+
+        fn skip_to_next(&mut self) {
+            if self.buf_start < self.next {
+                let n = (self.next - self.buf_start).min(self.buf.len());
+                self.buf.drain(..n);
+                self.buf_start += n;
+            }
+        }
+
+        fn take(&mut self, n: usize) {
+            if let Some(mut fp) = make_fingerprint(&self.buf[..n], self.sr, self.win_s, self.fp_type) {
+                fp.offset_s += (self.buf_start as f32) / self.sr;
+                self.fps.push(fp);
+            }
+        }
+    }
+
+    /// `bandpeak_v1`: argmax of 32 coarse bands (0–6 kHz) per ~23 ms frame.
+    fn bandpeak_bins(window: &[f32], sr: f32) -> (usize, f32, Vec<u8>) {
         // Spectrogram params
         let mut planner = RealFftPlanner::<f32>::new();
         let frame_len = ((sr * 0.023) as usize).max(256).next_power_of_two();
@@ -1490,61 +4500,319 @@ pub mod prescan {
         let k_max = ((max_hz / bin_hz).floor() as usize).max(8);
         let band_size = (k_max / n_bands).max(1);
 
-        // Walk frames across the selected window.
-        let start = best_i;
-        let end = start + win_len;
-        let mut bins = Vec::<u8>::new();
+        // Walk frames across the selected window.
+        let mut bins = Vec::<u8>::new();
+
+        let mut pos = 0usize;
+        while pos + frame_len <= window.len() {
+            for j in 0..frame_len {
+                inbuf[j] = window[pos + j] * hann_win[j];
+            }
+            r2c.process(&mut inbuf, &mut outbuf).ok();
+
+            // magnitude-squared energy per coarse band
+            let mut band_e = vec![0.0f32; n_bands];
+            for (k, c) in outbuf.iter().enumerate().take(k_max) {
+                let b = (k / band_size).min(n_bands - 1);
+                let v = c.norm_sqr();
+                band_e[b] += v;
+            }
+
+            // pick peak band (ties → lower index)
+            let mut best_b = 0usize;
+            let mut best_v = -1.0f32;
+            for (b, &e) in band_e.iter().enumerate().take(n_bands) {
+                if e > best_v {
+                    best_v = e;
+                    best_b = b;
+                }
+            }
+            bins.push(best_b as u8);
+
+            pos += hop_len;
+        }
+
+        (n_bands, (hop_len as f32) / sr, bins)
+    }
+
+    // melpeak_v3 parameters: frames fixed in time (not a power of two), bands fixed in Hz
+    const MELPEAK_BANDS: usize = 32;
+    const MELPEAK_LO_HZ: f32 = 100.0;
+    const MELPEAK_HI_HZ: f32 = 6000.0;
+    const MELPEAK_FRAME_S: f32 = 0.032;
+    const MELPEAK_HOP_S: f32 = 0.016;
+
+    /// `melpeak_v3`: argmax of 32 mel bands (100 Hz–6 kHz) per 16 ms hop. Unlike `bandpeak_v1`,
+    /// whose equal-width bands spend most of their resolution above 2 kHz and move with the
+    /// frame length, the bands and frames are the same at any sample rate.
+    fn melpeak_bins(window: &[f32], sr: f32) -> (usize, f32, Vec<u8>) {
+        let mut planner = RealFftPlanner::<f32>::new();
+        let frame_len = ((sr * MELPEAK_FRAME_S).round() as usize).max(256);
+        let hop_len = ((sr * MELPEAK_HOP_S).round() as usize).max(1);
+        let hann_win = super::prescan::hann(frame_len);
+        let r2c = planner.plan_fft_forward(frame_len);
+        let mut inbuf = vec![0.0f32; frame_len];
+        let mut outbuf = r2c.make_output_vec();
+        let hi_hz = MELPEAK_HI_HZ.min(sr * 0.5);
+        let map = mel_band_map(MELPEAK_BANDS, MELPEAK_LO_HZ, hi_hz, sr / (frame_len as f32), outbuf.len());
+
+        let mut bins = Vec::<u8>::new();
+        let mut pos = 0usize;
+        while pos + frame_len <= window.len() {
+            for j in 0..frame_len {
+                inbuf[j] = window[pos + j] * hann_win[j];
+            }
+            r2c.process(&mut inbuf, &mut outbuf).ok();
+            let mut band_e = [0.0f32; MELPEAK_BANDS];
+            for (c, b) in outbuf.iter().zip(&map) {
+                if let Some(b) = b {
+                    band_e[*b] += c.norm_sqr();
+                }
+            }
+            // ties → lower band
+            let mut best_b = 0usize;
+            for b in 1..MELPEAK_BANDS {
+                if band_e[b] > band_e[best_b] {
+                    best_b = b;
+                }
+            }
+            bins.push(best_b as u8);
+            pos += hop_len;
+        }
+        (MELPEAK_BANDS, (hop_len as f32) / sr, bins)
+    }
+
+    // constellation_v2 parameters. The hop is a fixed 10 ms (not a power of two) so frame
+    // indices line up between fingerprints taken at different sample rates.
+    const CONST_BANDS: usize = 128; // linear bands over 0..CONST_MAX_HZ (~39 Hz each)
+    const CONST_MAX_HZ: f32 = 5000.0;
+    const CONST_FRAME_S: f32 = 0.04; // fixed in time, so any sample rate sees the same grid
+    const CONST_HOP_S: f32 = 0.01;
+    const CONST_PEAK_DT: usize = 5; // peak must dominate ±5 frames…
+    const CONST_PEAK_DB: usize = 4; // …and ±4 bands
+    const CONST_PEAK_MIN_DB: f32 = 8.0; // …and stand this far above its frame's mean
+    const CONST_SMOOTH: usize = 2; // ±2 frame moving average before peak picking
+    const CONST_FLOOR_DB: f32 = 60.0; // ignore cells this far below the window's loudest
+    const CONST_PAIR_DT: u16 = 63; // target zone: up to 63 frames later (6-bit)…
+    const CONST_PAIR_DB: i32 = 16; // …and within ±16 bands
+    pub const CONST_DT_TOL: i32 = 2; // frames of jitter tolerated in Δt and in the offset vote
+
+    /// `constellation_v2`: local maxima of the log spectrogram, packed as (frame u16 BE, band u8).
+    /// Peaks are judged against their neighbours and their own frame only, never against the
+    /// rest of the window, so the same audio yields the same peaks wherever a window starts;
+    /// a smooth EQ or a volume change moves neighbouring bands together and leaves them in place.
+    fn constellation_bins(window: &[f32], sr: f32) -> (usize, f32, Vec<u8>) {
+        let mut planner = RealFftPlanner::<f32>::new();
+        let frame_len = ((sr * CONST_FRAME_S).round() as usize).max(256);
+        let hop_len = ((sr * CONST_HOP_S).round() as usize).max(1);
+        let hann_win = super::prescan::hann(frame_len);
+        let r2c = planner.plan_fft_forward(frame_len);
+        let mut inbuf = vec![0.0f32; frame_len];
+        let mut outbuf = r2c.make_output_vec();
+
+        let bin_hz = sr / (frame_len as f32);
+        let band_hz = CONST_MAX_HZ / (CONST_BANDS as f32);
+
+        // log band-energy spectrogram
+        let mut spec: Vec<[f32; CONST_BANDS]> = Vec::new();
+        let mut pos = 0usize;
+        while pos + frame_len <= window.len() {
+            for j in 0..frame_len {
+                inbuf[j] = window[pos + j] * hann_win[j];
+            }
+            r2c.process(&mut inbuf, &mut outbuf).ok();
+            // mean power per bin: bands hold one or two bins depending on the rate
+            let mut band_e = [0.0f32; CONST_BANDS];
+            let mut band_n = [0u32; CONST_BANDS];
+            for (k, c) in outbuf.iter().enumerate().skip(1) {
+                let b = (((k as f32) * bin_hz) / band_hz) as usize;
+                if b >= CONST_BANDS {
+                    break;
+                }
+                band_e[b] += c.norm_sqr();
+                band_n[b] += 1;
+            }
+            for (e, &n) in band_e.iter_mut().zip(&band_n) {
+                *e = 10.0 * (*e / (n.max(1) as f32) + 1e-12).log10();
+            }
+            spec.push(band_e);
+            pos += hop_len;
+        }
+        let n = spec.len();
+        if n == 0 || n > (u16::MAX as usize) {
+            return (CONST_BANDS, CONST_HOP_S, Vec::new());
+        }
+
+        // short moving average over time: a sustained note becomes a hump peaking mid-note
+        // instead of a noisy plateau, so peak times do not depend on where the window starts
+        let raw = spec.clone();
+        for (t, f) in spec.iter_mut().enumerate() {
+            let lo = t.saturating_sub(CONST_SMOOTH);
+            let hi = (t + CONST_SMOOTH).min(n - 1);
+            for b in 0..CONST_BANDS {
+                f[b] = (lo..=hi).map(|tt| raw[tt][b]).sum::<f32>() / ((hi - lo + 1) as f32);
+            }
+        }
+
+        let loudest = spec
+            .iter()
+            .flat_map(|f| f.iter())
+            .fold(f32::MIN, |m, &v| m.max(v));
+        let floor = loudest - CONST_FLOOR_DB;
+
+        // local maxima over a time × band neighbourhood that stand out from their frame
+        let mut peaks: Vec<(u16, u8)> = Vec::new();
+        for t in 0..n {
+            let frame_mean = spec[t].iter().sum::<f32>() / (CONST_BANDS as f32);
+            for b in 1..CONST_BANDS {
+                let v = spec[t][b];
+                if v < floor || v - frame_mean < CONST_PEAK_MIN_DB {
+                    continue;
+                }
+                let t_lo = t.saturating_sub(CONST_PEAK_DT);
+                let t_hi = (t + CONST_PEAK_DT).min(n - 1);
+                let b_lo = b.saturating_sub(CONST_PEAK_DB).max(1);
+                let b_hi = (b + CONST_PEAK_DB).min(CONST_BANDS - 1);
+                let is_peak = (t_lo..=t_hi).all(|tt| {
+                    (b_lo..=b_hi).all(|bb| (tt, bb) == (t, b) || spec[tt][bb] < v)
+                });
+                if is_peak {
+                    peaks.push((t as u16, b as u8));
+                }
+            }
+        }
+
+        let mut bins = Vec::with_capacity(peaks.len() * 3);
+        for (t, b) in peaks {
+            bins.extend_from_slice(&t.to_be_bytes());
+            bins.push(b);
+        }
+        (CONST_BANDS, (hop_len as f32) / sr, bins)
+    }
 
-        let mut pos = start;
-        while pos + frame_len <= end {
-            for j in 0..frame_len {
-                inbuf[j] = samples[pos + j] * hann_win[j];
-            }
-            r2c.process(&mut inbuf, &mut outbuf).ok();
+    /// Peaks of a `constellation_v2` fingerprint: (frame, band), in time order.
+    fn peaks(fp: &Fingerprint) -> Vec<(u16, u8)> {
+        fp.bins
+            .chunks_exact(3)
+            .map(|c| (u16::from_be_bytes([c[0], c[1]]), c[2]))
+            .collect()
+    }
 
-            // magnitude-squared energy per coarse band
-            let mut band_e = vec![0.0f32; n_bands];
-            for (k, c) in outbuf.iter().enumerate().take(k_max) {
-                let b = (k / band_size).min(n_bands - 1);
-                let v = c.norm_sqr();
-                band_e[b] += v;
+    /// Landmarks: (hash of band1/band2/Δframes, anchor frame).
+    fn landmarks(peaks: &[(u16, u8)]) -> Vec<(u32, u16)> {
+        let mut out = Vec::new();
+        for (i, &(t1, b1)) in peaks.iter().enumerate() {
+            let targets = peaks[i + 1..]
+                .iter()
+                .filter(|&&(t2, _)| t2 > t1)
+                .take_while(|&&(t2, _)| t2 - t1 <= CONST_PAIR_DT)
+                .filter(|&&(_, b2)| (b2 as i32 - b1 as i32).abs() <= CONST_PAIR_DB);
+            for &(t2, b2) in targets {
+                let hash = ((b1 as u32) << 14) | ((b2 as u32) << 6) | ((t2 - t1) as u32);
+                out.push((hash, t1));
             }
+        }
+        out
+    }
 
-            // pick peak band (ties → lower index)
-            let mut best_b = 0usize;
-            let mut best_v = -1.0f32;
-            for b in 0..n_bands {
-                if band_e[b] > best_v {
-                    best_v = band_e[b];
-                    best_b = b;
+    /// Landmarks of a `constellation_v2` fingerprint (empty for other types).
+    pub fn constellation_landmarks(fp: &Fingerprint) -> Vec<(u32, u16)> {
+        if FpType::parse(&fp.fp_type) != Some(FpType::ConstellationV2) {
+            return Vec::new();
+        }
+        landmarks(&peaks(fp))
+    }
+
+    /// Hashes a landmark may be stored under once peaks land a frame or two apart (Δt ± tolerance).
+    pub fn tolerant_hashes(h: u32) -> impl Iterator<Item = u32> {
+        let dt = (h & 0x3f) as i32;
+        ((dt - CONST_DT_TOL).max(1)..=(dt + CONST_DT_TOL).min(CONST_PAIR_DT as i32)).map(
+            move |d| (h & !0x3f) | (d as u32)
+        )
+    }
+
+    /// Fraction of landmarks that agree on one time offset (±2 frames). Only the span where the
+    /// two windows overlap at that offset counts, so a live window cut at a different point of
+    /// the song still scores high; overlaps under 40% of the shorter window score 0.
+    fn constellation_similarity(a: &Fingerprint, b: &Fingerprint) -> (f32, f32) {
+        // hops differ only if the sample rate is not a multiple of 100 Hz
+        if (a.hop_s - b.hop_s).abs() > 0.05 * a.hop_s.max(b.hop_s) {
+            return (0.0, 0.0);
+        }
+        let (pa, pb) = (peaks(a), peaks(b));
+        let (la, lb) = (landmarks(&pa), landmarks(&pb));
+        if la.is_empty() || lb.is_empty() {
+            return (0.0, 0.0);
+        }
+        let mut table: HashMap<u32, Vec<u16>> = HashMap::new();
+        for &(h, t) in &lb {
+            table.entry(h).or_default().push(t);
+        }
+        // peaks may land a frame or two apart in the two windows: look Δt up with some slack,
+        // counting each live landmark at most once per offset
+        let mut offsets: HashMap<i32, usize> = HashMap::new();
+        let mut seen: Vec<i32> = Vec::new();
+        for &(h, ta) in &la {
+            seen.clear();
+            for key in tolerant_hashes(h) {
+                let Some(tbs) = table.get(&key) else {
+                    continue;
+                };
+                for &tb in tbs {
+                    let o = (tb as i32) - (ta as i32);
+                    if !seen.contains(&o) {
+                        seen.push(o);
+                        *offsets.entry(o).or_default() += 1;
+                    }
                 }
             }
-            bins.push(best_b as u8);
-
-            pos += hop_len;
         }
+        let votes = |o: i32| {
+            (o - CONST_DT_TOL..=o + CONST_DT_TOL).filter_map(|k| offsets.get(&k)).sum::<usize>()
+        };
+        let Some((best_o, best)) = offsets
+            .keys()
+            .map(|&o| (o, votes(o)))
+            .max_by_key(|&(o, v)| (v, -o.abs(), o))
+        else {
+            return (0.0, 0.0);
+        };
 
-        if bins.is_empty() {
-            return None;
+        // overlap of a's frames [0, len_a) with b's frames shifted back by the offset
+        let span = |p: &[(u16, u8)]| p.last().map_or(0, |&(t, _)| (t as i32) + 1);
+        let (len_a, len_b) = (span(&pa), span(&pb));
+        let lo = (-best_o).max(0);
+        let hi = len_a.min(len_b - best_o);
+        if ((hi - lo) as f32) < 0.4 * (len_a.min(len_b) as f32) {
+            return (0.0, 0.0);
         }
-
-        Some(Fingerprint {
-            fp_type: "bandpeak_v1".to_string(),
-            bands: n_bands,
-            hop_s: (hop_len as f32) / sr,
-            offset_s: (start as f32) / sr,
-            bins,
-        })
+        // a landmark counts only if both of its peaks fall inside the overlap
+        let inside = |l: &[(u32, u16)], shift: i32| {
+            l.iter()
+                .filter(|&&(h, t)| {
+                    let t1 = (t as i32) - shift;
+                    let t2 = t1 + ((h & 0x3f) as i32);
+                    t1 >= lo && t2 < hi
+                })
+                .count()
+        };
+        let (in_a, in_b) = (inside(&la, 0), inside(&lb, best_o));
+        (((best as f32) / (in_a.min(in_b).max(1) as f32)).min(1.0), (best_o as f32) * a.hop_s)
     }
 
-    /// Compare two fingerprints; return similarity ∈ [0,1].
-    /// Sweeps a small lag window (±0.5 s) and returns best coincidence ratio.
-    pub fn fp_similarity(a: &Fingerprint, b: &Fingerprint) -> f32 {
+    /// Compare two fingerprints; return (similarity ∈ [0,1], lag_s), where content at time t of
+    /// `a`'s window sits at t + lag_s in `b`'s window.
+    /// `bandpeak_v1` sweeps a small lag window (±0.5 s) and returns the best coincidence ratio;
+    /// `constellation_v2` lets matching landmarks vote on the offset, so any lag is found.
+    pub fn fp_match(a: &Fingerprint, b: &Fingerprint) -> (f32, f32) {
         if a.fp_type != b.fp_type || a.bands != b.bands {
-            return 0.0;
+            return (0.0, 0.0);
         }
         if a.bins.is_empty() || b.bins.is_empty() {
-            return 0.0;
+            return (0.0, 0.0);
+        }
+        if FpType::parse(&a.fp_type) == Some(FpType::ConstellationV2) {
+            return constellation_similarity(a, b);
         }
 
         let step = a.hop_s.min(b.hop_s);
@@ -1552,11 +4820,11 @@ pub mod prescan {
         let dur_b = (b.bins.len().saturating_sub(1) as f32) * b.hop_s;
         let t_common = dur_a.min(dur_b);
         if t_common <= 0.0 {
-            return 0.0;
+            return (0.0, 0.0);
         }
 
         let lag_max = 0.5_f32;
-        let mut best = 0.0_f32;
+        let mut best = (0.0_f32, 0.0_f32);
 
         let mut lag = -lag_max;
         while lag <= lag_max + 1e-6 {
@@ -1582,8 +4850,8 @@ pub mod prescan {
 
             if total > 0 {
                 let s = (hits as f32) / (total as f32);
-                if s > best {
-                    best = s;
+                if s > best.0 {
+                    best = (s, lag);
                 }
             }
 
@@ -1595,95 +4863,186 @@ pub mod prescan {
 
     /// Compute per-window features and ranked segments
     pub fn analyze(samples: &[f32], p: &ScanParams) -> Vec<Segment> {
-        if samples.len() < (p.sr as usize) {
-            return vec![];
+        let mut analyzer = Analyzer::new(p);
+        analyzer.push(samples);
+        analyzer.finish()
+    }
+
+    /// Per-frame scalars kept until every window covering the frame has been scored.
+    struct FrameStat {
+        rms: f32,
+        crest_db: f32,
+        flux: f32,
+        time_s: f32,
+    }
+
+    /// Spectral features of a window's middle frame.
+    struct MidSpectrum {
+        bandwidth_hz_95: f32,
+        flatness: f32,
+        hf_ratio: f32,
+        mel_db: [f32; MEL_BANDS],
+    }
+
+    /// Incremental `analyze`: feed samples in chunks of any size, then `finish`.
+    /// Spectra are reduced to a few numbers as soon as they are computed, so memory
+    /// holds one window of frame scalars plus the per-window features, not the track.
+    pub struct Analyzer<'a> {
+        p: &'a ScanParams,
+        frame_len: usize,
+        hop_len: usize,
+        hann_win: Vec<f32>,
+        r2c: std::sync::Arc<dyn realfft::RealToComplex<f32>>,
+        inbuf: Vec<f32>,
+        outbuf: Vec<realfft::num_complex::Complex<f32>>,
+        prev_mag: Option<Vec<f32>>,
+
+        frames_per_win: usize,
+        stride_frames: usize,
+        bin_hz: f32,
+        hf_bin: usize,
+        mel_map: Vec<Option<usize>>, // mel band of each bin
+        window_len_s: f32,
+
+        pending: Vec<f32>, // samples not yet consumed by a frame
+        pending_start: usize, // sample index of pending[0]
+        total_samples: usize,
+        next_frame: usize,
+
+        frames: VecDeque<FrameStat>,
+        frames_start: usize, // frame index of frames[0]
+        mids: VecDeque<(usize, MidSpectrum)>,
+        next_win: usize, // first frame of the next window to score
+        wins: Vec<WindowFeat>,
+        spectrogram: Option<crate::spectrogram::Spectrogram>, // --export-spectrogram: every frame, kept
+    }
+
+    impl<'a> Analyzer<'a> {
+        pub fn new(p: &'a ScanParams) -> Self {
+            let frame_len = (((p.sr * p.frame_ms) / 1000.0).round() as usize)
+                .max(256)
+                .next_power_of_two();
+            let hop_len = (frame_len / 2).max(1);
+
+            let mut planner = RealFftPlanner::<f32>::new();
+            let r2c = planner.plan_fft_forward(frame_len);
+            let outbuf = r2c.make_output_vec();
+
+            let frames_per_win = ((p.window_s * p.sr) / (hop_len as f32)).round().max(1.0) as usize;
+            let stride_frames = (((p.stride_ms / 1000.0) * p.sr) / (hop_len as f32))
+                .round()
+                .max(1.0) as usize;
+            let bin_hz = p.sr / (frame_len as f32);
+
+            Self {
+                p,
+                frame_len,
+                hop_len,
+                hann_win: hann(frame_len),
+                r2c,
+                inbuf: vec![0.0f32; frame_len],
+                outbuf,
+                prev_mag: None,
+                frames_per_win,
+                stride_frames,
+                bin_hz,
+                hf_bin: (p.hf_split_hz / bin_hz).floor() as usize,
+                mel_map: mel_band_map(MEL_BANDS, MEL_LO_HZ, MEL_HI_HZ.min(p.sr * 0.5), bin_hz, frame_len / 2 + 1),
+                window_len_s: ((frames_per_win * hop_len) as f32) / p.sr,
+                pending: Vec::new(),
+                pending_start: 0,
+                total_samples: 0,
+                next_frame: 0,
+                frames: VecDeque::new(),
+                frames_start: 0,
+                mids: VecDeque::new(),
+                next_win: 0,
+                wins: Vec::new(),
+                spectrogram: None,
+            }
         }
 
-        // --- frame-level processing
-        let frame_len = (((p.sr * p.frame_ms) / 1000.0).round() as usize)
-            .max(256)
-            .next_power_of_two();
-        let hop_len = (frame_len / 2).max(1);
-        let hann_win = hann(frame_len);
+        /// Also keep every frame's band levels (`--export-spectrogram`).
+        pub fn keep_spectrogram(&mut self) {
+            self.spectrogram = Some(crate::spectrogram::Spectrogram::new(self.p.sr, self.frame_len, self.hop_len, &self.hann_win));
+        }
 
-        let mut planner = RealFftPlanner::<f32>::new();
-        let r2c = planner.plan_fft_forward(frame_len);
-        let mut inbuf = vec![0.0f32; frame_len];
-        let mut outbuf = r2c.make_output_vec();
+        /// The band levels kept so far, handed over.
+        pub fn take_spectrogram(&mut self) -> Option<crate::spectrogram::Spectrogram> {
+            self.spectrogram.take()
+        }
 
-        let mut frame_mags: Vec<Vec<f32>> = Vec::new();
-        let mut frame_rms: Vec<f32> = Vec::new();
-        let mut frame_crest: Vec<f32> = Vec::new();
-        let mut frame_times: Vec<f32> = Vec::new();
+        /// Samples analysed so far.
+        pub fn samples_seen(&self) -> usize {
+            self.total_samples
+        }
 
-        let nframes = samples.len().saturating_sub(frame_len) / hop_len + 1;
-        for f in 0..nframes {
-            let start = f * hop_len;
-            let end = start + frame_len;
-            if end > samples.len() {
-                break;
+        pub fn push(&mut self, samples: &[f32]) {
+            self.pending.extend_from_slice(samples);
+            self.total_samples += samples.len();
+
+            // --- frame-level processing
+            while self.next_frame * self.hop_len + self.frame_len <= self.total_samples {
+                let start = self.next_frame * self.hop_len;
+                let off = start - self.pending_start;
+                for i in 0..self.frame_len {
+                    self.inbuf[i] = self.pending[off + i] * self.hann_win[i];
+                }
+                self.frame(start);
+                self.next_frame += 1;
+                self.score_ready_windows();
             }
 
-            for i in 0..frame_len {
-                inbuf[i] = samples[start + i] * hann_win[i];
+            let consumed = (self.next_frame * self.hop_len).min(self.total_samples);
+            if consumed > self.pending_start {
+                self.pending.drain(..consumed - self.pending_start);
+                self.pending_start = consumed;
             }
+        }
 
-            let r = super::prescan::rms(&inbuf);
-            let peak = inbuf.iter().fold(0.0_f32, |m, &v| m.max(v.abs()));
+        /// Features of the frame now windowed in `inbuf`.
+        fn frame(&mut self, start: usize) {
+            let r = rms(&self.inbuf);
+            let peak = self.inbuf.iter().fold(0.0_f32, |m, &v| m.max(v.abs()));
             let crest_db = if r > 1e-9 { 20.0 * (peak / r).log10().max(0.0) } else { 0.0 };
 
-            r2c.process(&mut inbuf, &mut outbuf).ok();
-            let mag: Vec<f32> = outbuf
+            self.r2c.process(&mut self.inbuf, &mut self.outbuf).ok();
+            let mag: Vec<f32> = self.outbuf
                 .iter()
                 .map(|c| c.norm())
                 .collect();
 
-            frame_mags.push(mag);
-            frame_rms.push(r);
-            frame_crest.push(crest_db);
-            frame_times.push((start as f32) / p.sr);
-        }
-
-        if frame_mags.is_empty() {
-            return vec![];
-        }
-
-        let frames_per_win = ((p.window_s * p.sr) / (hop_len as f32)).round().max(1.0) as usize;
-        let stride_frames = (((p.stride_ms / 1000.0) * p.sr) / (hop_len as f32))
-            .round()
-            .max(1.0) as usize;
-
-        let bin_hz = p.sr / (frame_len as f32);
-        let hf_bin = (p.hf_split_hz / bin_hz).floor() as usize;
-
-        // spectral flux per frame
-        let mut flux_per_frame: Vec<f32> = vec![0.0; frame_mags.len()];
-        let mut prev: Option<&[f32]> = None;
-        for (i, m) in frame_mags.iter().enumerate() {
-            if let Some(pm) = prev {
-                let mut flux = 0.0f32;
-                for k in 0..m.len() {
-                    let d = m[k] - pm[k];
-                    if d > 0.0 {
-                        flux += d;
+            // spectral flux against the previous frame
+            let flux = match &self.prev_mag {
+                Some(pm) => {
+                    let mut flux = 0.0f32;
+                    for k in 0..mag.len() {
+                        let d = mag[k] - pm[k];
+                        if d > 0.0 {
+                            flux += d;
+                        }
                     }
+                    flux / (mag.len() as f32)
                 }
-                flux_per_frame[i] = flux / (m.len() as f32);
-            }
-            prev = Some(m);
-        }
+                None => 0.0,
+            };
 
-        let mut wins: Vec<WindowFeat> = Vec::new();
-        let total_frames = frame_mags.len();
-        let window_len_s = ((frames_per_win * hop_len) as f32) / p.sr;
+            // the middle frame of each window supplies its spectral shape
+            let f = self.next_frame;
+            let half = self.frames_per_win / 2;
+            if f >= half && (f - half).is_multiple_of(self.stride_frames) {
+                let spec = self.mid_spectrum(&mag);
+                self.mids.push_back((f, spec));
+            }
 
-        let mut idx = 0usize;
-        while idx + frames_per_win <= total_frames {
-            let s_idx = idx;
-            let e_idx = idx + frames_per_win;
+            if let Some(s) = self.spectrogram.as_mut() {
+                s.push(&mag);
+            }
+            self.frames.push_back(FrameStat { rms: r, crest_db, flux, time_s: (start as f32) / self.p.sr });
+            self.prev_mag = Some(mag);
+        }
 
-            let mid = (s_idx + e_idx) / 2;
-            let mag = &frame_mags[mid];
+        fn mid_spectrum(&self, mag: &[f32]) -> MidSpectrum {
             let power: Vec<f32> = mag
                 .iter()
                 .map(|v| v * v)
@@ -1700,7 +5059,7 @@ pub mod prescan {
                     break;
                 }
             }
-            let bandwidth_hz_95 = (roll95_bin as f32) * bin_hz;
+            let bandwidth_hz_95 = (roll95_bin as f32) * self.bin_hz;
 
             // flatness (GM/AM)
             let gm = (
@@ -1716,45 +5075,108 @@ pub mod prescan {
             let hf_e = power
                 .iter()
                 .enumerate()
-                .filter(|(k, _)| *k >= hf_bin)
+                .filter(|(k, _)| *k >= self.hf_bin)
                 .map(|(_, v)| *v)
                 .sum::<f32>();
             let hf_ratio = (hf_e / total_e).clamp(0.0, 1.0);
 
-            // crest / flux / loudness / dyn range in window
-            let crest_db = percentile(frame_crest[s_idx..e_idx].to_vec(), 75.0);
-            let flux = percentile(flux_per_frame[s_idx..e_idx].to_vec(), 90.0);
-            let r_med = median(frame_rms[s_idx..e_idx].to_vec());
-            let loudness_dbfs = if r_med > 1e-9 { 20.0 * r_med.log10() } else { -120.0 };
-            let r95 = percentile(frame_rms[s_idx..e_idx].to_vec(), 95.0);
-            let r50 = percentile(frame_rms[s_idx..e_idx].to_vec(), 50.0);
-            let dyn_range = (20.0 * (r95.max(1e-9) / r50.max(1e-9)).log10()).max(0.0);
-
-            let start_s = frame_times[s_idx];
-            let end_s = start_s + window_len_s;
-            wins.push(WindowFeat {
-                start_s,
-                end_s,
-                flux,
-                flatness,
-                crest_db,
-                bandwidth_hz_95,
-                hf_ratio,
-                dyn_range,
-                tonality: (1.0 - flatness).clamp(0.0, 1.0),
-                loudness_dbfs,
-                score: 0.0,
-                z: FeatZ::default(),
-            });
+            let mut mel_e = [0.0f32; MEL_BANDS];
+            for (pwr, b) in power.iter().zip(&self.mel_map) {
+                if let Some(b) = b {
+                    mel_e[*b] += *pwr;
+                }
+            }
+            let mel_db = mel_e.map(|e| 10.0 * (e / total_e + 1e-12).log10());
 
-            idx += stride_frames;
+            MidSpectrum { bandwidth_hz_95, flatness, hf_ratio, mel_db }
         }
 
-        if wins.is_empty() {
-            return vec![];
+        /// Score every window whose frames have all arrived, then forget frames no window needs.
+        fn score_ready_windows(&mut self) {
+            while self.next_win + self.frames_per_win <= self.next_frame {
+                let s_idx = self.next_win;
+                let mid = s_idx + self.frames_per_win / 2;
+                while self.mids.front().is_some_and(|(f, _)| *f < mid) {
+                    self.mids.pop_front();
+                }
+                let spec = match self.mids.front() {
+                    Some((f, spec)) if *f == mid => spec,
+                    _ => unreachable!("mid-frame spectrum recorded for every window"),
+                };
+
+                let span = || self.frames.range(s_idx - self.frames_start..s_idx - self.frames_start + self.frames_per_win);
+
+                // crest / flux / loudness / dyn range in window
+                let frame_rms: Vec<f32> = span().map(|f| f.rms).collect();
+                let crest_db = percentile(span().map(|f| f.crest_db).collect(), 75.0);
+                let flux = percentile(span().map(|f| f.flux).collect(), 90.0);
+                let r_med = median(frame_rms.clone());
+                let loudness_dbfs = if r_med > 1e-9 { 20.0 * r_med.log10() } else { -120.0 };
+                let r95 = percentile(frame_rms.clone(), 95.0);
+                let r50 = percentile(frame_rms, 50.0);
+                let dyn_range = (20.0 * (r95.max(1e-9) / r50.max(1e-9)).log10()).max(0.0);
+
+                let start_s = self.frames[s_idx - self.frames_start].time_s;
+                let end_s = start_s + self.window_len_s;
+                self.wins.push(WindowFeat {
+                    start_s,
+                    end_s,
+                    flux,
+                    flatness: spec.flatness,
+                    crest_db,
+                    bandwidth_hz_95: spec.bandwidth_hz_95,
+                    hf_ratio: spec.hf_ratio,
+                    dyn_range,
+                    tonality: (1.0 - spec.flatness).clamp(0.0, 1.0),
+                    loudness_dbfs,
+                    mel_db: spec.mel_db,
+                    score: 0.0,
+                    z: FeatZ::default(),
+                });
+
+                self.next_win += self.stride_frames;
+            }
+
+            let keep_from = self.next_win.min(self.next_frame);
+            while self.frames_start < keep_from && !self.frames.is_empty() {
+                self.frames.pop_front();
+                self.frames_start += 1;
+            }
+        }
+
+        /// Segments as they would rank if the input ended now.
+        pub fn snapshot(&self) -> Vec<Segment> {
+            if self.total_samples < (self.p.sr as usize) {
+                return vec![];
+            }
+            let mut wins = self.wins.clone();
+            score(&mut wins, self.p);
+            rank(&wins, self.p)
+        }
+
+        /// The scored windows themselves, unranked (every stride, in time order).
+        pub fn into_windows(self) -> Vec<WindowFeat> {
+            self.wins
         }
 
-        // z-scores + scoring
+        /// Rank the scored windows into segments.
+        pub fn finish(self) -> Vec<Segment> {
+            self.finish_with_windows().0
+        }
+
+        /// The segments, and every window with its z-scores and score filled in
+        /// (`--export-features`).
+        pub fn finish_with_windows(mut self) -> (Vec<Segment>, Vec<WindowFeat>) {
+            if self.total_samples < (self.p.sr as usize) {
+                return (vec![], vec![]);
+            }
+            score(&mut self.wins, self.p);
+            (rank(&self.wins, self.p), self.wins)
+        }
+    }
+
+    /// z-scores over the whole track, then each window's score.
+    fn score(wins: &mut [WindowFeat], p: &ScanParams) {
         let collect = |f: &dyn Fn(&WindowFeat) -> f32| -> Vec<f32> { wins.iter().map(f).collect() };
         let xs_flux = collect(&(|w| w.flux));
         let xs_flat = collect(&(|w| w.flatness));
@@ -1775,24 +5197,14 @@ pub mod prescan {
                 tonality_z: mad_zscore(&xs_tone, w.tonality),
             };
 
-            let mut score =
-                0.25 * z.flux_z +
-                0.2 * z.flatness_z +
-                0.2 * z.crest_z +
-                0.15 * z.bandwidth_z +
-                0.1 * z.hf_ratio_z +
-                0.1 * z.dynrange_z -
-                0.2 * z.tonality_z;
-
-            if w.loudness_dbfs < -45.0 {
-                score -= 0.5;
-            }
-            if w.loudness_dbfs < -60.0 {
-                score -= 1.0;
-            }
-
             w.z = z;
-            w.score = score as f32;
+            w.score = p.scorer.score(w);
+        }
+    }
+
+    fn rank(wins: &[WindowFeat], p: &ScanParams) -> Vec<Segment> {
+        if wins.is_empty() {
+            return vec![];
         }
 
         // local peaks above percentile + NMS + merge + clamp
@@ -1857,32 +5269,155 @@ pub mod prescan {
 
         segs
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::scanscore::Heuristic;
+
+        #[test]
+        fn chunked_push_matches_analyze() {
+            let params = ScanParams {
+                sr: 16000.0,
+                frame_ms: 32.0,
+                window_s: 1.0,
+                stride_ms: 250.0,
+                hf_split_hz: 4000.0,
+                top_n: 4,
+                min_percentile: 0.0,
+                nms_radius_s: 0.5,
+                merge_gap_s: 0.0,
+                clamp_min_s: 1.0,
+                clamp_max_s: 5.0,
+                scorer: Box::new(Heuristic(ScanWeights::default())),
+            };
+            // noise bursts over a tone, so the windows differ
+            let mut seed = 1u32;
+            let x: Vec<f32> = (0..16000 * 5 + 123)
+                .map(|i| {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    let noise = ((seed >> 8) as f32) / ((1 << 24) as f32) - 0.5;
+                    let t = (i as f32) / 16000.0;
+                    let burst = if ((t * 2.0) as usize).is_multiple_of(3) { 0.5 } else { 0.02 };
+                    0.2 * (2.0 * std::f32::consts::PI * 330.0 * t).sin() + burst * noise
+                })
+                .collect();
+
+            let mut analyzer = Analyzer::new(&params);
+            analyzer.push(&x);
+            let (segs, wins) = analyzer.finish_with_windows();
+
+            // odd chunk sizes, so frames and windows straddle pushes
+            let mut chunked = Analyzer::new(&params);
+            let (mut at, mut k) = (0, 0);
+            while at < x.len() {
+                let n = [1, 7, 333, 4099, 17][k % 5].min(x.len() - at);
+                chunked.push(&x[at..at + n]);
+                at += n;
+                k += 1;
+            }
+            assert_eq!(chunked.samples_seen(), x.len());
+            let (chunked_segs, chunked_wins) = chunked.finish_with_windows();
+
+            let window = |w: &WindowFeat| {
+                let mut v = vec![w.start_s, w.end_s, w.flux, w.flatness, w.crest_db, w.bandwidth_hz_95, w.hf_ratio];
+                v.extend([w.dyn_range, w.tonality, w.loudness_dbfs, w.score, w.z.flux_z, w.z.tonality_z]);
+                v.extend(w.mel_db);
+                v
+            };
+            assert!(wins.len() > 10);
+            assert_eq!(wins.iter().map(window).collect::<Vec<_>>(), chunked_wins.iter().map(window).collect::<Vec<_>>());
+            let seg = |s: &Segment| (s.start_s, s.end_s, s.peak.score);
+            assert!(!segs.is_empty());
+            assert_eq!(segs.iter().map(seg).collect::<Vec<_>>(), chunked_segs.iter().map(seg).collect::<Vec<_>>());
+        }
+    }
 }
 
 // ───────────────────────────────────────────────────────────────────────────────
 // Decoder for WAV/MP3/MP4 (AAC) using symphonia (used by offline mode)
 // ───────────────────────────────────────────────────────────────────────────────
 pub mod decode {
-    use std::{ fs::File, path::Path };
+    use std::{ fmt, fs::File, io, path::Path };
     use symphonia::core::{
         audio::SampleBuffer,
-        codecs::DecoderOptions,
+        codecs::{ Decoder, DecoderOptions },
         errors::Error,
-        formats::FormatOptions,
+        formats::{ FormatOptions, FormatReader, SeekMode, SeekTo },
         io::MediaSourceStream,
         meta::MetadataOptions,
         probe::Hint,
+        units::{ Time, TimeBase, TimeStamp },
     };
     use symphonia::default::{ get_codecs, get_probe };
+    use crate::audio::ChannelMix;
+
+    /// Why an audio file could not be read.
+    #[derive(Debug)]
+    pub enum DecodeError {
+        Open(io::Error), // the file itself
+        Format(Error), // not a container/codec symphonia reads, or corrupt past recovery
+        NoTrack,
+        NoSampleRate,
+        Seek(f64, Error), // target in seconds
+    }
+
+    impl fmt::Display for DecodeError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::Open(e) => write!(f, "{}", e),
+                Self::Format(e) => write!(f, "{}", e),
+                Self::NoTrack => write!(f, "no default audio track found"),
+                Self::NoSampleRate => write!(f, "unknown sample rate"),
+                Self::Seek(at_s, e) => write!(f, "cannot seek to {:.3}s: {}", at_s, e),
+            }
+        }
+    }
+
+    impl std::error::Error for DecodeError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                Self::Open(e) => Some(e),
+                Self::Format(e) | Self::Seek(_, e) => Some(e),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<io::Error> for DecodeError {
+        fn from(e: io::Error) -> Self {
+            Self::Open(e)
+        }
+    }
+
+    impl From<Error> for DecodeError {
+        fn from(e: Error) -> Self {
+            Self::Format(e)
+        }
+    }
 
     #[derive(Debug)]
     pub struct AudioData {
         pub sr: u32,
         pub channels: u16,
-        pub samples_mono: Vec<f32>, // first channel only
+        pub samples_mono: Vec<f32>, // all channels folded with the requested ChannelMix
+    }
+
+    /// Packet-by-packet mono decode of the default track; yields one chunk per decoded packet.
+    pub struct MonoStream {
+        pub sr: u32,
+        pub channels: u16,
+        mix: ChannelMix,
+        format: Box<dyn FormatReader>,
+        decoder: Box<dyn Decoder>,
+        track_id: u32,
+        time_base: Option<TimeBase>,
+        sample_buf: Option<SampleBuffer<f32>>,
+        skip_to_ts: Option<TimeStamp>, // after a seek: trim audio before this timestamp
+        done: bool,
     }
 
-    pub fn load_first_channel<P: AsRef<Path>>(path: P) -> anyhow::Result<AudioData> {
+    pub fn open_mono<P: AsRef<Path>>(path: P, mix: ChannelMix) -> Result<MonoStream, DecodeError> {
         let path_ref = path.as_ref();
 
         let file = File::open(path_ref)?;
@@ -1899,95 +5434,406 @@ pub mod decode {
             &FormatOptions::default(),
             &MetadataOptions::default()
         )?;
-        let mut format = probed.format;
+        let format = probed.format;
 
         let (track_id, codec_params) = {
             let track = format
                 .default_track()
-                .ok_or_else(|| anyhow::anyhow!("no default audio track found"))?;
+                .ok_or(DecodeError::NoTrack)?;
             (track.id, track.codec_params.clone())
         };
 
-        let mut decoder = get_codecs().make(&codec_params, &DecoderOptions::default())?;
+        let decoder = get_codecs().make(&codec_params, &DecoderOptions::default())?;
 
-        let sr = codec_params.sample_rate.ok_or_else(|| anyhow::anyhow!("unknown sample rate"))?;
+        let sr = codec_params.sample_rate.ok_or(DecodeError::NoSampleRate)?;
         let channels = codec_params.channels.map(|c| c.count() as u16).unwrap_or(1u16);
 
-        let mut sample_buf: Option<SampleBuffer<f32>> = None;
-        let mut mono = Vec::<f32>::new();
+        Ok(MonoStream {
+            sr,
+            channels,
+            mix,
+            format,
+            decoder,
+            track_id,
+            time_base: codec_params.time_base,
+            sample_buf: None,
+            skip_to_ts: None,
+            done: false,
+        })
+    }
 
-        loop {
-            let packet = match format.next_packet() {
-                Ok(packet) => packet,
-                Err(Error::ResetRequired) => {
-                    decoder.reset();
+    impl MonoStream {
+        /// Continue decoding from `start_s` into the track (sample-accurate).
+        pub fn seek(&mut self, start_s: f64) -> Result<(), DecodeError> {
+            // to the nearest timestamp: `Time::from` truncates, landing a frame early on most values
+            let to = match self.time_base {
+                Some(tb) => SeekTo::TimeStamp {
+                    ts: ((start_s * (tb.denom as f64)) / (tb.numer as f64)).round().max(0.0) as TimeStamp,
+                    track_id: self.track_id,
+                },
+                None => SeekTo::Time { time: Time::from(start_s), track_id: Some(self.track_id) },
+            };
+            let seeked = self.format.seek(SeekMode::Accurate, to).map_err(|e| DecodeError::Seek(start_s, e))?;
+            self.decoder.reset();
+            self.skip_to_ts = Some(seeked.required_ts);
+            Ok(())
+        }
+
+        /// Track timestamps → sample frames (timestamps are frames when there is no time base).
+        fn ts_to_frames(&self, ts: TimeStamp) -> usize {
+            match self.time_base {
+                Some(tb) => (((ts as f64) * (tb.numer as f64) * (self.sr as f64)) / (tb.denom as f64)).round() as usize,
+                None => ts as usize,
+            }
+        }
+    }
+
+    impl Iterator for MonoStream {
+        type Item = Result<Vec<f32>, DecodeError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            while !self.done {
+                let packet = match self.format.next_packet() {
+                    Ok(packet) => packet,
+                    Err(Error::ResetRequired) => {
+                        self.decoder.reset();
+                        continue;
+                    }
+                    Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        self.done = true;
+                        break;
+                    }
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err.into()));
+                    }
+                };
+
+                if packet.track_id() != self.track_id {
                     continue;
                 }
-                Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                    break;
+                let ts = packet.ts();
+
+                let decoded = match self.decoder.decode(&packet) {
+                    Ok(decoded) => decoded,
+                    Err(Error::DecodeError(_)) => {
+                        continue;
+                    }
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err.into()));
+                    }
+                };
+
+                let spec = *decoded.spec();
+                let chan_count = spec.channels.count();
+
+                if
+                    self.sample_buf
+                        .as_ref()
+                        .map(|b| b.capacity() < decoded.capacity())
+                        .unwrap_or(true)
+                {
+                    self.sample_buf = Some(SampleBuffer::<f32>::new(decoded.capacity() as u64, spec));
                 }
-                Err(err) => {
-                    return Err(err.into());
+                let buf = self.sample_buf.as_mut().unwrap();
+
+                buf.copy_interleaved_ref(decoded);
+                let mut mono = self.mix.downmix(buf.samples(), chan_count);
+
+                // a seek lands on the packet containing the target; drop its lead-in
+                if let Some(required) = self.skip_to_ts {
+                    if ts < required {
+                        let lead = self.ts_to_frames(required - ts).min(mono.len());
+                        mono.drain(..lead);
+                    } else {
+                        self.skip_to_ts = None;
+                    }
+                    if mono.is_empty() {
+                        continue;
+                    }
                 }
-            };
+                return Some(Ok(mono));
+            }
+            None
+        }
+    }
 
-            if packet.track_id() != track_id {
-                continue;
+    /// Decode the whole file into memory.
+    pub fn load_mono<P: AsRef<Path>>(path: P, mix: ChannelMix) -> Result<AudioData, DecodeError> {
+        let stream = open_mono(path, mix)?;
+        let (sr, channels) = (stream.sr, stream.channels);
+        let mut mono = Vec::<f32>::new();
+        for chunk in stream {
+            mono.extend(chunk?);
+        }
+        Ok(AudioData { sr, channels, samples_mono: mono })
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ResampleQuality {
+        Linear, // fast, but aliases above ~10 kHz
+        Sinc,
+    }
+
+    impl ResampleQuality {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                ResampleQuality::Linear => "linear",
+                ResampleQuality::Sinc => "sinc",
             }
+        }
+    }
 
-            let decoded = match decoder.decode(&packet) {
-                Ok(decoded) => decoded,
-                Err(Error::DecodeError(_)) => {
-                    continue;
-                }
-                Err(err) => {
-                    return Err(err.into());
+    pub fn resample_mono(x: &[f32], sr_in: u32, sr_out: u32, quality: ResampleQuality) -> Vec<f32> {
+        match quality {
+            ResampleQuality::Linear => resample_linear_mono(x, sr_in, sr_out),
+            ResampleQuality::Sinc => resample_sinc_mono(x, sr_in, sr_out),
+        }
+    }
+
+    // windowed-sinc kernel: zero crossings per side at the cutoff, table resolution per input sample
+    const SINC_ZERO_CROSSINGS: f64 = 16.0;
+    const SINC_PHASES: usize = 256;
+    const KAISER_BETA: f64 = 8.6; // ~85 dB stopband
+
+    /// Modified Bessel function of the first kind, order 0 (series; converges fast for beta ≤ 20).
+    fn bessel_i0(x: f64) -> f64 {
+        let mut sum = 1.0;
+        let mut term = 1.0;
+        let q = (x * x) / 4.0;
+        for k in 1..50 {
+            term *= q / ((k * k) as f64);
+            sum += term;
+            if term < sum * 1e-12 {
+                break;
+            }
+        }
+        sum
+    }
+
+    /// Incremental resampler (mono): feed chunks of any size, then `flush` once at the end.
+    /// Output is sample-identical to the batch functions below.
+    pub struct Resampler {
+        quality: ResampleQuality,
+        ratio: f64,
+        passthrough: bool,
+        kernel: Vec<f32>, // one side of the sinc kernel, SINC_PHASES points per input sample
+        reach: usize, // input samples needed on each side of an output position
+        buf: Vec<f32>,
+        buf_start: usize, // input index of buf[0]
+        total_in: usize,
+        next_out: usize,
+    }
+
+    impl Resampler {
+        pub fn new(sr_in: u32, sr_out: u32, quality: ResampleQuality) -> Self {
+            let passthrough = sr_in == 0 || sr_out == 0 || sr_in == sr_out;
+            let ratio = if passthrough { 1.0 } else { (sr_out as f64) / (sr_in as f64) };
+            let (kernel, reach) = match quality {
+                ResampleQuality::Linear => (Vec::new(), 1),
+                ResampleQuality::Sinc => {
+                    // cutoff at 95% of the lower Nyquist so downsampling does not fold HF back in
+                    let cutoff = 0.95 * ratio.min(1.0); // fraction of the input Nyquist
+                    let half = SINC_ZERO_CROSSINGS / cutoff; // kernel half-width in input samples
+                    let table_len = ((half * (SINC_PHASES as f64)).ceil() as usize) + 2;
+                    let i0_beta = bessel_i0(KAISER_BETA);
+                    let kernel = (0..table_len)
+                        .map(|k| {
+                            let t = (k as f64) / (SINC_PHASES as f64);
+                            if t >= half {
+                                return 0.0;
+                            }
+                            let arg = std::f64::consts::PI * cutoff * t;
+                            let sinc = if arg == 0.0 { 1.0 } else { arg.sin() / arg };
+                            let r = t / half;
+                            let window = bessel_i0(KAISER_BETA * (1.0 - r * r).sqrt()) / i0_beta;
+                            (cutoff * sinc * window) as f32
+                        })
+                        .collect();
+                    (kernel, half.ceil() as usize)
                 }
             };
+            Self {
+                quality,
+                ratio,
+                passthrough,
+                kernel,
+                reach,
+                buf: Vec::new(),
+                buf_start: 0,
+                total_in: 0,
+                next_out: 0,
+            }
+        }
+
+        /// Outputs that are fully determined by the input seen so far.
+        pub fn process(&mut self, x: &[f32]) -> Vec<f32> {
+            if self.passthrough {
+                return x.to_vec();
+            }
+            self.buf.extend_from_slice(x);
+            self.total_in += x.len();
+
+            // never emit past what the final length could be
+            let max_out = ((self.total_in as f64) * self.ratio).floor() as usize;
+            let mut y = Vec::new();
+            while self.next_out < max_out {
+                let center = ((self.next_out as f64) / self.ratio).floor() as usize;
+                if center + self.reach >= self.total_in {
+                    break;
+                }
+                y.push(self.output(self.next_out, self.total_in - 1));
+                self.next_out += 1;
+            }
 
-            let spec = *decoded.spec();
-            let chan_count = spec.channels.count();
+            // drop input no future output can reach
+            let center = ((self.next_out as f64) / self.ratio).floor() as usize;
+            let keep_from = (center + 1).saturating_sub(self.reach).min(self.total_in);
+            if keep_from > self.buf_start {
+                self.buf.drain(..keep_from - self.buf_start);
+                self.buf_start = keep_from;
+            }
+            y
+        }
 
-            if
-                sample_buf
-                    .as_ref()
-                    .map(|b| b.capacity() < decoded.capacity())
-                    .unwrap_or(true)
-            {
-                sample_buf = Some(SampleBuffer::<f32>::new(decoded.capacity() as u64, spec));
+        /// Remaining outputs once the input has ended.
+        pub fn flush(&mut self) -> Vec<f32> {
+            if self.passthrough || self.total_in == 0 {
+                return Vec::new();
             }
-            let buf = sample_buf.as_mut().unwrap();
+            let n_out = ((self.total_in as f64) * self.ratio).floor().max(1.0) as usize;
+            let y = (self.next_out..n_out).map(|i| self.output(i, self.total_in - 1)).collect();
+            self.next_out = n_out;
+            y
+        }
 
-            buf.copy_interleaved_ref(decoded);
-            let samples = buf.samples();
+        #[inline]
+        fn at(&self, i: usize) -> f32 {
+            self.buf[i - self.buf_start]
+        }
 
-            for i in (0..samples.len()).step_by(chan_count) {
-                mono.push(samples[i]);
+        #[inline]
+        fn kernel_at(&self, t: f64) -> f32 {
+            let f = t * (SINC_PHASES as f64);
+            let k = f as usize;
+            if k + 1 >= self.kernel.len() {
+                return 0.0;
             }
+            let frac = (f - (k as f64)) as f32;
+            self.kernel[k] + (self.kernel[k + 1] - self.kernel[k]) * frac
         }
 
-        Ok(AudioData { sr, channels, samples_mono: mono })
+        /// Output sample `i`; `last` is the last input index that may be read.
+        fn output(&self, i: usize, last: usize) -> f32 {
+            let pos = (i as f64) / self.ratio; // position in input
+            let i0 = pos.floor() as usize;
+            match self.quality {
+                ResampleQuality::Linear => {
+                    if i0 + 1 > last {
+                        self.at(last)
+                    } else {
+                        let t = (pos - (i0 as f64)) as f32; // frac
+                        let a = self.at(i0);
+                        let b = self.at(i0 + 1);
+                        a + (b - a) * t // lerp
+                    }
+                }
+                ResampleQuality::Sinc => {
+                    let lo = (i0 + 1).saturating_sub(self.reach);
+                    let hi = (i0 + self.reach).min(last);
+                    let mut acc = 0.0f32;
+                    for j in lo..=hi {
+                        acc += self.at(j) * self.kernel_at((pos - (j as f64)).abs());
+                    }
+                    acc
+                }
+            }
+        }
+    }
+
+    fn resample_all(x: &[f32], sr_in: u32, sr_out: u32, quality: ResampleQuality) -> Vec<f32> {
+        if x.is_empty() || sr_in == 0 || sr_out == 0 || sr_in == sr_out {
+            return x.to_vec();
+        }
+        let mut r = Resampler::new(sr_in, sr_out, quality);
+        let mut y = r.process(x);
+        y.extend(r.flush());
+        y
+    }
+
+    /// Band-limited (Kaiser-windowed sinc) resampler (mono). Same length rule as the linear one.
+    pub fn resample_sinc_mono(x: &[f32], sr_in: u32, sr_out: u32) -> Vec<f32> {
+        resample_all(x, sr_in, sr_out, ResampleQuality::Sinc)
+    }
+
+    /// simple linear resampler (mono)
+    pub fn resample_linear_mono(x: &[f32], sr_in: u32, sr_out: u32) -> Vec<f32> {
+        resample_all(x, sr_in, sr_out, ResampleQuality::Linear)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn seeking_then_decoding_matches_the_unseeked_stream() {
+            let dir = std::env::temp_dir().join(format!("decode-seek-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("ramp.wav");
+            let sr = 8000;
+            let x: Vec<f32> = (0..sr * 3).map(|i| ((i as f32) * 0.013).sin() * 0.5 + (i as f32) * 1e-5).collect();
+            let mut wav = crate::recorder::WavWriter::create(&path, sr as u32).unwrap();
+            wav.write(&x).unwrap();
+            wav.finish().unwrap();
+            drop(wav);
+
+            let all = load_mono(&path, ChannelMix::Average).unwrap().samples_mono;
+            assert_eq!(all, x);
+            for start_s in [0.0, 0.5, 1.2345, 2.999] {
+                let mut stream = open_mono(&path, ChannelMix::Average).unwrap();
+                stream.seek(start_s).unwrap();
+                let mut tail = Vec::new();
+                for chunk in stream {
+                    tail.extend(chunk.unwrap());
+                }
+                let from = ((start_s * (sr as f64)).round()) as usize;
+                assert_eq!(tail.len(), all.len() - from, "seek to {}", start_s);
+                assert!(tail == all[from..], "seek to {}", start_s);
+            }
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+
+        #[test]
+        fn chunked_resampling_matches_the_batch() {
+            let x: Vec<f32> = (0..12345).map(|i| ((i as f32) * 0.05).sin() * 0.5 + ((i as f32) * 0.71).cos() * 0.2).collect();
+            for quality in [ResampleQuality::Linear, ResampleQuality::Sinc] {
+                for (sr_in, sr_out) in [(44100, 48000), (48000, 16000), (22050, 48000)] {
+                    let batch = resample_mono(&x, sr_in, sr_out, quality);
+                    let mut r = Resampler::new(sr_in, sr_out, quality);
+                    let (mut y, mut at, mut k) = (Vec::new(), 0, 0);
+                    while at < x.len() {
+                        let n = [1, 3, 511, 7, 2049][k % 5].min(x.len() - at);
+                        y.extend(r.process(&x[at..at + n]));
+                        at += n;
+                        k += 1;
+                    }
+                    y.extend(r.flush());
+                    assert_eq!(y.len(), batch.len(), "{:?} {} -> {}", quality, sr_in, sr_out);
+                    assert_eq!(y, batch, "{:?} {} -> {}", quality, sr_in, sr_out);
+                }
+            }
+        }
     }
 }
 
 // ───────────────────────────────────────────────────────────────────────────────
 // Shared helpers used by multiple modes
 // ───────────────────────────────────────────────────────────────────────────────
-pub fn audio_sink_thread(rx: Receiver<Vec<f32>>, shared: SharedBuf) {
-    loop {
-        match rx.recv() {
-            Ok(block) => {
-                let mut ring = shared.buf.lock().unwrap();
-                ring.extend_from_slice(&block);
-                let cap = (*shared.sr.lock().unwrap() as usize) * 10;
-                if ring.len() > cap {
-                    let drop = ring.len() - cap;
-                    ring.drain(0..drop);
-                }
-            }
-            Err(_) => {
-                break;
-            }
+pub fn audio_sink_thread(rx: Receiver<Vec<f32>>, shared: SharedBuf, source: &'static str) {
+    while let Ok(block) = rx.recv() {
+        if !backpressure::skip_oldest(&rx, source) {
+            shared.push(&block);
         }
     }
 }
@@ -1996,9 +5842,11 @@ pub fn build_input_stream(
     device: &cpal::Device,
     config: &cpal::StreamConfig,
     channels: usize,
+    mix: audio::ChannelMix,
     tx: crossbeam_channel::Sender<Vec<f32>>,
+    pair: Option<crossbeam_channel::Sender<audio::StereoBlock>>, // first two channels as well (--bearing)
     logger: Arc<Logger>
-) -> Result<cpal::Stream> {
+) -> Result<cpal::Stream, audio::CaptureError> {
     let err_logger = logger.clone();
     let err_fn = move |e| {
         let _ = err_logger.error(&format!("audio stream error: {}", e));
@@ -2006,18 +5854,18 @@ pub fn build_input_stream(
 
     match device.default_input_config()?.sample_format() {
         cpal::SampleFormat::F32 => {
-            let tx = tx.clone();
+            let (tx, pair) = (tx.clone(), pair.clone());
             Ok(
                 device.build_input_stream(
                     config,
-                    move |data: &[f32], _| on_audio_input_first_channel(data, channels, &tx),
+                    move |data: &[f32], _| on_audio_input(data, channels, mix, &tx, pair.as_ref()),
                     err_fn,
                     None
                 )?
             )
         }
         cpal::SampleFormat::I16 => {
-            let tx = tx.clone();
+            let (tx, pair) = (tx.clone(), pair.clone());
             Ok(
                 device.build_input_stream(
                     config,
@@ -2026,7 +5874,7 @@ pub fn build_input_stream(
                         for &s in data {
                             tmp.push((s as f32) / 32768.0);
                         }
-                        on_audio_input_first_channel(&tmp, channels, &tx);
+                        on_audio_input(&tmp, channels, mix, &tx, pair.as_ref());
                     },
                     err_fn,
                     None
@@ -2034,7 +5882,7 @@ pub fn build_input_stream(
             )
         }
         cpal::SampleFormat::U16 => {
-            let tx = tx.clone();
+            let (tx, pair) = (tx.clone(), pair.clone());
             Ok(
                 device.build_input_stream(
                     config,
@@ -2043,33 +5891,29 @@ pub fn build_input_stream(
                         for &s in data {
                             tmp.push(((s as f32) / 65535.0) * 2.0 - 1.0);
                         }
-                        on_audio_input_first_channel(&tmp, channels, &tx);
+                        on_audio_input(&tmp, channels, mix, &tx, pair.as_ref());
                     },
                     err_fn,
                     None
                 )?
             )
         }
-        _ => anyhow::bail!("Unsupported sample format"),
+        other => Err(audio::CaptureError::UnsupportedFormat(other)),
     }
 }
 
-fn on_audio_input_first_channel<T: AsRef<[f32]>>(
+fn on_audio_input<T: AsRef<[f32]>>(
     data: T,
     channels: usize,
-    tx: &crossbeam_channel::Sender<Vec<f32>>
+    mix: audio::ChannelMix,
+    tx: &crossbeam_channel::Sender<Vec<f32>>,
+    pair: Option<&crossbeam_channel::Sender<audio::StereoBlock>>
 ) {
-    let data = data.as_ref();
-    if channels == 1 {
-        let _ = tx.send(data.to_vec());
-    } else {
-        let frames = data.len() / channels;
-        let mut mono = Vec::with_capacity(frames);
-        for f in 0..frames {
-            mono.push(data[f * channels]); // first channel only
-        }
-        let _ = tx.send(mono);
+    // never block the callback on channels nobody reads (a mode without --bearing support)
+    if let Some(pair) = pair {
+        let _ = pair.try_send(audio::split_stereo(data.as_ref(), channels));
     }
+    backpressure::send(tx, mix.downmix(data.as_ref(), channels), "mic");
 }
 
 pub fn maybe_rate_supported(device: &cpal::Device, want: u32) -> Option<u32> {
@@ -2097,14 +5941,55 @@ fn main() -> Result<()> {
         }
     };
 
-    let logger = Arc::new(Logger::new_with_level(&cli.log_path, true, cli.log_level)?);
+    let mut logger = Logger::new_with_level(&cli.log_path, cli.log_sinks.file, cli.log_level)?;
+    logger.set_rotation(output::Rotation::for_log(&cli));
+    logger.set_format(cli.log_format);
+    logger.set_console(cli.log_sinks.console.then(|| cli.verbosity.console_level()));
+    logger.set_system(cli.log_sinks.system);
+    let logger = Arc::new(logger);
+
+    // maintenance: drop a song from SongScan.csv instead of running a mode
+    if !cli.prune_url.is_empty() {
+        for msg in fpdb::prune_track(&cli)? {
+            println!("{}", msg);
+            logger.info(&msg)?;
+        }
+        return Ok(());
+    }
+
+    privacy::announce(&cli, &logger);
+    backpressure::set_policy(cli.backpressure);
+
+    // one run on the audio devices at a time; held until the mode returns
+    let _instance = match instance::acquire(&cli, &logger) {
+        Ok(lock) => lock,
+        Err(e) => {
+            let _ = logger.error(&format!("{:#}", e));
+            return Err(e);
+        }
+    };
 
-    match cli.mode {
+    // capture and worker threads may outlive the mode with a clone; write out what they queued
+    let log = logger.clone();
+    let result = match cli.mode {
         Mode::Presence => mods::presence::run_presence(&cli, logger, &cli.log_path),
         Mode::Scan => mods::scan::run_scan(&cli, &scan_meta, logger),
         Mode::Offline => mods::offline::run_offline(&cli, &scan_meta, logger),
         Mode::Gated => mods::gated::run_gated(&cli, logger),
         Mode::Enrich => mods::enrich::run_enrich(&cli, logger),
         Mode::Impulse => mods::impulse::run_impulse(&cli, logger), // Add this
-    }
+        Mode::Replay => mods::replay::run_replay(&cli, logger),
+        Mode::Play => mods::play::run_play(&cli, &scan_meta, logger),
+        Mode::Report => mods::report::run_report(&cli, logger),
+        Mode::SelfTest => mods::selftest::run_selftest(&cli, logger),
+        Mode::Meter => mods::meter::run_meter(&cli, logger),
+        Mode::Node => mods::node::run_node(&cli, logger),
+        Mode::Hub => mods::hub::run_hub(&cli, logger),
+        Mode::Eval => mods::eval::run_eval(&cli, logger),
+        Mode::Tune => mods::tune::run_tune(&cli, logger),
+        Mode::Auto => mods::auto::run_auto(&cli, logger),
+    };
+    privacy::wipe(&cli, &log);
+    let _ = log.flush();
+    result
 }
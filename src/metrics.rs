@@ -0,0 +1,351 @@
+//! src/metrics.rs
+//! Prometheus text exposition: served on `--metrics-addr` (`GET /metrics`)
+//! and/or written to `--metrics-file` for node_exporter's textfile collector.
+
+use std::{
+    io::{ BufRead, BufReader, Read, Write },
+    net::TcpListener,
+    path::PathBuf,
+    sync::{ atomic::{ AtomicU64, Ordering }, Arc },
+    time::{ Duration, Instant },
+};
+
+use crate::auth;
+use crate::backpressure;
+use crate::supervise;
+use crate::logger::Logger;
+use crate::Config;
+
+/// f64 gauge stored as raw bits so it can live in an atomic.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, v: f64) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Process-wide detector metrics, shared between the analysis loop and the exporter.
+#[derive(Default)]
+pub struct Metrics {
+    pub ticks: Counter,
+    pub detections: Counter, // ticks that produced a positive vote
+    pub state_changes: Counter,
+    pub stream_restarts: Counter,
+    pub present: Gauge,
+    pub distance_m: Gauge,
+    pub strength: Gauge,
+    pub agreement: Gauge,
+    pub corr_seconds_last: Gauge,
+    pub corr_seconds_sum: Gauge,
+    pub corr_count: Counter,
+    pub mic_fill: Gauge,
+    pub ref_fill: Gauge,
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Self> {
+        let m = Self::default();
+        m.distance_m.set(f64::NAN);
+        Arc::new(m)
+    }
+
+    /// Record the duration of one `estimate_from_ref` call.
+    pub fn observe_correlation(&self, secs: f64) {
+        self.corr_seconds_last.set(secs);
+        self.corr_seconds_sum.set(self.corr_seconds_sum.get() + secs);
+        self.corr_count.inc();
+    }
+
+    pub fn render(&self, mode: &str) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, v: u64| {
+            out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} counter\n"));
+            out.push_str(&format!("{name}{{mode=\"{mode}\"}} {v}\n"));
+        };
+        counter("sonar_ticks_total", "Analysis ticks processed.", self.ticks.get());
+        counter(
+            "sonar_detections_total",
+            "Ticks whose echo estimate voted present.",
+            self.detections.get()
+        );
+        counter(
+            "sonar_state_changes_total",
+            "Smoothed presence state flips.",
+            self.state_changes.get()
+        );
+        counter(
+            "sonar_stream_restarts_total",
+            "Capture stream restarts.",
+            self.stream_restarts.get()
+        );
+        let sources = ["mic", "ref"].map(|s| (s, backpressure::counts(s)));
+        for (name, help, dropped_not_late) in [
+            ("sonar_capture_dropped_blocks_total", "Capture blocks dropped on a full queue (--backpressure).", true),
+            ("sonar_capture_late_blocks_total", "Capture blocks that waited for room in a full queue.", false),
+        ] {
+            out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} counter\n"));
+            for (source, (dropped, late)) in sources {
+                let v = if dropped_not_late { dropped } else { late };
+                out.push_str(&format!("{name}{{mode=\"{mode}\",source=\"{source}\"}} {v}\n"));
+            }
+        }
+
+        let mut gauge = |name: &str, help: &str, labels: &str, v: f64| {
+            if !out.contains(&format!("# TYPE {name} ")) {
+                out.push_str(&format!("# HELP {name} {help}\n# TYPE {name} gauge\n"));
+            }
+            out.push_str(&format!("{name}{{mode=\"{mode}\"{labels}}} {}\n", fmt_f64(v)));
+        };
+        gauge("sonar_present", "Smoothed presence state (1 = present).", "", self.present.get());
+        gauge(
+            "sonar_distance_meters",
+            "Window-averaged echo distance.",
+            "",
+            self.distance_m.get()
+        );
+        gauge("sonar_strength", "Window-averaged echo strength.", "", self.strength.get());
+        gauge(
+            "sonar_agreement_ratio",
+            "Fraction of votes in the window asserting presence.",
+            "",
+            self.agreement.get()
+        );
+        gauge(
+            "sonar_correlation_last_seconds",
+            "Duration of the last correlation.",
+            "",
+            self.corr_seconds_last.get()
+        );
+        gauge(
+            "sonar_buffer_fill_ratio",
+            "Capture ring buffer fill level.",
+            ",source=\"mic\"",
+            self.mic_fill.get()
+        );
+        gauge(
+            "sonar_buffer_fill_ratio",
+            "Capture ring buffer fill level.",
+            ",source=\"ref\"",
+            self.ref_fill.get()
+        );
+
+        out.push_str(
+            "# HELP sonar_correlation_seconds Time spent correlating.\n# TYPE sonar_correlation_seconds summary\n"
+        );
+        out.push_str(
+            &format!(
+                "sonar_correlation_seconds_sum{{mode=\"{mode}\"}} {}\nsonar_correlation_seconds_count{{mode=\"{mode}\"}} {}\n",
+                fmt_f64(self.corr_seconds_sum.get()),
+                self.corr_count.get()
+            )
+        );
+        out
+    }
+}
+
+fn fmt_f64(v: f64) -> String {
+    if v.is_nan() {
+        "NaN".to_string()
+    } else if v.is_infinite() {
+        (if v > 0.0 { "+Inf" } else { "-Inf" }).to_string()
+    } else {
+        format!("{}", v)
+    }
+}
+
+/// Serve `GET /metrics` on `addr` from a background thread; with a `token`, only to requests
+/// that bear it.
+pub fn serve(
+    addr: &str,
+    token: &str,
+    metrics: Arc<Metrics>,
+    mode: &'static str,
+    logger: Arc<Logger>
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let _ = logger.info(&format!("Prometheus metrics on http://{}/metrics", addr));
+    auth::warn_if_open(addr, "the metrics endpoint", "--api-token", token, &logger);
+    let token = token.to_string();
+    supervise::spawn("metrics endpoint", logger, move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+            let (request_line, headers) = match read_head(&stream) {
+                Head::Read(line, headers) => (line, headers),
+                Head::TooLarge => {
+                    let _ = stream.write_all(
+                        b"HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    );
+                    continue;
+                }
+                Head::Broken => {
+                    continue;
+                }
+            };
+            let path = request_line.split_whitespace().nth(1).unwrap_or("");
+            let response = if !auth::authorized(&headers, &token) {
+                "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            } else if request_line.starts_with("GET ") && path == "/metrics" {
+                let body = metrics.render(mode);
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            };
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    Ok(())
+}
+
+/// A request head larger than this, or with more header lines, is answered 431.
+const MAX_HEAD_BYTES: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// What `read_head` found.
+#[derive(Debug, PartialEq)]
+enum Head {
+    Read(String, Vec<String>), // request line, header lines
+    TooLarge,
+    Broken, // cut short or unreadable: nothing to answer
+}
+
+/// The request line and headers of an HTTP request, up to `MAX_HEAD_BYTES` and `MAX_HEADERS`.
+fn read_head(stream: impl Read) -> Head {
+    let mut reader = BufReader::new(stream.take(MAX_HEAD_BYTES));
+    let mut line = String::new();
+    let mut request_line = None;
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(_) if !line.ends_with('\n') => {
+                // the head ran into the limit, or the client stopped sending
+                return if reader.get_ref().limit() == 0 { Head::TooLarge } else { Head::Broken };
+            }
+            Ok(_) => {}
+            Err(_) => {
+                return Head::Broken;
+            }
+        }
+        let text = line.trim_end().to_string();
+        if request_line.is_none() {
+            request_line = Some(text);
+        } else if text.is_empty() {
+            break;
+        } else if headers.len() == MAX_HEADERS {
+            return Head::TooLarge;
+        } else {
+            headers.push(text);
+        }
+    }
+    Head::Read(request_line.unwrap_or_default(), headers)
+}
+
+/// Owns the metrics of one running mode plus whichever exporters the CLI enabled.
+pub struct Exporter {
+    pub metrics: Arc<Metrics>,
+    mode: &'static str,
+    textfile: Option<PathBuf>,
+    last_write: Instant,
+}
+
+impl Exporter {
+    pub fn start(cfg: &Config, mode: &'static str, logger: Arc<Logger>) -> anyhow::Result<Self> {
+        let metrics = Metrics::new();
+        if !cfg.metrics_addr.is_empty() {
+            serve(&cfg.metrics_addr, &cfg.api_token, metrics.clone(), mode, logger.clone())?;
+        }
+        let textfile = if cfg.metrics_file.is_empty() {
+            None
+        } else {
+            let _ = logger.info(&format!("Prometheus textfile: {}", cfg.metrics_file));
+            Some(PathBuf::from(&cfg.metrics_file))
+        };
+        Ok(Self { metrics, mode, textfile, last_write: Instant::now() - Duration::from_secs(1) })
+    }
+
+    /// Call once per tick; rewrites the textfile at most once a second.
+    pub fn tick(&mut self) {
+        self.metrics.ticks.inc();
+        let Some(path) = &self.textfile else {
+            return;
+        };
+        if self.last_write.elapsed() >= Duration::from_secs(1) {
+            self.last_write = Instant::now();
+            let _ = crate::output::write_atomic(path, &self.metrics.render(self.mode));
+        }
+    }
+
+    /// Gauges after an aggregator update.
+    pub fn observe_window(&self, present: bool, avg_d: f64, avg_s: f64, agree: f32) {
+        self.metrics.present.set(if present { 1.0 } else { 0.0 });
+        self.metrics.distance_m.set(if present { avg_d } else { f64::NAN });
+        self.metrics.strength.set(avg_s);
+        self.metrics.agreement.set(agree as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn families_are_typed_once_and_only_counters_end_in_total() {
+        let m = Metrics::new();
+        m.observe_correlation(0.002);
+        let text = m.render("presence");
+        let types: Vec<(&str, &str)> = text
+            .lines()
+            .filter_map(|l| l.strip_prefix("# TYPE "))
+            .filter_map(|l| l.split_once(' '))
+            .collect();
+        for (i, (name, kind)) in types.iter().enumerate() {
+            assert!(!types[..i].iter().any(|(n, _)| n == name), "{name} typed twice");
+            assert_eq!(name.ends_with("_total"), *kind == "counter", "{name} is a {kind}");
+        }
+        assert!(text.contains("sonar_correlation_seconds_count{mode=\"presence\"} 1\n"));
+    }
+
+    #[test]
+    fn oversized_request_heads_are_refused() {
+        let ok = "GET /metrics HTTP/1.1\r\nHost: desk\r\nAuthorization: Bearer t\r\n\r\n";
+        assert_eq!(
+            read_head(ok.as_bytes()),
+            Head::Read("GET /metrics HTTP/1.1".to_string(), vec!["Host: desk".to_string(), "Authorization: Bearer t".to_string()])
+        );
+
+        let many = format!("GET /metrics HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEADERS + 1));
+        assert_eq!(read_head(many.as_bytes()), Head::TooLarge);
+        let long = format!("GET /metrics HTTP/1.1\r\nX-A: {}\r\n\r\n", "b".repeat(MAX_HEAD_BYTES as usize));
+        assert_eq!(read_head(long.as_bytes()), Head::TooLarge);
+        // a head that never ends is cut off at the limit too
+        assert_eq!(read_head(std::io::repeat(b'a')), Head::TooLarge);
+        assert_eq!(read_head("GET /metrics HTTP/1.1\r\nHost: d".as_bytes()), Head::Broken);
+    }
+}
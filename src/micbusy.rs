@@ -0,0 +1,187 @@
+//! src/micbusy.rs
+//! `--pause-on-mic-busy`: while another application records from a microphone (a video call,
+//! a dictation tool), the live modes close their streams and report `suspended` instead of
+//! competing for the device and listening in. Windows reports capture sessions per process;
+//! elsewhere the flag is ignored with a warning.
+
+use std::{
+    io,
+    sync::Arc,
+    time::{ Duration, Instant },
+};
+
+use crate::logger::{ Field, Logger };
+use crate::output::JsonObj;
+use crate::Config;
+
+/// How often the capture sessions are looked at.
+const CHECK_EVERY: Duration = Duration::from_secs(1);
+/// The microphone has to stay free this long before detection resumes, so a call that
+/// reopens its device (mute, switching headsets) does not bring the streams back in between.
+const FREE_FOR: Duration = Duration::from_secs(3);
+
+/// The live loop's side: polled every tick, says when the streams should close or open again.
+pub struct MicBusy {
+    busy: bool,
+    checked: Option<Instant>,
+    free_since: Option<Instant>, // while busy: when the last other recorder went away
+    logger: Arc<Logger>,
+}
+
+impl MicBusy {
+    /// None without --pause-on-mic-busy, or where the capture sessions cannot be listed.
+    pub fn from_config(cfg: &Config, logger: Arc<Logger>) -> Option<Self> {
+        if !cfg.pause_on_mic_busy {
+            return None;
+        }
+        if let Err(e) = platform::other_recorders() {
+            let _ = logger.warn(&format!("--pause-on-mic-busy is off: capture sessions unavailable ({})", e));
+            return None;
+        }
+        Some(Self { busy: false, checked: None, free_since: None, logger })
+    }
+
+    pub fn busy(&self) -> bool {
+        self.busy
+    }
+
+    /// Some(true) when another application started recording, Some(false) when the
+    /// microphone has been free for a while again.
+    pub fn poll(&mut self, now: Instant) -> Option<bool> {
+        if self.checked.is_some_and(|at| now.saturating_duration_since(at) < CHECK_EVERY) {
+            return None;
+        }
+        self.checked = Some(now);
+        match platform::other_recorders() {
+            Ok(pids) => self.update(&pids, now),
+            Err(e) => {
+                let _ = self.logger.debug(&format!("capture sessions unavailable: {}", e));
+                None
+            }
+        }
+    }
+
+    fn update(&mut self, pids: &[u32], now: Instant) -> Option<bool> {
+        if !pids.is_empty() {
+            self.free_since = None;
+            if self.busy {
+                return None;
+            }
+            self.busy = true;
+            let list = pids
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let _ = self.logger.event(
+                &format!("microphone in use by another application (PID {}): audio streams closed, detection suspended", list),
+                &[("mic_busy", Field::Str("suspended"))]
+            );
+            return Some(true);
+        }
+        if !self.busy {
+            return None;
+        }
+        let since = *self.free_since.get_or_insert(now);
+        if now.saturating_duration_since(since) < FREE_FOR {
+            return None;
+        }
+        self.busy = false;
+        self.free_since = None;
+        let _ = self.logger.event("microphone free again: opening the audio streams", &[("mic_busy", Field::Str("resumed"))]);
+        Some(false)
+    }
+}
+
+/// Status document (control `status`, gated status.json) while suspended: the state held
+/// from before, and that nothing is being measured.
+pub fn suspended_status(mode: &str, present: bool) -> String {
+    JsonObj::new()
+        .str("ts", &chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string())
+        .str("mode", mode)
+        .str("event", "suspended")
+        .bool("present", present)
+        .str("state", "suspended")
+        .str("reason", "mic_busy")
+        .finish()
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::*;
+    use windows::core::Interface;
+    use windows::Win32::Media::Audio::{
+        eCapture,
+        AudioSessionStateActive,
+        IAudioSessionControl2,
+        IAudioSessionManager2,
+        IMMDeviceEnumerator,
+        MMDeviceEnumerator,
+        DEVICE_STATE_ACTIVE,
+    };
+    use windows::Win32::System::Com::{ CoCreateInstance, CoInitializeEx, CLSCTX_ALL, COINIT_MULTITHREADED };
+
+    /// PIDs of the other processes with an active capture session on any active microphone.
+    pub fn other_recorders() -> io::Result<Vec<u32>> {
+        let mine = std::process::id();
+        let mut pids = Vec::new();
+        unsafe {
+            // already initialised on this thread is fine; so is another apartment
+            let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+            let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL).map_err(io::Error::other)?;
+            let devices = enumerator.EnumAudioEndpoints(eCapture, DEVICE_STATE_ACTIVE).map_err(io::Error::other)?;
+            for i in 0..devices.GetCount().map_err(io::Error::other)? {
+                let Ok(manager) = devices.Item(i).and_then(|d| d.Activate::<IAudioSessionManager2>(CLSCTX_ALL, None)) else {
+                    continue;
+                };
+                let sessions = manager.GetSessionEnumerator().map_err(io::Error::other)?;
+                for k in 0..sessions.GetCount().map_err(io::Error::other)? {
+                    let Ok(session) = sessions.GetSession(k).and_then(|s| s.cast::<IAudioSessionControl2>()) else {
+                        continue;
+                    };
+                    if session.GetState().ok() != Some(AudioSessionStateActive) {
+                        continue;
+                    }
+                    let pid = session.GetProcessId().unwrap_or(0);
+                    if pid != mine && !pids.contains(&pid) {
+                        pids.push(pid);
+                    }
+                }
+            }
+        }
+        Ok(pids)
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+mod platform {
+    use super::*;
+
+    pub fn other_recorders() -> io::Result<Vec<u32>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "only Windows reports which applications record"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suspends_at_once_and_resumes_once_free_for_a_while() {
+        let logger = Arc::new(Logger::new(&std::env::temp_dir().join("sonar-micbusy.log").to_string_lossy(), false).unwrap());
+        let mut m = MicBusy { busy: false, checked: None, free_since: None, logger };
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        assert_eq!(m.update(&[], at(0)), None);
+        assert_eq!(m.update(&[4242], at(1000)), Some(true));
+        assert_eq!(m.update(&[4242, 77], at(2000)), None);
+        // the call drops the device for a moment and takes it again
+        assert_eq!(m.update(&[], at(3000)), None);
+        assert_eq!(m.update(&[4242], at(4000)), None);
+        assert_eq!(m.update(&[], at(5000)), None);
+        assert_eq!(m.update(&[], at(7000)), None);
+        assert_eq!(m.update(&[], at(8000)), Some(false));
+        assert!(!m.busy());
+        assert!(suspended_status("presence", true).contains("\"reason\":\"mic_busy\""));
+    }
+}
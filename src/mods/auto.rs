@@ -0,0 +1,319 @@
+//! src/mods/auto.rs
+//! Auto mode: passive ref↔mic correlation while media plays, the inaudible impulse probe while
+//! the loopback is quiet. The switch waits for the loopback to stay quiet (or loud) for a while
+//! and needs twice `--min-ref-rms` to count media as back, so a pause between two songs or a
+//! short notification sound does not flip it. Each path keeps its own agreement window, sized
+//! for its own tick; the one taking over starts from the other's last window.
+
+use anyhow::Result;
+use std::{
+    sync::Arc,
+    time::{ Duration, Instant },
+};
+
+use crate::{ prescan, sonar_presence, Config };
+use crate::sonar_presence::PresenceState;
+use crate::audio::{ self, AudioSource };
+use crate::logger::{ Field, Logger };
+use crate::output;
+use crate::hooks::HookEvent;
+use crate::recorder::TickMeta;
+use crate::resume::Snapshot;
+use crate::strategy::{ self, DecisionPolicy };
+use crate::mods::engine::PresenceEngine;
+use crate::mods::impulse::{ self, ImpulseProbe, Prober };
+use crate::mods::presence::{ detector_status, log_window, Pairing, PresenceDetector, WindowState };
+
+/// Loopback quiet this long: start probing.
+const TO_ACTIVE_AFTER: Duration = Duration::from_secs(3);
+/// Media back this long: stop probing.
+const TO_PASSIVE_AFTER: Duration = Duration::from_millis(500);
+/// Media counts as back at this many times `--min-ref-rms`.
+const MEDIA_MARGIN: f32 = 2.0;
+/// Media level is measured below the probe band minus this, so the probe does not count as media.
+const GUARD_HZ: f32 = 1000.0;
+
+/// How presence is measured on a tick.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Path {
+    Passive, // the media in the loopback, correlated with the mic as in presence mode
+    Active, // pulses of our own, as in impulse mode
+}
+
+impl Path {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Path::Passive => "passive",
+            Path::Active => "active",
+        }
+    }
+}
+
+/// Picks the path from the loopback's media level, with hysteresis in level and time.
+pub struct PathSelector {
+    pub path: Path,
+    quiet_rms: f32, // --min-ref-rms
+    since: Option<Instant>, // the level has pointed to the other path since
+}
+
+impl PathSelector {
+    pub fn new(quiet_rms: f32) -> Self {
+        Self { path: Path::Passive, quiet_rms, since: None }
+    }
+
+    /// Feed one tick's media level; returns the new path when it switches.
+    pub fn update(&mut self, level: f32, now: Instant) -> Option<Path> {
+        let (leaving, hold, next) = match self.path {
+            Path::Passive => (level < self.quiet_rms, TO_ACTIVE_AFTER, Path::Active),
+            Path::Active => (level >= self.quiet_rms * MEDIA_MARGIN, TO_PASSIVE_AFTER, Path::Passive),
+        };
+        if !leaving {
+            self.since = None;
+            return None;
+        }
+        let since = *self.since.get_or_insert(now);
+        if now.saturating_duration_since(since) < hold {
+            return None;
+        }
+        self.since = None;
+        self.path = next;
+        Some(next)
+    }
+}
+
+/// Loopback level of `block` below the probe `band`.
+fn media_level(block: &[f32], sr: f32, band: (f32, f32)) -> f32 {
+    let mut x = block.to_vec();
+    sonar_presence::band_limit(&mut x, sr, 0.0, band.0 - GUARD_HZ);
+    prescan::rms(&x)
+}
+
+pub fn run_auto(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    let (mic, reference) = audio::sources_from_config(cli, cli.tick_ms)?;
+    run_auto_with(cli, logger, mic, reference)
+}
+
+/// Auto mode on arbitrary capture sources; the probe always goes to the default output.
+pub fn run_auto_with(cli: &Config, logger: Arc<Logger>, mic: Box<dyn AudioSource>, reference: Box<dyn AudioSource>) -> Result<()> {
+    // the click would be heard: probe with the burst in --probe-band unless told otherwise
+    let probe_cfg = match cli.impulse_probe {
+        ImpulseProbe::Click => Config { impulse_probe: ImpulseProbe::Burst, ..cli.clone() },
+        _ => cli.clone(),
+    };
+    // a measurement lasts at least the listen time of all its pulses, as in impulse mode
+    let pulse_ms = cli.tick_ms.max((cli.impulse_avg.max(1) as u64) * cli.impulse_listen_ms);
+    logger.info(
+        &format!(
+            "sonar-presence (auto) starting…  tick_ms={} (probing: {})  agg_frac={:.2}  window_sec={}  probe={} {:.0}-{:.0} Hz",
+            cli.tick_ms,
+            pulse_ms,
+            cli.agg_frac,
+            cli.window_sec,
+            probe_cfg.impulse_probe.as_str(),
+            probe_cfg.impulse_probe_band.0,
+            probe_cfg.impulse_probe_band.1
+        )
+    )?;
+
+    let mut engine = PresenceEngine::setup(cli, "auto", &cli.log_path, mic, reference, logger.clone())?;
+    let sr_used = engine.sr;
+
+    let mut det = PresenceDetector::new(cli);
+    let mut pulses = strategy::policy(cli, pulse_ms);
+    logger.info(&format!("detector: {}, policy: {}", det.detector.name(), det.policy.name()))?;
+
+    let mut selector = PathSelector::new(cli.min_ref_rms);
+    // opened on the first quiet spell; if the output cannot play the probe, the run stays passive
+    let mut prober: Option<Prober> = None;
+    let mut probe_failed = false;
+    let mut ref_seen = engine.shared_ref.written();
+    let mut held = Snapshot::default(); // the last full window of either path, handed over on a switch
+
+    while engine.running() {
+        let tick_len = Duration::from_millis(match selector.path {
+            Path::Passive => cli.tick_ms,
+            Path::Active => pulse_ms,
+        });
+        let present = on(selector.path, &mut det, &mut pulses).present();
+
+        let streams = engine.begin_tick(tick_len, present);
+        if streams.reopened.is_some() || streams.woke.is_some() {
+            det.restart(cli);
+            pulses.clear();
+        }
+        if let Some(st) = streams.idle {
+            engine.control.set_status(st);
+            engine.idle(present);
+            continue;
+        }
+        if !streams.restarted.is_empty() {
+            // a reopened device comes with its own delay
+            det.detector.restart();
+        }
+
+        if engine.follow_control(on(selector.path, &mut det, &mut pulses)) {
+            det.policy.clear();
+            pulses.clear();
+            let _ = logger.info("recalibrate: agreement window cleared");
+        }
+        if engine.paused(present) {
+            continue;
+        }
+
+        // the loopback since the last tick decides which path the next one takes; nothing new
+        // (a finished file, a stalled stream) is as quiet as silence
+        let (from, block) = engine.shared_ref.read_since(ref_seen);
+        ref_seen = from + (block.len() as u64);
+        let level = media_level(&block, sr_used, probe_cfg.impulse_probe_band);
+        if let Some(path) = selector.update(level, Instant::now()) {
+            if path == Path::Active && prober.is_none() && !probe_failed {
+                match Prober::start(&probe_cfg, logger.clone()).and_then(|p| p.check_mic(&engine.shared_mic, &logger).map(|_| p)) {
+                    Ok(p) => {
+                        prober = Some(p);
+                    }
+                    Err(e) => {
+                        logger.warn(&format!("auto: impulse probe unavailable ({:#}); staying passive", e))?;
+                        probe_failed = true;
+                    }
+                }
+            }
+            if path == Path::Active && prober.is_none() {
+                selector.path = Path::Passive;
+            }
+            if selector.path == path {
+                let (from, to) = match path {
+                    Path::Active => (det.policy.as_ref(), pulses.as_mut()),
+                    Path::Passive => (pulses.as_ref(), det.policy.as_mut()),
+                };
+                to.restore(from.present(), held.agree, held.vote());
+                if path == Path::Passive {
+                    // the rings have moved on while probing
+                    engine.frames.restart(&engine.shared_mic, &engine.shared_ref);
+                }
+                let _ = logger.event(
+                    &(match path {
+                        Path::Active => format!("auto: loopback quiet for {}s: probing with {}", TO_ACTIVE_AFTER.as_secs(), probe_cfg.impulse_probe.as_str()),
+                        Path::Passive => "auto: media playing: back to passive correlation".to_string(),
+                    }),
+                    &[("path", Field::Str(path.as_str())), ("media_rms", Field::Num(level as f64))]
+                );
+            }
+        }
+
+        engine.observe_fill();
+        let mut meta = TickMeta::default();
+        let window = match (selector.path, prober.as_ref()) {
+            (Path::Active, Some(p)) => {
+                let t_probe = Instant::now();
+                let vote = p.measure(&engine.shared_mic, &probe_cfg)?;
+                engine.exporter.metrics.observe_correlation(t_probe.elapsed().as_secs_f64());
+                meta = TickMeta { analysed: true, estimate: vote, vote: vote.is_some(), ..meta };
+                if vote.is_some() {
+                    engine.exporter.metrics.detections.inc();
+                }
+                pulses.push(vote, Instant::now()).map(|d| (impulse::pulse_window(&d, pulses.present()), vote.is_none()))
+            }
+            _ => {
+                engine.frames.ref_shift = det.drift.shift();
+                let frames = &mut engine.frames;
+                match frames.pair(&engine.shared_mic, &engine.shared_ref, &logger) {
+                    Pairing::Ready => {
+                        let t_corr = Instant::now();
+                        det.frames_at = Some(frames.positions());
+                        let tick = det.tick(&frames.reference, &frames.mic, sr_used, &engine.live, Instant::now(), Some(&logger));
+                        engine.exporter.metrics.observe_correlation(t_corr.elapsed().as_secs_f64());
+                        meta = tick.meta();
+                        if tick.voted {
+                            engine.exporter.metrics.detections.inc();
+                        }
+                        tick.window.map(|w| (w, tick.estimate.is_none()))
+                    }
+                    Pairing::Filling => {
+                        det.policy.age();
+                        None
+                    }
+                    _ => None,
+                }
+            }
+        };
+        if let Some((w, quiet)) = window {
+            report_window(&mut engine, &logger, &w, cli.window_sec, quiet);
+            held = Snapshot { state: w.state, agree: w.agree, avg_d: w.avg_d, avg_s: w.avg_s, aligned: None };
+        }
+
+        let present = on(selector.path, &mut det, &mut pulses).present();
+        // pulses give no motion to split present by
+        let state = match selector.path {
+            Path::Passive => det.activity.state(),
+            Path::Active => PresenceState::from_present(present),
+        };
+        engine.record(&TickMeta { present, state, ..meta });
+        engine.end_tick(Some(present));
+    }
+
+    engine.shutdown("sonar-presence (auto)")
+}
+
+/// The agreement window and hysteresis of `path`.
+fn on<'a>(path: Path, det: &'a mut PresenceDetector, pulses: &'a mut Box<dyn DecisionPolicy>) -> &'a mut dyn DecisionPolicy {
+    match path {
+        Path::Passive => det.policy.as_mut(),
+        Path::Active => pulses.as_mut(),
+    }
+}
+
+/// A full window of either path: Detection.csv and the hooks on a change, the log, status and metrics.
+fn report_window(engine: &mut PresenceEngine, logger: &Logger, w: &WindowState, window_sec: u32, quiet: bool) {
+    let present = w.state.present();
+    if w.state_changed {
+        let _ = logger.event(
+            &format!("state_change -> present={} state={}", present, w.state.as_str()),
+            &[
+                ("present", Field::Bool(present)),
+                ("state", Field::Str(w.state.as_str())),
+                ("avg_distance_m", Field::Num(w.avg_d)),
+            ]
+        );
+        let _ = output::write_detection_row(&mut engine.csv, w.state, w.avg_d, w.avg_s, w.agree, w.iqr_d, w.bearing_deg, w.quality, w.probability, None);
+    }
+    if w.flipped {
+        engine.flipped(HookEvent {
+            present,
+            state: w.state,
+            distance_m: w.avg_d,
+            strength: w.avg_s,
+            agree: w.agree,
+            probability: w.probability,
+        });
+    }
+    engine.exporter.observe_window(present, w.avg_d, w.avg_s, w.agree);
+    engine.control.set_status(detector_status(present, w));
+    log_window(logger, present, w, window_sec, quiet);
+    engine.status.update(present, w.avg_d, w.probability.unwrap_or(w.agree));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_switches_only_after_a_held_level() {
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let mut sel = PathSelector::new(0.01);
+
+        // a gap between two songs is not long enough to start probing
+        assert_eq!(sel.update(0.001, at(0)), None);
+        assert_eq!(sel.update(0.001, at(2000)), None);
+        assert_eq!(sel.update(0.2, at(2100)), None);
+        assert_eq!(sel.update(0.001, at(2200)), None);
+        assert_eq!(sel.update(0.001, at(5200)), Some(Path::Active));
+
+        // between --min-ref-rms and twice that is neither: probing goes on
+        assert_eq!(sel.update(0.015, at(5300)), None);
+        assert_eq!(sel.update(0.015, at(9000)), None);
+        assert_eq!(sel.update(0.05, at(9100)), None);
+        assert_eq!(sel.update(0.05, at(9600)), Some(Path::Passive));
+        assert_eq!(sel.path, Path::Passive);
+    }
+}
@@ -0,0 +1,400 @@
+//! src/mods/engine.rs
+//! The pipeline presence and gated mode share: capture with its watchdog, the `--active-hours`
+//! and `--pause-on-mic-busy` pauses, the control interface, and the outputs each tick and state
+//! change feed (Detection.csv, hooks, metrics, heartbeat, recordings). A mode sets it up, runs
+//! its own analysis between `begin_tick` and `end_tick`, and shuts it down.
+
+use anyhow::Result;
+use std::{
+    sync::{ atomic::{ AtomicBool, Ordering }, Arc },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::{ sonar_presence, Config, SharedBuf, RING_SECONDS };
+use crate::audio::{ self, AudioSource, StepClock };
+use crate::autolock::AutoLock;
+use crate::console::LiveStatus;
+use crate::control::Control;
+use crate::heartbeat::Heartbeat;
+use crate::hooks::{ HookEvent, Hooks };
+use crate::hours::{ self, ActiveHours };
+use crate::logger::{ Field, Logger };
+use crate::metrics::Exporter;
+use crate::micbusy::{ self, MicBusy };
+use crate::mods::presence::FramePairer;
+use crate::output::{ self, RotatingCsv };
+use crate::power::PowerWatch;
+use crate::recorder::{ DebugDump, SessionRecorder, TickMeta };
+use crate::strategy::DecisionPolicy;
+use crate::watchdog::Watchdog;
+
+/// What happened to the capture streams at the start of a tick, for the mode to follow up on.
+#[derive(Debug, Default)]
+pub struct Streams {
+    pub reopened: Option<&'static str>, // opened again after a pause: "active_hours" | "mic_busy"
+    pub closed: Option<&'static str>, // paused on this tick, for the same reasons
+    pub woke: Option<String>, // reopened after sleep or a default-device change: why
+    pub restarted: Vec<usize>, // sources opened again on this tick (0 = mic, 1 = reference)
+    pub idle: Option<String>, // still paused: the status for this tick, and nothing to analyse
+}
+
+pub struct PresenceEngine {
+    pub logger: Arc<Logger>,
+    pub mic: Box<dyn AudioSource>,
+    pub reference: Box<dyn AudioSource>,
+    pub shared_mic: SharedBuf,
+    pub shared_ref: SharedBuf,
+    pub stereo: Option<(SharedBuf, SharedBuf)>, // the mic's two channels, when it delivers them (--bearing)
+    pub sr: f32, // the mic's rate; the reference is captured at it too
+    pub analysis_len: usize,
+    pub frames: FramePairer,
+    pub live: Config, // thresholds as currently set through the control interface
+    pub csv: RotatingCsv, // Detection.csv
+    pub hooks: Hooks,
+    pub control: Arc<Control>,
+    pub exporter: Exporter,
+    pub status: LiveStatus,
+    pub t_run: Instant,
+    clock: Option<StepClock>, // stepped sources: ticks follow their virtual time, not the wall clock
+    mode: &'static str,
+    quit: Arc<AtomicBool>,
+    watchdog: Watchdog,
+    power: PowerWatch,
+    auto_lock: AutoLock,
+    recorder: Option<SessionRecorder>,
+    debug_dump: Option<DebugDump>,
+    measurements: Option<RotatingCsv>, // --log-every-tick
+    heartbeat: Option<Heartbeat>,
+    active_hours: Option<ActiveHours>,
+    mic_busy: Option<MicBusy>,
+    next: Instant,
+}
+
+impl PresenceEngine {
+    /// Open the streams (mic at 48 kHz if it can, the reference at the mic's rate) and every
+    /// output; Detection.csv goes beside `log_path`.
+    pub fn setup(
+        cli: &Config,
+        mode: &'static str,
+        log_path: &str,
+        mut mic: Box<dyn AudioSource>,
+        mut reference: Box<dyn AudioSource>,
+        logger: Arc<Logger>
+    ) -> Result<Self> {
+        let csv = output::open_detection_csv(&output::sibling_path(log_path, "Detection.csv"), output::Rotation::from_config(cli))?;
+        let measurements = output::open_measurements_csv(cli, log_path)?;
+
+        // ctrl+c to quit
+        let quit = Arc::new(AtomicBool::new(false));
+        {
+            let q = quit.clone();
+            let _ = ctrlc::set_handler(move || {
+                q.store(true, Ordering::SeqCst);
+            });
+        }
+
+        let (shared_mic, mic_feed) = audio::capture_fed(mic.as_mut(), Some(48_000), logger.clone())?;
+        let sr = shared_mic.sr;
+        // taken at once, so the stereo rings run sample for sample with the mono one
+        let stereo = mic.stereo().map(|rx| audio::capture_stereo(rx, sr, logger.clone()));
+        reference.hear(&shared_mic);
+        let (shared_ref, ref_feed) = audio::capture_as(reference.as_mut(), sr as u32, logger.clone())?;
+        let mut watchdog = Watchdog::new(cli, logger.clone());
+        watchdog.watch("mic", &shared_mic, mic_feed);
+        watchdog.watch("reference", &shared_ref, ref_feed);
+
+        let analysis_len = sonar_presence::analysis_len(sr, cli.front_max_m);
+        logger.info(&format!("Analysis window: {} samples (~{:.0} ms)", analysis_len, ((analysis_len as f32) / sr) * 1000.0))?;
+
+        let clock = mic.clock().or_else(|| reference.clock());
+        let t_run = clock.as_ref().map_or_else(Instant::now, StepClock::instant);
+        Ok(Self {
+            power: PowerWatch::start(logger.clone()),
+            auto_lock: AutoLock::new(cli, logger.clone()),
+            status: LiveStatus::new(cli),
+            exporter: Exporter::start(cli, mode, logger.clone())?,
+            hooks: Hooks::new(cli, mode, logger.clone()),
+            control: Control::start(cli, mode, logger.clone())?,
+            live: cli.clone(),
+            recorder: SessionRecorder::start(cli, &shared_ref, &shared_mic, logger.clone())?,
+            debug_dump: DebugDump::open(cli, logger.clone())?,
+            measurements,
+            heartbeat: Heartbeat::open(cli, mode, logger.clone())?,
+            frames: FramePairer::new(analysis_len, cli),
+            active_hours: ActiveHours::from_config(cli, logger.clone()),
+            mic_busy: MicBusy::from_config(cli, logger.clone()),
+            mic,
+            reference,
+            shared_mic,
+            shared_ref,
+            stereo,
+            sr,
+            analysis_len,
+            csv,
+            mode,
+            quit,
+            watchdog,
+            t_run,
+            clock,
+            next: t_run,
+            logger,
+        })
+    }
+
+    /// Until Ctrl+C, or `shutdown` on the control interface (another instance's --takeover).
+    pub fn running(&self) -> bool {
+        !self.quit.load(Ordering::SeqCst) && !self.control.shutdown_requested()
+    }
+
+    /// The time ticks are judged by: the wall clock, or the stepped sources' virtual time.
+    pub fn now(&self) -> Instant {
+        self.clock.as_ref().map_or_else(Instant::now, StepClock::instant)
+    }
+
+    /// Since the run started, by `now`.
+    pub fn elapsed(&self) -> Duration {
+        self.now().saturating_duration_since(self.t_run)
+    }
+
+    /// Start a tick `tick` after the last: close or reopen the streams for `--active-hours` and
+    /// `--pause-on-mic-busy`, and reopen those that stalled or that sleep took away. `present`
+    /// is the mode's state, for the status while paused.
+    pub fn begin_tick(&mut self, tick: Duration, present: bool) -> Streams {
+        self.next += tick;
+        let mut streams = Streams::default();
+        let busy = self.mic_busy.as_ref().is_some_and(MicBusy::busy);
+
+        let mut reopened = match self.active_hours.as_mut().and_then(|h| h.poll(chrono::Local::now().naive_local())) {
+            // another application still records: the streams stay closed until it is done
+            Some(true) if busy => Vec::new(),
+            Some(true) => {
+                streams.reopened = Some("active_hours");
+                self.watchdog.restart_all(&mut [self.mic.as_mut(), self.reference.as_mut()], "active hours began")
+            }
+            Some(false) => {
+                self.watchdog.close_all(&mut [self.mic.as_mut(), self.reference.as_mut()]);
+                streams.closed = Some("active_hours");
+                Vec::new()
+            }
+            None => Vec::new(),
+        };
+        if self.active_hours.as_ref().is_some_and(|h| !h.open()) {
+            streams.idle = Some(hours::paused_status(self.mode, present));
+            return streams;
+        }
+
+        match self.mic_busy.as_mut().and_then(|m| m.poll(Instant::now())) {
+            Some(true) => {
+                self.watchdog.close_all(&mut [self.mic.as_mut(), self.reference.as_mut()]);
+                streams.closed = Some("mic_busy");
+            }
+            Some(false) => {
+                reopened = self.watchdog.restart_all(&mut [self.mic.as_mut(), self.reference.as_mut()], "microphone free again");
+                streams.reopened = Some("mic_busy");
+            }
+            None => {}
+        }
+        if self.mic_busy.as_ref().is_some_and(MicBusy::busy) {
+            streams.idle = Some(micbusy::suspended_status(self.mode, present));
+            return streams;
+        }
+
+        let mut restarted = self.watchdog.poll(&mut [self.mic.as_mut(), self.reference.as_mut()]);
+        restarted.extend(reopened);
+        if let Some(why) = self.power.poll() {
+            let _ = self.logger.event(&format!("{}: reopening the audio streams", why), &[("reason", Field::Str(&why))]);
+            restarted = self.watchdog.restart_all(&mut [self.mic.as_mut(), self.reference.as_mut()], &why);
+            streams.woke = Some(why);
+        }
+        if !restarted.is_empty() {
+            self.frames.restart(&self.shared_mic, &self.shared_ref);
+            self.exporter.metrics.stream_restarts.add(restarted.len() as u64);
+        }
+        streams.restarted = restarted;
+        streams
+    }
+
+    /// A tick with the streams paused: the heartbeat still beats, the next check comes after
+    /// `hours::CLOSED_POLL`.
+    pub fn idle(&mut self, present: bool) {
+        self.beat(present);
+        thread::sleep(hours::CLOSED_POLL);
+        self.next = Instant::now();
+    }
+
+    /// Copy the control interface's settings onto `live` and `policy`; true when a
+    /// `recalibrate` was asked for.
+    pub fn follow_control(&mut self, policy: &mut dyn DecisionPolicy) -> bool {
+        self.control.apply(&mut self.live, policy);
+        self.control.take_recalibrate()
+    }
+
+    /// True, after sitting the tick out, while the control interface has the run paused.
+    pub fn paused(&mut self, present: bool) -> bool {
+        if !self.control.is_paused() {
+            return false;
+        }
+        self.beat(present);
+        self.pace();
+        true
+    }
+
+    /// Before pairing frames: how full the capture rings are.
+    pub fn observe_fill(&self) {
+        let ring_cap = (self.sr as f64) * (RING_SECONDS as f64);
+        self.exporter.metrics.mic_fill.set((self.shared_mic.len() as f64) / ring_cap);
+        self.exporter.metrics.ref_fill.set((self.shared_ref.len() as f64) / ring_cap);
+    }
+
+    /// The smoothed state flipped: count it and run the hooks.
+    pub fn flipped(&mut self, ev: HookEvent) {
+        self.exporter.metrics.state_changes.inc();
+        self.hooks.state_changed(ev);
+    }
+
+    /// The tick's row for `--record-session`, `--debug-dump`, `--log-every-tick` and the heartbeat.
+    pub fn record(&mut self, meta: &TickMeta) {
+        if let Some(rec) = self.recorder.as_mut() {
+            rec.tick(&self.shared_ref, &self.shared_mic, *meta);
+        }
+        let t = self.elapsed().as_secs_f64();
+        if let Some(dump) = self.debug_dump.as_mut() {
+            dump.tick(t, meta);
+        }
+        if let Some(csv) = self.measurements.as_mut() {
+            let _ = output::write_measurement_row(csv, t, meta);
+        }
+        if let Some(hb) = self.heartbeat.as_mut() {
+            hb.tick(meta);
+        }
+    }
+
+    /// Close the tick: reap finished hooks, follow `present` with `--auto-lock` (None leaves it
+    /// be), export the metrics, and sleep until the next tick is due.
+    pub fn end_tick(&mut self, present: Option<bool>) {
+        self.hooks.poll();
+        if let Some(present) = present {
+            self.auto_lock.update(present);
+        }
+        self.exporter.tick();
+        self.pace();
+    }
+
+    pub fn shutdown(self, name: &str) -> Result<()> {
+        self.logger.info(&format!("{} stopped.", name))?;
+        Ok(())
+    }
+
+    fn beat(&mut self, present: bool) {
+        if let Some(hb) = self.heartbeat.as_mut() {
+            hb.tick(&TickMeta { present, ..TickMeta::default() });
+        }
+        self.exporter.tick();
+    }
+
+    fn pace(&mut self) {
+        if let Some(clock) = &self.clock {
+            clock.advance_to(self.next);
+            return;
+        }
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        } else {
+            self.next = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::MemorySource;
+    use crate::mods::presence::Pairing;
+    use crate::sonar_presence::PresenceState;
+    use std::fs;
+
+    const SR: u32 = 48_000;
+
+    #[test]
+    fn ticks_pair_the_recording_up_to_their_time_and_feed_every_output() {
+        let dir = std::env::temp_dir().join(format!("engine_steps_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("Detection.log").to_string_lossy().into_owned();
+        let cfg = Config { log_every_tick: true, log_path: log_path.clone(), ..Config::default() };
+        let logger = Arc::new(Logger::new(&log_path, false).unwrap());
+
+        // two seconds of distinct, non-repeating samples per stream
+        let mic: Vec<f32> = (0..2 * SR).map(|i| ((i as f32) * 0.001).sin() * 0.5).collect();
+        let reference: Vec<f32> = (0..2 * SR).map(|i| ((i as f32) * 0.0007).cos() * 0.5).collect();
+        let clock = StepClock::new();
+        let mut engine = PresenceEngine::setup(
+            &cfg,
+            "presence",
+            &log_path,
+            Box::new(MemorySource::new("mic", mic.clone(), SR).stepped(&clock)),
+            Box::new(MemorySource::new("ref", reference.clone(), SR).stepped(&clock)),
+            logger
+        ).unwrap();
+        assert_eq!(engine.sr, SR as f32);
+
+        let tick = Duration::from_millis(100);
+        let mut ticks = 0u32;
+        let mut present = false;
+        while engine.running() && !engine.reference.finished() {
+            let streams = engine.begin_tick(tick, present);
+            assert!(streams.restarted.is_empty() && streams.idle.is_none());
+
+            // the rings hold exactly the audio up to the tick's virtual time
+            let end = (engine.elapsed().as_secs_f64() * (SR as f64)).round() as usize;
+            assert_eq!(end, (ticks as usize) * 4800);
+            assert_eq!(engine.shared_mic.written() as usize, end);
+            assert_eq!(engine.shared_ref.written() as usize, end);
+            let len = engine.analysis_len;
+            let pairing = engine.frames.pair(&engine.shared_mic, &engine.shared_ref, &engine.logger);
+            if end >= len {
+                assert_eq!(pairing, Pairing::Ready);
+                assert_eq!(engine.frames.mic, mic[end - len..end]);
+                assert_eq!(engine.frames.reference, reference[end - len..end]);
+            } else {
+                assert_eq!(pairing, Pairing::Filling);
+            }
+
+            // in from the 5th tick to the 10th
+            if (ticks == 5) != (ticks == 10) {
+                present = !present;
+                engine.flipped(HookEvent {
+                    present,
+                    state: if present { PresenceState::Active } else { PresenceState::Absent },
+                    distance_m: 1.0,
+                    strength: 0.5,
+                    agree: 1.0,
+                    probability: None,
+                });
+            }
+            let state = if present { PresenceState::Active } else { PresenceState::Absent };
+            engine.record(&TickMeta { analysed: pairing == Pairing::Ready, present, state, ..TickMeta::default() });
+            engine.end_tick(Some(present));
+            ticks += 1;
+        }
+        assert_eq!(ticks, 20);
+        assert_eq!(engine.exporter.metrics.state_changes.get(), 2);
+        engine.shutdown("Presence").unwrap();
+
+        // one Measurements.csv row per tick, on the recording's clock
+        let rows: Vec<Vec<String>> = fs::read_to_string(dir.join("Measurements.csv"))
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').map(str::to_string).collect())
+            .collect();
+        assert_eq!(rows.len(), 20);
+        for (i, row) in rows.iter().enumerate() {
+            assert_eq!(row[1], format!("{:.3}", (i as f64) * 0.1));
+            assert_eq!(row[7], ((5..10).contains(&i)).to_string());
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
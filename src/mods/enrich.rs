@@ -1,21 +1,101 @@
 use anyhow::Result;
-use std::path::Path;
+use std::fs;
+use std::path::{ Path, PathBuf };
 use std::process::Command;
-use std::sync::Arc;
+use std::sync::{ atomic::{ AtomicUsize, Ordering }, Arc };
+use std::thread;
 
+use crate::csvio;
+use crate::output::{ self, JsonObj };
+use crate::recorder::WavWriter;
+use crate::{ decode, prescan };
+use crate::scanscore::Heuristic;
 use crate::{Config, Logger};
 
+/// Output sample rate of the enriched file; pings must stay below its Nyquist frequency.
+const OUT_SR: u32 = 48000;
+/// Sweep width of chirp pings and band width of noise pings, centred on --ping-freq-hz.
+const PING_BW_HZ: f32 = 2000.0;
+
+/// Masking content is measured from this far below the ping band up (masking spreads upward).
+const MASK_SPREAD_HZ: f32 = 4000.0;
+/// Masked pings are not made quieter than this below --ping-level-db; such slots stay empty.
+const MASK_RANGE_DB: f32 = 20.0;
+/// Raised-cosine fade in/out of synthesized pings, against clicks.
+const PING_FADE_S: f32 = 0.005;
+
+/// Where pings go in the track.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PingPlacement {
+    Interval, // every --interval-length, whatever is playing
+    Masked, // at most one per interval, where loud content hides it
+}
+
+impl PingPlacement {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "interval" => Some(PingPlacement::Interval),
+            "masked" => Some(PingPlacement::Masked),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PingPlacement::Interval => "interval",
+            PingPlacement::Masked => "masked",
+        }
+    }
+}
+
+/// One ping as inserted into the track.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ping {
+    pub start_s: f32,
+    pub level_db: f32,
+}
+
+/// Shape of each ping burst.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PingWaveform {
+    Sine, // steady tone at --ping-freq-hz
+    Chirp, // linear up-sweep across PING_BW_HZ
+    Noise, // white noise band-passed to PING_BW_HZ
+}
+
+impl PingWaveform {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "sine" => Some(PingWaveform::Sine),
+            "chirp" => Some(PingWaveform::Chirp),
+            "noise" => Some(PingWaveform::Noise),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PingWaveform::Sine => "sine",
+            PingWaveform::Chirp => "chirp",
+            PingWaveform::Noise => "noise",
+        }
+    }
+
+    /// Lowest and highest frequency the ping occupies.
+    fn band(&self, freq_hz: f32) -> (f32, f32) {
+        match self {
+            PingWaveform::Sine => (freq_hz, freq_hz),
+            PingWaveform::Chirp | PingWaveform::Noise => (freq_hz - PING_BW_HZ / 2.0, freq_hz + PING_BW_HZ / 2.0),
+        }
+    }
+}
+
 pub fn run_enrich(config: &Config, logger: Arc<Logger>) -> Result<()> {
     logger.info("Starting enrich mode")?;
 
     // Validate input parameters
-    if config.enrich_song_path.is_empty() {
-        anyhow::bail!("Song path is required for enrich mode. Use --song-path <PATH>");
-    }
-
-    let input_path = Path::new(&config.enrich_song_path);
-    if !input_path.exists() {
-        anyhow::bail!("Input song file does not exist: {}", config.enrich_song_path);
+    if config.enrich_song_path.is_empty() && config.enrich_song_dir.is_empty() {
+        anyhow::bail!("Song path is required for enrich mode. Use --song-path <PATH> or --song-dir <DIR>");
     }
 
     // Validate ffmpeg path
@@ -24,32 +104,313 @@ pub fn run_enrich(config: &Config, logger: Arc<Logger>) -> Result<()> {
         anyhow::bail!("FFmpeg executable not found at: {}", config.ffmpeg_path);
     }
 
+    let (lo, hi) = config.enrich_ping_waveform.band(config.enrich_ping_freq_hz);
+    if lo <= 0.0 || hi >= (OUT_SR as f32) / 2.0 {
+        anyhow::bail!(
+            "{} ping at {:.0} Hz spans {:.0}-{:.0} Hz, outside 0-{} Hz at {} Hz output",
+            config.enrich_ping_waveform.as_str(),
+            config.enrich_ping_freq_hz,
+            lo,
+            hi,
+            OUT_SR / 2,
+            OUT_SR
+        );
+    }
+    if config.enrich_ping_level_db > 0.0 {
+        anyhow::bail!("--ping-level-db must be <= 0 (dB relative to full scale)");
+    }
+
+    logger.info(&format!("Interval length: {:.2}s", config.enrich_interval_length_s))?;
+    logger.info(&format!("Ping length: {:.2}s", config.enrich_ping_length_s))?;
+    logger.info(
+        &format!(
+            "Ping: {} at {:.0} Hz, {:.1} dB, {} placement",
+            config.enrich_ping_waveform.as_str(),
+            config.enrich_ping_freq_hz,
+            config.enrich_ping_level_db,
+            config.enrich_ping_placement.as_str()
+        )
+    )?;
+
+    if !config.enrich_song_dir.is_empty() {
+        return run_batch(config, logger);
+    }
+
+    let input_path = Path::new(&config.enrich_song_path);
+    if !input_path.exists() {
+        anyhow::bail!("Input song file does not exist: {}", config.enrich_song_path);
+    }
+
     // Generate output filename (input without extension + "_3pings.flac")
     let output_path = generate_output_path(input_path)?;
 
     logger.info(&format!("Input file: {}", config.enrich_song_path))?;
     logger.info(&format!("Output file: {}", output_path))?;
-    logger.info(&format!("Interval length: {:.2}s", config.enrich_interval_length_s))?;
-    logger.info(&format!("Ping length: {:.2}s", config.enrich_ping_length_s))?;
 
-    // Build the FFmpeg command
-    let result = run_ffmpeg_command(config, &output_path, logger.clone());
+    enrich_file(config, input_path, &output_path, logger.clone())?;
+    logger.info("Enrich processing completed successfully")?;
+    println!("✓ Audio file enriched with sonar pings");
+    println!("  Output: {}", output_path);
+
+    Ok(())
+}
 
-    match result {
-        Ok(_) => {
-            logger.info("Enrich processing completed successfully")?;
-            println!("✓ Audio file enriched with sonar pings");
-            println!("  Output: {}", output_path);
+/// Result of enriching one file.
+struct Enriched {
+    sidecar: String,
+    pings: Option<usize>, // None when the output could not be read back
+}
+
+/// Mix pings into `input_path`, writing `output_path` and its JSON sidecar.
+fn enrich_file(config: &Config, input_path: &Path, output_path: &str, logger: Arc<Logger>) -> Result<Enriched> {
+    // masked placement: find the slots in the song, then synthesize the ping track to mix in
+    let masked = match config.enrich_ping_placement {
+        PingPlacement::Interval => None,
+        PingPlacement::Masked => {
+            let audio = decode::load_mono(input_path, config.channel_mix)?;
+            let samples = if audio.sr == OUT_SR {
+                audio.samples_mono
+            } else {
+                decode::resample_mono(&audio.samples_mono, audio.sr, OUT_SR, config.resample_quality)
+            };
+            let pings = masked_schedule(&samples, config);
+            let slots = (((samples.len() as f32) / (OUT_SR as f32)) / config.enrich_interval_length_s).ceil();
+            logger.info(
+                &format!(
+                    "Masked placement for {}: {} ping(s) in {:.0} interval(s), {:.1} to {:.1} dB",
+                    input_path.display(),
+                    pings.len(),
+                    slots,
+                    pings.iter().map(|p| p.level_db).fold(f32::INFINITY, f32::min),
+                    pings.iter().map(|p| p.level_db).fold(f32::NEG_INFINITY, f32::max)
+                )
+            )?;
+            let track_path = Path::new(output_path).with_extension("pings.wav");
+            let mut wav = WavWriter::create(&track_path, OUT_SR)?;
+            wav.write(&ping_track(&pings, samples.len(), config))?;
+            wav.finish()?;
+            Some((pings, track_path))
         }
-        Err(e) => {
-            logger.error(&format!("Enrich processing failed: {}", e))?;
-            anyhow::bail!("FFmpeg processing failed: {}", e);
+    };
+
+    // Build the FFmpeg command
+    let result = run_ffmpeg_command(
+        config,
+        input_path,
+        output_path,
+        masked.as_ref().map(|(_, p)| p.as_path()),
+        logger.clone()
+    );
+    if let Some((_, track_path)) = &masked {
+        let _ = std::fs::remove_file(track_path);
+    }
+    if let Err(e) = result {
+        logger.error(&format!("Enrich processing failed for {}: {}", input_path.display(), e))?;
+        anyhow::bail!("FFmpeg processing failed: {}", e);
+    }
+
+    // interval pings run to the end of the output, so measure it
+    let pings = match masked {
+        Some((pings, _)) => Some(pings),
+        None =>
+            match decoded_seconds(output_path, config) {
+                Ok(secs) => Some(interval_schedule(secs, config)),
+                Err(e) => {
+                    logger.warn(&format!("Could not read back {} ({}); sidecar lists no pings", output_path, e))?;
+                    None
+                }
+            }
+    };
+    let sidecar = write_sidecar(config, input_path, output_path, pings.as_deref())?;
+    logger.info(
+        &format!(
+            "Ping schedule ({} ping(s)) written to {}",
+            pings.as_ref().map_or(0, |p| p.len()),
+            sidecar
+        )
+    )?;
+    Ok(Enriched { sidecar, pings: pings.map(|p| p.len()) })
+}
+
+/// `--song-dir`: enrich every audio file of a folder (recursively) or an M3U playlist into
+/// `--out-dir`, keeping paths relative to the folder/playlist, on `--jobs` threads, and list
+/// the results in `manifest.csv` there.
+fn run_batch(config: &Config, logger: Arc<Logger>) -> Result<()> {
+    let source = Path::new(&config.enrich_song_dir);
+    if !source.exists() {
+        anyhow::bail!("--song-dir does not exist: {}", config.enrich_song_dir);
+    }
+    let out_dir = if config.enrich_out_dir.is_empty() {
+        default_out_dir(source)
+    } else {
+        PathBuf::from(&config.enrich_out_dir)
+    };
+    let inputs = batch_inputs(source, &out_dir)?;
+    if inputs.is_empty() {
+        anyhow::bail!("No audio files found in {}", config.enrich_song_dir);
+    }
+    let jobs = match config.enrich_jobs {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }.min(inputs.len());
+    logger.info(
+        &format!(
+            "Batch: {} file(s) from {} into {}, {} job(s)",
+            inputs.len(),
+            source.display(),
+            out_dir.display(),
+            jobs
+        )
+    )?;
+    println!("Enriching {} file(s) into {} ({} job(s))", inputs.len(), out_dir.display(), jobs);
+
+    // workers take the next file in list order; rows are put back in that order for the manifest
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = crossbeam_channel::unbounded();
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            let tx = tx.clone();
+            let logger = logger.clone();
+            let (next, inputs, out_dir) = (&next, &inputs, &out_dir);
+            s.spawn(move || {
+                loop {
+                    let k = next.fetch_add(1, Ordering::Relaxed);
+                    let Some((input, rel)) = inputs.get(k) else {
+                        break;
+                    };
+                    let output = batch_output_path(out_dir, rel);
+                    let result = output
+                        .parent()
+                        .map_or(Ok(()), fs::create_dir_all)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|_| enrich_file(config, input, &output.display().to_string(), logger.clone()));
+                    match &result {
+                        Ok(_) => println!("  ✓ {}", rel.display()),
+                        Err(e) => println!("  ✗ {}: {}", rel.display(), e),
+                    }
+                    let _ = tx.send((k, output, result));
+                }
+            });
         }
+    });
+    drop(tx);
+    let mut results: Vec<_> = rx.into_iter().collect();
+    results.sort_by_key(|(k, _, _)| *k);
+
+    let mut manifest = String::from("source,output,sidecar,pings,status,error\n");
+    let mut failed = 0;
+    for (k, output, result) in &results {
+        let source = inputs[*k].0.display().to_string();
+        let output = output.display().to_string();
+        let row = match result {
+            Ok(done) => {
+                let pings = done.pings.map(|n| n.to_string()).unwrap_or_default();
+                csvio::record(&[source.as_str(), &output, &done.sidecar, &pings, "ok", ""])
+            }
+            Err(e) => {
+                failed += 1;
+                let err = e.to_string().lines().next().unwrap_or("").to_string();
+                csvio::record(&[source.as_str(), &output, "", "", "failed", &err])
+            }
+        };
+        manifest.push_str(&row);
+        manifest.push('\n');
     }
+    let manifest_path = out_dir.join("manifest.csv");
+    output::write_atomic(&manifest_path, &manifest)?;
 
+    logger.info(
+        &format!(
+            "Batch finished: {} of {} file(s) enriched, manifest {}",
+            results.len() - failed,
+            results.len(),
+            manifest_path.display()
+        )
+    )?;
+    println!("✓ Enriched {} of {} file(s)", results.len() - failed, results.len());
+    println!("  Manifest: {}", manifest_path.display());
+    if failed > 0 {
+        anyhow::bail!("{} of {} file(s) failed; see {}", failed, results.len(), manifest_path.display());
+    }
     Ok(())
 }
 
+/// `<dir>_3pings` beside a folder, `<playlist stem>_3pings` beside a playlist.
+fn default_out_dir(source: &Path) -> PathBuf {
+    let name = if source.is_dir() { source.file_name() } else { source.file_stem() };
+    let name = name.map_or("enriched".into(), |n| n.to_string_lossy());
+    source.with_file_name(format!("{}_3pings", name))
+}
+
+/// Files to enrich with their paths relative to the folder or playlist: audio files below a
+/// folder (sorted, skipping earlier outputs and `out_dir`), or the entries of an M3U playlist.
+fn batch_inputs(source: &Path, out_dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut inputs = Vec::new();
+    if source.is_dir() {
+        let mut stack = vec![source.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            if dir != source && same_path(&dir, out_dir) {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    stack.push(path);
+                } else if is_audio(&path) && !is_enriched(&path) {
+                    let rel = path.strip_prefix(source).unwrap_or(&path).to_path_buf();
+                    inputs.push((path, rel));
+                }
+            }
+        }
+        inputs.sort_by(|a, b| a.1.cmp(&b.1));
+    } else {
+        // M3U / M3U8: one path per line, '#' lines are comments; relative paths are from the playlist
+        let base = source.parent().unwrap_or(Path::new("."));
+        let text = fs::read_to_string(source)?;
+        let mut seen = std::collections::HashSet::new();
+        for line in text.lines().map(|l| l.trim_start_matches('\u{feff}').trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let path = base.join(line);
+            let rel = match path.strip_prefix(base) {
+                Ok(rel) if !line.contains("..") => rel.to_path_buf(),
+                _ => PathBuf::from(path.file_name().unwrap_or(line.as_ref())),
+            };
+            // the same output twice would overwrite itself
+            if seen.insert(rel.clone()) {
+                inputs.push((path, rel));
+            }
+        }
+    }
+    Ok(inputs)
+}
+
+fn batch_output_path(out_dir: &Path, rel: &Path) -> PathBuf {
+    let stem = rel.file_stem().map_or("track".into(), |s| s.to_string_lossy());
+    out_dir.join(rel).with_file_name(format!("{}_3pings.flac", stem))
+}
+
+/// Extensions of the containers listed under Supported Audio Formats.
+fn is_audio(path: &Path) -> bool {
+    const AUDIO_EXTS: [&str; 6] = ["wav", "mp3", "mp4", "m4a", "flac", "mkv"];
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| AUDIO_EXTS.contains(&e.to_lowercase().as_str()))
+}
+
+/// Output of an earlier enrich run (`<name>_3pings.flac`).
+fn is_enriched(path: &Path) -> bool {
+    path.file_stem().is_some_and(|s| s.to_string_lossy().ends_with("_3pings"))
+}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
 fn generate_output_path(input_path: &Path) -> Result<String> {
     let stem = input_path
         .file_stem()
@@ -69,14 +430,232 @@ fn generate_output_path(input_path: &Path) -> Result<String> {
         .to_string())
 }
 
-fn run_ffmpeg_command(config: &Config, output_path: &str, logger: Arc<Logger>) -> Result<()> {
+/// FFmpeg source (plus filters) producing the ping track: a burst of `--ping-length` every
+/// `--interval-length`, peaking at `--ping-level-db`.
+fn ping_source(config: &Config) -> String {
+    let gate = format!(
+        "lt(mod(t,{}),{})*pow(10,{}/20)",
+        config.enrich_interval_length_s,
+        config.enrich_ping_length_s,
+        config.enrich_ping_level_db
+    );
+    let (lo, hi) = config.enrich_ping_waveform.band(config.enrich_ping_freq_hz);
+    let (expr, filters) = match config.enrich_ping_waveform {
+        PingWaveform::Sine => (format!("sin(2*PI*{}*t)", config.enrich_ping_freq_hz), String::new()),
+        // phase restarts with each burst, sweeping lo -> hi over the ping length
+        PingWaveform::Chirp =>
+            (
+                format!(
+                    "sin(2*PI*({lo}*mod(t,{i})+{k}*pow(mod(t,{i}),2)))",
+                    lo = lo,
+                    i = config.enrich_interval_length_s,
+                    k = (hi - lo) / (2.0 * config.enrich_ping_length_s)
+                ),
+                String::new(),
+            ),
+        // uniform noise has RMS 1/sqrt(3); after keeping PING_BW_HZ of the band scale it back
+        // to a sine's RMS, so the level means the same loudness for every waveform
+        PingWaveform::Noise =>
+            (
+                format!(
+                    "{}*(2*random(0)-1)",
+                    (1.5 * ((OUT_SR as f32) / 2.0 / PING_BW_HZ)).sqrt()
+                ),
+                format!(
+                    ",bandpass=f={f}:width_type=h:w={w},bandpass=f={f}:width_type=h:w={w}",
+                    f = config.enrich_ping_freq_hz,
+                    w = PING_BW_HZ
+                ),
+            ),
+    };
+    format!("aevalsrc=exprs='{}*{}':s={}:d=999999:channel_layout=stereo{}", gate, expr, OUT_SR, filters)
+}
+
+/// Pick the masked ping slots: for each --interval-length slot, the window (prescan features,
+/// one ping long) with the most energy from MASK_SPREAD_HZ below the ping band up. The ping sits
+/// --ping-mask-margin-db under that energy, capped at --ping-level-db; a slot whose best window
+/// would need it more than MASK_RANGE_DB quieter than that gets no ping.
+pub fn masked_schedule(samples: &[f32], config: &Config) -> Vec<Ping> {
+    let (lo, _) = config.enrich_ping_waveform.band(config.enrich_ping_freq_hz);
+    let params = prescan::ScanParams {
+        sr: OUT_SR as f32,
+        frame_ms: config.frame_ms,
+        window_s: config.enrich_ping_length_s.max(0.05),
+        stride_ms: 20.0,
+        hf_split_hz: (lo - MASK_SPREAD_HZ).max(1000.0),
+        top_n: 0,
+        min_percentile: 0.0,
+        nms_radius_s: 0.0,
+        merge_gap_s: 0.0,
+        clamp_min_s: 0.0,
+        clamp_max_s: 0.0,
+        scorer: Box::new(Heuristic(config.scan_weights)), // the windows are used unranked
+    };
+    let mut analyzer = prescan::Analyzer::new(&params);
+    analyzer.push(samples);
+    let wins = analyzer.into_windows();
+
+    let interval = config.enrich_interval_length_s;
+    let max_db = config.enrich_ping_level_db;
+    let mut pings = Vec::new();
+    let mut w = 0;
+    let mut slot = 0;
+    while w < wins.len() {
+        let (from, to) = ((slot as f32) * interval, ((slot + 1) as f32) * interval);
+        let mut best: Option<(f32, f32)> = None; // (masker dB, start_s)
+        while w < wins.len() && wins[w].start_s < to {
+            let win = &wins[w];
+            // the whole ping has to fit in its slot
+            if win.start_s >= from && win.start_s + config.enrich_ping_length_s <= to {
+                let masker_db = win.loudness_dbfs + 10.0 * win.hf_ratio.max(1e-12).log10();
+                if best.is_none_or(|(db, _)| masker_db > db) {
+                    best = Some((masker_db, win.start_s));
+                }
+            }
+            w += 1;
+        }
+        if let Some((masker_db, start_s)) = best {
+            let level_db = (masker_db - config.enrich_mask_margin_db).min(max_db);
+            if level_db >= max_db - MASK_RANGE_DB {
+                pings.push(Ping { start_s, level_db });
+            }
+        }
+        slot += 1;
+    }
+    pings
+}
+
+/// Mono ping track `len` samples long at OUT_SR with the scheduled bursts.
+fn ping_track(pings: &[Ping], len: usize, config: &Config) -> Vec<f32> {
+    let sr = OUT_SR as f32;
+    let n = ((config.enrich_ping_length_s * sr) as usize).max(1);
+    let fade = ((PING_FADE_S * sr) as usize).min(n / 2);
+    let (lo, hi) = config.enrich_ping_waveform.band(config.enrich_ping_freq_hz);
+
+    // noise: a fixed set of random-phase partials across the band, each 1/sqrt(N) of the
+    // amplitude so the burst has a sine's RMS
+    const PARTIALS: usize = 32;
+    let mut seed = 0x5eed_u64;
+    let mut rand = move || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((seed >> 40) as f32) / ((1u64 << 24) as f32)
+    };
+    let partials: Vec<(f32, f32)> = (0..PARTIALS)
+        .map(|_| (lo + (hi - lo) * rand(), std::f32::consts::TAU * rand()))
+        .collect();
+
+    let burst: Vec<f32> = (0..n)
+        .map(|i| {
+            let t = (i as f32) / sr;
+            let v = match config.enrich_ping_waveform {
+                PingWaveform::Sine => (std::f32::consts::TAU * config.enrich_ping_freq_hz * t).sin(),
+                PingWaveform::Chirp =>
+                    (std::f32::consts::TAU * (lo * t + ((hi - lo) / (2.0 * config.enrich_ping_length_s)) * t * t)).sin(),
+                PingWaveform::Noise =>
+                    partials
+                        .iter()
+                        .map(|(f, ph)| (std::f32::consts::TAU * f * t + ph).sin())
+                        .sum::<f32>() / (PARTIALS as f32).sqrt(),
+            };
+            let edge = i.min(n - 1 - i);
+            let gain = if edge < fade {
+                0.5 - 0.5 * (std::f32::consts::PI * (edge as f32) / (fade as f32)).cos()
+            } else {
+                1.0
+            };
+            v * gain
+        })
+        .collect();
+
+    let mut out = vec![0.0f32; len];
+    for p in pings {
+        let amp = (10.0f32).powf(p.level_db / 20.0);
+        let start = (p.start_s * sr) as usize;
+        for (o, b) in out.iter_mut().skip(start).zip(&burst) {
+            *o += amp * b;
+        }
+    }
+    out
+}
+
+/// Pings of interval placement over `secs` of track: every --interval-length at --ping-level-db.
+fn interval_schedule(secs: f32, config: &Config) -> Vec<Ping> {
+    let interval = config.enrich_interval_length_s;
+    (0..)
+        .map(|k| (k as f32) * interval)
+        .take_while(|&t| t < secs)
+        .map(|start_s| Ping { start_s, level_db: config.enrich_ping_level_db })
+        .collect()
+}
+
+/// Length of an audio file, by decoding it.
+fn decoded_seconds(path: &str, config: &Config) -> Result<f32> {
+    let stream = decode::open_mono(path, config.channel_mix)?;
+    let sr = stream.sr as f32;
+    let mut n = 0usize;
+    for chunk in stream {
+        n += chunk?.len();
+    }
+    Ok((n as f32) / sr)
+}
+
+/// Record the ping parameters and every ping beside the enriched file (`<output stem>.json`);
+/// `--ping-schedule` reads it back so detection knows when and where to expect probe energy.
+fn write_sidecar(config: &Config, input_path: &Path, output_path: &str, pings: Option<&[Ping]>) -> Result<String> {
+    let path = Path::new(output_path).with_extension("json");
+    let doc = JsonObj::new()
+        .str("source", &input_path.display().to_string())
+        .str("output", output_path)
+        .int("sample_rate_hz", OUT_SR as i64)
+        .str("ping_waveform", config.enrich_ping_waveform.as_str())
+        .num("ping_freq_hz", config.enrich_ping_freq_hz as f64)
+        .num("ping_bandwidth_hz", (if config.enrich_ping_waveform == PingWaveform::Sine { 0.0 } else { PING_BW_HZ }) as f64)
+        .num("ping_level_db", config.enrich_ping_level_db as f64)
+        .num("ping_length_s", config.enrich_ping_length_s as f64)
+        .num("interval_length_s", config.enrich_interval_length_s as f64)
+        .str("ping_placement", config.enrich_ping_placement.as_str());
+    let doc = match pings {
+        Some(pings) => {
+            let items: Vec<String> = pings
+                .iter()
+                .map(|p| {
+                    JsonObj::new()
+                        .num("start_s", p.start_s as f64)
+                        .num("length_s", config.enrich_ping_length_s as f64)
+                        .num("freq_hz", config.enrich_ping_freq_hz as f64)
+                        .num("level_db", p.level_db as f64)
+                        .finish()
+                })
+                .collect();
+            doc.arr("pings", &items)
+        }
+        None => doc,
+    }
+    .finish();
+    output::write_atomic(&path, &doc)?;
+    Ok(path.display().to_string())
+}
+
+/// Mix the pings into the song: generated by FFmpeg for interval placement, or read from
+/// `ping_track` (a WAV as long as the song) for masked placement.
+fn run_ffmpeg_command(
+    config: &Config,
+    input_path: &Path,
+    output_path: &str,
+    ping_track: Option<&Path>,
+    logger: Arc<Logger>
+) -> Result<()> {
     logger.info("Executing FFmpeg command...")?;
 
     // Build the filter complex string
+    let pings = match ping_track {
+        Some(_) => "[1:a]aformat=channel_layouts=stereo".to_string(),
+        None => ping_source(config),
+    };
     let filter_complex = format!(
-        "[0:a]aresample=48000,aformat=sample_rates=48000:channel_layouts=stereo[a];aevalsrc=exprs='(lt(mod(t,{}),{}))*pow(10,-35/20)*sin(2*PI*18500*t)':s=48000:d=999999:channel_layout=stereo[u];[a][u]amix=inputs=2:duration=first:dropout_transition=0[out]",
-        config.enrich_interval_length_s,
-        config.enrich_ping_length_s
+        "[0:a]aresample={sr},aformat=sample_rates={sr}:channel_layouts=stereo[a];{pings}[u];[a][u]amix=inputs=2:duration=first:dropout_transition=0[out]",
+        sr = OUT_SR,
+        pings = pings
     );
 
     logger.info(&format!("Filter complex: {}", filter_complex))?;
@@ -86,7 +665,11 @@ fn run_ffmpeg_command(config: &Config, output_path: &str, logger: Arc<Logger>) -
     command
         .arg("-hide_banner")
         .arg("-i")
-        .arg(&config.enrich_song_path)
+        .arg(input_path);
+    if let Some(track) = ping_track {
+        command.arg("-i").arg(track);
+    }
+    command
         .arg("-filter_complex")
         .arg(&filter_complex)
         .arg("-map")
@@ -124,4 +707,67 @@ fn run_ffmpeg_command(config: &Config, output_path: &str, logger: Arc<Logger>) -
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Lcg;
+
+    #[test]
+    fn masked_pings_go_where_content_hides_them() {
+        let sr = OUT_SR as f32;
+        let cfg = Config { enrich_ping_placement: PingPlacement::Masked, ..Config::default() };
+        // 5 s of loud broadband noise, then 5 s of a quiet 220 Hz tone with nothing up high
+        let mut rng = Lcg(7);
+        let mut song: Vec<f32> = (0..(5.0 * sr) as usize).map(|_| 0.5 * rng.next()).collect();
+        song.extend((0..(5.0 * sr) as usize).map(|i| 0.1 * (std::f32::consts::TAU * 220.0 * (i as f32) / sr).sin()));
+
+        let pings = masked_schedule(&song, &cfg);
+        assert!(pings.len() >= 4, "{:?}", pings);
+        for (k, p) in pings.iter().enumerate() {
+            assert!(p.start_s + cfg.enrich_ping_length_s <= 5.0, "ping under the tone: {:?}", p);
+            assert!(p.level_db <= cfg.enrich_ping_level_db && p.level_db >= cfg.enrich_ping_level_db - MASK_RANGE_DB);
+            // one per interval
+            if k > 0 {
+                assert!(p.start_s.floor() > pings[k - 1].start_s.floor());
+            }
+        }
+
+        // every waveform lands in the track at its scheduled time and level
+        for wave in [PingWaveform::Sine, PingWaveform::Chirp, PingWaveform::Noise] {
+            let cfg = Config { enrich_ping_waveform: wave, ..cfg.clone() };
+            let ping = Ping { start_s: 1.0, level_db: -20.0 };
+            let track = ping_track(&[ping], song.len(), &cfg);
+            let n = (cfg.enrich_ping_length_s * sr) as usize;
+            let burst = &track[sr as usize..sr as usize + n];
+            let rms_db = 20.0 * prescan::rms(burst).log10();
+            assert!((rms_db - (-20.0 - 3.0)).abs() < 1.5, "{:?}: {:.1} dB", wave, rms_db);
+            assert!(track[..sr as usize].iter().all(|&v| v == 0.0));
+        }
+    }
+
+    #[test]
+    fn batch_keeps_relative_paths_and_skips_outputs() {
+        let root = std::env::temp_dir().join(format!("enrich-batch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let music = root.join("music");
+        let out = music.join("out");
+        for f in ["a.mp3", "b/c.FLAC", "b/c_3pings.flac", "b/notes.txt", "out/x.wav"] {
+            let p = music.join(f);
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(p, b"").unwrap();
+        }
+        fs::write(root.join("list.m3u8"), "#EXTM3U\nmusic/a.mp3\n\n#EXTINF:1,x\nmusic/b/c.FLAC\nmusic/a.mp3\n").unwrap();
+
+        let from_dir = batch_inputs(&music, &out).unwrap();
+        let from_list = batch_inputs(&root.join("list.m3u8"), &out).unwrap();
+        let _ = fs::remove_dir_all(&root);
+
+        let rels: Vec<PathBuf> = from_dir.iter().map(|(_, r)| r.clone()).collect();
+        assert_eq!(rels, [PathBuf::from("a.mp3"), Path::new("b").join("c.FLAC")]);
+        assert_eq!(from_list.len(), 2);
+        assert_eq!(from_list[1].1, Path::new("music").join("b").join("c.FLAC"));
+        assert_eq!(batch_output_path(&out, &rels[1]), out.join("b").join("c_3pings.flac"));
+        assert_eq!(default_out_dir(&music), root.join("music_3pings"));
+    }
+}
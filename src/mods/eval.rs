@@ -0,0 +1,237 @@
+use anyhow::Result;
+use std::{ path::Path, sync::Arc };
+
+use crate::{ calibration, output, Config };
+use crate::logger::Logger;
+use crate::mods::replay;
+use crate::mods::report::ReportFormat;
+
+pub const EVAL_CSV_HEADER: &str =
+    "scored_s,labelled_present_s,precision,recall,stretches,detected,latency_median_s,latency_mean_s,latency_max_s,flips,false_flips";
+
+/// Scores a replayed session's smoothed presence state against its labels, tick by tick.
+/// Scoring starts with the first full window: before that the detector has no opinion.
+#[derive(Clone, Debug, Default)]
+pub struct Evaluation {
+    labels: Vec<(f64, f64)>,
+    from_s: Option<f64>,
+    last: Option<(f64, bool)>, // previous scored tick: time and state
+    scored_s: f64,
+    labelled_s: f64,
+    // seconds the state and the labels spent in each combination
+    true_pos_s: f64,
+    false_pos_s: f64,
+    false_neg_s: f64,
+    flips: u64,
+    false_flips: u64, // entered while labelled absent, or left while labelled present
+    detected: Vec<Option<f64>>, // per label: first present tick, seconds after its start
+}
+
+impl Evaluation {
+    pub fn new(labels: Vec<(f64, f64)>) -> Self {
+        let detected = vec![None; labels.len()];
+        Self { labels, detected, ..Self::default() }
+    }
+
+    /// One tick at `t_s` into the recording; `full` once the agreement window has filled.
+    pub fn tick(&mut self, t_s: f64, full: bool, present: bool) {
+        if self.from_s.is_none() {
+            if !full {
+                return;
+            }
+            self.from_s = Some(t_s);
+        }
+        let truth = calibration::labelled_present(&self.labels, t_s);
+        if let Some((t_prev, was)) = self.last {
+            // the tick stands for the time since the one before it
+            let dt = t_s - t_prev;
+            self.scored_s += dt;
+            match (present, truth) {
+                (true, true) => self.true_pos_s += dt,
+                (true, false) => self.false_pos_s += dt,
+                (false, true) => self.false_neg_s += dt,
+                (false, false) => {}
+            }
+            if truth {
+                self.labelled_s += dt;
+            }
+            if present != was {
+                self.flips += 1;
+                if present != truth {
+                    self.false_flips += 1;
+                }
+            }
+        }
+        if present && truth {
+            let from_s = self.from_s.unwrap_or(0.0);
+            for (&(from, to), seen) in self.labels.iter().zip(self.detected.iter_mut()) {
+                if seen.is_none() && from >= from_s && (from..to).contains(&t_s) {
+                    *seen = Some(t_s - from);
+                }
+            }
+        }
+        self.last = Some((t_s, present));
+    }
+
+    /// Present stretches that began while scoring; one that began before cannot be timed.
+    fn stretches(&self) -> usize {
+        let Some(from_s) = self.from_s else {
+            return 0;
+        };
+        self.labels.iter().filter(|l| l.0 >= from_s && l.0 < from_s + self.scored_s).count()
+    }
+
+    pub fn finish(&self) -> EvalReport {
+        combine(std::slice::from_ref(self))
+    }
+}
+
+/// One report over several sessions, their times and counts added up.
+pub fn combine(evals: &[Evaluation]) -> EvalReport {
+    let sum = |f: fn(&Evaluation) -> f64| evals.iter().map(f).sum::<f64>();
+    let (true_pos_s, false_pos_s, false_neg_s) = (sum(|e| e.true_pos_s), sum(|e| e.false_pos_s), sum(|e| e.false_neg_s));
+    let mut latencies: Vec<f64> = evals.iter().flat_map(|e| e.detected.iter().flatten().copied()).collect();
+    latencies.sort_by(|a, b| a.total_cmp(b));
+    let ratio = |num: f64, den: f64| (den > 0.0).then(|| num / den);
+    EvalReport {
+        scored_s: sum(|e| e.scored_s),
+        labelled_s: sum(|e| e.labelled_s),
+        precision: ratio(true_pos_s, true_pos_s + false_pos_s),
+        recall: ratio(true_pos_s, true_pos_s + false_neg_s),
+        stretches: evals.iter().map(Evaluation::stretches).sum(),
+        detected: latencies.len(),
+        latency_median_s: (!latencies.is_empty()).then(|| latencies[latencies.len() / 2]),
+        latency_mean_s: ratio(latencies.iter().sum(), latencies.len() as f64),
+        latency_max_s: latencies.last().copied(),
+        flips: evals.iter().map(|e| e.flips).sum(),
+        false_flips: evals.iter().map(|e| e.false_flips).sum(),
+    }
+}
+
+/// What `--mode eval` reports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalReport {
+    pub scored_s: f64,
+    pub labelled_s: f64, // of the scored time, labelled present
+    pub precision: Option<f64>, // of the time reported present, the share labelled present
+    pub recall: Option<f64>, // of the time labelled present, the share reported present
+    pub stretches: usize, // labelled present stretches that began while scoring
+    pub detected: usize, // ...and were reported present before they ended
+    pub latency_median_s: Option<f64>, // from a stretch's start to its first present tick
+    pub latency_mean_s: Option<f64>,
+    pub latency_max_s: Option<f64>,
+    pub flips: u64,
+    pub false_flips: u64,
+}
+
+impl EvalReport {
+    pub fn to_csv(&self) -> String {
+        let opt = |v: Option<f64>, prec: usize| v.map(|v| format!("{:.*}", prec, v)).unwrap_or_default();
+        format!(
+            "{}\n{:.1},{:.1},{},{},{},{},{},{},{},{},{}\n",
+            EVAL_CSV_HEADER,
+            self.scored_s,
+            self.labelled_s,
+            opt(self.precision, 3),
+            opt(self.recall, 3),
+            self.stretches,
+            self.detected,
+            opt(self.latency_median_s, 2),
+            opt(self.latency_mean_s, 2),
+            opt(self.latency_max_s, 2),
+            self.flips,
+            self.false_flips
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        output::JsonObj
+            ::new()
+            .num("scored_s", self.scored_s)
+            .num("labelled_present_s", self.labelled_s)
+            .opt_num("precision", self.precision)
+            .opt_num("recall", self.recall)
+            .int("stretches", self.stretches as i64)
+            .int("detected", self.detected as i64)
+            .opt_num("latency_median_s", self.latency_median_s)
+            .opt_num("latency_mean_s", self.latency_mean_s)
+            .opt_num("latency_max_s", self.latency_max_s)
+            .int("flips", self.flips as i64)
+            .int("false_flips", self.false_flips as i64)
+            .finish() + "\n"
+    }
+
+    /// One line for Detection.log.
+    pub fn summary(&self) -> String {
+        let pct = |v: Option<f64>| v.map_or("n/a".to_string(), |v| format!("{:.1}%", v * 100.0));
+        format!(
+            "eval: {:.0}s scored, precision {}, recall {}, {}/{} stretches detected (latency median {} max {}), {} flips, {} false",
+            self.scored_s,
+            pct(self.precision),
+            pct(self.recall),
+            self.detected,
+            self.stretches,
+            self.latency_median_s.map_or("n/a".to_string(), |v| format!("{:.1}s", v)),
+            self.latency_max_s.map_or("n/a".to_string(), |v| format!("{:.1}s", v)),
+            self.flips,
+            self.false_flips
+        )
+    }
+}
+
+/// Eval mode: replay `--ref-wav`/`--mic-wav` as fast as possible with the current settings and
+/// score the presence state against `--labels`. Writes CSV or JSON to `--report-out`, or stdout.
+pub fn run_eval(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    if cli.labels.is_empty() {
+        anyhow::bail!("--labels <CSV> is required in eval mode");
+    }
+    let labels = calibration::load_labels(Path::new(&cli.labels))?;
+    logger.info(&format!("Eval: {} present stretches in {}", labels.len(), cli.labels))?;
+
+    let mut eval = Evaluation::new(labels);
+    let cfg = Config { replay_speed: 0.0, ..cli.clone() };
+    replay::replay_session(&cfg, &logger, |det, t_s, res| {
+        eval.tick(t_s, res.is_some_and(|r| r.window.is_some()), det.policy.present());
+    })?;
+    let report = eval.finish();
+    logger.info(&report.summary())?;
+
+    let text = match cli.report_format {
+        ReportFormat::Csv => report.to_csv(),
+        ReportFormat::Json => report.to_json(),
+    };
+    if cli.report_out.is_empty() {
+        print!("{}", text);
+    } else {
+        output::write_atomic(Path::new(&cli.report_out), &text)?;
+        logger.info(&format!("Eval ({}) written to {}", cli.report_format.as_str(), cli.report_out))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scores_time_latency_and_false_flips() {
+        // someone there 10–20 s and 30–40 s; scoring starts at 2 s
+        let mut eval = Evaluation::new(vec![(10.0, 20.0), (30.0, 40.0)]);
+        for k in 0..500 {
+            let t = (k as f64) * 0.1;
+            let present = (12.0..21.0).contains(&t) || (25.0..26.0).contains(&t);
+            eval.tick(t, t >= 2.0, present);
+        }
+        let r = eval.finish();
+        assert!((r.scored_s - 47.9).abs() < 1e-6);
+        assert!((r.labelled_s - 20.0).abs() < 0.15);
+        // present 9 s + 1 s, of which 8 s labelled; 20 s labelled, of which 8 s reported
+        assert!((r.precision.unwrap() - 0.8).abs() < 0.02, "{:?}", r);
+        assert!((r.recall.unwrap() - 0.4).abs() < 0.02, "{:?}", r);
+        assert_eq!((r.stretches, r.detected), (2, 1));
+        assert!((r.latency_median_s.unwrap() - 2.0).abs() < 1e-6);
+        // enter 12 (right), exit 21 (right: labels over), enter 25 (false), exit 26 (right)
+        assert_eq!((r.flips, r.false_flips), (4, 1));
+        assert_eq!(r.to_csv().lines().count(), 2);
+    }
+}
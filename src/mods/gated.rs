@@ -1,33 +1,36 @@
 use anyhow::Result;
-use cpal::traits::{ DeviceTrait, HostTrait, StreamTrait };
-use crossbeam_channel::bounded;
 use std::{
-    fs::{ File, OpenOptions },
-    io::{ BufRead, BufReader, Write },
-    path::Path,
-    sync::{ atomic::{ AtomicBool, Ordering }, Arc, Mutex },
-    thread,
+    collections::HashMap,
+    fmt,
+    fs,
+    io,
+    path::{ Path, PathBuf },
+    sync::Arc,
     time::{ Duration, Instant },
 };
 
-use crate::{
-    audio_sink_thread,
-    build_input_stream,
-    maybe_rate_supported,
-    prescan,
-    sonar_presence,
-    wasapi_loopback,
-    SharedBuf,
-    Config,
-};
-use crate::logger::Logger;
-
-#[cfg(target_os = "windows")]
-use crate::{ start_probe, ENABLE_PROBE_TONE };
+use crate::{ csvio, prescan, sonar_presence, Config };
+use crate::sonar_presence::PresenceState;
+use crate::fpdb::{ FpDb, SongWindows };
+use crate::audio::{ self, AudioSource };
+use crate::logger::{ Field, Logger };
+use crate::output::{ self, JsonObj };
+use crate::hooks::HookEvent;
+use crate::mods::engine::PresenceEngine;
+use crate::mods::presence::Pairing;
+use crate::recorder::TickMeta;
+use crate::smtc::{ self, MediaSession, Playback };
+use crate::pingsched::{ self, PingSchedule };
+use crate::strategy::{ self, Decision, DetectorKind };
+use crate::hours;
+use crate::micbusy;
+use crate::resume::{ Resume, Snapshot };
+use crate::calibration::Calibration;
+use crate::winquality::WindowQuality;
 
 /// Small local hex decoder (kept here so this file is self-contained).
 fn from_hex(s: &str) -> Option<Vec<u8>> {
-    if s.len() % 2 != 0 {
+    if !s.len().is_multiple_of(2) {
         return None;
     }
     let mut out = Vec::with_capacity(s.len() / 2);
@@ -40,69 +43,60 @@ fn from_hex(s: &str) -> Option<Vec<u8>> {
     Some(out)
 }
 
-#[derive(Clone, Debug)]
-struct SongFingerprint {
-    url: String,
-    fp_type: String,
-    bands: usize,
-    hop_s: f32,
-    offset_s: f32,
-    bins: Vec<u8>,
+/// Why SongScan.csv could not be turned into songs to match against.
+#[derive(Debug)]
+pub enum SongScanError {
+    NotFound(PathBuf),
+    Read(io::Error),
+    Empty,
+    MissingColumn(&'static str),
 }
 
-#[derive(Clone, Debug)]
-struct SongWindows {
-    url: String,
-    segs: Vec<(f32, f32)>, // [start_s, end_s]
-    fp: SongFingerprint,
+impl fmt::Display for SongScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(path) => write!(f, "SongScan.csv not found at {}", path.display()),
+            Self::Read(e) => write!(f, "cannot read SongScan.csv: {}", e),
+            Self::Empty => write!(f, "SongScan.csv is empty"),
+            Self::MissingColumn(name) => write!(f, "SongScan.csv missing '{}' column", name),
+        }
+    }
 }
 
-fn parse_scansong(csv_path: &Path, logger: &Logger) -> Result<Vec<SongWindows>> {
-    let file = File::open(csv_path)?;
-    let reader = BufReader::new(file);
-    let mut lines = reader.lines();
+impl std::error::Error for SongScanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Read(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn parse_scansong(csv_path: &Path, logger: &Logger) -> Result<Vec<SongWindows>, SongScanError> {
+    let text = fs::read_to_string(csv_path).map_err(SongScanError::Read)?;
+    let mut records = csvio::parse(&text).into_iter();
 
     // header
-    let header = lines.next().ok_or_else(|| anyhow::anyhow!("SongScan.csv is empty"))??;
-    let cols: Vec<&str> = header.split(',').collect();
-    let mut idx = |name: &str| -> Option<usize> { cols.iter().position(|c| c.trim() == name) };
+    let cols = records.next().ok_or(SongScanError::Empty)?;
+    let idx = |name: &'static str| cols.iter().position(|c| c.trim() == name).ok_or(SongScanError::MissingColumn(name));
 
     // required columns
-    let i_url = idx("url").ok_or_else(|| anyhow::anyhow!("SongScan.csv missing 'url' column"))?;
-    let i_start = idx("start_s").ok_or_else(|| anyhow::anyhow!("SongScan.csv missing 'start_s'"))?;
-    let i_end = idx("end_s").ok_or_else(|| anyhow::anyhow!("SongScan.csv missing 'end_s'"))?;
+    let i_url = idx("url")?;
+    let i_start = idx("start_s")?;
+    let i_end = idx("end_s")?;
 
     // fingerprint columns
-    let i_fp_type = idx("fp_type").ok_or_else(||
-        anyhow::anyhow!("SongScan.csv missing 'fp_type'")
-    )?;
-    let i_fp_bands = idx("fp_bands").ok_or_else(||
-        anyhow::anyhow!("SongScan.csv missing 'fp_bands'")
-    )?;
-    let i_fp_hop = idx("fp_hop_s").ok_or_else(||
-        anyhow::anyhow!("SongScan.csv missing 'fp_hop_s'")
-    )?;
-    let i_fp_off = idx("fp_offset_s").ok_or_else(||
-        anyhow::anyhow!("SongScan.csv missing 'fp_offset_s'")
-    )?;
-    let i_fp_bins = idx("fp_bins_hex").ok_or_else(||
-        anyhow::anyhow!("SongScan.csv missing 'fp_bins_hex'")
-    )?;
+    let i_fp_type = idx("fp_type")?;
+    let i_fp_bands = idx("fp_bands")?;
+    let i_fp_hop = idx("fp_hop_s")?;
+    let i_fp_off = idx("fp_offset_s")?;
+    let i_fp_bins = idx("fp_bins_hex")?;
 
     use std::collections::BTreeMap;
-    let mut by_url: BTreeMap<String, (Option<SongFingerprint>, Vec<(f32, f32)>)> = BTreeMap::new();
+    type Rows = (Vec<prescan::Fingerprint>, Vec<(f32, f32)>); // fingerprints, windows
+    let mut by_url: BTreeMap<String, Rows> = BTreeMap::new();
 
-    for line in lines {
-        let line = match line {
-            Ok(s) => s,
-            Err(_) => {
-                continue;
-            }
-        };
-        if line.trim().is_empty() {
-            continue;
-        }
-        let parts: Vec<&str> = line.split(',').collect();
+    for parts in records {
         if parts.len() <= i_end {
             continue;
         }
@@ -112,48 +106,84 @@ fn parse_scansong(csv_path: &Path, logger: &Logger) -> Result<Vec<SongWindows>>
             continue;
         }
 
-        let start_s: f32 = parts[i_start].trim().parse().unwrap_or(0.0);
-        let end_s: f32 = parts[i_end].trim().parse().unwrap_or(0.0);
-
-        let entry = by_url.entry(url.clone()).or_insert((None, Vec::new()));
-        entry.1.push((start_s, end_s));
-
-        if entry.0.is_none() {
-            let fp_type = parts[i_fp_type].trim().to_string();
-            let bands = parts[i_fp_bands].trim().parse::<usize>().unwrap_or(0);
-            let hop_s = parts[i_fp_hop].trim().parse::<f32>().unwrap_or(0.0);
-            let offset_s = parts[i_fp_off].trim().parse::<f32>().unwrap_or(0.0);
-            let bins_hex = parts
-                .get(i_fp_bins)
-                .map(|s| s.trim())
-                .unwrap_or("");
-            if !fp_type.is_empty() && bands > 0 && hop_s > 0.0 && !bins_hex.is_empty() {
-                if let Some(bins) = from_hex(bins_hex) {
-                    entry.0 = Some(SongFingerprint {
-                        url: url.clone(),
-                        fp_type,
-                        bands,
-                        hop_s,
-                        offset_s,
-                        bins,
-                    });
-                }
+        let entry = by_url.entry(url.clone()).or_insert((Vec::new(), Vec::new()));
+
+        // fingerprint-only rows leave the segment columns empty
+        let start = parts[i_start].trim();
+        if !start.is_empty() {
+            let start_s: f32 = start.parse().unwrap_or(0.0);
+            let end_s: f32 = parts[i_end].trim().parse().unwrap_or(0.0);
+            entry.1.push((start_s, end_s));
+        }
+
+        let field = |i: usize| parts.get(i).map(|s| s.trim()).unwrap_or("");
+        let fp_type = field(i_fp_type).to_string();
+        let bands = field(i_fp_bands).parse::<usize>().unwrap_or(0);
+        let hop_s = field(i_fp_hop).parse::<f32>().unwrap_or(0.0);
+        let offset_s = field(i_fp_off).parse::<f32>().unwrap_or(0.0);
+        let bins_hex = field(i_fp_bins);
+        // segment rows all repeat the lead-in fingerprint
+        let known = entry.0.iter().any(|f| f.fp_type == fp_type && (f.offset_s - offset_s).abs() < 1e-3);
+        if !known && !fp_type.is_empty() && bands > 0 && hop_s > 0.0 && !bins_hex.is_empty() {
+            if let Some(bins) = from_hex(bins_hex) {
+                entry.0.push(prescan::Fingerprint { fp_type, bands, hop_s, offset_s, bins });
             }
         }
     }
 
     let mut out = Vec::<SongWindows>::new();
-    for (url, (maybe_fp, mut segs)) in by_url {
-        if let Some(fp) = maybe_fp {
-            segs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-            out.push(SongWindows { url, segs, fp });
-        } else {
+    for (url, (mut fps, mut segs)) in by_url {
+        if fps.is_empty() {
             let _ = logger.warn(&format!("Skipping url with no usable fingerprint: {}", url));
+        } else if segs.is_empty() {
+            let _ = logger.warn(&format!("Skipping url with no segments: {}", url));
+        } else {
+            segs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            fps.sort_by(|a, b| a.offset_s.partial_cmp(&b.offset_s).unwrap());
+            out.push(SongWindows { url, segs, fps });
         }
     }
     Ok(out)
 }
 
+/// Where the songs come from: `--fp-db` if set, else SongScan.csv.
+fn db_source(cli: &Config) -> &str {
+    if cli.fp_db.is_empty() { &cli.scansong_path } else { &cli.fp_db }
+}
+
+/// Songs to match against. With `--fp-db` the binary database is loaded, or built from
+/// SongScan.csv (and saved) the first time and whenever SongScan.csv is newer, e.g. after a
+/// `scan` run without `--fp-db`; without it SongScan.csv is parsed and indexed in memory.
+fn load_db(cli: &Config, logger: &Logger) -> Result<FpDb> {
+    let db_path = Path::new(&cli.fp_db);
+    let csv_scan_path = Path::new(&cli.scansong_path);
+    if !cli.fp_db.is_empty() && db_path.exists() && !newer(csv_scan_path, db_path) {
+        return FpDb::load(db_path);
+    }
+    if !csv_scan_path.exists() {
+        return Err(SongScanError::NotFound(csv_scan_path.to_path_buf()).into());
+    }
+    let db = FpDb::new(parse_scansong(csv_scan_path, logger)?);
+    if !cli.fp_db.is_empty() {
+        db.save(db_path)?;
+        logger.info(&format!("Built fingerprint database {} from {}", db_path.display(), csv_scan_path.display()))?;
+    }
+    Ok(db)
+}
+
+/// Whether `a` was modified after `b` (false if either time is unknown, e.g. `a` is missing).
+fn newer(a: &Path, b: &Path) -> bool {
+    let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    matches!((modified(a), modified(b)), (Some(ta), Some(tb)) if ta > tb)
+}
+
+/// Write WindowQuality.csv; a failure is logged and the scores kept for the next attempt.
+fn save_quality(quality: &WindowQuality, logger: &Logger) {
+    if let Err(e) = quality.save() {
+        let _ = logger.warn(&format!("{:#}", e));
+    }
+}
+
 fn rms_dbfs(x: &[f32]) -> f32 {
     if x.is_empty() {
         return -120.0;
@@ -170,127 +200,489 @@ fn rms_dbfs(x: &[f32]) -> f32 {
     }
 }
 
+/// Outcome of matching the live fingerprints against the stored songs.
+#[derive(Clone, Debug, Default)]
+struct Match {
+    url: String, // empty = nothing matched
+    similarity: f32,
+    second: f32, // best similarity of any other song
+    song_s: f32, // position in the song of the start of the live chunk
+}
+
+/// Compare the stored fingerprints with the live fingerprint of their type (`live` fingerprints
+/// were all taken from the same chunk); the database narrows them down to the candidates worth
+/// scoring. A song scores its best fingerprint; the winning fingerprint's lag gives the playback
+/// position.
+fn best_match(db: &FpDb, live: &[prescan::Fingerprint]) -> Match {
+    let mut best = Match::default();
+
+    let mut per_song: Vec<Option<(f32, f32)>> = vec![None; db.songs.len()]; // (similarity, song_s)
+    for l in live {
+        for (si, fi) in db.candidates(l) {
+            let stored = &db.songs[si].fps[fi];
+            let (sim, lag_s) = prescan::fp_match(l, stored);
+            if per_song[si].is_none_or(|(b, _)| sim > b) {
+                per_song[si] = Some((sim, stored.offset_s + lag_s - l.offset_s));
+            }
+        }
+    }
+
+    for (s, song_best) in db.songs.iter().zip(per_song) {
+        let Some((sim, song_s)) = song_best else {
+            continue;
+        };
+        if sim > best.similarity {
+            best.second = best.similarity;
+            best.url = s.url.clone();
+            best.similarity = sim;
+            best.song_s = song_s;
+        } else if sim > best.second {
+            best.second = sim;
+        }
+    }
+    best
+}
+
+/// Where the aligned playback position sits relative to a song's windows.
+#[derive(Clone, Copy, Debug, Default)]
+struct GatePos {
+    paused: bool, // playback silent: position frozen, no window active
+    active_idx: Option<usize>, // window currently being analysed (guard included)
+    active_seg: Option<(f32, f32)>, // its (start_s, end_s) as stored, without the guard
+    active_remaining_s: Option<f32>, // seconds until the active window closes
+    next_idx: Option<usize>, // next window after t_song
+    next_in_s: Option<f32>, // seconds until the next window opens
+}
+
+impl GatePos {
+    /// The active window for Detection.csv: index, start_s, end_s.
+    fn segment(&self) -> Option<(usize, f32, f32)> {
+        self.active_idx.zip(self.active_seg).map(|(i, (a, b))| (i, a, b))
+    }
+}
+
+fn gate_position(segs: &[(f32, f32)], t_song: f32, guard_s: f32) -> GatePos {
+    let mut pos = GatePos::default();
+    for (i, &(a, b)) in segs.iter().enumerate() {
+        let open = a - guard_s;
+        let close = b + guard_s;
+        if pos.active_idx.is_none() && t_song >= open && t_song <= close {
+            pos.active_idx = Some(i);
+            pos.active_seg = Some((a, b));
+            pos.active_remaining_s = Some(close - t_song);
+        } else if t_song < open {
+            pos.next_idx = Some(i);
+            pos.next_in_s = Some(open - t_song);
+            break;
+        }
+    }
+    pos
+}
+
+/// Loopback this long below --fp-arm-dbfs while aligned counts as paused playback.
+const PAUSE_AFTER_S: f32 = 2.0;
+/// Most recent loopback fingerprinted while unaligned (at least --fp-win-s).
+const FP_LIVE_S: f32 = 7.0;
+/// A pause longer than this means playback stopped: the alignment is dropped.
+const PAUSE_DROP_S: f32 = 60.0;
+/// Unaligned this long (music stopped, the user may have gone with it): --auto-lock takes it
+/// as absence rather than holding the last state.
+const UNALIGNED_ABSENT_S: f32 = 60.0;
+/// Re-alignment corrections at least this large are reported as a seek rather than drift.
+const SEEK_S: f32 = 1.0;
+/// Drift below this is left alone (about one fingerprint frame).
+const DRIFT_MIN_S: f32 = 0.03;
+/// Slack kept around the stored window cut from the loopback (bandpeak_v1 sweeps ±0.5 s).
+const RECHECK_SLACK_S: f32 = 0.5;
+
+/// The song playback is aligned to, and what keeps the position in sync with it.
+struct Alignment {
+    url: String,
+    t0: Instant, // when the song started, as if it had played without interruption
+    clean_since: Instant, // loopback after this is continuous playback at the current t0
+    next_check: Instant,
+    misses: u32, // consecutive re-alignment checks that did not find the song
+    paused: Option<(f32, Instant)>, // song position where the loopback went silent, and when
+    // --smtc: title key of the media session track this follows, and the session's position
+    // minus ours as of the last tick (a jump in it is a seek in the player)
+    media: Option<(String, f32)>,
+}
+
+impl Alignment {
+    fn new(url: &str, t_song: f32, now: Instant, cli: &Config) -> Self {
+        let mut a = Self {
+            url: url.to_string(),
+            t0: now,
+            clean_since: now,
+            next_check: now + Duration::from_secs_f32(cli.realign_s.max(0.0)),
+            misses: 0,
+            paused: None,
+            media: None,
+        };
+        a.set_position(t_song, now);
+        a
+    }
+
+    fn t_song(&self, now: Instant) -> f32 {
+        match self.paused {
+            Some((at, _)) => at,
+            None => (now - self.t0).as_secs_f32(),
+        }
+    }
+
+    fn set_position(&mut self, t_song: f32, now: Instant) {
+        self.t0 = now - Duration::from_secs_f32(t_song.max(0.0));
+    }
+}
+
+/// The song a media session title refers to: one learned from an earlier fingerprint alignment,
+/// else the only song whose URL/tag contains the title (letters and digits, case-insensitive).
+fn media_song<'a>(songs: &'a [SongWindows], key: &str, learned: &'a HashMap<String, String>) -> Option<&'a str> {
+    if let Some(url) = learned.get(key) {
+        return Some(url);
+    }
+    // very short titles ("Intro", "1") would match half the library
+    if key.chars().count() < 4 {
+        return None;
+    }
+    let mut hits = songs.iter().filter(|s| smtc::normalize(&s.url).contains(key));
+    match (hits.next(), hits.next()) {
+        (Some(s), None) => Some(&s.url),
+        _ => None,
+    }
+}
+
+/// Outcome of one re-alignment check.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Recheck {
+    Skipped, // not enough audio, or nothing this song's fingerprints can verify
+    Found { similarity: f32, error_s: f32 }, // true position = assumed + error_s
+    Missed(f32), // best similarity, below --fp-thr
+}
+
+/// Check the assumed position `t_song` (at the end of `recent`, continuous loopback) against
+/// `song`. The stored window that should lie inside `recent` is cut out where the position says
+/// and compared directly, so any type verifies it and the lag is the drift; failing that, the
+/// whole chunk is matched against every fingerprint of the song, which finds a seek.
+fn recheck(song: &SongWindows, recent: &[f32], sr: f32, t_song: f32, cli: &Config) -> Recheck {
+    let recent_s = (recent.len() as f32) / sr;
+    let win_len = (cli.fp_win_s * sr) as usize;
+    let mut best_sim = 0.0f32;
+    let mut tried = false;
+
+    // latest stored window fully inside the chunk
+    let expected = song.fps
+        .iter()
+        .filter(|f| {
+            f.offset_s >= t_song - recent_s + RECHECK_SLACK_S &&
+                f.offset_s + cli.fp_win_s <= t_song - RECHECK_SLACK_S
+        })
+        .filter_map(|f| prescan::FpType::parse(&f.fp_type).map(|t| (f, t)))
+        .max_by(|a, b| a.0.offset_s.total_cmp(&b.0.offset_s));
+    if let Some((stored, fp_type)) = expected {
+        let start = recent.len().saturating_sub(((t_song - stored.offset_s) * sr) as usize);
+        if let Some(live) = recent
+            .get(start..start + win_len)
+            .and_then(|w| prescan::make_fingerprint(w, sr, cli.fp_win_s, fp_type))
+        {
+            tried = true;
+            let (sim, lag_s) = prescan::fp_match(&live, stored);
+            if sim >= cli.fp_thr {
+                return Recheck::Found { similarity: sim, error_s: lag_s };
+            }
+            best_sim = sim;
+        }
+    }
+
+    // anywhere in the song (playback was sought)
+    if recent_s < cli.fp_win_s {
+        return if tried { Recheck::Missed(best_sim) } else { Recheck::Skipped };
+    }
+    let chunk = &recent[recent.len().saturating_sub((7.0 * sr) as usize)..];
+    let chunk_s = (chunk.len() as f32) / sr;
+    let mut found: Option<(f32, f32)> = None; // (similarity, position now)
+    let mut types: Vec<prescan::FpType> = Vec::new();
+    for stored in &song.fps {
+        let Some(t) = prescan::FpType::parse(&stored.fp_type) else {
+            continue;
+        };
+        if !types.contains(&t) {
+            types.push(t);
+        }
+    }
+    for t in types {
+        let Some(live) = prescan::make_fingerprint(chunk, sr, cli.fp_win_s, t) else {
+            continue;
+        };
+        for stored in song.fps.iter().filter(|f| f.fp_type == live.fp_type) {
+            let (sim, lag_s) = prescan::fp_match(&live, stored);
+            if found.is_none_or(|(b, _)| sim > b) {
+                found = Some((sim, stored.offset_s + lag_s - live.offset_s + chunk_s));
+            }
+        }
+    }
+    match found {
+        Some((sim, now_s)) if sim >= cli.fp_thr => Recheck::Found { similarity: sim, error_s: now_s - t_song },
+        Some((sim, _)) => {
+            // bandpeak_v1 only matches near a stored window, so a miss here proves nothing
+            let anywhere = song.fps
+                .iter()
+                .any(|f| prescan::FpType::parse(&f.fp_type) == Some(prescan::FpType::ConstellationV2));
+            if tried || anywhere { Recheck::Missed(best_sim.max(sim)) } else { Recheck::Skipped }
+        }
+        None => if tried { Recheck::Missed(best_sim) } else { Recheck::Skipped },
+    }
+}
+
+/// A song is flagged for re-scan after this many consecutive poor plays.
+const RESCAN_AFTER_BAD_PLAYS: u32 = 2;
+/// Plays with fewer in-window ticks than this are too short to judge.
+const MIN_JUDGED_WINDOW_TICKS: u32 = 8;
+
+/// Live statistics for one aligned play of a song.
+#[derive(Clone, Debug, Default)]
+struct PlayStats {
+    margin: f32, // fingerprint top1-top2 margin at alignment
+    window_ticks: u32, // ticks analysed inside windows
+    est_ticks: u32, // ticks where the correlation produced an echo estimate
+    strength_sum: f32, // sum of echo prominence over est_ticks
+    ref_db_sum: f32, // sum of loopback dBFS over window_ticks
+}
+
+impl PlayStats {
+    /// Why this play performed poorly (None when it looked healthy).
+    fn diagnose(&self, url: &str, cli: &Config) -> Option<String> {
+        if self.window_ticks < MIN_JUDGED_WINDOW_TICKS {
+            return None;
+        }
+        let ref_db = self.ref_db_sum / (self.window_ticks as f32);
+        let est_frac = (self.est_ticks as f32) / (self.window_ticks as f32);
+        let strength = if self.est_ticks > 0 {
+            self.strength_sum / (self.est_ticks as f32)
+        } else {
+            0.0
+        };
+
+        if ref_db < cli.fp_arm_dbfs {
+            Some(
+                format!(
+                    "segments too quiet for '{}' (avg loopback {:.0} dBFS inside windows); re-scan with a higher --min-percentile",
+                    url,
+                    ref_db
+                )
+            )
+        } else if self.margin < 2.0 * cli.fp_margin {
+            Some(
+                format!(
+                    "re-scan '{}' at 48 kHz (fingerprint margin {:.2} is barely above --fp-margin {:.2})",
+                    url,
+                    self.margin,
+                    cli.fp_margin
+                )
+            )
+        } else if est_frac < 0.3 || strength < 0.5 * cli.strength_thr {
+            Some(
+                format!(
+                    "windows of '{}' rarely yield a usable echo ({:.0}% of ticks, avg strength {:.2}); re-scan it",
+                    url,
+                    est_frac * 100.0,
+                    strength
+                )
+            )
+        } else {
+            None
+        }
+    }
+}
+
+/// Per-song history of poor plays; yields a recommendation once a song consistently underperforms.
+#[derive(Default)]
+struct SongHealth {
+    bad_plays: HashMap<String, u32>,
+}
+
+impl SongHealth {
+    fn finish_play(&mut self, url: &str, play: &PlayStats, cli: &Config) -> Option<String> {
+        if play.window_ticks < MIN_JUDGED_WINDOW_TICKS {
+            return None;
+        }
+        let count = self.bad_plays.entry(url.to_string()).or_insert(0);
+        match play.diagnose(url, cli) {
+            Some(reason) => {
+                *count += 1;
+                if *count >= RESCAN_AFTER_BAD_PLAYS {
+                    *count = 0;
+                    Some(reason)
+                } else {
+                    None
+                }
+            }
+            None => {
+                *count = 0;
+                None
+            }
+        }
+    }
+}
+
+/// --resume-grace-s: the last window's summary with the state and alignment as of now.
+fn save_resume(
+    resume: &mut Option<Resume>,
+    held: &mut Option<Snapshot>,
+    state: PresenceState,
+    aligned: Option<&Alignment>,
+    now: Instant,
+    force: bool
+) {
+    if let (Some(r), Some(snap)) = (resume.as_mut(), held.as_mut()) {
+        snap.state = state;
+        snap.aligned = aligned.map(|a| (a.url.clone(), a.t_song(now)));
+        r.save(snap, force);
+    }
+}
+
+/// Status document shared by status.json and the JSONL events.
+fn gated_status(
+    event: &str,
+    present: bool,
+    aligned: Option<(&str, f32)>,
+    pos: &GatePos
+) -> JsonObj {
+    let obj = JsonObj::new()
+        .str("ts", &chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string())
+        .str("mode", "gated")
+        .str("event", event)
+        .bool("present", present);
+    match aligned {
+        Some((url, t_song)) =>
+            obj
+                .str(
+                    "state",
+                    if pos.paused {
+                        "paused"
+                    } else if pos.active_idx.is_some() {
+                        "active"
+                    } else {
+                        "gated"
+                    }
+                )
+                .str("url", url)
+                .num("t_song", t_song as f64)
+                .opt_int("segment_index", pos.active_idx.map(|i| i as i64))
+                .opt_num("segment_start_s", pos.active_seg.map(|s| s.0 as f64))
+                .opt_num("segment_end_s", pos.active_seg.map(|s| s.1 as f64))
+                .opt_num("active_remaining_s", pos.active_remaining_s.map(|v| v as f64))
+                .opt_int("next_segment_index", pos.next_idx.map(|i| i as i64))
+                .opt_num("seconds_to_next_window", pos.next_in_s.map(|v| v as f64)),
+        None => obj.str("state", "waiting"),
+    }
+}
+
 /// Gated mode:
 /// 1) align playback to a song via 5s fingerprint,
 /// 2) run presence only inside that song's exported windows (+/- guard).
 pub fn run_gated(cli: &Config, logger: Arc<Logger>) -> Result<()> {
-    logger.info(
-        "sonar-presence-gated starting… will align via 5s fingerprint, then run presence only inside SongScan windows"
-    )?;
+    let (mic, reference) = audio::sources_from_config(cli, cli.tick_ms.min(50))?;
+    run_gated_with(cli, logger, mic, reference)
+}
 
-    // load SongScan.csv (with fingerprint columns)
-    let csv_scan_path = Path::new(&cli.scansong_path);
-    if !csv_scan_path.exists() {
-        anyhow::bail!("SongScan.csv not found at {}", csv_scan_path.display());
+/// Gated mode on arbitrary capture sources.
+pub fn run_gated_with(
+    cli: &Config,
+    logger: Arc<Logger>,
+    mic: Box<dyn AudioSource>,
+    reference: Box<dyn AudioSource>
+) -> Result<()> {
+    if cli.detector == DetectorKind::Onnx {
+        anyhow::bail!("--detector onnx works in presence, play and replay mode; gated mode votes by the thresholds");
     }
-    let songs = parse_scansong(csv_scan_path, &logger)?;
-    if songs.is_empty() {
-        anyhow::bail!("No songs with fingerprints found in {}", csv_scan_path.display());
+    if !cli.event_script.trim().is_empty() || !cli.event_rules.is_empty() {
+        anyhow::bail!("--event-script and --event-rules work in presence and play mode");
     }
-    logger.info(&format!("Loaded {} song(s) with fingerprints.", songs.len()))?;
+    logger.info(
+        "sonar-presence-gated starting… will align via 5s fingerprint, then run presence only inside SongScan windows"
+    )?;
 
-    // ctrl+c to quit
-    let quit = Arc::new(AtomicBool::new(false));
-    {
-        let q = quit.clone();
-        let _ = ctrlc::set_handler(move || {
-            q.store(true, Ordering::SeqCst);
-        });
+    let mut song_db = load_db(cli, &logger)?;
+    if song_db.songs.is_empty() {
+        anyhow::bail!("No songs with fingerprints found in {}", db_source(cli));
     }
-
-    // === devices: mic + loopback ===
-    let host = cpal::default_host();
-    let mic_device = host
-        .default_input_device()
-        .ok_or_else(|| anyhow::anyhow!("No default input device (microphone) found"))?;
-    let mut mic_config = mic_device.default_input_config()?.config();
-    if let Some(sr) = maybe_rate_supported(&mic_device, 48_000) {
-        mic_config.sample_rate.0 = sr;
+    // how well each window detected on earlier runs; --window-min-quality drops the poor ones
+    let quality_path = WindowQuality::path_for(&cli.scansong_path);
+    let mut quality = WindowQuality::load(&quality_path).unwrap_or_else(|e| {
+        let _ = logger.warn(&format!("{:#}; starting window scores afresh", e));
+        WindowQuality::new(&quality_path)
+    });
+    if cli.window_min_quality > 0.0 {
+        for (url, a, b, score) in quality.prune(&mut song_db.songs, cli.window_min_quality) {
+            logger.info(
+                &format!(
+                    "Dropping window {:.1}-{:.1}s of '{}': quality {:.2} below --window-min-quality {:.2}",
+                    a,
+                    b,
+                    url,
+                    score,
+                    cli.window_min_quality
+                )
+            )?;
+        }
     }
-    let sr_mic = mic_config.sample_rate.0 as f32;
-
-    logger.info(&format!("Mic device: {}", mic_device.name().unwrap_or_default()))?;
+    let songs = &song_db.songs;
+    // the live fingerprint is taken once per type present in the file
+    let fp_types = song_db.fp_types();
     logger.info(
         &format!(
-            "Mic: sample rate {} Hz, channels {}",
-            mic_config.sample_rate.0,
-            mic_config.channels
+            "Loaded {} song(s) with {} fingerprint(s) ({}) from {}.",
+            songs.len(),
+            song_db.fingerprint_count(),
+            fp_types
+                .iter()
+                .map(|t| t.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            db_source(cli)
         )
     )?;
+    let schedules = pingsched::load_all(&cli.ping_schedules, &logger)?;
 
-    let shared_mic = SharedBuf {
-        buf: Arc::new(Mutex::new(Vec::with_capacity((sr_mic as usize) * 10))),
-        sr: Arc::new(Mutex::new(sr_mic)),
-    };
+    let mut engine = PresenceEngine::setup(cli, "gated", &cli.log_path, mic, reference, logger.clone())?;
 
-    let (tx_mic, rx_mic) = bounded::<Vec<f32>>(8);
-    let mic_channels = mic_config.channels.max(1) as usize;
+    // machine-readable state for the web GUI
+    let status_path = output::sibling_path(&cli.log_path, "status.json");
+    let jsonl_path = output::sibling_path(&cli.log_path, "Detection.jsonl");
 
-    let mic_stream = build_input_stream(
-        &mic_device,
-        &mic_config,
-        mic_channels,
-        tx_mic,
-        logger.clone()
-    )?;
-    mic_stream.play()?;
-
-    {
-        let shared_clone = shared_mic.clone();
-        thread::spawn(move || audio_sink_thread(rx_mic, shared_clone));
-    }
-
-    // loopback at mic SR
-    let sr_target = sr_mic as u32;
-    #[cfg(target_os = "windows")]
-    let _probe_stream = if ENABLE_PROBE_TONE { start_probe(sr_target).ok() } else { None };
-
-    let shared_ref = SharedBuf {
-        buf: Arc::new(Mutex::new(Vec::with_capacity((sr_target as usize) * 20))),
-        sr: Arc::new(Mutex::new(sr_mic)),
-    };
-    let rx_ref = wasapi_loopback::start(sr_target, logger.clone(), cli.tick_ms.min(50))?;
-    {
-        let shared_ref_clone = shared_ref.clone();
-        thread::spawn(move || audio_sink_thread(rx_ref, shared_ref_clone));
-    }
-
-    // prepare Detection.csv beside the normal log
-    let csv_path_det = {
-        let p = Path::new(&cli.log_path);
-        let dir = p.parent().ok_or_else(|| anyhow::anyhow!("Log path has no parent"))?;
-        dir.join("Detection.csv")
-    };
-    let mut csv_file = OpenOptions::new().create(true).append(true).open(&csv_path_det)?;
-    if csv_file.metadata()?.len() == 0 {
-        writeln!(csv_file, "timestamp,present,avg_distance_m,avg_strength,agree_pct")?;
-        csv_file.flush()?;
-    }
-
-    // presence analysis constants (same as presence mode)
-    let sr_used = *shared_mic.sr.lock().unwrap();
-    let c = 343.0_f32;
-    let echo_max = (((2.0 * cli.front_max_m) / c) * sr_used).ceil() as usize;
-    let base_max = (
-        ((sonar_presence::MAX_PIPELINE_DELAY_MS as f32) / 1000.0) *
-        sr_used
-    ).ceil() as usize;
-    let analysis_len = (base_max + echo_max + 1024).next_power_of_two().max(4096);
+    let sr_used = engine.sr;
+    let analysis_len = engine.analysis_len;
 
-    logger.info(
-        &format!(
-            "Analysis window: {} samples (~{:.0} ms)",
-            analysis_len,
-            ((analysis_len as f32) / sr_used) * 1000.0
-        )
-    )?;
+    let mut detector = strategy::detector(cli);
+    let mut policy = strategy::policy(cli, cli.tick_ms);
+    let mut activity = sonar_presence::Activity::new(cli);
+    let calibration = Calibration::from_config(cli, &logger)?;
 
-    let mut agg = sonar_presence::Aggregator::new(cli.window_sec, cli.tick_ms, cli.agg_frac);
-    let mut smooth_present = false;
-    let mut last_flip = Instant::now() - Duration::from_millis(cli.min_dwell_ms);
+    let mut aligned: Option<Alignment> = None;
 
-    // current alignment: (url, t0 when song started, t0 offset_s)
-    let mut aligned: Option<(String, Instant, f32)> = None;
+    // --smtc: titles learned from fingerprint alignments, and one whose song fingerprints refuted
+    let media = MediaSession::start(cli, logger.clone());
+    let mut media_titles: HashMap<String, String> = HashMap::new();
+    let mut media_rejected: Option<String> = None;
+
+    // live quality of the current play, judged when the alignment ends
+    let mut play = PlayStats::default();
+    let mut health = SongHealth::default();
+
+    // --resume-grace-s: a restart carries on from the saved state, the song where it would be by now
+    let mut resume = Resume::from_config(cli, "gated", logger.clone());
+    if let Some(snap) = resume.as_ref().and_then(Resume::load) {
+        policy.restore(snap.state.present(), snap.agree, snap.vote());
+        activity.restore(snap.state, engine.now());
+        if let Some((song, t_song)) = snap.aligned.and_then(|(url, t)| Some((songs.iter().find(|s| s.url == url)?, t))) {
+            aligned = Some(Alignment::new(&song.url, t_song, engine.now(), cli));
+            let pos = gate_position(&song.segs, t_song, cli.guard_s);
+            let ev = gated_status("aligned", policy.present(), Some((&song.url, t_song)), &pos).str("source", "resume").finish();
+            let _ = output::append_jsonl(&jsonl_path, &ev);
+        }
+    }
+    let mut held: Option<Snapshot> = None; // the last full window, saved as the run goes
 
     logger.info(
         &format!(
@@ -300,80 +692,161 @@ pub fn run_gated(cli: &Config, logger: Arc<Logger>) -> Result<()> {
     )?;
 
     // main loop
-    let mut next = Instant::now();
-    while !quit.load(Ordering::SeqCst) {
-        next += Duration::from_millis(cli.tick_ms);
+    // mic/loopback clock drift, followed through the direct path across songs
+    let mut drift = sonar_presence::DriftTracker::new(cli.drift_window_s);
+    // the loopback tail for fingerprints, refilled in place
+    let mut loop_recent = Vec::new();
+    let mut unaligned_since: Option<Instant> = None;
+
+    while engine.running() {
+        // a recording (--ref-wav) ends the run when it has been played out
+        if engine.reference.finished() {
+            logger.info(&format!("{} finished", engine.reference.describe()))?;
+            break;
+        }
+        let streams = engine.begin_tick(Duration::from_millis(cli.tick_ms), policy.present());
+        // streams closed (--active-hours, --pause-on-mic-busy) or reopened after sleep: the song
+        // has moved on or stopped meanwhile
+        if streams.closed.is_some() || streams.woke.is_some() {
+            if let Some(a) = aligned.take() {
+                logger.info(&format!("dropped alignment to '{}'", a.url))?;
+            }
+        }
+        if let Some(reason) = streams.reopened {
+            // whatever played meanwhile: align again, with an empty window
+            policy.clear();
+            drift = sonar_presence::DriftTracker::new(cli.drift_window_s);
+            detector.restart();
+            let ev = gated_status("resumed", policy.present(), None, &GatePos::default()).str("reason", reason).finish();
+            let _ = output::append_jsonl(&jsonl_path, &ev);
+        }
+        match streams.closed {
+            Some("active_hours") => {
+                let _ = output::append_jsonl(&jsonl_path, &hours::paused_status("gated", policy.present()));
+            }
+            Some(_) => {
+                let _ = output::append_jsonl(&jsonl_path, &micbusy::suspended_status("gated", policy.present()));
+            }
+            None => {}
+        }
+        if let Some(st) = streams.idle {
+            let _ = output::write_status(&status_path, &st);
+            engine.control.set_status(st);
+            engine.idle(policy.present());
+            continue;
+        }
+        if streams.woke.is_some() {
+            policy.clear();
+            drift = sonar_presence::DriftTracker::new(cli.drift_window_s);
+        }
+        if !streams.restarted.is_empty() {
+            // a reopened device comes with its own delay
+            detector.restart();
+        }
+
+        if engine.follow_control(policy.as_mut()) {
+            // re-run fingerprint alignment and refill the agreement window
+            policy.clear();
+            if let Some(a) = aligned.take() {
+                logger.info(&format!("recalibrate: dropped alignment to '{}'", a.url))?;
+            }
+        }
+        if engine.paused(policy.present()) {
+            continue;
+        }
+        // the tick's time: the wall clock, or a recording's (--replay-speed max)
+        let now = engine.now();
+        let shared_ref = &engine.shared_ref;
+
+        // Step 0: the media session names a known song; align to its position directly.
+        let playback: Option<Playback> = media.as_ref().and_then(|m| m.current());
+        if let Some(p) = &playback {
+            let key = p.title_key();
+            if media_rejected.as_ref().is_some_and(|k| *k != key) {
+                media_rejected = None;
+            }
+            let song = media_song(songs, &key, &media_titles)
+                .filter(|_| aligned.is_none() && p.playing && media_rejected.is_none())
+                .and_then(|url| songs.iter().find(|s| s.url == url));
+            if let Some(song) = song {
+                let t_song = p.position_at(now);
+                let mut a = Alignment::new(&song.url, t_song, now, cli);
+                a.media = Some((key, 0.0));
+                aligned = Some(a);
+                play = PlayStats { margin: 1.0, ..Default::default() };
+                let pos = gate_position(&song.segs, t_song, cli.guard_s);
+                let ev = gated_status("aligned", policy.present(), Some((&song.url, t_song)), &pos)
+                    .str("source", "smtc")
+                    .finish();
+                let _ = output::append_jsonl(&jsonl_path, &ev);
+                logger.info(
+                    &format!(
+                        "Aligned to '{}' via media session ('{}' by '{}' in {}) at {:.1}s into the song.",
+                        song.url,
+                        p.title,
+                        p.artist,
+                        p.app,
+                        t_song
+                    )
+                )?;
+            }
+        }
 
         // Step 1: if not aligned, try to match live 5s fingerprint.
         if aligned.is_none() {
-            let (loop_recent, sr_loop) = {
-                let b = shared_ref.buf.lock().unwrap();
-                let sr = *shared_ref.sr.lock().unwrap();
-                (b.clone(), sr)
-            };
+            // up to the last ~7s
+            let sr_loop = shared_ref.sr;
+            shared_ref.tail_into((FP_LIVE_S.max(cli.fp_win_s) * sr_loop) as usize, &mut loop_recent);
 
             let db = rms_dbfs(&loop_recent);
             if
                 db > cli.fp_arm_dbfs &&
                 (loop_recent.len() as f32) >= cli.fp_win_s * sr_loop + 1024.0
             {
-                // take up to last ~7s
-                let need_secs = (7.0f32)
-                    .min((loop_recent.len() as f32) / sr_loop)
-                    .max(cli.fp_win_s);
-                let need = (need_secs * sr_loop) as usize;
-                let start = loop_recent.len().saturating_sub(need);
-                let live_chunk = &loop_recent[start..];
-
-                if let Some(live_fp) = prescan::make_fingerprint(live_chunk, sr_loop, cli.fp_win_s) {
+                let live_chunk = &loop_recent[..];
+
+                let live_fps: Vec<prescan::Fingerprint> = fp_types
+                    .iter()
+                    .filter_map(|&t| prescan::make_fingerprint(live_chunk, sr_loop, cli.fp_win_s, t))
+                    .collect();
+                if !live_fps.is_empty() {
                     // compare against all stored songs
-                    let mut best: (String, f32) = (String::new(), 0.0);
-                    let mut second = 0.0f32;
-
-                    for s in &songs {
-                        let ref_fp = prescan::Fingerprint {
-                            fp_type: s.fp.fp_type.clone(),
-                            bands: s.fp.bands,
-                            hop_s: s.fp.hop_s,
-                            offset_s: s.fp.offset_s,
-                            bins: s.fp.bins.clone(),
-                        };
-                        let sim = prescan::fp_similarity(&live_fp, &ref_fp);
-                        if sim > best.1 {
-                            second = best.1;
-                            best = (s.url.clone(), sim);
-                        } else if sim > second {
-                            second = sim;
-                        }
-                    }
+                    let m = best_match(&song_db, &live_fps);
 
-                    let top = best.1;
-                    let margin = top - second;
+                    let top = m.similarity;
+                    let margin = top - m.second;
                     logger.info(
                         &format!(
                             "Fingerprint match: top={:.2} margin={:.2} url={}",
                             top,
                             margin,
-                            if best.0.is_empty() {
+                            if m.url.is_empty() {
                                 "<none>"
                             } else {
-                                &best.0
+                                &m.url
                             }
                         )
                     )?;
 
-                    if !best.0.is_empty() && top >= cli.fp_thr && margin >= cli.fp_margin {
-                        let url = best.0.clone();
+                    if !m.url.is_empty() && top >= cli.fp_thr && margin >= cli.fp_margin {
+                        let url = m.url.clone();
                         let song = songs
                             .iter()
                             .find(|s| s.url == url)
                             .unwrap();
-                        let t0_offset = song.fp.offset_s;
-                        let t0 = Instant::now() - Duration::from_secs_f32(t0_offset);
-                        aligned = Some((url.clone(), t0, t0_offset));
+                        // the chunk ends now
+                        let t0_offset = (m.song_s + (live_chunk.len() as f32) / sr_loop).max(0.0);
+                        aligned = Some(Alignment::new(&url, t0_offset, now, cli));
+                        play = PlayStats { margin, ..Default::default() };
+                        let pos = gate_position(&song.segs, t0_offset, cli.guard_s);
+                        let ev = gated_status("aligned", policy.present(), Some((&url, t0_offset)), &pos)
+                            .str("source", "fingerprint")
+                            .num("similarity", top as f64)
+                            .finish();
+                        let _ = output::append_jsonl(&jsonl_path, &ev);
                         logger.info(
                             &format!(
-                                "Aligned to '{}' (similarity {:.2}). t0 offset {:.3}s.",
+                                "Aligned to '{}' (similarity {:.2}) at {:.1}s into the song.",
                                 url,
                                 top,
                                 t0_offset
@@ -385,130 +858,801 @@ pub fn run_gated(cli: &Config, logger: Arc<Logger>) -> Result<()> {
                 }
             }
 
-            // pacing
-            let now = Instant::now();
-            if next > now {
-                thread::sleep(next - now);
-            } else {
-                next = now;
-            }
+            let st = gated_status("tick", policy.present(), None, &GatePos::default()).finish();
+            let _ = output::write_status(&status_path, &st);
+            engine.control.set_status(st);
+            save_resume(&mut resume, &mut held, activity.state(), None, now, false);
+
+            engine.record(&TickMeta { present: policy.present(), state: activity.state(), ..TickMeta::default() });
+            let since = *unaligned_since.get_or_insert(now);
+            engine.end_tick(Some(policy.present() && (now - since).as_secs_f32() < UNALIGNED_ABSENT_S));
             continue;
         }
+        unaligned_since = None;
 
-        // Step 2: aligned — gate presence to that song's windows.
-        let (active_url, t0, _t0_off) = aligned.clone().unwrap();
+        // Step 2: aligned — keep the position in sync, gate presence to that song's windows.
+        let a = aligned.as_mut().unwrap();
+        let active_url = a.url.clone();
         let song = songs
             .iter()
             .find(|s| s.url == active_url)
             .unwrap();
+        // set when the alignment has to go: the reason for the unaligned event, and the message to log
+        let mut unalign: Option<(&str, String)> = None;
 
-        let t_song = (Instant::now() - t0).as_secs_f32();
-
-        let mut inside = false;
-        for &(a, b) in &song.segs {
-            if t_song >= a - cli.guard_s && t_song <= b + cli.guard_s {
-                inside = true;
-                break;
+        // --smtc: follow the media session while it plays this song, let go when it moves on
+        if let Some(p) = &playback {
+            let key = p.title_key();
+            match a.media.as_ref().map(|(k, _)| k.clone()) {
+                Some(followed) if followed != key => {
+                    unalign = Some((
+                        "media",
+                        format!("Media session moved on to '{}'; clearing alignment to '{}'…", p.title, active_url),
+                    ));
+                }
+                Some(_) => {}
+                // fingerprint-aligned: adopt the session if its title is this song or unknown
+                None if !key.is_empty() && p.playing && a.paused.is_none() && a.misses == 0 => {
+                    match media_song(songs, &key, &media_titles).map(str::to_string) {
+                        Some(url) if url != active_url => {}
+                        found => {
+                            if found.is_none() {
+                                logger.debug(&format!("Media session title '{}' is '{}'", p.title, active_url))?;
+                                media_titles.insert(key.clone(), active_url.clone());
+                            }
+                            a.media = Some((key, p.position_at(now) - a.t_song(now)));
+                        }
+                    }
+                }
+                None => {}
             }
+        } else if a.media.take().is_some() {
+            logger.debug("Media session gone; following the loopback")?;
         }
 
-        if inside {
-            let mic_frame = {
-                let b = shared_mic.buf.lock().unwrap();
-                if b.len() < analysis_len {
-                    Vec::new()
-                } else {
-                    b[b.len() - analysis_len..].to_vec()
+        if cli.realign_s > 0.0 || a.media.is_some() {
+            let sr_loop = shared_ref.sr;
+
+            // pause / resume: the media session's state while following it, else the loopback
+            // going silent and coming back
+            let media_pos = playback
+                .as_ref()
+                .filter(|_| a.media.is_some())
+                .map(|p| (p.playing, p.position_at(now)));
+            let pause_len = (PAUSE_AFTER_S * sr_loop) as usize;
+            let (stopped, playing) = match media_pos {
+                Some((playing, _)) => (!playing, playing),
+                None => {
+                    let tick_len = (((cli.tick_ms as f32) / 1000.0) * sr_loop) as usize;
+                    shared_ref.tail_into(pause_len.max(tick_len), &mut loop_recent);
+                    let tail_db = |len: usize| rms_dbfs(&loop_recent[loop_recent.len().saturating_sub(len)..]);
+                    (
+                        loop_recent.len() >= pause_len && tail_db(pause_len) < cli.fp_arm_dbfs,
+                        tail_db(tick_len) >= cli.fp_arm_dbfs,
+                    )
                 }
             };
-            let ref_frame = {
-                let b = shared_ref.buf.lock().unwrap();
-                if b.len() < analysis_len {
-                    Vec::new()
-                } else {
-                    b[b.len() - analysis_len..].to_vec()
+            match a.paused {
+                None if stopped => {
+                    let at = match media_pos {
+                        Some((_, p)) => p,
+                        None => (a.t_song(now) - PAUSE_AFTER_S).max(0.0),
+                    };
+                    a.paused = Some((at, now));
+                    logger.info(&format!("Playback paused at {:.1}s into '{}'", at, active_url))?;
+                    let pos = GatePos { paused: true, ..GatePos::default() };
+                    let ev = gated_status("paused", policy.present(), Some((&active_url, at)), &pos).finish();
+                    let _ = output::append_jsonl(&jsonl_path, &ev);
                 }
-            };
+                Some((at, since)) if playing => {
+                    let at = media_pos.map_or(at, |(_, p)| p);
+                    a.paused = None;
+                    a.set_position(at, now);
+                    a.clean_since = now;
+                    // verify as soon as a fingerprint's worth of audio is back
+                    a.next_check = now + Duration::from_secs_f32(cli.fp_win_s + 1.0);
+                    logger.info(
+                        &format!(
+                            "Playback resumed at {:.1}s into '{}' after {:.1}s",
+                            at,
+                            active_url,
+                            (now - since).as_secs_f32()
+                        )
+                    )?;
+                    let pos = gate_position(&song.segs, at, cli.guard_s);
+                    let ev = gated_status("resumed", policy.present(), Some((&active_url, at)), &pos).finish();
+                    let _ = output::append_jsonl(&jsonl_path, &ev);
+                }
+                Some((_, since)) if now - since > Duration::from_secs_f32(PAUSE_DROP_S) => {
+                    unalign = Some((
+                        "paused",
+                        format!("Playback paused for over {:.0}s; clearing alignment and waiting for playback…", PAUSE_DROP_S),
+                    ));
+                }
+                _ => {}
+            }
 
-            if mic_frame.len() == analysis_len && ref_frame.len() == analysis_len {
-                if
-                    let Some((d, s)) = sonar_presence::estimate_from_ref(
-                        &ref_frame,
-                        &mic_frame,
-                        sr_used,
-                        cli,
-                        Some(&logger)
-                    )
-                {
-                    let present_instant = d <= cli.dist_max_m && s >= cli.strength_thr;
-                    let vote = if present_instant { Some((d, s)) } else { None };
+            // the player seeked: the session's position jumped against ours
+            if let (Some((_, offset)), Some((true, p)), None) = (&a.media, media_pos, a.paused) {
+                let t_song = a.t_song(now);
+                let to = p - offset;
+                if (to - t_song).abs() >= SEEK_S {
+                    a.set_position(to, now);
+                    a.clean_since = now;
+                    logger.info(&format!("Media session seek in '{}': {:.1}s -> {:.1}s", active_url, t_song, to))?;
+                    let pos = gate_position(&song.segs, to, cli.guard_s);
+                    let ev = gated_status("seek", policy.present(), Some((&active_url, to)), &pos)
+                        .num("from_s", t_song as f64)
+                        .str("source", "smtc")
+                        .finish();
+                    let _ = output::append_jsonl(&jsonl_path, &ev);
+                }
+            }
 
-                    if let Some((_present_raw, avg_d, avg_s, agree)) = agg.push(vote) {
-                        let nowi = Instant::now();
-                        let want_present = if smooth_present {
-                            agree >= cli.exit_frac
+            // periodic re-fingerprint against the aligned song
+            if cli.realign_s > 0.0 && a.paused.is_none() && now >= a.next_check {
+                a.next_check = now + Duration::from_secs_f32(cli.realign_s);
+                shared_ref.tail_into(((now - a.clean_since).as_secs_f32() * sr_loop) as usize, &mut loop_recent);
+                let recent = &loop_recent[..];
+                let t_song = a.t_song(now);
+                let check = if rms_dbfs(recent) < cli.fp_arm_dbfs {
+                    Recheck::Skipped
+                } else {
+                    recheck(song, recent, sr_loop, t_song, cli)
+                };
+                match check {
+                    Recheck::Skipped => {
+                        a.next_check = now + Duration::from_secs(1);
+                    }
+                    Recheck::Found { similarity, error_s } => {
+                        a.misses = 0;
+                        if error_s.abs() >= SEEK_S {
+                            a.set_position(t_song + error_s, now);
+                            a.clean_since = now;
+                            logger.info(
+                                &format!(
+                                    "Seek detected in '{}': {:.1}s -> {:.1}s (similarity {:.2})",
+                                    active_url,
+                                    t_song,
+                                    t_song + error_s,
+                                    similarity
+                                )
+                            )?;
+                            let pos = gate_position(&song.segs, t_song + error_s, cli.guard_s);
+                            let ev = gated_status("seek", policy.present(), Some((&active_url, t_song + error_s)), &pos)
+                                .num("from_s", t_song as f64)
+                                .str("source", "fingerprint")
+                                .num("similarity", similarity as f64)
+                                .finish();
+                            let _ = output::append_jsonl(&jsonl_path, &ev);
+                        } else if error_s.abs() >= DRIFT_MIN_S {
+                            a.set_position(t_song + error_s, now);
+                            logger.debug(
+                                &format!(
+                                    "Drift correction for '{}': {:+.3}s at {:.1}s (similarity {:.2})",
+                                    active_url,
+                                    error_s,
+                                    t_song,
+                                    similarity
+                                )
+                            )?;
+                        }
+                    }
+                    Recheck::Missed(similarity) => {
+                        a.misses += 1;
+                        logger.info(
+                            &format!(
+                                "Re-alignment check: '{}' not found at {:.1}s (similarity {:.2}), {}/{}",
+                                active_url,
+                                t_song,
+                                similarity,
+                                a.misses,
+                                cli.realign_misses
+                            )
+                        )?;
+                        if a.misses >= cli.realign_misses {
+                            unalign = Some((
+                                "lost",
+                                format!("Lost alignment to '{}' (track changed?); clearing it and re-acquiring…", active_url),
+                            ));
+                            // the session's title misled us: fingerprints only until it changes
+                            if let Some((key, _)) = a.media.take() {
+                                media_titles.remove(&key);
+                                media_rejected = Some(key);
+                            }
                         } else {
-                            agree >= cli.enter_frac
-                        };
+                            // confirm quickly rather than gating on a stale position
+                            a.next_check = now + Duration::from_secs_f32(cli.realign_s.min(2.0));
+                        }
+                    }
+                }
+            }
 
-                        if
-                            want_present != smooth_present &&
-                            nowi.duration_since(last_flip) >=
-                                Duration::from_millis(cli.min_dwell_ms)
-                        {
-                            smooth_present = want_present;
-                            last_flip = nowi;
+            // re-base on the session's position so corrections made above do not read as a seek
+            if let Some((_, p)) = media_pos {
+                let t_song = a.t_song(now);
+                if let Some((_, offset)) = a.media.as_mut() {
+                    *offset = p - t_song;
+                }
+            }
+        }
 
-                            logger.info(
+        let t_song = a.t_song(now);
+
+        let pos = if a.paused.is_some() {
+            GatePos { paused: true, ..GatePos::default() }
+        } else {
+            gate_position(&song.segs, t_song, cli.guard_s)
+        };
+        // an enriched song with --ping-schedule: analyse the frames holding a ping, in its band,
+        // instead of the SongScan windows
+        let schedule: Option<&PingSchedule> = schedules.iter().find(|s| s.matches(&active_url));
+        let frame_s = (analysis_len as f32) / sr_used;
+        let ping = schedule.map(|s| a.paused.is_none() && s.covers(t_song - frame_s, t_song));
+        let inside = ping.unwrap_or(pos.active_idx.is_some());
+        let window = pos.segment().filter(|_| ping.is_none()).map(|(_, a, b)| (a, b));
+        if quality.observe(&active_url, window) {
+            save_quality(&quality, &logger);
+        }
+
+        let mut meta = TickMeta::default();
+        if inside {
+            engine.observe_fill();
+
+            let frames = &mut engine.frames;
+            frames.ref_shift = drift.shift();
+            let pairing = frames.pair(&engine.shared_mic, &engine.shared_ref, &logger);
+            if pairing == Pairing::Ready {
+                let at = frames.positions();
+                let (mic_frame, ref_frame) = (&mut frames.mic[..], &mut frames.reference[..]);
+                play.window_ticks += 1;
+                play.ref_db_sum += rms_dbfs(ref_frame);
+                if let Some((lo, hi)) = cli.corr_band.or(schedule.map(PingSchedule::band)) {
+                    sonar_presence::band_limit(ref_frame, sr_used, lo, hi);
+                    sonar_presence::band_limit(mic_frame, sr_used, lo, hi);
+                } else {
+                    detector.frames_at(at);
+                }
+                let t_corr = Instant::now();
+                let measurement = detector.process_tick(ref_frame, mic_frame, sr_used, &engine.live, Some(&logger));
+                engine.exporter.metrics.observe_correlation(t_corr.elapsed().as_secs_f64());
+                let estimate = measurement.as_ref().map(sonar_presence::Measurement::pair);
+                meta.analysed = true;
+                meta.estimate = estimate;
+                meta.rms = Some((prescan::rms(ref_frame), prescan::rms(mic_frame)));
+                meta.peak_sidelobe = measurement.as_ref().map(|m| m.peak_sidelobe);
+                meta.snr_db = measurement.as_ref().map(|m| m.snr_db);
+                meta.direct_r = measurement.as_ref().map(|m| m.direct_r);
+                meta.direct_lag = measurement.as_ref().map(|m| m.direct_lag);
+                meta.ref_shift = frames.ref_shift;
+                quality.tick(meta.snr_db);
+                if let Some(m) = &measurement {
+                    drift.observe(now, m.direct_lag, sr_used);
+                }
+                meta.drift_ppm = drift.ppm().map(|p| p as f32);
+
+                if let Some((d, s)) = estimate {
+                    play.est_ticks += 1;
+                    play.strength_sum += s;
+                    let present_instant = d <= engine.live.dist_max_m && s >= engine.live.strength_thr;
+                    let vote = if present_instant { Some((d, s)) } else { None };
+                    if present_instant {
+                        engine.exporter.metrics.detections.inc();
+                    }
+                    meta.vote = present_instant;
+
+                    if let Some(Decision { flipped, avg_d, avg_s, agree, iqr_d }) = policy.push(vote, now) {
+                        meta.agree = Some(agree);
+                        let probability = calibration.as_ref().map(|c| c.probability(agree));
+                        engine.exporter.observe_window(policy.present(), avg_d, avg_s, agree);
+                        engine.status.update(policy.present(), avg_d, probability.unwrap_or(agree));
+                        if let Some(state) = activity.update(policy.present(), iqr_d, now) {
+                            logger.event(
                                 &format!(
-                                    "state_change(hysteresis,gated url={}) -> present={}",
+                                    "state_change({},gated url={}) -> present={} state={}",
+                                    policy.name(),
                                     active_url,
-                                    smooth_present
-                                )
+                                    state.present(),
+                                    state.as_str()
+                                ),
+                                &[
+                                    ("present", Field::Bool(state.present())),
+                                    ("state", Field::Str(state.as_str())),
+                                    ("url", Field::Str(&active_url)),
+                                ]
                             )?;
 
-                            let ts = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-                            let _ = writeln!(
-                                csv_file,
-                                "{},{},{:.2},{:.2},{:.0}",
-                                ts,
-                                smooth_present,
+                            let _ = output::write_detection_row(
+                                &mut engine.csv,
+                                state,
                                 avg_d,
                                 avg_s,
-                                agree * 100.0
+                                agree,
+                                iqr_d,
+                                None,
+                                measurement.as_ref().map(sonar_presence::Measurement::quality),
+                                probability,
+                                Some(output::SongPos { url: &active_url, t_song, segment: pos.segment() })
                             );
-                            let _ = csv_file.flush();
+
+                            let ev = gated_status(
+                                "state_change",
+                                policy.present(),
+                                Some((&active_url, t_song)),
+                                &pos
+                            )
+                                .str("presence", state.as_str())
+                                .num("avg_distance_m", avg_d)
+                                .num("avg_strength", avg_s)
+                                .num("agree_pct", (agree * 100.0) as f64)
+                                .opt_num("probability", probability.map(|p| p as f64))
+                                .num("dist_iqr_m", iqr_d)
+                                .opt_num("peak_sidelobe", meta.peak_sidelobe.map(|v| v as f64))
+                                .opt_num("snr_db", meta.snr_db.map(|v| v as f64))
+                                .opt_num("direct_r", meta.direct_r.map(|v| v as f64))
+                                .finish();
+                            let _ = output::append_jsonl(&jsonl_path, &ev);
+                        }
+                        held = Some(Snapshot { agree, avg_d, avg_s, ..Snapshot::default() });
+                        if flipped {
+                            engine.flipped(HookEvent {
+                                present: policy.present(),
+                                state: activity.state(),
+                                distance_m: avg_d,
+                                strength: avg_s,
+                                agree,
+                                probability,
+                            });
                         }
                     }
                 } else {
-                    let _ = agg.push(None);
+                    policy.age();
                 }
-            } else {
-                let _ = agg.push(None);
+            } else if pairing == Pairing::Filling {
+                policy.age();
             }
         } else {
-            // outside windows: decay the aggregator; optionally drop alignment after far past end
-            let _ = agg.push(None);
+            // outside windows: decay the aggregator (between scheduled pings: leave it as it is);
+            // optionally drop alignment after far past end
+            if ping.is_none() {
+                policy.age();
+            }
             if let Some(&(_, last_b)) = song.segs.last() {
-                if t_song > last_b + 60.0 {
-                    logger.info(
-                        "End of windows passed; clearing alignment and waiting for next track…"
-                    )?;
-                    aligned = None;
-                    smooth_present = false;
-                    last_flip = Instant::now() - Duration::from_millis(cli.min_dwell_ms);
+                if t_song > last_b + 60.0 && unalign.is_none() {
+                    unalign = Some(("end", "End of windows passed; clearing alignment and waiting for next track…".to_string()));
                 }
             }
         }
 
-        let now = Instant::now();
-        if next > now {
-            thread::sleep(next - now);
-        } else {
-            next = now;
+        if let Some((reason, msg)) = unalign {
+            logger.info(&msg)?;
+            aligned = None;
+            policy.reset();
+            let ev = gated_status("unaligned", policy.present(), None, &pos)
+                .str("url", &active_url)
+                .str("reason", reason)
+                .finish();
+            let _ = output::append_jsonl(&jsonl_path, &ev);
+            if quality.finish() {
+                save_quality(&quality, &logger);
+            }
+            if let Some(advice) = health.finish_play(&active_url, &play, cli) {
+                logger.warn(&format!("recommendation: {}", advice))?;
+                let ev = gated_status("recommendation", policy.present(), None, &pos)
+                    .str("url", &active_url)
+                    .str("advice", &advice)
+                    .finish();
+                let _ = output::append_jsonl(&jsonl_path, &ev);
+            }
+            let _ = activity.update(false, 0.0, now);
+            engine.hooks.state_changed(HookEvent {
+                present: false,
+                state: PresenceState::Absent,
+                distance_m: f64::INFINITY,
+                strength: 0.0,
+                agree: 0.0,
+                probability: None,
+            });
+        }
+
+        if aligned.is_some() {
+            let st = gated_status("tick", policy.present(), Some((&active_url, t_song)), &pos).finish();
+            let _ = output::write_status(&status_path, &st);
+            engine.control.set_status(st);
+        }
+        save_resume(&mut resume, &mut held, activity.state(), aligned.as_ref(), now, false);
+
+        engine.record(&TickMeta { present: policy.present(), state: activity.state(), ..meta });
+        engine.end_tick(Some(policy.present()));
+    }
+
+    save_resume(&mut resume, &mut held, activity.state(), aligned.as_ref(), engine.now(), true);
+    if quality.finish() {
+        save_quality(&quality, &logger);
+    }
+    engine.shutdown("sonar-presence-gated")
+}
+
+#[cfg(test)]
+mod tests {
+    //! End-to-end gated pipeline without audio devices:
+    //! scan → SongScan.csv → fingerprint alignment → windowed detection → Detection.csv.
+
+    use super::*;
+    use crate::{ output, songscan };
+    use crate::audio::{ MemorySource, StepClock };
+    use crate::scanscore::Heuristic;
+    use crate::simulator::{ Lcg, Room };
+    use std::fs;
+
+    const SR: f32 = 48_000.0;
+
+    /// Synthetic enriched track: a melody that changes note every 250 ms over broadband noise,
+    /// with loud/quiet phrases, plus enrich-style 18.5 kHz pings (0.1 s every 1 s at -35 dB).
+    fn enriched_track(seed: u64, secs: f32) -> Vec<f32> {
+        let mut rng = Lcg(seed);
+        let n = (secs * SR) as usize;
+        let note_len = (0.25 * SR) as usize;
+        let ping_amp = (10.0f32).powf(-35.0 / 20.0);
+        let mut freq = 440.0f32;
+        let mut out = Vec::with_capacity(n);
+        for i in 0..n {
+            if i % note_len == 0 {
+                freq = 200.0 + 4800.0 * (rng.next() * 0.5 + 0.5);
+            }
+            let t = (i as f32) / SR;
+            let phrase = if (t / 8.0).fract() < 0.5 { 1.0 } else { 0.3 };
+            let ping = if t % 1.0 < 0.1 { ping_amp * (2.0 * std::f32::consts::PI * 18_500.0 * t).sin() } else { 0.0 };
+            out.push(
+                phrase * (0.3 * (2.0 * std::f32::consts::PI * freq * t).sin() + 0.2 * rng.next()) + ping
+            );
+        }
+        out
+    }
+
+    fn scan_params(cfg: &Config) -> prescan::ScanParams {
+        prescan::ScanParams {
+            sr: SR,
+            frame_ms: cfg.frame_ms,
+            window_s: cfg.scan_window_s,
+            stride_ms: cfg.stride_ms,
+            hf_split_hz: cfg.hf_split_hz,
+            top_n: cfg.top_n,
+            min_percentile: cfg.min_percentile,
+            nms_radius_s: cfg.nms_radius_s,
+            merge_gap_s: cfg.merge_gap_s,
+            clamp_min_s: cfg.clamp_min_s,
+            clamp_max_s: cfg.clamp_max_s,
+            scorer: Box::new(Heuristic(cfg.scan_weights)),
         }
     }
 
-    logger.info("sonar-presence-gated stopped.")?;
-    Ok(())
+    #[test]
+    fn scan_align_detect_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("sonar-gated-e2e-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cfg = Config {
+            window_sec: 1,
+            tick_ms: 500,
+            min_dwell_ms: 0,
+            ..Config::default()
+        };
+        let params = scan_params(&cfg);
+        let logger = Logger::new(&dir.join("Detection.log").to_string_lossy(), false).unwrap();
+
+        // --- scan two tracks into SongScan.csv
+        let song_a = enriched_track(1, 30.0);
+        let song_b = enriched_track(2, 30.0);
+        // a URL with a comma and quotes must survive the CSV round-trip
+        let url_b = "https://example.test/watch?v=b&list=\"mix,2\"";
+        let scan_path = dir.join("SongScan.csv");
+        let _ = fs::remove_file(&scan_path);
+        let fp_a = prescan::make_fingerprint(&song_a, SR, cfg.fp_win_s, cfg.fp_type).unwrap();
+        {
+            let mut csv = songscan::open_songscan_csv(&scan_path).unwrap();
+            for (tag, track) in [("test://song-a", &song_a), (url_b, &song_b)] {
+                let segs = prescan::analyze(track, &params);
+                assert!(!segs.is_empty(), "{} produced no segments", tag);
+                songscan::write_segment_rows(&mut csv, tag, &segs, &params, &fingerprints(track, &cfg)).unwrap();
+            }
+        }
+
+        // CSV schema is what gated (and the GUI) expect
+        let text = fs::read_to_string(&scan_path).unwrap();
+        assert_eq!(text.lines().next().unwrap(), songscan::SONGSCAN_HEADER);
+
+        // --- fingerprint round-trip through the CSV
+        let songs = parse_scansong(&scan_path, &logger).unwrap();
+        assert_eq!(songs.len(), 2);
+        assert!(songs.iter().any(|s| s.url == url_b), "urls: {:?}", songs.iter().map(|s| &s.url).collect::<Vec<_>>());
+        let stored = songs
+            .iter()
+            .find(|s| s.url == "test://song-a")
+            .unwrap();
+        // lead-in first, then one every fp_every_s
+        assert_eq!(stored.fps.len(), 1 + ((30.0 - cfg.fp_win_s) / cfg.fp_every_s) as usize);
+        assert_eq!(stored.fps[0].fp_type, fp_a.fp_type);
+        assert_eq!(stored.fps[0].bands, fp_a.bands);
+        assert_eq!(stored.fps[0].bins, fp_a.bins);
+        assert!((stored.fps[0].hop_s - fp_a.hop_s).abs() < 1e-4);
+        assert!((stored.fps[1].offset_s - cfg.fp_every_s).abs() < 1e-3);
+        assert!(stored.segs.windows(2).all(|w| w[0].0 <= w[1].0), "segments sorted by start");
+
+        // --- alignment: playback of song A from the start
+        let live = prescan::make_fingerprint(&song_a[..(7.0 * SR) as usize], SR, cfg.fp_win_s, cfg.fp_type).unwrap();
+        let m = best_match(&FpDb::new(songs.clone()), &[live]);
+        assert_eq!(m.url, "test://song-a");
+        assert!(m.similarity >= cfg.fp_thr, "similarity {} below fp_thr", m.similarity);
+        assert!(m.similarity - m.second >= cfg.fp_margin, "margin {} below fp_margin", m.similarity - m.second);
+        assert!(m.song_s.abs() < 0.1, "live chunk placed at {}s", m.song_s);
+
+        // --- the live loop on a recording of song A with a person in front who leaves after
+        // 20 s, recorded for 26 s: it aligns by fingerprint, then detects inside the windows → Detection.csv
+        assert!(stored.segs.iter().any(|&(_, b)| b > cfg.fp_win_s + 2.0), "no window left after the alignment");
+        let person_m = 0.8f32;
+        let (leave, stop) = ((20.0 * SR) as usize, (26.0 * SR) as usize);
+        let with = Room::new(SR).with_person(person_m).render(&song_a[..stop]);
+        let without = Room::new(SR).render(&song_a[..stop]);
+        let mic: Vec<f32> = with[..leave].iter().chain(&without[leave..]).copied().collect();
+        let run = Config {
+            scansong_path: scan_path.to_string_lossy().into_owned(),
+            log_path: dir.join("Detection.log").to_string_lossy().into_owned(),
+            realign_s: 0.0,
+            ..cfg.clone()
+        };
+        let clock = StepClock::new();
+        run_gated_with(
+            &run,
+            Arc::new(logger),
+            Box::new(MemorySource::new("mic", mic, SR as u32).stepped(&clock)),
+            Box::new(MemorySource::new("ref", song_a[..stop].to_vec(), SR as u32).stepped(&clock))
+        ).unwrap();
+
+        let events = fs::read_to_string(dir.join("Detection.jsonl")).unwrap();
+        let aligned = events.lines().find(|l| l.contains("\"event\":\"aligned\"")).expect("no aligned event");
+        assert!(aligned.contains("\"url\":\"test://song-a\"") && aligned.contains("\"source\":\"fingerprint\""), "{}", aligned);
+
+        let rows: Vec<String> = fs
+            ::read_to_string(dir.join("Detection.csv"))
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(rows[0], output::DETECTION_CSV_HEADER);
+        assert_eq!(rows.len(), 3, "expected enter + exit rows, got {:?}", rows);
+        let enter: Vec<&str> = rows[1].split(',').collect();
+        assert_eq!(enter[1], "true");
+        assert!((enter[2].parse::<f32>().unwrap() - person_m).abs() < 0.05, "distance {}", enter[2]);
+        // the entering echo stands clear of its sidelobes over a direct path the mic hears
+        let (psr, direct_r): (f32, f32) = (enter[7].parse().unwrap(), enter[9].parse().unwrap());
+        assert!(psr > 1.0 && direct_r > 0.1, "peak/sidelobe {} direct r {}", psr, direct_r);
+        // the song, the position in it and the window it fell in
+        assert_eq!(enter[12], "test://song-a");
+        let t_song: f32 = enter[13].parse().unwrap();
+        let (win_a, win_b): (f32, f32) = (enter[15].parse().unwrap(), enter[16].parse().unwrap());
+        assert!(win_a <= t_song && t_song <= win_b + cfg.guard_s, "t_song {} outside {}-{}", t_song, win_a, win_b);
+        assert!(stored.segs.iter().any(|&(a, b)| (a - win_a).abs() < 1e-3 && (b - win_b).abs() < 1e-3));
+        let exit: Vec<&str> = rows[2].split(',').collect();
+        assert_eq!(exit[1], "false");
+        let t_exit: f32 = exit[13].parse().unwrap();
+        assert!(t_exit > 20.0 && t_exit < 23.0, "left at {}", t_exit);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn songscan_errors_say_what_is_wrong() {
+        let dir = std::env::temp_dir().join(format!("sonar-gated-errors-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let logger = Logger::new(&dir.join("Detection.log").to_string_lossy(), false).unwrap();
+        let scan_path = dir.join("SongScan.csv");
+
+        fs::write(&scan_path, "").unwrap();
+        assert!(matches!(parse_scansong(&scan_path, &logger), Err(SongScanError::Empty)));
+        fs::write(&scan_path, "url,start_s,end_s,fp_bands\n").unwrap();
+        assert!(matches!(parse_scansong(&scan_path, &logger), Err(SongScanError::MissingColumn("fp_type"))));
+
+        // through anyhow at the mode boundary the cause is still there to match on
+        let cfg = Config { scansong_path: dir.join("missing.csv").to_string_lossy().into_owned(), ..Config::default() };
+        let err = load_db(&cfg, &logger).err().unwrap();
+        assert!(matches!(err.downcast_ref::<SongScanError>(), Some(SongScanError::NotFound(_))), "{}", err);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_database_older_than_songscan_is_rebuilt() {
+        let dir = std::env::temp_dir().join(format!("sonar-gated-stale-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let logger = Logger::new(&dir.join("Detection.log").to_string_lossy(), false).unwrap();
+        let scan_path = dir.join("SongScan.csv");
+        let db_path = dir.join("songs.fpdb");
+        let cfg = Config {
+            scansong_path: scan_path.to_string_lossy().into_owned(),
+            fp_db: db_path.to_string_lossy().into_owned(),
+            ..Config::default()
+        };
+        let row = |url: &str, seed: u64| {
+            let f = &fingerprints(&enriched_track(seed, 8.0), &cfg)[0];
+            format!("{},1.000,4.000,{},{},{:.5},{:.3},{}\n", url, f.fp_type, f.bands, f.hop_s, f.offset_s, songscan::to_hex(&f.bins))
+        };
+        let header = "url,start_s,end_s,fp_type,fp_bands,fp_hop_s,fp_offset_s,fp_bins_hex\n";
+        fs::write(&scan_path, format!("{}{}", header, row("a", 1))).unwrap();
+        assert_eq!(load_db(&cfg, &logger).unwrap().songs.len(), 1);
+        assert!(db_path.exists());
+
+        // a later `scan` without --fp-db only adds to SongScan.csv
+        fs::write(&scan_path, format!("{}{}{}", header, row("a", 1), row("b", 2))).unwrap();
+        let later = std::time::SystemTime::now() + Duration::from_secs(10);
+        fs::File::options().write(true).open(&scan_path).unwrap().set_modified(later).unwrap();
+        assert_eq!(load_db(&cfg, &logger).unwrap().songs.len(), 2);
+        assert_eq!(FpDb::load(&db_path).unwrap().songs.len(), 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// One-pole low-pass, then a gain: a crude stand-in for a different EQ/volume setting.
+    fn eq_and_gain(x: &[f32], gain: f32) -> Vec<f32> {
+        let a = 0.6f32;
+        let mut y = 0.0f32;
+        x.iter()
+            .map(|&v| {
+                y = a * y + (1.0 - a) * v;
+                gain * (0.5 * v + y)
+            })
+            .collect()
+    }
+
+    /// Fingerprints as scan/offline store them.
+    fn fingerprints(track: &[f32], cfg: &Config) -> Vec<prescan::Fingerprint> {
+        let mut f = prescan::Fingerprinter::new(SR, cfg.fp_win_s, cfg.fp_type, cfg.fp_every_s);
+        for block in track.chunks(4800) {
+            f.push(block);
+        }
+        f.finish()
+    }
+
+    fn constellation_db(cfg: &Config, tracks: &[(&str, &[f32])]) -> FpDb {
+        FpDb::new(
+            tracks
+                .iter()
+                .map(|&(url, track)| SongWindows {
+                    url: url.to_string(),
+                    segs: vec![(0.0, 1.0)],
+                    fps: fingerprints(track, cfg),
+                })
+                .collect()
+        )
+    }
+
+    #[test]
+    fn constellation_survives_eq_and_offset() {
+        let cfg = Config { fp_type: prescan::FpType::ConstellationV2, fp_every_s: 0.0, ..Config::default() };
+        let song_a = enriched_track(3, 12.0);
+        let song_b = enriched_track(4, 12.0);
+        let db = constellation_db(&cfg, &[("a", &song_a), ("b", &song_b)]);
+        assert_eq!(db.songs[0].fps.len(), 1);
+        assert_eq!(db.songs[0].fps[0].fp_type, "constellation_v2");
+
+        // playback of A, 1.3 s in, through a different EQ at a lower volume
+        let start = (1.3 * SR) as usize;
+        let live_audio = eq_and_gain(&song_a[start..start + (7.0 * SR) as usize], 0.4);
+        let live = prescan::make_fingerprint(&live_audio, SR, cfg.fp_win_s, cfg.fp_type).unwrap();
+        let m = best_match(&db, &[live]);
+        assert_eq!(m.url, "a");
+        assert!(m.similarity >= cfg.fp_thr, "similarity {} below fp_thr", m.similarity);
+        assert!(m.similarity - m.second >= cfg.fp_margin, "margin {} below fp_margin", m.similarity - m.second);
+        assert!((m.song_s - 1.3).abs() < 0.02, "live chunk placed at {}s", m.song_s);
+
+        // the same playback captured at 44.1 kHz against the 48 kHz scan
+        let live_441 = crate::decode::resample_linear_mono(&live_audio, SR as u32, 44_100);
+        let live = prescan::make_fingerprint(&live_441, 44_100.0, cfg.fp_win_s, cfg.fp_type).unwrap();
+        let m = best_match(&db, &[live]);
+        assert_eq!(m.url, "a");
+        // a different rate must not move the peaks: nearly every landmark still agrees
+        assert!(m.similarity >= 0.95, "44.1 kHz similarity {}", m.similarity);
+        assert!((m.song_s - 1.3).abs() < 0.02, "44.1 kHz live chunk placed at {}s", m.song_s);
+
+        // a bandpeak_v1 live fingerprint is never compared with constellation_v2 songs
+        let other = prescan::make_fingerprint(&live_audio, SR, cfg.fp_win_s, prescan::FpType::BandPeakV1).unwrap();
+        assert_eq!(best_match(&db, &[other]).similarity, 0.0);
+    }
+
+    #[test]
+    fn aligns_when_playback_starts_mid_song() {
+        let cfg = Config { fp_type: prescan::FpType::ConstellationV2, ..Config::default() };
+        let song_a = enriched_track(5, 40.0);
+        let song_b = enriched_track(6, 40.0);
+        let db = constellation_db(&cfg, &[("a", &song_a), ("b", &song_b)]);
+
+        for start_s in [13.0f32, 21.7, 31.2] {
+            let start = (start_s * SR) as usize;
+            let chunk = &song_a[start..start + (7.0 * SR) as usize];
+            let live = prescan::make_fingerprint(chunk, SR, cfg.fp_win_s, cfg.fp_type).unwrap();
+            let m = best_match(&db, &[live]);
+            assert_eq!(m.url, "a", "start {}s", start_s);
+            assert!(m.similarity >= cfg.fp_thr, "start {}s: similarity {}", start_s, m.similarity);
+            assert!(m.similarity - m.second >= cfg.fp_margin, "start {}s: margin {}", start_s, m.similarity - m.second);
+            assert!((m.song_s - start_s).abs() < 0.02, "start {}s: placed at {}s", start_s, m.song_s);
+        }
+    }
+
+    #[test]
+    fn recheck_corrects_drift_and_finds_seeks() {
+        let song_a = enriched_track(7, 40.0);
+        let song_b = enriched_track(8, 40.0);
+        // the loopback ring: 10 s of song A ending at `true_s`
+        let ring = |track: &[f32], true_s: f32| {
+            let end = (true_s * SR) as usize;
+            track[end - (10.0 * SR) as usize..end].to_vec()
+        };
+        let song = |cfg: &Config| SongWindows { url: "a".to_string(), segs: vec![(0.0, 1.0)], fps: fingerprints(&song_a, cfg) };
+
+        for fp_type in [prescan::FpType::BandPeakV1, prescan::FpType::ConstellationV2, prescan::FpType::MelPeakV3] {
+            let cfg = Config { fp_type, ..Config::default() };
+            let a = song(&cfg);
+
+            // playback is 0.3 s behind the assumed position
+            match recheck(&a, &ring(&song_a, 23.0), SR, 23.3, &cfg) {
+                Recheck::Found { error_s, .. } => assert!((error_s + 0.3).abs() < 0.05, "{:?}: error {}", fp_type, error_s),
+                other => panic!("{:?}: drift not found: {:?}", fp_type, other),
+            }
+            // another song is playing
+            assert!(matches!(recheck(&a, &ring(&song_b, 23.0), SR, 23.0, &cfg), Recheck::Missed(_)), "{:?}", fp_type);
+        }
+
+        // constellation_v2 finds a seek anywhere in the song
+        let cfg = Config { fp_type: prescan::FpType::ConstellationV2, ..Config::default() };
+        match recheck(&song(&cfg), &ring(&song_a, 31.0), SR, 15.0, &cfg) {
+            Recheck::Found { error_s, .. } => assert!((error_s - 16.0).abs() < 0.05, "seek error {}", error_s),
+            other => panic!("seek not found: {:?}", other),
+        }
+
+        // a bandpeak_v1 lead-in cannot vouch for a position mid-song
+        let cfg = Config { fp_type: prescan::FpType::BandPeakV1, fp_every_s: 0.0, ..Config::default() };
+        assert_eq!(recheck(&song(&cfg), &ring(&song_a, 25.0), SR, 25.0, &cfg), Recheck::Skipped);
+    }
+
+    #[test]
+    fn melpeak_matches_across_sample_rates() {
+        // the same notes rendered at two rates, as a song scanned from a 44.1 kHz file and
+        // heard through a 48 kHz loopback
+        let notes = |sr: f32| -> Vec<f32> {
+            (0..((7.0 * sr) as usize))
+                .map(|i| {
+                    let t = (i as f32) / sr;
+                    let freq = 150.0 + (((t / 0.25) as u32 * 7919) % 4000) as f32;
+                    (2.0 * std::f32::consts::PI * freq * t).sin() * 0.3
+                })
+                .collect()
+        };
+        let fp = |sr: f32| prescan::make_fingerprint(&notes(sr), sr, 5.0, prescan::FpType::MelPeakV3).unwrap();
+        let (a, b) = (fp(44100.0), fp(48000.0));
+        assert!(a.bands == 32 && (a.hop_s - 0.016).abs() < 1e-4);
+        // the lag makes up for the two windows starting at different points of the lead-in
+        let (similarity, lag_s) = prescan::fp_match(&a, &b);
+        let error_s = lag_s - (a.offset_s - b.offset_s);
+        assert!(similarity > 0.9 && error_s.abs() < 0.05, "similarity {} at {} s off", similarity, error_s);
+    }
+
+    #[test]
+    fn media_titles_resolve_to_one_song() {
+        let songs: Vec<SongWindows> = ["Artist - Night Drive.mp3", "Artist - Night Drive (Live).mp3", "Other - Sunrise.flac"]
+            .iter()
+            .map(|url| SongWindows { url: url.to_string(), segs: vec![], fps: vec![] })
+            .collect();
+        let mut learned = HashMap::new();
+        let key = |t: &str| smtc::normalize(t);
+
+        assert_eq!(media_song(&songs, &key("SUNRISE"), &learned), Some("Other - Sunrise.flac"));
+        assert_eq!(media_song(&songs, &key("Night Drive (Live)"), &learned), Some("Artist - Night Drive (Live).mp3"));
+        // ambiguous, too short or unknown titles leave alignment to fingerprints
+        assert_eq!(media_song(&songs, &key("Night Drive"), &learned), None);
+        assert_eq!(media_song(&songs, &key("Art"), &learned), None);
+        assert_eq!(media_song(&songs, &key("Moonset"), &learned), None);
+        // a title learned from a fingerprint alignment wins
+        learned.insert(key("Night Drive"), "Artist - Night Drive.mp3".to_string());
+        assert_eq!(media_song(&songs, &key("Night Drive"), &learned), Some("Artist - Night Drive.mp3"));
+    }
 }
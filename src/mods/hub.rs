@@ -0,0 +1,332 @@
+//! src/mods/hub.rs
+//! `--mode hub`: no audio of its own. Sensor nodes (`--mode node`) send their per-tick
+//! measurements to `--node-listen`; the hub puts each node's ticks on its own clock, fuses the
+//! nodes' votes by weight into one vote per tick, and runs the usual decision policy on that,
+//! so the room gets one presence state and one event stream.
+
+use anyhow::Result;
+use std::{
+    collections::{ BTreeMap, VecDeque },
+    sync::{ atomic::{ AtomicBool, Ordering }, Arc },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::control::Control;
+use crate::hooks::{ HookEvent, Hooks };
+use crate::logger::{ Field, Logger };
+use crate::node::{ NodeEvent, NodeHub, NodeTick };
+use crate::output::{ self, JsonObj };
+use crate::{ sonar_presence, strategy, Config };
+
+/// Transit a tick may add to a node's clock offset, so a node clock running slow is followed
+/// (0.1 ms per tick: ten times the drift of a poor crystal at 100 ms ticks).
+const OFFSET_EASE_S: f64 = 1e-4;
+
+/// `--node-weights`, 1 for a node it does not name.
+fn weight_in(weights: &[(String, f32)], node: &str) -> f32 {
+    weights
+        .iter()
+        .find(|(n, _)| n == node)
+        .map_or(1.0, |&(_, w)| w)
+}
+
+/// One node's ticks, on the hub's clock.
+#[derive(Default)]
+struct Track {
+    offset: Option<f64>, // hub time − node time, from the quickest transit seen
+    last_t: f64, // node time of its last tick
+    pending: VecDeque<(f64, NodeTick)>, // not fused yet, by hub time
+    late: u64, // arrived after their tick was fused
+}
+
+/// One fused tick.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Fused {
+    pub vote: Option<(f32, f32)>, // the strongest voting node's echo, when the quorum voted
+    pub score: f32, // weight of the nodes that voted over the weight of those heard
+    pub heard: Vec<String>, // healthy nodes with a tick in this slot
+    pub voters: Vec<String>,
+}
+
+/// Time-aligns the nodes' ticks and fuses them one hub tick at a time.
+pub struct Fusion {
+    t0: Instant,
+    delay_s: f64,
+    weights: Vec<(String, f32)>,
+    tracks: BTreeMap<String, Track>,
+    fused_to: Option<f64>, // hub time up to which ticks have been fused
+}
+
+impl Fusion {
+    pub fn new(cfg: &Config, t0: Instant) -> Self {
+        Self {
+            t0,
+            delay_s: (cfg.hub_delay_ms as f64) / 1000.0,
+            weights: cfg.node_weights.clone(),
+            tracks: BTreeMap::new(),
+            fused_to: None,
+        }
+    }
+
+    pub fn weight(&self, node: &str) -> f32 {
+        weight_in(&self.weights, node)
+    }
+
+    /// Ticks that arrived after their slot was fused.
+    pub fn late(&self, node: &str) -> u64 {
+        self.tracks.get(node).map_or(0, |t| t.late)
+    }
+
+    pub fn arrive(&mut self, node: &str, tick: NodeTick, at: Instant) {
+        let hub_t = at.saturating_duration_since(self.t0).as_secs_f64();
+        let track = self.tracks.entry(node.to_string()).or_default();
+        if tick.t_s < track.last_t {
+            // the node restarted: its clock starts over
+            *track = Track::default();
+        }
+        track.last_t = tick.t_s;
+        let transit = hub_t - tick.t_s;
+        let offset = track.offset.map_or(transit, |o| transit.min(o + OFFSET_EASE_S));
+        track.offset = Some(offset);
+        let t = tick.t_s + offset;
+        if self.fused_to.is_some_and(|f| t <= f) {
+            track.late += 1;
+            return;
+        }
+        track.pending.push_back((t, tick));
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.tracks.remove(node);
+    }
+
+    /// Fuse everything up to `--hub-delay-ms` before `now`: each healthy node with a tick in
+    /// the slot is heard, with its strongest counting echo as its vote.
+    pub fn fuse(&mut self, now: Instant, healthy: &dyn Fn(&str) -> bool, cfg: &Config) -> Fused {
+        let upto = now.saturating_duration_since(self.t0).as_secs_f64() - self.delay_s;
+        let mut fused = Fused::default();
+        let (mut heard_w, mut voted_w) = (0.0, 0.0);
+        for (name, track) in self.tracks.iter_mut() {
+            let mut heard = false;
+            let mut echo: Option<(f32, f32)> = None;
+            while track.pending.front().is_some_and(|&(t, _)| t <= upto) {
+                let Some((_, tick)) = track.pending.pop_front() else {
+                    break;
+                };
+                heard = true;
+                if let Some((d, s)) = tick.counts(cfg) {
+                    if echo.is_none_or(|(_, best)| s > best) {
+                        echo = Some((d, s));
+                    }
+                }
+            }
+            let w = weight_in(&self.weights, name);
+            if !heard || w <= 0.0 || !healthy(name) {
+                continue;
+            }
+            heard_w += w;
+            fused.heard.push(name.clone());
+            if let Some((d, s)) = echo {
+                voted_w += w;
+                fused.voters.push(name.clone());
+                if fused.vote.is_none_or(|(_, best)| s > best) {
+                    fused.vote = Some((d, s));
+                }
+            }
+        }
+        self.fused_to = Some(self.fused_to.map_or(upto, |f| f.max(upto)));
+        fused.score = if heard_w > 0.0 { voted_w / heard_w } else { 0.0 };
+        if fused.score < cfg.hub_quorum {
+            fused.vote = None;
+        }
+        fused
+    }
+}
+
+fn hub_event(event: &str, present: bool) -> JsonObj {
+    JsonObj::new()
+        .str("ts", &chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string())
+        .str("mode", "hub")
+        .str("event", event)
+        .bool("present", present)
+}
+
+/// status.json / control `status`: the fused state and every node's health.
+fn hub_status(present: bool, state: &str, fused: &Fused, hub: &NodeHub, fusion: &Fusion) -> String {
+    let nodes: Vec<String> = hub
+        .nodes()
+        .iter()
+        .map(|(name, seen)| {
+            JsonObj::new()
+                .str("node", name)
+                .num("weight", fusion.weight(name) as f64)
+                .bool("healthy", !seen.lost)
+                .int("ticks", seen.ticks as i64)
+                .int("late", fusion.late(name) as i64)
+                .num("silent_s", seen.last.elapsed().as_secs_f64())
+                .finish()
+        })
+        .collect();
+    hub_event("status", present)
+        .str("state", state)
+        .num("score", fused.score as f64)
+        .arr("nodes", &nodes)
+        .finish()
+}
+
+pub fn run_hub(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    if cli.node_listen.is_empty() {
+        anyhow::bail!("--node-listen <HOST:PORT> is required in hub mode (where the nodes send to)");
+    }
+    logger.info("sonar-presence hub starting… (Ctrl+C to stop)")?;
+    let quit = Arc::new(AtomicBool::new(false));
+    {
+        let q = quit.clone();
+        let _ = ctrlc::set_handler(move || {
+            q.store(true, Ordering::SeqCst);
+        });
+    }
+
+    let Some(mut hub) = NodeHub::start(cli, logger.clone())? else {
+        return Ok(());
+    };
+    let status_path = output::sibling_path(&cli.log_path, "status.json");
+    let jsonl_path = output::sibling_path(&cli.log_path, "Detection.jsonl");
+    let mut policy = strategy::policy(cli, cli.tick_ms);
+    let mut activity = sonar_presence::Activity::new(cli);
+    let mut hooks = Hooks::new(cli, "hub", logger.clone());
+    let control = Control::start(cli, "hub", logger.clone())?;
+    let mut live = cli.clone();
+    logger.info(&format!("policy: {}, quorum {:.0}%, fusing {} ms behind", policy.name(), cli.hub_quorum * 100.0, cli.hub_delay_ms))?;
+
+    let t_run = Instant::now();
+    let mut fusion = Fusion::new(cli, t_run);
+    let mut next = t_run;
+    while !quit.load(Ordering::SeqCst) && !control.shutdown_requested() {
+        next += Duration::from_millis(cli.tick_ms);
+        control.apply(&mut live, policy.as_mut());
+
+        for ev in hub.receive() {
+            let (name, health) = match ev {
+                NodeEvent::Tick(name, tick, at) => {
+                    fusion.arrive(&name, tick, at);
+                    continue;
+                }
+                NodeEvent::Joined(name) => (name, "joined"),
+                NodeEvent::Lost(name) => (name, "lost"),
+                NodeEvent::Back(name) => (name, "back"),
+                NodeEvent::Left(name, _) => {
+                    fusion.remove(&name);
+                    (name, "left")
+                }
+            };
+            let ev = hub_event("node", policy.present()).str("node", &name).str("health", health).finish();
+            let _ = output::append_jsonl(&jsonl_path, &ev);
+        }
+
+        let fused = fusion.fuse(Instant::now(), &|n| hub.nodes().get(n).is_some_and(|s| !s.lost), &live);
+        if control.is_paused() || fused.heard.is_empty() {
+            // nothing measured this tick: the votes age, the state holds
+            policy.age();
+        } else if let Some(d) = policy.push(fused.vote, Instant::now()) {
+            if let Some(state) = activity.update(policy.present(), d.iqr_d, Instant::now()) {
+                logger.event(
+                    &format!(
+                        "state_change({},hub) -> present={} state={} nodes={}",
+                        policy.name(),
+                        state.present(),
+                        state.as_str(),
+                        fused.voters.join(",")
+                    ),
+                    &[
+                        ("present", Field::Bool(state.present())),
+                        ("state", Field::Str(state.as_str())),
+                    ]
+                )?;
+                let voters: Vec<String> = fused.voters
+                    .iter()
+                    .map(|v| {
+                        let mut q = String::new();
+                        output::push_json_str(&mut q, v);
+                        q
+                    })
+                    .collect();
+                let ev = hub_event("state_change", policy.present())
+                    .str("presence", state.as_str())
+                    .num("avg_distance_m", d.avg_d)
+                    .num("avg_strength", d.avg_s)
+                    .num("agree_pct", (d.agree * 100.0) as f64)
+                    .num("dist_iqr_m", d.iqr_d)
+                    .num("score", fused.score as f64)
+                    .arr("voters", &voters)
+                    .finish();
+                let _ = output::append_jsonl(&jsonl_path, &ev);
+            }
+            if d.flipped {
+                hooks.state_changed(HookEvent {
+                    present: policy.present(),
+                    state: activity.state(),
+                    distance_m: d.avg_d,
+                    strength: d.avg_s,
+                    agree: d.agree,
+                    probability: None,
+                });
+            }
+        }
+        hooks.poll();
+        let st = hub_status(policy.present(), activity.state().as_str(), &fused, &hub, &fusion);
+        let _ = output::write_status(&status_path, &st);
+        control.set_status(st);
+
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        } else {
+            next = now;
+        }
+    }
+    logger.info("sonar-presence hub stopped.")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_are_aligned_by_transit_and_fused_by_weight() {
+        let cfg = Config {
+            node_weights: vec![("door".to_string(), 2.0)],
+            hub_delay_ms: 200,
+            hub_quorum: 0.5,
+            ..Config::default()
+        };
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let tick = |t_s: f64, estimate| NodeTick { t_s, rms: (0.02, 0.01), estimate };
+        let mut f = Fusion::new(&cfg, t0);
+        let all = |_: &str| true;
+
+        // door started 10 s before the hub and is 30 ms away; desk's clock is its own
+        f.arrive("door", tick(10.0, Some((0.8, 0.5))), at(30));
+        f.arrive("desk", tick(3.0, None), at(50));
+        f.arrive("kitchen", tick(1.0, None), at(40));
+        // nothing is fused before the delay has passed
+        assert!(f.fuse(at(100), &all, &cfg).heard.is_empty());
+        let fused = f.fuse(at(300), &all, &cfg);
+        assert_eq!(fused.heard, vec!["desk", "door", "kitchen"]);
+        assert_eq!(fused.voters, vec!["door"]);
+        assert_eq!(fused.score, 0.5); // door's 2 of 4
+        assert_eq!(fused.vote, Some((0.8, 0.5)));
+
+        // a lost node is not heard; a tick for a slot already fused is late
+        f.arrive("door", tick(10.1, None), at(130));
+        f.arrive("kitchen", tick(1.1, Some((1.0, 0.6))), at(340));
+        let fused = f.fuse(at(600), &|n| n != "door", &cfg);
+        assert_eq!((fused.heard.clone(), fused.vote), (vec!["kitchen".to_string()], Some((1.0, 0.6))));
+        f.arrive("desk", tick(3.05, Some((0.5, 0.9))), at(100));
+        assert_eq!(f.late("desk"), 1);
+        assert_eq!(f.weight("kitchen"), 1.0);
+    }
+}
@@ -3,122 +3,304 @@
 
 use anyhow::Result;
 use cpal::traits::{ DeviceTrait, HostTrait, StreamTrait };
-use std::sync::{ Arc, Mutex };
+use realfft::RealFftPlanner;
+use rustfft::{ num_complex::Complex, FftPlanner };
+use crossbeam_channel::{ unbounded, Receiver, Sender };
+use std::sync::{ atomic::{ AtomicBool, Ordering }, Arc };
 use std::thread;
 use std::time::{ Duration, Instant };
-use crate::logger::Logger;
-use crate::Config;
+use crate::logger::{ Field, Logger };
+use crate::hooks::{ HookEvent, Hooks };
+use crate::autolock::AutoLock;
+use crate::console::LiveStatus;
+use crate::audio::{ self, AudioSource, CpalMic };
+use crate::{ output, sonar_presence, strategy, Config, SharedBuf };
+use crate::strategy::Decision;
+use crate::sonar_presence::PresenceState;
+use crate::mods::presence::{ log_window, WindowState };
 
 const CORRELATION_THRESHOLD: f32 = 0.15;
-const MIN_DETECTIONS_FOR_PRESENCE: f32 = 0.5; // 50% detection ratio
+
+/// Signal emitted for each measurement.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImpulseProbe {
+    Click, // 3-sample decaying click: broadband and audible
+    Burst, // Hann-windowed tone at the centre of --probe-band, as short as the band allows
+    Chirp, // Hann-tapered linear sweep across --probe-band over --impulse-length-ms
+}
+
+impl ImpulseProbe {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "click" => Some(ImpulseProbe::Click),
+            "burst" => Some(ImpulseProbe::Burst),
+            "chirp" => Some(ImpulseProbe::Chirp),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImpulseProbe::Click => "click",
+            ImpulseProbe::Burst => "burst",
+            ImpulseProbe::Chirp => "chirp",
+        }
+    }
+}
+
+/// `LO-HI` in Hz, e.g. `17000-20000`.
+pub fn parse_band(s: &str) -> Option<(f32, f32)> {
+    let (lo, hi) = s.split_once('-')?;
+    let (lo, hi) = (lo.trim().parse::<f32>().ok()?, hi.trim().parse::<f32>().ok()?);
+    (lo > 0.0 && hi > lo).then_some((lo, hi))
+}
 
 #[derive(Debug, Clone)]
 struct ImpulseDetection {
-    timestamp: Instant,
     distance: Option<f32>,
     confidence: f32,
     detected: bool,
 }
 
 pub fn run_impulse(config: &Config, logger: Arc<Logger>) -> Result<()> {
-    println!("\n===== Impulse-based Presence Detection Mode =====");
-    println!("Configuration:");
-    println!("  Detection range: {:.1}m - {:.1}m", config.front_min_m, config.front_max_m);
-    println!("  Window duration: {} seconds", config.window_sec);
-    println!("  Tick interval: {} ms", config.tick_ms);
-    println!("  Impulse duration: {:.1} ms", config.impulse_length_ms);
-    println!("  Listen duration: {} ms", config.impulse_listen_ms);
-    println!("  Amplitude: {:.2}", config.impulse_amplitude);
-    println!("\nStarting continuous presence detection...");
-
-    logger.info("Starting impulse-based presence detection mode")?;
-
-    // Setup audio
-    let host = cpal::default_host();
-    let output_device = host
-        .default_output_device()
-        .ok_or_else(|| anyhow::anyhow!("No output device available"))?;
-    let input_device = host
-        .default_input_device()
-        .ok_or_else(|| anyhow::anyhow!("No input device available"))?;
-
-    let output_config = output_device.default_output_config()?;
-    let input_config = input_device.default_input_config()?;
-    let sample_rate = output_config.sample_rate().0;
-
-    println!("Using sample rate: {} Hz", sample_rate);
-    logger.info(&format!("Sample rate: {} Hz", sample_rate))?;
-
-    // Calculate window parameters
-    let window_duration = Duration::from_secs(config.window_sec as u64);
-    let tick_duration = Duration::from_millis(config.tick_ms);
-    let measurements_per_window = (window_duration.as_millis() /
-        tick_duration.as_millis()) as usize;
-
-    println!("Measurements per window: {}", measurements_per_window);
-
-    // Detection history buffer for sliding window
-    let mut detection_buffer = Vec::with_capacity(measurements_per_window);
-    let mut window_start = Instant::now();
-    let mut presence_state = false;
-
-    // Main detection loop
-    loop {
-        let measurement_start = Instant::now();
+    run_impulse_with(config, logger, Box::new(CpalMic::new(config.channel_mix)))
+}
 
-        // Perform single impulse measurement
-        let detection = perform_impulse_measurement(
-            &output_device,
-            &input_device,
-            &output_config.config(),
-            &input_config.config(),
-            sample_rate,
-            config,
-            &logger
+/// Impulse mode on an arbitrary mic source; the impulse itself always goes to the default output.
+/// Measurements go through the same `--policy` as presence mode, and state
+/// changes are written to `Detection.csv` beside the log file.
+pub fn run_impulse_with(config: &Config, logger: Arc<Logger>, mut mic: Box<dyn AudioSource>) -> Result<()> {
+    // a measurement lasts at least the listen time of all its pulses, so ticks are never shorter
+    let tick_ms = config.tick_ms.max((config.impulse_avg.max(1) as u64) * config.impulse_listen_ms);
+    logger.info(
+        &format!(
+            "sonar-presence (impulse) starting…  tick_ms={}  agg_frac={:.2}  window_sec={}  range={:.1}-{:.1}m",
+            tick_ms,
+            config.agg_frac,
+            config.window_sec,
+            config.front_min_m,
+            config.front_max_m
+        )
+    )?;
+    logger.info(
+        &format!(
+            "Impulse: {:.1} ms, listen {} ms, amplitude {:.2}, {} pulse(s) averaged per measurement",
+            config.impulse_length_ms,
+            config.impulse_listen_ms,
+            config.impulse_amplitude,
+            config.impulse_avg.max(1)
+        )
+    )?;
+    if tick_ms > config.tick_ms {
+        logger.warn(
+            &format!(
+                "--tick-ms {} is shorter than --impulse-avg x --impulse-listen-ms; ticking every {} ms",
+                config.tick_ms,
+                tick_ms
+            )
         )?;
+    }
 
-        // Add to buffer
-        detection_buffer.push(detection);
+    // CSV path sits beside the log file.
+    let csv_path = output::sibling_path(&config.log_path, "Detection.csv");
+    let mut csv_file = output::open_detection_csv(&csv_path, output::Rotation::from_config(config))?;
+
+    // ctrl+c to quit
+    let quit = Arc::new(AtomicBool::new(false));
+    {
+        let q = quit.clone();
+        let _ = ctrlc::set_handler(move || {
+            q.store(true, Ordering::SeqCst);
+        });
+    }
 
-        // Check if window is complete
-        if measurement_start.duration_since(window_start) >= window_duration {
-            // Analyze window for presence
-            let presence = analyze_window(&detection_buffer, measurements_per_window);
+    let prober = Prober::start(config, logger.clone())?;
 
-            // State change detection
-            if presence != presence_state {
-                presence_state = presence;
-                let state_str = if presence { "PRESENT" } else { "ABSENT" };
+    // the mic runs continuously; each measurement takes what arrived while listening
+    let shared_mic = audio::capture(mic.as_mut(), Some(prober.sample_rate), logger.clone())?;
+    prober.check_mic(&shared_mic, &logger)?;
 
-                println!("\n>>> Presence state changed: {}", state_str);
-                logger.info(&format!("Presence state: {}", state_str))?;
-            }
+    // --policy: agreement window + smoothed presence state, as in presence mode
+    let mut policy = strategy::policy(config, tick_ms);
+    let mut hooks = Hooks::new(config, "impulse", logger.clone());
+    let mut auto_lock = AutoLock::new(config, logger.clone());
+    let mut status = LiveStatus::new(config);
+    let tick_duration = Duration::from_millis(tick_ms);
 
-            // Reset window
-            detection_buffer.clear();
-            window_start = Instant::now();
+    while !quit.load(Ordering::SeqCst) {
+        let measurement_start = Instant::now();
 
-            println!("Window complete. Presence: {}", if presence { "YES" } else { "NO" });
+        // Perform single impulse measurement
+        let vote = prober.measure(&shared_mic, config)?;
+
+        let window = policy.push(vote, Instant::now()).map(|d| pulse_window(&d, policy.present()));
+        if let Some(w) = window {
+            if w.flipped {
+                // CSV on state change
+                let _ = output::write_detection_row(&mut csv_file, w.state, w.avg_d, w.avg_s, w.agree, w.iqr_d, None, None, None, None);
+                let _ = logger.event(
+                    &format!("Presence state: {}", if policy.present() { "PRESENT" } else { "ABSENT" }),
+                    &[("present", Field::Bool(policy.present()))]
+                );
+
+                hooks.state_changed(HookEvent {
+                    present: policy.present(),
+                    state: w.state,
+                    distance_m: w.avg_d,
+                    strength: w.avg_s,
+                    agree: w.agree,
+                    probability: None,
+                });
+            }
+            log_window(&logger, policy.present(), &w, config.window_sec, vote.is_none());
+            status.update(policy.present(), w.avg_d, w.agree);
         }
 
+        hooks.poll();
+        auto_lock.update(policy.present());
+
         // Wait for next tick
         let elapsed = measurement_start.elapsed();
         if elapsed < tick_duration {
             thread::sleep(tick_duration - elapsed);
         }
     }
+
+    logger.info("sonar-presence (impulse) stopped.")?;
+    Ok(())
 }
 
-fn perform_impulse_measurement(
-    output_device: &cpal::Device,
-    input_device: &cpal::Device,
-    output_config: &cpal::StreamConfig,
-    input_config: &cpal::StreamConfig,
-    sample_rate: u32,
-    config: &Config,
-    _logger: &Arc<Logger>
-) -> Result<ImpulseDetection> {
-    // Generate impulse signal using config values
+/// The impulse output of a run, on the default output device.
+pub struct Prober {
+    pub sample_rate: u32, // the output's
+    band: Option<(f32, f32)>, // probe_band
+    output: ImpulseOutput,
+}
+
+impl Prober {
+    /// Open the default output at its own rate and keep it open; fails when `--probe-band` does not fit it.
+    pub fn start(config: &Config, logger: Arc<Logger>) -> Result<Self> {
+        let host = cpal::default_host();
+        let output_device = host
+            .default_output_device()
+            .ok_or_else(|| anyhow::anyhow!("No output device available"))?;
+
+        let output_config = output_device.default_output_config()?;
+        let sample_rate = output_config.sample_rate().0;
+
+        logger.info(&format!("Output sample rate: {} Hz", sample_rate))?;
+
+        let band = probe_band(config);
+        if let Some((_, hi)) = band {
+            if hi >= (sample_rate as f32) / 2.0 {
+                anyhow::bail!("--probe-band reaches {:.0} Hz, above the output's {} Hz limit", hi, sample_rate / 2);
+            }
+        }
+        logger.info(&format!("Probe: {}", probe_label(config)))?;
+
+        // one output stream for the whole run: impulses are queued into it, not played on new streams
+        let output = ImpulseOutput::start(&output_device, &output_config, logger)?;
+        Ok(Self { sample_rate, band, output })
+    }
+
+    /// The mic must hear the probe band at its own rate.
+    pub fn check_mic(&self, shared_mic: &SharedBuf, logger: &Logger) -> Result<()> {
+        let mic_rate = shared_mic.sr as u32;
+        if mic_rate != self.sample_rate {
+            logger.warn(
+                &format!("Mic runs at {} Hz, output at {} Hz; analysing at the mic rate", mic_rate, self.sample_rate)
+            )?;
+        }
+        if let Some((_, hi)) = self.band {
+            if hi >= (mic_rate as f32) / 2.0 {
+                anyhow::bail!("--probe-band reaches {:.0} Hz, above the mic's {} Hz limit", hi, mic_rate / 2);
+            }
+        }
+        Ok(())
+    }
+
+    /// One measurement (--impulse-avg pulses): the vote (distance, confidence), None when
+    /// nothing within `--dist-max-m` answered.
+    pub fn measure(&self, shared_mic: &SharedBuf, config: &Config) -> Result<Option<(f32, f32)>> {
+        let detection = perform_impulse_measurement(&self.output, shared_mic, self.sample_rate, config)?;
+        Ok(
+            detection.distance
+                .filter(|&d| detection.detected && d <= config.dist_max_m)
+                .map(|d| (d, detection.confidence))
+        )
+    }
+}
+
+/// A policy window of pulse votes, as presence mode reports its windows.
+pub fn pulse_window(d: &Decision, present: bool) -> WindowState {
+    WindowState {
+        flipped: d.flipped,
+        // pulses give no motion to split present by
+        state: PresenceState::from_present(present),
+        state_changed: d.flipped,
+        avg_d: d.avg_d,
+        avg_s: d.avg_s,
+        agree: d.agree,
+        iqr_d: d.iqr_d,
+        bearing_deg: None,
+        quality: None,
+        // the curve is learned from correlation windows, not pulses
+        probability: None,
+    }
+}
+
+/// Band the probe occupies; None for the broadband click.
+fn probe_band(config: &Config) -> Option<(f32, f32)> {
+    match config.impulse_probe {
+        ImpulseProbe::Click => None,
+        ImpulseProbe::Burst | ImpulseProbe::Chirp => Some(config.impulse_probe_band),
+    }
+}
+
+fn probe_label(config: &Config) -> String {
+    match probe_band(config) {
+        None => config.impulse_probe.as_str().to_string(),
+        Some((lo, hi)) => format!("{} {:.0}-{:.0} Hz", config.impulse_probe.as_str(), lo, hi),
+    }
+}
+
+/// The probe signal at `sample_rate`, peaking at --impulse-amplitude.
+fn make_impulse(sample_rate: u32, config: &Config) -> Vec<f32> {
+    let sr = sample_rate as f32;
+    let amp = config.impulse_amplitude;
+    let (lo, hi) = config.impulse_probe_band;
+    match config.impulse_probe {
+        ImpulseProbe::Click => make_click(sample_rate, config),
+        ImpulseProbe::Burst => {
+            // a Hann window's main lobe is 4/T wide: the shortest burst that stays inside the band
+            let n = ((4.0 / (hi - lo)) * sr).round().max(2.0) as usize;
+            let fc = 0.5 * (lo + hi);
+            (0..n)
+                .map(|i| amp * hann(i, n) * (std::f32::consts::TAU * fc * (i as f32) / sr).sin())
+                .collect()
+        }
+        ImpulseProbe::Chirp => {
+            // tapered over its whole length: range sidelobes of the compressed pulse stay
+            // ~30 dB down instead of the ~13 dB of a flat sweep, below CORRELATION_THRESHOLD
+            let n = ((config.impulse_length_ms / 1000.0) * sr).round().max(2.0) as usize;
+            let dur = (n as f32) / sr;
+            (0..n)
+                .map(|i| {
+                    let t = (i as f32) / sr;
+                    let phase = std::f32::consts::TAU * (lo * t + (0.5 * (hi - lo) * t * t) / dur);
+                    amp * hann(i, n) * phase.sin()
+                })
+                .collect()
+        }
+    }
+}
+
+fn hann(i: usize, n: usize) -> f32 {
+    0.5 - 0.5 * ((std::f32::consts::TAU * (i as f32)) / ((n - 1) as f32)).cos()
+}
+
+/// Short decaying click at `sample_rate`, shaped by the config.
+fn make_click(sample_rate: u32, config: &Config) -> Vec<f32> {
     let impulse_samples = ((config.impulse_length_ms / 1000.0) * (sample_rate as f32)) as usize;
     let mut impulse = vec![0.0f32; impulse_samples];
 
@@ -132,75 +314,133 @@ fn perform_impulse_measurement(
     if impulse_samples > 2 {
         impulse[2] = config.impulse_amplitude * 0.25;
     }
+    impulse
+}
 
-    // Recording buffer
-    let recording_buffer = Arc::new(Mutex::new(Vec::new()));
-    let recording_clone = recording_buffer.clone();
-
-    // Setup input stream
-    let channels = input_config.channels as usize;
-    let input_stream = input_device.build_input_stream(
-        input_config,
-        move |data: &[f32], _: &cpal::InputCallbackInfo| {
-            let mut buffer = recording_clone.lock().unwrap();
-            // Extract first channel only
-            for frame in data.chunks(channels) {
-                if let Some(sample) = frame.first() {
-                    buffer.push(*sample);
-                }
-            }
-        },
-        |err| eprintln!("Input stream error: {}", err),
-        None
-    )?;
+/// The output stream of the run. The callback plays queued signal and silence otherwise, so
+/// the device stays open between impulses (no clicks from stream start/stop, no contention).
+struct ImpulseOutput {
+    trains: Sender<Vec<f32>>, // whole signals, handed to the callback in one piece
+    spent: Receiver<Vec<f32>>, // played ones, handed back so the callback never frees memory
+    _stream: cpal::Stream,
+}
 
-    // Setup output stream
-    let impulse_clone = impulse.clone();
-    let mut sample_clock = Arc::new(Mutex::new(0usize));
-    let sample_clock_clone = sample_clock.clone();
-    let output_channels = output_config.channels as usize;
-
-    let output_stream = output_device.build_output_stream(
-        output_config,
-        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            let mut clock = sample_clock_clone.lock().unwrap();
-            for frame in data.chunks_mut(output_channels) {
-                let sample_value = if *clock < impulse_clone.len() {
-                    let val = impulse_clone[*clock];
-                    *clock += 1;
-                    val
-                } else {
-                    0.0
-                };
-                for sample in frame.iter_mut() {
-                    *sample = sample_value;
-                }
-            }
-        },
-        |err| eprintln!("Output stream error: {}", err),
-        None
-    )?;
+impl ImpulseOutput {
+    fn start(device: &cpal::Device, supported: &cpal::SupportedStreamConfig, logger: Arc<Logger>) -> Result<Self> {
+        let config = supported.config();
+        let (trains, queued) = unbounded();
+        let (done, spent) = unbounded();
+        let stream = match supported.sample_format() {
+            cpal::SampleFormat::F32 => build_output::<f32>(device, &config, queued, done, logger)?,
+            cpal::SampleFormat::I16 => build_output::<i16>(device, &config, queued, done, logger)?,
+            cpal::SampleFormat::U16 => build_output::<u16>(device, &config, queued, done, logger)?,
+            other => anyhow::bail!("Unsupported output sample format: {:?}", other),
+        };
+        stream.play()?;
+        Ok(Self { trains, spent, _stream: stream })
+    }
+
+    /// Play `signal` from the next device buffer on, after anything still queued.
+    fn fire(&self, signal: &[f32]) {
+        while self.spent.try_recv().is_ok() {}
+        let _ = self.trains.send(signal.to_vec());
+    }
+}
 
-    // Start recording
-    input_stream.play()?;
-    thread::sleep(Duration::from_millis(10)); // Small delay
+fn build_output<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    queued: Receiver<Vec<f32>>,
+    done: Sender<Vec<f32>>,
+    logger: Arc<Logger>
+) -> Result<cpal::Stream>
+    where T: cpal::SizedSample + cpal::FromSample<f32>
+{
+    let channels = (config.channels as usize).max(1);
+    // the signal being played and the next sample of it; the channel never blocks the callback,
+    // and a signal arrives whole, so a burst is never cut by a busy producer
+    let mut playing: Vec<f32> = Vec::new();
+    let mut at = 0;
+    Ok(
+        device.build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                for frame in data.chunks_mut(channels) {
+                    if at == playing.len() {
+                        if let Ok(next) = queued.try_recv() {
+                            let _ = done.try_send(std::mem::replace(&mut playing, next));
+                            at = 0;
+                        }
+                    }
+                    let v = playing.get(at).copied().unwrap_or(0.0);
+                    at = (at + 1).min(playing.len());
+                    frame.fill(T::from_sample(v));
+                }
+            },
+            move |err| {
+                let _ = logger.error(&format!("Output stream error: {}", err));
+            },
+            None
+        )?
+    )
+}
 
-    // Play impulse
-    output_stream.play()?;
+/// Mic samples `from..from + len` (positions counted by `SharedBuf::written`), waiting up to
+/// `timeout` for them to arrive; None if they never do or have already left the ring.
+fn carve(shared: &SharedBuf, from: u64, len: usize, timeout: Duration) -> Option<Vec<f32>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if shared.written() >= from + (len as u64) {
+            return shared.read(from, len);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
 
-    // Record for configured duration
-    thread::sleep(Duration::from_millis(config.impulse_listen_ms));
+fn perform_impulse_measurement(
+    output: &ImpulseOutput,
+    shared_mic: &SharedBuf,
+    sample_rate: u32,
+    config: &Config
+) -> Result<ImpulseDetection> {
+    // Generate impulse signal using config values; --impulse-avg of them, one listen time apart,
+    // queued as one train so their spacing is exact on the output clock
+    let impulse = make_impulse(sample_rate, config);
+    let pulses = config.impulse_avg.max(1);
+    let period_out = ((((config.impulse_listen_ms as f32) / 1000.0) * (sample_rate as f32)) as usize).max(impulse.len());
+    let mut train = vec![0.0f32; (pulses - 1) * period_out + impulse.len()];
+    for k in 0..pulses {
+        train[k * period_out..k * period_out + impulse.len()].copy_from_slice(&impulse);
+    }
 
-    // Stop streams
-    drop(output_stream);
-    drop(input_stream);
+    // The measurement window starts at the current write position of the mic ring
+    let mark = shared_mic.written();
+    output.fire(&train);
+
+    // exactly --impulse-listen-ms of mic audio after each pulse, from the mark on
+    let mic_rate = shared_mic.sr as u32;
+    let listen_len = (((config.impulse_listen_ms as f32) / 1000.0) * (mic_rate as f32)) as usize;
+    let period_mic = (((period_out as f64) * (mic_rate as f64)) / (sample_rate as f64)).round() as usize;
+    let total = (pulses - 1) * period_mic + listen_len;
+    let timeout = Duration::from_millis(2 * (pulses as u64) * config.impulse_listen_ms + 500);
+    let Some(recording) = carve(shared_mic, mark, total, timeout) else {
+        return Ok(ImpulseDetection {
+            distance: None,
+            confidence: 0.0,
+            detected: false,
+        });
+    };
+    let recording = average_periods(&recording, pulses, period_mic, listen_len);
 
     // Analyze recording
-    let recording = recording_buffer.lock().unwrap().clone();
     let detection = analyze_impulse_response(
-        &impulse,
+        &make_impulse(mic_rate, config),
         &recording,
-        sample_rate,
+        mic_rate,
+        probe_band(config),
         config.front_min_m,
         config.front_max_m
     );
@@ -208,24 +448,53 @@ fn perform_impulse_measurement(
     Ok(detection)
 }
 
+/// Mean of `count` segments of `len` samples, `period` apart. The matched filter is linear, so
+/// filtering this equals averaging the filter outputs of every pulse: coherent averaging that
+/// keeps the echoes and lowers uncorrelated noise by √count, for the cost of one filter.
+fn average_periods(recording: &[f32], count: usize, period: usize, len: usize) -> Vec<f32> {
+    if count <= 1 {
+        return recording[..len.min(recording.len())].to_vec();
+    }
+    let mut out = vec![0.0f32; len];
+    for k in 0..count {
+        for (o, &v) in out.iter_mut().zip(recording.iter().skip(k * period)) {
+            *o += v;
+        }
+    }
+    let scale = 1.0 / (count as f32);
+    for o in out.iter_mut() {
+        *o *= scale;
+    }
+    out
+}
+
+/// Find reflections of `impulse` in `recording`. With a probe `band`, the recording is filtered
+/// to it first and peaks are picked on the matched filter's envelope, not its carrier ripple.
 fn analyze_impulse_response(
     impulse: &[f32],
     recording: &[f32],
     sample_rate: u32,
+    band: Option<(f32, f32)>,
     min_distance: f32,
     max_distance: f32
 ) -> ImpulseDetection {
     if recording.len() < impulse.len() {
         return ImpulseDetection {
-            timestamp: Instant::now(),
             distance: None,
             confidence: 0.0,
             detected: false,
         };
     }
 
-    // Simple cross-correlation to find reflections
-    let correlation = compute_correlation(impulse, recording);
+    // Matched filter to find reflections
+    let correlation = match band {
+        None => compute_correlation(impulse, recording, false),
+        Some((lo, hi)) => {
+            let mut filtered = recording.to_vec();
+            sonar_presence::band_limit(&mut filtered, sample_rate as f32, lo, hi);
+            compute_correlation(impulse, &filtered, true)
+        }
+    };
 
     // Find peaks in correlation
     let peaks = find_correlation_peaks(&correlation, CORRELATION_THRESHOLD);
@@ -247,14 +516,12 @@ fn analyze_impulse_response(
     // Determine if detection is valid
     if let Some(&(dist, strength)) = valid_reflections.first() {
         ImpulseDetection {
-            timestamp: Instant::now(),
             distance: Some(dist),
             confidence: strength.min(1.0),
             detected: true,
         }
     } else {
         ImpulseDetection {
-            timestamp: Instant::now(),
             distance: None,
             confidence: 0.0,
             detected: false,
@@ -262,47 +529,87 @@ fn analyze_impulse_response(
     }
 }
 
-fn compute_correlation(signal: &[f32], recording: &[f32]) -> Vec<f32> {
-    let mut correlation = Vec::with_capacity(recording.len());
+/// Normalized matched filter: |Σ s[i]·r[lag+i]| / √(E_s·E_r(lag)) for every lag where the signal
+/// fits, E_r being the recording's energy under the signal. The sums come from one FFT product
+/// and the energies from a running sum, so the cost is O(N log N) instead of O(N·M).
+/// With `envelope` the sums are replaced by the magnitude of their analytic signal, which
+/// follows the peak of a band-pass probe instead of oscillating at its carrier.
+fn compute_correlation(signal: &[f32], recording: &[f32], envelope: bool) -> Vec<f32> {
     let signal_len = signal.len();
 
     // Normalize signal
-    let signal_energy: f32 = signal
+    let signal_energy: f64 = signal
         .iter()
-        .map(|x| x * x)
+        .map(|&x| (x as f64) * (x as f64))
         .sum();
     if signal_energy == 0.0 {
         return vec![0.0; recording.len()];
     }
+    let lags = recording.len().saturating_sub(signal_len);
+    if lags == 0 {
+        return Vec::new();
+    }
 
-    // Compute correlation at each lag
-    for lag in 0..recording.len().saturating_sub(signal_len) {
-        let mut sum = 0.0f32;
-        let mut rec_energy = 0.0f32;
-
-        for i in 0..signal_len {
-            sum += signal[i] * recording[lag + i];
-            rec_energy += recording[lag + i] * recording[lag + i];
+    // zero-padded past N + M: the circular correlation has no wrap-around at the lags we read
+    let size = (recording.len() + signal_len).next_power_of_two();
+    let mut planner = RealFftPlanner::<f32>::new();
+    let r2c = planner.plan_fft_forward(size);
+    let c2r = planner.plan_fft_inverse(size);
+    let mut rec = vec![0.0f32; size];
+    rec[..recording.len()].copy_from_slice(recording);
+    let mut sig = vec![0.0f32; size];
+    sig[..signal_len].copy_from_slice(signal);
+    let mut rec_spec = r2c.make_output_vec();
+    let mut sig_spec = r2c.make_output_vec();
+    if r2c.process(&mut rec, &mut rec_spec).is_err() || r2c.process(&mut sig, &mut sig_spec).is_err() {
+        return vec![0.0; lags];
+    }
+    for (r, s) in rec_spec.iter_mut().zip(&sig_spec) {
+        *r *= s.conj();
+    }
+    let sums: Vec<f32> = if envelope {
+        // analytic signal: positive frequencies doubled, negative ones dropped
+        let mut full = vec![Complex::new(0.0f32, 0.0); size];
+        for (k, c) in rec_spec.iter().enumerate() {
+            full[k] = if k == 0 || k == size / 2 { *c } else { *c * 2.0 };
         }
-
-        // Normalized correlation
-        let norm_corr = if rec_energy > 0.0 {
-            sum / (signal_energy * rec_energy).sqrt()
-        } else {
-            0.0
-        };
-
-        correlation.push(norm_corr.abs());
+        FftPlanner::<f32>::new().plan_fft_inverse(size).process(&mut full);
+        full.iter().map(|c| c.norm()).collect()
+    } else {
+        let mut sums = vec![0.0f32; size];
+        if c2r.process(&mut rec_spec, &mut sums).is_err() {
+            return vec![0.0; lags];
+        }
+        sums
+    };
+
+    // energy of recording[lag..lag + M] as a difference of prefix sums
+    let mut prefix = Vec::with_capacity(recording.len() + 1);
+    prefix.push(0.0f64);
+    for &x in recording {
+        prefix.push(prefix.last().unwrap() + (x as f64) * (x as f64));
     }
 
-    correlation
+    // a prefix difference keeps rounding residue of the whole sum: silence must not look like signal
+    let floor = prefix[recording.len()] * 1e-9;
+    (0..lags)
+        .map(|lag| {
+            let rec_energy = prefix[lag + signal_len] - prefix[lag];
+            if rec_energy > floor {
+                let sum = (sums[lag] as f64) / (size as f64);
+                (sum / (signal_energy * rec_energy).sqrt()).abs() as f32
+            } else {
+                0.0
+            }
+        })
+        .collect()
 }
 
 fn find_correlation_peaks(correlation: &[f32], threshold: f32) -> Vec<(usize, f32)> {
     let mut peaks: Vec<(usize, f32)> = Vec::new();
     let min_distance = 20; // Minimum samples between peaks
 
-    for i in 1..correlation.len() - 1 {
+    for i in 1..correlation.len().saturating_sub(1) {
         // Check if local maximum above threshold
         if
             correlation[i] > threshold &&
@@ -319,16 +626,123 @@ fn find_correlation_peaks(correlation: &[f32], threshold: f32) -> Vec<(usize, f3
     peaks
 }
 
-fn analyze_window(detections: &[ImpulseDetection], expected_count: usize) -> bool {
-    // Count valid detections in window
-    let valid_detections = detections
-        .iter()
-        .filter(|d| d.detected)
-        .count();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Lcg;
+
+    /// The O(N·M) definition the FFT version replaces.
+    fn naive_correlation(signal: &[f32], recording: &[f32]) -> Vec<f32> {
+        let es: f32 = signal.iter().map(|x| x * x).sum();
+        (0..recording.len().saturating_sub(signal.len()))
+            .map(|lag| {
+                let w = &recording[lag..lag + signal.len()];
+                let sum: f32 = signal.iter().zip(w).map(|(a, b)| a * b).sum();
+                let er: f32 = w.iter().map(|x| x * x).sum();
+                if er > 0.0 { (sum / (es * er).sqrt()).abs() } else { 0.0 }
+            })
+            .collect()
+    }
 
-    // Calculate detection ratio
-    let detection_ratio = (valid_detections as f32) / (expected_count.max(1) as f32);
+    #[test]
+    fn fft_matched_filter_equals_direct_correlation() {
+        let mut rng = Lcg(5);
+        let signal: Vec<f32> = (0..300).map(|_| rng.next()).collect();
+        // silence, the signal at 1000, an inverted echo at 1600, silence, noise
+        let mut recording = vec![0.0f32; 4000];
+        for (i, &v) in signal.iter().enumerate() {
+            recording[1000 + i] += v;
+            recording[1600 + i] -= 0.3 * v;
+        }
+        for v in recording[3000..].iter_mut() {
+            *v = 0.05 * rng.next();
+        }
 
-    // Presence if sufficient detections
-    detection_ratio >= MIN_DETECTIONS_FOR_PRESENCE
+        let fast = compute_correlation(&signal, &recording, false);
+        let slow = naive_correlation(&signal, &recording);
+        assert_eq!(fast.len(), slow.len());
+        for (lag, (f, s)) in fast.iter().zip(&slow).enumerate() {
+            assert!((f - s).abs() < 1e-3, "lag {}: {} vs {}", lag, f, s);
+        }
+        assert!(fast[1000] > 0.99 && fast[1600] > 0.9);
+        assert!(fast[1900..2700].iter().all(|&v| v == 0.0), "silence after the echo");
+        assert!(fast[..700].iter().all(|&v| v == 0.0));
+
+        assert!(compute_correlation(&signal, &signal, false).is_empty());
+        assert!(find_correlation_peaks(&[], 0.1).is_empty());
+    }
+
+    #[test]
+    fn band_limited_probes_range_on_the_envelope() {
+        let sr = 48_000u32;
+        // echo 1.0 m behind the direct path (5.83 ms round trip), music-like noise below 8 kHz
+        let gap = ((2.0 / 343.0) * (sr as f32)).round() as usize;
+        for probe in [ImpulseProbe::Burst, ImpulseProbe::Chirp] {
+            let cfg = Config { impulse_probe: probe, ..Config::default() };
+            let p = make_impulse(sr, &cfg);
+            let spectrum_ok = {
+                let mut x = p.clone();
+                sonar_presence::band_limit(&mut x, sr as f32, 16_000.0, 21_000.0);
+                let e = |v: &[f32]| v.iter().map(|a| a * a).sum::<f32>();
+                e(&x) > 0.95 * e(&p)
+            };
+            assert!(spectrum_ok, "{:?} leaks outside its band", probe);
+
+            let mut rng = Lcg(9);
+            let mut low = vec![0.0f32; sr as usize / 5];
+            for v in low.iter_mut() {
+                *v = 0.3 * rng.next();
+            }
+            sonar_presence::band_limit(&mut low, sr as f32, 50.0, 8_000.0);
+            let mut rec = low;
+            for (i, &v) in p.iter().enumerate() {
+                rec[500 + i] += 0.5 * v;
+                rec[500 + gap + i] += 0.2 * v;
+            }
+
+            let corr = compute_correlation(&p, &{
+                let mut f = rec.clone();
+                sonar_presence::band_limit(&mut f, sr as f32, cfg.impulse_probe_band.0, cfg.impulse_probe_band.1);
+                f
+            }, true);
+            let peaks = find_correlation_peaks(&corr, CORRELATION_THRESHOLD);
+            assert!(peaks.len() >= 2, "{:?}: {:?}", probe, peaks);
+            assert!(peaks[0].0.abs_diff(500) <= 3, "{:?} direct at {}", probe, peaks[0].0);
+            assert!(peaks[1].0.abs_diff(500 + gap) <= 3, "{:?} echo at {} (want {})", probe, peaks[1].0, 500 + gap);
+        }
+        assert_eq!(parse_band("17000-20000"), Some((17000.0, 20000.0)));
+        assert_eq!(parse_band("20000-17000"), None);
+    }
+
+    #[test]
+    fn averaged_pulses_lift_a_buried_echo() {
+        let sr = 48_000u32;
+        let cfg = Config { impulse_probe: ImpulseProbe::Chirp, impulse_length_ms: 10.0, ..Config::default() };
+        let p = make_impulse(sr, &cfg);
+        let (period, len, pulses) = (6000usize, 5000usize, 16usize);
+        let (direct, echo) = (400usize, 400 + 280);
+
+        // every period: direct path, a faint echo, and fresh broadband noise
+        let mut rng = Lcg(21);
+        let mut rec: Vec<f32> = (0..(pulses - 1) * period + len).map(|_| 3.0 * rng.next()).collect();
+        for k in 0..pulses {
+            for (i, &v) in p.iter().enumerate() {
+                rec[k * period + direct + i] += 0.5 * v;
+                rec[k * period + echo + i] += 0.2 * v;
+            }
+        }
+        let (lo, hi) = cfg.impulse_probe_band;
+        let echo_corr = |x: &[f32]| {
+            let mut f = x.to_vec();
+            sonar_presence::band_limit(&mut f, sr as f32, lo, hi);
+            compute_correlation(&p, &f, true)[echo - 3..=echo + 3].iter().cloned().fold(0.0f32, f32::max)
+        };
+
+        let single = echo_corr(&rec[..len]);
+        let averaged = average_periods(&rec, pulses, period, len);
+        assert_eq!(averaged.len(), len);
+        let avg = echo_corr(&averaged);
+        assert!(single < 0.2, "single pulse already clear: {:.3}", single);
+        assert!(avg > 0.25 && avg > 1.5 * single, "single {:.3}, averaged {:.3}", single, avg);
+    }
 }
@@ -0,0 +1,159 @@
+//! src/mods/meter.rs
+//! `--mode meter`: live mic and loopback levels, direct-path delay and echo-band SNR, with a
+//! plain-language hint at what to fix, for setting up a room before the first presence run.
+
+use anyhow::Result;
+use std::{
+    io::{ self, IsTerminal, Write },
+    sync::{ atomic::{ AtomicBool, Ordering }, Arc },
+    thread,
+    time::Duration,
+};
+
+use crate::audio;
+use crate::logger::Logger;
+use crate::mods::presence::{ FramePairer, Pairing };
+use crate::{ prescan, sonar_presence, Config, SharedBuf };
+
+/// Redraw interval; levels are measured over the same span.
+const UPDATE: Duration = Duration::from_millis(500);
+/// Below this a stream counts as silent.
+const SILENT_DBFS: f32 = -60.0;
+/// Quieter than this the mic is unlikely to pick up echoes.
+const QUIET_MIC_DBFS: f32 = -50.0;
+/// Louder than this the mic is close to clipping.
+const HOT_MIC_DBFS: f32 = -3.0;
+/// Correlation at the direct path below which the mic does not hear the speaker.
+const DIRECT_MIN_R: f32 = 0.1;
+/// Echo-band SNR below which echoes are hard to tell from noise.
+const LOW_SNR_DB: f32 = 6.0;
+
+/// One meter update.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Reading {
+    pub mic_dbfs: f32,
+    pub ref_dbfs: f32,
+    pub direct: Option<(f32, f32)>, // (delay ms after the loopback, correlation there)
+    pub snr_db: Option<f32>, // echo peak over the echo band's median
+}
+
+impl Reading {
+    /// What to fix first, or that the setup looks usable.
+    pub fn advice(&self) -> &'static str {
+        if self.ref_dbfs < SILENT_DBFS {
+            return "no loopback signal — is audio playing?";
+        }
+        if self.mic_dbfs < SILENT_DBFS {
+            return "no mic signal — is the right recording device the default, and unmuted?";
+        }
+        if self.mic_dbfs < QUIET_MIC_DBFS {
+            return "mic too quiet — raise its input level or turn the speaker up";
+        }
+        if self.mic_dbfs > HOT_MIC_DBFS {
+            return "mic clipping — lower its input level";
+        }
+        match (self.direct, self.snr_db) {
+            (None, _) => "no correlation yet — keep audio playing",
+            (Some((_, r)), _) if r.abs() < DIRECT_MIN_R =>
+                "mic does not hear the speaker — move them closer, and switch off noise suppression/echo cancellation",
+            (_, Some(snr)) if snr < LOW_SNR_DB => "weak echoes — turn the volume up or reduce background noise",
+            _ => "levels look good",
+        }
+    }
+
+    /// `mic -42 dBFS  loopback -18 dBFS  direct 12.3 ms (r 0.45)  SNR 14 dB  → levels look good`
+    pub fn line(&self) -> String {
+        let direct = match self.direct {
+            Some((ms, r)) => format!("direct {:.1} ms (r {:.2})", ms, r),
+            None => "direct --".to_string(),
+        };
+        let snr = self.snr_db.map(|s| format!("SNR {:.0} dB", s)).unwrap_or_else(|| "SNR --".to_string());
+        format!(
+            "mic {:>4.0} dBFS  loopback {:>4.0} dBFS  {}  {}  → {}",
+            self.mic_dbfs,
+            self.ref_dbfs,
+            direct,
+            snr,
+            self.advice()
+        )
+    }
+}
+
+fn dbfs(buf: &SharedBuf) -> f32 {
+    let n = ((buf.sr as f64) * UPDATE.as_secs_f64()) as usize;
+    let rms = buf.latest(n).map(|x| prescan::rms(&x)).unwrap_or(0.0);
+    20.0 * rms.max(1e-6).log10()
+}
+
+pub fn run_meter(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    logger.info("sonar-presence meter starting… (Ctrl+C to stop)")?;
+    let quit = Arc::new(AtomicBool::new(false));
+    {
+        let q = quit.clone();
+        let _ = ctrlc::set_handler(move || {
+            q.store(true, Ordering::SeqCst);
+        });
+    }
+
+    let (mut mic, mut reference) = audio::sources_from_config(cli, cli.tick_ms)?;
+    let shared_mic = audio::capture(mic.as_mut(), Some(48_000), logger.clone())?;
+    reference.hear(&shared_mic);
+    let (shared_ref, _) = audio::capture_as(reference.as_mut(), shared_mic.sr as u32, logger.clone())?;
+    let len = sonar_presence::analysis_len(shared_mic.sr, cli.front_max_m);
+    let mut frames = FramePairer::new(len, cli);
+
+    // in place on a terminal, one line per update when piped
+    let redraw = io::stdout().is_terminal();
+    let mut advice = "";
+    while !quit.load(Ordering::SeqCst) && !reference.finished() {
+        thread::sleep(UPDATE);
+        let mut reading = Reading { mic_dbfs: dbfs(&shared_mic), ref_dbfs: dbfs(&shared_ref), ..Reading::default() };
+        if frames.pair(&shared_mic, &shared_ref, &logger) == Pairing::Ready {
+            let measured = sonar_presence::estimate_from_ref(&frames.reference, &frames.mic, shared_mic.sr, cli, false, None);
+            if let Some(m) = measured {
+                reading.direct = Some((((m.direct_lag as f32) / shared_mic.sr) * 1000.0, m.direct_r));
+                reading.snr_db = Some(m.snr_db);
+            }
+        }
+
+        let mut out = io::stdout().lock();
+        if redraw {
+            let _ = write!(out, "\r\x1b[2K{}", reading.line());
+            let _ = out.flush();
+        } else {
+            let _ = writeln!(out, "{}", reading.line());
+        }
+        if reading.advice() != advice {
+            advice = reading.advice();
+            let _ = logger.info(&format!("meter: {}", reading.line()));
+        }
+    }
+    if redraw {
+        println!();
+    }
+    logger.info("sonar-presence meter stopped.")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advice_names_the_first_problem() {
+        let good = Reading { mic_dbfs: -30.0, ref_dbfs: -20.0, direct: Some((12.3, 0.45)), snr_db: Some(14.0) };
+        assert_eq!(good.advice(), "levels look good");
+        assert_eq!(
+            good.line(),
+            "mic  -30 dBFS  loopback  -20 dBFS  direct 12.3 ms (r 0.45)  SNR 14 dB  → levels look good"
+        );
+        // silence on both: the loopback is reported first
+        let silent = Reading { mic_dbfs: -90.0, ref_dbfs: -90.0, ..good };
+        assert!(silent.advice().starts_with("no loopback signal"));
+        assert!(Reading { mic_dbfs: -55.0, ..good }.advice().starts_with("mic too quiet"));
+        assert!(Reading { mic_dbfs: -1.0, ..good }.advice().starts_with("mic clipping"));
+        assert!(Reading { direct: Some((12.3, 0.02)), ..good }.advice().starts_with("mic does not hear"));
+        assert!(Reading { snr_db: Some(3.0), ..good }.advice().starts_with("weak echoes"));
+        assert!(Reading { direct: None, snr_db: None, ..good }.line().contains("direct --  SNR --"));
+    }
+}
@@ -3,4 +3,16 @@ pub mod scan;
 pub mod offline;
 pub mod gated;
 pub mod enrich;
-pub mod impulse;
\ No newline at end of file
+pub mod impulse;
+pub mod replay;
+pub mod play;
+pub mod report;
+pub mod selftest;
+pub mod meter;
+pub mod eval;
+pub mod tune;
+
+pub mod node;
+pub mod hub;
+pub mod engine;
+pub mod auto;
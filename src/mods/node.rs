@@ -0,0 +1,62 @@
+//! src/mods/node.rs
+//! `--mode node`: a sensor node. Measures the echoes around this machine's own speaker and mic
+//! and sends each tick's measurement to the presence instance at `--server`.
+
+use anyhow::Result;
+use std::{
+    sync::{ atomic::{ AtomicBool, Ordering }, Arc },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::audio;
+use crate::logger::Logger;
+use crate::mods::presence::{ FramePairer, Pairing };
+use crate::node::{ Hello, NodeLink, NodeTick };
+use crate::{ prescan, sonar_presence, strategy, Config };
+
+/// Frames whose loopback is below `--min-ref-rms` are not correlated; their levels are still sent.
+pub fn run_node(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    let to = if cli.server.is_empty() { "whatever answers on mDNS" } else { &cli.server };
+    logger.info(&format!("sonar-presence node '{}' starting… sending to {}", cli.node_name, to))?;
+    let quit = Arc::new(AtomicBool::new(false));
+    {
+        let q = quit.clone();
+        let _ = ctrlc::set_handler(move || {
+            q.store(true, Ordering::SeqCst);
+        });
+    }
+
+    let (mut mic, mut reference) = audio::sources_from_config(cli, cli.tick_ms)?;
+    let shared_mic = audio::capture(mic.as_mut(), Some(48_000), logger.clone())?;
+    reference.hear(&shared_mic);
+    let (shared_ref, _) = audio::capture_as(reference.as_mut(), shared_mic.sr as u32, logger.clone())?;
+    let sr = shared_mic.sr;
+    let mut frames = FramePairer::new(sonar_presence::analysis_len(sr, cli.front_max_m), cli);
+    let mut detector = strategy::detector(cli);
+    let hello = Hello { node: cli.node_name.clone(), tick_ms: cli.tick_ms, token: cli.pair_token.clone() };
+    let mut link = NodeLink::new(&cli.server, hello, logger.clone());
+
+    let t_run = Instant::now();
+    let mut next = t_run;
+    while !quit.load(Ordering::SeqCst) && !reference.finished() {
+        next += Duration::from_millis(cli.tick_ms);
+        if frames.pair(&shared_mic, &shared_ref, &logger) == Pairing::Ready {
+            let rms = (prescan::rms(&frames.reference), prescan::rms(&frames.mic));
+            let estimate = if rms.0 < cli.min_ref_rms {
+                None
+            } else {
+                detector.process_tick(&frames.reference, &frames.mic, sr, cli, Some(&logger)).map(|m| m.pair())
+            };
+            link.send(&NodeTick { t_s: t_run.elapsed().as_secs_f64(), rms, estimate });
+        }
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        } else {
+            next = now;
+        }
+    }
+    logger.info("sonar-presence node stopped.")?;
+    Ok(())
+}
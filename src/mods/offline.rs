@@ -1,45 +1,10 @@
 use anyhow::Result;
 use std::{
-    fs::OpenOptions,
-    io::Write,
     path::Path,
     sync::Arc,
 };
 
-use crate::{logger::Logger, prescan, decode};
-
-/// tiny hex encoder so this file is standalone
-fn to_hex(bytes: &[u8]) -> String {
-    let mut s = String::with_capacity(bytes.len() * 2);
-    for b in bytes {
-        s.push_str(&format!("{:02x}", b));
-    }
-    s
-}
-
-/// simple linear resampler (mono)
-fn resample_linear_mono(x: &[f32], sr_in: u32, sr_out: u32) -> Vec<f32> {
-    if x.is_empty() || sr_in == 0 || sr_out == 0 || sr_in == sr_out {
-        return x.to_vec();
-    }
-    let ratio = (sr_out as f64) / (sr_in as f64);
-    let n_out = ((x.len() as f64) * ratio).floor().max(1.0) as usize;
-    let mut y = Vec::with_capacity(n_out);
-
-    for i in 0..n_out {
-        let pos = (i as f64) / ratio; // position in input
-        let i0 = pos.floor() as usize;
-        if i0 + 1 >= x.len() {
-            y.push(*x.last().unwrap());
-        } else {
-            let t = (pos - (i0 as f64)) as f32; // frac
-            let a = x[i0];
-            let b = x[i0 + 1];
-            y.push(a + (b - a) * t); // lerp
-        }
-    }
-    y
-}
+use crate::{logger::Logger, fpdb, prescan, decode, scanfeatures, scanscore, songscan};
 
 /// Offline mode — analyze a local audio file directly (WAV/MP3/MP4/M4A)
 /// Writes rows to `SongScan.csv` (path from CLI).
@@ -66,37 +31,58 @@ pub fn run_offline(
     }
 
     logger.info(&format!("Decoding: {}", path.display()))?;
-    let audio = decode::load_first_channel(path)?;
-    logger.info(&format!(
-        "Decoded: sr={} Hz, channels={}, samples(mono)={}",
-        audio.sr, audio.channels, audio.samples_mono.len()
-    ))?;
+    let mut stream = decode::open_mono(path, cli.channel_mix)?;
+    logger.info(&format!("Input: sr={} Hz, channels={}", stream.sr, stream.channels))?;
+    let native_sr = stream.sr;
+
+    // optional time range; results keep file-relative timestamps
+    let start_s = cli.offline_start_s;
+    if start_s > 0.0 {
+        stream.seek(start_s as f64)?;
+    }
+    let mut remaining = if cli.offline_duration_s > 0.0 {
+        ((cli.offline_duration_s as f64) * (native_sr as f64)).round() as usize
+    } else {
+        usize::MAX
+    };
+    if start_s > 0.0 || cli.offline_duration_s > 0.0 {
+        logger.info(
+            &format!(
+                "Range: from {:.3}s, {}",
+                start_s,
+                if cli.offline_duration_s > 0.0 {
+                    format!("{:.3}s", cli.offline_duration_s)
+                } else {
+                    "to the end".to_string()
+                }
+            )
+        )?;
+    }
 
     // choose target SR (0 => keep native, else force e.g. 48000)
     let target_sr: u32 = if cli.offline_sample_rate_hz == 0 {
-        audio.sr
+        native_sr
     } else {
         cli.offline_sample_rate_hz
     };
 
     // resample if needed
-    let samples_mono: Vec<f32> = if audio.sr != target_sr {
-        logger.info(&format!("Resampling offline audio: {} Hz → {} Hz", audio.sr, target_sr))?;
-        resample_linear_mono(&audio.samples_mono, audio.sr, target_sr)
+    let mut resampler = if native_sr != target_sr {
+        logger.info(
+            &format!(
+                "Resampling offline audio: {} Hz → {} Hz ({})",
+                native_sr,
+                target_sr,
+                cli.resample_quality.as_str()
+            )
+        )?;
+        Some(decode::Resampler::new(native_sr, target_sr, cli.resample_quality))
     } else {
-        audio.samples_mono.clone()
+        None
     };
 
     // CSV path for scan results
     let csv_path = Path::new(&cli.scansong_path);
-    let mut csv_file = OpenOptions::new().create(true).append(true).open(csv_path)?;
-    if csv_file.metadata()?.len() == 0 {
-        writeln!(
-            csv_file,
-            "url,start_s,end_s,score,frame_ms,window_s,stride_s,bandwidth_z,flatness_z,flux_z,crest_db,hf_ratio,dynrange_z,tonality_z,loudness_dbfs,notes,fp_type,fp_bands,fp_hop_s,fp_offset_s,fp_bins_hex"
-        )?;
-        csv_file.flush()?;
-    }
 
     // Build scan params (on target SR)
     let params = prescan::ScanParams {
@@ -111,20 +97,66 @@ pub fn run_offline(
         merge_gap_s: cli.merge_gap_s,
         clamp_min_s: cli.clamp_min_s,
         clamp_max_s: cli.clamp_max_s,
+        scorer: scanscore::scorer(cli, &logger)?,
     };
 
+    // Decode → resample → analyse packet by packet; only the current fingerprint window is buffered
+    let mut analyzer = prescan::Analyzer::new(&params);
+    if !cli.export_spectrogram.is_empty() {
+        analyzer.keep_spectrogram();
+    }
+    let mut fingerprinter = prescan::Fingerprinter::new(params.sr, cli.fp_win_s, cli.fp_type, cli.fp_every_s);
+    let mut feed = |chunk: &[f32]| {
+        fingerprinter.push(chunk);
+        analyzer.push(chunk);
+    };
+    for chunk in stream {
+        let mut chunk = chunk?;
+        chunk.truncate(remaining);
+        remaining -= chunk.len();
+        match resampler.as_mut() {
+            Some(r) => feed(&r.process(&chunk)),
+            None => feed(&chunk),
+        }
+        if remaining == 0 {
+            break;
+        }
+    }
+    if let Some(r) = resampler.as_mut() {
+        feed(&r.flush());
+    }
+
     logger.info(&format!(
-        "Analyzing {:.1} seconds of audio…",
-        (samples_mono.len() as f32) / (target_sr as f32)
+        "Analyzed {:.1} seconds of audio",
+        (analyzer.samples_seen() as f32) / (target_sr as f32)
     ))?;
 
-    // Fingerprint first ~N seconds (on the resampled grid)
-    let fp = prescan::make_fingerprint(&samples_mono, params.sr, cli.fp_win_s);
+    // Fingerprints of the lead-in and every --fp-every-s (on the resampled grid)
+    let mut fps = fingerprinter.finish();
 
-    let segs = prescan::analyze(&samples_mono, &params);
-    if segs.is_empty() {
-        logger.info("No candidate segments found (audio too short or too quiet).")?;
-        return Ok(());
+    let spectrogram = analyzer.take_spectrogram();
+    let (mut segs, mut wins) = analyzer.finish_with_windows();
+
+    // analysis ran on the slice; shift back onto the file's timeline
+    if start_s > 0.0 {
+        for s in segs.iter_mut() {
+            s.start_s += start_s;
+            s.end_s += start_s;
+            s.peak.start_s += start_s;
+            s.peak.end_s += start_s;
+        }
+        for f in fps.iter_mut() {
+            f.offset_s += start_s;
+        }
+        for w in wins.iter_mut() {
+            w.start_s += start_s;
+            w.end_s += start_s;
+        }
+    }
+
+    if let Some(spec) = &spectrogram {
+        spec.write(Path::new(&cli.export_spectrogram), start_s, &segs)?;
+        logger.info(&format!("Wrote the spectrogram ({} frames) to {}", spec.frames(), cli.export_spectrogram))?;
     }
 
     // Tag column: use --scan-url if provided, else file:// path
@@ -134,42 +166,28 @@ pub fn run_offline(
         format!("file://{}", path.display())
     };
 
-    for s in &segs {
-        let w = &s.peak;
-        let (fp_type, fp_bands, fp_hop_s, fp_offset_s, fp_bins_hex) = if let Some(ref f) = fp {
-            (f.fp_type.as_str(), f.bands as u32, f.hop_s, f.offset_s, to_hex(&f.bins))
-        } else {
-            ("", 0, 0.0, 0.0, String::new())
-        };
-        writeln!(
-            csv_file,
-            "{},{:.3},{:.3},{:.3},{:.0},{:.1},{:.1},{:.2},{:.2},{:.2},{:.1},{:.3},{:.2},{:.2},{:.1},{}\
-            ,{},{},{:.5},{:.3},{}",
-            &tag,
-            s.start_s,
-            s.end_s,
-            w.score,
-            params.frame_ms,
-            params.window_s,
-            params.stride_ms / 1000.0,
-            w.z.bandwidth_z,
-            w.z.flatness_z,
-            w.z.flux_z,
-            w.crest_db,
-            w.hf_ratio,
-            w.z.dynrange_z,
-            w.z.tonality_z,
-            w.loudness_dbfs,
-            "\"\"",
-            fp_type,
-            fp_bands,
-            fp_hop_s,
-            fp_offset_s,
-            fp_bins_hex
-        )?;
+    if !cli.export_features.is_empty() {
+        scanfeatures::write(Path::new(&cli.export_features), &tag, &params, &wins, &segs)?;
+        logger.info(&format!("Wrote the features of {} window(s) to {}", wins.len(), cli.export_features))?;
+    }
+    if segs.is_empty() {
+        logger.info("No candidate segments found (audio too short or too quiet).")?;
+        return Ok(());
+    }
+
+    let replaced = songscan::store_segments(csv_path, &tag, &segs, &params, &fps, cli.scan_append)?;
+    if replaced > 0 {
+        logger.info(&format!("Replaced {} earlier row(s) for {}", replaced, tag))?;
     }
-    csv_file.flush()?;
 
-    logger.info(&format!("Wrote {} segment(s) to {}", segs.len(), csv_path.display()))?;
+    logger.info(
+        &format!(
+            "Wrote {} segment(s) and {} fingerprint(s) to {}",
+            segs.len(),
+            fps.len(),
+            csv_path.display()
+        )
+    )?;
+    fpdb::store_track(cli, &tag, &segs, &fps, &logger)?;
     Ok(())
 }
@@ -0,0 +1,25 @@
+use anyhow::Result;
+use std::{ path::Path, sync::Arc };
+
+use crate::audio::{ CpalMic, FilePlayer };
+use crate::logger::Logger;
+use crate::mods::presence;
+use crate::{ Config, ScanMeta };
+
+/// Play mode: play `--input` on the default output and run presence detection against it,
+/// with the file itself as the reference instead of the loopback. Stops when the file ends.
+pub fn run_play(cli: &Config, meta: &ScanMeta, logger: Arc<Logger>) -> Result<()> {
+    if meta.input_path.is_empty() {
+        anyhow::bail!("--input <PATH> is required in play mode");
+    }
+    let input = Path::new(&meta.input_path);
+    if !input.exists() {
+        anyhow::bail!("Input file does not exist: {}", meta.input_path);
+    }
+
+    let player = FilePlayer::from_file(input, cli.channel_mix, cli.resample_quality)?;
+    logger.info(&format!("Playing {} ({:.1}s)", meta.input_path, player.duration_s()))?;
+    println!("Playing {} ({:.1}s); Ctrl+C to stop", meta.input_path, player.duration_s());
+
+    presence::run_presence_with(cli, logger, &cli.log_path, Box::new(CpalMic::new(cli.channel_mix).keep_stereo(cli.bearing)), Box::new(player))
+}
@@ -1,32 +1,46 @@
 use anyhow::Result;
-use cpal::traits::{ DeviceTrait, HostTrait, StreamTrait };
-use crossbeam_channel::bounded;
 use std::{
-    fs::OpenOptions,
-    io::Write,
-    path::Path,
-    sync::{ atomic::{ AtomicBool, Ordering }, Arc, Mutex },
-    thread,
+    borrow::Cow,
+    collections::VecDeque,
+    sync::Arc,
     time::{ Duration, Instant },
 };
 
-use crate::{
-    audio_sink_thread,
-    build_input_stream,
-    maybe_rate_supported,
-    sonar_presence,
-    wasapi_loopback,
-    SharedBuf,
-    Config,
-};
-use crate::logger::Logger;
-
-#[cfg(target_os = "windows")]
-use crate::{ start_probe, ENABLE_PROBE_TONE };
+use crate::{ prescan, sonar_presence, Config, SharedBuf };
+use crate::sonar_presence::PresenceState;
+use crate::audio::{ self, AudioSource };
+use crate::logger::{ Field, LogLevel, Logger };
+use crate::output;
+use crate::hooks::HookEvent;
+use crate::recorder::TickMeta;
+use crate::power::PowerSave;
+use crate::pingsched;
+use crate::strategy;
+use crate::correlator::FramePos;
+use crate::resume::{ Resume, Snapshot };
+use crate::calibration::Calibration;
+use crate::onnx::Model;
+use crate::script::EventScript;
+use crate::features::{ self, FeatureTable, Features };
+use crate::devcal::{ DeviceCal, DeviceStore };
+use crate::node::NodeHub;
+use crate::mods::engine::PresenceEngine;
 
 /// Presence mode: ref↔mic correlation with sliding aggregator.
 /// Writes state changes to `Detection.csv` next to the configured log file.
 pub fn run_presence(cli: &Config, logger: Arc<Logger>, log_path: &str) -> Result<()> {
+    let (mic, reference) = audio::sources_from_config(cli, cli.tick_ms)?;
+    run_presence_with(cli, logger, log_path, mic, reference)
+}
+
+/// Presence mode on arbitrary capture sources.
+pub fn run_presence_with(
+    cli: &Config,
+    logger: Arc<Logger>,
+    log_path: &str,
+    mic: Box<dyn AudioSource>,
+    reference: Box<dyn AudioSource>
+) -> Result<()> {
     logger.info(
         &format!(
             "sonar-presence (ref↔mic, WASAPI loopback) starting…  tick_ms={}  agg_frac={:.2}  window_sec={}",
@@ -36,246 +50,729 @@ pub fn run_presence(cli: &Config, logger: Arc<Logger>, log_path: &str) -> Result
         )
     )?;
 
-    // CSV path sits beside the log file.
-    let csv_path = {
-        let p = Path::new(log_path);
-        let dir = p.parent().ok_or_else(|| anyhow::anyhow!("Log path has no parent"))?;
-        dir.join("Detection.csv")
-    };
-    let mut csv_file = OpenOptions::new().create(true).append(true).open(&csv_path)?;
-    if csv_file.metadata()?.len() == 0 {
-        writeln!(csv_file, "timestamp,present,avg_distance_m,avg_strength,agree_pct")?;
-        csv_file.flush()?;
+    let mut engine = PresenceEngine::setup(cli, "presence", log_path, mic, reference, logger.clone())?;
+    if cli.bearing && engine.stereo.is_some() {
+        logger.info(&format!("Bearing from both mic channels, {:.2} m apart", cli.mic_spacing_m))?;
     }
-
-    // ctrl+c to quit
-    let quit = Arc::new(AtomicBool::new(false));
-    {
-        let q = quit.clone();
-        let _ = ctrlc::set_handler(move || {
-            q.store(true, Ordering::SeqCst);
-        });
+    let sr_used = engine.sr;
+    let analysis_len = engine.analysis_len;
+    let mut script = EventScript::start(cli, "presence", logger.clone())?;
+    let mut feature_table = FeatureTable::open(cli, logger.clone())?;
+
+    // --detector measures each tick, --policy turns the votes into the smoothed presence state
+    let mut det = PresenceDetector::new(cli);
+    det.probe = pingsched::correlation_band(cli, &logger)?;
+    det.calibration = Calibration::from_config(cli, &logger)?;
+    det.model = Model::from_config(cli, &logger)?;
+    logger.info(&format!("detector: {}, policy: {}", det.detector.name(), det.policy.name()))?;
+    // the delay, drift and empty-room echoes learned about this render/capture pair before
+    let mut device_store = DeviceStore::open(cli, engine.reference.device(), engine.mic.device(), logger.clone()).map(|(store, cal)| {
+        det.use_device_cal(cal, sr_used);
+        store
+    });
+
+    // --resume-grace-s: a restart carries on from the saved state
+    let mut resume = Resume::from_config(cli, "presence", logger.clone());
+    if let Some(snap) = resume.as_ref().and_then(Resume::load) {
+        det.restore(&snap, Instant::now());
     }
+    let mut held: Option<Snapshot> = None; // the last full window, saved as the run goes
+
+    let mut power_save = PowerSave::from_config(cli, engine.t_run);
+    // --node-listen: sensor nodes' echoes vote alongside the local one
+    let mut nodes = NodeHub::start(cli, logger.clone())?;
+    while engine.running() {
+        let tick_len = power_save.as_ref().map_or(Duration::from_millis(cli.tick_ms), PowerSave::tick);
+        // --power-save: an idle tick only needs the echo range after the (locked) direct path
+        let frame_len = match &power_save {
+            Some(ps) if ps.idle() =>
+                sonar_presence::frame_len(sr_used, engine.live.front_max_m, det.detector.direct_lags(&engine.live, sr_used).1),
+            _ => analysis_len,
+        };
+        engine.frames.set_len(frame_len);
 
-    // === microphone (cpal) ===
-    let host = cpal::default_host();
-    let mic_device = host
-        .default_input_device()
-        .ok_or_else(|| anyhow::anyhow!("No default input device (microphone) found"))?;
-    let mut mic_config = mic_device.default_input_config()?.config();
+        // a played file (--mode play) ends the run when it has been played out
+        if engine.reference.finished() {
+            logger.info(&format!("{} finished", engine.reference.describe()))?;
+            break;
+        }
 
-    // Prefer 48 kHz if available.
-    if let Some(sr) = maybe_rate_supported(&mic_device, 48_000) {
-        mic_config.sample_rate.0 = sr;
-    }
-    let sr_mic = mic_config.sample_rate.0 as f32;
+        let streams = engine.begin_tick(tick_len, det.policy.present());
+        if streams.reopened.is_some() || streams.woke.is_some() {
+            det.restart(cli);
+        }
+        if let Some(st) = streams.idle {
+            engine.control.set_status(st);
+            engine.idle(det.policy.present());
+            continue;
+        }
+        if !streams.restarted.is_empty() {
+            // a reopened device comes with its own delay
+            det.detector.restart();
+        }
+        // the stereo rings no longer line up with the restarted mic's mono ring
+        if streams.restarted.contains(&0) && engine.stereo.take().is_some() {
+            logger.warn("--bearing is off for the rest of the run after the mic restart")?;
+        }
 
-    logger.info(&format!("Mic device: {}", mic_device.name().unwrap_or_default()))?;
-    logger.info(
-        &format!(
-            "Mic: sample rate {} Hz, channels {}",
-            mic_config.sample_rate.0,
-            mic_config.channels
-        )
-    )?;
+        if engine.follow_control(det.policy.as_mut()) {
+            det.policy.clear();
+            let _ = logger.info("recalibrate: agreement window cleared");
+        }
+        if engine.paused(det.policy.present()) {
+            continue;
+        }
 
-    let shared_mic = SharedBuf {
-        buf: Arc::new(Mutex::new(Vec::with_capacity((sr_mic as usize) * 10))),
-        sr: Arc::new(Mutex::new(sr_mic)),
-    };
+        engine.observe_fill();
+
+        let mut meta = TickMeta::default();
+        engine.frames.ref_shift = det.drift.shift();
+        let now = engine.now();
+        let frames = &mut engine.frames;
+        let pairing = frames.pair(&engine.shared_mic, &engine.shared_ref, &logger);
+        if pairing == Pairing::Ready {
+            let t_corr = Instant::now();
+            let pair = match &engine.stereo {
+                Some((left, right)) if frames.pair_stereo(left, right) => Some((&frames.left[..], &frames.right[..])),
+                _ => None,
+            };
+            det.frames_at = Some(frames.positions());
+            det.remote = nodes.as_mut().and_then(|h| h.take_vote(&engine.live));
+            let tick = det.tick_stereo(&frames.reference, &frames.mic, pair, sr_used, &engine.live, now, Some(&logger));
+            engine.exporter.metrics.observe_correlation(t_corr.elapsed().as_secs_f64());
+            meta = tick.meta();
+            if tick.voted {
+                engine.exporter.metrics.detections.inc();
+            }
 
-    let (tx_mic, rx_mic) = bounded::<Vec<f32>>(8);
-    let mic_channels = mic_config.channels.max(1) as usize;
+            // --event-script may rewrite or drop the window before anything reports it
+            let window = tick.window.and_then(|mut w| {
+                let passed = script.as_mut().is_none_or(|s| s.filter(&mut w));
+                passed.then_some(w)
+            });
+            if let Some(w) = window {
+                if w.state_changed {
+                    let _ = logger.event(
+                        &format!("state_change -> present={} state={}", w.state.present(), w.state.as_str()),
+                        &[
+                            ("present", Field::Bool(w.state.present())),
+                            ("state", Field::Str(w.state.as_str())),
+                            ("avg_distance_m", Field::Num(w.avg_d)),
+                        ]
+                    );
 
-    let mic_stream = build_input_stream(
-        &mic_device,
-        &mic_config,
-        mic_channels,
-        tx_mic,
-        logger.clone()
-    )?;
-    mic_stream.play()?;
+                    // CSV on state change
+                    let _ = output::write_detection_row(&mut engine.csv, w.state, w.avg_d, w.avg_s, w.agree, w.iqr_d, w.bearing_deg, w.quality, w.probability, None);
+                }
+                if w.flipped {
+                    engine.flipped(HookEvent {
+                        present: w.state.present(),
+                        state: w.state,
+                        distance_m: w.avg_d,
+                        strength: w.avg_s,
+                        agree: w.agree,
+                        probability: w.probability,
+                    });
+                }
+
+                engine.exporter.observe_window(w.state.present(), w.avg_d, w.avg_s, w.agree);
+                engine.control.set_status(detector_status(w.state.present(), &w));
+                log_window(&logger, w.state.present(), &w, cli.window_sec, tick.estimate.is_none());
+                engine.status.update(w.state.present(), w.avg_d, w.probability.unwrap_or(w.agree));
+                let snap = held.insert(Snapshot { state: w.state, agree: w.agree, avg_d: w.avg_d, avg_s: w.avg_s, aligned: None });
+                if let Some(r) = resume.as_mut() {
+                    r.save(snap, false);
+                }
+            }
+        } else if pairing == Pairing::Filling {
+            det.policy.age();
+        }
+
+        let meta = TickMeta { present: det.policy.present(), state: det.activity.state(), ..meta };
+        if let Some(ps) = power_save.as_mut() {
+            if let Some(tick) = ps.update(meta.vote, meta.present, engine.now()) {
+                let _ = if ps.idle() {
+                    logger.info(&format!("power save: nobody there, ticking every {} ms", tick.as_millis()))
+                } else {
+                    logger.info(&format!("power save: someone there, back to {} ms ticks", tick.as_millis()))
+                };
+            }
+        }
+        if let Some(table) = feature_table.as_mut() {
+            table.tick(engine.elapsed().as_secs_f64(), &meta);
+        }
+        engine.record(&meta);
+
+        if let (Some(store), Some(cal)) = (device_store.as_mut(), det.device_cal(sr_used)) {
+            store.save(cal, false);
+        }
+        engine.end_tick(Some(script.as_ref().and_then(EventScript::present).unwrap_or(det.policy.present())));
+    }
 
-    {
-        let shared_clone = shared_mic.clone();
-        thread::spawn(move || audio_sink_thread(rx_mic, shared_clone));
+    if let (Some(r), Some(snap)) = (resume.as_mut(), &held) {
+        r.save(snap, true);
+    }
+    if let (Some(store), Some(cal)) = (device_store.as_mut(), det.device_cal(sr_used)) {
+        store.save(cal, true);
     }
+    engine.shutdown("sonar-presence")
+}
 
-    // === loopback (render reference) ===
-    let sr_target = sr_mic as u32;
+/// One full agreement window, produced once the aggregator has filled.
+#[derive(Clone, Copy, Debug)]
+pub struct WindowState {
+    pub flipped: bool, // smoothed state changed on this tick
+    pub state: PresenceState, // the smoothed state, present split into idle/active
+    pub state_changed: bool, // ...changed on this tick (flipped, or idle/active)
+    pub avg_d: f64,
+    pub avg_s: f64,
+    pub agree: f32,
+    pub iqr_d: f64, // spread of the window's vote distances
+    pub bearing_deg: Option<f64>, // --bearing, while present: median of the recent echoes' bearings
+    pub quality: Option<sonar_presence::CorrQuality>, // of this tick's correlation, if it found an echo
+    pub probability: Option<f32>, // --calibration: the agreement as a probability of presence
+}
 
-    #[cfg(target_os = "windows")]
-    let _probe_stream = if ENABLE_PROBE_TONE { start_probe(sr_target).ok() } else { None };
+/// Outcome of one analysis tick.
+#[derive(Clone, Copy, Debug)]
+pub struct TickResult {
+    pub estimate: Option<(f32, f32)>, // echo (distance_m, strength), if the correlation found one
+    pub voted: bool, // ...and it counted as a presence vote
+    pub window: Option<WindowState>,
+    pub rms: (f32, f32), // (ref, mic) of the correlated frames (band-limited with a probe band)
+    pub peak_sidelobe: Option<f32>,
+    pub snr_db: Option<f32>,
+    pub direct_r: Option<f32>,
+    pub bearing_deg: Option<f32>, // --bearing, on voted ticks with both mic channels
+    pub direct_lag: Option<i64>,
+    pub ref_shift: i64, // drift compensation the frames were read with
+    pub drift_ppm: Option<f32>,
+    pub features: Option<Features>, // --features
+}
 
-    let shared_ref = SharedBuf {
-        buf: Arc::new(Mutex::new(Vec::with_capacity((sr_target as usize) * 10))),
-        sr: Arc::new(Mutex::new(sr_mic)),
-    };
-    let rx_ref = wasapi_loopback::start(sr_target, logger.clone(), cli.tick_ms)?;
-    {
-        let shared_ref_clone = shared_ref.clone();
-        thread::spawn(move || audio_sink_thread(rx_ref, shared_ref_clone));
+impl TickResult {
+    /// The per-tick record `--record-session` and `--debug-dump` write (`present` is filled in later).
+    pub fn meta(&self) -> TickMeta {
+        TickMeta {
+            analysed: true,
+            estimate: self.estimate,
+            bearing_deg: self.bearing_deg,
+            rms: Some(self.rms),
+            peak_sidelobe: self.peak_sidelobe,
+            snr_db: self.snr_db,
+            direct_r: self.direct_r,
+            direct_lag: self.direct_lag,
+            ref_shift: self.ref_shift,
+            drift_ppm: self.drift_ppm,
+            vote: self.voted,
+            agree: self.window.map(|w| w.agree),
+            present: false,
+            state: PresenceState::Absent,
+            features: self.features,
+        }
     }
+}
 
-    // === analysis constants ===
-    let sr_used = *shared_mic.sr.lock().unwrap();
+/// `--detector` and `--policy` of one ref/mic stream pair, advanced once per tick.
+/// Live capture and `--mode replay` both go through this, so they make identical decisions.
+pub struct PresenceDetector {
+    pub detector: Box<dyn strategy::Detector>,
+    pub policy: Box<dyn strategy::DecisionPolicy>,
+    pub probe: Option<(f32, f32)>, // --corr-band / --ping-schedule band: correlate only this, only while it carries energy
+    pub drift: sonar_presence::DriftTracker, // read the reference `drift.shift()` samples earlier
+    pub template: Option<audio::ProbeTemplate>, // --probe-signal prbs: correlate the mic against the clean sequence
+    pub activity: sonar_presence::Activity, // idle/active while present
+    pub frames_at: Option<FramePos>, // where the next tick's frames were cut from the rings, if the caller knows
+    pub calibration: Option<Calibration>, // --calibration: agreement → probability of presence
+    pub features: Option<features::Extractor>, // --features, and the model's input
+    pub model: Option<Model>, // --detector onnx
+    pub device: Option<DeviceCal>, // device calibration: learns the empty room's echoes, holds votes to them
+    pub remote: Option<(f32, f32)>, // --node-listen: a sensor node's echo that counted this tick
+    bearings: VecDeque<f32>, // --bearing: degrees of the last window's worth of voted echoes
+    bearing_cap: usize,
+}
 
-    let c = 343.0_f32;
-    let echo_max = (((2.0 * cli.front_max_m) / c) * sr_used).ceil() as usize;
-    let base_max = (
-        ((sonar_presence::MAX_PIPELINE_DELAY_MS as f32) / 1000.0) *
-        sr_used
-    ).ceil() as usize;
-    let analysis_len = (base_max + echo_max + 1024).next_power_of_two().max(4096);
+impl PresenceDetector {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            detector: strategy::detector(cfg),
+            policy: strategy::policy(cfg, cfg.tick_ms),
+            probe: cfg.corr_band,
+            drift: sonar_presence::DriftTracker::new(cfg.drift_window_s),
+            template: audio::ProbeTemplate::from_config(cfg),
+            activity: sonar_presence::Activity::new(cfg),
+            frames_at: None,
+            calibration: None,
+            features: features::Extractor::from_config(cfg),
+            model: None,
+            device: None,
+            remote: None,
+            bearings: VecDeque::new(),
+            bearing_cap: sonar_presence::window_cap(cfg.window_sec, cfg.tick_ms),
+        }
+    }
 
-    logger.info(
-        &format!(
-            "Analysis window: {} samples (~{:.0} ms)",
-            analysis_len,
-            ((analysis_len as f32) / sr_used) * 1000.0
-        )
-    )?;
+    /// Start over after a gap in the audio (sleep, a session switch): an empty agreement
+    /// window, a new drift fit and a pipeline delay learned again. The smoothed state holds until the window has refilled.
+    pub fn restart(&mut self, cfg: &Config) {
+        self.detector.restart();
+        self.policy.clear();
+        self.drift = sonar_presence::DriftTracker::new(cfg.drift_window_s);
+        self.bearings.clear();
+        if let Some(f) = self.features.as_mut() {
+            f.restart();
+        }
+    }
+
+    /// Start from what an earlier run learned about the devices: locked to its pipeline delay,
+    /// following its drift, and with its empty-room echoes held to their own thresholds.
+    pub fn use_device_cal(&mut self, cal: DeviceCal, sr: f32) {
+        if let Some(ms) = cal.delay_ms {
+            self.detector.seed_delay(((ms / 1000.0) * sr).round() as i64);
+        }
+        if let Some(ppm) = cal.drift_ppm {
+            self.drift.seed(ppm);
+        }
+        self.device = Some(cal);
+    }
 
-    let mut agg = sonar_presence::Aggregator::new(cli.window_sec, cli.tick_ms, cli.agg_frac);
+    /// The device calibration with the delay and drift as they stand, to save.
+    pub fn device_cal(&mut self, sr: f32) -> Option<&DeviceCal> {
+        let cal = self.device.as_mut()?;
+        if let Some(lag) = self.detector.locked_delay() {
+            cal.delay_ms = Some(((lag as f32) / sr) * 1000.0);
+        }
+        if let Some(ppm) = self.drift.ppm() {
+            cal.drift_ppm = Some(ppm);
+        }
+        Some(cal)
+    }
 
-    // smoothed presence state with hysteresis+dwell
-    let mut smooth_present = false;
-    let mut last_flip = Instant::now() - Duration::from_millis(cli.min_dwell_ms);
+    /// Carry on from a saved state (`--resume-grace-s`): the policy holds it with a full window.
+    pub fn restore(&mut self, snap: &Snapshot, now: Instant) {
+        self.policy.restore(snap.state.present(), snap.agree, snap.vote());
+        self.activity.restore(snap.state, now);
+    }
 
-    let mut next = Instant::now();
-    while !quit.load(Ordering::SeqCst) {
-        next += Duration::from_millis(cli.tick_ms);
+    /// Correlate one frame pair and feed the result through the window and hysteresis.
+    pub fn tick(
+        &mut self,
+        ref_frame: &[f32],
+        mic_frame: &[f32],
+        sr: f32,
+        cfg: &Config,
+        now: Instant,
+        logger: Option<&Logger>
+    ) -> TickResult {
+        self.tick_stereo(ref_frame, mic_frame, None, sr, cfg, now, logger)
+    }
 
-        let mic_frame = {
-            let b = shared_mic.buf.lock().unwrap();
-            if b.len() < analysis_len {
-                Vec::new()
-            } else {
-                b[b.len() - analysis_len..].to_vec()
+    /// `tick`, plus the bearing of a voted echo when the mic's two channels are given (`--bearing`).
+    #[allow(clippy::too_many_arguments)]
+    pub fn tick_stereo(
+        &mut self,
+        ref_frame: &[f32],
+        mic_frame: &[f32],
+        pair: Option<(&[f32], &[f32])>,
+        sr: f32,
+        cfg: &Config,
+        now: Instant,
+        logger: Option<&Logger>
+    ) -> TickResult {
+        let at = self.frames_at.take();
+        // the prbs probe is in the loopback: its clean stretch stands in for the reference
+        let locked = self.template.as_mut().and_then(|t| Some((t.align(ref_frame, sr)?, t.band())));
+        let (measurement, rms, reference, band) = match (locked, self.probe) {
+            (Some((clean, (lo, hi))), _) => {
+                let mut m = mic_frame.to_vec();
+                sonar_presence::band_limit(&mut m, sr, lo, hi);
+                let rms = (prescan::rms(ref_frame), prescan::rms(&m));
+                (self.detector.process_tick(&clean, &m, sr, cfg, logger), rms, Cow::Owned(clean), Some((lo, hi)))
+            }
+            (None, None) => {
+                // only raw frames are cuts of the rings
+                if let Some(at) = at {
+                    self.detector.frames_at(at);
+                }
+                (
+                    self.detector.process_tick(ref_frame, mic_frame, sr, cfg, logger),
+                    (prescan::rms(ref_frame), prescan::rms(mic_frame)),
+                    Cow::Borrowed(ref_frame),
+                    None,
+                )
+            }
+            (None, Some((lo, hi))) => {
+                let (mut r, mut m) = (ref_frame.to_vec(), mic_frame.to_vec());
+                sonar_presence::band_limit(&mut r, sr, lo, hi);
+                sonar_presence::band_limit(&mut m, sr, lo, hi);
+                let rms = (prescan::rms(&r), prescan::rms(&m));
+                // no ping in the loopback right now: skip the tick rather than count it as absent
+                if rms.0 < cfg.min_ref_rms {
+                    return TickResult {
+                        estimate: None,
+                        voted: false,
+                        window: None,
+                        rms,
+                        peak_sidelobe: None,
+                        snr_db: None,
+                        direct_r: None,
+                        bearing_deg: None,
+                        direct_lag: None,
+                        ref_shift: self.drift.shift(),
+                        drift_ppm: self.drift.ppm().map(|p| p as f32),
+                        features: None,
+                    };
+                }
+                (self.detector.process_tick(&r, &m, sr, cfg, logger), rms, Cow::Owned(r), Some((lo, hi)))
             }
         };
-        let ref_frame = {
-            let b = shared_ref.buf.lock().unwrap();
-            if b.len() < analysis_len {
-                Vec::new()
-            } else {
-                b[b.len() - analysis_len..].to_vec()
+        let estimate = measurement.as_ref().map(sonar_presence::Measurement::pair);
+        let features = self.features.as_mut().map(|f| f.extract(&reference, mic_frame, sr, band, measurement.as_ref(), cfg));
+        let vote = match (&self.model, &measurement, &features) {
+            // --detector onnx: the model's probability decides in place of the thresholds
+            (Some(model), Some(m), Some(f)) =>
+                match model.probability(&features::model_input(rms, m, f)) {
+                    Ok(p) => estimate.filter(|_| p >= cfg.model_thr),
+                    Err(e) => {
+                        if let Some(log) = logger {
+                            let _ = log.debug(&format!("model: {:#}", e));
+                        }
+                        None
+                    }
+                }
+            (Some(_), _, _) => None,
+            (None, _, _) =>
+                estimate.filter(|&(d, s)| {
+                    let background = self.device.as_ref().and_then(|c| c.threshold_at(d));
+                    d <= cfg.dist_max_m && s >= cfg.strength_thr && background.is_none_or(|t| s >= t)
+                }),
+        };
+        // while nobody is there, the best echo is the room's own
+        if let (Some(cal), Some((d, s))) = (self.device.as_mut(), estimate) {
+            if !self.policy.present() {
+                cal.observe_absent(d, s);
             }
+        }
+
+        // second stage: where the voted echo lands in each channel
+        let bearing_deg = match (pair, &measurement) {
+            (Some((left, right)), Some(m)) if vote.is_some() =>
+                sonar_presence::echo_bearing(&reference, left, right, band, m.peak_lag, sr, cfg.mic_spacing_m),
+            _ => None,
         };
+        if let Some(b) = bearing_deg {
+            if self.bearings.len() == self.bearing_cap {
+                self.bearings.pop_front();
+            }
+            self.bearings.push_back(b);
+        }
+        // a sensor node's echo votes for the room when the local one does not
+        let vote = vote.or(self.remote.take());
+
+        // the frames were cut with the shift as it stood; the new lag may move it for the next tick
+        let ref_shift = self.drift.shift();
+        if let Some(m) = &measurement {
+            self.drift.observe(now, m.direct_lag, sr);
+            if let (Some(log), Some(ppm)) = (logger, self.drift.ppm()) {
+                if self.drift.shift() != ref_shift {
+                    let _ = log.debug(&format!("clock drift {:+.1} ppm: reference shifted {} samples", ppm, self.drift.shift()));
+                }
+            }
+        }
 
-        if mic_frame.len() == analysis_len && ref_frame.len() == analysis_len {
-            if
-                let Some((d, s)) = sonar_presence::estimate_from_ref(
-                    &ref_frame,
-                    &mic_frame,
-                    sr_used,
-                    cli,
-                    Some(&logger)
-                )
-            {
-                let present_instant = d <= cli.dist_max_m && s >= cli.strength_thr;
-                let vote = if present_instant { Some((d, s)) } else { None };
-
-                if let Some((_present_raw, avg_d, avg_s, agree)) = agg.push(vote) {
-                    let nowi = Instant::now();
-                    let want_present = if smooth_present {
-                        agree >= cli.exit_frac
-                    } else {
-                        agree >= cli.enter_frac
-                    };
+        // dwell/hysteresis even on quiet ticks
+        let decision = self.policy.push(vote, now);
+        let state_changed = decision.and_then(|d| self.activity.update(self.policy.present(), d.iqr_d, now)).is_some();
+        let window = decision.map(|d| WindowState {
+            flipped: d.flipped,
+            state: self.activity.state(),
+            state_changed,
+            avg_d: d.avg_d,
+            avg_s: d.avg_s,
+            agree: d.agree,
+            iqr_d: d.iqr_d,
+            bearing_deg: self.window_bearing().filter(|_| self.policy.present()),
+            quality: measurement.as_ref().map(sonar_presence::Measurement::quality),
+            probability: self.calibration.as_ref().map(|c| c.probability(d.agree)),
+        });
+        TickResult {
+            estimate,
+            voted: vote.is_some(),
+            window,
+            rms,
+            peak_sidelobe: measurement.as_ref().map(|m| m.peak_sidelobe),
+            snr_db: measurement.as_ref().map(|m| m.snr_db),
+            direct_r: measurement.as_ref().map(|m| m.direct_r),
+            bearing_deg,
+            direct_lag: measurement.as_ref().map(|m| m.direct_lag),
+            ref_shift,
+            drift_ppm: self.drift.ppm().map(|p| p as f32),
+            features,
+        }
+    }
 
-                    if
-                        want_present != smooth_present &&
-                        nowi.duration_since(last_flip) >= Duration::from_millis(cli.min_dwell_ms)
-                    {
-                        smooth_present = want_present;
-                        last_flip = nowi;
-
-                        // CSV on state change
-                        let ts = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-                        let _ = writeln!(
-                            csv_file,
-                            "{},{},{:.2},{:.2},{:.0}",
-                            ts,
-                            smooth_present,
-                            avg_d,
-                            avg_s,
-                            agree * 100.0
-                        );
-                        let _ = csv_file.flush();
-                    }
+    /// Median bearing of the recent voted echoes, once there are any.
+    fn window_bearing(&self) -> Option<f64> {
+        if self.bearings.is_empty() {
+            return None;
+        }
+        let mut b: Vec<f32> = self.bearings.iter().copied().collect();
+        b.sort_by(|x, y| x.total_cmp(y));
+        Some(b[b.len() / 2] as f64)
+    }
+}
 
-                    let _ = logger.info(
-                        &format!(
-                            "present={} avg_distance_m={:.2} avg_strength={:.2} window={}s agree={:.0}%",
-                            smooth_present,
-                            if smooth_present {
-                                avg_d
-                            } else {
-                                f64::INFINITY
-                            },
-                            avg_s,
-                            cli.window_sec,
-                            agree * 100.0
-                        )
-                    );
-                }
-            } else if let Some((_present_raw, avg_d, avg_s, agree)) = agg.push(None) {
-                // dwell/hysteresis even on quiet ticks
-                let nowi = Instant::now();
-                let want_present = if smooth_present {
-                    agree >= cli.exit_frac
-                } else {
-                    agree >= cli.enter_frac
-                };
+/// What `FramePairer::pair` could cut this tick.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pairing {
+    Ready, // `mic` and `reference` hold frames ending at the same moment
+    Filling, // a ring does not hold the frame yet
+    Skewed, // the streams' newest samples are more than --max-skew-ms apart: one has stalled
+}
 
-                if
-                    want_present != smooth_present &&
-                    nowi.duration_since(last_flip) >= Duration::from_millis(cli.min_dwell_ms)
-                {
-                    smooth_present = want_present;
-                    last_flip = nowi;
-
-                    let ts = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
-                    let _ = writeln!(
-                        csv_file,
-                        "{},{},{:.2},{:.2},{:.0}",
-                        ts,
-                        smooth_present,
-                        avg_d,
-                        avg_s,
-                        agree * 100.0
-                    );
-                    let _ = csv_file.flush();
-                }
+/// Cuts mic and reference frames that describe the same moment, by the arrival time of each
+/// ring's newest block: the stream that is ahead is read from further back. The frames are
+/// per-tick scratch, refilled in place.
+pub struct FramePairer {
+    len: usize,
+    max_skew: Duration,
+    skewed_since: Option<Instant>,
+    fresh_from: (u64, u64), // (mic, reference) ring positions frames may start at: nothing from before a restart
+    pub ref_shift: i64, // clock drift compensation: read the reference this many samples earlier (negative: the mic)
+    mic_start: u64, // where the last `mic` frame was cut
+    ref_start: u64, // and the last `reference` frame
+    pub mic: Vec<f32>,
+    pub reference: Vec<f32>,
+    pub left: Vec<f32>, // --bearing: the mic's channels over the same samples as `mic`
+    pub right: Vec<f32>,
+}
 
-                let _ = logger.info(
-                    &format!(
-                        "present={} avg_distance_m={:.2} avg_strength={:.2} window={}s agree={:.0}% (quiet/none)",
-                        smooth_present,
-                        if smooth_present {
-                            avg_d
-                        } else {
-                            f64::INFINITY
-                        },
-                        avg_s,
-                        cli.window_sec,
-                        agree * 100.0
-                    )
+impl FramePairer {
+    pub fn new(len: usize, cfg: &Config) -> Self {
+        Self {
+            len,
+            max_skew: Duration::from_millis(cfg.max_skew_ms),
+            skewed_since: None,
+            fresh_from: (0, 0),
+            ref_shift: 0,
+            mic_start: 0,
+            ref_start: 0,
+            mic: Vec::with_capacity(len),
+            reference: Vec::with_capacity(len),
+            left: Vec::new(),
+            right: Vec::new(),
+        }
+    }
+
+    pub fn pair(&mut self, mic: &SharedBuf, reference: &SharedBuf, logger: &Logger) -> Pairing {
+        let (mic_end, mic_at) = mic.head();
+        let (ref_end, ref_at) = reference.head();
+        let (at, skew) = if mic_at <= ref_at { (mic_at, ref_at - mic_at) } else { (ref_at, mic_at - ref_at) };
+
+        if skew > self.max_skew {
+            self.mic.clear();
+            self.reference.clear();
+            if self.skewed_since.is_none() {
+                let behind = if mic_at < ref_at { "mic" } else { "reference" };
+                let _ = logger.warn(
+                    &format!("{} stream {:.0} ms behind the other; skipping ticks until it catches up", behind, skew.as_secs_f64() * 1000.0)
                 );
+                self.skewed_since = Some(Instant::now());
             }
-        } else {
-            let _ = agg.push(None);
+            return Pairing::Skewed;
+        }
+        if let Some(since) = self.skewed_since.take() {
+            let _ = logger.info(&format!("mic and reference back in step after {:.1}s", since.elapsed().as_secs_f32()));
         }
 
-        let now = Instant::now();
-        if next > now {
-            thread::sleep(next - now);
+        // both frames end at `at`, the older of the two newest samples
+        let start = |end: u64, end_at: Instant, sr: f32| {
+            let lead = ((end_at - at).as_secs_f64() * (sr as f64)).round() as u64;
+            end.checked_sub(lead + (self.len as u64))
+        };
+        // drift compensation moves one frame back in time: the reference for a positive shift, the mic for a negative one
+        let back = |from: Option<u64>, by: i64| from.and_then(|f| f.checked_sub(by.max(0) as u64));
+        let mic_start = back(start(mic_end, mic_at, mic.sr), -self.ref_shift);
+        let ref_start = back(start(ref_end, ref_at, reference.sr), self.ref_shift);
+        let ready = match (mic_start, ref_start) {
+            (Some(m), Some(r)) if m >= self.fresh_from.0 && r >= self.fresh_from.1 => {
+                (self.mic_start, self.ref_start) = (m, r);
+                mic.read_into(m, self.len, &mut self.mic) && reference.read_into(r, self.len, &mut self.reference)
+            }
+            _ => false,
+        };
+        if ready {
+            Pairing::Ready
         } else {
-            next = now;
+            self.mic.clear();
+            self.reference.clear();
+            Pairing::Filling
         }
     }
 
-    logger.info("sonar-presence stopped.")?;
-    Ok(())
+    /// After the streams were opened again: pair only samples that arrive from now on, so no
+    /// frame mixes audio from before and after the gap.
+    pub fn restart(&mut self, mic: &SharedBuf, reference: &SharedBuf) {
+        self.fresh_from = (mic.written(), reference.written());
+        self.skewed_since = None;
+    }
+
+    /// Cut frames of `len` samples from the next pairing on.
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+
+    /// After a `Ready` pairing: where `reference` and `mic` were cut from their rings.
+    pub fn positions(&self) -> FramePos {
+        FramePos { reference: self.ref_start, mic: self.mic_start }
+    }
+
+    /// After a `Ready` pairing: cut `left` and `right` over the same samples as `mic`. False while
+    /// the stereo rings have not caught up with the mono one.
+    pub fn pair_stereo(&mut self, left: &SharedBuf, right: &SharedBuf) -> bool {
+        left.read_into(self.mic_start, self.len, &mut self.left) && right.read_into(self.mic_start, self.len, &mut self.right)
+    }
+}
+
+/// Per-window summary line in Detection.log.
+pub fn log_window(logger: &Logger, present: bool, w: &WindowState, window_sec: u32, quiet: bool) {
+    let avg_d = if present { w.avg_d } else { f64::INFINITY };
+    let _ = logger.log_fields(
+        LogLevel::Info,
+        &format!(
+            "present={} avg_distance_m={:.2} avg_strength={:.2} window={}s agree={:.0}% dist_iqr_m={:.2}{}{} state={}{}",
+            present,
+            avg_d,
+            w.avg_s,
+            window_sec,
+            w.agree * 100.0,
+            w.iqr_d,
+            w.probability.map(|p| format!(" probability={:.2}", p)).unwrap_or_default(),
+            w.bearing_deg.map(|b| format!(" bearing_deg={:.0}", b)).unwrap_or_default(),
+            w.state.as_str(),
+            if quiet {
+                " (quiet/none)"
+            } else {
+                ""
+            }
+        ),
+        &[
+            ("present", Field::Bool(present)),
+            ("state", Field::Str(w.state.as_str())),
+            ("avg_distance_m", Field::Num(avg_d)),
+            ("dist_iqr_m", Field::Num(w.iqr_d)),
+            ("avg_strength", Field::Num(w.avg_s)),
+            ("window_s", Field::Int(window_sec as i64)),
+            ("agree_pct", Field::Num((w.agree as f64) * 100.0)),
+            ("probability", w.probability.map_or(Field::Null, |p| Field::Num(p as f64))),
+            ("bearing_deg", w.bearing_deg.map_or(Field::Null, Field::Num)),
+            ("peak_sidelobe", w.quality.map_or(Field::Null, |q| Field::Num(q.peak_sidelobe as f64))),
+            ("snr_db", w.quality.map_or(Field::Null, |q| Field::Num(q.snr_db as f64))),
+            ("direct_r", w.quality.map_or(Field::Null, |q| Field::Num(q.direct_r as f64))),
+            ("quiet", Field::Bool(quiet)),
+        ]
+    );
+}
+
+/// Latest window summary for the control interface's `status` reply.
+pub fn detector_status(present: bool, w: &WindowState) -> String {
+    output::JsonObj
+        ::new()
+        .str("ts", &chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string())
+        .bool("present", present)
+        .str("state", w.state.as_str())
+        .num("avg_distance_m", if present { w.avg_d } else { f64::INFINITY })
+        .num("dist_iqr_m", w.iqr_d)
+        .num("avg_strength", w.avg_s)
+        .num("agree_pct", (w.agree * 100.0) as f64)
+        .opt_num("probability", w.probability.map(|p| p as f64))
+        .opt_num("bearing_deg", w.bearing_deg)
+        .opt_num("peak_sidelobe", w.quality.map(|q| q.peak_sidelobe as f64))
+        .opt_num("snr_db", w.quality.map(|q| q.snr_db as f64))
+        .opt_num("direct_r", w.quality.map(|q| q.direct_r as f64))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{ MemorySource, StepClock };
+    use crate::simulator;
+    use std::{ fs, path::Path };
+
+    const SR: u32 = 48_000;
+
+    /// Run presence mode on a recorded session through stepped sources; the log goes to `dir`.
+    fn run_session(dir: &Path, reference: &[f32], mic: &[f32]) -> (String, String) {
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let log_path = dir.join("Detection.log").to_string_lossy().into_owned();
+        let cfg = Config {
+            tick_ms: 250,
+            window_sec: 1,
+            min_dwell_ms: 500,
+            resume_grace_s: 0.0,
+            log_every_tick: true,
+            log_path: log_path.clone(),
+            ..Config::default()
+        };
+        let logger = Arc::new(Logger::new(&log_path, true).unwrap());
+        let clock = StepClock::new();
+        let mic = MemorySource::new("mic", mic.to_vec(), SR).stepped(&clock);
+        let reference = MemorySource::new("ref", reference.to_vec(), SR).stepped(&clock);
+        run_presence_with(&cfg, logger.clone(), &log_path, Box::new(mic), Box::new(reference)).unwrap();
+        logger.flush().unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        (read("Detection.csv"), read("Measurements.csv"))
+    }
+
+    /// Measurements.csv without its wall-clock column.
+    fn ticks(csv: &str) -> Vec<Vec<String>> {
+        csv.lines()
+            .skip(1)
+            .map(|l| l.split(',').skip(1).map(str::to_string).collect())
+            .collect()
+    }
+
+    #[test]
+    fn memory_session_runs_the_live_loop_deterministically() {
+        let (reference, mic) = simulator::walk_in(SR as f32, 6.0, 3.0, 0.8, 21);
+        let dir = std::env::temp_dir().join(format!("presence_e2e_{}", std::process::id()));
+        let (detections, measured) = run_session(&dir, &reference, &mic);
+
+        // one row: the person arriving, at 0.8 m
+        let rows: Vec<Vec<&str>> = detections.lines().skip(1).map(|l| l.split(',').collect()).collect();
+        assert_eq!(rows.len(), 1, "{}", detections);
+        assert_eq!(rows[0][1], "true");
+        let d: f64 = rows[0][2].parse().unwrap();
+        assert!((d - 0.8).abs() < 0.1, "distance {}", d);
+
+        // ticks on the virtual clock, every 250 ms of the recording, the state turning once
+        // the person is in
+        let t = ticks(&measured);
+        assert!((t.len() as i64 - 24).abs() <= 1, "{} ticks", t.len());
+        let t_s = |row: &Vec<String>| row[0].parse::<f64>().unwrap();
+        assert!(t.windows(2).all(|w| (t_s(&w[1]) - t_s(&w[0]) - 0.25).abs() < 1e-6));
+        let first_present = t.iter().find(|r| r[6] == "true").map(t_s).unwrap();
+        assert!((3.0..4.0).contains(&first_present), "present from {}", first_present);
+        let ranged = |r: &&Vec<String>| (r[2].parse::<f64>().unwrap() - 0.8).abs() < 0.05;
+        assert!(t.iter().filter(|r| t_s(r) > 3.0).all(|r| r[6] == "true" && ranged(&r)));
+
+        // window lines keep the key order the web GUI's PRESENT_LINE_RE reads
+        // (sonar-web-gui/src/App.js): present, avg_distance_m, avg_strength, window, agree
+        let log = fs::read_to_string(dir.join("Detection.log")).unwrap();
+        let windows: Vec<&str> = log.lines().filter(|l| l.contains(" avg_strength=")).collect();
+        assert!(!windows.is_empty());
+        for line in windows {
+            let at = line.find("present=").unwrap();
+            let keys: Vec<&str> = line[at..].split_whitespace().take(5).map(|t| t.split('=').next().unwrap()).collect();
+            assert_eq!(keys, ["present", "avg_distance_m", "avg_strength", "window", "agree"], "{}", line);
+            assert!(line.contains(" state="), "{}", line);
+        }
+
+        // the same session again decides the same, tick for tick
+        let (_, again) = run_session(&dir, &reference, &mic);
+        assert_eq!(ticks(&again), t);
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
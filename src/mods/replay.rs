@@ -0,0 +1,259 @@
+use anyhow::Result;
+use std::{
+    path::Path,
+    sync::{ atomic::{ AtomicBool, Ordering }, Arc },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::{ calibration, decode, output, pingsched, sonar_presence, Config };
+use crate::calibration::Calibration;
+use crate::features::FeatureTable;
+use crate::onnx::Model;
+use crate::correlator::FramePos;
+use crate::logger::Logger;
+use crate::mods::gated;
+use crate::mods::presence::{ log_window, PresenceDetector, TickResult };
+use crate::recorder::{ DebugDump, TickMeta };
+
+/// Replay mode: feed a recorded loopback/mic pair through the presence detector.
+/// Frames are cut from the files exactly as the live ring buffers would hold them
+/// at each tick, so Detection.csv matches what the live run decided.
+pub fn run_replay(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    if cli.replay_gated {
+        return replay_gated(cli, logger);
+    }
+    // --labels: learn this session into the --calibration curve
+    let labels = if cli.labels.is_empty() {
+        None
+    } else {
+        if cli.calibration.is_empty() {
+            anyhow::bail!("--labels needs --calibration <PATH> to learn into");
+        }
+        Some(calibration::load_labels(Path::new(&cli.labels))?)
+    };
+    let mut learned = Calibration::new();
+
+    let csv_path = output::sibling_path(&cli.log_path, "Detection.csv");
+    let mut csv_file = output::open_detection_csv(&csv_path, output::Rotation::from_config(cli))?;
+
+    let mut flips = 0u64;
+    let mut present = false;
+    let end = replay_session(cli, &logger, |det, t_s, res| {
+        present = det.policy.present();
+        let Some((res, w)) = res.and_then(|r| Some((r, r.window?))) else {
+            return;
+        };
+        if let Some(labels) = &labels {
+            learned.add(w.agree, calibration::labelled_present(labels, t_s));
+        }
+        if w.flipped {
+            flips += 1;
+        }
+        if w.state_changed {
+            let _ = output::write_detection_row(&mut csv_file, w.state, w.avg_d, w.avg_s, w.agree, w.iqr_d, w.bearing_deg, w.quality, w.probability, None);
+            let _ = logger.info(
+                &format!("state_change at t={:.2}s -> present={} state={}", t_s, w.state.present(), w.state.as_str())
+            );
+        }
+        log_window(&logger, present, &w, cli.window_sec, res.estimate.is_none());
+    })?;
+
+    logger.info(
+        &format!(
+            "replay finished: {:.1}s of audio, {} ticks, {} state changes, final present={}",
+            end.seconds,
+            end.ticks,
+            flips,
+            present
+        )
+    )?;
+
+    if labels.is_some() {
+        learn(cli, learned, &logger)?;
+    }
+    Ok(())
+}
+
+/// `--replay-gated`: the recorded pair through gated mode's live loop, on a clock stepped at
+/// `--replay-speed` (or in real time at 1), so the alignment and SongScan windows decide as they
+/// did in the session.
+fn replay_gated(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    if cli.replay_ref_wav.is_empty() || cli.replay_mic_wav.is_empty() {
+        anyhow::bail!("--ref-wav <PATH> and --mic-wav <PATH> are required in replay mode");
+    }
+    if !cli.labels.is_empty() {
+        anyhow::bail!("--labels learns from presence replays; drop --replay-gated");
+    }
+    logger.info(
+        &format!(
+            "sonar-presence (gated replay) starting…  ref={}  mic={}  speed={}",
+            cli.replay_ref_wav,
+            cli.replay_mic_wav,
+            if cli.replay_speed > 0.0 {
+                format!("{}x", cli.replay_speed)
+            } else {
+                "max".to_string()
+            }
+        )
+    )?;
+    gated::run_gated(cli, logger)
+}
+
+/// How much of the recording a replay went through.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayEnd {
+    pub seconds: f64,
+    pub ticks: u64,
+}
+
+/// Run `--ref-wav`/`--mic-wav` through the presence detector tick by tick, calling `on_tick`
+/// after each with the detector, the recording time in seconds and the tick's result (None
+/// before the first frame is full). Paced by `--replay-speed`; `--debug-dump` and `--features`
+/// are written here.
+pub fn replay_session(
+    cli: &Config,
+    logger: &Arc<Logger>,
+    mut on_tick: impl FnMut(&PresenceDetector, f64, Option<&TickResult>)
+) -> Result<ReplayEnd> {
+    if cli.replay_ref_wav.is_empty() || cli.replay_mic_wav.is_empty() {
+        anyhow::bail!("--ref-wav <PATH> and --mic-wav <PATH> are required in replay mode");
+    }
+
+    logger.info(
+        &format!(
+            "sonar-presence (replay) starting…  ref={}  mic={}  speed={}",
+            cli.replay_ref_wav,
+            cli.replay_mic_wav,
+            if cli.replay_speed > 0.0 {
+                format!("{}x", cli.replay_speed)
+            } else {
+                "max".to_string()
+            }
+        )
+    )?;
+
+    let mic = decode::load_mono(Path::new(&cli.replay_mic_wav), cli.channel_mix)?;
+    let reference = decode::load_mono(Path::new(&cli.replay_ref_wav), cli.channel_mix)?;
+    logger.info(
+        &format!(
+            "Decoded: mic {} Hz / {} samples, ref {} Hz / {} samples",
+            mic.sr,
+            mic.samples_mono.len(),
+            reference.sr,
+            reference.samples_mono.len()
+        )
+    )?;
+
+    // live capture resamples the loopback to the mic rate; do the same
+    let sr_used = mic.sr as f32;
+    let ref_samples = if reference.sr != mic.sr {
+        logger.info(&format!("Resampling reference: {} Hz → {} Hz", reference.sr, mic.sr))?;
+        decode::resample_linear_mono(&reference.samples_mono, reference.sr, mic.sr)
+    } else {
+        reference.samples_mono
+    };
+    let mic_samples = mic.samples_mono;
+    let total = ref_samples.len().min(mic_samples.len());
+
+    let analysis_len = sonar_presence::analysis_len(sr_used, cli.front_max_m);
+    logger.info(
+        &format!(
+            "Analysis window: {} samples (~{:.0} ms)",
+            analysis_len,
+            ((analysis_len as f32) / sr_used) * 1000.0
+        )
+    )?;
+
+    // ctrl+c to quit
+    let quit = Arc::new(AtomicBool::new(false));
+    {
+        let q = quit.clone();
+        let _ = ctrlc::set_handler(move || {
+            q.store(true, Ordering::SeqCst);
+        });
+    }
+
+    let mut det = PresenceDetector::new(cli);
+    let mut debug_dump = DebugDump::open(cli, logger.clone())?;
+    let mut measurements = output::open_measurements_csv(cli, &cli.log_path)?;
+    let mut feature_table = FeatureTable::open(cli, logger.clone())?;
+    det.probe = pingsched::correlation_band(cli, logger)?;
+    det.calibration = Calibration::from_config(cli, logger)?;
+    det.model = Model::from_config(cli, logger)?;
+    let tick = Duration::from_millis(cli.tick_ms);
+    let hop = (((cli.tick_ms as f32) / 1000.0) * sr_used).round() as usize;
+
+    // the detector runs on a virtual clock so dwell times hold at any replay speed
+    let t_start = Instant::now();
+    let mut ticks = 0u64;
+    let mut pos = 0usize;
+    while !quit.load(Ordering::SeqCst) {
+        pos += hop;
+        if pos > total {
+            break;
+        }
+        ticks += 1;
+        let t_virtual = tick * (ticks as u32);
+        let t_s = (pos as f64) / (sr_used as f64);
+
+        let mut meta = TickMeta::default();
+        // clock drift compensation reads one of the two further back
+        let shift = det.drift.shift();
+        let (ref_back, mic_back) = (shift.max(0) as usize, (-shift).max(0) as usize);
+        if pos >= analysis_len + ref_back.max(mic_back) {
+            let ref_frame = &ref_samples[pos - ref_back - analysis_len..pos - ref_back];
+            let mic_frame = &mic_samples[pos - mic_back - analysis_len..pos - mic_back];
+            det.frames_at = Some(FramePos {
+                reference: (pos - ref_back - analysis_len) as u64,
+                mic: (pos - mic_back - analysis_len) as u64,
+            });
+            let res = det.tick(ref_frame, mic_frame, sr_used, cli, t_start + t_virtual, Some(logger));
+            meta = res.meta();
+            on_tick(&det, t_s, Some(&res));
+        } else {
+            det.policy.age();
+            on_tick(&det, t_s, None);
+        }
+        let meta = TickMeta { present: det.policy.present(), state: det.activity.state(), ..meta };
+        if let Some(dump) = debug_dump.as_mut() {
+            dump.tick(t_virtual.as_secs_f64(), &meta);
+        }
+        if let Some(csv) = measurements.as_mut() {
+            let _ = output::write_measurement_row(csv, t_virtual.as_secs_f64(), &meta);
+        }
+        if let Some(table) = feature_table.as_mut() {
+            table.tick(t_virtual.as_secs_f64(), &meta);
+        }
+
+        if cli.replay_speed > 0.0 {
+            let due = t_start + t_virtual.div_f32(cli.replay_speed);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+    }
+
+    Ok(ReplayEnd { seconds: (total as f64) / (sr_used as f64), ticks })
+}
+
+/// Add a labelled session's windows to the --calibration file and refit it.
+fn learn(cli: &Config, session: Calibration, logger: &Logger) -> Result<()> {
+    let path = Path::new(&cli.calibration);
+    let mut cal = if path.exists() { Calibration::load(path)? } else { Calibration::new() };
+    let before = cal.windows();
+    cal.merge(&session);
+    cal.fit();
+    cal.save(path)?;
+    logger.info(
+        &format!(
+            "Calibration: {} labelled windows from this session, {} in all; Brier score {:.3} → {}",
+            session.windows(),
+            before + session.windows(),
+            cal.brier(),
+            cli.calibration
+        )
+    )?;
+    Ok(())
+}
@@ -0,0 +1,372 @@
+use anyhow::{ Context, Result };
+use chrono::{ Duration, NaiveDateTime, Timelike };
+use std::{
+    fs,
+    path::{ Path, PathBuf },
+    sync::Arc,
+};
+
+use crate::{ csvio, output, Config };
+use crate::logger::Logger;
+
+/// `--report-by`: the periods occupancy is summed over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportBy {
+    Day,
+    Hour,
+}
+
+impl ReportBy {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "day" => Ok(ReportBy::Day),
+            "hour" => Ok(ReportBy::Hour),
+            _ => Err(format!("Invalid report period: {}. Valid options: day, hour", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportBy::Day => "day",
+            ReportBy::Hour => "hour",
+        }
+    }
+
+    /// Start of the period `t` falls in.
+    fn floor(&self, t: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            ReportBy::Day => t.date().and_hms_opt(0, 0, 0).unwrap(),
+            ReportBy::Hour => t.date().and_hms_opt(t.hour(), 0, 0).unwrap(),
+        }
+    }
+
+    fn step(&self) -> Duration {
+        match self {
+            ReportBy::Day => Duration::days(1),
+            ReportBy::Hour => Duration::hours(1),
+        }
+    }
+
+    fn label(&self, start: NaiveDateTime) -> String {
+        match self {
+            ReportBy::Day => start.format("%Y-%m-%d").to_string(),
+            ReportBy::Hour => start.format("%Y-%m-%d %H:00").to_string(),
+        }
+    }
+}
+
+/// `--report-format`: what `--mode report` writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+}
+
+impl ReportFormat {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(ReportFormat::Csv),
+            "json" => Ok(ReportFormat::Json),
+            _ => Err(format!("Invalid report format: {}. Valid options: csv, json", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "csv",
+            ReportFormat::Json => "json",
+        }
+    }
+}
+
+/// One Detection.csv row: the smoothed state changed at `at`.
+#[derive(Clone, Copy, Debug)]
+pub struct Transition {
+    pub at: NaiveDateTime,
+    pub present: bool,
+    pub distance_m: f64, // window distance when it flipped; infinite on exits
+}
+
+/// Occupancy within one period (or the whole log, for the total).
+#[derive(Clone, Debug, Default)]
+pub struct Occupancy {
+    pub label: String,
+    pub covered_s: f64, // part of the period the log spans
+    pub present_s: f64,
+    pub enters: u32,
+    pub exits: u32,
+    distance_sum: f64,
+    distance_n: u32,
+    pub longest_absence_s: f64, // longest stretch between an exit and the next enter, within the period
+}
+
+impl Occupancy {
+    pub fn present_pct(&self) -> f64 {
+        if self.covered_s > 0.0 { (100.0 * self.present_s) / self.covered_s } else { 0.0 }
+    }
+
+    /// Mean distance reported on entering; None without enters.
+    pub fn avg_distance_m(&self) -> Option<f64> {
+        (self.distance_n > 0).then(|| self.distance_sum / (self.distance_n as f64))
+    }
+
+    fn json(&self) -> String {
+        output::JsonObj
+            ::new()
+            .str("period", &self.label)
+            .num("covered_s", self.covered_s)
+            .num("present_s", self.present_s)
+            .num("present_pct", self.present_pct())
+            .int("enters", self.enters as i64)
+            .int("exits", self.exits as i64)
+            .opt_num("avg_distance_m", self.avg_distance_m())
+            .num("longest_absence_s", self.longest_absence_s)
+            .finish()
+    }
+
+    fn csv_row(&self) -> String {
+        let distance = self.avg_distance_m().map(|d| format!("{:.2}", d)).unwrap_or_default();
+        csvio::record(
+            &[
+                self.label.clone(),
+                format!("{:.0}", self.covered_s),
+                format!("{:.0}", self.present_s),
+                format!("{:.1}", self.present_pct()),
+                self.enters.to_string(),
+                self.exits.to_string(),
+                distance,
+                format!("{:.0}", self.longest_absence_s),
+            ]
+        )
+    }
+}
+
+pub const REPORT_CSV_HEADER: &str = "period,covered_s,present_s,present_pct,enters,exits,avg_distance_m,longest_absence_s";
+
+/// Occupancy per period plus the whole log, from the first to the last state change.
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    pub periods: Vec<Occupancy>,
+    pub total: Occupancy,
+    pub longest_absence_from: Option<NaiveDateTime>,
+}
+
+/// Parse Detection.csv text. Rows that do not parse (a header, a cut-off last line) are skipped.
+pub fn parse_detections(text: &str) -> Vec<Transition> {
+    csvio
+        ::parse(text)
+        .into_iter()
+        .filter_map(|rec| {
+            let at = NaiveDateTime::parse_from_str(rec.first()?, "%Y-%m-%d %H:%M:%S").ok()?;
+            let present = rec.get(1)?.parse::<bool>().ok()?;
+            let distance_m = rec
+                .get(2)
+                .and_then(|d| d.parse::<f64>().ok())
+                .unwrap_or(f64::INFINITY);
+            Some(Transition { at, present, distance_m })
+        })
+        .collect()
+}
+
+/// Sum the state between consecutive transitions into periods. The time after the last row is
+/// not counted: the log does not say whether the detector was still running.
+pub fn build(transitions: &[Transition], by: ReportBy) -> Option<Report> {
+    let mut ts = transitions.to_vec();
+    ts.sort_by_key(|t| t.at);
+    let (from, to) = (ts.first()?.at, ts.last()?.at);
+
+    let first = by.floor(from);
+    let mut periods: Vec<Occupancy> = Vec::new();
+    let mut start = first;
+    while start <= to {
+        periods.push(Occupancy { label: by.label(start), ..Occupancy::default() });
+        start += by.step();
+    }
+    let index = |t: NaiveDateTime| ((by.floor(t) - first).num_seconds() / by.step().num_seconds()) as usize;
+    let mut total = Occupancy { label: "total".to_string(), ..Occupancy::default() };
+
+    let mut present = false;
+    let mut longest_absence_from = None;
+    for (i, t) in ts.iter().enumerate() {
+        // a repeated state (a restart that found the same state again) is not a transition
+        if i == 0 || t.present != present {
+            for o in [&mut periods[index(t.at)], &mut total] {
+                if t.present {
+                    o.enters += 1;
+                    if t.distance_m.is_finite() {
+                        o.distance_sum += t.distance_m;
+                        o.distance_n += 1;
+                    }
+                } else {
+                    o.exits += 1;
+                }
+            }
+        }
+        present = t.present;
+
+        // the stretch until the next row, split at period boundaries
+        let Some(next) = ts.get(i + 1) else {
+            break;
+        };
+        let whole_s = ((next.at - t.at).num_milliseconds() as f64) / 1000.0;
+        // an absence only counts between an exit and the next enter
+        let absence = !present && next.present;
+        if absence && whole_s > total.longest_absence_s {
+            total.longest_absence_s = whole_s;
+            longest_absence_from = Some(t.at);
+        }
+        total.covered_s += whole_s;
+        if present {
+            total.present_s += whole_s;
+        }
+        let mut a = t.at;
+        while a < next.at {
+            let b = (by.floor(a) + by.step()).min(next.at);
+            let piece_s = ((b - a).num_milliseconds() as f64) / 1000.0;
+            let o = &mut periods[index(a)];
+            o.covered_s += piece_s;
+            if present {
+                o.present_s += piece_s;
+            }
+            if absence {
+                o.longest_absence_s = o.longest_absence_s.max(piece_s);
+            }
+            a = b;
+        }
+    }
+    Some(Report { from, to, periods, total, longest_absence_from })
+}
+
+impl Report {
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from(REPORT_CSV_HEADER);
+        out.push('\n');
+        for o in self.periods.iter().chain(std::iter::once(&self.total)) {
+            out.push_str(&o.csv_row());
+            out.push('\n');
+        }
+        out
+    }
+
+    pub fn to_json(&self, by: ReportBy) -> String {
+        let periods: Vec<String> = self.periods.iter().map(Occupancy::json).collect();
+        let fmt = |t: NaiveDateTime| t.format("%Y-%m-%d %H:%M:%S").to_string();
+        let obj = output::JsonObj
+            ::new()
+            .str("from", &fmt(self.from))
+            .str("to", &fmt(self.to))
+            .str("by", by.as_str())
+            .num("present_s", self.total.present_s)
+            .num("present_pct", self.total.present_pct())
+            .int("enters", self.total.enters as i64)
+            .int("exits", self.total.exits as i64)
+            .opt_num("avg_distance_m", self.total.avg_distance_m())
+            .num("longest_absence_s", self.total.longest_absence_s);
+        let obj = match self.longest_absence_from {
+            Some(t) => obj.str("longest_absence_from", &fmt(t)),
+            None => obj.null("longest_absence_from"),
+        };
+        obj.arr("periods", &periods).finish()
+    }
+}
+
+/// Detection.csv to read: `--report-in`, or the one beside the log, each with its rotated archives.
+fn input_files(cli: &Config) -> Result<Vec<PathBuf>> {
+    let path = if cli.report_in.is_empty() {
+        output::sibling_path(&cli.log_path, "Detection.csv")
+    } else {
+        PathBuf::from(&cli.report_in)
+    };
+    let mut files = output::rotated_siblings(&path).unwrap_or_default();
+    if path.exists() {
+        files.push(path.clone());
+    }
+    if files.is_empty() {
+        anyhow::bail!("No detection log found at {}", path.display());
+    }
+    Ok(files)
+}
+
+/// Report mode: occupancy statistics from Detection.csv for dashboards.
+/// Writes CSV or JSON to `--report-out`, or stdout.
+pub fn run_report(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    let mut transitions = Vec::new();
+    for file in input_files(cli)? {
+        let text = fs::read_to_string(&file).with_context(|| format!("reading {}", file.display()))?;
+        let rows = parse_detections(&text);
+        logger.info(&format!("Report: {} state changes from {}", rows.len(), file.display()))?;
+        transitions.extend(rows);
+    }
+    let report = build(&transitions, cli.report_by).ok_or_else(|| anyhow::anyhow!("No state changes to report on"))?;
+
+    let text = match cli.report_format {
+        ReportFormat::Csv => report.to_csv(),
+        ReportFormat::Json => report.to_json(cli.report_by) + "\n",
+    };
+    if cli.report_out.is_empty() {
+        print!("{}", text);
+    } else {
+        output::write_atomic(Path::new(&cli.report_out), &text)?;
+        logger.info(
+            &format!("Report ({} by {}) written to {}", cli.report_format.as_str(), cli.report_by.as_str(), cli.report_out)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "\
+timestamp,present,avg_distance_m,avg_strength,agree_pct,dist_iqr_m
+2026-10-14 08:30:00,true,0.80,0.60,80,0.05
+2026-10-14 09:15:00,false,inf,0.10,20,inf
+2026-10-14 10:00:00,true,1.00,0.55,75,0.04,12.0
+2026-10-14 10:00:00,true,1.00,0.55,75,0.04,12.0
+2026-10-15 01:00:00,false,inf,0.00,0,inf
+2026-10-15 01:30:00,true,0.90
+";
+
+    #[test]
+    fn occupancy_per_day_and_hour() {
+        let ts = parse_detections(LOG);
+        assert_eq!(ts.len(), 6);
+
+        let days = build(&ts, ReportBy::Day).unwrap();
+        assert_eq!(days.periods.len(), 2);
+        let (d1, d2) = (&days.periods[0], &days.periods[1]);
+        assert_eq!(d1.label, "2026-10-14");
+        // 08:30-09:15 and 10:00-midnight present
+        assert_eq!((d1.present_s, d1.covered_s), (45.0 * 60.0 + 14.0 * 3600.0, 15.5 * 3600.0));
+        assert_eq!((d1.enters, d1.exits), (2, 1));
+        assert!((d1.avg_distance_m().unwrap() - 0.9).abs() < 1e-9);
+        assert_eq!(d1.longest_absence_s, 45.0 * 60.0);
+        // midnight-01:00 present, then 30 min absent
+        assert_eq!((d2.present_s, d2.covered_s, d2.enters, d2.exits), (3600.0, 5400.0, 1, 1));
+        assert_eq!(d2.longest_absence_s, 1800.0);
+
+        let t = &days.total;
+        assert_eq!((t.enters, t.exits), (3, 2));
+        assert_eq!(t.longest_absence_s, 45.0 * 60.0);
+        assert_eq!(days.longest_absence_from.unwrap().to_string(), "2026-10-14 09:15:00");
+
+        let hours = build(&ts, ReportBy::Hour).unwrap();
+        let h9 = hours.periods.iter().find(|p| p.label == "2026-10-14 09:00").unwrap();
+        assert_eq!((h9.present_s, h9.covered_s, h9.exits), (900.0, 3600.0, 1));
+        assert_eq!(hours.periods.len(), 18); // 08:00 on the 14th to 01:00 on the 15th
+
+        let csv = days.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], REPORT_CSV_HEADER);
+        assert_eq!(lines[2], "2026-10-15,5400,3600,66.7,1,1,0.90,1800");
+        assert!(lines[3].starts_with("total,"));
+        let json = crate::json::parse(&days.to_json(ReportBy::Day)).unwrap();
+        assert_eq!(json.get("enters").and_then(|v| v.as_f64()), Some(3.0));
+        assert_eq!(json.get("periods").and_then(|v| v.as_array()).map(|p| p.len()), Some(2));
+
+        assert!(build(&[], ReportBy::Day).is_none());
+    }
+}
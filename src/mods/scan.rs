@@ -1,25 +1,140 @@
 use anyhow::Result;
+use crossbeam_channel::{ unbounded, Receiver, RecvTimeoutError };
 use std::{
-    fs::OpenOptions,
-    io::Write,
+    collections::VecDeque,
+    io::BufRead,
     path::Path,
     sync::Arc,
-    time::Duration,
+    thread,
+    time::{ Duration, Instant },
 };
 
-use crate::{logger::Logger, prescan, wasapi_loopback};
+use crate::{logger::Logger, fpdb, prescan, scanscore, songscan, spectrogram, wasapi_loopback};
+use crate::spectrogram::Spectrogram;
 
-/// tiny hex encoder so this file is standalone
-fn to_hex(bytes: &[u8]) -> String {
-    let mut s = String::with_capacity(bytes.len() * 2);
-    for b in bytes {
-        s.push_str(&format!("{:02x}", b));
+/// How often the provisional segments are re-ranked and saved.
+const PROVISIONAL_EVERY_S: u64 = 10;
+
+/// One track of the capture, analysed as it plays.
+struct Track<'a> {
+    number: usize,
+    analyzer: prescan::Analyzer<'a>,
+    fingerprinter: prescan::Fingerprinter,
+}
+
+impl<'a> Track<'a> {
+    fn new(number: usize, params: &'a prescan::ScanParams, cli: &crate::Config) -> Self {
+        let mut analyzer = prescan::Analyzer::new(params);
+        if !cli.export_spectrogram.is_empty() {
+            analyzer.keep_spectrogram();
+        }
+        Self {
+            number,
+            analyzer,
+            fingerprinter: prescan::Fingerprinter::new(params.sr, cli.fp_win_s, cli.fp_type, cli.fp_every_s),
+        }
+    }
+
+    fn push(&mut self, block: &[f32]) {
+        self.fingerprinter.push(block);
+        self.analyzer.push(block);
+    }
+
+    fn finish(mut self, sr: f32) -> FinishedTrack {
+        FinishedTrack {
+            number: self.number,
+            secs: (self.analyzer.samples_seen() as f32) / sr,
+            fps: self.fingerprinter.finish(),
+            spectrogram: self.analyzer.take_spectrogram(),
+            segs: self.analyzer.finish(),
+        }
+    }
+}
+
+struct FinishedTrack {
+    number: usize,
+    secs: f32,
+    segs: Vec<prescan::Segment>,
+    fps: Vec<prescan::Fingerprint>,
+    spectrogram: Option<Spectrogram>,
+}
+
+/// CSV tag of a track: the `--scan-url`, numbered when the capture is split into tracks.
+fn track_tag(url: &str, number: usize, split: bool) -> String {
+    match (split, url.is_empty()) {
+        (false, _) => url.to_string(),
+        (true, true) => format!("track{:02}", number),
+        (true, false) => format!("{}#{:02}", url, number),
+    }
+}
+
+fn write_track(
+    csv_path: &Path,
+    cli: &crate::Config,
+    tag: &str,
+    track: &FinishedTrack,
+    params: &prescan::ScanParams,
+    logger: &Logger
+) -> Result<()> {
+    if let Some(spec) = &track.spectrogram {
+        let path = Path::new(&cli.export_spectrogram);
+        let path = if cli.scan_split_gap_s > 0.0 { spectrogram::track_path(path, track.number) } else { path.to_path_buf() };
+        spec.write(&path, 0.0, &track.segs)?;
+        logger.info(&format!("Track {}: wrote the spectrogram ({} frames) to {}", track.number, spec.frames(), path.display()))?;
+    }
+    if track.segs.is_empty() {
+        logger.info(
+            &format!("Track {} ({:.1}s): no candidate segments found (too short or too quiet).", track.number, track.secs)
+        )?;
+        return Ok(());
     }
-    s
+    // One row per segment carrying the lead-in fingerprint, plus a row per later fingerprint.
+    let replaced = songscan::store_segments(csv_path, tag, &track.segs, params, &track.fps, cli.scan_append)?;
+    logger.info(
+        &format!(
+            "Track {} ({:.1}s, url={}): wrote {} segment(s) and {} fingerprint(s) to {}{}",
+            track.number,
+            track.secs,
+            tag,
+            track.segs.len(),
+            track.fps.len(),
+            csv_path.display(),
+            if replaced > 0 { format!(" (replaced {} earlier row(s))", replaced) } else { String::new() }
+        )
+    )?;
+    fpdb::store_track(cli, tag, &track.segs, &track.fps, logger)?;
+    Ok(())
+}
+
+/// Lines typed on stdin, read on a separate thread so capture never blocks on the prompt.
+fn stdin_lines() -> Receiver<String> {
+    let (tx, rx) = unbounded();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            if tx.send(line).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+fn prompt_for(track: &FinishedTrack) {
+    println!(
+        "Track {} ended ({:.1}s, {} segment(s)). URL for it (Enter = auto-number):",
+        track.number,
+        track.secs,
+        track.segs.len()
+    );
 }
 
 /// Loopback-only pre-scan of the currently playing audio (e.g., YouTube).
-/// Captures at configurable SR, then extracts and writes best segments to `SongScan.csv`.
+/// Captures at configurable SR and analyses as it goes; on Ctrl+C the final
+/// segments are appended to `SongScan.csv`. With `--split-gap-s` a playlist is
+/// cut into tracks at silent gaps, each with its own fingerprint and rows.
 pub fn run_scan(cli: &crate::Config, meta: &crate::ScanMeta, logger: Arc<Logger>) -> Result<()> {
     logger.info(&format!(
         "sonar-prescan (loopback-only) starting…  frame_ms={:.0} window_s={:.1} stride_ms={:.0} top_n={} min_pct={:.0}",
@@ -35,14 +150,6 @@ pub fn run_scan(cli: &crate::Config, meta: &crate::ScanMeta, logger: Arc<Logger>
 
     // CSV path for scan results
     let csv_path = Path::new(&cli.scansong_path);
-    let mut csv_file = OpenOptions::new().create(true).append(true).open(csv_path)?;
-    if csv_file.metadata()?.len() == 0 {
-        writeln!(
-            csv_file,
-            "url,start_s,end_s,score,frame_ms,window_s,stride_s,bandwidth_z,flatness_z,flux_z,crest_db,hf_ratio,dynrange_z,tonality_z,loudness_dbfs,notes,fp_type,fp_bands,fp_hop_s,fp_offset_s,fp_bins_hex"
-        )?;
-        csv_file.flush()?;
-    }
 
     // ctrl+c to stop capture of a song
     let quit = Arc::new(std::sync::atomic::AtomicBool::new(false));
@@ -62,22 +169,13 @@ pub fn run_scan(cli: &crate::Config, meta: &crate::ScanMeta, logger: Arc<Logger>
 
     // Smaller chunking for capture; analysis will re-frame anyway.
     let tick_ms_for_capture = 50u64;
-    let rx = wasapi_loopback::start(sr_target, logger.clone(), tick_ms_for_capture)?;
-
-    logger.info("Playback your YouTube track now. Press Ctrl+C when the track ends to analyze.")?;
-
-    let mut song: Vec<f32> = Vec::with_capacity((sr_target as usize) * 600); // ~10 min
-    while !quit.load(std::sync::atomic::Ordering::SeqCst) {
-        match rx.recv_timeout(Duration::from_millis(100)) {
-            Ok(block) => song.extend_from_slice(&block),
-            Err(_timeout) => { /* keep polling until Ctrl+C */ }
-        }
-    }
-
-    logger.info(&format!(
-        "Captured {:.1} seconds of loopback audio; analyzing…",
-        (song.len() as f32) / (sr_target as f32)
-    ))?;
+    let rx = wasapi_loopback::start(
+        sr_target,
+        logger.clone(),
+        tick_ms_for_capture,
+        cli.channel_mix,
+        cli.loopback.clone()
+    )?;
 
     // Build scan params
     let params = prescan::ScanParams {
@@ -92,54 +190,246 @@ pub fn run_scan(cli: &crate::Config, meta: &crate::ScanMeta, logger: Arc<Logger>
         merge_gap_s: cli.merge_gap_s,
         clamp_min_s: cli.clamp_min_s,
         clamp_max_s: cli.clamp_max_s,
+        scorer: scanscore::scorer(cli, &logger)?,
     };
 
-    // One fingerprint for the track (first ~N seconds)
-    let fp = prescan::make_fingerprint(&song, params.sr, cli.fp_win_s);
+    // Analysis runs while the track plays; provisional results go to SongScan.partial.csv
+    // so a crash keeps what was found so far.
+    let partial_path = songscan::partial_path(csv_path);
+    let sr = params.sr;
+    let mut last_snapshot = Instant::now();
+    let mut last_count = 0usize;
 
-    let segs = prescan::analyze(&song, &params);
-    if segs.is_empty() {
-        logger.info("No candidate segments found (audio too short or too quiet).")?;
-        return Ok(());
+    // track splitting: a new track starts with the first loud block after a gap
+    let split = cli.scan_split_gap_s > 0.0;
+    let split_gap_samples = ((cli.scan_split_gap_s as f64) * (sr_target as f64)).round() as usize;
+    let mut tracks_started = 0usize;
+    let mut track: Option<Track> = None;
+    if !split {
+        tracks_started = 1;
+        track = Some(Track::new(1, &params, cli));
     }
 
-    // Append rows; include same fingerprint per row.
-    for s in &segs {
-        let w = &s.peak;
-        let (fp_type, fp_bands, fp_hop_s, fp_offset_s, fp_bins_hex) = if let Some(ref f) = fp {
-            (f.fp_type.as_str(), f.bands as u32, f.hop_s, f.offset_s, to_hex(&f.bins))
-        } else {
-            ("", 0, 0.0, 0.0, String::new())
-        };
-        writeln!(
-            csv_file,
-            "{},{:.3},{:.3},{:.3},{:.0},{:.1},{:.1},{:.2},{:.2},{:.2},{:.1},{:.3},{:.2},{:.2},{:.1},{}\
-            ,{},{},{:.5},{:.3},{}",
-            if meta.url.is_empty() { "" } else { &meta.url },
-            s.start_s,
-            s.end_s,
-            w.score,
-            params.frame_ms,
-            params.window_s,
-            params.stride_ms / 1000.0,
-            w.z.bandwidth_z,
-            w.z.flatness_z,
-            w.z.flux_z,
-            w.crest_db,
-            w.hf_ratio,
-            w.z.dynrange_z,
-            w.z.tonality_z,
-            w.loudness_dbfs,
-            "\"\"",
-            fp_type,
-            fp_bands,
-            fp_hop_s,
-            fp_offset_s,
-            fp_bins_hex
+    // finished tracks waiting for a URL typed on stdin
+    let answers = if split && cli.scan_split_prompt { Some(stdin_lines()) } else { None };
+    let mut pending: VecDeque<FinishedTrack> = VecDeque::new();
+
+    // unattended stop: fixed length, or sustained silence after the track has started
+    let max_samples = if cli.scan_capture_duration_s > 0.0 {
+        ((cli.scan_capture_duration_s as f64) * (sr_target as f64)).round() as usize
+    } else {
+        usize::MAX
+    };
+    let silence_samples = ((cli.scan_silence_stop_s as f64) * (sr_target as f64)).round() as usize;
+    let mut captured = 0usize;
+    let mut heard = false;
+    let mut silent_run = 0usize;
+
+    let mut stops = Vec::new();
+    if cli.scan_capture_duration_s > 0.0 {
+        stops.push(format!("after {:.0}s", cli.scan_capture_duration_s));
+    }
+    if cli.scan_silence_stop_s > 0.0 {
+        stops.push(format!("after {:.0}s below {:.0} dBFS", cli.scan_silence_stop_s, cli.scan_silence_dbfs));
+    }
+    if split {
+        logger.info(
+            &format!(
+                "Splitting tracks at gaps of {:.1}s below {:.0} dBFS",
+                cli.scan_split_gap_s,
+                cli.scan_silence_dbfs
+            )
         )?;
     }
-    csv_file.flush()?;
+    if stops.is_empty() {
+        logger.info("Playback your YouTube track now. Press Ctrl+C when the track ends to finalize.")?;
+    } else {
+        logger.info(
+            &format!("Playback your YouTube track now. Capture stops {} (or on Ctrl+C).", stops.join(" or "))
+        )?;
+    }
+
+    let capture_start = Instant::now();
+    while !quit.load(std::sync::atomic::Ordering::SeqCst) {
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(mut block) => {
+                block.truncate(max_samples - captured);
+                captured += block.len();
+
+                let r = prescan::rms(&block);
+                let dbfs = if r > 1e-9 { 20.0 * r.log10() } else { -120.0 };
+                let loud = !block.is_empty() && dbfs >= cli.scan_silence_dbfs;
+                if loud {
+                    heard = true;
+                    silent_run = 0;
+                } else if heard {
+                    silent_run += block.len();
+                }
+
+                if track.is_none() && loud {
+                    tracks_started += 1;
+                    logger.info(&format!("Track {} started", tracks_started))?;
+                    track = Some(Track::new(tracks_started, &params, cli));
+                }
+                if let Some(t) = track.as_mut() {
+                    t.push(&block);
+                }
+            }
+            Err(_timeout) => {
+                // loopback delivers nothing at all while no stream is playing: that is silence too
+                if heard {
+                    silent_run += (sr_target as usize) / 10;
+                }
+            }
+        }
+
+        if split && silent_run >= split_gap_samples && track.is_some() {
+            let done = track.take().unwrap().finish(sr);
+            last_count = 0;
+            match &answers {
+                Some(_) => {
+                    if pending.is_empty() {
+                        prompt_for(&done);
+                    }
+                    pending.push_back(done);
+                }
+                None => {
+                    let tag = track_tag(&meta.url, done.number, split);
+                    write_track(csv_path, cli, &tag, &done, &params, &logger)?;
+                }
+            }
+        }
+
+        // answer the oldest prompt
+        if let (Some(lines), Some(front)) = (&answers, pending.front()) {
+            if let Ok(line) = lines.try_recv() {
+                let tag = if line.trim().is_empty() {
+                    track_tag(&meta.url, front.number, split)
+                } else {
+                    line.trim().to_string()
+                };
+                write_track(csv_path, cli, &tag, front, &params, &logger)?;
+                pending.pop_front();
+                if let Some(next) = pending.front() {
+                    prompt_for(next);
+                }
+            }
+        }
+
+        // the wall clock also counts, for the same reason
+        if
+            captured >= max_samples ||
+            (cli.scan_capture_duration_s > 0.0 &&
+                capture_start.elapsed().as_secs_f32() >= cli.scan_capture_duration_s)
+        {
+            logger.info(&format!("Capture duration of {:.0}s reached.", cli.scan_capture_duration_s))?;
+            break;
+        }
+        if silence_samples > 0 && silent_run >= silence_samples {
+            logger.info(&format!("Silence for {:.0}s after playback; stopping capture.", cli.scan_silence_stop_s))?;
+            break;
+        }
+
+        if last_snapshot.elapsed() >= Duration::from_secs(PROVISIONAL_EVERY_S) {
+            last_snapshot = Instant::now();
+            if let Some(t) = track.as_ref() {
+                let segs = t.analyzer.snapshot();
+                let tag = track_tag(&meta.url, t.number, split);
+                if let Err(e) = songscan::write_snapshot(&partial_path, &tag, &segs, &params, t.fingerprinter.fingerprints()) {
+                    let _ = logger.error(&format!("Could not write {}: {}", partial_path.display(), e));
+                }
+                if segs.len() != last_count {
+                    last_count = segs.len();
+                    let _ = logger.info(
+                        &format!(
+                            "{:.0}s scanned: {} provisional segment(s)",
+                            (t.analyzer.samples_seen() as f32) / (sr_target as f32),
+                            segs.len()
+                        )
+                    );
+                }
+            }
+        }
+    }
 
-    logger.info(&format!("Wrote {} segment(s) to {}", segs.len(), csv_path.display()))?;
+    logger.info(&format!(
+        "Captured {:.1} seconds of loopback audio; finalizing…",
+        (captured as f32) / (sr_target as f32)
+    ))?;
+
+    if let Some(t) = track.take() {
+        let done = t.finish(sr);
+        if !split {
+            let _ = std::fs::remove_file(&partial_path);
+            if done.segs.is_empty() {
+                logger.info("No candidate segments found (audio too short or too quiet).")?;
+                return Ok(());
+            }
+            let replaced = songscan::store_segments(
+                csv_path,
+                &meta.url,
+                &done.segs,
+                &params,
+                &done.fps,
+                cli.scan_append
+            )?;
+            if replaced > 0 {
+                logger.info(&format!("Replaced {} earlier row(s) for url={}", replaced, meta.url))?;
+            }
+            logger.info(
+                &format!(
+                    "Wrote {} segment(s) and {} fingerprint(s) to {}",
+                    done.segs.len(),
+                    done.fps.len(),
+                    csv_path.display()
+                )
+            )?;
+            fpdb::store_track(cli, &meta.url, &done.segs, &done.fps, &logger)?;
+            return Ok(());
+        }
+        if answers.is_some() {
+            if pending.is_empty() {
+                prompt_for(&done);
+            }
+            pending.push_back(done);
+        } else {
+            let tag = track_tag(&meta.url, done.number, split);
+            write_track(csv_path, cli, &tag, &done, &params, &logger)?;
+        }
+    }
+
+    // capture has stopped: wait for the remaining URLs (stdin closed = auto-number). The
+    // Ctrl+C that ended capture is spent; another one auto-numbers the tracks still waiting.
+    if let Some(lines) = &answers {
+        if !pending.is_empty() {
+            quit.store(false, std::sync::atomic::Ordering::SeqCst);
+            logger.info("Capture stopped; Ctrl+C again to number the remaining tracks automatically.")?;
+        }
+        while let Some(front) = pending.pop_front() {
+            let answer = loop {
+                if quit.load(std::sync::atomic::Ordering::SeqCst) {
+                    break None;
+                }
+                match lines.recv_timeout(Duration::from_millis(200)) {
+                    Ok(line) => break Some(line),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break None,
+                }
+            };
+            let tag = match answer {
+                Some(line) if !line.trim().is_empty() => line.trim().to_string(),
+                _ => track_tag(&meta.url, front.number, split),
+            };
+            write_track(csv_path, cli, &tag, &front, &params, &logger)?;
+            if let Some(next) = pending.front().filter(|_| !quit.load(std::sync::atomic::Ordering::SeqCst)) {
+                prompt_for(next);
+            }
+        }
+    }
+    let _ = std::fs::remove_file(&partial_path);
+
+    if tracks_started == 0 {
+        logger.info("No audio above the silence threshold; nothing to write.")?;
+    }
     Ok(())
 }
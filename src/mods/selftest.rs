@@ -0,0 +1,273 @@
+//! src/mods/selftest.rs
+//! `--mode selftest`: checks the things a presence run depends on, one by one, and prints
+//! what failed with a hint at the fix. Exits non-zero when any check failed.
+
+use anyhow::Result;
+use std::{
+    fs::{ self, OpenOptions },
+    io,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use crate::audio::{ self, AudioSource, CpalMic, Loopback, ProbeMode, ProbeSignal, ProbeTone };
+use crate::logger::Logger;
+use crate::mods::presence::{ FramePairer, Pairing };
+use crate::{ output, prescan, sonar_presence, Config, SharedBuf };
+
+/// How long both streams run before they are judged.
+const LISTEN: Duration = Duration::from_secs(3);
+/// The test tone is played at least this loud (linear), so a quiet `--probe-amp` still shows.
+const TEST_TONE_AMP: f32 = 0.1;
+/// Below this the loopback counts as silent.
+const SILENT_RMS: f32 = 1e-3;
+/// Minimum normalized correlation at the direct path for the mic to be hearing the speaker.
+const DIRECT_MIN_R: f32 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    Skip, // could not run because an earlier check failed
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pass => "PASS",
+            Self::Fail => "FAIL",
+            Self::Skip => "SKIP",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+    pub hint: &'static str, // printed with failures only
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: String) -> Self {
+        Self { name, outcome: Outcome::Pass, detail, hint: "" }
+    }
+
+    fn fail(name: &'static str, detail: String, hint: &'static str) -> Self {
+        Self { name, outcome: Outcome::Fail, detail, hint }
+    }
+
+    fn skip(name: &'static str, detail: &str) -> Self {
+        Self { name, outcome: Outcome::Skip, detail: detail.to_string(), hint: "" }
+    }
+}
+
+pub fn run_selftest(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    logger.info("sonar-presence self-test starting…")?;
+    let mut checks = vec![check_paths(cli)];
+
+    // both streams run at once, the loopback with the test tone playing into it
+    let mut mic = CpalMic::new(cli.channel_mix);
+    let shared_mic = audio::capture(&mut mic, Some(48_000), logger.clone());
+    let want_sr = shared_mic.as_ref().map(|m| m.sr as u32).unwrap_or(48_000);
+    let tone = ProbeTone {
+        mode: ProbeMode::On,
+        signal: ProbeSignal::Prbs,
+        amp: cli.probe_amp.max(TEST_TONE_AMP),
+        ..ProbeTone::from_config(cli)
+    };
+    let mut loopback = Loopback::new(cli.tick_ms, cli.channel_mix, cli.loopback.clone(), tone);
+    let shared_ref = audio::capture_as(&mut loopback, want_sr, logger.clone()).map(|(buf, _)| buf);
+    thread::sleep(LISTEN);
+
+    let shared_mic = match shared_mic {
+        Ok(buf) => {
+            checks.push(check_mic(&mic, &buf));
+            Some(buf)
+        }
+        Err(e) => {
+            checks.push(
+                Check::fail(
+                    "microphone",
+                    format!("cannot open: {}", e),
+                    "Check that a recording device is set as default in the sound settings and that apps may use the microphone."
+                )
+            );
+            None
+        }
+    };
+    let shared_ref = match shared_ref {
+        Ok(buf) => {
+            checks.push(check_loopback(&loopback, &buf, tone.freq_hz));
+            Some(buf)
+        }
+        Err(e) => {
+            checks.push(
+                Check::fail(
+                    "loopback",
+                    format!("cannot open: {}", e),
+                    "Loopback capture needs Windows; check --loopback-device/--loopback-process and that an output device is active."
+                )
+            );
+            None
+        }
+    };
+    let streams_ok = checks[1..].iter().all(|c| c.outcome == Outcome::Pass);
+    checks.push(match (&shared_mic, &shared_ref) {
+        (Some(m), Some(r)) if streams_ok => check_direct_path(cli, m, r, &logger),
+        _ => Check::skip("direct path", "needs working microphone and loopback"),
+    });
+
+    for c in &checks {
+        let line = format!("self-test {}: {} {}", c.name, c.outcome.as_str(), c.detail);
+        let _ = if c.outcome == Outcome::Fail { logger.warn(&line) } else { logger.info(&line) };
+    }
+    print!("{}", report(&checks));
+
+    let failed = checks
+        .iter()
+        .filter(|c| c.outcome == Outcome::Fail)
+        .count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} self-test checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+/// The pass/fail table, with the hint under each failure.
+pub fn report(checks: &[Check]) -> String {
+    let mut out = String::from("sonar-presence self-test\n");
+    for c in checks {
+        out.push_str(&format!("  {}  {:<14} {}\n", c.outcome.as_str(), c.name, c.detail));
+        if c.outcome == Outcome::Fail && !c.hint.is_empty() {
+            out.push_str(&format!("        → {}\n", c.hint));
+        }
+    }
+    let failed = checks
+        .iter()
+        .filter(|c| c.outcome == Outcome::Fail)
+        .count();
+    if failed == 0 {
+        out.push_str("All checks passed.\n");
+    } else {
+        out.push_str(&format!("{} of {} checks failed.\n", failed, checks.len()));
+    }
+    out
+}
+
+/// Detection.log, Detection.csv and SongScan.csv can be created or appended to.
+pub fn check_paths(cli: &Config) -> Check {
+    let csv = output::sibling_path(&cli.log_path, "Detection.csv");
+    let paths = [Path::new(&cli.log_path), csv.as_path(), Path::new(&cli.scansong_path)];
+    let failures: Vec<String> = paths
+        .iter()
+        .filter_map(|p| writable(p).err().map(|e| format!("{}: {}", p.display(), e)))
+        .collect();
+    if failures.is_empty() {
+        let names: Vec<String> = paths
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        Check::pass("output paths", format!("writable: {}", names.join(", ")))
+    } else {
+        Check::fail(
+            "output paths",
+            failures.join("; "),
+            "Point --log-path/--scansong-path at a folder you can write to, or create it first."
+        )
+    }
+}
+
+/// Append access to `path` if it exists, else room to create it, without leaving anything behind.
+fn writable(path: &Path) -> io::Result<()> {
+    if path.exists() {
+        return OpenOptions::new().append(true).open(path).map(|_| ());
+    }
+    let probe = path.with_extension("selftest");
+    OpenOptions::new().create(true).truncate(true).write(true).open(&probe)?;
+    fs::remove_file(&probe)
+}
+
+fn dbfs(rms: f32) -> f32 {
+    20.0 * rms.max(1e-6).log10()
+}
+
+fn check_mic(mic: &CpalMic, buf: &SharedBuf) -> Check {
+    // a working device delivers close to real time
+    let expected = (buf.sr * LISTEN.as_secs_f32() * 0.5) as u64;
+    if buf.written() < expected {
+        return Check::fail(
+            "microphone",
+            format!("{}: only {} samples in {}s", mic.describe(), buf.written(), LISTEN.as_secs()),
+            "The device opened but delivers no audio; close programs holding it exclusively, or pick another default."
+        );
+    }
+    let level = buf.latest(buf.sr as usize).map(|x| prescan::rms(&x)).unwrap_or(0.0);
+    Check::pass("microphone", format!("{}: {} Hz, {:.0} dBFS", mic.describe(), buf.sr, dbfs(level)))
+}
+
+fn check_loopback(loopback: &Loopback, buf: &SharedBuf, tone_hz: f32) -> Check {
+    let level = buf.latest(buf.sr as usize).map(|x| prescan::rms(&x)).unwrap_or(0.0);
+    if level < SILENT_RMS {
+        return Check::fail(
+            "loopback",
+            format!("{}: silent ({:.0} dBFS) while a {:.0} Hz test tone played", loopback.describe(), dbfs(level), tone_hz),
+            "Unmute the output device and turn its volume up; with --loopback-device, check it is the one playing."
+        );
+    }
+    Check::pass("loopback", format!("{}: {} Hz, {:.0} dBFS with the test tone", loopback.describe(), buf.sr, dbfs(level)))
+}
+
+/// The mic hears the test tone from the speaker within the pipeline delay the detector expects.
+fn check_direct_path(cli: &Config, mic: &SharedBuf, reference: &SharedBuf, logger: &Logger) -> Check {
+    let len = sonar_presence::analysis_len(mic.sr, cli.front_max_m);
+    let mut frames = FramePairer::new(len, cli);
+    if frames.pair(mic, reference, logger) != Pairing::Ready {
+        return Check::fail(
+            "direct path",
+            "mic and loopback streams do not line up in time".to_string(),
+            "One stream stalls or lags; try another mic, or raise --max-skew-ms."
+        );
+    }
+    let measured = sonar_presence::estimate_from_ref(&frames.reference, &frames.mic, mic.sr, cli, false, None);
+    let found = measured.map(|m| (m.direct_lag, m.direct_r));
+    let hint = "Put the mic within a metre or two of the speaker, turn the volume up, and switch off the mic's noise suppression/echo cancellation.";
+    match found {
+        Some((lag, r)) if r.abs() >= DIRECT_MIN_R => {
+            let ms = ((lag as f32) / mic.sr) * 1000.0;
+            Check::pass("direct path", format!("{:.1} ms after the loopback (correlation {:.2})", ms, r))
+        }
+        Some((_, r)) => Check::fail("direct path", format!("the mic does not hear the test tone (correlation {:.2})", r), hint),
+        None => Check::fail("direct path", "no correlation (signals below --min-rms/--min-ref-rms)".to_string(), hint),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_and_report() {
+        let dir = std::env::temp_dir().join(format!("sonar-selftest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let in_dir = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let cfg = Config { log_path: in_dir("Detection.log"), scansong_path: in_dir("SongScan.csv"), ..Config::default() };
+        let ok = check_paths(&cfg);
+        assert_eq!(ok.outcome, Outcome::Pass, "{}", ok.detail);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0, "the check left files behind");
+
+        let missing = Config { scansong_path: in_dir("no/such/dir/SongScan.csv"), ..cfg };
+        let bad = check_paths(&missing);
+        assert_eq!(bad.outcome, Outcome::Fail);
+        assert!(bad.detail.contains("SongScan.csv"), "{}", bad.detail);
+
+        let text = report(&[ok, bad, Check::skip("direct path", "needs working microphone and loopback")]);
+        assert!(text.contains("  PASS  output paths"), "{}", text);
+        assert!(text.contains("→ Point --log-path"), "{}", text);
+        assert!(text.ends_with("1 of 3 checks failed.\n"), "{}", text);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{ Context, Result };
+use std::{
+    path::{ Path, PathBuf },
+    sync::Arc,
+    time::{ Duration, Instant },
+};
+
+use crate::{ calibration, output, profile, strategy, Config };
+use crate::logger::Logger;
+use crate::mods::eval::{ self, EvalReport, Evaluation };
+use crate::mods::replay;
+use crate::strategy::{ DetectorKind, PolicyKind };
+
+/// Settings shown in Detection.log besides the winner.
+const RUNNERS_UP: usize = 5;
+
+/// A tick's time in the recording and, once the frames are full, the echo it found (if any).
+type Measured = (f64, Option<Option<(f32, f32)>>);
+
+/// One recorded session, correlated once: what each tick measured, and the labels.
+struct Session {
+    name: String,
+    ticks: Vec<Measured>,
+    labels: Vec<(f64, f64)>,
+}
+
+/// One point of the grid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Candidate {
+    strength_thr: f32,
+    enter_frac: f32,
+    exit_frac: f32,
+    agg_frac: f32,
+    window_sec: u32,
+}
+
+impl Candidate {
+    fn apply(&self, cli: &Config) -> Config {
+        Config {
+            strength_thr: self.strength_thr,
+            enter_frac: self.enter_frac,
+            exit_frac: self.exit_frac,
+            agg_frac: self.agg_frac,
+            window_sec: self.window_sec,
+            ..cli.clone()
+        }
+    }
+
+    /// The settings this candidate decides, as profile lines. Hysteresis ignores --agg-frac and
+    /// majority the enter/exit pair, so only the ones the policy uses are written.
+    fn profile_values(&self, policy: PolicyKind) -> Vec<(&'static str, String)> {
+        let mut values = vec![
+            ("policy", format!("\"{}\"", policy.as_str())),
+            ("strength_thr", format!("{}", self.strength_thr)),
+            ("window_sec", format!("{}", self.window_sec)),
+        ];
+        match policy {
+            PolicyKind::Hysteresis => {
+                values.push(("enter_frac", format!("{}", self.enter_frac)));
+                values.push(("exit_frac", format!("{}", self.exit_frac)));
+            }
+            PolicyKind::Majority => values.push(("agg_frac", format!("{}", self.agg_frac))),
+        }
+        values
+    }
+}
+
+/// The grid around the current settings: the strength threshold scaled, the window shortened
+/// and lengthened, and the agreement fractions the policy uses stepped through.
+fn grid(cli: &Config) -> Vec<Candidate> {
+    let strengths: Vec<f32> = [0.5, 0.7, 0.85, 1.0, 1.2, 1.5, 2.0].iter().map(|k| (k * cli.strength_thr * 1000.0).round() / 1000.0).collect();
+    let mut windows: Vec<u32> = [0.5, 0.75, 1.0, 1.5, 2.0]
+        .iter()
+        .map(|k| ((cli.window_sec as f32) * k).round().max(1.0) as u32)
+        .collect();
+    windows.dedup();
+    let fracs = |from: u32, to: u32| (from..=to).map(|k| (k as f32) / 10.0).collect::<Vec<f32>>();
+    let mut out = Vec::new();
+    for &strength_thr in &strengths {
+        for &window_sec in &windows {
+            let base = Candidate { strength_thr, enter_frac: cli.enter_frac, exit_frac: cli.exit_frac, agg_frac: cli.agg_frac, window_sec };
+            match cli.policy {
+                PolicyKind::Hysteresis => {
+                    for &enter_frac in &fracs(3, 9) {
+                        for &exit_frac in fracs(1, 6).iter().filter(|&&x| x < enter_frac) {
+                            out.push(Candidate { enter_frac, exit_frac, ..base });
+                        }
+                    }
+                }
+                PolicyKind::Majority => {
+                    for &agg_frac in &fracs(2, 9) {
+                        out.push(Candidate { agg_frac, ..base });
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Run the smoothing stage alone over a session's measurements: the correlation does not
+/// depend on any of the tuned settings, so it is done once per session.
+fn evaluate(session: &Session, cfg: &Config) -> Evaluation {
+    let mut policy = strategy::policy(cfg, cfg.tick_ms);
+    let mut eval = Evaluation::new(session.labels.clone());
+    let t0 = Instant::now();
+    for &(t_s, measured) in &session.ticks {
+        let full = match measured {
+            Some(estimate) => {
+                let vote = estimate.filter(|&(d, s)| d <= cfg.dist_max_m && s >= cfg.strength_thr);
+                policy.push(vote, t0 + Duration::from_secs_f64(t_s)).is_some()
+            }
+            None => {
+                policy.age();
+                false
+            }
+        };
+        eval.tick(t_s, full, policy.present());
+    }
+    eval
+}
+
+/// Higher is better: F1 of precision and recall, then fewer false flips, then a quicker detection.
+fn rank(r: &EvalReport) -> (i64, i64, i64) {
+    let (p, rc) = (r.precision.unwrap_or(0.0), r.recall.unwrap_or(0.0));
+    let f1 = if p + rc > 0.0 { (2.0 * p * rc) / (p + rc) } else { 0.0 };
+    ((f1 * 1000.0).round() as i64, -(r.false_flips as i64), -(r.latency_mean_s.unwrap_or(f64::MAX / 1e6) * 10.0) as i64)
+}
+
+/// `--tune-session` directories (ref.wav, mic.wav, labels.csv), and --ref-wav/--mic-wav/--labels.
+fn session_sources(cli: &Config) -> Result<Vec<(PathBuf, PathBuf, PathBuf)>> {
+    let mut out: Vec<(PathBuf, PathBuf, PathBuf)> = cli.tune_sessions
+        .iter()
+        .map(|dir| {
+            let dir = Path::new(dir);
+            (dir.join("ref.wav"), dir.join("mic.wav"), dir.join("labels.csv"))
+        })
+        .collect();
+    if !cli.replay_ref_wav.is_empty() || !cli.replay_mic_wav.is_empty() || !cli.labels.is_empty() {
+        if cli.replay_ref_wav.is_empty() || cli.replay_mic_wav.is_empty() || cli.labels.is_empty() {
+            anyhow::bail!("--ref-wav, --mic-wav and --labels go together");
+        }
+        out.push((PathBuf::from(&cli.replay_ref_wav), PathBuf::from(&cli.replay_mic_wav), PathBuf::from(&cli.labels)));
+    }
+    if out.is_empty() {
+        anyhow::bail!("tune mode needs labelled sessions: --tune-session <DIR> (repeatable) or --ref-wav/--mic-wav/--labels");
+    }
+    Ok(out)
+}
+
+/// Tune mode: replay labelled sessions once each, then score every point of a grid of
+/// `--strength-thr`, window length and the policy's agreement fractions against the labels.
+/// The best settings are written as a profile (`--profile`) to `--report-out`, or stdout.
+pub fn run_tune(cli: &Config, logger: Arc<Logger>) -> Result<()> {
+    if cli.detector == DetectorKind::Onnx {
+        anyhow::bail!("tune mode sweeps the voting thresholds, which --detector onnx leaves to its model");
+    }
+    let mut sessions = Vec::new();
+    for (reference, mic, labels) in session_sources(cli)? {
+        let labels = calibration::load_labels(&labels)?;
+        let cfg = Config {
+            replay_ref_wav: reference.to_string_lossy().into_owned(),
+            replay_mic_wav: mic.to_string_lossy().into_owned(),
+            replay_speed: 0.0,
+            labels: String::new(),
+            debug_dump: String::new(),
+            features: String::new(),
+            log_every_tick: false,
+            ..cli.clone()
+        };
+        let mut ticks = Vec::new();
+        replay::replay_session(&cfg, &logger, |_, t_s, res| ticks.push((t_s, res.map(|r| r.estimate))))
+            .with_context(|| format!("replaying {}", reference.display()))?;
+        let name = reference.parent().map_or_else(|| reference.display().to_string(), |p| p.display().to_string());
+        logger.info(&format!("Tune: {} ticks, {} present stretches from {}", ticks.len(), labels.len(), name))?;
+        sessions.push(Session { name, ticks, labels });
+    }
+
+    let candidates = grid(cli);
+    logger.info(&format!("Tune: trying {} settings over {} session(s)", candidates.len(), sessions.len()))?;
+    let mut scored: Vec<(Candidate, EvalReport)> = candidates
+        .iter()
+        .map(|c| {
+            let cfg = c.apply(cli);
+            let evals: Vec<Evaluation> = sessions.iter().map(|s| evaluate(s, &cfg)).collect();
+            (*c, eval::combine(&evals))
+        })
+        .collect();
+    scored.sort_by_key(|(_, r)| std::cmp::Reverse(rank(r)));
+
+    for (c, r) in scored.iter().take(RUNNERS_UP) {
+        let values: Vec<String> = c.profile_values(cli.policy).iter().skip(1).map(|(k, v)| format!("{}={}", k, v)).collect();
+        logger.info(&format!("Tune: {}: {}", values.join(" "), r.summary()))?;
+    }
+    let (best, report) = scored.first().context("no settings to try")?;
+    let current = eval::combine(&sessions.iter().map(|s| evaluate(s, cli)).collect::<Vec<_>>());
+    let header = vec![
+        format!("sonar-presence profile from --mode tune, {}", chrono::Local::now().format("%Y-%m-%d %H:%M")),
+        format!("best of {} settings over {}", candidates.len(), sessions.iter().map(|s| s.name.as_str()).collect::<Vec<_>>().join(", ")),
+        format!("best:    {}", report.summary()),
+        format!("current: {}", current.summary()),
+        "use with: sonar-presence --profile <this file>".to_string(),
+    ];
+    let text = profile::render(&header, &best.profile_values(cli.policy));
+    if cli.report_out.is_empty() {
+        print!("{}", text);
+    } else {
+        output::write_atomic(Path::new(&cli.report_out), &text)?;
+        logger.info(&format!("Tuned profile written to {}", cli.report_out))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_sweep_finds_a_threshold_between_noise_and_echo() {
+        // someone there 20–40 s whose echo comes in at strength 0.5; before and after, noise at 0.3
+        let ticks = (1..600)
+            .map(|k| {
+                let t = (k as f64) * 0.1;
+                let strength = if (20.0..40.0).contains(&t) { 0.5 } else { 0.3 };
+                (t, Some(Some((0.8f32, strength))))
+            })
+            .collect();
+        let session = Session { name: "synthetic".to_string(), ticks, labels: vec![(20.0, 40.0)] };
+        let cli = Config { strength_thr: 0.2, window_sec: 2, tick_ms: 100, min_dwell_ms: 0, ..Config::default() };
+        // at the current threshold the noise counts and the room never empties
+        assert_eq!(evaluate(&session, &cli).finish().precision.map(|p| p < 0.5), Some(true));
+
+        let best = grid(&cli)
+            .into_iter()
+            .max_by_key(|c| rank(&evaluate(&session, &c.apply(&cli)).finish()))
+            .unwrap();
+        assert!(best.strength_thr > 0.3 && best.strength_thr <= 0.5, "{:?}", best);
+        let r = evaluate(&session, &best.apply(&cli)).finish();
+        assert!(r.precision.unwrap() > 0.95 && r.recall.unwrap() > 0.85, "{:?}", r);
+        assert_eq!(r.false_flips, 0);
+    }
+}
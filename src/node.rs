@@ -0,0 +1,518 @@
+//! src/node.rs
+//! Sensor nodes: cheap machines placed around a room run `--mode node --server <addr>`,
+//! correlate their own mic against their own speaker's loopback, and send one line of JSON
+//! per tick to a presence instance started with `--node-listen <addr>`. What travels is the
+//! measurement (levels, echo distance and strength), never audio. The central instance holds
+//! each node's echo to its own thresholds and counts a tick as voted when it or any node
+//! found an echo that counts, so everything after the vote (window, hysteresis, outputs)
+//! sees one room. Without `--server` a node finds the instance over mDNS (see discovery.rs);
+//! `--pair-token` keeps nodes and instances that share a network but not a room apart.
+
+use anyhow::{ Context, Result };
+use crossbeam_channel::{ unbounded, Receiver, Sender };
+use std::{
+    collections::{ BTreeMap, HashSet },
+    io::{ BufRead, BufReader, Read, Write },
+    net::{ SocketAddr, TcpListener, TcpStream, ToSocketAddrs },
+    sync::{ Arc, Mutex },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::auth;
+use crate::discovery::{ self, Advert };
+use crate::logger::{ Field, Logger };
+use crate::output::JsonObj;
+use crate::supervise;
+use crate::{ json, Config };
+
+/// Wire format version, in the hello line. 2: the hello carries the pairing token, and the
+/// server answers it.
+pub const PROTOCOL: i64 = 2;
+/// A node that has sent nothing for this long is reported lost.
+const SILENT_AFTER: Duration = Duration::from_secs(5);
+/// The node waits this long between attempts to reach the server.
+pub const RECONNECT: Duration = Duration::from_secs(5);
+
+/// How long a node waits for the server to answer its hello.
+const ANSWER_WAIT: Duration = Duration::from_secs(3);
+/// How long the server waits for a new connection's hello, and the most of it read; a real
+/// hello is a short line, and it is read before the pairing token has been checked.
+const HELLO_WAIT: Duration = Duration::from_secs(5);
+const MAX_HELLO_BYTES: u64 = 4 * 1024;
+
+/// The name this machine goes by, for nodes and the instances they find.
+pub fn host_name() -> String {
+    std::env
+        ::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "node".to_string())
+}
+
+/// The first line a node sends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hello {
+    pub node: String,
+    pub tick_ms: u64,
+    pub token: String, // --pair-token; empty = none
+}
+
+impl Hello {
+    pub fn line(&self) -> String {
+        JsonObj::new()
+            .int("protocol", PROTOCOL)
+            .str("node", &self.node)
+            .int("tick_ms", self.tick_ms as i64)
+            .str("token", &self.token)
+            .finish()
+    }
+
+    /// The hello, or why it cannot be taken.
+    fn parse(line: &str) -> Result<Self, &'static str> {
+        let doc = json::parse(line).map_err(|_| "not a sonar-presence node")?;
+        let protocol = doc.get("protocol").and_then(|v| v.as_f64()).ok_or("not a sonar-presence node")?;
+        if protocol as i64 != PROTOCOL {
+            return Err("different protocol version: update the node and the server together");
+        }
+        let text_of = |k: &str| doc.get(k).and_then(|v| v.as_str()).map(str::to_string);
+        Ok(Self {
+            node: text_of("node").ok_or("hello without a node name")?,
+            tick_ms: doc.get("tick_ms").and_then(|v| v.as_f64()).unwrap_or(0.0) as u64,
+            token: text_of("token").unwrap_or_default(),
+        })
+    }
+}
+
+/// One analysed tick on a node.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeTick {
+    pub t_s: f64, // since the node started
+    pub rms: (f32, f32), // (loopback, mic)
+    pub estimate: Option<(f32, f32)>, // echo (distance_m, strength), if one was found
+}
+
+impl NodeTick {
+    pub fn line(&self) -> String {
+        let obj = JsonObj::new().num("t", self.t_s).num("rms_ref", self.rms.0 as f64).num("rms_mic", self.rms.1 as f64);
+        match self.estimate {
+            Some((d, s)) => obj.num("distance_m", d as f64).num("strength", s as f64),
+            None => obj.null("distance_m").null("strength"),
+        }.finish()
+    }
+
+    /// The echo, if it counts under `cfg`'s thresholds.
+    pub fn counts(&self, cfg: &Config) -> Option<(f32, f32)> {
+        self.estimate.filter(|&(d, s)| d <= cfg.dist_max_m && s >= cfg.strength_thr)
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let doc = json::parse(line).ok()?;
+        let num = |k: &str| doc.get(k).and_then(|v| v.as_f64());
+        Some(Self {
+            t_s: num("t")?,
+            rms: (num("rms_ref")? as f32, num("rms_mic")? as f32),
+            estimate: num("distance_m").zip(num("strength")).map(|(d, s)| (d as f32, s as f32)),
+        })
+    }
+}
+
+/// From the connection threads; `u64` numbers the connection, so only the one a node joined
+/// on can send its ticks or say it left.
+enum NodeMsg {
+    Refused(SocketAddr, String),
+    Joined(u64, String, SocketAddr),
+    Tick(u64, String, NodeTick, Instant),
+    Left(u64, String),
+}
+
+/// What the central instance knows of one node.
+#[derive(Clone, Debug)]
+pub struct Seen {
+    pub last: Instant, // its last tick arrived
+    pub ticks: u64,
+    pub lost: bool, // sent nothing for SILENT_AFTER
+    conn: u64, // the connection it joined on
+}
+
+/// What came in from the nodes since the last `receive`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeEvent {
+    Joined(String),
+    Tick(String, NodeTick, Instant), // with when it arrived
+    Lost(String),
+    Back(String),
+    Left(String, u64), // with the ticks it sent
+}
+
+/// Central side (`--node-listen`): accepts nodes and hands their votes to the loop.
+pub struct NodeHub {
+    rx: Receiver<NodeMsg>,
+    nodes: BTreeMap<String, Seen>,
+    logger: Arc<Logger>,
+}
+
+impl NodeHub {
+    /// None without --node-listen.
+    pub fn start(cfg: &Config, logger: Arc<Logger>) -> Result<Option<Self>> {
+        if cfg.node_listen.is_empty() {
+            return Ok(None);
+        }
+        let listener = TcpListener::bind(&cfg.node_listen).with_context(|| format!("--node-listen {}", cfg.node_listen))?;
+        let port = listener.local_addr()?.port();
+        logger.info(&format!("listening for sensor nodes on {}", listener.local_addr()?))?;
+        auth::warn_if_open(&cfg.node_listen, "--node-listen", "--pair-token", &cfg.pair_token, &logger);
+        if cfg.mdns {
+            let txt = vec![
+                format!("protocol={}", PROTOCOL),
+                format!("mode={}", cfg.mode.as_str()),
+                format!("token={}", if cfg.pair_token.is_empty() { "none" } else { "required" })
+            ];
+            match discovery::advertise(Advert::new(&host_name(), port, txt), logger.clone()) {
+                Ok(()) => logger.info(&format!("advertised as {} on mDNS", discovery::SERVICE))?,
+                Err(e) => logger.warn(&format!("not advertised on mDNS ({}); nodes need --server", e))?,
+            }
+        }
+        let (tx, rx) = unbounded();
+        let token = cfg.pair_token.clone();
+        let names = Arc::new(Mutex::new(HashSet::new()));
+        supervise::spawn("node listener", logger.clone(), move || {
+            for (conn, stream) in (0u64..).zip(listener.incoming().flatten()) {
+                let (tx, token, names) = (tx.clone(), token.clone(), names.clone());
+                thread::spawn(move || serve(conn, stream, tx, &token, &names));
+            }
+        });
+        Ok(Some(Self { rx, nodes: BTreeMap::new(), logger }))
+    }
+
+    /// Take in what the nodes sent, logging who came, went or fell silent.
+    pub fn receive(&mut self) -> Vec<NodeEvent> {
+        let now = Instant::now();
+        let mut events = Vec::new();
+        for msg in self.rx.try_iter() {
+            match msg {
+                NodeMsg::Refused(from, why) => {
+                    let _ = self.logger.warn(&format!("refused a node from {}: {}", from, why));
+                }
+                NodeMsg::Joined(conn, name, from) => {
+                    let _ = self.logger.event(&format!("node '{}' connected from {}", name, from), &[("node", Field::Str(&name))]);
+                    self.nodes.insert(name.clone(), Seen { last: now, ticks: 0, lost: false, conn });
+                    events.push(NodeEvent::Joined(name));
+                }
+                NodeMsg::Tick(conn, name, tick, at) => {
+                    let Some(seen) = self.nodes.get_mut(&name).filter(|s| s.conn == conn) else {
+                        continue;
+                    };
+                    seen.last = at;
+                    seen.ticks += 1;
+                    if seen.lost {
+                        seen.lost = false;
+                        let _ = self.logger.info(&format!("node '{}' is sending again", name));
+                        events.push(NodeEvent::Back(name.clone()));
+                    }
+                    events.push(NodeEvent::Tick(name, tick, at));
+                }
+                NodeMsg::Left(conn, name) => {
+                    if self.nodes.get(&name).is_some_and(|s| s.conn == conn) {
+                        let seen = self.nodes.remove(&name).unwrap();
+                        let _ = self.logger.event(
+                            &format!("node '{}' disconnected after {} ticks", name, seen.ticks),
+                            &[("node", Field::Str(&name))]
+                        );
+                        events.push(NodeEvent::Left(name, seen.ticks));
+                    }
+                }
+            }
+        }
+        for (name, seen) in self.nodes.iter_mut() {
+            if !seen.lost && now.saturating_duration_since(seen.last) > SILENT_AFTER {
+                seen.lost = true;
+                let _ = self.logger.warn(&format!("node '{}' has sent nothing for {} s", name, SILENT_AFTER.as_secs()));
+                events.push(NodeEvent::Lost(name.clone()));
+            }
+        }
+        events
+    }
+
+    /// The nodes connected now.
+    pub fn nodes(&self) -> &BTreeMap<String, Seen> {
+        &self.nodes
+    }
+
+    /// Take in what the nodes sent; the strongest of their echoes that counts under `cfg`'s
+    /// thresholds since the last call, if any.
+    pub fn take_vote(&mut self, cfg: &Config) -> Option<(f32, f32)> {
+        let mut vote: Option<(f32, f32)> = None;
+        for ev in self.receive() {
+            let NodeEvent::Tick(_, tick, _) = ev else {
+                continue;
+            };
+            if let Some((d, s)) = tick.counts(cfg) {
+                if vote.is_none_or(|(_, best)| s > best) {
+                    vote = Some((d, s));
+                }
+            }
+        }
+        vote
+    }
+}
+
+/// `--node-weights kitchen=2,door=0.5`: how much each node's vote counts in hub mode.
+pub fn parse_weights(s: &str) -> Result<Vec<(String, f32)>, String> {
+    s.split(',')
+        .filter(|p| !p.trim().is_empty())
+        .map(|p| {
+            let (name, w) = p.split_once('=').ok_or_else(|| format!("Invalid node weight '{}' (expected NAME=WEIGHT)", p))?;
+            let w = w
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|w| w.is_finite() && *w >= 0.0)
+                .ok_or_else(|| format!("Invalid node weight '{}' (expected NAME=WEIGHT)", p))?;
+            Ok((name.trim().to_string(), w))
+        })
+        .collect()
+}
+
+/// One node connection: a hello, answered, then ticks until it goes away. `names` holds the
+/// names of the nodes connected now; a hello under one of them is refused.
+fn serve(conn: u64, stream: TcpStream, tx: Sender<NodeMsg>, token: &str, names: &Mutex<HashSet<String>>) {
+    let (Ok(from), Ok(mut answer)) = (stream.peer_addr(), stream.try_clone()) else {
+        return;
+    };
+    let _ = stream.set_read_timeout(Some(HELLO_WAIT));
+    let mut reader = BufReader::new(stream.take(MAX_HELLO_BYTES));
+    let mut first = String::new();
+    let read = reader.read_line(&mut first);
+    let hello = match read {
+        Ok(_) if first.ends_with('\n') => Hello::parse(&first),
+        // ran into the limit; anything else (cut short, silent past HELLO_WAIT) gets no answer
+        Ok(_) if reader.get_ref().limit() == 0 => Err("hello too long"),
+        _ => {
+            return;
+        }
+    };
+    let hello = hello
+        .and_then(|h| {
+            if auth::same_secret(&h.token, token) { Ok(h) } else { Err("wrong pairing token (--pair-token)") }
+        })
+        .and_then(|h| {
+            if names.lock().unwrap().insert(h.node.clone()) { Ok(h) } else { Err("a node of that name is already connected") }
+        });
+    let hello = match hello {
+        Ok(h) => h,
+        Err(why) => {
+            let _ = writeln!(answer, "{}", JsonObj::new().bool("accepted", false).str("reason", why).finish());
+            let _ = tx.send(NodeMsg::Refused(from, why.to_string()));
+            return;
+        }
+    };
+    if writeln!(answer, "{}", JsonObj::new().bool("accepted", true).finish()).is_ok() {
+        reader.get_mut().set_limit(u64::MAX);
+        let _ = answer.set_read_timeout(None);
+        let _ = tx.send(NodeMsg::Joined(conn, hello.node.clone(), from));
+        for line in reader.lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if let Some(tick) = NodeTick::parse(&line) {
+                if tx.send(NodeMsg::Tick(conn, hello.node.clone(), tick, Instant::now())).is_err() {
+                    break;
+                }
+            }
+        }
+        let _ = tx.send(NodeMsg::Left(conn, hello.node.clone()));
+    }
+    // after Left, so a node that reconnects under the name joins after it left
+    names.lock().unwrap().remove(&hello.node);
+}
+
+/// Node side: the connection to the server, made again whenever it drops. Without a server
+/// address, whatever answers on mDNS is tried in turn.
+pub struct NodeLink {
+    server: String,
+    hello: Hello,
+    stream: Option<TcpStream>,
+    tried: Option<Instant>,
+    logger: Arc<Logger>,
+}
+
+impl NodeLink {
+    pub fn new(server: &str, hello: Hello, logger: Arc<Logger>) -> Self {
+        Self { server: server.to_string(), hello, stream: None, tried: None, logger }
+    }
+
+    fn target(&self) -> &str {
+        if self.server.is_empty() { "a server on mDNS" } else { &self.server }
+    }
+
+    /// Connect, say hello and take the server's answer.
+    fn connect_to(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(3))?;
+        stream.set_nodelay(true)?;
+        writeln!(stream, "{}", self.hello.line())?;
+        stream.set_read_timeout(Some(ANSWER_WAIT))?;
+        let mut line = String::new();
+        BufReader::new(stream.try_clone()?).read_line(&mut line).context("no answer to the hello")?;
+        let doc = json::parse(&line).ok().context("no answer to the hello (an older server?)")?;
+        if doc.get("accepted").and_then(|v| v.as_bool()) != Some(true) {
+            let why = doc.get("reason").and_then(|v| v.as_str()).unwrap_or("no reason given");
+            anyhow::bail!("refused: {}", why);
+        }
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// The connection, and whom it is to.
+    fn connect(&self) -> Result<(String, TcpStream)> {
+        if !self.server.is_empty() {
+            let addr = self.server
+                .to_socket_addrs()?
+                .next()
+                .with_context(|| format!("{} resolves to no address", self.server))?;
+            return Ok((self.server.clone(), self.connect_to(addr)?));
+        }
+        let found = discovery::browse(discovery::BROWSE_FOR).context("mDNS")?;
+        if found.is_empty() {
+            anyhow::bail!("nothing answered on mDNS (is --node-listen set there, or pass --server)");
+        }
+        let mut failed = Vec::new();
+        for f in found {
+            let who = format!("'{}' at {}", f.name, f.addr);
+            match self.connect_to(f.addr) {
+                Ok(stream) => {
+                    return Ok((who, stream));
+                }
+                Err(e) => failed.push(format!("{}: {:#}", who, e)),
+            }
+        }
+        anyhow::bail!("{}", failed.join("; "))
+    }
+
+    /// Send one tick; dropped while the server cannot be reached.
+    pub fn send(&mut self, tick: &NodeTick) {
+        if self.stream.is_none() {
+            if self.tried.is_some_and(|at| at.elapsed() < RECONNECT) {
+                return;
+            }
+            self.tried = Some(Instant::now());
+            match self.connect() {
+                Ok((who, stream)) => {
+                    let _ = self.logger.info(&format!("connected to {} as node '{}'", who, self.hello.node));
+                    self.stream = Some(stream);
+                }
+                Err(e) => {
+                    let _ = self.logger.warn(&format!("cannot reach {}: {:#}; retrying every {} s", self.target(), e, RECONNECT.as_secs()));
+                    return;
+                }
+            }
+        }
+        if let Some(stream) = self.stream.as_mut() {
+            if let Err(e) = writeln!(stream, "{}", tick.line()) {
+                let _ = self.logger.warn(&format!("lost {}: {}", self.target(), e));
+                self.stream = None;
+                self.tried = Some(Instant::now());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_ticks_reach_the_hub_as_votes() {
+        let log_path = std::env::temp_dir().join("sonar-node.log");
+        let logger = Arc::new(Logger::new(&log_path.to_string_lossy(), false).unwrap());
+        let cfg = Config { node_listen: "127.0.0.1:0".to_string(), ..Config::default() };
+        // bind here to learn the port, then hand the hub a listener on it
+        let listener = TcpListener::bind(&cfg.node_listen).unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let cfg = Config { node_listen: addr.to_string(), pair_token: "s3cret".to_string(), mdns: false, ..cfg };
+        let mut hub = NodeHub::start(&cfg, logger.clone()).unwrap().unwrap();
+
+        let hello = |token: &str| Hello { node: "kitchen".to_string(), tick_ms: 100, token: token.to_string() };
+        let stranger = NodeLink::new(&addr.to_string(), hello("guess"), logger.clone());
+        let err = stranger.connect().err().unwrap().to_string();
+        assert!(err.contains("pairing token"), "{}", err);
+        let mut link = NodeLink::new(&addr.to_string(), hello("s3cret"), logger);
+        let tick = |estimate| NodeTick { t_s: 1.5, rms: (0.02, 0.01), estimate };
+        link.send(&tick(None));
+        link.send(&tick(Some((0.9, 0.05)))); // too weak to count
+        link.send(&tick(Some((0.8, 0.4))));
+        link.send(&tick(Some((1.2, 0.3))));
+        let t0 = Instant::now();
+        let mut votes = Vec::new();
+        while hub.nodes.get("kitchen").is_none_or(|n| n.ticks < 4) && t0.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(20));
+            votes.extend(hub.take_vote(&cfg));
+        }
+        // the weak echo never counts; the strong one does, whichever call it came in with
+        assert_eq!(votes.first(), Some(&(0.8, 0.4)));
+        assert!(votes.iter().all(|v| v.1 >= 0.3));
+        assert!(hub.nodes.contains_key("kitchen"));
+        assert_eq!(NodeTick::parse(&tick(None).line()), Some(tick(None)));
+    }
+
+    #[test]
+    fn a_second_node_cannot_take_a_connected_name() {
+        let log_path = std::env::temp_dir().join("sonar-node-names.log");
+        let logger = Arc::new(Logger::new(&log_path.to_string_lossy(), false).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let cfg = Config { node_listen: addr.to_string(), mdns: false, ..Config::default() };
+        let mut hub = NodeHub::start(&cfg, logger.clone()).unwrap().unwrap();
+        let hello = Hello { node: "kitchen".to_string(), tick_ms: 100, token: String::new() };
+        let wait_for = |hub: &mut NodeHub, want: &dyn Fn(&NodeEvent) -> bool| {
+            let t0 = Instant::now();
+            while t0.elapsed() < Duration::from_secs(5) {
+                if hub.receive().iter().any(want) {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            false
+        };
+
+        let mut first = NodeLink::new(&addr.to_string(), hello.clone(), logger.clone());
+        first.send(&NodeTick { t_s: 0.1, rms: (0.02, 0.01), estimate: None });
+        assert!(wait_for(&mut hub, &|e| matches!(e, NodeEvent::Joined(_))));
+
+        // an impostor is refused, and its going away does not remove the real one
+        let impostor = NodeLink::new(&addr.to_string(), hello.clone(), logger.clone());
+        let err = impostor.connect().err().unwrap().to_string();
+        assert!(err.contains("already connected"), "{}", err);
+        drop(impostor);
+        first.send(&NodeTick { t_s: 0.2, rms: (0.02, 0.01), estimate: None });
+        assert!(wait_for(&mut hub, &|e| matches!(e, NodeEvent::Tick(..))));
+        assert!(hub.nodes().contains_key("kitchen"));
+
+        // once the first has left, the name is free again
+        drop(first);
+        assert!(wait_for(&mut hub, &|e| matches!(e, NodeEvent::Left(..))));
+        let again = NodeLink::new(&addr.to_string(), hello, logger);
+        assert!(again.connect().is_ok());
+    }
+
+    #[test]
+    fn an_endless_hello_is_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = unbounded();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(0, stream, tx, "s3cret", &Mutex::new(HashSet::new()));
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // exactly the limit, so the server has read everything sent when it closes
+        stream.write_all(&vec![b'x'; MAX_HELLO_BYTES as usize]).unwrap();
+        let mut answer = String::new();
+        BufReader::new(&stream).read_line(&mut answer).unwrap();
+        assert!(answer.contains("\"accepted\":false") && answer.contains("too long"), "{}", answer);
+        assert!(matches!(rx.recv_timeout(Duration::from_secs(5)), Ok(NodeMsg::Refused(_, why)) if why == "hello too long"));
+    }
+}
@@ -0,0 +1,779 @@
+//! src/onnx.rs
+//! `--detector onnx --model <PATH>`: a user-trained classifier decides each tick's vote from
+//! the `--features` vector. The model file is read and run here, without an ONNX runtime:
+//! protobuf is decoded field by field and the graph is evaluated node by node on f32 tensors.
+//! That covers the small feed-forward models such a classifier is (logistic regression, an
+//! MLP, as exported from PyTorch or Keras): Gemm, MatMul, the element-wise arithmetic and
+//! activations, Softmax, Flatten, Reshape, Constant. Other operators are refused at load, as
+//! are IR versions and opsets outside `IR_VERSIONS` and `OPSETS` and tensors of negative or
+//! symbolic size.
+
+use anyhow::{ anyhow, bail, Context, Result };
+use std::{ collections::HashMap, fs, path::Path };
+
+use crate::logger::Logger;
+use crate::strategy::DetectorKind;
+use crate::Config;
+
+/// A dense tensor; integer tensors (shapes, axes) are held as f32 too.
+#[derive(Clone, Debug, PartialEq)]
+struct Tensor {
+    shape: Vec<usize>,
+    data: Vec<f32>,
+}
+
+impl Tensor {
+    fn new(shape: Vec<usize>, data: Vec<f32>) -> Result<Self> {
+        if shape.iter().product::<usize>() != data.len() {
+            bail!("tensor of shape {:?} holds {} values", shape, data.len());
+        }
+        Ok(Self { shape, data })
+    }
+
+    /// Rows and columns of a matrix; a vector is one row.
+    fn matrix(&self) -> Result<(usize, usize)> {
+        match self.shape[..] {
+            [n] => Ok((1, n)),
+            [r, c] => Ok((r, c)),
+            _ => bail!("expected a matrix, got shape {:?}", self.shape),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Attr {
+    f: Option<f32>,
+    i: Option<i64>,
+    t: Option<Tensor>,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    op: String,
+    inputs: Vec<String>, // "" = optional input left out
+    outputs: Vec<String>,
+    attrs: HashMap<String, Attr>,
+}
+
+impl Node {
+    fn f(&self, name: &str, default: f32) -> f32 {
+        self.attrs.get(name).and_then(|a| a.f).unwrap_or(default)
+    }
+
+    fn i(&self, name: &str, default: i64) -> i64 {
+        self.attrs.get(name).and_then(|a| a.i).unwrap_or(default)
+    }
+}
+
+const SUPPORTED: &[&str] = &[
+    "Gemm", "MatMul", "Add", "Sub", "Mul", "Div", "Relu", "LeakyRelu", "Sigmoid", "Tanh", "Softmax", "Flatten",
+    "Reshape", "Identity", "Dropout", "Constant", "Clip",
+];
+
+/// The ModelProto `ir_version`s read here (ONNX 1.2 to 1.18).
+const IR_VERSIONS: std::ops::RangeInclusive<i64> = 3..=11;
+
+/// The default-domain opsets whose versions of the operators above behave as `run` does.
+const OPSETS: std::ops::RangeInclusive<i64> = 7..=23;
+
+/// A loaded model: its nodes in order, its weights, and the names of its input and output.
+#[derive(Clone, Debug)]
+pub struct Model {
+    nodes: Vec<Node>,
+    weights: HashMap<String, Tensor>,
+    input: String,
+    width: Option<usize>, // the input's last dimension, when the file states it
+    output: String,
+}
+
+impl Model {
+    /// The `--model` of `--detector onnx`; None for the other detectors.
+    pub fn from_config(cfg: &Config, logger: &Logger) -> Result<Option<Self>> {
+        if cfg.detector != DetectorKind::Onnx {
+            return Ok(None);
+        }
+        if cfg.model.is_empty() {
+            bail!("--detector onnx needs --model <PATH>");
+        }
+        let model = Self::load(Path::new(&cfg.model))?;
+        logger.info(
+            &format!(
+                "Model: {} ({} nodes, {} weights), voting at probability ≥ {:.2}",
+                cfg.model,
+                model.nodes.len(),
+                model.weights.len(),
+                cfg.model_thr
+            )
+        )?;
+        Ok(Some(model))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let model = Self::read(path)?;
+        if let Some(w) = model.width.filter(|&w| w != crate::features::MODEL_INPUTS.len()) {
+            bail!(
+                "{}: the model takes {} inputs, the feature vector has {} (see --features)",
+                path.display(),
+                w,
+                crate::features::MODEL_INPUTS.len()
+            );
+        }
+        Ok(model)
+    }
+
+    /// A model of any input width (`load` checks it against the feature vector).
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("reading model {}", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("loading model {}", path.display()))
+    }
+
+    /// The input's width, when the file states it.
+    pub fn inputs(&self) -> Option<usize> {
+        self.width
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let model = fields(bytes)?;
+        let graph = model
+            .iter()
+            .find(|(n, _)| *n == 7)
+            .and_then(|(_, v)| v.bytes())
+            .context("no graph in the file (not an ONNX model?)")?;
+        let ir = model.iter().find(|(n, _)| *n == 1).and_then(|(_, v)| v.int()).context("the model states no ir_version")?;
+        if !IR_VERSIONS.contains(&ir) {
+            bail!("IR version {} is not supported ({} to {})", ir, IR_VERSIONS.start(), IR_VERSIONS.end());
+        }
+        let opsets = sub_of(&model, 8)
+            .into_iter()
+            .map(opset)
+            .collect::<Result<Vec<_>>>()?;
+        if opsets.is_empty() {
+            bail!("the model imports no opset");
+        }
+        for (domain, version) in &opsets {
+            if !domain.is_empty() && domain != "ai.onnx" {
+                bail!("opset domain {} is not supported (only the default ai.onnx)", domain);
+            }
+            if !OPSETS.contains(version) {
+                bail!("opset {} is not supported ({} to {})", version, OPSETS.start(), OPSETS.end());
+            }
+        }
+        let (mut nodes, mut weights, mut inputs, mut outputs) = (Vec::new(), HashMap::new(), Vec::new(), Vec::new());
+        for (n, v) in fields(graph)? {
+            match (n, v.bytes()) {
+                (1, Some(b)) => nodes.push(node(b)?),
+                (5, Some(b)) => {
+                    let (name, t) = tensor(b)?;
+                    weights.insert(name, t);
+                }
+                (11, Some(b)) => inputs.push(value_info(b)?),
+                (12, Some(b)) => outputs.push(value_info(b)?),
+                _ => {}
+            }
+        }
+        if let Some(n) = nodes.iter().find(|n| !SUPPORTED.contains(&n.op.as_str())) {
+            bail!("operator {} is not supported (supported: {})", n.op, SUPPORTED.join(", "));
+        }
+        // initializers may also be listed as inputs; the one left is the feature vector
+        let (input, width) = inputs
+            .into_iter()
+            .find(|(name, _)| !weights.contains_key(name))
+            .context("the model has no input")?;
+        let output = outputs.into_iter().next().context("the model has no output")?.0;
+        Ok(Self { nodes, weights, input, width, output })
+    }
+
+    /// Probability of presence for one feature vector: the output's only value, or its second
+    /// (class 1 of a two-class softmax), clamped to 0..1. Any other output shape is an error.
+    pub fn probability(&self, features: &[f32]) -> Result<f32> {
+        let mut values: HashMap<&str, Tensor> = HashMap::new();
+        values.insert(&self.input, Tensor::new(vec![1, features.len()], features.to_vec())?);
+        for node in &self.nodes {
+            let get = |k: usize| -> Result<Option<&Tensor>> {
+                match node.inputs.get(k).map(String::as_str) {
+                    None | Some("") => Ok(None),
+                    Some(name) =>
+                        values
+                            .get(name)
+                            .or_else(|| self.weights.get(name))
+                            .map(Some)
+                            .ok_or_else(|| anyhow!("{}: input {} not computed", node.op, name)),
+                }
+            };
+            let need = |k: usize| get(k)?.ok_or_else(|| anyhow!("{}: input {} missing", node.op, k));
+            let out = run(node, &need, &get).with_context(|| format!("running {}", node.op))?;
+            values.insert(node.outputs.first().map_or("", String::as_str), out);
+        }
+        let out = values
+            .get(self.output.as_str())
+            .or_else(|| self.weights.get(&self.output))
+            .ok_or_else(|| anyhow!("output {} not computed", self.output))?;
+        let p = match (&out.data[..], out.shape.last()) {
+            ([p], _) => *p,
+            ([_, p], Some(2)) => *p,
+            _ => bail!("output of shape {:?}: expected one probability or two class scores", out.shape),
+        };
+        Ok(if p.is_nan() { 0.0 } else { p.clamp(0.0, 1.0) })
+    }
+}
+
+/// One node's output.
+fn run<'a>(
+    node: &Node,
+    need: &dyn Fn(usize) -> Result<&'a Tensor>,
+    get: &dyn Fn(usize) -> Result<Option<&'a Tensor>>
+) -> Result<Tensor> {
+    let map = |x: &Tensor, f: &dyn Fn(f32) -> f32| Tensor { shape: x.shape.clone(), data: x.data.iter().map(|&v| f(v)).collect() };
+    Ok(match node.op.as_str() {
+        "Gemm" => {
+            let (a, b) = (need(0)?, need(1)?);
+            let (ta, tb) = (node.i("transA", 0) != 0, node.i("transB", 0) != 0);
+            let y = matmul(a, b, ta, tb)?;
+            let y = map(&y, &|v| v * node.f("alpha", 1.0));
+            match get(2)? {
+                Some(c) => broadcast(&y, &map(c, &|v| v * node.f("beta", 1.0)), |x, y| x + y)?,
+                None => y,
+            }
+        }
+        "MatMul" => matmul(need(0)?, need(1)?, false, false)?,
+        "Add" => broadcast(need(0)?, need(1)?, |x, y| x + y)?,
+        "Sub" => broadcast(need(0)?, need(1)?, |x, y| x - y)?,
+        "Mul" => broadcast(need(0)?, need(1)?, |x, y| x * y)?,
+        "Div" => broadcast(need(0)?, need(1)?, |x, y| x / y)?,
+        "Relu" => map(need(0)?, &|v| v.max(0.0)),
+        "LeakyRelu" => {
+            let alpha = node.f("alpha", 0.01);
+            map(need(0)?, &|v| if v < 0.0 { alpha * v } else { v })
+        }
+        "Sigmoid" => map(need(0)?, &|v| 1.0 / (1.0 + (-v).exp())),
+        "Tanh" => map(need(0)?, &f32::tanh),
+        "Clip" => {
+            let bound = |k: usize, attr: &str, default: f32| -> Result<f32> {
+                Ok(get(k)?.and_then(|t| t.data.first().copied()).unwrap_or(node.f(attr, default)))
+            };
+            let (lo, hi) = (bound(1, "min", f32::MIN)?, bound(2, "max", f32::MAX)?);
+            // not clamp: the bounds can be tensors, so min > max or a NaN bound must not panic
+            map(need(0)?, &|v| v.max(lo).min(hi))
+        }
+        "Softmax" => {
+            let x = need(0)?;
+            let width = *x.shape.last().context("scalar softmax")?;
+            let axis = node.i("axis", -1);
+            if axis != -1 && axis != (x.shape.len() as i64) - 1 {
+                bail!("softmax over axis {} (only the last is supported)", axis);
+            }
+            let mut data = x.data.clone();
+            for row in data.chunks_mut(width.max(1)) {
+                let m = row.iter().copied().fold(f32::MIN, f32::max);
+                row.iter_mut().for_each(|v| *v = (*v - m).exp());
+                let sum: f32 = row.iter().sum();
+                row.iter_mut().for_each(|v| *v /= sum);
+            }
+            Tensor { shape: x.shape.clone(), data }
+        }
+        "Flatten" => {
+            let x = need(0)?;
+            let rank = x.shape.len() as i64;
+            let axis = node.i("axis", 1);
+            let axis = if axis < 0 { axis + rank } else { axis };
+            if !(0..=rank).contains(&axis) {
+                bail!("flatten axis {} of a rank {} tensor", node.i("axis", 1), rank);
+            }
+            let axis = axis as usize;
+            let outer = x.shape[..axis].iter().product();
+            Tensor { shape: vec![outer, x.data.len() / outer.max(1)], data: x.data.clone() }
+        }
+        "Reshape" => {
+            let (x, spec) = (need(0)?, need(1)?);
+            let mut shape: Vec<i64> = spec.data.iter().map(|&v| v as i64).collect();
+            for (k, d) in shape.iter_mut().enumerate() {
+                if *d == 0 {
+                    *d = *x.shape.get(k).context("reshape: 0 past the input's rank")? as i64;
+                }
+            }
+            if shape.iter().any(|&d| d < -1) || shape.iter().filter(|&&d| d == -1).count() > 1 {
+                bail!("reshape to {:?}", shape);
+            }
+            let known: i64 = shape.iter().filter(|&&d| d >= 0).product();
+            let shape = shape
+                .iter()
+                .map(|&d| if d == -1 { (x.data.len() as i64) / known.max(1) } else { d })
+                .map(|d| d as usize)
+                .collect();
+            Tensor::new(shape, x.data.clone())?
+        }
+        "Identity" | "Dropout" => need(0)?.clone(),
+        "Constant" => node.attrs.get("value").and_then(|a| a.t.clone()).context("Constant without a tensor value")?,
+        other => bail!("operator {} is not supported", other),
+    })
+}
+
+/// `a`·`b` of matrices (or a vector and a matrix), either side transposed.
+fn matmul(a: &Tensor, b: &Tensor, ta: bool, tb: bool) -> Result<Tensor> {
+    let ((ar, ac), (br, bc)) = (a.matrix()?, b.matrix()?);
+    let (n, k) = if ta { (ac, ar) } else { (ar, ac) };
+    let (k2, m) = if tb { (bc, br) } else { (br, bc) };
+    if k != k2 {
+        bail!("matmul of {:?} and {:?}", a.shape, b.shape);
+    }
+    let at = |i: usize, j: usize| if ta { a.data[j * ac + i] } else { a.data[i * ac + j] };
+    let bt = |i: usize, j: usize| if tb { b.data[j * bc + i] } else { b.data[i * bc + j] };
+    let mut data = vec![0.0; n * m];
+    for i in 0..n {
+        for j in 0..m {
+            data[i * m + j] = (0..k).map(|x| at(i, x) * bt(x, j)).sum();
+        }
+    }
+    Tensor::new(vec![n, m], data)
+}
+
+/// Element-wise `f` with numpy broadcasting.
+fn broadcast(a: &Tensor, b: &Tensor, f: impl Fn(f32, f32) -> f32) -> Result<Tensor> {
+    let rank = a.shape.len().max(b.shape.len());
+    let pad = |s: &[usize]| [vec![1; rank - s.len()], s.to_vec()].concat();
+    let (sa, sb) = (pad(&a.shape), pad(&b.shape));
+    let mut shape = Vec::with_capacity(rank);
+    for (&x, &y) in sa.iter().zip(&sb) {
+        if x != y && x != 1 && y != 1 {
+            bail!("cannot broadcast {:?} with {:?}", a.shape, b.shape);
+        }
+        shape.push(x.max(y));
+    }
+    let strides = |s: &[usize]| -> Vec<usize> {
+        let mut st = vec![0; rank];
+        let mut acc = 1;
+        for d in (0..rank).rev() {
+            st[d] = if s[d] == 1 { 0 } else { acc };
+            acc *= s[d];
+        }
+        st
+    };
+    let (st_a, st_b) = (strides(&sa), strides(&sb));
+    let total: usize = shape.iter().product();
+    let mut data = Vec::with_capacity(total);
+    for flat in 0..total {
+        let (mut rem, mut ia, mut ib) = (flat, 0, 0);
+        for d in (0..rank).rev() {
+            let idx = rem % shape[d];
+            rem /= shape[d];
+            ia += idx * st_a[d];
+            ib += idx * st_b[d];
+        }
+        data.push(f(a.data[ia], b.data[ib]));
+    }
+    Tensor::new(shape, data)
+}
+
+// ---- protobuf ----
+
+enum Value<'a> {
+    Int(u64),
+    Fixed32(u32),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    fn bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn string(&self) -> String {
+        self.bytes().map(|b| String::from_utf8_lossy(b).into_owned()).unwrap_or_default()
+    }
+
+    fn int(&self) -> Option<i64> {
+        match self {
+            Value::Int(v) => Some(*v as i64),
+            _ => None,
+        }
+    }
+}
+
+fn varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let b = *buf.get(*pos).context("truncated varint")?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    bail!("varint too long")
+}
+
+/// The fields of one message, in order.
+fn fields(buf: &[u8]) -> Result<Vec<(u64, Value<'_>)>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let key = varint(buf, &mut pos)?;
+        let take = |pos: &mut usize, n: usize| -> Result<&[u8]> {
+            let b = buf.get(*pos..*pos + n).context("truncated field")?;
+            *pos += n;
+            Ok(b)
+        };
+        let value = match key & 7 {
+            0 => Value::Int(varint(buf, &mut pos)?),
+            1 => Value::Fixed64(u64::from_le_bytes(take(&mut pos, 8)?.try_into()?)),
+            2 => {
+                let n = varint(buf, &mut pos)? as usize;
+                Value::Bytes(take(&mut pos, n)?)
+            }
+            5 => Value::Fixed32(u32::from_le_bytes(take(&mut pos, 4)?.try_into()?)),
+            w => bail!("unsupported wire type {}", w),
+        };
+        out.push((key >> 3, value));
+    }
+    Ok(out)
+}
+
+/// A repeated int64 field, packed or not.
+fn push_ints(v: &Value, out: &mut Vec<i64>) -> Result<()> {
+    match v {
+        Value::Int(x) => out.push(*x as i64),
+        Value::Bytes(b) => {
+            let mut pos = 0;
+            while pos < b.len() {
+                out.push(varint(b, &mut pos)? as i64);
+            }
+        }
+        _ => bail!("expected integers"),
+    }
+    Ok(())
+}
+
+/// A repeated float field, packed or not.
+fn push_floats(v: &Value, out: &mut Vec<f32>) -> Result<()> {
+    match v {
+        Value::Fixed32(x) => out.push(f32::from_bits(*x)),
+        Value::Bytes(b) => out.extend(b.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))),
+        _ => bail!("expected floats"),
+    }
+    Ok(())
+}
+
+/// TensorProto: float, double and int32/int64 data, inline or raw.
+fn tensor(buf: &[u8]) -> Result<(String, Tensor)> {
+    let (mut dims, mut dtype, mut name, mut data, mut ints, mut raw) = (Vec::new(), 1, String::new(), Vec::new(), Vec::new(), None);
+    for (n, v) in fields(buf)? {
+        match n {
+            1 => push_ints(&v, &mut dims)?,
+            2 => dtype = v.int().unwrap_or(1),
+            4 => push_floats(&v, &mut data)?,
+            5 | 7 => push_ints(&v, &mut ints)?,
+            8 => name = v.string(),
+            9 => raw = v.bytes(),
+            10 =>
+                match v {
+                    Value::Fixed64(x) => data.push(f64::from_bits(x) as f32),
+                    Value::Bytes(b) => data.extend(b.chunks_exact(8).map(|c| f64::from_le_bytes(c.try_into().unwrap()) as f32)),
+                    _ => {}
+                }
+            _ => {}
+        }
+    }
+    if let Some(raw) = raw {
+        data = match dtype {
+            1 => raw.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect(),
+            6 => raw.chunks_exact(4).map(|c| i32::from_le_bytes([c[0], c[1], c[2], c[3]]) as f32).collect(),
+            7 => raw.chunks_exact(8).map(|c| i64::from_le_bytes(c.try_into().unwrap()) as f32).collect(),
+            11 => raw.chunks_exact(8).map(|c| f64::from_le_bytes(c.try_into().unwrap()) as f32).collect(),
+            t => bail!("tensor {}: data type {} is not supported", name, t),
+        };
+    } else if data.is_empty() {
+        data = ints.iter().map(|&v| v as f32).collect();
+    }
+    if let Some(d) = dims.iter().find(|&&d| d < 0) {
+        bail!("tensor {}: negative dimension {}", name, d);
+    }
+    let shape = dims.iter().map(|&d| d as usize).collect();
+    let t = Tensor::new(shape, data).with_context(|| format!("tensor {}", name))?;
+    Ok((name, t))
+}
+
+fn node(buf: &[u8]) -> Result<Node> {
+    let mut node = Node { op: String::new(), inputs: Vec::new(), outputs: Vec::new(), attrs: HashMap::new() };
+    for (n, v) in fields(buf)? {
+        match n {
+            1 => node.inputs.push(v.string()),
+            2 => node.outputs.push(v.string()),
+            4 => node.op = v.string(),
+            5 => {
+                let mut name = String::new();
+                let mut attr = Attr::default();
+                for (an, av) in fields(v.bytes().context("attribute")?)? {
+                    match (an, &av) {
+                        (1, _) => name = av.string(),
+                        (2, Value::Fixed32(x)) => attr.f = Some(f32::from_bits(*x)),
+                        (3, _) => attr.i = av.int(),
+                        (5, Value::Bytes(b)) => attr.t = Some(tensor(b)?.1),
+                        _ => {}
+                    }
+                }
+                node.attrs.insert(name, attr);
+            }
+            _ => {}
+        }
+    }
+    Ok(node)
+}
+
+/// The embedded messages in `field` of `buf`.
+fn sub(buf: &[u8], field: u64) -> Result<Vec<&[u8]>> {
+    Ok(sub_of(&fields(buf)?, field))
+}
+
+/// The embedded messages in `field` of fields already read.
+fn sub_of<'a>(fields: &[(u64, Value<'a>)], field: u64) -> Vec<&'a [u8]> {
+    fields.iter().filter(|(n, _)| *n == field).filter_map(|(_, v)| v.bytes()).collect()
+}
+
+/// OperatorSetIdProto: the domain ("" is ai.onnx) and its version.
+fn opset(buf: &[u8]) -> Result<(String, i64)> {
+    let (mut domain, mut version) = (String::new(), None);
+    for (n, v) in fields(buf)? {
+        match n {
+            1 => domain = v.string(),
+            2 => version = v.int(),
+            _ => {}
+        }
+    }
+    Ok((domain, version.context("opset import without a version")?))
+}
+
+/// ValueInfoProto: the name, and the last dimension of a tensor type. A dimension left
+/// unnamed is unknown (the batch usually is); a negative one, or a symbolic (named) last one,
+/// is refused, since the input's width has to be known.
+fn value_info(buf: &[u8]) -> Result<(String, Option<usize>)> {
+    let (mut name, mut width) = (String::new(), None);
+    for (n, v) in fields(buf)? {
+        match (n, v.bytes()) {
+            (1, _) => name = v.string(),
+            (2, Some(ty)) => {
+                // TypeProto.tensor_type.shape.dim[]: dim_value (1) or dim_param (2)
+                for tt in sub(ty, 1)? {
+                    for shape in sub(tt, 2)? {
+                        let dims = sub(shape, 1)?;
+                        width = None;
+                        for (k, dim) in dims.iter().enumerate() {
+                            let dim = fields(dim)?;
+                            let value = dim.iter().find(|(n, _)| *n == 1).and_then(|(_, v)| v.int());
+                            let param = dim.iter().find(|(n, _)| *n == 2).map(|(_, v)| v.string());
+                            match (value, param) {
+                                (Some(d), _) if d < 0 => bail!("{}: negative dimension {}", name, d),
+                                (Some(d), _) if k + 1 == dims.len() => width = Some(d as usize),
+                                (None, Some(p)) if k + 1 == dims.len() => bail!("{}: symbolic last dimension {}", name, p),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((name, width))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a few protobuf writers, enough to build a model by hand
+    fn key(field: u64, wire: u64, out: &mut Vec<u8>) {
+        put_varint((field << 3) | wire, out);
+    }
+    fn put_varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push(((v & 0x7f) as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+    fn bytes(field: u64, b: &[u8], out: &mut Vec<u8>) {
+        key(field, 2, out);
+        put_varint(b.len() as u64, out);
+        out.extend_from_slice(b);
+    }
+    fn float_tensor(name: &str, dims: &[u64], values: &[f32]) -> Vec<u8> {
+        let mut t = Vec::new();
+        for &d in dims {
+            key(1, 0, &mut t);
+            put_varint(d, &mut t);
+        }
+        key(2, 0, &mut t);
+        put_varint(1, &mut t);
+        bytes(8, name.as_bytes(), &mut t);
+        let raw: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        bytes(9, &raw, &mut t);
+        t
+    }
+    fn node(op: &str, inputs: &[&str], output: &str, trans_b: bool) -> Vec<u8> {
+        let mut n = Vec::new();
+        for i in inputs {
+            bytes(1, i.as_bytes(), &mut n);
+        }
+        bytes(2, output.as_bytes(), &mut n);
+        bytes(4, op.as_bytes(), &mut n);
+        if trans_b {
+            let mut a = Vec::new();
+            bytes(1, b"transB", &mut a);
+            key(3, 0, &mut a);
+            put_varint(1, &mut a);
+            bytes(5, &a, &mut n);
+        }
+        n
+    }
+    /// A ModelProto of `graph` at `ir_version`, importing the default-domain `opsets`.
+    fn model_proto(graph: &[u8], ir_version: u64, opsets: &[(&str, u64)]) -> Vec<u8> {
+        let mut m = Vec::new();
+        key(1, 0, &mut m);
+        put_varint(ir_version, &mut m);
+        bytes(7, graph, &mut m);
+        for (domain, version) in opsets {
+            let mut o = Vec::new();
+            bytes(1, domain.as_bytes(), &mut o);
+            key(2, 0, &mut o);
+            put_varint(*version, &mut o);
+            bytes(8, &o, &mut m);
+        }
+        m
+    }
+    fn value_info(name: &str, width: u64) -> Vec<u8> {
+        let mut dim = Vec::new();
+        key(1, 0, &mut dim);
+        put_varint(width, &mut dim);
+        let (mut shape, mut tt, mut ty, mut vi) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        bytes(1, &[], &mut shape); // batch: no fixed size
+        bytes(1, &dim, &mut shape);
+        bytes(2, &shape, &mut tt);
+        bytes(1, &tt, &mut ty);
+        bytes(1, name.as_bytes(), &mut vi);
+        bytes(2, &ty, &mut vi);
+        vi
+    }
+
+    #[test]
+    fn runs_a_hand_built_two_layer_classifier() {
+        // hidden = relu(x·W1ᵀ + b1) over the first two inputs; p = sigmoid(hidden·w2 + b2)
+        let width = crate::features::MODEL_INPUTS.len();
+        let mut w1 = vec![0.0; 2 * width];
+        w1[0] = 1.0; // hidden 0 = x0
+        w1[width + 1] = -1.0; // hidden 1 = -x1
+        let mut g = Vec::new();
+        bytes(1, &node("Gemm", &["x", "w1", "b1"], "h", true), &mut g);
+        bytes(1, &node("Relu", &["h"], "r", false), &mut g);
+        bytes(1, &node("MatMul", &["r", "w2"], "z", false), &mut g);
+        bytes(1, &node("Add", &["z", "b2"], "y", false), &mut g);
+        bytes(1, &node("Sigmoid", &["y"], "p", false), &mut g);
+        bytes(5, &float_tensor("w1", &[2, width as u64], &w1), &mut g);
+        bytes(5, &float_tensor("b1", &[2], &[0.0, 0.0]), &mut g);
+        bytes(5, &float_tensor("w2", &[2, 1], &[2.0, 3.0]), &mut g);
+        bytes(5, &float_tensor("b2", &[1], &[-1.0]), &mut g);
+        bytes(11, &value_info("x", width as u64), &mut g);
+        bytes(12, &value_info("p", 1), &mut g);
+        let m = model_proto(&g, 8, &[("", 13)]);
+
+        let model = Model::parse(&m).unwrap();
+        assert_eq!(model.width, Some(width));
+        let mut x = vec![0.0; width];
+        let p = |x: &[f32]| model.probability(x).unwrap();
+        assert!((p(&x) - 1.0 / (1.0 + 1f32.exp())).abs() < 1e-6); // sigmoid(-1)
+        x[0] = 1.0;
+        x[1] = -1.0;
+        assert!((p(&x) - 1.0 / (1.0 + (-4f32).exp())).abs() < 1e-6); // sigmoid(2 + 3 - 1)
+
+        let mut bad = Vec::new();
+        bytes(1, &node("LSTM", &["x"], "p", false), &mut bad);
+        let m = model_proto(&bad, 8, &[("", 13)]);
+        assert!(format!("{:#}", Model::parse(&m).unwrap_err()).contains("LSTM"));
+    }
+
+    #[test]
+    fn clip_with_crossed_or_nan_bounds_does_not_panic() {
+        let width = crate::features::MODEL_INPUTS.len();
+        let clipped = |lo: f32, hi: f32, x0: f32| {
+            let mut w = vec![0.0; width];
+            w[0] = 1.0;
+            let mut g = Vec::new();
+            bytes(1, &node("MatMul", &["x", "w"], "z", false), &mut g);
+            bytes(1, &node("Clip", &["z", "lo", "hi"], "p", false), &mut g);
+            bytes(5, &float_tensor("w", &[width as u64, 1], &w), &mut g);
+            bytes(5, &float_tensor("lo", &[], &[lo]), &mut g);
+            bytes(5, &float_tensor("hi", &[], &[hi]), &mut g);
+            bytes(11, &value_info("x", width as u64), &mut g);
+            bytes(12, &value_info("p", 1), &mut g);
+            let model = Model::parse(&model_proto(&g, 8, &[("", 13)])).unwrap();
+            let mut x = vec![0.0; width];
+            x[0] = x0;
+            model.probability(&x).unwrap()
+        };
+        assert_eq!(clipped(0.2, 0.8, 0.5), 0.5);
+        assert_eq!(clipped(0.2, 0.8, 0.9), 0.8);
+        // min > max: everything is max, as in numpy.clip
+        assert_eq!(clipped(0.8, 0.2, 0.5), 0.2);
+        // a NaN bound is no bound
+        assert_eq!(clipped(f32::NAN, 0.8, 0.1), 0.1);
+        assert_eq!(clipped(0.2, f32::NAN, 0.9), 0.9);
+    }
+
+    #[test]
+    fn refuses_what_it_cannot_read() {
+        let error = |m: &[u8]| format!("{:#}", Model::parse(m).unwrap_err());
+        let width = crate::features::MODEL_INPUTS.len() as u64;
+        let graph = |extra: &dyn Fn(&mut Vec<u8>)| {
+            let mut g = Vec::new();
+            bytes(1, &node("Identity", &["x"], "p", false), &mut g);
+            bytes(11, &value_info("x", width), &mut g);
+            bytes(12, &value_info("p", width), &mut g);
+            extra(&mut g);
+            g
+        };
+        let g = graph(&|_| {});
+        assert!(Model::parse(&model_proto(&g, 8, &[("", 13), ("ai.onnx", 13)])).is_ok());
+        assert!(error(&model_proto(&g, 2, &[("", 13)])).contains("IR version 2"));
+        assert!(error(&model_proto(&g, 8, &[])).contains("no opset"));
+        assert!(error(&model_proto(&g, 8, &[("", 99)])).contains("opset 99"));
+        assert!(error(&model_proto(&g, 8, &[("", 13), ("ai.onnx.ml", 3)])).contains("ai.onnx.ml"));
+
+        // a weight of negative size, and an input whose width is a name
+        let g = graph(&|g| {
+            let mut t = Vec::new();
+            key(1, 0, &mut t);
+            put_varint(-2i64 as u64, &mut t);
+            bytes(8, b"w", &mut t);
+            bytes(5, &t, g);
+        });
+        assert!(error(&model_proto(&g, 8, &[("", 13)])).contains("negative dimension -2"));
+        let mut g = Vec::new();
+        let (mut dim, mut shape, mut tt, mut ty, mut vi) = (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+        bytes(2, b"features", &mut dim);
+        bytes(1, &dim, &mut shape);
+        bytes(2, &shape, &mut tt);
+        bytes(1, &tt, &mut ty);
+        bytes(1, b"x", &mut vi);
+        bytes(2, &ty, &mut vi);
+        bytes(11, &vi, &mut g);
+        assert!(error(&model_proto(&g, 8, &[("", 13)])).contains("symbolic last dimension features"));
+
+        // the identity's output is the whole feature vector, not a probability
+        let m = Model::parse(&model_proto(&graph(&|_| {}), 8, &[("", 13)])).unwrap();
+        let err = m.probability(&vec![0.0; width as usize]).unwrap_err();
+        assert!(err.to_string().contains("expected one probability"));
+    }
+
+    #[test]
+    fn broadcasts_like_numpy() {
+        let a = Tensor::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+        let row = Tensor::new(vec![3], vec![10.0, 20.0, 30.0]).unwrap();
+        let col = Tensor::new(vec![2, 1], vec![100.0, 200.0]).unwrap();
+        assert_eq!(broadcast(&a, &row, |x, y| x + y).unwrap().data, [11.0, 22.0, 33.0, 14.0, 25.0, 36.0]);
+        assert_eq!(broadcast(&a, &col, |x, y| x + y).unwrap().data, [101.0, 102.0, 103.0, 204.0, 205.0, 206.0]);
+        assert!(broadcast(&a, &Tensor::new(vec![2], vec![0.0, 0.0]).unwrap(), |x, y| x + y).is_err());
+    }
+}
@@ -0,0 +1,528 @@
+//! src/output.rs
+//! Machine-readable outputs shared by the detection modes:
+//! `status.json` (overwritten every tick) and `Detection.jsonl` (one event per line),
+//! plus the size/day rotation applied to `Detection.log`, `Detection.csv` and `Measurements.csv`.
+
+use std::{
+    fs::{ self, File, OpenOptions },
+    io::{ self, BufRead, Write },
+    path::{ Path, PathBuf },
+    time::{ Duration, SystemTime },
+};
+
+use crate::sonar_presence::{ CorrQuality, PresenceState };
+use crate::recorder::TickMeta;
+use crate::{ csvio, Config };
+
+/// Path of a file that sits beside the configured log file (e.g. `Detection.csv`).
+pub fn sibling_path(log_path: &str, name: &str) -> PathBuf {
+    match Path::new(log_path).parent() {
+        Some(dir) => dir.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+/// Rotation/retention policy for the append-only outputs (`--log-rotate-mb`, `--log-keep-days`).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Rotation {
+    pub max_bytes: u64, // 0 = no size limit
+    pub keep_days: u32, // 0 = never roll over daily nor delete
+    pub keep_files: u32, // >0: numbered archives `<name>.1` (newest) … `<name>.N` instead of timestamped ones
+}
+
+impl Rotation {
+    pub fn from_config(cfg: &Config) -> Self {
+        Self {
+            max_bytes: (cfg.log_rotate_mb * 1024.0 * 1024.0) as u64,
+            keep_days: cfg.log_keep_days,
+            keep_files: 0,
+        }
+    }
+
+    /// Detection.log's policy: `--log-max-mb` switches it to `--log-keep` numbered archives,
+    /// otherwise it rotates like the CSVs.
+    pub fn for_log(cfg: &Config) -> Self {
+        if cfg.log_max_mb <= 0.0 {
+            return Self::from_config(cfg);
+        }
+        Self {
+            max_bytes: (cfg.log_max_mb * 1024.0 * 1024.0) as u64,
+            keep_days: cfg.log_keep_days,
+            keep_files: cfg.log_keep.max(1),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes > 0 || self.keep_days > 0
+    }
+}
+
+/// Move `path` aside as `<stem>.<YYYYmmdd-HHMMSS>.<ext>` when it exceeds the size limit
+/// or was last written on an earlier day, then prune old rotated files.
+/// Returns true when the file was rotated.
+pub fn rotate_if_due(path: &Path, policy: &Rotation) -> io::Result<bool> {
+    if !policy.is_enabled() {
+        return Ok(false);
+    }
+    let meta = match fs::metadata(path) {
+        Ok(m) => m,
+        Err(_) => {
+            return Ok(false);
+        }
+    };
+    if meta.len() == 0 {
+        return Ok(false);
+    }
+
+    let too_big = policy.max_bytes > 0 && meta.len() >= policy.max_bytes;
+    let stale_day =
+        policy.keep_days > 0 &&
+        meta
+            .modified()
+            .map(
+                |m|
+                    chrono::DateTime::<chrono::Local>::from(m).date_naive() <
+                    chrono::Local::now().date_naive()
+            )
+            .unwrap_or(false);
+    if !too_big && !stale_day {
+        return Ok(false);
+    }
+    rotate_now(path, policy)?;
+    Ok(true)
+}
+
+/// Move `path` aside the way `policy` keeps archives, whether or not it is due.
+fn rotate_now(path: &Path, policy: &Rotation) -> io::Result<()> {
+    if policy.keep_files > 0 {
+        shift_numbered(path, policy.keep_files)?;
+        if policy.keep_days > 0 {
+            prune_numbered(path, policy.keep_files, policy.keep_days);
+        }
+        return Ok(());
+    }
+
+    let (stem, ext) = split_name(path);
+    let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let mut target = path.with_file_name(format!("{}.{}{}", stem, stamp, ext));
+    let mut n = 1;
+    while target.exists() {
+        target = path.with_file_name(format!("{}.{}-{}{}", stem, stamp, n, ext));
+        n += 1;
+    }
+    fs::rename(path, &target)?;
+
+    if policy.keep_days > 0 {
+        prune_rotated(path, policy.keep_days)?;
+    }
+    Ok(())
+}
+
+/// `<path>.<n>`, e.g. `Detection.log.2`.
+pub fn numbered_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// `path` becomes `.1`, `.1` becomes `.2` and so on; whatever would become `.keep + 1` is deleted.
+fn shift_numbered(path: &Path, keep: u32) -> io::Result<()> {
+    let _ = fs::remove_file(numbered_path(path, keep));
+    for n in (1..keep).rev() {
+        let from = numbered_path(path, n);
+        if from.exists() {
+            fs::rename(&from, numbered_path(path, n + 1))?;
+        }
+    }
+    fs::rename(path, numbered_path(path, 1))
+}
+
+/// Delete numbered archives of `path` last modified more than `keep_days` ago.
+fn prune_numbered(path: &Path, keep: u32, keep_days: u32) {
+    let max_age = Duration::from_secs((keep_days as u64) * 86_400);
+    let now = SystemTime::now();
+    for n in 1..=keep {
+        let archive = numbered_path(path, n);
+        let old = fs
+            ::metadata(&archive)
+            .and_then(|m| m.modified())
+            .map(|m| now.duration_since(m).unwrap_or_default() > max_age)
+            .unwrap_or(false);
+        if old {
+            let _ = fs::remove_file(&archive);
+        }
+    }
+}
+
+/// (`Detection`, `.csv`) for `…/Detection.csv`.
+fn split_name(path: &Path) -> (String, String) {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    (stem, ext)
+}
+
+/// Rotated archives of `path` (`<stem>.<stamp>[-n]<ext>` beside it), sorted by name.
+pub fn rotated_siblings(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let (stem, ext) = split_name(path);
+    let prefix = format!("{}.", stem);
+    let mut found = Vec::new();
+    for entry in fs::read_dir(&dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(middle) = name.strip_prefix(&prefix).and_then(|r| r.strip_suffix(ext.as_str())) else {
+            continue;
+        };
+        // only our own `<stem>.<stamp>[-n]<ext>` archives
+        if middle.is_empty() || !middle.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            continue;
+        }
+        found.push(entry.path());
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Delete rotated siblings of `path` last modified more than `keep_days` ago.
+fn prune_rotated(path: &Path, keep_days: u32) -> io::Result<()> {
+    let max_age = Duration::from_secs((keep_days as u64) * 86_400);
+    let now = SystemTime::now();
+    for archive in rotated_siblings(path)? {
+        let old = fs
+            ::metadata(&archive)
+            .and_then(|m| m.modified())
+            .map(|m| now.duration_since(m).unwrap_or_default() > max_age)
+            .unwrap_or(false);
+        if old {
+            let _ = fs::remove_file(&archive);
+        }
+    }
+    Ok(())
+}
+
+/// Append-only CSV that rotates itself per [`Rotation`] and re-writes its header after each rotation.
+/// A file left with another header (by an older version) is moved aside on open, so one file
+/// never mixes row layouts.
+pub struct RotatingCsv {
+    path: PathBuf,
+    header: &'static str,
+    policy: Rotation,
+    file: Option<File>,
+}
+
+impl RotatingCsv {
+    pub fn open(path: &Path, header: &'static str, policy: Rotation) -> io::Result<Self> {
+        let mut csv = Self { path: path.to_path_buf(), header, policy, file: None };
+        let first_line = File::open(path).ok().and_then(|f| io::BufReader::new(f).lines().next()?.ok());
+        if first_line.is_some_and(|l| l.trim_end() != header) {
+            rotate_now(path, &policy)?;
+        }
+        rotate_if_due(path, &policy)?;
+        csv.reopen()?;
+        Ok(csv)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let mut f = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if f.metadata()?.len() == 0 {
+            writeln!(f, "{}", self.header)?;
+            f.flush()?;
+        }
+        self.file = Some(f);
+        Ok(())
+    }
+
+    /// Write one complete line, rotating first when the policy says so.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.policy.is_enabled() {
+            // close before renaming (Windows refuses to rename open files)
+            let file = self.file.take();
+            drop(file);
+            rotate_if_due(&self.path, &self.policy)?;
+        }
+        if self.file.is_none() {
+            self.reopen()?;
+        }
+        let f = self.file.as_mut().unwrap();
+        writeln!(f, "{}", line)?;
+        f.flush()
+    }
+}
+
+pub const DETECTION_CSV_HEADER: &str =
+    "timestamp,present,avg_distance_m,avg_strength,agree_pct,dist_iqr_m,bearing_deg,peak_sidelobe,snr_db,direct_r,state,probability,song_url,t_song_s,segment_index,segment_start_s,segment_end_s";
+
+/// Gated mode: the song a Detection.csv row was written in, and where in it.
+#[derive(Clone, Copy, Debug)]
+pub struct SongPos<'a> {
+    pub url: &'a str,
+    pub t_song: f32, // seconds into the song
+    pub segment: Option<(usize, f32, f32)>, // SongScan window being analysed: index, start_s, end_s
+}
+
+/// Open `Detection.csv` for appending, writing the header to a new file.
+pub fn open_detection_csv(path: &Path, policy: Rotation) -> io::Result<RotatingCsv> {
+    RotatingCsv::open(path, DETECTION_CSV_HEADER, policy)
+}
+
+/// One Detection.csv row, written on every smoothed state change.
+#[allow(clippy::too_many_arguments)]
+pub fn write_detection_row(
+    csv: &mut RotatingCsv,
+    state: PresenceState,
+    avg_d: f64,
+    avg_s: f64,
+    agree: f32,
+    iqr_d: f64,
+    bearing_deg: Option<f64>, // --bearing; empty otherwise
+    quality: Option<CorrQuality>, // of the tick that flipped the state; empty without a correlation (impulse mode)
+    probability: Option<f32>, // --calibration; empty otherwise
+    song: Option<SongPos> // gated mode; empty otherwise
+) -> io::Result<()> {
+    let ts = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let bearing = bearing_deg.map(|b| format!("{:.1}", b)).unwrap_or_default();
+    let quality = quality
+        .map(|q| format!("{:.2},{:.1},{:.3}", q.peak_sidelobe, q.snr_db, q.direct_r))
+        .unwrap_or_else(|| ",,".to_string());
+    let probability = probability.map(|p| format!("{:.3}", p)).unwrap_or_default();
+    let song = song
+        .map(|s| {
+            let segment = s.segment
+                .map(|(i, a, b)| format!("{},{:.3},{:.3}", i, a, b))
+                .unwrap_or_else(|| ",,".to_string());
+            format!("{},{:.3},{}", csvio::field(s.url), s.t_song, segment)
+        })
+        .unwrap_or_else(|| ",,,,".to_string());
+    csv.write_line(
+        &format!(
+            "{},{},{:.2},{:.2},{:.0},{:.2},{},{},{},{},{}",
+            ts,
+            state.present(),
+            avg_d,
+            avg_s,
+            agree * 100.0,
+            iqr_d,
+            bearing,
+            quality,
+            state.as_str(),
+            probability,
+            song
+        )
+    )
+}
+
+pub const MEASUREMENTS_CSV_HEADER: &str = "timestamp,t_s,analysed,distance_m,strength,vote,agree_pct,present,state";
+
+/// `--log-every-tick`: `Measurements.csv` beside `log_path`, rotated like Detection.csv; None when off.
+pub fn open_measurements_csv(cfg: &Config, log_path: &str) -> io::Result<Option<RotatingCsv>> {
+    if !cfg.log_every_tick {
+        return Ok(None);
+    }
+    RotatingCsv::open(&sibling_path(log_path, "Measurements.csv"), MEASUREMENTS_CSV_HEADER, Rotation::from_config(cfg)).map(Some)
+}
+
+/// One Measurements.csv row per tick; `t_s` is the run's (or replay's) clock.
+pub fn write_measurement_row(csv: &mut RotatingCsv, t_s: f64, meta: &TickMeta) -> io::Result<()> {
+    csv.write_line(&measurement_row(&chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string(), t_s, meta))
+}
+
+fn measurement_row(ts: &str, t_s: f64, meta: &TickMeta) -> String {
+    let cell = |v: Option<f32>, prec: usize| v.map(|v| format!("{:.*}", prec, v)).unwrap_or_default();
+    format!(
+        "{},{:.3},{},{},{},{},{},{},{}",
+        ts,
+        t_s,
+        meta.analysed,
+        cell(meta.estimate.map(|e| e.0), 3),
+        cell(meta.estimate.map(|e| e.1), 3),
+        meta.vote,
+        cell(meta.agree.map(|a| a * 100.0), 0),
+        meta.present,
+        meta.state.as_str()
+    )
+}
+
+/// Minimal JSON object builder (no serde in this crate).
+pub struct JsonObj {
+    buf: String,
+}
+
+impl Default for JsonObj {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JsonObj {
+    pub fn new() -> Self {
+        Self { buf: String::from("{") }
+    }
+
+    fn key(&mut self, k: &str) {
+        if self.buf.len() > 1 {
+            self.buf.push(',');
+        }
+        push_json_str(&mut self.buf, k);
+        self.buf.push(':');
+    }
+
+    pub fn str(mut self, k: &str, v: &str) -> Self {
+        self.key(k);
+        push_json_str(&mut self.buf, v);
+        self
+    }
+
+    pub fn num(mut self, k: &str, v: f64) -> Self {
+        self.key(k);
+        if v.is_finite() {
+            self.buf.push_str(&format!("{}", (v * 1000.0).round() / 1000.0));
+        } else {
+            // JSON has no Infinity/NaN
+            self.buf.push_str("null");
+        }
+        self
+    }
+
+    pub fn int(mut self, k: &str, v: i64) -> Self {
+        self.key(k);
+        self.buf.push_str(&v.to_string());
+        self
+    }
+
+    pub fn bool(mut self, k: &str, v: bool) -> Self {
+        self.key(k);
+        self.buf.push_str(if v { "true" } else { "false" });
+        self
+    }
+
+    pub fn null(mut self, k: &str) -> Self {
+        self.key(k);
+        self.buf.push_str("null");
+        self
+    }
+
+    /// Array of already-serialized JSON values (e.g. `JsonObj::finish` results).
+    pub fn arr(mut self, k: &str, items: &[String]) -> Self {
+        self.key(k);
+        self.buf.push('[');
+        self.buf.push_str(&items.join(","));
+        self.buf.push(']');
+        self
+    }
+
+    /// `v` must already be valid JSON (a nested object or array).
+    pub fn raw(mut self, k: &str, v: &str) -> Self {
+        self.key(k);
+        self.buf.push_str(v);
+        self
+    }
+
+    pub fn opt_num(self, k: &str, v: Option<f64>) -> Self {
+        match v {
+            Some(x) => self.num(k, x),
+            None => self.null(k),
+        }
+    }
+
+    pub fn opt_int(self, k: &str, v: Option<i64>) -> Self {
+        match v {
+            Some(x) => self.int(k, x),
+            None => self.null(k),
+        }
+    }
+
+    pub fn finish(mut self) -> String {
+        self.buf.push('}');
+        self.buf
+    }
+}
+
+pub fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Replace `status.json` atomically, so the web GUI never reads a half-written document.
+pub fn write_status(path: &Path, json: &str) -> io::Result<()> {
+    write_atomic(path, json)
+}
+
+/// Write to a temp file beside `path`, then rename over it.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Append one JSON object as a line to a JSONL event file.
+pub fn append_jsonl(path: &Path, json: &str) -> io::Result<()> {
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(f, "{}", json)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measurement_rows_leave_unmeasured_cells_empty() {
+        let voted = TickMeta {
+            analysed: true,
+            estimate: Some((0.8123, 0.41)),
+            vote: true,
+            agree: Some(0.75),
+            present: true,
+            state: PresenceState::Active,
+            ..TickMeta::default()
+        };
+        assert_eq!(measurement_row("2026-10-15 09:30:01.250", 12.25, &voted), "2026-10-15 09:30:01.250,12.250,true,0.812,0.410,true,75,true,active");
+        let idle = TickMeta::default();
+        assert_eq!(measurement_row("t", 0.0, &idle), "t,0.000,false,,,false,,false,absent");
+        assert_eq!(MEASUREMENTS_CSV_HEADER.split(',').count(), 9);
+    }
+
+    #[test]
+    fn a_csv_with_an_older_header_is_moved_aside() {
+        let dir = std::env::temp_dir().join(format!("output-header-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Detection.csv");
+        let old = "timestamp,present,avg_distance_m,avg_strength,agree_pct\n2026-10-14 10:00:00,true,0.80,0.40,75\n";
+        fs::write(&path, old).unwrap();
+
+        let mut csv = open_detection_csv(&path, Rotation::default()).unwrap();
+        csv.write_line("row").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\nrow\n", DETECTION_CSV_HEADER));
+        let archived = rotated_siblings(&path).unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(fs::read_to_string(&archived[0]).unwrap(), old);
+
+        // the same header again: appended to, not moved
+        drop(csv);
+        open_detection_csv(&path, Rotation::default()).unwrap().write_line("more").unwrap();
+        assert!(fs::read_to_string(&path).unwrap().ends_with("row\nmore\n"));
+        assert_eq!(rotated_siblings(&path).unwrap().len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,169 @@
+//! src/pingsched.rs
+//! `--ping-schedule <FILE>`: the pings enrich mode put into a track, read back from its JSON
+//! sidecar, so detection can correlate only the ping band and, in gated mode, only while a
+//! ping is playing.
+
+use anyhow::{ anyhow, Context, Result };
+use std::{ fs, path::Path };
+
+use crate::json::{ self, Json };
+use crate::logger::Logger;
+use crate::Config;
+
+/// Room left around the ping band for the correlation's band limit.
+const BAND_PAD_HZ: f32 = 500.0;
+/// A ping counts as inside an analysis frame when at least this share of it is.
+const MIN_OVERLAP: f32 = 0.5;
+
+#[derive(Clone, Debug)]
+pub struct PingSchedule {
+    pub path: String,
+    pub track: String, // enriched file's stem, lowercase: the song tag must contain it
+    pub freq_hz: f32,
+    pub bandwidth_hz: f32,
+    pub pings: Vec<(f32, f32)>, // (start_s, length_s), sorted by start
+}
+
+impl PingSchedule {
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+        let doc = json::parse(&text).with_context(|| format!("parsing {}", path))?;
+        let num = |k: &str| {
+            doc.get(k)
+                .and_then(Json::as_f64)
+                .map(|v| v as f32)
+                .ok_or_else(|| anyhow!("{}: missing '{}'", path, k))
+        };
+        let output = doc
+            .get("output")
+            .and_then(Json::as_str)
+            .ok_or_else(|| anyhow!("{}: missing 'output'", path))?;
+        let track = Path::new(output)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let mut pings = Vec::new();
+        for p in doc
+            .get("pings")
+            .and_then(Json::as_array)
+            .ok_or_else(|| anyhow!("{}: no 'pings' list (written by an older enrich?)", path))? {
+            let start = p.get("start_s").and_then(Json::as_f64);
+            let len = p.get("length_s").and_then(Json::as_f64);
+            match (start, len) {
+                (Some(s), Some(l)) => pings.push((s as f32, l as f32)),
+                _ => anyhow::bail!("{}: ping without start_s/length_s", path),
+            }
+        }
+        pings.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Ok(Self {
+            path: path.to_string(),
+            track,
+            freq_hz: num("ping_freq_hz")?,
+            bandwidth_hz: num("ping_bandwidth_hz")?,
+            pings,
+        })
+    }
+
+    /// Frequency range to correlate in: the ping band plus BAND_PAD_HZ on both sides.
+    pub fn band(&self) -> (f32, f32) {
+        let half = self.bandwidth_hz / 2.0 + BAND_PAD_HZ;
+        ((self.freq_hz - half).max(0.0), self.freq_hz + half)
+    }
+
+    /// Whether this schedule belongs to the song tagged `url` (tag contains the enriched file's name).
+    pub fn matches(&self, url: &str) -> bool {
+        !self.track.is_empty() && url.to_lowercase().contains(&self.track)
+    }
+
+    /// Whether a ping plays in the song-time span `from..to` (most of it inside).
+    pub fn covers(&self, from: f32, to: f32) -> bool {
+        // pings are sorted; the first that could still overlap starts after from - its length
+        let first = self.pings.partition_point(|&(s, l)| s + l <= from);
+        self.pings[first..]
+            .iter()
+            .take_while(|&&(s, _)| s < to)
+            .any(|&(s, l)| (to.min(s + l) - from.max(s)) >= MIN_OVERLAP * l)
+    }
+}
+
+/// Load every `--ping-schedule`; a bad file is an error rather than silently unprobed playback.
+pub fn load_all(paths: &[String], logger: &Logger) -> Result<Vec<PingSchedule>> {
+    let mut out = Vec::new();
+    for p in paths {
+        let s = PingSchedule::load(p)?;
+        let (lo, hi) = s.band();
+        logger.info(
+            &format!(
+                "Ping schedule {}: {} ping(s) for '{}', correlating {:.0}-{:.0} Hz",
+                s.path,
+                s.pings.len(),
+                s.track,
+                lo,
+                hi
+            )
+        )?;
+        out.push(s);
+    }
+    Ok(out)
+}
+
+/// Band presence/replay mode correlate in: `--corr-band` if given, else the ping schedules' band.
+pub fn correlation_band(cfg: &Config, logger: &Logger) -> Result<Option<(f32, f32)>> {
+    let schedules = load_all(&cfg.ping_schedules, logger)?;
+    if let Some((lo, hi)) = cfg.corr_band {
+        logger.info(&format!("Correlating {:.0}-{:.0} Hz only (--corr-band)", lo, hi))?;
+        return Ok(Some((lo, hi)));
+    }
+    Ok(probe_band(&schedules, logger))
+}
+
+/// Without a song clock presence/replay mode take the first schedule's band for every track
+/// and analyse whenever the loopback carries energy there.
+pub fn probe_band(schedules: &[PingSchedule], logger: &Logger) -> Option<(f32, f32)> {
+    let band = schedules.first()?.band();
+    if schedules.iter().any(|s| s.band() != band) {
+        let _ = logger.warn(
+            &format!("--ping-schedule files use different ping bands; presence mode uses {:.0}-{:.0} Hz", band.0, band.1)
+        );
+    }
+    Some(band)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::JsonObj;
+
+    #[test]
+    fn sidecar_round_trip_and_coverage() {
+        let pings: Vec<String> = [(1.0, 0.1), (2.5, 0.1), (3.0, 0.2)]
+            .iter()
+            .map(|&(s, l)| JsonObj::new().num("start_s", s).num("length_s", l).num("level_db", -40.0).finish())
+            .collect();
+        let doc = JsonObj::new()
+            .str("output", "music/My Track_3pings.flac")
+            .num("ping_freq_hz", 18500.0)
+            .num("ping_bandwidth_hz", 2000.0)
+            .arr("pings", &pings)
+            .finish();
+        let path = std::env::temp_dir().join(format!("pingsched-{}.json", std::process::id()));
+        fs::write(&path, doc).unwrap();
+        let s = PingSchedule::load(path.to_str().unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(s.pings.len(), 3);
+        assert_eq!(s.band(), (17000.0, 20000.0));
+        assert_eq!(s.track, "my track_3pings");
+        assert!(s.matches("file://D:\\Music\\My Track_3pings.flac"));
+        assert!(!s.matches("file://D:\\Music\\My Track.flac"));
+
+        assert!(s.covers(0.9, 1.2));
+        assert!(s.covers(1.04, 1.3)); // 0.06 of the 0.1 s ping
+        assert!(!s.covers(1.06, 1.3)); // only 0.04
+        assert!(!s.covers(1.2, 2.4));
+        assert!(s.covers(2.9, 3.15)); // 0.15 of the 0.2 s ping
+        assert!(!s.covers(3.5, 9.0));
+    }
+}
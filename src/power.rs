@@ -0,0 +1,261 @@
+//! src/power.rs
+//! Sleep/resume and fast user switching for the live modes. Audio clients do not survive
+//! either, so when the machine wakes or the session returns to the console the streams are
+//! opened again and the detector starts over from an empty window. `--power-save` spends
+//! less of the battery on an empty room.
+
+use crossbeam_channel::Receiver;
+use std::{
+    io,
+    sync::Arc,
+    time::{ Duration, Instant, SystemTime },
+};
+
+use crate::logger::Logger;
+use crate::Config;
+
+/// Wall-clock time that passes between two polls without the monotonic clock, beyond which
+/// the machine is taken to have slept (the monotonic clock stops in suspend on Linux/macOS).
+const SLEEP_GAP: Duration = Duration::from_secs(10);
+/// The clock and the notification can both report one resume; the second is ignored.
+const HOLDOFF: Duration = Duration::from_secs(5);
+
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))] // only Windows sends these
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerEvent {
+    Suspend,
+    Resume,
+}
+
+/// Watches for the moments the capture streams have to be rebuilt.
+pub struct PowerWatch {
+    events: Option<Receiver<PowerEvent>>, // Windows suspend/resume notifications
+    at_console: Option<bool>, // this session is the one at the console (Windows)
+    wall: SystemTime, // both clocks at the last poll
+    mono: Instant,
+    rebuilt: Option<Instant>, // when `poll` last asked for new streams
+    logger: Arc<Logger>,
+}
+
+impl PowerWatch {
+    pub fn start(logger: Arc<Logger>) -> Self {
+        let events = match platform::subscribe() {
+            Ok(rx) => Some(rx),
+            Err(e) => {
+                let _ = logger.debug(&format!("power notifications unavailable ({}); watching the clock for sleep", e));
+                None
+            }
+        };
+        Self { events, at_console: platform::at_console(), wall: SystemTime::now(), mono: Instant::now(), rebuilt: None, logger }
+    }
+
+    /// Call once per tick. Some(reason) when the streams should be opened again.
+    pub fn poll(&mut self) -> Option<String> {
+        let (wall, mono) = (SystemTime::now(), Instant::now());
+        let slept = sleep_gap(self.wall, self.mono, wall, mono);
+        (self.wall, self.mono) = (wall, mono);
+
+        let mut reason = slept.map(|gap| format!("resumed after about {:.0}s asleep", gap.as_secs_f64()));
+        for ev in self.events.iter().flat_map(|rx| rx.try_iter()) {
+            match ev {
+                PowerEvent::Suspend => {
+                    let _ = self.logger.info("system suspending");
+                }
+                PowerEvent::Resume => {
+                    reason = Some("system resumed from sleep".to_string());
+                }
+            }
+        }
+        let at_console = platform::at_console();
+        if at_console != self.at_console {
+            match at_console {
+                Some(false) => {
+                    let _ = self.logger.info("session switched away from the console");
+                }
+                Some(true) if self.at_console == Some(false) => {
+                    reason = Some("session is back at the console".to_string());
+                }
+                _ => {}
+            }
+            self.at_console = at_console;
+        }
+        if self.rebuilt.is_some_and(|at| mono.saturating_duration_since(at) < HOLDOFF) {
+            return None;
+        }
+        if reason.is_some() {
+            self.rebuilt = Some(mono);
+        }
+        reason
+    }
+}
+
+/// `--power-save`: once no tick has voted for `--idle-after-s`, the tick doubles every
+/// `--power-ramp-s` up to `--idle-tick-ms`; the first vote brings the configured tick back.
+pub struct PowerSave {
+    configured: Duration, // --tick-ms
+    idle_tick: Duration,
+    idle_after: Duration,
+    ramp: Duration,
+    last_vote: Instant,
+    stepped: Instant, // when the tick last changed
+    current: Duration,
+}
+
+impl PowerSave {
+    /// None without --power-save.
+    pub fn from_config(cfg: &Config, now: Instant) -> Option<Self> {
+        let tick = Duration::from_millis(cfg.tick_ms);
+        cfg.power_save.then(|| Self {
+            configured: tick,
+            idle_tick: Duration::from_millis(cfg.idle_tick_ms).max(tick),
+            idle_after: Duration::try_from_secs_f32(cfg.idle_after_s).unwrap_or(Duration::MAX),
+            ramp: Duration::try_from_secs_f32(cfg.power_ramp_s).unwrap_or(Duration::MAX),
+            last_vote: now,
+            stepped: now,
+            current: tick,
+        })
+    }
+
+    pub fn tick(&self) -> Duration {
+        self.current
+    }
+
+    /// Slower than --tick-ms: the frame may shrink too.
+    pub fn idle(&self) -> bool {
+        self.current > self.configured
+    }
+
+    /// After each tick: `voted` if it counted as a presence vote, `present` the smoothed state.
+    /// Some(new tick) when the tick changes.
+    pub fn update(&mut self, voted: bool, present: bool, now: Instant) -> Option<Duration> {
+        let before = self.current;
+        if voted || present {
+            self.last_vote = now;
+            self.current = self.configured;
+        } else {
+            let empty = now.saturating_duration_since(self.last_vote) >= self.idle_after;
+            // the first step comes as soon as the room has been empty long enough, later ones every ramp
+            let step_due = !self.idle() || now.saturating_duration_since(self.stepped) >= self.ramp;
+            if empty && step_due {
+                self.current = (self.current * 2).min(self.idle_tick);
+            }
+        }
+        if self.current == before {
+            return None;
+        }
+        self.stepped = now;
+        Some(self.current)
+    }
+}
+
+/// How long the machine slept between two polls, judged by wall time the monotonic clock
+/// did not see. A clock set forward by hand looks the same; rebuilding the streams then is
+/// harmless.
+fn sleep_gap(wall0: SystemTime, mono0: Instant, wall: SystemTime, mono: Instant) -> Option<Duration> {
+    let wall_gap = wall.duration_since(wall0).ok()?;
+    let gap = wall_gap.saturating_sub(mono.saturating_duration_since(mono0));
+    (gap > SLEEP_GAP).then_some(gap)
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::*;
+    use crossbeam_channel::{ bounded, Sender };
+    use std::ffi::c_void;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::System::Power::{ PowerRegisterSuspendResumeNotification, DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS };
+    use windows::Win32::System::RemoteDesktop::{ ProcessIdToSessionId, WTSGetActiveConsoleSessionId };
+    use windows::Win32::UI::WindowsAndMessaging::{ DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC, PBT_APMSUSPEND };
+
+    unsafe extern "system" fn on_power(context: *const c_void, kind: u32, _setting: *const c_void) -> u32 {
+        let tx = &*(context as *const Sender<PowerEvent>);
+        let ev = match kind {
+            PBT_APMSUSPEND => Some(PowerEvent::Suspend),
+            PBT_APMRESUMEAUTOMATIC => Some(PowerEvent::Resume),
+            _ => None,
+        };
+        if let Some(ev) = ev {
+            let _ = tx.try_send(ev);
+        }
+        0
+    }
+
+    /// The callback runs on a system thread; the registration lasts as long as the process.
+    pub fn subscribe() -> io::Result<Receiver<PowerEvent>> {
+        let (tx, rx) = bounded(16);
+        let params = Box::leak(
+            Box::new(DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+                Callback: Some(on_power),
+                Context: Box::into_raw(Box::new(tx)) as *mut c_void,
+            })
+        );
+        let mut registration = std::ptr::null_mut();
+        unsafe {
+            PowerRegisterSuspendResumeNotification(
+                DEVICE_NOTIFY_CALLBACK,
+                HANDLE(params as *mut DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS as *mut c_void),
+                &mut registration
+            )
+                .to_hresult()
+                .ok()
+                .map_err(io::Error::other)?;
+        }
+        Ok(rx)
+    }
+
+    /// False while another user has the console (fast user switching) or we run in a
+    /// disconnected session.
+    pub fn at_console() -> Option<bool> {
+        let mut mine = 0u32;
+        unsafe { ProcessIdToSessionId(std::process::id(), &mut mine) }.ok()?;
+        Some(unsafe { WTSGetActiveConsoleSessionId() } == mine)
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+mod platform {
+    use super::*;
+
+    pub fn subscribe() -> io::Result<Receiver<PowerEvent>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "power notifications are only used on Windows"))
+    }
+
+    pub fn at_console() -> Option<bool> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wall_time_the_monotonic_clock_missed_is_sleep() {
+        let (wall, mono) = (SystemTime::now(), Instant::now());
+        let later = |wall_s: u64, mono_ms: u64| (wall + Duration::from_secs(wall_s), mono + Duration::from_millis(mono_ms));
+        let (w, m) = later(1, 1000);
+        assert_eq!(sleep_gap(wall, mono, w, m), None); // an ordinary tick
+        let (w, m) = later(30, 2000);
+        assert_eq!(sleep_gap(wall, mono, w, m), Some(Duration::from_secs(28))); // 28 s suspended
+        let (w, m) = later(30, 30_000);
+        assert_eq!(sleep_gap(wall, mono, w, m), None); // a stalled loop, awake throughout
+        assert_eq!(sleep_gap(wall + Duration::from_secs(60), mono, wall, mono), None); // clock set back
+    }
+
+    #[test]
+    fn an_empty_room_stretches_the_tick_and_a_vote_restores_it() {
+        let cfg = Config { power_save: true, tick_ms: 250, idle_after_s: 30.0, idle_tick_ms: 1000, power_ramp_s: 10.0, ..Config::default() };
+        let t0 = Instant::now();
+        let at = |s: u64| t0 + Duration::from_secs(s);
+        let mut ps = PowerSave::from_config(&cfg, t0).unwrap();
+        assert_eq!(ps.update(false, false, at(29)), None);
+        assert_eq!(ps.update(false, false, at(30)), Some(Duration::from_millis(500)));
+        assert_eq!(ps.update(false, false, at(35)), None); // the ramp waits
+        assert_eq!(ps.update(false, false, at(40)), Some(Duration::from_millis(1000)));
+        assert_eq!(ps.update(false, false, at(60)), None); // --idle-tick-ms is the floor
+        assert!(ps.idle());
+        assert_eq!(ps.update(true, false, at(61)), Some(Duration::from_millis(250)));
+        assert_eq!(ps.update(false, false, at(62)), None); // idle again only after another --idle-after-s
+        assert!(PowerSave::from_config(&Config::default(), t0).is_none());
+    }
+}
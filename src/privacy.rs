@@ -0,0 +1,85 @@
+//! src/privacy.rs
+//! `--privacy-strict`: for an always-on mic where nothing heard may be kept. The outputs that
+//! can hold audio or what was measured on it tick by tick (session recordings, the debug dump,
+//! the feature table) are refused at startup, the capture rings are zeroed when the run ends,
+//! and the log says so where an audit will find it.
+
+use std::sync::Mutex;
+
+use crate::logger::{ Field, Logger };
+use crate::supervise::LockExt;
+use crate::{ Config, SharedBuf };
+
+/// Every capture ring opened in this process, for zeroing at exit.
+static CAPTURED: Mutex<Vec<SharedBuf>> = Mutex::new(Vec::new());
+
+/// A capture ring to zero at exit.
+pub fn register(buf: &SharedBuf) {
+    CAPTURED.locked().push(buf.clone());
+}
+
+/// The settings `--privacy-strict` does not allow, as the error for the first one given.
+pub fn check(cfg: &Config) -> Result<(), String> {
+    if !cfg.privacy_strict {
+        return Ok(());
+    }
+    let refused = [
+        ("--record-session", &cfg.record_session),
+        ("--debug-dump", &cfg.debug_dump),
+        ("--features", &cfg.features),
+    ];
+    match refused.iter().find(|(_, v)| !v.is_empty()) {
+        Some((flag, _)) => Err(format!("{} writes what the mic heard; it is not allowed with --privacy-strict", flag)),
+        None => Ok(()),
+    }
+}
+
+/// The audit line at startup.
+pub fn announce(cfg: &Config, logger: &Logger) {
+    if cfg.privacy_strict {
+        let _ = logger.event(
+            "privacy: strict (no session recording, debug dump or feature table; capture buffers zeroed at exit)",
+            &[("privacy", Field::Str("strict"))]
+        );
+    }
+}
+
+/// Zero every capture ring; the audit line at exit.
+pub fn wipe(cfg: &Config, logger: &Logger) {
+    if !cfg.privacy_strict {
+        return;
+    }
+    let samples: usize = CAPTURED.locked()
+        .iter()
+        .map(|b| {
+            b.wipe();
+            b.capacity()
+        })
+        .sum();
+    let _ = logger.event(
+        &format!("privacy: strict: capture buffers zeroed ({} samples)", samples),
+        &[("privacy", Field::Str("wiped"))]
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_refuses_recordings_and_zeroes_the_rings() {
+        let strict = Config { privacy_strict: true, ..Config::default() };
+        assert!(check(&strict).is_ok());
+        assert!(check(&Config { debug_dump: "d.csv".to_string(), ..Config::default() }).is_ok());
+        let err = check(&Config { record_session: "sessions".to_string(), ..strict.clone() }).unwrap_err();
+        assert!(err.contains("--record-session"), "{}", err);
+        assert!(check(&Config { features: "f.csv".to_string(), ..strict.clone() }).is_err());
+
+        let ring = SharedBuf::new(100.0, 1);
+        ring.push(&[0.5; 60]);
+        register(&ring);
+        let logger = Logger::new(&std::env::temp_dir().join("sonar-privacy.log").to_string_lossy(), false).unwrap();
+        wipe(&strict, &logger);
+        assert_eq!(ring.read(0, 60), Some(vec![0.0; 60]));
+    }
+}
@@ -0,0 +1,175 @@
+//! src/profile.rs
+//! `--profile <TOML>`: command-line settings kept in a file. Each `key = value` stands for
+//! `--key value` (underscores become dashes), read where `--profile` appears, so flags after
+//! it override the file. `--mode tune` writes its best settings this way.
+//! Only the flat part of TOML is understood: numbers, quoted strings, `true` (a switch given;
+//! `false` leaves it out), arrays of strings (the flag repeated) and `#` comments.
+//! A profile may name another with `profile = "other.toml"`; each file is read at most once,
+//! so profiles that include themselves or each other are refused instead of looping.
+
+use std::{ fmt::Write, path::{ Path, PathBuf } };
+
+/// The flags the profile at `path` stands for. `loaded` holds the profiles already read on
+/// this command line; reading one of them again is an include loop.
+pub fn load(path: &str, loaded: &mut Vec<PathBuf>) -> Result<Vec<String>, String> {
+    let key = Path::new(path).canonicalize().map_err(|e| format!("Cannot read profile {}: {}", path, e))?;
+    if loaded.contains(&key) {
+        return Err(format!("Profile {} is included more than once (profiles include each other)", path));
+    }
+    let text = std::fs::read_to_string(&key).map_err(|e| format!("Cannot read profile {}: {}", path, e))?;
+    let flags = to_args(&text).map_err(|e| format!("Invalid profile {}: {}", path, e))?;
+    loaded.push(key);
+    Ok(flags)
+}
+
+/// The flags a profile stands for.
+pub fn to_args(text: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() || line.starts_with('[') {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| format!("line {}: expected key = value", n + 1))?;
+        let flag = format!("--{}", key.trim().replace('_', "-"));
+        let value = value.trim();
+        match value {
+            "true" => args.push(flag),
+            "false" => {}
+            v if v.starts_with('[') && v.ends_with(']') => {
+                for item in split_items(&v[1..v.len() - 1]).into_iter().map(str::trim).filter(|s| !s.is_empty()) {
+                    args.push(flag.clone());
+                    args.push(unquote(item).ok_or_else(|| format!("line {}: bad array item {}", n + 1, item))?);
+                }
+            }
+            v => {
+                args.push(flag);
+                args.push(unquote(v).ok_or_else(|| format!("line {}: bad value {}", n + 1, v))?);
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// A profile of `key = value` lines under a comment header (`header` lines get `# `).
+pub fn render(header: &[String], values: &[(&str, String)]) -> String {
+    let mut out = String::new();
+    for line in header {
+        let _ = writeln!(out, "# {}", line);
+    }
+    for (key, value) in values {
+        let _ = writeln!(out, "{} = {}", key, value);
+    }
+    out
+}
+
+/// `"a # b"  # note` keeps the quoted `#`; `\"` inside quotes does not end them.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// `"a, b", "c"` splits into its two items; commas inside quotes stay.
+fn split_items(list: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut quoted, mut escaped, mut start) = (false, false, 0);
+    for (i, c) in list.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&list[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&list[start..]);
+    items
+}
+
+fn unquote(v: &str) -> Option<String> {
+    match v.strip_prefix('"') {
+        Some(rest) => rest.strip_suffix('"').map(|s| s.replace("\\\"", "\"").replace("\\\\", "\\")),
+        // bare numbers (and words, for hand-written files)
+        None => (!v.contains(char::is_whitespace)).then(|| v.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_lines_become_flags() {
+        let text = "# tuned\nstrength_thr = 0.25\nwindow_sec = 3 # seconds\npolicy = \"majority\"\npower_save = true\nbearing = false\n\
+                    active_hours = [\"mon-fri 08:00-18:00\", \"sat 10:00-12:00\"]\n";
+        assert_eq!(
+            to_args(text).unwrap(),
+            [
+                "--strength-thr", "0.25", "--window-sec", "3", "--policy", "majority", "--power-save",
+                "--active-hours", "mon-fri 08:00-18:00", "--active-hours", "sat 10:00-12:00",
+            ]
+        );
+        assert!(to_args("window_sec 3").is_err());
+        let rendered = render(&["best".to_string()], &[("enter_frac", "0.6".to_string())]);
+        assert_eq!(rendered, "# best\nenter_frac = 0.6\n");
+        assert_eq!(to_args(&rendered).unwrap(), ["--enter-frac", "0.6"]);
+    }
+
+    #[test]
+    fn escaped_quotes_and_quoted_commas_stay_in_their_value() {
+        let text = "node_name = \"say \\\"hi\\\" # not a comment\" # a comment\n\
+                    active_hours = [\"mon, tue 08:00-18:00\", \"sat \\\"x, y\\\"\"]\n";
+        assert_eq!(
+            to_args(text).unwrap(),
+            [
+                "--node-name", "say \"hi\" # not a comment",
+                "--active-hours", "mon, tue 08:00-18:00", "--active-hours", "sat \"x, y\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn profiles_that_include_each_other_are_refused() {
+        let dir = std::env::temp_dir().join(format!("sonar_profile_loop_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.toml");
+        let b = dir.join("b.toml");
+        std::fs::write(&a, format!("window_sec = 3\nprofile = \"{}\"\n", b.display())).unwrap();
+        std::fs::write(&b, format!("profile = \"{}\"\n", a.display())).unwrap();
+        std::fs::write(dir.join("self.toml"), format!("profile = \"{}\"\n", dir.join("self.toml").display())).unwrap();
+
+        // expand the way the command line does: each --profile in turn
+        let expand = |start: &Path| -> Result<Vec<String>, String> {
+            let mut loaded = Vec::new();
+            let mut args = vec!["--profile".to_string(), start.display().to_string()];
+            let mut i = 0;
+            while i < args.len() {
+                if args[i] == "--profile" {
+                    let flags = load(&args[i + 1], &mut loaded)?;
+                    args.splice(i..i + 2, flags);
+                } else {
+                    i += 1;
+                }
+            }
+            Ok(args)
+        };
+        assert!(expand(&a).unwrap_err().contains("more than once"));
+        assert!(expand(&dir.join("self.toml")).unwrap_err().contains("more than once"));
+        std::fs::write(&b, "policy = \"majority\"\n").unwrap();
+        assert_eq!(expand(&a).unwrap(), ["--window-sec", "3", "--policy", "majority"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
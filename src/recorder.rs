@@ -0,0 +1,368 @@
+//! src/recorder.rs
+//! `--record-session <DIR>`: dump the loopback reference and mic streams as WAV files
+//! plus a per-tick metadata CSV, so field reports can be re-run with `--mode replay`;
+//! `--debug-dump <PATH>`: the detector's per-tick features as one table for threshold tuning.
+
+use std::{
+    fs::{ self, File },
+    io::{ self, BufWriter, Seek, SeekFrom, Write },
+    ops::Range,
+    path::{ Path, PathBuf },
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::features::Features;
+use crate::sonar_presence::PresenceState;
+use crate::logger::Logger;
+use crate::{ Config, SharedBuf };
+
+/// Mono 32-bit float WAV, sizes patched in on `finish` (and on drop).
+pub struct WavWriter {
+    out: BufWriter<File>,
+    frames: u32,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sr: u32) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?; // patched
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&3u16.to_le_bytes())?; // IEEE float
+        out.write_all(&1u16.to_le_bytes())?; // mono
+        out.write_all(&sr.to_le_bytes())?;
+        out.write_all(&(sr * 4).to_le_bytes())?; // byte rate
+        out.write_all(&4u16.to_le_bytes())?; // block align
+        out.write_all(&32u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?; // patched
+        Ok(Self { out, frames: 0, finished: false })
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for s in samples {
+            self.out.write_all(&s.to_le_bytes())?;
+        }
+        self.frames = self.frames.saturating_add(samples.len() as u32);
+        self.finished = false;
+        Ok(())
+    }
+
+    /// Patch the RIFF/data sizes; the file stays playable even if the process dies later.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let data_bytes = self.frames.saturating_mul(4);
+        self.out.flush()?;
+        let f = self.out.get_mut();
+        f.seek(SeekFrom::Start(4))?;
+        f.write_all(&data_bytes.saturating_add(36).to_le_bytes())?;
+        f.seek(SeekFrom::Start(40))?;
+        f.write_all(&data_bytes.to_le_bytes())?;
+        f.seek(SeekFrom::End(0))?;
+        f.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.finish();
+        }
+    }
+}
+
+/// What the detector made of one tick, logged next to the audio.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TickMeta {
+    pub analysed: bool, // false outside gated windows / before the buffers filled
+    pub estimate: Option<(f32, f32)>, // (distance_m, strength)
+    pub bearing_deg: Option<f32>, // --bearing
+    pub rms: Option<(f32, f32)>, // (ref, mic) of the analysed frames
+    pub peak_sidelobe: Option<f32>,
+    pub snr_db: Option<f32>,
+    pub direct_r: Option<f32>, // correlation at the direct path
+    pub direct_lag: Option<i64>, // samples, as measured on the (drift-shifted) frames
+    pub ref_shift: i64, // samples the reference was read earlier by to compensate clock drift
+    pub drift_ppm: Option<f32>,
+    pub vote: bool,
+    pub agree: Option<f32>,
+    pub present: bool,
+    pub state: PresenceState, // present split by motion
+    pub features: Option<Features>, // --features
+}
+
+/// Follows one ring buffer and appends whatever arrived since the previous tick.
+struct Tap {
+    wav: WavWriter,
+    origin: u64, // ring position of the file's first sample
+    seen: u64,
+    dropped: u64,
+}
+
+/// What a tap read on one tick: samples from file position `from`, where `lost` (file
+/// positions) was overrun in the ring and is already silence.
+struct Pulled {
+    from: u64,
+    lost: Range<u64>,
+    samples: Vec<f32>,
+}
+
+impl Tap {
+    fn new(wav: WavWriter, shared: &SharedBuf) -> Self {
+        let seen = shared.written();
+        Self { wav, origin: seen, seen, dropped: 0 }
+    }
+
+    fn pull(&mut self, shared: &SharedBuf) -> Pulled {
+        // the ring only holds RING_SECONDS; anything older is gone
+        let (start, new) = shared.read_since(self.seen);
+        let lost = start.saturating_sub(self.seen);
+        let from = self.seen - self.origin;
+        let mut samples = vec![0.0; lost as usize];
+        samples.extend(new);
+        self.dropped += lost;
+        self.seen = self.origin + from + (samples.len() as u64);
+        Pulled { from, lost: from..from + lost, samples }
+    }
+
+    /// Append `pulled` with every span in `gaps` silenced, so a span one tap lost is missing
+    /// from both files alike and the pair stays in step.
+    fn write(&mut self, mut pulled: Pulled, gaps: &[Range<u64>]) -> io::Result<()> {
+        for gap in gaps {
+            let end = pulled.from + (pulled.samples.len() as u64);
+            for pos in gap.start.max(pulled.from)..gap.end.min(end) {
+                pulled.samples[(pos - pulled.from) as usize] = 0.0;
+            }
+        }
+        self.wav.write(&pulled.samples)
+    }
+}
+
+pub struct SessionRecorder {
+    dir: PathBuf,
+    ref_tap: Tap,
+    mic_tap: Tap,
+    ticks: BufWriter<File>,
+    sr: u32,
+    tick: u64,
+    started: Instant,
+    logger: Arc<Logger>,
+}
+
+impl SessionRecorder {
+    /// None unless `--record-session` is set. Recording starts at the current ring positions.
+    pub fn start(
+        cfg: &Config,
+        shared_ref: &SharedBuf,
+        shared_mic: &SharedBuf,
+        logger: Arc<Logger>
+    ) -> anyhow::Result<Option<Self>> {
+        if cfg.record_session.is_empty() {
+            return Ok(None);
+        }
+        let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+        let dir = Path::new(&cfg.record_session).join(format!("session-{}", stamp));
+        fs::create_dir_all(&dir)?;
+
+        // ref is captured at the mic rate, so both files share one clock
+        let sr = shared_mic.sr as u32;
+        let mut ticks = BufWriter::new(File::create(dir.join("ticks.csv"))?);
+        writeln!(
+            ticks,
+            "tick,t_s,ref_samples,mic_samples,analysed,distance_m,strength,vote,agree_pct,present,ref_lost,mic_lost"
+        )?;
+
+        let ref_tap = Tap::new(WavWriter::create(&dir.join("ref.wav"), sr)?, shared_ref);
+        let mic_tap = Tap::new(WavWriter::create(&dir.join("mic.wav"), sr)?, shared_mic);
+
+        let _ = logger.info(&format!("recording session to {}", dir.display()));
+        Ok(Some(Self { dir, ref_tap, mic_tap, ticks, sr, tick: 0, started: Instant::now(), logger }))
+    }
+
+    /// Call once per tick after the detector ran.
+    pub fn tick(&mut self, shared_ref: &SharedBuf, shared_mic: &SharedBuf, meta: TickMeta) {
+        if let Err(e) = self.write_tick(shared_ref, shared_mic, meta) {
+            let _ = self.logger.error(&format!("session recorder: {}", e));
+        }
+    }
+
+    fn write_tick(&mut self, shared_ref: &SharedBuf, shared_mic: &SharedBuf, meta: TickMeta) -> io::Result<()> {
+        let (ref_new, mic_new) = (self.ref_tap.pull(shared_ref), self.mic_tap.pull(shared_mic));
+        let gaps = [ref_new.lost.clone(), mic_new.lost.clone()];
+        let lost = (gaps[0].end - gaps[0].start, gaps[1].end - gaps[1].start);
+        if lost != (0, 0) {
+            let at = (gaps[0].start.min(gaps[1].start) as f64) / (self.sr.max(1) as f64);
+            let _ = self.logger.warn(
+                &format!(
+                    "session recorder: ring overrun at {:.1}s (ref {} mic {} samples lost), silenced in both files",
+                    at,
+                    lost.0,
+                    lost.1
+                )
+            );
+        }
+        self.ref_tap.write(ref_new, &gaps)?;
+        self.mic_tap.write(mic_new, &gaps)?;
+        self.tick += 1;
+
+        let (d, s) = match meta.estimate {
+            Some((d, s)) => (format!("{:.3}", d), format!("{:.3}", s)),
+            None => (String::new(), String::new()),
+        };
+        writeln!(
+            self.ticks,
+            "{},{:.3},{},{},{},{},{},{},{},{},{},{}",
+            self.tick,
+            self.started.elapsed().as_secs_f64(),
+            self.ref_tap.wav.frames,
+            self.mic_tap.wav.frames,
+            meta.analysed,
+            d,
+            s,
+            meta.vote,
+            meta.agree.map(|a| format!("{:.0}", a * 100.0)).unwrap_or_default(),
+            meta.present,
+            lost.0,
+            lost.1
+        )?;
+
+        // keep files usable if the process is killed
+        if self.tick.is_multiple_of(20) {
+            self.ticks.flush()?;
+            self.ref_tap.wav.finish()?;
+            self.mic_tap.wav.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        let _ = self.ticks.flush();
+        let _ = self.ref_tap.wav.finish();
+        let _ = self.mic_tap.wav.finish();
+        let _ = self.logger.info(
+            &format!(
+                "session saved to {} ({} ticks, {:.1}s; samples lost to ring overrun: ref {} mic {})",
+                self.dir.display(),
+                self.tick,
+                (self.mic_tap.wav.frames as f64) / (self.sr.max(1) as f64),
+                self.ref_tap.dropped,
+                self.mic_tap.dropped
+            )
+        );
+    }
+}
+
+/// `--debug-dump`: one CSV row per tick with everything the vote was decided on.
+pub struct DebugDump {
+    out: BufWriter<File>,
+    path: PathBuf,
+    tick: u64,
+    logger: Arc<Logger>,
+}
+
+impl DebugDump {
+    /// None unless `--debug-dump` is set. An existing file is replaced.
+    pub fn open(cfg: &Config, logger: Arc<Logger>) -> anyhow::Result<Option<Self>> {
+        if cfg.debug_dump.is_empty() {
+            return Ok(None);
+        }
+        let path = PathBuf::from(&cfg.debug_dump);
+        let mut out = BufWriter::new(File::create(&path)?);
+        writeln!(
+            out,
+            "tick,t_s,rms_ref,rms_mic,distance_m,strength,bearing_deg,peak_sidelobe,snr_db,direct_r,direct_lag,ref_shift,drift_ppm,vote,agree_pct,present"
+        )?;
+        let _ = logger.info(&format!("per-tick debug dump to {}", path.display()));
+        Ok(Some(Self { out, path, tick: 0, logger }))
+    }
+
+    /// Call once per tick after the detector ran; `t_s` is the run's (or replay's) clock.
+    pub fn tick(&mut self, t_s: f64, meta: &TickMeta) {
+        if let Err(e) = self.write_tick(t_s, meta) {
+            let _ = self.logger.error(&format!("debug dump {}: {}", self.path.display(), e));
+        }
+    }
+
+    fn write_tick(&mut self, t_s: f64, meta: &TickMeta) -> io::Result<()> {
+        self.tick += 1;
+        let cell = |v: Option<f32>, prec: usize| v.map(|v| format!("{:.*}", prec, v)).unwrap_or_default();
+        writeln!(
+            self.out,
+            "{},{:.3},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.tick,
+            t_s,
+            cell(meta.rms.map(|r| r.0), 5),
+            cell(meta.rms.map(|r| r.1), 5),
+            cell(meta.estimate.map(|e| e.0), 3),
+            cell(meta.estimate.map(|e| e.1), 3),
+            cell(meta.bearing_deg, 1),
+            cell(meta.peak_sidelobe, 2),
+            cell(meta.snr_db, 1),
+            cell(meta.direct_r, 3),
+            meta.direct_lag.map(|l| l.to_string()).unwrap_or_default(),
+            meta.ref_shift,
+            cell(meta.drift_ppm, 1),
+            meta.vote,
+            cell(meta.agree.map(|a| a * 100.0), 0),
+            meta.present
+        )?;
+        if self.tick.is_multiple_of(20) {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DebugDump {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav_samples(path: &Path) -> Vec<f32> {
+        fs::read(path).unwrap()[44..].chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect()
+    }
+
+    #[test]
+    fn a_span_lost_on_one_tap_is_silenced_on_both() {
+        let dir = std::env::temp_dir().join(format!("recorder_gap_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("Detection.log").to_string_lossy().into_owned();
+        let cfg = Config { record_session: dir.to_string_lossy().into_owned(), ..Config::default() };
+        let logger = Arc::new(Logger::new(&log_path, false).unwrap());
+        // the reference ring holds 10 samples, the mic's 40
+        let (shared_ref, shared_mic) = (SharedBuf::new(10.0, 1), SharedBuf::new(10.0, 4));
+        let mut rec = SessionRecorder::start(&cfg, &shared_ref, &shared_mic, logger).unwrap().unwrap();
+
+        let ramp = |from: usize, n: usize| (from..from + n).map(|i| (i + 1) as f32).collect::<Vec<f32>>();
+        shared_ref.push(&ramp(0, 5));
+        shared_mic.push(&ramp(0, 5));
+        rec.tick(&shared_ref, &shared_mic, TickMeta::default());
+        // 25 more: the reference ring keeps only the last 10, so positions 5..20 are lost
+        shared_ref.push(&ramp(5, 25));
+        shared_mic.push(&ramp(5, 25));
+        rec.tick(&shared_ref, &shared_mic, TickMeta::default());
+        let session = rec.dir.clone();
+        drop(rec);
+
+        let expected: Vec<f32> = (0..30).map(|i| if (5..20).contains(&i) { 0.0 } else { (i + 1) as f32 }).collect();
+        assert_eq!(wav_samples(&session.join("ref.wav")), expected);
+        assert_eq!(wav_samples(&session.join("mic.wav")), expected);
+        let ticks = fs::read_to_string(session.join("ticks.csv")).unwrap();
+        let rows: Vec<Vec<&str>> = ticks.lines().skip(1).map(|l| l.split(',').collect()).collect();
+        assert_eq!((rows[0][2], rows[0][10], rows[0][11]), ("5", "0", "0"));
+        assert_eq!((rows[1][2], rows[1][3], rows[1][10], rows[1][11]), ("30", "30", "15", "0"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,158 @@
+//! src/resume.rs
+//! `--resume-grace-s`: a live mode started again soon after it stopped (an update, a crash,
+//! a service restart) carries on where it was instead of starting absent with an empty window.
+//! What it knew is kept in resume.json beside the log: the presence state, a summary of the
+//! agreement window and, in gated mode, the song alignment.
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{ Duration, Instant, SystemTime, UNIX_EPOCH },
+};
+
+use crate::logger::Logger;
+use crate::output::{ self, JsonObj };
+use crate::sonar_presence::PresenceState;
+use crate::{ json, Config };
+
+/// The file is rewritten at most this often (and once more on the way out).
+const SAVE_EVERY: Duration = Duration::from_secs(5);
+
+/// One live mode's state, as saved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub state: PresenceState,
+    pub agree: f32, // share of the window that voted
+    pub avg_d: f64, // their distance and strength
+    pub avg_s: f64,
+    pub aligned: Option<(String, f32)>, // gated mode: song and position in it
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self { state: PresenceState::Absent, agree: 0.0, avg_d: f64::INFINITY, avg_s: 0.0, aligned: None }
+    }
+}
+
+impl Snapshot {
+    /// The window's votes as one representative vote, for `DecisionPolicy::restore`.
+    pub fn vote(&self) -> Option<(f32, f32)> {
+        self.avg_d.is_finite().then_some((self.avg_d as f32, self.avg_s as f32))
+    }
+
+    fn to_json(&self, mode: &str, saved_s: f64) -> String {
+        let obj = JsonObj::new()
+            .str("mode", mode)
+            .num("saved_unix_s", saved_s)
+            .str("state", self.state.as_str())
+            .num("agree", self.agree as f64)
+            .num("avg_distance_m", self.avg_d)
+            .num("avg_strength", self.avg_s);
+        match &self.aligned {
+            Some((url, t_song)) => obj.str("url", url).num("t_song", *t_song as f64),
+            None => obj.null("url"),
+        }.finish()
+    }
+
+    /// A snapshot `mode` saved, and when (unix seconds).
+    fn from_json(text: &str, mode: &str) -> Option<(Self, f64)> {
+        let doc = json::parse(text).ok()?;
+        if doc.get("mode")?.as_str()? != mode {
+            return None;
+        }
+        let state = match doc.get("state")?.as_str()? {
+            "idle" => PresenceState::Idle,
+            "active" => PresenceState::Active,
+            _ => PresenceState::Absent,
+        };
+        let url = doc.get("url").and_then(|u| u.as_str());
+        let t_song = doc.get("t_song").and_then(|t| t.as_f64());
+        let snap = Self {
+            state,
+            agree: doc.get("agree")?.as_f64()? as f32,
+            avg_d: doc.get("avg_distance_m").and_then(|d| d.as_f64()).unwrap_or(f64::INFINITY),
+            avg_s: doc.get("avg_strength").and_then(|s| s.as_f64()).unwrap_or(0.0),
+            aligned: url.zip(t_song).map(|(u, t)| (u.to_string(), t as f32)),
+        };
+        Some((snap, doc.get("saved_unix_s")?.as_f64()?))
+    }
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// The live loop's side: load once at startup, save as it goes.
+pub struct Resume {
+    path: PathBuf,
+    mode: &'static str,
+    grace: Duration,
+    saved: Option<Instant>,
+    logger: Arc<Logger>,
+}
+
+impl Resume {
+    /// None with --resume-grace-s 0.
+    pub fn from_config(cfg: &Config, mode: &'static str, logger: Arc<Logger>) -> Option<Self> {
+        let grace = Duration::try_from_secs_f32(cfg.resume_grace_s).ok().filter(|g| !g.is_zero())?;
+        Some(Self { path: output::sibling_path(&cfg.log_path, "resume.json"), mode, grace, saved: None, logger })
+    }
+
+    /// What the last run of this mode left, if it stopped within the grace period. A gated
+    /// alignment comes back moved on by the time in between, as if the song had kept playing.
+    pub fn load(&self) -> Option<Snapshot> {
+        let text = fs::read_to_string(&self.path).ok()?;
+        let (mut snap, saved_s) = Snapshot::from_json(&text, self.mode)?;
+        let age = unix_now() - saved_s;
+        if !(0.0..=self.grace.as_secs_f64()).contains(&age) {
+            let _ = self.logger.debug(&format!("{} is {:.0}s old; starting afresh", self.path.display(), age));
+            return None;
+        }
+        if let Some((_, t_song)) = snap.aligned.as_mut() {
+            *t_song += age as f32;
+        }
+        let _ = self.logger.info(
+            &format!(
+                "resuming from {:.0}s ago: {} (agreement {:.0}%){}",
+                age,
+                snap.state.as_str(),
+                snap.agree * 100.0,
+                snap.aligned.as_ref().map(|(url, t)| format!(", aligned to '{}' at {:.1}s", url, t)).unwrap_or_default()
+            )
+        );
+        Some(snap)
+    }
+
+    /// Call every tick; writes when SAVE_EVERY has passed, or now with `force`.
+    pub fn save(&mut self, snap: &Snapshot, force: bool) {
+        if !force && self.saved.is_some_and(|at| at.elapsed() < SAVE_EVERY) {
+            return;
+        }
+        self.saved = Some(Instant::now());
+        if let Err(e) = output::write_atomic(&self.path, &snap.to_json(self.mode, unix_now())) {
+            let _ = self.logger.warn(&format!("could not write {}: {}", self.path.display(), e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_round_trips_through_the_file_format() {
+        let snap = Snapshot {
+            state: PresenceState::Active,
+            agree: 0.75,
+            avg_d: 0.8,
+            avg_s: 0.4,
+            aligned: Some(("song.mp3".to_string(), 42.5)),
+        };
+        let text = snap.to_json("gated", 1_700_000_000.0);
+        assert_eq!(Snapshot::from_json(&text, "gated"), Some((snap.clone(), 1_700_000_000.0)));
+        assert_eq!(Snapshot::from_json(&text, "presence"), None); // another mode's state
+        let absent = Snapshot { state: PresenceState::Absent, avg_d: f64::INFINITY, aligned: None, ..snap };
+        assert_eq!(Snapshot::from_json(&absent.to_json("presence", 1.0), "presence").map(|(s, _)| s), Some(absent));
+    }
+}
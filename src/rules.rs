@@ -0,0 +1,587 @@
+//! src/rules.rs
+//! `--event-rules <FILE>`: the embedded counterpart of `--event-script`. A small rule language,
+//! parsed once at start and evaluated in-process on every event, so a rule costs microseconds
+//! and never holds up a tick:
+//!
+//!   # nobody while a call is on
+//!   when exists("/run/user/1000/on-call") { rewrite present = false; log "on a call" }
+//!   when event == "state_change" && present && hour >= 22 { webhook "http://10.0.0.5:8123/hook" }
+//!
+//! The rules whose condition holds run in file order, and a later statement overrides an
+//! earlier one (`suppress`, `pass`, `rewrite present = …`, `rewrite state = …` decide the action;
+//! `log` and `webhook` the message and the URL). Names are the event's members, `null` when it
+//! has none, and `hour`, `minute` and `weekday` (0 = Monday) of the local time. Functions:
+//! `exists(path)`, `env(name)` (`null` when unset) and `contains(text, part)`.
+
+use anyhow::{ anyhow, bail, Context, Result };
+use chrono::{ Datelike, NaiveDateTime, Timelike };
+use std::{ fs, path::Path };
+
+use crate::json::Json;
+use crate::script::Verdict;
+use crate::sonar_presence::PresenceState;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Num(v) => *v != 0.0,
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+
+    /// As text, for `+` with a string and for `log`.
+    fn text(&self) -> String {
+        match self {
+            Value::Null => "null".to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Num(v) => format!("{}", v),
+            Value::Str(s) => s.clone(),
+        }
+    }
+
+    fn from_json(v: Option<&Json>) -> Self {
+        match v {
+            Some(Json::Bool(b)) => Value::Bool(*b),
+            Some(Json::Num(v)) => Value::Num(*v),
+            Some(Json::Str(s)) => Value::Str(s.clone()),
+            Some(other @ (Json::Arr(_) | Json::Obj(_))) => Value::Str(other.to_text()),
+            Some(Json::Null) | None => Value::Null,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Func {
+    Exists,
+    Env,
+    Contains,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Lit(Value),
+    Var(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Bin(Op, Box<Expr>, Box<Expr>),
+    Call(Func, Vec<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Stmt {
+    Suppress,
+    Pass,
+    RewritePresent(Expr),
+    RewriteState(Expr),
+    Log(Expr),
+    Webhook(Expr),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Rule {
+    line: usize,
+    when: Expr,
+    then: Vec<Stmt>,
+}
+
+/// A parsed rule file.
+#[derive(Clone, Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> Result<Self> {
+        let src = fs::read_to_string(path).with_context(|| format!("reading event rules {}", path.display()))?;
+        Self::parse(&src).with_context(|| format!("event rules {}", path.display()))
+    }
+
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut p = Parser { toks: tokenize(src)?, at: 0 };
+        let mut rules = Vec::new();
+        while p.peek().is_some() {
+            rules.push(p.rule()?);
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// What the rules make of `event` at local time `now`. An error names the rule's line.
+    pub fn verdict(&self, event: &Json, now: NaiveDateTime) -> Result<Verdict> {
+        let cx = Scope { event, now };
+        let mut v = Verdict::default();
+        for rule in &self.rules {
+            cx.apply(rule, &mut v).with_context(|| format!("rule on line {}", rule.line))?;
+        }
+        Ok(v)
+    }
+}
+
+struct Scope<'a> {
+    event: &'a Json,
+    now: NaiveDateTime,
+}
+
+impl Scope<'_> {
+    fn apply(&self, rule: &Rule, v: &mut Verdict) -> Result<()> {
+        if self.eval(&rule.when)?.truthy() {
+            for stmt in &rule.then {
+                self.run(stmt, v)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&self, stmt: &Stmt, v: &mut Verdict) -> Result<()> {
+        match stmt {
+            Stmt::Suppress => {
+                *v = Verdict { suppress: true, state: None, present: None, ..v.clone() };
+            }
+            Stmt::Pass => {
+                *v = Verdict { suppress: false, state: None, present: None, ..v.clone() };
+            }
+            Stmt::RewritePresent(e) => {
+                let Value::Bool(b) = self.eval(e)? else {
+                    bail!("rewrite present needs true or false");
+                };
+                *v = Verdict { suppress: false, state: None, present: Some(b), ..v.clone() };
+            }
+            Stmt::RewriteState(e) => {
+                let state = match self.eval(e)? {
+                    Value::Str(s) if s == "absent" => PresenceState::Absent,
+                    Value::Str(s) if s == "idle" => PresenceState::Idle,
+                    Value::Str(s) if s == "active" => PresenceState::Active,
+                    other => bail!("rewrite state needs \"absent\", \"idle\" or \"active\", not {}", other.text()),
+                };
+                *v = Verdict { suppress: false, state: Some(state), present: None, ..v.clone() };
+            }
+            Stmt::Log(e) => {
+                v.log = Some(self.eval(e)?.text());
+            }
+            Stmt::Webhook(e) => {
+                v.webhook = Some((self.eval(e)?.text(), None));
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(&self, e: &Expr) -> Result<Value> {
+        Ok(match e {
+            Expr::Lit(v) => v.clone(),
+            Expr::Var(name) =>
+                match name.as_str() {
+                    "hour" => Value::Num(self.now.hour() as f64),
+                    "minute" => Value::Num(self.now.minute() as f64),
+                    "weekday" => Value::Num(self.now.weekday().num_days_from_monday() as f64),
+                    _ => Value::from_json(self.event.get(name)),
+                }
+            Expr::Not(a) => Value::Bool(!self.eval(a)?.truthy()),
+            Expr::Neg(a) =>
+                match self.eval(a)? {
+                    Value::Num(v) => Value::Num(-v),
+                    other => bail!("cannot negate {}", other.text()),
+                }
+            Expr::Bin(Op::And, a, b) => Value::Bool(self.eval(a)?.truthy() && self.eval(b)?.truthy()),
+            Expr::Bin(Op::Or, a, b) => Value::Bool(self.eval(a)?.truthy() || self.eval(b)?.truthy()),
+            Expr::Bin(op, a, b) => binary(*op, self.eval(a)?, self.eval(b)?)?,
+            Expr::Call(f, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| self.eval(a))
+                    .collect::<Result<Vec<_>>>()?;
+                match f {
+                    Func::Exists => Value::Bool(Path::new(&args[0].text()).exists()),
+                    Func::Env => std::env::var(args[0].text()).map_or(Value::Null, Value::Str),
+                    Func::Contains => Value::Bool(args[0].text().contains(&args[1].text())),
+                }
+            }
+        })
+    }
+}
+
+fn binary(op: Op, a: Value, b: Value) -> Result<Value> {
+    Ok(match (op, &a, &b) {
+        (Op::Eq, _, _) => Value::Bool(a == b),
+        (Op::Ne, _, _) => Value::Bool(a != b),
+        (Op::Add, Value::Str(_), _) | (Op::Add, _, Value::Str(_)) => Value::Str(a.text() + &b.text()),
+        (_, Value::Num(x), Value::Num(y)) =>
+            match op {
+                Op::Lt => Value::Bool(x < y),
+                Op::Le => Value::Bool(x <= y),
+                Op::Gt => Value::Bool(x > y),
+                Op::Ge => Value::Bool(x >= y),
+                Op::Add => Value::Num(x + y),
+                Op::Sub => Value::Num(x - y),
+                Op::Mul => Value::Num(x * y),
+                Op::Div => Value::Num(x / y),
+                Op::Eq | Op::Ne | Op::And | Op::Or => unreachable!(),
+            }
+        (Op::Lt | Op::Le | Op::Gt | Op::Ge, Value::Str(x), Value::Str(y)) =>
+            Value::Bool(match op {
+                Op::Lt => x < y,
+                Op::Le => x <= y,
+                Op::Gt => x > y,
+                _ => x >= y,
+            }),
+        // a comparison with a member the event does not have
+        (Op::Lt | Op::Le | Op::Gt | Op::Ge, Value::Null, _) | (Op::Lt | Op::Le | Op::Gt | Op::Ge, _, Value::Null) => Value::Bool(false),
+        _ => bail!("{:?} of {} and {}", op, a.text(), b.text()),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Num(f64),
+    Str(String),
+    Ident(String),
+    Punct(&'static str),
+}
+
+const PUNCT: [&str; 20] = ["==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", ";", ",", "=", "<", ">", "!", "+", "-", "*", "/"];
+
+/// Tokens with the line each starts on.
+fn tokenize(src: &str) -> Result<Vec<(Tok, usize)>> {
+    let mut toks = Vec::new();
+    for (n, text) in src.lines().enumerate() {
+        let line = n + 1;
+        let mut rest = text;
+        loop {
+            rest = rest.trim_start();
+            let Some(c) = rest.chars().next() else {
+                break;
+            };
+            if c == '#' {
+                break;
+            }
+            if c == '"' {
+                let mut s = String::new();
+                let mut chars = rest[1..].char_indices();
+                let end = loop {
+                    match chars.next() {
+                        Some((i, '"')) => {
+                            break i + 2;
+                        }
+                        Some((_, '\\')) =>
+                            match chars.next() {
+                                Some((_, 'n')) => s.push('\n'),
+                                Some((_, 't')) => s.push('\t'),
+                                Some((_, c @ ('"' | '\\'))) => s.push(c),
+                                Some((_, c)) => bail!("line {}: unknown escape \\{}", line, c),
+                                None => bail!("line {}: unterminated string", line),
+                            }
+                        Some((_, c)) => s.push(c),
+                        None => bail!("line {}: unterminated string", line),
+                    }
+                };
+                toks.push((Tok::Str(s), line));
+                rest = &rest[end..];
+            } else if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|d: char| d.is_ascii_digit())) {
+                let len = rest.find(|d: char| !(d.is_ascii_digit() || d == '.')).unwrap_or(rest.len());
+                let v = rest[..len].parse::<f64>().map_err(|_| anyhow!("line {}: bad number {}", line, &rest[..len]))?;
+                toks.push((Tok::Num(v), line));
+                rest = &rest[len..];
+            } else if c.is_alphabetic() || c == '_' {
+                let len = rest.find(|d: char| !(d.is_alphanumeric() || d == '_')).unwrap_or(rest.len());
+                toks.push((Tok::Ident(rest[..len].to_string()), line));
+                rest = &rest[len..];
+            } else {
+                let p = PUNCT.iter()
+                    .find(|p| rest.starts_with(**p))
+                    .ok_or_else(|| anyhow!("line {}: unexpected '{}'", line, c))?;
+                toks.push((Tok::Punct(p), line));
+                rest = &rest[p.len()..];
+            }
+        }
+    }
+    Ok(toks)
+}
+
+struct Parser {
+    toks: Vec<(Tok, usize)>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.toks.get(self.at).map(|(t, _)| t)
+    }
+
+    fn line(&self) -> usize {
+        self.toks
+            .get(self.at)
+            .or(self.toks.last())
+            .map_or(1, |(_, l)| *l)
+    }
+
+    fn next(&mut self) -> Result<Tok> {
+        let tok = self.toks.get(self.at).map(|(t, _)| t.clone());
+        self.at += 1;
+        tok.ok_or_else(|| anyhow!("line {}: unexpected end of the rules", self.line()))
+    }
+
+    /// Consume `p` if it comes next.
+    fn eat(&mut self, p: &str) -> bool {
+        if matches!(self.peek(), Some(Tok::Punct(q)) if *q == p) {
+            self.at += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, p: &str) -> Result<()> {
+        if !self.eat(p) {
+            bail!("line {}: expected '{}'", self.line(), p);
+        }
+        Ok(())
+    }
+
+    fn word(&mut self) -> Result<String> {
+        let line = self.line();
+        match self.next()? {
+            Tok::Ident(w) => Ok(w),
+            other => bail!("line {}: expected a name, found {:?}", line, other),
+        }
+    }
+
+    fn rule(&mut self) -> Result<Rule> {
+        let line = self.line();
+        if self.word()? != "when" {
+            bail!("line {}: a rule starts with 'when'", line);
+        }
+        let when = self.expr()?;
+        self.expect("{")?;
+        let mut then = Vec::new();
+        while !self.eat("}") {
+            then.push(self.stmt()?);
+            self.eat(";");
+        }
+        Ok(Rule { line, when, then })
+    }
+
+    fn stmt(&mut self) -> Result<Stmt> {
+        let line = self.line();
+        Ok(match self.word()?.as_str() {
+            "suppress" => Stmt::Suppress,
+            "pass" => Stmt::Pass,
+            "log" => Stmt::Log(self.expr()?),
+            "webhook" => Stmt::Webhook(self.expr()?),
+            "rewrite" => {
+                let what = self.word()?;
+                self.expect("=")?;
+                match what.as_str() {
+                    "present" => Stmt::RewritePresent(self.expr()?),
+                    "state" => Stmt::RewriteState(self.expr()?),
+                    other => bail!("line {}: rewrite present or state, not {}", line, other),
+                }
+            }
+            other => bail!("line {}: unknown statement {}", line, other),
+        })
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut e = self.and()?;
+        while self.eat("||") {
+            e = Expr::Bin(Op::Or, Box::new(e), Box::new(self.and()?));
+        }
+        Ok(e)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut e = self.cmp()?;
+        while self.eat("&&") {
+            e = Expr::Bin(Op::And, Box::new(e), Box::new(self.cmp()?));
+        }
+        Ok(e)
+    }
+
+    fn cmp(&mut self) -> Result<Expr> {
+        let e = self.sum()?;
+        for (p, op) in [("==", Op::Eq), ("!=", Op::Ne), ("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)] {
+            if self.eat(p) {
+                return Ok(Expr::Bin(op, Box::new(e), Box::new(self.sum()?)));
+            }
+        }
+        Ok(e)
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        let mut e = self.product()?;
+        loop {
+            let op = if self.eat("+") {
+                Op::Add
+            } else if self.eat("-") {
+                Op::Sub
+            } else {
+                return Ok(e);
+            };
+            e = Expr::Bin(op, Box::new(e), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        let mut e = self.unary()?;
+        loop {
+            let op = if self.eat("*") {
+                Op::Mul
+            } else if self.eat("/") {
+                Op::Div
+            } else {
+                return Ok(e);
+            };
+            e = Expr::Bin(op, Box::new(e), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let line = self.line();
+        Ok(match self.next()? {
+            Tok::Num(v) => Expr::Lit(Value::Num(v)),
+            Tok::Str(s) => Expr::Lit(Value::Str(s)),
+            Tok::Punct("(") => {
+                let e = self.expr()?;
+                self.expect(")")?;
+                e
+            }
+            Tok::Ident(w) =>
+                match w.as_str() {
+                    "true" => Expr::Lit(Value::Bool(true)),
+                    "false" => Expr::Lit(Value::Bool(false)),
+                    "null" => Expr::Lit(Value::Null),
+                    _ if self.eat("(") => {
+                        let (func, arity) = match w.as_str() {
+                            "exists" => (Func::Exists, 1),
+                            "env" => (Func::Env, 1),
+                            "contains" => (Func::Contains, 2),
+                            other => bail!("line {}: unknown function {}", line, other),
+                        };
+                        let mut args = Vec::new();
+                        if !self.eat(")") {
+                            loop {
+                                args.push(self.expr()?);
+                                if self.eat(")") {
+                                    break;
+                                }
+                                self.expect(",")?;
+                            }
+                        }
+                        if args.len() != arity {
+                            bail!("line {}: {} takes {} argument(s)", line, w, arity);
+                        }
+                        Expr::Call(func, args)
+                    }
+                    _ => Expr::Var(w),
+                }
+            other => bail!("line {}: unexpected {:?}", line, other),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    fn at(hour: u32) -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2026, 10, 15).unwrap().and_hms_opt(hour, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn rules_decide_in_file_order() {
+        let marker = std::env::temp_dir().join(format!("rules_on_call_{}", std::process::id()));
+        let _ = fs::remove_file(&marker);
+        let src = format!(
+            r#"
+            # far echoes are the hallway
+            when distance_m > 2.5 && event == "measurement" {{ suppress }}
+            when exists("{}") {{ rewrite present = false; log "on a call, " + distance_m + " m" }}
+            when event == "state_change" && present && (hour >= 22 || hour < 6) {{
+                webhook "http://10.0.0.5:8123/hook"
+            }}
+            when probability != null && probability < 0.2 {{ rewrite state = "idle" }}
+            "#,
+            marker.display().to_string().replace('\\', "\\\\")
+        );
+        let rules = Rules::parse(&src).unwrap();
+        assert_eq!(rules.len(), 4);
+        let ev = |text: &str| json::parse(text).unwrap();
+
+        let near = ev(r#"{"event":"measurement","present":true,"state":"active","distance_m":1.2}"#);
+        assert_eq!(rules.verdict(&near, at(12)).unwrap(), Verdict::default());
+        let far = ev(r#"{"event":"measurement","present":true,"distance_m":3}"#);
+        assert!(rules.verdict(&far, at(12)).unwrap().suppress);
+
+        // a later rule overrides the suppress, and the message has the distance in it
+        fs::write(&marker, "").unwrap();
+        let v = rules.verdict(&far, at(12)).unwrap();
+        assert_eq!((v.suppress, v.present, v.log.as_deref()), (false, Some(false), Some("on a call, 3 m")));
+        fs::remove_file(&marker).unwrap();
+
+        let change = ev(r#"{"event":"state_change","present":true,"distance_m":1}"#);
+        assert_eq!(rules.verdict(&change, at(12)).unwrap().webhook, None);
+        assert_eq!(rules.verdict(&change, at(23)).unwrap().webhook, Some(("http://10.0.0.5:8123/hook".to_string(), None)));
+        let unsure = ev(r#"{"event":"measurement","probability":0.1,"distance_m":1}"#);
+        assert_eq!(rules.verdict(&unsure, at(12)).unwrap().state, Some(PresenceState::Idle));
+
+        // precedence and arithmetic
+        let r = Rules::parse(r#"when !false && 1 + 2 * 3 == 7 && -(2 - 4) / 2 == 1 && "a\"b" == "a\"b" { suppress }"#).unwrap();
+        assert!(r.verdict(&near, at(0)).unwrap().suppress);
+    }
+
+    #[test]
+    fn mistakes_are_reported_with_their_line() {
+        let err = |src: &str| format!("{:#}", Rules::parse(src).unwrap_err());
+        assert!(err("when true { suppress }\nwhen x > { pass }").contains("line 2"), "{}", err("when x > { pass }"));
+        assert!(err("when true { explode }").contains("unknown statement explode"));
+        assert!(err("when nope(1) { pass }").contains("unknown function nope"));
+        assert!(err("when contains(\"a\") { pass }").contains("takes 2"));
+        assert!(err("when \"open { pass }").contains("unterminated string"));
+        assert!(err("if true { pass }").contains("starts with 'when'"));
+
+        let r = Rules::parse("\n\nwhen state > 1 { pass }\nwhen true { rewrite state = \"gone\" }").unwrap();
+        let ev = json::parse(r#"{"state":"idle"}"#).unwrap();
+        let e = format!("{:#}", r.verdict(&ev, at(0)).unwrap_err());
+        assert!(e.contains("line 3"), "{}", e);
+        let ev = json::parse(r#"{"state":2}"#).unwrap();
+        let e = format!("{:#}", r.verdict(&ev, at(0)).unwrap_err());
+        assert!(e.contains("line 4") && e.contains("gone"), "{}", e);
+    }
+}
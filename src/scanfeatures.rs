@@ -0,0 +1,142 @@
+//! src/scanfeatures.rs
+//! `--export-features <PATH>`: every window offline mode analysed, with its raw features,
+//! their z-scores over the track and its score, as one JSON document. SongScan.csv only keeps
+//! the top segments; this keeps everything a custom re-ranking needs, so trying one means
+//! reading the JSON rather than decoding and analysing the audio again.
+
+use anyhow::{ Context, Result };
+use std::{ fs, path::Path };
+
+use crate::output::JsonObj;
+use crate::prescan::{ ScanParams, Segment, WindowFeat, MEL_BANDS, MEL_HI_HZ, MEL_LO_HZ };
+
+pub const VERSION: i64 = 1;
+
+/// Full precision: a feature like `hf_ratio` lives well below JsonObj::num's 3 decimals.
+fn num(v: f32) -> String {
+    if v.is_finite() { format!("{}", v) } else { "null".to_string() }
+}
+
+fn window(w: &WindowFeat) -> String {
+    let z = &w.z;
+    let zs = JsonObj::new()
+        .raw("flux", &num(z.flux_z))
+        .raw("flatness", &num(z.flatness_z))
+        .raw("crest", &num(z.crest_z))
+        .raw("bandwidth", &num(z.bandwidth_z))
+        .raw("hf_ratio", &num(z.hf_ratio_z))
+        .raw("dynrange", &num(z.dynrange_z))
+        .raw("tonality", &num(z.tonality_z))
+        .finish();
+    let mel: Vec<String> = w.mel_db.iter().map(|&v| num(v)).collect();
+    JsonObj::new()
+        .raw("start_s", &num(w.start_s))
+        .raw("end_s", &num(w.end_s))
+        .raw("flux", &num(w.flux))
+        .raw("flatness", &num(w.flatness))
+        .raw("crest_db", &num(w.crest_db))
+        .raw("bandwidth_hz_95", &num(w.bandwidth_hz_95))
+        .raw("hf_ratio", &num(w.hf_ratio))
+        .raw("dyn_range", &num(w.dyn_range))
+        .raw("tonality", &num(w.tonality))
+        .raw("loudness_dbfs", &num(w.loudness_dbfs))
+        .raw("z", &zs)
+        .arr("mel_db", &mel)
+        .raw("score", &num(w.score))
+        .finish()
+}
+
+/// The document for the track tagged `tag`: the analysis settings, the chosen segments and
+/// every window, in time order. Times are seconds of the file.
+pub fn to_json(tag: &str, params: &ScanParams, wins: &[WindowFeat], segs: &[Segment]) -> String {
+    let segments: Vec<String> = segs
+        .iter()
+        .map(|s| {
+            JsonObj::new()
+                .raw("start_s", &num(s.start_s))
+                .raw("end_s", &num(s.end_s))
+                .raw("peak_start_s", &num(s.peak.start_s))
+                .raw("score", &num(s.peak.score))
+                .finish()
+        })
+        .collect();
+    let windows: Vec<String> = wins.iter().map(window).collect();
+    JsonObj::new()
+        .int("version", VERSION)
+        .str("url", tag)
+        .raw("sample_rate_hz", &num(params.sr))
+        .raw("frame_ms", &num(params.frame_ms))
+        .raw("window_s", &num(params.window_s))
+        .raw("stride_ms", &num(params.stride_ms))
+        .raw("hf_split_hz", &num(params.hf_split_hz))
+        .str("scorer", &params.scorer.note())
+        .raw(
+            "mel_bands",
+            &JsonObj::new()
+                .int("count", MEL_BANDS as i64)
+                .raw("lo_hz", &num(MEL_LO_HZ))
+                .raw("hi_hz", &num(MEL_HI_HZ.min(params.sr * 0.5)))
+                .finish()
+        )
+        .arr("segments", &segments)
+        .arr("windows", &windows)
+        .finish()
+}
+
+pub fn write(path: &Path, tag: &str, params: &ScanParams, wins: &[WindowFeat], segs: &[Segment]) -> Result<()> {
+    fs::write(path, to_json(tag, params, wins, segs)).with_context(|| format!("writing features {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{ self, Json };
+    use crate::prescan::{ Analyzer, ScanWeights };
+    use crate::scanscore::Heuristic;
+
+    #[test]
+    fn every_window_round_trips_through_json() {
+        let params = ScanParams {
+            sr: 16000.0,
+            frame_ms: 32.0,
+            window_s: 1.0,
+            stride_ms: 500.0,
+            hf_split_hz: 4000.0,
+            top_n: 2,
+            min_percentile: 0.0,
+            nms_radius_s: 0.5,
+            merge_gap_s: 0.0,
+            clamp_min_s: 1.0,
+            clamp_max_s: 5.0,
+            scorer: Box::new(Heuristic(ScanWeights::default())),
+        };
+        // a tone that swells and fades, with clicks in the middle
+        let mut x: Vec<f32> = (0..16000 * 6)
+            .map(|i| {
+                let t = (i as f32) / 16000.0;
+                0.3 * (t / 6.0 * std::f32::consts::PI).sin() * (2.0 * std::f32::consts::PI * 440.0 * t).sin()
+            })
+            .collect();
+        for i in (16000 * 2..16000 * 4).step_by(800) {
+            x[i] = 0.9;
+        }
+        let mut analyzer = Analyzer::new(&params);
+        analyzer.push(&x);
+        let (segs, wins) = analyzer.finish_with_windows();
+        assert!(wins.len() > 4 && !segs.is_empty());
+
+        let doc = json::parse(&to_json("a \"b\"", &params, &wins, &segs)).unwrap();
+        assert_eq!(doc.get("url").and_then(|v| v.as_str()), Some("a \"b\""));
+        let Some(Json::Arr(rows)) = doc.get("windows") else {
+            panic!("no windows array");
+        };
+        assert_eq!(rows.len(), wins.len());
+        for (row, w) in rows.iter().zip(&wins) {
+            let f = |k: &str| row.get(k).and_then(|v| v.as_f64()).unwrap() as f32;
+            assert_eq!((f("start_s"), f("hf_ratio"), f("score")), (w.start_s, w.hf_ratio, w.score));
+            assert_eq!(row.get("z").and_then(|z| z.get("crest")).and_then(|v| v.as_f64()).map(|v| v as f32), Some(w.z.crest_z));
+            assert!(matches!(row.get("mel_db"), Some(Json::Arr(m)) if m.len() == MEL_BANDS));
+        }
+        assert!(matches!(doc.get("segments"), Some(Json::Arr(s)) if s.len() == segs.len()));
+    }
+}
@@ -0,0 +1,225 @@
+//! src/scanscore.rs
+//! `--scan-scorer`: how scan and offline mode score a window of the track, behind a trait.
+//! The windows are ranked by their score (percentile threshold, NMS, merge) into the segments
+//! SongScan.csv keeps, so swapping the scorer changes which parts of a song gated mode listens
+//! in. A new scorer is one more impl and one more `parse` arm.
+
+use anyhow::{ bail, Context, Result };
+use std::path::Path;
+
+use crate::logger::Logger;
+use crate::onnx::Model;
+use crate::prescan::{ ScanWeights, WindowFeat, MEL_BANDS };
+use crate::Config;
+
+/// Scores one analysis window; higher is a better place to listen for an echo.
+pub trait SegmentScorer: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// `w` has its features and their z-scores over the whole track filled in.
+    fn score(&self, w: &WindowFeat) -> f32;
+
+    /// What SongScan.csv's `notes` records, enough to score the same way again.
+    fn note(&self) -> String {
+        format!("scan-scorer {}", self.name())
+    }
+}
+
+/// `--scan-scorer`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScorerKind {
+    Heuristic, // weighted sum of the feature z-scores (--scan-weights)
+    HfEnergy, // level above --hf-split-hz alone
+    Crest, // crest factor alone: transient-rich windows
+    Onnx, // a --scan-model regressor of the window's features
+}
+
+impl ScorerKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "heuristic" => Some(ScorerKind::Heuristic),
+            "hf-energy" | "hf" => Some(ScorerKind::HfEnergy),
+            "crest" => Some(ScorerKind::Crest),
+            "onnx" => Some(ScorerKind::Onnx),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScorerKind::Heuristic => "heuristic",
+            ScorerKind::HfEnergy => "hf-energy",
+            ScorerKind::Crest => "crest",
+            ScorerKind::Onnx => "onnx",
+        }
+    }
+}
+
+/// The `--scan-scorer` of `cfg`; the onnx scorer loads `--scan-model`.
+pub fn scorer(cfg: &Config, logger: &Logger) -> Result<Box<dyn SegmentScorer>> {
+    Ok(match cfg.scan_scorer {
+        ScorerKind::Heuristic => Box::new(Heuristic(cfg.scan_weights)),
+        ScorerKind::HfEnergy => Box::new(HfEnergy),
+        ScorerKind::Crest => Box::new(Crest),
+        ScorerKind::Onnx => {
+            if cfg.scan_model.is_empty() {
+                bail!("--scan-scorer onnx needs --scan-model <PATH>");
+            }
+            let scorer = Learned::load(Path::new(&cfg.scan_model))?;
+            logger.info(&format!("Scan scorer: model {}", cfg.scan_model))?;
+            Box::new(scorer)
+        }
+    })
+}
+
+pub struct Heuristic(pub ScanWeights);
+
+impl SegmentScorer for Heuristic {
+    fn name(&self) -> &'static str {
+        "heuristic"
+    }
+
+    fn score(&self, w: &WindowFeat) -> f32 {
+        self.0.score(&w.z, w.loudness_dbfs)
+    }
+
+    fn note(&self) -> String {
+        format!("scan-weights {}", self.0.as_arg())
+    }
+}
+
+/// Median level of the band above --hf-split-hz, in dB: where a probe would hide best.
+pub struct HfEnergy;
+
+impl SegmentScorer for HfEnergy {
+    fn name(&self) -> &'static str {
+        "hf-energy"
+    }
+
+    fn score(&self, w: &WindowFeat) -> f32 {
+        w.loudness_dbfs + 10.0 * w.hf_ratio.max(1e-6).log10()
+    }
+}
+
+/// 75th-percentile crest factor of the window's frames, in dB.
+pub struct Crest;
+
+impl SegmentScorer for Crest {
+    fn name(&self) -> &'static str {
+        "crest"
+    }
+
+    fn score(&self, w: &WindowFeat) -> f32 {
+        w.crest_db
+    }
+}
+
+/// Model inputs, in order: these, then `mel_db` from the lowest band to the highest.
+pub const SCALAR_INPUTS: [&str; 8] = [
+    "flux_z",
+    "flatness_z",
+    "crest_z",
+    "bandwidth_z",
+    "hf_ratio_z",
+    "dynrange_z",
+    "tonality_z",
+    "loudness_dbfs",
+];
+pub const MODEL_INPUTS: usize = SCALAR_INPUTS.len() + MEL_BANDS;
+
+/// A model trained on which windows detected well (WindowQuality.csv's `score`, say): its
+/// output for the window's features is the score.
+pub struct Learned {
+    model: Model,
+    path: String,
+}
+
+impl Learned {
+    pub fn load(path: &Path) -> Result<Self> {
+        let model = Model::read(path)?;
+        if let Some(n) = model.inputs().filter(|&n| n != MODEL_INPUTS) {
+            bail!(
+                "{}: the model takes {} inputs, a scan window has {} ({}, then {} mel bands)",
+                path.display(),
+                n,
+                MODEL_INPUTS,
+                SCALAR_INPUTS.join(", "),
+                MEL_BANDS
+            );
+        }
+        // an unsupported shape shows up now rather than as windows that all score 0
+        model.probability(&[0.0; MODEL_INPUTS]).with_context(|| format!("running model {}", path.display()))?;
+        Ok(Self { model, path: path.display().to_string() })
+    }
+}
+
+pub fn model_input(w: &WindowFeat) -> [f32; MODEL_INPUTS] {
+    let z = &w.z;
+    let mut x = [0.0; MODEL_INPUTS];
+    x[..SCALAR_INPUTS.len()].copy_from_slice(
+        &[z.flux_z, z.flatness_z, z.crest_z, z.bandwidth_z, z.hf_ratio_z, z.dynrange_z, z.tonality_z, w.loudness_dbfs]
+    );
+    x[SCALAR_INPUTS.len()..].copy_from_slice(&w.mel_db);
+    x
+}
+
+impl SegmentScorer for Learned {
+    fn name(&self) -> &'static str {
+        "onnx"
+    }
+
+    fn score(&self, w: &WindowFeat) -> f32 {
+        self.model.probability(&model_input(w)).unwrap_or(0.0)
+    }
+
+    fn note(&self) -> String {
+        format!("scan-scorer onnx scan-model {}", self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prescan::FeatZ;
+
+    fn window(crest_db: f32, hf_ratio: f32, loudness_dbfs: f32) -> WindowFeat {
+        WindowFeat {
+            start_s: 0.0,
+            end_s: 3.0,
+            flux: 0.0,
+            flatness: 0.0,
+            crest_db,
+            bandwidth_hz_95: 0.0,
+            hf_ratio,
+            dyn_range: 0.0,
+            tonality: 0.0,
+            loudness_dbfs,
+            mel_db: [0.0; MEL_BANDS],
+            score: 0.0,
+            z: FeatZ { crest_z: crest_db / 10.0, ..FeatZ::default() },
+        }
+    }
+
+    #[test]
+    fn scorers_rank_by_their_own_feature() {
+        let (punchy, bright) = (window(18.0, 0.01, -20.0), window(6.0, 0.5, -20.0));
+        assert!(Crest.score(&punchy) > Crest.score(&bright));
+        assert!(HfEnergy.score(&bright) > HfEnergy.score(&punchy));
+        assert!((HfEnergy.score(&bright) - (-20.0 + 10.0 * (0.5f32).log10())).abs() < 1e-4);
+        let heuristic = Heuristic(ScanWeights::default());
+        assert!(heuristic.score(&punchy) > heuristic.score(&bright));
+        // a quiet window pays both loudness penalties
+        assert!((heuristic.score(&window(0.0, 0.0, -70.0)) - -1.5).abs() < 1e-6);
+        assert_eq!(Crest.note(), "scan-scorer crest");
+        for kind in [ScorerKind::Heuristic, ScorerKind::HfEnergy, ScorerKind::Crest, ScorerKind::Onnx] {
+            assert_eq!(ScorerKind::parse(kind.as_str()), Some(kind));
+        }
+    }
+
+    #[test]
+    fn onnx_without_a_model_is_refused() {
+        let cfg = Config { scan_scorer: ScorerKind::Onnx, ..Config::default() };
+        let logger = Logger::new(&std::env::temp_dir().join("sonar-scanscore.log").to_string_lossy(), false).unwrap();
+        assert!(scorer(&cfg, &logger).is_err());
+    }
+}
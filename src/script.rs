@@ -0,0 +1,466 @@
+//! src/script.rs
+//! `--event-script <CMD>`: site rules for what gets reported, in any language. The command is
+//! started once and kept running; each full window is written to its stdin as a JSON line
+//! (`"event":"measurement"`), and so is each change of the reported state (`"state_change"`).
+//! It answers every event with one JSON line on stdout, echoing the event's `"seq"`:
+//!   `{"seq":7}` or `{"seq":7,"action":"pass"}`  report as measured
+//!   `{"seq":7,"action":"suppress"}`             drop it: the reported state holds
+//!   `{"seq":7,"action":"rewrite","present":false}`  report this instead (or `"state":"idle"`)
+//! and may add `"log":"…"` for Detection.log or `"webhook":"http://…"` (with an optional
+//! `"body"`, else the event) to POST. Replies for other events are discarded, and one that does
+//! not come within `--event-script-timeout-ms` passes the event; a script that exits is not
+//! restarted. `--event-rules` decides the same events in-process instead (src/rules.rs).
+
+use anyhow::{ bail, Context, Result };
+use crossbeam_channel::{ unbounded, Receiver, RecvTimeoutError };
+use std::{
+    io::{ BufRead, BufReader, Read, Write },
+    net::{ TcpStream, ToSocketAddrs },
+    path::Path,
+    process::{ Child, ChildStdin, Command, Stdio },
+    sync::Arc,
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::json::{ self, Json };
+use crate::logger::Logger;
+use crate::mods::presence::WindowState;
+use crate::output::JsonObj;
+use crate::rules::Rules;
+use crate::sonar_presence::PresenceState;
+use crate::Config;
+
+/// What the script made of an event.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Verdict {
+    pub suppress: bool,
+    pub state: Option<PresenceState>, // rewrite to this
+    pub present: Option<bool>, // rewrite to present (idle unless already present) or absent
+    pub log: Option<String>,
+    pub webhook: Option<(String, Option<String>)>, // url, body
+}
+
+impl Verdict {
+    /// A reply line; anything unreadable passes the event.
+    pub fn parse(reply: &str) -> Result<Self> {
+        let doc = json::parse(reply.trim())?;
+        let text = |k: &str| doc.get(k).and_then(Json::as_str).map(str::to_string);
+        let mut v = Verdict { log: text("log"), ..Verdict::default() };
+        if let Some(url) = text("webhook") {
+            let body = match doc.get("body") {
+                None | Some(Json::Null) => None,
+                Some(Json::Str(s)) => Some(s.clone()),
+                Some(other) => Some(other.to_text()),
+            };
+            v.webhook = Some((url, body));
+        }
+        match text("action").as_deref().unwrap_or("pass") {
+            "pass" => {}
+            "suppress" => v.suppress = true,
+            "rewrite" => {
+                v.present = match doc.get("present") {
+                    Some(Json::Bool(b)) => Some(*b),
+                    _ => None,
+                };
+                v.state = match text("state").as_deref() {
+                    Some("absent") => Some(PresenceState::Absent),
+                    Some("idle") => Some(PresenceState::Idle),
+                    Some("active") => Some(PresenceState::Active),
+                    Some(other) => bail!("unknown state {}", other),
+                    None => None,
+                };
+                if v.present.is_none() && v.state.is_none() {
+                    bail!("rewrite without present or state");
+                }
+            }
+            other => bail!("unknown action {}", other),
+        }
+        Ok(v)
+    }
+
+    /// The state a rewrite turns `state` into.
+    fn rewrite(&self, state: PresenceState) -> PresenceState {
+        match (self.state, self.present) {
+            (Some(s), _) => s,
+            (None, Some(false)) => PresenceState::Absent,
+            (None, Some(true)) if !state.present() => PresenceState::Idle,
+            _ => state,
+        }
+    }
+}
+
+/// The reply of an event script: the `"seq"` it answers, if it gave one.
+pub fn reply_seq(reply: &str) -> Option<u64> {
+    json::parse(reply.trim()).ok()?.get("seq")?.as_f64().map(|s| s as u64)
+}
+
+/// The external program and its replies.
+struct Process {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    replies: Receiver<String>,
+}
+
+/// Where the verdicts come from.
+enum Judge {
+    Process(Process), // --event-script
+    Rules(Rules), // --event-rules
+}
+
+/// The running script or rules and the state reported through them.
+pub struct EventScript {
+    judge: Judge,
+    mode: &'static str,
+    timeout: Duration,
+    webhook_timeout: Duration,
+    seq: u64,
+    reported: Option<PresenceState>, // None until the first window passed
+    slow: bool, // warned about a missed reply
+    stray: bool, // warned about a reply for another event
+    failed: bool, // warned about a rule that could not be evaluated
+    logger: Arc<Logger>,
+}
+
+impl EventScript {
+    /// None unless `--event-script` or `--event-rules` is set.
+    pub fn start(cfg: &Config, mode: &'static str, logger: Arc<Logger>) -> Result<Option<Self>> {
+        let judge = match (cfg.event_script.trim().is_empty(), cfg.event_rules.is_empty()) {
+            (true, true) => {
+                return Ok(None);
+            }
+            (false, false) => bail!("--event-script and --event-rules are alternatives; give one"),
+            (true, false) => {
+                let rules = Rules::load(Path::new(&cfg.event_rules))?;
+                logger.info(&format!("event rules: {} rule(s) from {}", rules.len(), cfg.event_rules))?;
+                Judge::Rules(rules)
+            }
+            (false, true) => Judge::Process(Process::start(cfg, mode, &logger)?),
+        };
+        Ok(
+            Some(Self {
+                judge,
+                mode,
+                timeout: Duration::from_millis(cfg.event_script_timeout_ms),
+                webhook_timeout: Duration::from_millis(cfg.hook_timeout_ms),
+                seq: 0,
+                reported: None,
+                slow: false,
+                stray: false,
+                failed: false,
+                logger,
+            })
+        )
+    }
+    /// The state as last reported, once a window has been.
+    pub fn present(&self) -> Option<bool> {
+        self.reported.map(|s| s.present())
+    }
+
+    /// Put one full window through the script. False: suppressed, report nothing for it.
+    /// Otherwise `w` holds what to report, its flip flags relative to the reported state.
+    pub fn filter(&mut self, w: &mut WindowState) -> bool {
+        let event = self.event("measurement", w, None);
+        let verdict = self.ask(&event);
+        if verdict.suppress {
+            return false;
+        }
+        // the detector starts absent, and so does what is reported
+        let before = self.reported.unwrap_or_default();
+        settle(before, w, &verdict);
+        if w.state_changed {
+            let event = self.event("state_change", w, Some(before));
+            if self.ask(&event).suppress {
+                // the change is not reported: hold the state from before it
+                w.state = before;
+                w.flipped = false;
+                w.state_changed = false;
+            }
+        }
+        self.reported = Some(w.state);
+        true
+    }
+
+    fn event(&mut self, kind: &str, w: &WindowState, from: Option<PresenceState>) -> String {
+        self.seq += 1;
+        let mut o = JsonObj::new()
+            .str("event", kind)
+            .str("mode", self.mode)
+            .int("seq", self.seq as i64)
+            .str("time", &chrono::Local::now().to_rfc3339())
+            .bool("present", w.state.present())
+            .str("state", w.state.as_str());
+        if let Some(from) = from {
+            o = o.str("from", from.as_str());
+        }
+        o.opt_num("distance_m", w.avg_d.is_finite().then_some(w.avg_d))
+            .num("strength", w.avg_s)
+            .num("agree", w.agree as f64)
+            .opt_num("probability", w.probability.map(|p| p as f64))
+            .opt_num("bearing_deg", w.bearing_deg)
+            .finish()
+    }
+
+    /// Decide an event and act on the verdict's log line and webhook; a missing or bad reply, or
+    /// a rule that fails, passes it.
+    fn ask(&mut self, event: &str) -> Verdict {
+        let verdict = if let Judge::Rules(rules) = &self.judge {
+            let doc = json::parse(event).expect("events are valid JSON");
+            match rules.verdict(&doc, chrono::Local::now().naive_local()) {
+                Ok(v) => v,
+                Err(e) => {
+                    let msg = format!("event rules: {:#}; passing the event", e);
+                    let _ = if self.failed { self.logger.debug(&msg) } else { self.logger.warn(&msg) };
+                    self.failed = true;
+                    return Verdict::default();
+                }
+            }
+        } else {
+            let Some(reply) = self.reply(event) else {
+                return Verdict::default();
+            };
+            match Verdict::parse(&reply) {
+                Ok(v) => v,
+                Err(e) => {
+                    let _ = self.logger.warn(&format!("event script reply {:?}: {:#}", reply, e));
+                    return Verdict::default();
+                }
+            }
+        };
+        if let Some(msg) = &verdict.log {
+            let _ = self.logger.info(&format!("script: {}", msg));
+        }
+        if let Some((url, body)) = verdict.webhook.clone() {
+            let (logger, timeout) = (self.logger.clone(), self.webhook_timeout);
+            let body = body.unwrap_or_else(|| event.to_string());
+            thread::spawn(move || {
+                match post(&url, &body, timeout) {
+                    Ok(status) if (200..300).contains(&status) => {
+                        let _ = logger.debug(&format!("webhook {}: {}", url, status));
+                    }
+                    Ok(status) => {
+                        let _ = logger.warn(&format!("webhook {}: HTTP {}", url, status));
+                    }
+                    Err(e) => {
+                        let _ = logger.warn(&format!("webhook {}: {:#}", url, e));
+                    }
+                }
+            });
+        }
+        verdict
+    }
+
+    /// Send an event to the script and wait for the reply carrying its seq; replies to earlier
+    /// events that come in meanwhile are discarded.
+    fn reply(&mut self, event: &str) -> Option<String> {
+        let Judge::Process(p) = &mut self.judge else {
+            return None;
+        };
+        let stdin = p.stdin.as_mut()?;
+        if writeln!(stdin, "{}", event).and_then(|_| stdin.flush()).is_err() {
+            let status = p.child.try_wait().ok().flatten();
+            let _ = self.logger.warn(&format!("event script stopped ({:?}); reporting events as measured", status.and_then(|s| s.code())));
+            p.stdin = None;
+            return None;
+        }
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            match p.replies.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) =>
+                    match reply_seq(&line) {
+                        Some(seq) if seq == self.seq => {
+                            return Some(line);
+                        }
+                        // late, for an event that was already passed
+                        Some(seq) if seq < self.seq => {
+                            let _ = self.logger.debug(&format!("event script reply for seq {} came late; discarded", seq));
+                        }
+                        _ => {
+                            if !self.stray {
+                                let _ = self.logger.warn(
+                                    &format!("event script reply {:?} does not echo seq {}; discarded", line, self.seq)
+                                );
+                                self.stray = true;
+                            }
+                        }
+                    }
+                Err(RecvTimeoutError::Timeout) => {
+                    if !self.slow {
+                        let _ = self.logger.warn(&format!("event script did not reply within {} ms; passing the event", self.timeout.as_millis()));
+                        self.slow = true;
+                    }
+                    return None;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = self.logger.warn("event script closed its output; reporting events as measured");
+                    p.stdin = None;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl Process {
+    /// `--event-script` through the shell, its stdout read line by line.
+    fn start(cfg: &Config, mode: &'static str, logger: &Arc<Logger>) -> Result<Self> {
+        #[cfg(target_os = "windows")]
+        let mut command = {
+            let mut c = Command::new("cmd");
+            c.arg("/C").arg(&cfg.event_script);
+            c
+        };
+        #[cfg(not(target_os = "windows"))]
+        let mut command = {
+            let mut c = Command::new("sh");
+            c.arg("-c").arg(&cfg.event_script);
+            c
+        };
+        let mut child = command
+            .env("SONAR_MODE", mode)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("starting event script: {}", cfg.event_script))?;
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take().context("event script stdout")?;
+        let (tx, replies) = unbounded();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        logger.info(&format!("event script running: {}", cfg.event_script))?;
+        Ok(Self { child, stdin, replies })
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // closing stdin ends a script that reads to the end; one that does not is stopped
+        self.stdin = None;
+        for _ in 0..10 {
+            if matches!(self.child.try_wait(), Ok(Some(_))) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Apply a verdict to a window and set its flip flags against the state reported before.
+fn settle(before: PresenceState, w: &mut WindowState, verdict: &Verdict) {
+    w.state = verdict.rewrite(w.state);
+    w.state_changed = w.state != before;
+    w.flipped = w.state.present() != before.present();
+}
+
+/// POST `body` as JSON to an `http://` URL; the status code.
+fn post(url: &str, body: &str, timeout: Duration) -> Result<u16> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        bail!("only http:// webhooks are supported");
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let host = if authority.contains(':') { authority.to_string() } else { format!("{}:80", authority) };
+    let addr = host.to_socket_addrs()?.next().with_context(|| format!("cannot resolve {}", authority))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        authority,
+        body.len(),
+        body
+    )?;
+    let mut head = [0u8; 64];
+    let n = stream.read(&mut head)?;
+    let line = String::from_utf8_lossy(&head[..n]);
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .with_context(|| format!("no HTTP status in {:?}", line.lines().next().unwrap_or("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(state: PresenceState) -> WindowState {
+        WindowState {
+            flipped: false,
+            state,
+            state_changed: false,
+            avg_d: 1.0,
+            avg_s: 0.5,
+            agree: 0.8,
+            iqr_d: 0.1,
+            bearing_deg: None,
+            quality: None,
+            probability: None,
+        }
+    }
+
+    #[test]
+    fn replies_rewrite_and_suppress_against_the_reported_state() {
+        assert_eq!(Verdict::parse("{}").unwrap(), Verdict::default());
+        assert!(Verdict::parse(r#"{"action":"suppress"}"#).unwrap().suppress);
+        assert!(Verdict::parse(r#"{"action":"rewrite"}"#).is_err());
+        assert!(Verdict::parse("ok").is_err());
+        let hook = Verdict::parse(r#"{"webhook":"http://h:8/x","body":{"a":[1,"b\"c"]}}"#).unwrap();
+        assert_eq!(hook.webhook, Some(("http://h:8/x".to_string(), Some(r#"{"a":[1,"b\"c"]}"#.to_string()))));
+
+        // on a call: the detector says present, the script says absent; nothing flips
+        let absent = Verdict::parse(r#"{"action":"rewrite","present":false}"#).unwrap();
+        let mut w = window(PresenceState::Active);
+        settle(PresenceState::Absent, &mut w, &absent);
+        assert_eq!((w.state, w.flipped, w.state_changed), (PresenceState::Absent, false, false));
+
+        // call over: passed through, the flip is reported on this window
+        let mut w = window(PresenceState::Active);
+        settle(PresenceState::Absent, &mut w, &Verdict::default());
+        assert_eq!((w.state, w.flipped, w.state_changed), (PresenceState::Active, true, true));
+
+        let present = Verdict::parse(r#"{"action":"rewrite","present":true}"#).unwrap();
+        assert_eq!(present.rewrite(PresenceState::Absent), PresenceState::Idle);
+        assert_eq!(present.rewrite(PresenceState::Active), PresenceState::Active);
+        assert!(post("https://example.test/", "{}", Duration::from_millis(10)).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_reply_echoing_the_seq_counts() {
+        assert_eq!(reply_seq(r#"{"seq":7,"action":"pass"}"#), Some(7));
+        assert_eq!(reply_seq("{}"), None);
+
+        let dir = std::env::temp_dir().join(format!("event_script_seq_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let logger = Arc::new(Logger::new(&dir.join("Detection.log").to_string_lossy(), false).unwrap());
+        // answers each event twice: first as if for the event before, then for this one
+        let cfg = Config {
+            event_script: r#"while read -r l; do s=$(echo "$l" | sed 's/.*"seq":\([0-9]*\).*/\1/'); echo "{\"seq\":$((s-1)),\"action\":\"suppress\"}"; echo "{\"seq\":$s,\"action\":\"rewrite\",\"present\":false}"; done"#.to_string(),
+            event_script_timeout_ms: 2000,
+            ..Config::default()
+        };
+        let mut script = EventScript::start(&cfg, "presence", logger).unwrap().unwrap();
+        for _ in 0..3 {
+            let mut w = window(PresenceState::Active);
+            assert!(script.filter(&mut w), "a stale suppress was taken for this event");
+            assert_eq!(w.state, PresenceState::Absent);
+        }
+        drop(script);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
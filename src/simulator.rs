@@ -0,0 +1,492 @@
+//! src/simulator.rs
+//! Synthetic room for tests: a reference signal plus the mic picture of it
+//! (direct path, optional person echo at a given distance, noise floor).
+
+/// Speed of sound used by the detector.
+pub const C: f32 = 343.0;
+
+/// Deterministic noise source (no `rand` in this crate).
+pub struct Lcg(pub u64);
+
+impl Lcg {
+    /// Uniform in [-1, 1).
+    pub fn next(&mut self) -> f32 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.0 >> 40) as f32) / ((1u64 << 23) as f32) - 1.0
+    }
+}
+
+/// Music-like reference with enough broadband content for the correlator.
+pub fn music(sr: f32, secs: f32, seed: u64) -> Vec<f32> {
+    melody(sr, secs, seed, 0.15, 0.3)
+}
+
+/// Tone-dominated reference (sustained notes, little noise): the material scan/gated mode avoids.
+pub fn tonal(sr: f32, secs: f32, seed: u64) -> Vec<f32> {
+    melody(sr, secs, seed, 0.3, 0.05)
+}
+
+/// A note that changes every 250 ms over broadband noise.
+fn melody(sr: f32, secs: f32, seed: u64, tone_amp: f32, noise_amp: f32) -> Vec<f32> {
+    let mut rng = Lcg(seed);
+    let n = (secs * sr) as usize;
+    let note_len = ((0.25 * sr) as usize).max(1);
+    let mut freq = 440.0f32;
+    (0..n)
+        .map(|i| {
+            if i % note_len == 0 {
+                freq = 200.0 + 4800.0 * (rng.next() * 0.5 + 0.5);
+            }
+            let t = (i as f32) / sr;
+            tone_amp * (2.0 * std::f32::consts::PI * freq * t).sin() + noise_amp * rng.next()
+        })
+        .collect()
+}
+
+/// Render→mic acoustics. Gains are linear, relative to the reference.
+#[derive(Clone, Copy, Debug)]
+pub struct Room {
+    pub sr: f32,
+    pub direct_delay_s: f32, // render pipeline + speaker→mic latency
+    pub direct_gain: f32,
+    pub person_m: Option<f32>, // None = nobody in front of the screen
+    pub echo_gain: f32,
+    pub noise_rms: f32,
+    pub seed: u64,
+}
+
+impl Room {
+    pub fn new(sr: f32) -> Self {
+        Self {
+            sr,
+            direct_delay_s: 0.01,
+            direct_gain: 0.6,
+            person_m: None,
+            echo_gain: 0.3,
+            noise_rms: 0.0005,
+            seed: 1,
+        }
+    }
+
+    pub fn with_person(mut self, distance_m: f32) -> Self {
+        self.person_m = Some(distance_m);
+        self
+    }
+
+    /// Noise floor set so the echo sits `snr_db` above it, for a reference of RMS `ref_rms`.
+    pub fn with_echo_snr(mut self, snr_db: f32, ref_rms: f32) -> Self {
+        let echo_rms = self.echo_gain * ref_rms;
+        self.noise_rms = echo_rms / (10.0f32).powf(snr_db / 20.0);
+        self
+    }
+
+    /// Round-trip delay of the person echo in samples.
+    pub fn echo_delay(&self) -> Option<usize> {
+        self.person_m.map(|d| (((2.0 * d) / C) * self.sr).round() as usize)
+    }
+
+    /// Mic signal for `reference`, same length and time base.
+    pub fn render(&self, reference: &[f32]) -> Vec<f32> {
+        self.render_mic(reference, 0, self.seed)
+    }
+
+    /// Two mics for `reference`: the echo reaches the right one `skew` samples before the left
+    /// (negative: after), as from a person off to that side. The speaker is equally far from both.
+    pub fn render_pair(&self, reference: &[f32], skew: isize) -> (Vec<f32>, Vec<f32>) {
+        (self.render_mic(reference, skew.max(0) as usize, self.seed), self.render_mic(reference, (-skew).max(0) as usize, self.seed + 1))
+    }
+
+    fn render_mic(&self, reference: &[f32], echo_late: usize, seed: u64) -> Vec<f32> {
+        let mut rng = Lcg(seed);
+        // uniform noise has RMS = amplitude / sqrt(3)
+        let noise_amp = self.noise_rms * (3.0f32).sqrt();
+        let direct = (self.direct_delay_s * self.sr).round() as usize;
+        let echo = self.echo_delay().map(|e| direct + e + echo_late);
+        (0..reference.len())
+            .map(|i| {
+                let mut v = noise_amp * rng.next();
+                if i >= direct {
+                    v += self.direct_gain * reference[i - direct];
+                }
+                if let Some(e) = echo {
+                    if i >= e {
+                        v += self.echo_gain * reference[i - e];
+                    }
+                }
+                v
+            })
+            .collect()
+    }
+}
+
+/// `secs` of music with nobody there until `enter_s`, then a person at `distance_m`: the
+/// (reference, mic) pair of a session, for runs of the whole pipeline.
+pub fn walk_in(sr: f32, secs: f32, enter_s: f32, distance_m: f32, seed: u64) -> (Vec<f32>, Vec<f32>) {
+    let reference = music(sr, secs, seed);
+    let empty = Room::new(sr).render(&reference);
+    let person = Room::new(sr).with_person(distance_m).render(&reference);
+    let enter = ((enter_s * sr) as usize).min(reference.len());
+    let mic = empty[..enter].iter().chain(&person[enter..]).copied().collect();
+    (reference, mic)
+}
+
+pub fn rms(x: &[f32]) -> f32 {
+    if x.is_empty() {
+        return 0.0;
+    }
+    (
+        x
+            .iter()
+            .map(|v| v * v)
+            .sum::<f32>() / (x.len() as f32)
+    ).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::presence::PresenceDetector;
+    use crate::{ sonar_presence, Config };
+    use std::time::{ Duration, Instant };
+
+    // 16 kHz keeps the correlation cheap enough for debug-build tests
+    const SR: f32 = 16_000.0;
+
+    fn frame_pair(room: &Room, reference: &[f32], end: usize, len: usize) -> (Vec<f32>, Vec<f32>) {
+        let mic = room.render(&reference[..end]);
+        (reference[end - len..end].to_vec(), mic[end - len..end].to_vec())
+    }
+
+    #[test]
+    fn distance_accuracy() {
+        let cfg = Config::default();
+        let len = sonar_presence::analysis_len(SR, cfg.front_max_m);
+        let reference = music(SR, 1.0, 7);
+        for d in [0.5f32, 0.8, 1.1, 1.4] {
+            let room = Room::new(SR).with_person(d);
+            let (r, m) = frame_pair(&room, &reference, len, len);
+            let meas = sonar_presence::estimate_from_ref(&r, &m, SR, &cfg, true, None).unwrap();
+            let (est, strength) = meas.pair();
+            // one sample is ~1 cm of distance at 16 kHz
+            assert!((est - d).abs() < 0.03, "d={} estimated {}", d, est);
+            assert!(strength >= cfg.strength_thr, "d={} strength {}", d, strength);
+            let lags = (meas.peak_lag - meas.direct_lag) as f32;
+            assert!((lags - (2.0 * d * SR) / C).abs() <= 2.0, "d={} lags {}", d, lags);
+            assert!(meas.snr_db > 6.0, "d={} snr {:.1} dB", d, meas.snr_db);
+            assert!(meas.r_at(meas.peak_lag).is_some_and(|r| r > 0.0));
+        }
+    }
+
+    #[test]
+    fn distance_across_snr() {
+        let cfg = Config::default();
+        let len = sonar_presence::analysis_len(SR, cfg.front_max_m);
+        let reference = music(SR, 1.0, 11);
+        let ref_rms = rms(&reference);
+        for snr_db in [30.0f32, 20.0, 10.0] {
+            let room = Room::new(SR).with_person(0.9).with_echo_snr(snr_db, ref_rms);
+            let (r, m) = frame_pair(&room, &reference, len, len);
+            let (est, _) = sonar_presence::estimate_from_ref(&r, &m, SR, &cfg, false, None).unwrap().pair();
+            assert!((est - 0.9).abs() < 0.03, "snr={}dB estimated {}", snr_db, est);
+        }
+    }
+
+    #[test]
+    fn mic_ahead_of_the_loopback_needs_the_lead_window() {
+        let len = sonar_presence::analysis_len(SR, 1.5);
+        let reference = music(SR, 1.0, 13);
+        let room = Room::new(SR).with_person(0.8);
+        // the loopback arrives 40 ms late: the mic frame holds sound the reference frame has yet to
+        let late = (0.04 * SR) as usize;
+        let mic = room.render(&reference[..len + late]);
+        let (r, m) = (&reference[..len], &mic[late..len + late]);
+
+        let measure = |lead_ms: u32| {
+            let cfg = Config { max_lead_ms: lead_ms, ..Config::default() };
+            sonar_presence::estimate_from_ref(r, m, SR, &cfg, true, None).unwrap()
+        };
+        let meas = measure(50);
+        let direct = (room.direct_delay_s * SR).round() as i64 - (late as i64);
+        assert!((meas.direct_lag - direct).abs() <= 1, "direct lag {} vs {}", meas.direct_lag, direct);
+        assert!((meas.distance_m - 0.8).abs() < 0.03, "estimated {}", meas.distance_m);
+        assert_eq!(meas.first_lag, -((0.05 * SR) as i64));
+        assert!(meas.r_at(meas.direct_lag).is_some_and(|r| r == meas.direct_r));
+        // without it the direct path is off the searched lags
+        assert!(measure(0).direct_lag >= 0);
+    }
+
+    #[test]
+    fn delay_lock_narrows_the_search_and_lets_go_of_a_moved_path() {
+        let cfg = Config { delay_lock_s: 1.0, tick_ms: 250, delay_margin_ms: 2.0, ..Config::default() };
+        let len = sonar_presence::analysis_len(SR, cfg.front_max_m);
+        let reference = music(SR, 1.0, 17);
+        let room = Room::new(SR).with_person(0.9);
+        let (r, m) = frame_pair(&room, &reference, len, len);
+
+        let mut lock = sonar_presence::DelayLock::new(&cfg);
+        assert_eq!(lock.lags(&cfg, SR), sonar_presence::full_lags(&cfg, SR));
+        let full = sonar_presence::estimate_in(&r, &m, SR, &cfg, lock.lags(&cfg, SR), false, None).unwrap();
+        // one wild pick among the first lags does not stop the lock
+        let mut changes = Vec::new();
+        for lag in [full.direct_lag, full.direct_lag + 1, 900, full.direct_lag, full.direct_lag - 1, full.direct_lag, full.direct_lag, full.direct_lag] {
+            changes.extend(lock.observe(lag, SR));
+        }
+        assert_eq!(changes, vec![sonar_presence::LockChange::Locked(full.direct_lag)]);
+
+        let (lo, hi) = lock.lags(&cfg, SR);
+        assert!(hi - lo < 100, "locked window {}..{}", lo, hi);
+        let near = sonar_presence::estimate_in(&r, &m, SR, &cfg, (lo, hi), false, None).unwrap();
+        assert_eq!((near.direct_lag, near.peak_lag), (full.direct_lag, full.peak_lag));
+
+        // the path moved: five ticks on the edge and the full search is back
+        let moved: Vec<_> = (0..5).filter_map(|_| lock.observe(hi, SR)).collect();
+        assert_eq!(moved, vec![sonar_presence::LockChange::Lost]);
+        assert_eq!(lock.lags(&cfg, SR), sonar_presence::full_lags(&cfg, SR));
+    }
+
+    #[test]
+    fn tonal_reference_does_not_vote() {
+        let cfg = Config::default();
+        let len = sonar_presence::analysis_len(SR, cfg.front_max_m);
+        let reference = tonal(SR, 1.0, 7);
+        let room = Room::new(SR).with_person(0.8);
+        let (r, m) = frame_pair(&room, &reference, len, len);
+        // correlation sidelobes of a sustained tone must not pass as a confident echo
+        if let Some(sonar_presence::Measurement { strength, .. }) = sonar_presence::estimate_from_ref(&r, &m, SR, &cfg, false, None) {
+            assert!(strength < cfg.strength_thr, "tonal strength {}", strength);
+        }
+    }
+
+    /// Run the presence detector over `secs` of simulated audio; returns the smoothed state per tick.
+    fn run_detector(cfg: &Config, room: &Room, secs: f32, seed: u64) -> Vec<bool> {
+        let len = sonar_presence::analysis_len(SR, cfg.front_max_m);
+        let reference = music(SR, secs, seed);
+        let mic = room.render(&reference);
+        let hop = (((cfg.tick_ms as f32) / 1000.0) * SR) as usize;
+        let mut det = PresenceDetector::new(cfg);
+        let t0 = Instant::now();
+        let mut states = Vec::new();
+        let mut pos = len;
+        let mut tick = 0u32;
+        while pos <= reference.len() {
+            let now = t0 + Duration::from_millis(cfg.tick_ms) * tick;
+            det.tick(&reference[pos - len..pos], &mic[pos - len..pos], SR, cfg, now, None);
+            states.push(det.policy.present());
+            pos += hop;
+            tick += 1;
+        }
+        states
+    }
+
+    fn test_config() -> Config {
+        Config { tick_ms: 250, window_sec: 1, min_dwell_ms: 500, ..Config::default() }
+    }
+
+    #[test]
+    fn presence_decision_with_person() {
+        let cfg = test_config();
+        let room = Room::new(SR).with_person(0.7);
+        let states = run_detector(&cfg, &room, 3.0, 3);
+        assert!(*states.last().unwrap(), "person at 0.7 m not detected: {:?}", states);
+    }
+
+    #[test]
+    fn presence_decision_at_low_snr() {
+        let cfg = test_config();
+        let reference_rms = rms(&music(SR, 1.0, 5));
+        let room = Room::new(SR).with_person(1.0).with_echo_snr(15.0, reference_rms);
+        let states = run_detector(&cfg, &room, 3.0, 5);
+        assert!(*states.last().unwrap(), "person at 15 dB SNR not detected: {:?}", states);
+    }
+
+    #[test]
+    fn person_beyond_range_is_absent() {
+        let cfg = test_config();
+        // echo well outside front_max_m: nothing in the search band
+        let room = Room::new(SR).with_person(3.0);
+        let states = run_detector(&cfg, &room, 3.0, 9);
+        assert!(states.iter().all(|p| !p), "false presence: {:?}", states);
+    }
+
+    #[test]
+    fn ewma_aggregator_reacts_before_the_window() {
+        let tick_ms = 250;
+        let cfg = Config { window_sec: 4, agg_strategy: sonar_presence::AggStrategy::Ewma, ewma_tau_ms: 1000, ..Config::default() };
+        let mut window = sonar_presence::Aggregator::new(cfg.window_sec, tick_ms, cfg.agg_frac);
+        let mut ewma = sonar_presence::Aggregator::from_config(&cfg, tick_ms);
+        let ticks_to_absent = |agg: &mut sonar_presence::Aggregator| {
+            for _ in 0..40 {
+                let _ = agg.push(Some((0.8, 0.6)));
+            }
+            let (present, d, s, _, _) = agg.push(Some((0.8, 0.6))).unwrap();
+            assert!(present && (d - 0.8).abs() < 1e-3 && (s - 0.6).abs() < 1e-3);
+            (1..).find(|_| !agg.push(None).unwrap().0).unwrap()
+        };
+        // the window has to lose more than half of its 16 ticks; the EWMA drops below 50% after ~tau·ln2
+        assert_eq!(ticks_to_absent(&mut window), 9);
+        assert_eq!(ticks_to_absent(&mut ewma), 3);
+
+        // no estimate until one time constant of ticks has been seen
+        ewma.clear();
+        assert!((0..3).all(|_| ewma.push(Some((0.8, 0.6))).is_none()));
+        assert_eq!(ewma.push(Some((0.8, 0.6))).map(|w| w.3), Some(1.0));
+    }
+
+    #[test]
+    fn activity_splits_present_into_idle_and_active() {
+        use sonar_presence::PresenceState::*;
+        let cfg = Config { active_spread_cm: 20.0, active_hold_ms: 2000, ..Config::default() };
+        let mut activity = sonar_presence::Activity::new(&cfg);
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        assert_eq!(activity.update(true, 0.03, at(0)), Some(Idle)); // sitting still
+        assert_eq!(activity.update(true, 0.35, at(500)), Some(Active)); // walking about
+        assert_eq!(activity.update(true, 0.05, at(1500)), None); // held...
+        assert_eq!(activity.update(true, 0.05, at(2500)), Some(Idle)); // ...for --active-hold-ms
+        assert_eq!(activity.update(false, f64::INFINITY, at(3000)), Some(Absent));
+        assert!(!activity.state().present());
+        let mut never = sonar_presence::Activity::new(&Config { active_spread_cm: 0.0, ..cfg });
+        assert_eq!(never.update(true, 1.0, at(0)), Some(Idle));
+    }
+
+    #[test]
+    fn robust_distance_ignores_outlier_echoes() {
+        use sonar_presence::DistStat;
+        // 8 votes around 0.8 m, two stray far echoes
+        let votes = [0.78f32, 0.8, 0.81, 0.79, 0.8, 0.82, 0.8, 0.79, 1.45, 1.5];
+        let run = |stat: DistStat| {
+            let mut agg = sonar_presence::Aggregator::new(1, 100, 0.5).with_dist_stat(stat);
+            votes
+                .iter()
+                .map(|&d| agg.push(Some((d, 0.5))))
+                .last()
+                .flatten()
+                .unwrap()
+        };
+        let (_, mean, _, _, iqr) = run(DistStat::Mean);
+        assert!(mean > 0.9, "mean {}", mean);
+        for stat in [DistStat::Median, DistStat::Trimmed, DistStat::Cluster] {
+            let (_, d, _, _, _) = run(stat);
+            assert!((d - 0.8).abs() < 0.02, "{:?} {}", stat, d);
+        }
+        assert!((iqr - 0.025).abs() < 1e-3, "iqr {}", iqr);
+
+        assert_eq!(sonar_presence::distance_stats(&mut [], DistStat::Median, 0.1), (f64::INFINITY, f64::INFINITY));
+
+        // a tight group of four against six scattered echoes: the median lands between them
+        let mut split = [0.5f32, 0.52, 0.51, 0.5, 0.8, 0.95, 1.1, 1.2, 1.35, 1.45];
+        let (median, _) = sonar_presence::distance_stats(&mut split, DistStat::Median, 0.1);
+        let (cluster, _) = sonar_presence::distance_stats(&mut split, DistStat::Cluster, 0.1);
+        assert!(median > 0.8 && (cluster - 0.5075).abs() < 1e-3, "median {} cluster {}", median, cluster);
+    }
+
+    #[test]
+    fn drift_tracker_holds_a_walking_direct_path_still() {
+        // reference clock 100 ppm off at 48 kHz: the direct path walks 4.8 samples/s
+        let sr = 48_000.0;
+        let t0 = Instant::now();
+        let mut drift = sonar_presence::DriftTracker::new(60.0);
+        let mut seen = Vec::new();
+        for tick in 0..480 {
+            let t = (tick as f64) * 0.25;
+            let jitter = [0.0, 1.0, -1.0][tick % 3];
+            let mut measured = (300.0 + 4.8 * t + jitter - (drift.shift() as f64)).round() as i64;
+            if tick == 200 {
+                measured += 200; // a mispicked direct path
+            }
+            drift.observe(t0 + Duration::from_secs_f64(t), measured, sr);
+            seen.push(measured);
+        }
+        let ppm = drift.ppm().unwrap();
+        assert!((ppm - 100.0).abs() < 3.0, "{}", ppm);
+        assert!(drift.shift() > 400);
+        // once fitted, the compensated lag stays put instead of walking 480 samples
+        let held = seen[80];
+        assert!(seen[400..].iter().all(|&l| l.abs_diff(held) <= 3), "{} vs {:?}", held, &seen[400..]);
+
+        let mut off = sonar_presence::DriftTracker::new(0.0);
+        off.observe(t0, 300, sr);
+        off.observe(t0 + Duration::from_secs(100), 800, sr);
+        assert_eq!((off.ppm(), off.shift()), (None, 0));
+    }
+
+    #[test]
+    fn corr_band_finds_a_ping_under_tonal_music() {
+        let len = sonar_presence::analysis_len(SR, Config::default().front_max_m);
+        // sustained notes below 5 kHz plus a faint noise ping at 6-7.5 kHz, ~33 dB under the music
+        let mut rng = Lcg(107);
+        let mut ping: Vec<f32> = (0..(SR as usize)).map(|_| rng.next()).collect();
+        sonar_presence::band_limit(&mut ping, SR, 6000.0, 7500.0);
+        let reference: Vec<f32> = tonal(SR, 1.0, 7).iter().zip(&ping).map(|(t, p)| t + 0.02 * p).collect();
+        let room = Room::new(SR).with_person(0.8);
+        let (r, m) = frame_pair(&room, &reference, len, len);
+
+        let tick = |cfg: &Config| PresenceDetector::new(cfg).tick(&r, &m, SR, cfg, Instant::now(), None);
+        let full = tick(&Config::default());
+        assert!(!full.voted, "full band {:?}", full.estimate);
+        let banded = tick(&Config { corr_band: Some((5500.0, 8000.0)), ..Config::default() });
+        let (d, _) = banded.estimate.unwrap();
+        assert!(banded.voted && (d - 0.8).abs() < 0.03, "band-limited {:?}", banded.estimate);
+    }
+
+    #[test]
+    fn prbs_probe_locks_through_media_and_a_narrowband_interferer() {
+        use crate::audio::{ ProbeMode, ProbeSignal, ProbeTemplate, ProbeTone };
+        let cfg = Config {
+            probe_tone: ProbeMode::On,
+            probe_signal: ProbeSignal::Prbs,
+            probe_seed: 7,
+            probe_freq_hz: 5000.0,
+            ..Config::default()
+        };
+        let len = sonar_presence::analysis_len(SR, cfg.front_max_m);
+        let n = SR as usize;
+        let period = ProbeTone::from_config(&cfg).prbs_period(SR).unwrap();
+        let tone = |f: f32, amp: f32, i: usize| amp * (2.0 * std::f32::consts::PI * f * (i as f32) / SR).sin();
+        // loopback: the probe plus a sustained note inside its band
+        let reference: Vec<f32> = (0..n).map(|i| 0.02 * period[i % period.len()] + tone(4500.0, 0.03, i)).collect();
+        let room = Room::new(SR).with_person(0.8);
+        let (r, mut m) = frame_pair(&room, &reference, n, len);
+        // and a whistle in the room the loopback never hears
+        for (i, v) in m.iter_mut().enumerate() {
+            *v += tone(5200.0, 0.05, i);
+        }
+
+        let tick = |cfg: &Config| PresenceDetector::new(cfg).tick(&r, &m, SR, cfg, Instant::now(), None);
+        let raw = tick(&Config { probe_tone: ProbeMode::Off, ..cfg.clone() });
+        assert!(!raw.voted, "raw loopback {:?}", raw.estimate);
+        let res = tick(&cfg);
+        let (d, _) = res.estimate.unwrap();
+        assert!(res.voted && (d - 0.8).abs() < 0.03, "prbs {:?}", res.estimate);
+
+        // the template only locks onto its own seed
+        assert!(ProbeTemplate::from_config(&cfg).unwrap().align(&r, SR).is_some());
+        let other = Config { probe_seed: 8, ..cfg.clone() };
+        assert!(ProbeTemplate::from_config(&other).unwrap().align(&r, SR).is_none());
+        assert!(ProbeTemplate::from_config(&Config { probe_signal: ProbeSignal::Sine, ..cfg }).is_none());
+    }
+
+    #[test]
+    fn bearing_follows_the_echo_delay_between_two_mics() {
+        let cfg = Config { bearing: true, mic_spacing_m: 0.2, ..Config::default() };
+        let len = sonar_presence::analysis_len(SR, cfg.front_max_m);
+        let reference = music(SR, 1.0, 13);
+        let room = Room::new(SR).with_person(0.8);
+        let r = &reference[..len];
+        for skew in [5isize, 0, -3] {
+            let (left, right) = room.render_pair(&reference[..len], skew);
+            let mono: Vec<f32> = left.iter().zip(&right).map(|(a, b)| 0.5 * (a + b)).collect();
+            let res = PresenceDetector::new(&cfg).tick_stereo(r, &mono, Some((&left, &right)), SR, &cfg, Instant::now(), None);
+            let want = ((skew as f32) * C / (SR * cfg.mic_spacing_m)).asin().to_degrees();
+            let got = res.bearing_deg.unwrap_or_else(|| panic!("skew {}: no bearing, {:?}", skew, res.estimate));
+            assert!((got - want).abs() < 3.0, "skew {}: {:.1}° vs {:.1}°", skew, got, want);
+        }
+
+        // a single channel, or nobody there, gives no bearing
+        let mic = room.render(r);
+        assert!(PresenceDetector::new(&cfg).tick(r, &mic, SR, &cfg, Instant::now(), None).bearing_deg.is_none());
+        let (left, right) = Room::new(SR).render_pair(r, 5);
+        let empty = PresenceDetector::new(&cfg).tick_stereo(r, &left, Some((&left, &right)), SR, &cfg, Instant::now(), None);
+        assert!(empty.bearing_deg.is_none());
+    }
+}
@@ -0,0 +1,193 @@
+//! src/smtc.rs
+//! `--smtc`: follow the Windows media session (GlobalSystemMediaTransportControls) for the
+//! playing track's title, play/pause state and position, so gated mode can align to a song
+//! without waiting for a fingerprint match.
+
+use std::{
+    sync::{ Arc, Mutex },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use crate::logger::Logger;
+use crate::supervise::LockExt;
+use crate::Config;
+
+/// How often the session is read; positions in between are extrapolated.
+const POLL: Duration = Duration::from_millis(500);
+
+/// What the media session reports about the current track.
+#[derive(Clone, Debug)]
+pub struct Playback {
+    pub app: String, // AppUserModelId of the player
+    pub title: String,
+    pub artist: String,
+    pub playing: bool,
+    pub position_s: f32, // position at `updated`
+    pub updated: Instant,
+}
+
+impl Playback {
+    /// Position at `now`, advancing with the wall clock while playing.
+    pub fn position_at(&self, now: Instant) -> f32 {
+        if self.playing {
+            self.position_s + now.saturating_duration_since(self.updated).as_secs_f32()
+        } else {
+            self.position_s
+        }
+    }
+
+    /// Title reduced to lowercase letters and digits, for matching against song tags.
+    pub fn title_key(&self) -> String {
+        normalize(&self.title)
+    }
+}
+
+/// Lowercase letters and digits only: "Artist - Song (Live).mp3" → "artistsonglivemp3".
+pub fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+/// Latest state of the media session, read on a background thread.
+pub struct MediaSession {
+    latest: Arc<Mutex<Option<Playback>>>,
+}
+
+impl MediaSession {
+    /// None unless `--smtc` is set and the session manager is available.
+    pub fn start(cfg: &Config, logger: Arc<Logger>) -> Option<Self> {
+        if !cfg.smtc {
+            return None;
+        }
+        if !cfg!(target_os = "windows") {
+            let _ = logger.warn("--smtc is only supported on Windows; aligning by fingerprint only");
+            return None;
+        }
+        let latest = Arc::new(Mutex::new(None));
+        let shared = latest.clone();
+        let thread_logger = logger.clone();
+        let (ready_tx, ready_rx) = crossbeam_channel::bounded::<anyhow::Result<()>>(1);
+        thread::spawn(move || {
+            let session = match platform::Session::open() {
+                Ok(s) => {
+                    let _ = ready_tx.send(Ok(()));
+                    s
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let mut last_err = String::new();
+            loop {
+                let state = match session.poll() {
+                    Ok(p) => p,
+                    Err(e) => {
+                        // a player may drop its session between calls; log each new error once
+                        let msg = e.to_string();
+                        if msg != last_err {
+                            let _ = thread_logger.debug(&format!("media session: {}", msg));
+                            last_err = msg;
+                        }
+                        None
+                    }
+                };
+                *shared.locked() = state;
+                thread::sleep(POLL);
+            }
+        });
+        match ready_rx.recv() {
+            Ok(Ok(())) => Some(Self { latest }),
+            Ok(Err(e)) => {
+                let _ = logger.warn(&format!("Media session unavailable ({}); aligning by fingerprint only", e));
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// The current track, or None when no player publishes a usable session.
+    pub fn current(&self) -> Option<Playback> {
+        self.latest.locked().clone()
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::Playback;
+    use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+    use windows::Media::Control::{
+        GlobalSystemMediaTransportControlsSessionManager as SessionManager,
+        GlobalSystemMediaTransportControlsSessionPlaybackStatus as PlaybackStatus,
+    };
+    use windows::Win32::System::Com::{ CoInitializeEx, COINIT_MULTITHREADED };
+
+    /// 100 ns ticks between 1601-01-01 (Windows DateTime) and 1970-01-01.
+    const EPOCH_DIFF_TICKS: i64 = 116_444_736_000_000_000;
+
+    pub struct Session(SessionManager);
+
+    impl Session {
+        pub fn open() -> anyhow::Result<Self> {
+            unsafe {
+                let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+            }
+            Ok(Self(SessionManager::RequestAsync()?.get()?))
+        }
+
+        /// The current session's track; None when there is no session or it has no timeline.
+        pub fn poll(&self) -> anyhow::Result<Option<Playback>> {
+            // no player registered: the call fails rather than returning a null session
+            let Ok(session) = self.0.GetCurrentSession() else {
+                return Ok(None);
+            };
+            let timeline = session.GetTimelineProperties()?;
+            // players that do not report a timeline leave EndTime at 0
+            if timeline.EndTime()?.Duration <= 0 {
+                return Ok(None);
+            }
+            let props = session.TryGetMediaPropertiesAsync()?.get()?;
+            let status = session.GetPlaybackInfo()?.PlaybackStatus()?;
+
+            // the position is as of LastUpdatedTime; carry that over to the monotonic clock
+            let updated_ticks = timeline.LastUpdatedTime()?.UniversalTime;
+            let now_ticks = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| (d.as_nanos() / 100) as i64 + EPOCH_DIFF_TICKS)
+                .unwrap_or(updated_ticks);
+            let age = Duration::from_nanos((now_ticks - updated_ticks).max(0) as u64 * 100);
+            let now = Instant::now();
+
+            Ok(
+                Some(Playback {
+                    app: session.SourceAppUserModelId()?.to_string_lossy(),
+                    title: props.Title()?.to_string_lossy(),
+                    artist: props.Artist()?.to_string_lossy(),
+                    playing: status == PlaybackStatus::Playing,
+                    position_s: (timeline.Position()?.Duration as f64 / 1e7) as f32,
+                    updated: now.checked_sub(age).unwrap_or(now),
+                })
+            )
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+mod platform {
+    use super::Playback;
+
+    pub struct Session;
+
+    impl Session {
+        pub fn open() -> anyhow::Result<Self> {
+            anyhow::bail!("the media session is only available on Windows")
+        }
+
+        pub fn poll(&self) -> anyhow::Result<Option<Playback>> {
+            Ok(None)
+        }
+    }
+}
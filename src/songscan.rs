@@ -0,0 +1,316 @@
+//! src/songscan.rs
+//! SongScan.csv writer shared by scan and offline modes (gated mode reads it back).
+
+use std::{
+    fs::{ self, File, OpenOptions },
+    io::{ self, BufWriter, Write },
+    path::{ Path, PathBuf },
+};
+
+use crate::csvio;
+use crate::prescan::{ Fingerprint, ScanParams, Segment };
+
+pub const SONGSCAN_HEADER: &str =
+    "url,start_s,end_s,score,frame_ms,window_s,stride_s,bandwidth_z,flatness_z,flux_z,crest_db,hf_ratio,dynrange_z,tonality_z,loudness_dbfs,notes,fp_type,fp_bands,fp_hop_s,fp_offset_s,fp_bins_hex";
+
+/// tiny hex encoder (fingerprint bins are stored as hex in the CSV)
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+/// Open SongScan.csv for appending, writing the header to a new file.
+pub fn open_songscan_csv(path: &Path) -> io::Result<File> {
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    if f.metadata()?.len() == 0 {
+        writeln!(f, "{}", SONGSCAN_HEADER)?;
+        f.flush()?;
+    }
+    Ok(f)
+}
+
+/// `SongScan.partial.csv` next to `SongScan.csv`: provisional results of a scan in progress.
+pub fn partial_path(path: &Path) -> PathBuf {
+    path.with_extension("partial.csv")
+}
+
+/// Replace `path` with a complete SongScan file holding `segs` (write to a temp file, then rename),
+/// so a reader or a crash never sees a half-written file.
+pub fn write_snapshot(
+    path: &Path,
+    tag: &str,
+    segs: &[Segment],
+    params: &ScanParams,
+    fps: &[Fingerprint]
+) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        writeln!(w, "{}", SONGSCAN_HEADER)?;
+        write_segment_rows(&mut w, tag, segs, params, fps)?;
+        // a write error must surface here, not be swallowed on drop with the rename still done
+        w.flush()?;
+        w.get_ref().sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+/// The url/file tag of a data row (first column).
+fn row_tag(row: &[String]) -> &str {
+    row.first().map(|s| s.trim()).unwrap_or("")
+}
+
+/// Existing header and the data rows not tagged `tag` (re-serialised); the count is how many
+/// rows were dropped.
+fn rows_except(path: &Path, tag: &str) -> io::Result<(String, Vec<String>, usize)> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e);
+        }
+    };
+    let mut records = csvio::parse(&text).into_iter().peekable();
+    let header = match records.peek() {
+        Some(h) if row_tag(h) == "url" => csvio::record(&records.next().unwrap()),
+        _ => SONGSCAN_HEADER.to_string(),
+    };
+    let mut kept = Vec::new();
+    let mut dropped = 0usize;
+    for rec in records {
+        if rec.iter().all(|f| f.trim().is_empty()) {
+            continue;
+        }
+        if row_tag(&rec) == tag {
+            dropped += 1;
+        } else {
+            kept.push(csvio::record(&rec));
+        }
+    }
+    Ok((header, kept, dropped))
+}
+
+/// Replace the file atomically: header, `rows`, then whatever `extra` writes.
+fn rewrite(
+    path: &Path,
+    header: &str,
+    rows: &[String],
+    extra: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>
+) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        writeln!(w, "{}", header)?;
+        for r in rows {
+            writeln!(w, "{}", r)?;
+        }
+        extra(&mut w)?;
+        w.flush()?;
+        w.get_ref().sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+/// Store a track's segments. By default this is an upsert: rows already stored under `tag`
+/// are replaced, so re-scanning a song does not duplicate it. `append` keeps them (`--scan-append`).
+/// Returns the number of rows replaced.
+pub fn store_segments(
+    path: &Path,
+    tag: &str,
+    segs: &[Segment],
+    params: &ScanParams,
+    fps: &[Fingerprint],
+    append: bool
+) -> io::Result<usize> {
+    if append {
+        let mut f = open_songscan_csv(path)?;
+        write_segment_rows(&mut f, tag, segs, params, fps)?;
+        return Ok(0);
+    }
+    let (header, kept, dropped) = rows_except(path, tag)?;
+    rewrite(path, &header, &kept, |w| write_segment_rows(w, tag, segs, params, fps))?;
+    Ok(dropped)
+}
+
+/// Delete every row tagged `tag` (`--prune-url`). Returns the number of rows removed.
+pub fn prune_url(path: &Path, tag: &str) -> io::Result<usize> {
+    let (header, kept, dropped) = rows_except(path, tag)?;
+    if dropped > 0 {
+        rewrite(path, &header, &kept, |_| Ok(()))?;
+    }
+    Ok(dropped)
+}
+
+/// `notes` value of the segment rows: the `--scan-scorer` (and its weights) that scored them.
+pub fn weights_note(params: &ScanParams) -> String {
+    params.scorer.note()
+}
+
+/// `notes` value of the extra fingerprint rows (their segment columns are empty).
+pub const FINGERPRINT_NOTE: &str = "fingerprint";
+
+/// Append one row per segment, each carrying the track's lead-in fingerprint (`fps[0]`),
+/// then one fingerprint-only row for every later fingerprint.
+pub fn write_segment_rows<W: Write>(
+    w: &mut W,
+    tag: &str,
+    segs: &[Segment],
+    params: &ScanParams,
+    fps: &[Fingerprint]
+) -> io::Result<()> {
+    let (fp_type, fp_bands, fp_hop_s, fp_offset_s, fp_bins_hex) = if let Some(f) = fps.first() {
+        (f.fp_type.as_str(), f.bands as u32, f.hop_s, f.offset_s, to_hex(&f.bins))
+    } else {
+        ("", 0, 0.0, 0.0, String::new())
+    };
+    for s in segs {
+        let w_peak = &s.peak;
+        writeln!(
+            w,
+            "{},{:.3},{:.3},{:.3},{:.0},{:.1},{:.1},{:.2},{:.2},{:.2},{:.1},{:.3},{:.2},{:.2},{:.1},{}\
+            ,{},{},{:.5},{:.3},{}",
+            csvio::field(tag),
+            s.start_s,
+            s.end_s,
+            w_peak.score,
+            params.frame_ms,
+            params.window_s,
+            params.stride_ms / 1000.0,
+            w_peak.z.bandwidth_z,
+            w_peak.z.flatness_z,
+            w_peak.z.flux_z,
+            w_peak.crest_db,
+            w_peak.hf_ratio,
+            w_peak.z.dynrange_z,
+            w_peak.z.tonality_z,
+            w_peak.loudness_dbfs,
+            csvio::field(&weights_note(params)),
+            fp_type,
+            fp_bands,
+            fp_hop_s,
+            fp_offset_s,
+            fp_bins_hex
+        )?;
+    }
+    let cols: Vec<&str> = SONGSCAN_HEADER.split(',').collect();
+    for f in fps.iter().skip(1) {
+        let mut row = vec![String::new(); cols.len()];
+        for (name, value) in [
+            ("url", tag.to_string()),
+            ("notes", FINGERPRINT_NOTE.to_string()),
+            ("fp_type", f.fp_type.clone()),
+            ("fp_bands", format!("{}", f.bands)),
+            ("fp_hop_s", format!("{:.5}", f.hop_s)),
+            ("fp_offset_s", format!("{:.3}", f.offset_s)),
+            ("fp_bins_hex", to_hex(&f.bins)),
+        ] {
+            if let Some(i) = cols.iter().position(|c| *c == name) {
+                row[i] = value;
+            }
+        }
+        writeln!(w, "{}", csvio::record(&row))?;
+    }
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prescan::{ FeatZ, ScanWeights, WindowFeat, MEL_BANDS };
+    use crate::scanscore::Heuristic;
+
+    fn params(weights: ScanWeights) -> ScanParams {
+        ScanParams {
+            sr: 48000.0,
+            frame_ms: 23.0,
+            window_s: 3.0,
+            stride_ms: 200.0,
+            hf_split_hz: 2500.0,
+            top_n: 20,
+            min_percentile: 85.0,
+            nms_radius_s: 1.0,
+            merge_gap_s: 3.0,
+            clamp_min_s: 3.0,
+            clamp_max_s: 60.0,
+            scorer: Box::new(Heuristic(weights)),
+        }
+    }
+
+    /// A 3 s segment from `start_s`.
+    fn segment(start_s: f32) -> Segment {
+        let peak = WindowFeat {
+            start_s,
+            end_s: start_s + 3.0,
+            flux: 0.0,
+            flatness: 0.0,
+            crest_db: 0.0,
+            bandwidth_hz_95: 0.0,
+            hf_ratio: 0.0,
+            dyn_range: 0.0,
+            tonality: 0.0,
+            loudness_dbfs: -20.0,
+            mel_db: [0.0; MEL_BANDS],
+            score: 1.0,
+            z: FeatZ::default(),
+        };
+        Segment { start_s, end_s: start_s + 3.0, peak }
+    }
+
+    #[test]
+    fn segment_rows_record_the_scan_weights() {
+        let weights = ScanWeights::parse("flux=0.5, tonality=-0.1,silent=2").unwrap();
+        assert_eq!((weights.flux, weights.flatness, weights.tonality, weights.silent), (0.5, 0.2, -0.1, 2.0));
+        assert!(ScanWeights::parse("flux").is_err() && ScanWeights::parse("loud=1").is_err());
+        let params = params(weights);
+        let mut out = Vec::new();
+        write_segment_rows(&mut out, "a", &[segment(1.0)], &params, &[]).unwrap();
+        let rows = csvio::parse(&String::from_utf8(out).unwrap());
+        let notes = SONGSCAN_HEADER.split(',').position(|c| c == "notes").unwrap();
+        let note = rows[0][notes].strip_prefix("scan-weights ").unwrap();
+        // the note is a valid --scan-weights value that reproduces the set
+        assert_eq!(ScanWeights::parse(note).unwrap(), weights);
+    }
+
+    #[test]
+    fn upsert_and_prune_touch_only_that_url() {
+        let dir = std::env::temp_dir().join(format!("songscan-upsert-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("SongScan.csv");
+        let params = params(ScanWeights::default());
+        let starts = |tag: &str| -> Vec<String> {
+            csvio::parse(&fs::read_to_string(&path).unwrap())
+                .into_iter()
+                .skip(1)
+                .filter(|r| row_tag(r) == tag)
+                .map(|r| r[1].clone())
+                .collect()
+        };
+
+        assert_eq!(store_segments(&path, "a", &[segment(1.0), segment(10.0)], &params, &[], false).unwrap(), 0);
+        assert_eq!(store_segments(&path, "b", &[segment(2.0)], &params, &[], false).unwrap(), 0);
+        assert_eq!(store_segments(&path, "a b", &[segment(3.0)], &params, &[], false).unwrap(), 0);
+        let others = |p: &Path| -> Vec<String> {
+            fs::read_to_string(p).unwrap().lines().filter(|l| !l.starts_with("a,")).map(str::to_string).collect()
+        };
+        let before = others(&path);
+
+        // re-scanning "a" replaces its two rows and leaves every other row as it was
+        assert_eq!(store_segments(&path, "a", &[segment(5.0)], &params, &[], false).unwrap(), 2);
+        assert_eq!(starts("a"), ["5.000"]);
+        assert_eq!(others(&path), before);
+        // --scan-append keeps them
+        store_segments(&path, "a", &[segment(7.0)], &params, &[], true).unwrap();
+        assert_eq!(starts("a"), ["5.000", "7.000"]);
+
+        assert_eq!(prune_url(&path, "a").unwrap(), 2);
+        assert!(starts("a").is_empty());
+        assert_eq!((starts("b"), starts("a b")), (vec!["2.000".to_string()], vec!["3.000".to_string()]));
+        assert_eq!(prune_url(&path, "a").unwrap(), 0);
+        assert!(fs::read_to_string(&path).unwrap().starts_with(SONGSCAN_HEADER));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
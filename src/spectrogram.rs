@@ -0,0 +1,330 @@
+//! src/spectrogram.rs
+//! `--export-spectrogram <PATH>`: the spectrogram scan and offline mode analysed, with the
+//! segments they chose, to check by eye why a window scored as it did. A `.png` path gets an
+//! image (time left to right, low bands at the bottom, the segments marked); any other path the
+//! band levels as raw little-endian f32 behind a small header, for numpy or a plotting script.
+//! The PNG is encoded here (stored deflate blocks, no compression), so no image crate is needed.
+
+use anyhow::{ Context, Result };
+use std::{ fs, path::{ Path, PathBuf } };
+
+use crate::prescan::{ self, Segment };
+
+/// Mel bands from LO_HZ to Nyquist (at most 20 kHz).
+pub const BANDS: usize = 128;
+const LO_HZ: f32 = 50.0;
+/// Wider tracks are pooled (loudest frame per column) to this many columns.
+const MAX_COLUMNS: usize = 4096;
+/// Levels shown below the loudest cell; anything quieter is black.
+const RANGE_DB: f32 = 80.0;
+const ROW_PX: usize = 2; // image rows per band
+const STRIP_PX: usize = 10; // segment strip above the spectrogram
+
+const MAGIC: &[u8; 6] = b"SSSPEC";
+const VERSION: u16 = 1;
+
+/// Band levels per analysis frame, in dB relative to a full-scale sine.
+pub struct Spectrogram {
+    hop_s: f32,
+    hi_hz: f32,
+    bins: Vec<(usize, usize)>, // FFT bins k0..k1 of each band
+    norm: f32, // power of a full-scale sine's peak bin
+    frames: Vec<[f32; BANDS]>,
+}
+
+impl Spectrogram {
+    /// For frames of `frame_len` samples weighted by `window`, `hop_len` apart.
+    pub fn new(sr: f32, frame_len: usize, hop_len: usize, window: &[f32]) -> Self {
+        let bin_hz = sr / (frame_len as f32);
+        let n_bins = frame_len / 2 + 1;
+        let hi_hz = (sr * 0.5).min(20000.0);
+        let (m_lo, m_hi) = (prescan::hz_to_mel(LO_HZ), prescan::hz_to_mel(hi_hz));
+        let bins = (0..BANDS)
+            .map(|b| {
+                let edge = |i: usize| prescan::mel_to_hz(m_lo + ((m_hi - m_lo) * (i as f32)) / (BANDS as f32)) / bin_hz;
+                let (lo, hi) = (edge(b), edge(b + 1));
+                let k0 = (lo.ceil() as usize).min(n_bins - 1);
+                let k1 = (hi.ceil() as usize).min(n_bins);
+                // a band narrower than a bin (the bass, at short frames) takes its nearest bin
+                if k1 > k0 {
+                    (k0, k1)
+                } else {
+                    let k = (((lo + hi) / 2.0).round() as usize).min(n_bins - 1);
+                    (k, k + 1)
+                }
+            })
+            .collect();
+        let gain = window.iter().sum::<f32>() / 2.0;
+        Self { hop_s: (hop_len as f32) / sr, hi_hz, bins, norm: (gain * gain).max(1e-12), frames: Vec::new() }
+    }
+
+    /// One frame's FFT magnitudes.
+    pub fn push(&mut self, mag: &[f32]) {
+        let mut row = [0.0f32; BANDS];
+        for (v, &(k0, k1)) in row.iter_mut().zip(&self.bins) {
+            let k1 = k1.min(mag.len());
+            let p = mag[k0.min(k1)..k1]
+                .iter()
+                .map(|m| m * m)
+                .sum::<f32>() / ((k1.saturating_sub(k0)).max(1) as f32);
+            *v = 10.0 * (p / self.norm + 1e-12).log10();
+        }
+        self.frames.push(row);
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Write to `path` (PNG for a `.png` extension, else raw), `segs` marked. Frame 0 of the
+    /// spectrogram is at `t0_s` on the segments' timeline (offline mode's --start-s).
+    pub fn write(&self, path: &Path, t0_s: f32, segs: &[Segment]) -> Result<()> {
+        let bytes = if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("png")) {
+            self.to_png(t0_s, segs)
+        } else {
+            self.to_raw(t0_s, segs)
+        };
+        fs::write(path, bytes).with_context(|| format!("writing spectrogram {}", path.display()))
+    }
+
+    /// SSSPEC, version (u16), frames, bands (u32), hop_s, t0_s, lo_hz, hi_hz (f32), segment
+    /// count (u32) and each segment's start_s, end_s, score (f32), then frames × bands f32 dB.
+    fn to_raw(&self, t0_s: f32, segs: &[Segment]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64 + segs.len() * 12 + self.frames.len() * BANDS * 4);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(BANDS as u32).to_le_bytes());
+        for v in [self.hop_s, t0_s, LO_HZ, self.hi_hz] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&(segs.len() as u32).to_le_bytes());
+        for s in segs {
+            for v in [s.start_s, s.end_s, s.peak.score] {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        for row in &self.frames {
+            for v in row {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    fn to_png(&self, t0_s: f32, segs: &[Segment]) -> Vec<u8> {
+        let per_col = self.frames.len().div_ceil(MAX_COLUMNS).max(1);
+        let width = self.frames.len().div_ceil(per_col).max(1);
+        let height = STRIP_PX + BANDS * ROW_PX;
+        let cols: Vec<[f32; BANDS]> = self.frames
+            .chunks(per_col)
+            .map(|c| {
+                let mut col = [f32::NEG_INFINITY; BANDS];
+                for row in c {
+                    for (v, &x) in col.iter_mut().zip(row) {
+                        *v = v.max(x);
+                    }
+                }
+                col
+            })
+            .collect();
+        let top = cols
+            .iter()
+            .flatten()
+            .fold(f32::NEG_INFINITY, |m, &v| m.max(v));
+
+        // what each column shows above the spectrogram: nothing, a segment, or its peak window
+        let col_s = self.hop_s * (per_col as f32);
+        let col_of = |t: f32| (((t - t0_s) / col_s).max(0.0) as usize).min(width);
+        let mut strip = vec![0u8; width];
+        let mut edges = vec![false; width];
+        for s in segs {
+            let (a, b) = (col_of(s.start_s), col_of(s.end_s));
+            strip[a..b].iter_mut().for_each(|m| *m = (*m).max(1));
+            strip[col_of(s.peak.start_s)..col_of(s.peak.end_s)].iter_mut().for_each(|m| *m = 2);
+            for c in [a, b.saturating_sub(1)] {
+                if c < width {
+                    edges[c] = true;
+                }
+            }
+        }
+
+        let mut raw = Vec::with_capacity(height * (1 + width * 3));
+        for y in 0..height {
+            raw.push(0); // filter: none
+            for x in 0..width {
+                let rgb = if y < STRIP_PX {
+                    match strip[x] {
+                        2 => [255, 255, 255],
+                        1 => [0, 200, 120],
+                        _ => [32, 32, 32],
+                    }
+                } else if edges[x] {
+                    [0, 220, 255]
+                } else {
+                    let band = BANDS - 1 - (y - STRIP_PX) / ROW_PX;
+                    colour((cols[x][band] - (top - RANGE_DB)) / RANGE_DB)
+                };
+                raw.extend_from_slice(&rgb);
+            }
+        }
+        png(width as u32, height as u32, &raw)
+    }
+}
+
+/// `path` for track `number` of a split scan: `<stem>-NN.<ext>`.
+pub fn track_path(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(ext) => format!("{}-{:02}.{}", stem, number, ext.to_string_lossy()),
+        None => format!("{}-{:02}", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+/// Black through purple and orange to pale yellow, for 0..1.
+fn colour(x: f32) -> [u8; 3] {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 4.0],
+        [87.0, 16.0, 110.0],
+        [188.0, 55.0, 84.0],
+        [249.0, 142.0, 9.0],
+        [252.0, 255.0, 164.0],
+    ];
+    let x = if x.is_finite() { x.clamp(0.0, 1.0) } else { 0.0 } * ((STOPS.len() - 1) as f32);
+    let i = (x.floor() as usize).min(STOPS.len() - 2);
+    let t = x - (i as f32);
+    let mut rgb = [0u8; 3];
+    for (c, v) in rgb.iter_mut().enumerate() {
+        *v = (STOPS[i][c] + (STOPS[i + 1][c] - STOPS[i][c]) * t).round() as u8;
+    }
+    rgb
+}
+
+/// An 8-bit RGB PNG of `raw` scanlines (each led by its filter byte).
+fn png(width: u32, height: u32, raw: &[u8]) -> Vec<u8> {
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // depth, RGB, deflate, no filter set, no interlace
+    chunk(&mut out, b"IHDR", &ihdr);
+    chunk(&mut out, b"IDAT", &zlib_stored(raw));
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = if data.is_empty() { vec![&[]] } else { data.chunks(65535).collect() };
+    for (i, block) in blocks.iter().enumerate() {
+        out.push((i + 1 == blocks.len()) as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + (byte as u32)) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prescan::{ FeatZ, WindowFeat, MEL_BANDS };
+
+    fn segment(start_s: f32, end_s: f32) -> Segment {
+        let peak = WindowFeat {
+            start_s,
+            end_s: start_s + 0.5,
+            flux: 0.0,
+            flatness: 0.0,
+            crest_db: 0.0,
+            bandwidth_hz_95: 0.0,
+            hf_ratio: 0.0,
+            dyn_range: 0.0,
+            tonality: 0.0,
+            loudness_dbfs: 0.0,
+            mel_db: [0.0; MEL_BANDS],
+            score: 1.5,
+            z: FeatZ::default(),
+        };
+        Segment { start_s, end_s, peak }
+    }
+
+    /// A 1 kHz full-scale sine, 100 frames of 1024 at 48 kHz.
+    fn sine_spectrogram() -> Spectrogram {
+        let (sr, n) = (48000.0, 1024);
+        let window: Vec<f32> = (0..n).map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * (i as f32) / (n as f32)).cos()).collect();
+        let mut spec = Spectrogram::new(sr, n, 512, &window);
+        let mut planner = realfft::RealFftPlanner::<f32>::new();
+        let r2c = planner.plan_fft_forward(n);
+        for _ in 0..100 {
+            let mut x: Vec<f32> = (0..n)
+                .map(|i| (2.0 * std::f32::consts::PI * 1000.0 * (i as f32) / sr).sin() * window[i])
+                .collect();
+            let mut out = r2c.make_output_vec();
+            r2c.process(&mut x, &mut out).unwrap();
+            spec.push(&out.iter().map(|c| c.norm()).collect::<Vec<_>>());
+        }
+        spec
+    }
+
+    #[test]
+    fn levels_peak_at_the_tone() {
+        let spec = sine_spectrogram();
+        let row = &spec.frames[0];
+        let loudest = (0..BANDS).max_by(|&a, &b| row[a].total_cmp(&row[b])).unwrap();
+        let mid_hz = prescan::mel_to_hz(
+            prescan::hz_to_mel(LO_HZ) +
+                ((prescan::hz_to_mel(spec.hi_hz) - prescan::hz_to_mel(LO_HZ)) * ((loudest as f32) + 0.5)) / (BANDS as f32)
+        );
+        assert!((mid_hz - 1000.0).abs() < 60.0, "loudest band at {} Hz", mid_hz);
+        assert!(row[loudest] > -10.0 && row[loudest] < 3.0, "{} dB", row[loudest]);
+    }
+
+    #[test]
+    fn png_and_raw_are_well_formed() {
+        let spec = sine_spectrogram();
+        let segs = [segment(0.2, 0.6)];
+        let png = spec.to_png(0.0, &segs);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(u32::from_be_bytes(png[16..20].try_into().unwrap()), 100);
+        assert_eq!(u32::from_be_bytes(png[20..24].try_into().unwrap()), (STRIP_PX + BANDS * ROW_PX) as u32);
+        // IEND's CRC is fixed by the format
+        assert_eq!(&png[png.len() - 4..], &[0xae, 0x42, 0x60, 0x82]);
+
+        let raw = spec.to_raw(1.0, &segs);
+        assert_eq!(&raw[..6], MAGIC);
+        assert_eq!(u32::from_le_bytes(raw[8..12].try_into().unwrap()), 100);
+        assert_eq!(f32::from_le_bytes(raw[20..24].try_into().unwrap()), 1.0);
+        assert_eq!(raw.len(), 36 + 12 + 100 * BANDS * 4);
+        assert_eq!(track_path(Path::new("out/spec.png"), 3), Path::new("out/spec-03.png"));
+    }
+}
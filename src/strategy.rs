@@ -0,0 +1,445 @@
+//! src/strategy.rs
+//! `--detector` / `--policy`: the two halves of a live mode's decision, behind traits. A
+//! `Detector` turns one ref/mic frame pair into a measurement; a `DecisionPolicy` turns the
+//! tick's vote into the presence state. Presence, replay and gated mode take both from here,
+//! impulse mode (which measures its own pulses) the policy. A new algorithm is one more impl
+//! and one more `parse` arm.
+
+use std::time::Instant;
+
+use crate::correlator::{ Correlator, FramePos };
+use crate::features;
+use crate::logger::Logger;
+use crate::sonar_presence::{ self, Aggregator, DelayLock, Hysteresis, LockChange, Measurement };
+use crate::{ prescan, Config };
+
+/// Finds the person echo in one tick's frames.
+pub trait Detector: Send {
+    fn name(&self) -> &'static str;
+
+    /// Measure `mic` against `reference` (same length, same moment); None when the frames are
+    /// too quiet or too short to tell.
+    fn process_tick(
+        &mut self,
+        reference: &[f32],
+        mic: &[f32],
+        sr: f32,
+        cfg: &Config,
+        logger: Option<&Logger>
+    ) -> Option<Measurement>;
+
+    /// Forget what was learned about the streams (after sleep, a session switch).
+    fn restart(&mut self) {}
+
+    /// Where the next tick's frames were cut from the rings, when they are passed on unfiltered:
+    /// a detector that correlates them as they are may reuse the products of the overlap.
+    fn frames_at(&mut self, _at: FramePos) {}
+
+    /// Direct-path lags the next tick searches (narrower once the pipeline delay is locked).
+    fn direct_lags(&self, cfg: &Config, sr: f32) -> (i64, i64) {
+        sonar_presence::full_lags(cfg, sr)
+    }
+
+    /// The pipeline delay the search is locked to, in samples.
+    fn locked_delay(&self) -> Option<i64> {
+        None
+    }
+
+    /// Lock to a pipeline delay learned in an earlier run, in samples.
+    fn seed_delay(&mut self, _lag: i64) {}
+}
+
+/// Turns per-tick votes into a smoothed present/absent state.
+pub trait DecisionPolicy: Send {
+    fn name(&self) -> &'static str;
+
+    /// One analysed tick: `vote` is Some((distance_m, strength)) for an echo that counts.
+    /// Some once the window is full.
+    fn push(&mut self, vote: Option<(f32, f32)>, now: Instant) -> Option<Decision>;
+
+    /// A tick that could not be analysed: the votes age, the state holds.
+    fn age(&mut self);
+
+    fn present(&self) -> bool;
+
+    /// Forget the votes (the window refills); the state holds until it has.
+    fn clear(&mut self);
+
+    /// Back to absent.
+    fn reset(&mut self);
+
+    /// Take up a saved state with the window refilled from its summary (`--resume-grace-s`).
+    fn restore(&mut self, present: bool, agree: f32, vote: Option<(f32, f32)>);
+
+    /// Take the thresholds the control interface changed (`--control`).
+    fn tune(&mut self, _cfg: &Config) {}
+}
+
+/// What a policy makes of a full window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decision {
+    pub flipped: bool, // the state changed on this tick
+    pub avg_d: f64,
+    pub avg_s: f64,
+    pub agree: f32,
+    pub iqr_d: f64,
+}
+
+/// `--detector`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DetectorKind {
+    Xcorr, // normalized cross-correlation of the pre-emphasized frames
+    Phat, // the same after whitening both spectra (GCC-PHAT): sharper peaks, more noise
+    Onnx, // xcorr measures, a --model classifier of the tick's features votes
+}
+
+impl DetectorKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "xcorr" => Some(DetectorKind::Xcorr),
+            "phat" | "gcc-phat" => Some(DetectorKind::Phat),
+            "onnx" => Some(DetectorKind::Onnx),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DetectorKind::Xcorr => "xcorr",
+            DetectorKind::Phat => "phat",
+            DetectorKind::Onnx => "onnx",
+        }
+    }
+}
+
+/// `--policy`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyKind {
+    Hysteresis, // agreement window, then --enter-frac/--exit-frac and --min-dwell-ms
+    Majority, // agreement window alone: present while at least --agg-frac of the votes agree
+}
+
+impl PolicyKind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "hysteresis" => Some(PolicyKind::Hysteresis),
+            "majority" => Some(PolicyKind::Majority),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicyKind::Hysteresis => "hysteresis",
+            PolicyKind::Majority => "majority",
+        }
+    }
+}
+
+/// The `--detector` of `cfg`.
+pub fn detector(cfg: &Config) -> Box<dyn Detector> {
+    match cfg.detector {
+        // the model takes its vote from what the correlation found (presence::PresenceDetector runs it)
+        DetectorKind::Xcorr | DetectorKind::Onnx => Box::new(Xcorr { lock: DelayLock::new(cfg), corr: Correlator::new(), at: None }),
+        DetectorKind::Phat => Box::new(Phat { lock: DelayLock::new(cfg), corr: Correlator::new() }),
+    }
+}
+
+/// The `--policy` of `cfg`; `tick_ms` is the caller's actual tick.
+pub fn policy(cfg: &Config, tick_ms: u64) -> Box<dyn DecisionPolicy> {
+    let agg = Aggregator::from_config(cfg, tick_ms);
+    match cfg.policy {
+        PolicyKind::Hysteresis =>
+            Box::new(Hysteretic { agg, hyst: Hysteresis::new(cfg.enter_frac, cfg.exit_frac, cfg.min_dwell_ms) }),
+        PolicyKind::Majority => Box::new(Majority { agg, present: false }),
+    }
+}
+
+/// Correlate within the lags `lock` allows and teach it the direct path found.
+#[allow(clippy::too_many_arguments)]
+fn locked_estimate(
+    lock: &mut DelayLock,
+    corr: &mut Correlator,
+    at: Option<FramePos>,
+    reference: &[f32],
+    mic: &[f32],
+    sr: f32,
+    cfg: &Config,
+    logger: Option<&Logger>
+) -> Option<Measurement> {
+    // --features (and the model reading them) summarize the echo band of the correlation
+    let keep = features::wanted(cfg);
+    let m = sonar_presence::estimate_with(corr, at, reference, mic, sr, cfg, lock.lags(cfg, sr), keep, logger)?;
+    let change = lock.observe(m.direct_lag, sr);
+    if let (Some(change), Some(log)) = (change, logger) {
+        let (first, last) = sonar_presence::full_lags(cfg, sr);
+        let _ = match change {
+            LockChange::Locked(lag) =>
+                log.info(
+                    &format!(
+                        "pipeline delay locked at {:.1} ms: searching ±{} ms around it instead of {:.0}..{:.0} ms",
+                        ((lag as f32) / sr) * 1000.0,
+                        cfg.delay_margin_ms,
+                        ((first as f32) / sr) * 1000.0,
+                        ((last as f32) / sr) * 1000.0
+                    )
+                ),
+            LockChange::Lost => log.warn("direct path left the locked delay window; searching all lags again"),
+        };
+    }
+    Some(m)
+}
+
+pub struct Xcorr {
+    lock: DelayLock,
+    corr: Correlator,
+    at: Option<FramePos>, // this tick's frames, if they are ring cuts
+}
+
+impl Detector for Xcorr {
+    fn name(&self) -> &'static str {
+        "xcorr"
+    }
+
+    fn process_tick(
+        &mut self,
+        reference: &[f32],
+        mic: &[f32],
+        sr: f32,
+        cfg: &Config,
+        logger: Option<&Logger>
+    ) -> Option<Measurement> {
+        locked_estimate(&mut self.lock, &mut self.corr, self.at.take(), reference, mic, sr, cfg, logger)
+    }
+
+    fn restart(&mut self) {
+        self.lock.reset();
+        self.corr.clear();
+    }
+
+    fn frames_at(&mut self, at: FramePos) {
+        self.at = Some(at);
+    }
+
+    fn direct_lags(&self, cfg: &Config, sr: f32) -> (i64, i64) {
+        self.lock.lags(cfg, sr)
+    }
+
+    fn locked_delay(&self) -> Option<i64> {
+        self.lock.locked()
+    }
+
+    fn seed_delay(&mut self, lag: i64) {
+        self.lock.seed(lag);
+    }
+}
+
+pub struct Phat {
+    lock: DelayLock,
+    corr: Correlator, // whitened frames are not ring cuts: nothing kept between ticks
+}
+
+impl Detector for Phat {
+    fn name(&self) -> &'static str {
+        "phat"
+    }
+
+    fn process_tick(
+        &mut self,
+        reference: &[f32],
+        mic: &[f32],
+        sr: f32,
+        cfg: &Config,
+        logger: Option<&Logger>
+    ) -> Option<Measurement> {
+        let (mut r, mut m) = (reference.to_vec(), mic.to_vec());
+        whiten(&mut r);
+        whiten(&mut m);
+        locked_estimate(&mut self.lock, &mut self.corr, None, &r, &m, sr, cfg, logger)
+    }
+
+    fn restart(&mut self) {
+        self.lock.reset();
+    }
+
+    fn direct_lags(&self, cfg: &Config, sr: f32) -> (i64, i64) {
+        self.lock.lags(cfg, sr)
+    }
+
+    fn locked_delay(&self) -> Option<i64> {
+        self.lock.locked()
+    }
+
+    fn seed_delay(&mut self, lag: i64) {
+        self.lock.seed(lag);
+    }
+}
+
+/// Flatten the magnitude spectrum of `x`, keeping its phase and its RMS (so the level gates
+/// of `estimate_from_ref` see the original frame). |X|·|Y| is |X·Y*|, so correlating two
+/// whitened frames is the PHAT-weighted cross-correlation.
+fn whiten(x: &mut [f32]) {
+    let n = x.len();
+    let rms = prescan::rms(x);
+    if n < 2 || rms <= 0.0 {
+        return;
+    }
+    let mut planner = realfft::RealFftPlanner::<f32>::new();
+    let r2c = planner.plan_fft_forward(n);
+    let c2r = planner.plan_fft_inverse(n);
+    let mut spec = r2c.make_output_vec();
+    let mut buf = x.to_vec();
+    if r2c.process(&mut buf, &mut spec).is_err() {
+        return;
+    }
+    // bins this far below the strongest carry no phase worth keeping
+    let floor = spec.iter().map(|c| c.norm()).fold(0.0f32, f32::max) * 1e-4;
+    for c in spec.iter_mut() {
+        let mag = c.norm();
+        *c = if mag > floor { *c / mag } else { realfft::num_complex::Complex::new(0.0, 0.0) };
+    }
+    // the inverse needs real DC and Nyquist bins
+    spec[0].im = 0.0;
+    if let Some(last) = spec.last_mut() {
+        last.im = 0.0;
+    }
+    if c2r.process(&mut spec, x).is_err() {
+        return;
+    }
+    let scale = rms / prescan::rms(x).max(1e-12);
+    for v in x.iter_mut() {
+        *v *= scale;
+    }
+}
+
+/// Agreement window, then enter/exit thresholds and a minimum dwell (the default).
+pub struct Hysteretic {
+    agg: Aggregator,
+    hyst: Hysteresis,
+}
+
+impl DecisionPolicy for Hysteretic {
+    fn name(&self) -> &'static str {
+        "hysteresis"
+    }
+
+    fn push(&mut self, vote: Option<(f32, f32)>, now: Instant) -> Option<Decision> {
+        let (_present_raw, avg_d, avg_s, agree, iqr_d) = self.agg.push(vote)?;
+        Some(Decision { flipped: self.hyst.update(agree, now), avg_d, avg_s, agree, iqr_d })
+    }
+
+    fn age(&mut self) {
+        let _ = self.agg.push(None);
+    }
+
+    fn present(&self) -> bool {
+        self.hyst.present
+    }
+
+    fn clear(&mut self) {
+        self.agg.clear();
+    }
+
+    fn reset(&mut self) {
+        self.hyst.reset();
+    }
+
+    fn restore(&mut self, present: bool, agree: f32, vote: Option<(f32, f32)>) {
+        self.agg.seed(agree, vote);
+        self.hyst.present = present;
+    }
+
+    fn tune(&mut self, cfg: &Config) {
+        self.hyst.set_params(cfg.enter_frac, cfg.exit_frac, cfg.min_dwell_ms);
+    }
+}
+
+/// The agreement window's own verdict, flipping as soon as it crosses --agg-frac.
+pub struct Majority {
+    agg: Aggregator,
+    present: bool,
+}
+
+impl DecisionPolicy for Majority {
+    fn name(&self) -> &'static str {
+        "majority"
+    }
+
+    fn push(&mut self, vote: Option<(f32, f32)>, _now: Instant) -> Option<Decision> {
+        let (present, avg_d, avg_s, agree, iqr_d) = self.agg.push(vote)?;
+        let flipped = present != self.present;
+        self.present = present;
+        Some(Decision { flipped, avg_d, avg_s, agree, iqr_d })
+    }
+
+    fn age(&mut self) {
+        let _ = self.agg.push(None);
+    }
+
+    fn present(&self) -> bool {
+        self.present
+    }
+
+    fn clear(&mut self) {
+        self.agg.clear();
+    }
+
+    fn reset(&mut self) {
+        self.present = false;
+    }
+
+    fn restore(&mut self, present: bool, agree: f32, vote: Option<(f32, f32)>) {
+        self.agg.seed(agree, vote);
+        self.present = present;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn majority_flips_at_once_and_hysteresis_waits_for_the_dwell() {
+        let cfg = Config { window_sec: 1, tick_ms: 100, agg_frac: 0.5, min_dwell_ms: 10_000, ..Config::default() };
+        let t0 = Instant::now();
+        let run = |kind: PolicyKind| {
+            let mut p = policy(&Config { policy: kind, ..cfg.clone() }, cfg.tick_ms);
+            let mut flips = 0;
+            for i in 0..40u64 {
+                let vote = (i < 20).then_some((0.8, 0.6)); // someone there for 2 s, then gone
+                if p.push(vote, t0 + Duration::from_millis(i * 100)).is_some_and(|d| d.flipped) {
+                    flips += 1;
+                }
+            }
+            (flips, p.present())
+        };
+        assert_eq!(run(PolicyKind::Majority), (2, false));
+        // entered, then held by the 10 s dwell
+        assert_eq!(run(PolicyKind::Hysteresis), (1, true));
+        assert_eq!(PolicyKind::parse("Majority"), Some(PolicyKind::Majority));
+        assert_eq!(DetectorKind::parse("gcc-phat").map(|k| k.as_str()), Some("phat"));
+    }
+
+    #[test]
+    fn a_restored_policy_holds_its_state_with_a_full_window() {
+        let cfg = Config { window_sec: 1, tick_ms: 100, agg_frac: 0.5, enter_frac: 0.6, exit_frac: 0.3, ..Config::default() };
+        for kind in [PolicyKind::Hysteresis, PolicyKind::Majority] {
+            let mut p = policy(&Config { policy: kind, ..cfg.clone() }, cfg.tick_ms);
+            p.restore(true, 0.8, Some((0.9, 0.5)));
+            assert!(p.present());
+            // the first tick already decides, from a window eight tenths voted
+            let d = p.push(None, Instant::now()).expect("window full");
+            assert!(!d.flipped && (d.agree - 0.7).abs() < 0.11, "{:?}", d);
+            assert!((d.avg_d - 0.9).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn whitening_keeps_the_level() {
+        let mut x: Vec<f32> = (0..2048).map(|i| ((i as f32) * 0.05).sin() + 0.1 * ((i as f32) * 1.3).sin()).collect();
+        let before = prescan::rms(&x);
+        whiten(&mut x);
+        assert!((prescan::rms(&x) - before).abs() < 1e-3 * before);
+    }
+}
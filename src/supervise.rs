@@ -0,0 +1,90 @@
+//! src/supervise.rs
+//! Surviving a panicking thread. Mutexes are locked with poison recovery: what a panicking
+//! holder left behind is a finished value (a tunable, a snapshot), never half a structure, so
+//! the others carry on with it. Threads that keep a component running (ring fillers, the
+//! control, metrics and node servers, the mDNS responder) are started again after a panic.
+
+use std::{
+    panic::{ self, AssertUnwindSafe },
+    sync::{ Arc, Mutex, MutexGuard, PoisonError },
+    thread::{ self, JoinHandle },
+    time::{ Duration, Instant },
+};
+
+use crate::logger::Logger;
+
+/// Pause before the first restart; doubled per panic in a row, up to `MAX_PAUSE`.
+const FIRST_PAUSE: Duration = Duration::from_millis(100);
+const MAX_PAUSE: Duration = Duration::from_secs(30);
+/// A component that ran this long before panicking is restarted after `FIRST_PAUSE` again.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// `Mutex::lock` that takes over the value of a poisoned mutex instead of panicking in turn.
+pub trait LockExt<T> {
+    fn locked(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+    fn locked(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Run `body` until it returns; after a panic, log it and run it again. `body` is the same
+/// closure each time, so what it owns survives; it must start from whatever a panic left.
+pub fn restarting(what: &str, logger: &Logger, mut body: impl FnMut()) {
+    let mut pause = FIRST_PAUSE;
+    loop {
+        let started = Instant::now();
+        let Err(payload) = panic::catch_unwind(AssertUnwindSafe(&mut body)) else {
+            return;
+        };
+        if started.elapsed() >= STABLE_AFTER {
+            pause = FIRST_PAUSE;
+        }
+        let _ = logger.error(&format!("{} panicked ({}); restarting it in {:.1}s", what, panic_message(&payload), pause.as_secs_f32()));
+        thread::sleep(pause);
+        pause = (pause * 2).min(MAX_PAUSE);
+    }
+}
+
+/// `restarting` on a thread of its own.
+pub fn spawn(what: &'static str, logger: Arc<Logger>, body: impl FnMut() + Send + 'static) -> JoinHandle<()> {
+    thread::spawn(move || restarting(what, &logger, body))
+}
+
+fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("no message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_poison_nothing_and_restart_the_component() {
+        let logger = Logger::new(&std::env::temp_dir().join("sonar-supervise.log").to_string_lossy(), false).unwrap();
+        let shared = Arc::new(Mutex::new(1));
+        let s = shared.clone();
+        let _ = thread::spawn(move || {
+            let mut v = s.locked();
+            *v = 2;
+            panic!("device error");
+        }).join();
+        assert!(shared.is_poisoned());
+        assert_eq!(*shared.locked(), 2);
+
+        let mut runs = 0;
+        restarting("test component", &logger, || {
+            runs += 1;
+            if runs < 3 {
+                panic!("run {}", runs);
+            }
+        });
+        assert_eq!(runs, 3);
+    }
+}
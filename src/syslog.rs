@@ -0,0 +1,154 @@
+//! src/syslog.rs
+//! `--log-sink eventlog`: warnings, errors and state changes to the system log, for service
+//! deployments where nobody tails Detection.log. The Windows Event Log on Windows, the local
+//! syslog socket elsewhere.
+
+use std::io;
+
+use crate::logger::LogLevel;
+
+/// Event source / syslog tag.
+pub const SOURCE: &str = "sonar-presence";
+
+pub struct SystemLog {
+    handle: platform::Handle,
+}
+
+impl SystemLog {
+    pub fn open() -> io::Result<Self> {
+        Ok(Self { handle: platform::Handle::open()? })
+    }
+
+    pub fn send(&self, level: LogLevel, message: &str) -> io::Result<()> {
+        self.handle.send(level, message)
+    }
+}
+
+/// RFC 3164 message for the local syslog socket, facility `user`; the daemon adds the time.
+pub fn syslog_line(level: LogLevel, message: &str, pid: u32) -> String {
+    let severity = match level {
+        LogLevel::Error => 3,
+        LogLevel::Warning => 4,
+        LogLevel::Info => 6,
+        LogLevel::Debug => 7,
+    };
+    format!("<{}>{}[{}]: {}", 8 + severity, SOURCE, pid, message)
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::*;
+    use std::{ os::unix::net::UnixDatagram, path::Path };
+
+    const SOCKETS: [&str; 2] = ["/dev/log", "/var/run/syslog"]; // Linux, macOS
+
+    pub struct Handle(UnixDatagram);
+
+    impl Handle {
+        pub fn open() -> io::Result<Self> {
+            let found = SOCKETS.iter().find(|p| Path::new(p).exists());
+            let path = found.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no syslog socket"))?;
+            Self::open_at(Path::new(path))
+        }
+
+        pub fn open_at(path: &Path) -> io::Result<Self> {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            Ok(Self(socket))
+        }
+
+        pub fn send(&self, level: LogLevel, message: &str) -> io::Result<()> {
+            self.0.send(syslog_line(level, message, std::process::id()).as_bytes()).map(|_| ())
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::*;
+    use windows::core::PCWSTR;
+    use windows::Win32::Foundation::HANDLE;
+    use windows::Win32::Security::PSID;
+    use windows::Win32::System::EventLog::{
+        DeregisterEventSource,
+        RegisterEventSourceW,
+        ReportEventW,
+        EVENTLOG_ERROR_TYPE,
+        EVENTLOG_INFORMATION_TYPE,
+        EVENTLOG_WARNING_TYPE,
+    };
+
+    pub struct Handle(HANDLE);
+
+    impl Handle {
+        /// The source is not registered, so Event Viewer prefixes the text with a note that
+        /// the description is missing; the message itself is shown in full.
+        pub fn open() -> io::Result<Self> {
+            let name: Vec<u16> = SOURCE.encode_utf16().chain(Some(0)).collect();
+            let h = unsafe { RegisterEventSourceW(PCWSTR::null(), PCWSTR(name.as_ptr())) }.map_err(io::Error::other)?;
+            Ok(Self(h))
+        }
+
+        pub fn send(&self, level: LogLevel, message: &str) -> io::Result<()> {
+            let (kind, id) = match level {
+                LogLevel::Error => (EVENTLOG_ERROR_TYPE, 3),
+                LogLevel::Warning => (EVENTLOG_WARNING_TYPE, 2),
+                LogLevel::Info | LogLevel::Debug => (EVENTLOG_INFORMATION_TYPE, 1),
+            };
+            let text: Vec<u16> = message.encode_utf16().chain(Some(0)).collect();
+            unsafe { ReportEventW(self.0, kind, 0, id, PSID::default(), 0, Some(&[PCWSTR(text.as_ptr())]), None) }.map_err(
+                io::Error::other
+            )
+        }
+    }
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = DeregisterEventSource(self.0);
+            }
+        }
+    }
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+mod platform {
+    use super::*;
+
+    pub struct Handle;
+
+    impl Handle {
+        pub fn open() -> io::Result<Self> {
+            Err(io::Error::new(io::ErrorKind::Unsupported, "no system log on this platform"))
+        }
+
+        pub fn send(&self, _level: LogLevel, _message: &str) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syslog_lines_carry_severity_and_tag() {
+        assert_eq!(syslog_line(LogLevel::Warning, "mic stalled", 42), "<12>sonar-presence[42]: mic stalled");
+        assert!(syslog_line(LogLevel::Error, "x", 1).starts_with("<11>"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn datagrams_reach_the_socket() {
+        let path = std::env::temp_dir().join(format!("sonar-syslog-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        let log = SystemLog { handle: platform::Handle::open_at(&path).unwrap() };
+        log.send(LogLevel::Info, "state_change -> present=true").unwrap();
+        let mut buf = [0u8; 256];
+        let n = server.recv(&mut buf).unwrap();
+        assert!(std::str::from_utf8(&buf[..n]).unwrap().ends_with("]: state_change -> present=true"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,212 @@
+//! src/watchdog.rs
+//! `--watchdog-s`: notices a capture stream that stopped delivering (device removed, driver
+//! glitch, a dead WASAPI thread) and opens it again into the same ring buffer, instead of
+//! leaving the detector to skip ticks for the rest of the run. After sleep or a session switch
+//! (`power.rs`) it opens all of them again.
+
+use std::{ sync::Arc, time::{ Duration, Instant } };
+
+use crate::audio::{ self, AudioSource, Feed };
+use crate::logger::{ Field, LogLevel, Logger };
+use crate::{ Config, SharedBuf };
+
+/// Share of the nominal sample rate below which a stream counts as stalled.
+const MIN_RATE: f64 = 0.5;
+/// Longest wait between restarts of a stream that stays silent.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+struct Watched {
+    role: &'static str, // "mic", "reference"
+    buf: SharedBuf,
+    feed: Feed,
+    seen: u64, // samples written when the current interval began
+    since: Instant,
+    wait: Duration, // interval before the next check: --watchdog-s, doubled after each restart that did not help
+    restarts: u64,
+}
+
+impl Watched {
+    /// True when the interval is over and the stream delivered too little of it.
+    fn stalled(&mut self, timeout: Duration, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed < self.wait {
+            return false;
+        }
+        let written = self.buf.written();
+        let got = written.saturating_sub(self.seen) as f64;
+        self.seen = written;
+        self.since = now;
+        if got >= (self.buf.sr as f64) * elapsed.as_secs_f64() * MIN_RATE {
+            self.wait = timeout;
+            return false;
+        }
+        true
+    }
+}
+
+/// Block arrival per capture source, checked every tick, and the one place streams are
+/// opened again: when one stalls, or all of them after sleep or a session switch.
+pub struct Watchdog {
+    timeout: Option<Duration>, // None with --watchdog-s 0: restart only on request
+    streams: Vec<Watched>,
+    logger: Arc<Logger>,
+}
+
+impl Watchdog {
+    pub fn new(cfg: &Config, logger: Arc<Logger>) -> Self {
+        let timeout = (cfg.watchdog_s > 0.0).then(|| Duration::from_secs_f32(cfg.watchdog_s));
+        Self { timeout, streams: Vec::new(), logger }
+    }
+
+    /// Watch the ring `capture_fed` returned; `poll` takes the sources in the order they were added.
+    pub fn watch(&mut self, role: &'static str, buf: &SharedBuf, feed: Feed) {
+        self.streams.push(Watched {
+            role,
+            buf: buf.clone(),
+            feed,
+            seen: buf.written(),
+            since: Instant::now(),
+            wait: self.timeout.unwrap_or(MAX_BACKOFF),
+            restarts: 0,
+        });
+    }
+
+    /// Restart every restartable source whose stream stalled. Returns the indices restarted.
+    pub fn poll(&mut self, sources: &mut [&mut dyn AudioSource]) -> Vec<usize> {
+        let Some(timeout) = self.timeout else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        let mut restarted = Vec::new();
+        for (i, source) in sources.iter_mut().enumerate().take(self.streams.len()) {
+            let w = &mut self.streams[i];
+            if !source.restartable() || !w.stalled(timeout, now) {
+                continue;
+            }
+            let quiet_s = w.wait.as_secs_f64();
+            w.wait = (w.wait * 2).min(MAX_BACKOFF);
+            let why = format!("stalled for {:.0}s", quiet_s);
+            if self.restart(i, &mut **source, &why, &[("stalled_s", Field::Num(quiet_s))]) {
+                restarted.push(i);
+            }
+        }
+        restarted
+    }
+
+    /// Open every restartable source again, whether or not it still delivers: after the
+    /// system resumed from sleep or the session came back, its audio clients are gone.
+    pub fn restart_all(&mut self, sources: &mut [&mut dyn AudioSource], why: &str) -> Vec<usize> {
+        let mut restarted = Vec::new();
+        for (i, source) in sources.iter_mut().enumerate().take(self.streams.len()) {
+            if source.restartable() && self.restart(i, &mut **source, why, &[]) {
+                restarted.push(i);
+            }
+        }
+        restarted
+    }
+
+    /// Close every restartable source until `restart_all` (`--active-hours`).
+    pub fn close_all(&mut self, sources: &mut [&mut dyn AudioSource]) {
+        for (i, source) in sources.iter_mut().enumerate().take(self.streams.len()) {
+            if source.restartable() {
+                audio::close(&mut **source, &self.streams[i].buf, &self.streams[i].feed);
+            }
+        }
+    }
+
+    fn restart(&mut self, i: usize, source: &mut dyn AudioSource, why: &str, fields: &[(&str, Field)]) -> bool {
+        let w = &mut self.streams[i];
+        let ok = match audio::restart(source, &w.buf, &w.feed, self.logger.clone()) {
+            Ok(()) => {
+                w.restarts += 1;
+                let mut all = vec![("stream", Field::Str(w.role)), ("restarts", Field::Int(w.restarts as i64))];
+                all.extend_from_slice(fields);
+                let _ = self.logger.log_fields(
+                    LogLevel::Warning,
+                    &format!("{} stream {}; restarted {} (restart {})", w.role, why, source.describe(), w.restarts),
+                    &all
+                );
+                true
+            }
+            Err(e) => {
+                let _ = self.logger.log_fields(
+                    LogLevel::Warning,
+                    &format!("{} stream {}; restart failed: {:#} (next try in {:.0}s)", w.role, why, e, w.wait.as_secs_f64()),
+                    &[("stream", Field::Str(w.role)), ("restarts", Field::Int(w.restarts as i64))]
+                );
+                false
+            }
+        };
+        // the new stream gets a full interval before it is judged
+        w.seen = w.buf.written();
+        w.since = Instant::now();
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use crossbeam_channel::{ bounded, Receiver };
+    use std::thread;
+
+    /// Delivers nothing on its first start, then steady 10 ms blocks.
+    struct Flaky {
+        starts: usize,
+    }
+
+    impl AudioSource for Flaky {
+        fn describe(&self) -> String {
+            "flaky".to_string()
+        }
+
+        fn start(&mut self, _want_sr: Option<u32>, _logger: Arc<Logger>) -> Result<(f32, Receiver<Vec<f32>>)> {
+            self.starts += 1;
+            let (tx, rx) = bounded::<Vec<f32>>(8);
+            if self.starts > 1 {
+                thread::spawn(move || {
+                    while tx.send(vec![0.1; 80]).is_ok() {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                });
+            }
+            Ok((8000.0, rx))
+        }
+
+        fn restartable(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn stalled_stream_is_restarted_into_the_same_ring() {
+        let path = std::env::temp_dir().join(format!("sonar-watchdog-{}.log", std::process::id()));
+        let logger = Arc::new(Logger::new(&path.to_string_lossy(), false).unwrap());
+        let cfg = Config { watchdog_s: 0.1, ..Config::default() };
+        let mut dog = Watchdog::new(&cfg, logger.clone());
+
+        let mut source = Flaky { starts: 0 };
+        let (buf, feed) = audio::capture_fed(&mut source, None, logger.clone()).unwrap();
+        dog.watch("mic", &buf, feed);
+        assert!(dog.poll(&mut [&mut source]).is_empty(), "judged before the interval was over");
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(dog.poll(&mut [&mut source]), vec![0]);
+        assert_eq!(source.starts, 2);
+        thread::sleep(Duration::from_millis(250));
+        assert!(buf.written() > 0, "the restarted stream does not reach the ring");
+        assert!(dog.poll(&mut [&mut source]).is_empty(), "a delivering stream was restarted");
+
+        // after sleep everything is reopened, stalled or not; with --watchdog-s 0 only then
+        assert_eq!(dog.restart_all(&mut [&mut source], "after resume"), vec![0]);
+        assert_eq!(source.starts, 3);
+        let mut off = Watchdog::new(&Config { watchdog_s: 0.0, ..cfg }, logger.clone());
+        let mut idle = Flaky { starts: 1 };
+        let (buf, feed) = audio::capture_fed(&mut Flaky { starts: 0 }, None, logger).unwrap();
+        off.watch("mic", &buf, feed);
+        thread::sleep(Duration::from_millis(150));
+        assert!(off.poll(&mut [&mut idle]).is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
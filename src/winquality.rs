@@ -0,0 +1,251 @@
+//! src/winquality.rs
+//! How well each SongScan window detects, kept across runs in WindowQuality.csv beside
+//! SongScan.csv. Gated mode scores every visit to a window by the echo SNR of its analysed
+//! ticks and folds the visit into the window's running score; `--window-min-quality` drops
+//! windows that keep scoring below it when the songs are loaded.
+
+use anyhow::{ Context, Result };
+use std::{ collections::HashMap, fs, path::{ Path, PathBuf } };
+
+use crate::csvio;
+use crate::fpdb::SongWindows;
+use crate::output;
+
+pub const WINDOW_QUALITY_HEADER: &str = "url,start_s,end_s,plays,ticks,est_ticks,mean_snr_db,score";
+
+/// Echo SNR that counts as a fully clean tick.
+const SNR_FULL_DB: f32 = 20.0;
+/// Visits shorter than this (a seek through the window, a pause) are not scored.
+const MIN_VISIT_TICKS: u32 = 4;
+/// Plays a window needs before `--window-min-quality` may drop it.
+pub const MIN_JUDGED_PLAYS: u32 = 3;
+/// Weight of the newest visit in the running score.
+const SCORE_ALPHA: f32 = 0.3;
+
+/// (url, start ms, end ms): a rescan that moves the bounds starts a fresh record.
+type Key = (String, i64, i64);
+
+fn key(url: &str, (a, b): (f32, f32)) -> Key {
+    (url.to_string(), (a * 1000.0).round() as i64, (b * 1000.0).round() as i64)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WindowStats {
+    pub plays: u32,
+    pub ticks: u64, // analysed ticks over all plays
+    pub est_ticks: u64, // ... of which gave an echo
+    pub mean_snr_db: f32, // over the ticks with an echo
+    pub score: f32, // 0..1, recent plays weigh most
+}
+
+/// One pass through a window.
+#[derive(Clone, Copy, Debug, Default)]
+struct Visit {
+    ticks: u32,
+    est_ticks: u32,
+    snr_sum: f64,
+    quality_sum: f64,
+}
+
+impl Visit {
+    fn score(&self) -> f32 {
+        (self.quality_sum / (self.ticks.max(1) as f64)) as f32
+    }
+}
+
+pub struct WindowQuality {
+    path: PathBuf,
+    windows: HashMap<Key, WindowStats>,
+    visit: Option<(Key, Visit)>,
+}
+
+impl WindowQuality {
+    /// WindowQuality.csv beside `scansong_path`.
+    pub fn path_for(scansong_path: &str) -> PathBuf {
+        output::sibling_path(scansong_path, "WindowQuality.csv")
+    }
+
+    /// No scores yet, to be saved at `path`.
+    pub fn new(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), windows: HashMap::new(), visit: None }
+    }
+
+    /// The scores saved at `path`; none yet when it does not exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut quality = Self::new(path);
+        if path.exists() {
+            let text = fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+            for (n, rec) in csvio::parse(&text).into_iter().enumerate().skip(1) {
+                if rec.len() == 1 && rec[0].is_empty() {
+                    continue;
+                }
+                let (k, stats) = parse_row(&rec).with_context(|| format!("{} line {}", path.display(), n + 1))?;
+                quality.windows.insert(k, stats);
+            }
+        }
+        Ok(quality)
+    }
+
+    pub fn get(&self, url: &str, seg: (f32, f32)) -> Option<&WindowStats> {
+        self.windows.get(&key(url, seg))
+    }
+
+    /// Where playback is now: inside `seg` of `url`, or in no window. Leaving a window ends its
+    /// visit; true when that visit was scored (and the file wants saving).
+    pub fn observe(&mut self, url: &str, seg: Option<(f32, f32)>) -> bool {
+        let now = seg.map(|s| key(url, s));
+        if self.visit.as_ref().map(|(k, _)| k) == now.as_ref() {
+            return false;
+        }
+        let scored = self.finish();
+        self.visit = now.map(|k| (k, Visit::default()));
+        scored
+    }
+
+    /// One analysed tick of the current visit; `snr_db` None when no echo was found.
+    pub fn tick(&mut self, snr_db: Option<f32>) {
+        if let Some((_, v)) = self.visit.as_mut() {
+            v.ticks += 1;
+            if let Some(snr) = snr_db {
+                v.est_ticks += 1;
+                v.snr_sum += snr as f64;
+                v.quality_sum += (snr / SNR_FULL_DB).clamp(0.0, 1.0) as f64;
+            }
+        }
+    }
+
+    /// End the current visit (alignment lost, shutdown); true when it was scored.
+    pub fn finish(&mut self) -> bool {
+        let Some((k, v)) = self.visit.take() else {
+            return false;
+        };
+        if v.ticks < MIN_VISIT_TICKS {
+            return false;
+        }
+        let w = self.windows.entry(k).or_default();
+        let echoes = w.est_ticks + (v.est_ticks as u64);
+        if echoes > 0 {
+            w.mean_snr_db = ((w.mean_snr_db as f64) * (w.est_ticks as f64) + v.snr_sum) as f32 / (echoes as f32);
+        }
+        w.score = if w.plays == 0 { v.score() } else { w.score + SCORE_ALPHA * (v.score() - w.score) };
+        w.plays += 1;
+        w.ticks += v.ticks as u64;
+        w.est_ticks = echoes;
+        true
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut rows: Vec<(&Key, &WindowStats)> = self.windows.iter().collect();
+        rows.sort_by(|a, b| a.0.cmp(b.0));
+        let mut out = String::from(WINDOW_QUALITY_HEADER);
+        out.push('\n');
+        for ((url, a, b), w) in rows {
+            out.push_str(
+                &csvio::record(
+                    &[
+                        url.clone(),
+                        format!("{:.3}", (*a as f32) / 1000.0),
+                        format!("{:.3}", (*b as f32) / 1000.0),
+                        w.plays.to_string(),
+                        w.ticks.to_string(),
+                        w.est_ticks.to_string(),
+                        format!("{:.2}", w.mean_snr_db),
+                        format!("{:.3}", w.score),
+                    ]
+                )
+            );
+            out.push('\n');
+        }
+        output::write_atomic(&self.path, &out).with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    /// Remove the windows judged over at least MIN_JUDGED_PLAYS plays to score below
+    /// `min_score`; a song whose windows all score that low keeps them. Returns the
+    /// dropped windows as (url, start_s, end_s, score).
+    pub fn prune(&self, songs: &mut [SongWindows], min_score: f32) -> Vec<(String, f32, f32, f32)> {
+        let mut dropped = Vec::new();
+        for song in songs.iter_mut() {
+            let poor = |seg: &(f32, f32)| {
+                self.get(&song.url, *seg).filter(|w| w.plays >= MIN_JUDGED_PLAYS && w.score < min_score).map(|w| w.score)
+            };
+            if song.segs.iter().all(|s| poor(s).is_some()) {
+                continue;
+            }
+            let mut kept = Vec::with_capacity(song.segs.len());
+            for seg in &song.segs {
+                match poor(seg) {
+                    Some(score) => dropped.push((song.url.clone(), seg.0, seg.1, score)),
+                    None => kept.push(*seg),
+                }
+            }
+            song.segs = kept;
+        }
+        dropped
+    }
+}
+
+fn parse_row(rec: &[String]) -> Result<(Key, WindowStats)> {
+    if rec.len() < 8 {
+        anyhow::bail!("expected 8 fields, found {}", rec.len());
+    }
+    let f = |i: usize| rec[i].trim().parse::<f64>().with_context(|| format!("bad number '{}'", rec[i]));
+    let seg = (f(1)? as f32, f(2)? as f32);
+    let stats = WindowStats {
+        plays: f(3)? as u32,
+        ticks: f(4)? as u64,
+        est_ticks: f(5)? as u64,
+        mean_snr_db: f(6)? as f32,
+        score: f(7)? as f32,
+    };
+    Ok((key(&rec[0], seg), stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(q: &mut WindowQuality, url: &str, seg: (f32, f32), snr: &[Option<f32>]) -> bool {
+        q.observe(url, Some(seg));
+        for &s in snr {
+            q.tick(s);
+        }
+        q.observe(url, None)
+    }
+
+    #[test]
+    fn scores_visits_and_prunes_poor_windows() {
+        let dir = std::env::temp_dir().join(format!("winquality_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("WindowQuality.csv");
+        let _ = fs::remove_file(&path);
+        let mut q = WindowQuality::load(&path).unwrap();
+        let (good, bad) = ((10.0, 20.0), (30.0, 40.0));
+        for _ in 0..MIN_JUDGED_PLAYS {
+            assert!(play(&mut q, "a, b", good, &[Some(20.0); 8]));
+            assert!(play(&mut q, "a, b", bad, &[None, None, None, Some(4.0)]));
+        }
+        // a seek through a window is not a play
+        assert!(!play(&mut q, "a, b", good, &[Some(0.0)]));
+        let w = *q.get("a, b", bad).unwrap();
+        assert_eq!((w.plays, w.ticks, w.est_ticks), (3, 12, 3));
+        assert!((w.mean_snr_db - 4.0).abs() < 1e-4 && (w.score - 0.05).abs() < 1e-4);
+        q.save().unwrap();
+
+        let loaded = WindowQuality::load(&path).unwrap();
+        assert_eq!(loaded.get("a, b", good).map(|w| (w.plays, w.score)), Some((3, 1.0)));
+        assert_eq!(loaded.get("a, b", bad).map(|w| w.est_ticks), Some(3));
+
+        let mut songs = vec![
+            SongWindows { url: "a, b".to_string(), segs: vec![good, bad], fps: Vec::new() },
+            SongWindows { url: "c".to_string(), segs: vec![bad], fps: Vec::new() }
+        ];
+        let dropped = loaded.prune(&mut songs, 0.2);
+        assert_eq!(songs[0].segs, vec![good]);
+        assert_eq!(songs[1].segs, vec![bad]);
+        assert_eq!(dropped.len(), 1);
+        // every window poor: the song keeps them all
+        songs[0].segs = vec![bad];
+        assert!(loaded.prune(&mut songs, 0.2).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}